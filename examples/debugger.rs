@@ -0,0 +1,245 @@
+//! A tiny interactive debugger built on this crate.
+//!
+//! Run it with:
+//!
+//! ```text
+//! cargo run --example debugger --features debugger -- /bin/ls -l
+//! ```
+//!
+//! Type `help` at the prompt for a list of commands.
+
+#[cfg(target_arch = "x86_64")]
+mod debugger {
+    use nix::sys::signal::Signal;
+    use nix::unistd::Pid;
+    use spawn_ptrace::{Breakpoints, Event, SpawnOptions, TraceSession};
+    use std::io::{self, BufRead, Write};
+    use std::process::Command;
+
+    const HELP: &str = "\
+break <addr>        insert a breakpoint
+delete <addr>       remove a breakpoint
+continue            resume until the next breakpoint, signal or exit
+step                execute a single instruction
+next                execute a single instruction, stepping over calls
+finish              run until the current function returns
+regs                show the general-purpose registers
+mem <addr> [len]    dump memory
+bt                  show a frame-pointer backtrace
+maps                show the memory map
+quit                kill the tracee and exit
+
+Addresses are hexadecimal, with or without a leading 0x.";
+
+    struct Debugger {
+        session: TraceSession,
+        breakpoints: Breakpoints,
+        pid: Pid,
+        /// A signal to deliver when the tracee is next resumed.
+        signal: Option<Signal>,
+    }
+
+    impl Debugger {
+        fn spawn(args: &[String]) -> io::Result<Debugger> {
+            let mut session = TraceSession::new();
+            let pid =
+                session.spawn(Command::new(&args[0]).args(&args[1..]), SpawnOptions::new())?;
+            Ok(Debugger {
+                session,
+                breakpoints: Breakpoints::new(),
+                pid,
+                signal: None,
+            })
+        }
+
+        /// Handle one command. Returns `false` once the debugger should exit.
+        fn command(&mut self, line: &str) -> io::Result<bool> {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => {}
+                ["help"] | ["h"] => println!("{}", HELP),
+                ["break", addr] | ["b", addr] => {
+                    let addr = parse_addr(addr)?;
+                    self.breakpoints.insert(self.pid, addr)?;
+                    println!("Breakpoint at {:#x}", addr);
+                }
+                ["delete", addr] | ["d", addr] => {
+                    if !self.breakpoints.remove(self.pid, parse_addr(addr)?)? {
+                        println!("No breakpoint there");
+                    }
+                }
+                ["continue"] | ["c"] => return self.resume(false),
+                ["step"] | ["s"] => return self.resume(true),
+                ["next"] | ["n"] => {
+                    let event = self
+                        .breakpoints
+                        .step_over_call(&mut self.session, self.pid)?;
+                    return self.report(event, true);
+                }
+                ["finish"] | ["f"] => {
+                    let event = self.breakpoints.step_out(&mut self.session, self.pid)?;
+                    return self.report(event, true);
+                }
+                ["regs"] => self.regs()?,
+                ["mem", addr] => self.mem(parse_addr(addr)?, 64)?,
+                ["mem", addr, len] => self.mem(parse_addr(addr)?, parse_addr(len)? as usize)?,
+                ["bt"] => {
+                    for (i, addr) in self.tracee().backtrace(64)?.iter().enumerate() {
+                        println!("#{:<3} {:#018x}", i, addr);
+                    }
+                }
+                ["maps"] => {
+                    for map in self.tracee().memory_maps()? {
+                        println!(
+                            "{:012x}-{:012x} {}{}{} {}",
+                            map.start,
+                            map.end,
+                            if map.readable { 'r' } else { '-' },
+                            if map.writable { 'w' } else { '-' },
+                            if map.executable { 'x' } else { '-' },
+                            map.pathname.as_deref().unwrap_or(""),
+                        );
+                    }
+                }
+                ["quit"] | ["q"] => return Ok(false),
+                _ => println!("Unknown command, try `help`"),
+            }
+            Ok(true)
+        }
+
+        fn tracee(&self) -> &spawn_ptrace::Tracee {
+            self.session
+                .get(self.pid)
+                .expect("Tracee is in the session")
+        }
+
+        fn resume(&mut self, single_step: bool) -> io::Result<bool> {
+            let rip = self.tracee().registers()?.rip;
+            let mut event = if self.breakpoints.contains(rip) || single_step {
+                // Get off the current instruction first, in case it is a breakpoint.
+                self.breakpoints.step_over(&mut self.session, self.pid)?
+            } else {
+                Event::Signal(Signal::SIGTRAP)
+            };
+            if !single_step && event == Event::Signal(Signal::SIGTRAP) {
+                nix::sys::ptrace::cont(self.pid, self.signal.take()).map_err(io::Error::other)?;
+                event = self.session.wait_for(self.pid)?.1;
+            }
+            self.report(event, single_step)
+        }
+
+        /// Show why the tracee stopped with `event`. A `SIGTRAP` is expected
+        /// at the end of a step, and otherwise looked up as a breakpoint.
+        /// Stepping over calls and out of functions has already done that.
+        fn report(&mut self, event: Event, single_step: bool) -> io::Result<bool> {
+            match event {
+                Event::Exited(code) => {
+                    println!("Process exited with status {}", code);
+                    return Ok(false);
+                }
+                Event::Signaled(signal, _) => {
+                    println!("Process killed by {:?}", signal);
+                    return Ok(false);
+                }
+                // A single step always ends with a trap.
+                Event::Signal(Signal::SIGTRAP) if single_step => {}
+                Event::Signal(Signal::SIGTRAP) => {
+                    if let Some(addr) = self.breakpoints.hit(self.pid)? {
+                        println!("Breakpoint at {:#x}", addr);
+                    } else {
+                        println!("Stopped by SIGTRAP");
+                    }
+                }
+                Event::Signal(signal) => {
+                    println!("Stopped by {:?}", signal);
+                    self.signal = Some(signal);
+                }
+                event => println!("Stopped: {:?}", event),
+            }
+            println!("rip = {:#x}", self.tracee().registers()?.rip);
+            Ok(true)
+        }
+
+        fn regs(&self) -> io::Result<()> {
+            let r = self.tracee().registers()?;
+            let regs = [
+                ("rax", r.rax),
+                ("rbx", r.rbx),
+                ("rcx", r.rcx),
+                ("rdx", r.rdx),
+                ("rsi", r.rsi),
+                ("rdi", r.rdi),
+                ("rbp", r.rbp),
+                ("rsp", r.rsp),
+                ("r8", r.r8),
+                ("r9", r.r9),
+                ("r10", r.r10),
+                ("r11", r.r11),
+                ("r12", r.r12),
+                ("r13", r.r13),
+                ("r14", r.r14),
+                ("r15", r.r15),
+                ("rip", r.rip),
+                ("eflags", r.eflags),
+            ];
+            for (name, value) in regs.iter() {
+                println!("{:<7}{:#018x}", name, value);
+            }
+            Ok(())
+        }
+
+        fn mem(&self, addr: u64, len: usize) -> io::Result<()> {
+            let mut buf = vec![0; len];
+            self.tracee().read_memory(addr, &mut buf)?;
+            for (i, line) in buf.chunks(16).enumerate() {
+                let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("{:#014x}  {}", addr + i as u64 * 16, hex.join(" "));
+            }
+            Ok(())
+        }
+    }
+
+    fn parse_addr(s: &str) -> io::Result<u64> {
+        u64::from_str_radix(s.trim_start_matches("0x"), 16)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    pub fn main() -> io::Result<()> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        if args.is_empty() {
+            eprintln!("Usage: debugger <program> [args...]");
+            std::process::exit(1);
+        }
+        let mut debugger = Debugger::spawn(&args)?;
+        println!("Stopped at exec of {} (pid {})", args[0], debugger.pid);
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("(dbg) ");
+            io::stdout().flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            match debugger.command(&line) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        if debugger.session.get(debugger.pid).is_some() {
+            let _ = nix::sys::signal::kill(debugger.pid, Signal::SIGKILL);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn main() -> std::io::Result<()> {
+    debugger::main()
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn main() {
+    eprintln!("The debugger example only supports x86-64.");
+}
@@ -0,0 +1,260 @@
+#!/usr/bin/env python3
+"""Regenerate the system call tables in src/syscall_table.
+
+Names and numbers come from the Linux uapi headers, as installed by
+linux-libc-dev (or `make headers_install`), and argument counts from the
+syscall trace events of the running kernel, so this needs root to mount
+tracefs if it isn't mounted already:
+
+    sudo scripts/gen-syscall-tables.py [--include /usr/include]
+
+Argument counts are those of the kernel's SYSCALL_DEFINE prototypes, so
+on 32-bit ABIs a 64-bit argument counts once although it takes two
+registers. System calls that only exist in 32-bit ABIs, or that the running
+kernel leaves out, have no trace events, so their argument counts are
+listed in EXTRA_ARGS below.
+"""
+
+import argparse
+import os
+import re
+import subprocess
+import sys
+import tempfile
+
+TRACEFS = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"]
+
+# Trace events named after the kernel function rather than the system call.
+ALIASES = {
+    "fstat": "newfstat",
+    "lstat": "newlstat",
+    "sendfile": "sendfile64",
+    "stat": "newstat",
+    "umount2": "umount",
+    "uname": "newuname",
+}
+
+# System calls of the i386 ABI that take other arguments than the x86-64
+# ones of the same name: both take a pointer to a structure holding them.
+I386_ARGS = {
+    "mmap": 1,
+    "select": 1,
+}
+
+# Argument counts of system calls without trace events: 32-bit ones, ones
+# that are configured out or obsolete, and unimplemented ones, which take
+# none.
+EXTRA_ARGS = {
+    "_llseek": 5,
+    "_newselect": 5,
+    "_sysctl": 1,
+    "afs_syscall": 0,
+    "bdflush": 2,
+    "break": 0,
+    "chown32": 3,
+    "clock_adjtime64": 2,
+    "clock_getres_time64": 2,
+    "clock_gettime64": 2,
+    "clock_nanosleep_time64": 4,
+    "clock_settime64": 2,
+    "create_module": 2,
+    "delete_module": 2,
+    "epoll_ctl_old": 4,
+    "epoll_wait_old": 4,
+    "fadvise64_64": 4,
+    "fchown32": 3,
+    "fcntl64": 3,
+    "finit_module": 3,
+    "fstat64": 2,
+    "fstatat64": 4,
+    "fstatfs64": 3,
+    "ftime": 0,
+    "ftruncate64": 2,
+    "futex_time64": 6,
+    "get_kernel_syms": 1,
+    "get_thread_area": 1,
+    "getegid32": 0,
+    "geteuid32": 0,
+    "getgid32": 0,
+    "getgroups32": 2,
+    "getpmsg": 5,
+    "getresgid32": 3,
+    "getresuid32": 3,
+    "getuid32": 0,
+    "gtty": 0,
+    "idle": 0,
+    "init_module": 3,
+    "io_pgetevents_time64": 6,
+    "ipc": 6,
+    "kexec_file_load": 5,
+    "kexec_load": 4,
+    "lchown32": 3,
+    "lock": 0,
+    "lookup_dcookie": 3,
+    "lstat64": 2,
+    "mmap2": 6,
+    "mpx": 0,
+    "mq_timedreceive_time64": 5,
+    "mq_timedsend_time64": 5,
+    "nfsservctl": 3,
+    "nice": 1,
+    "oldfstat": 2,
+    "oldlstat": 2,
+    "oldolduname": 1,
+    "oldstat": 2,
+    "olduname": 1,
+    "ppoll_time64": 5,
+    "prof": 0,
+    "profil": 0,
+    "pselect6_time64": 6,
+    "putpmsg": 5,
+    "query_module": 5,
+    "readdir": 3,
+    "recvmmsg_time64": 5,
+    "rt_sigtimedwait_time64": 4,
+    "sched_rr_get_interval_time64": 2,
+    "security": 0,
+    "semtimedop_time64": 4,
+    "set_thread_area": 1,
+    "setfsgid32": 1,
+    "setfsuid32": 1,
+    "setgid32": 1,
+    "setgroups32": 2,
+    "setregid32": 2,
+    "setresgid32": 3,
+    "setresuid32": 3,
+    "setreuid32": 2,
+    "setuid32": 1,
+    "sgetmask": 0,
+    "sigaction": 3,
+    "signal": 2,
+    "sigpending": 1,
+    "sigprocmask": 3,
+    "sigreturn": 0,
+    "sigsuspend": 1,
+    "socketcall": 2,
+    "ssetmask": 1,
+    "stat64": 2,
+    "statfs64": 3,
+    "stime": 1,
+    "stty": 0,
+    "timer_gettime64": 2,
+    "timer_settime64": 4,
+    "timerfd_gettime64": 2,
+    "timerfd_settime64": 4,
+    "truncate64": 2,
+    "tuxcall": 0,
+    "ugetrlimit": 2,
+    "ulimit": 2,
+    "uselib": 1,
+    "utimensat_time64": 4,
+    "vm86": 3,
+    "vm86old": 1,
+    "vserver": 0,
+    "waitpid": 3,
+}
+
+# The `__ARCH_WANT_*` definitions of arch/arm64/include/uapi/asm/unistd.h,
+# which select the generic table entries arm64 uses.
+ARM64_WANTS = [
+    "__ARCH_WANT_RENAMEAT",
+    "__ARCH_WANT_NEW_STAT",
+    "__ARCH_WANT_SET_GET_RLIMIT",
+    "__ARCH_WANT_TIME32_SYSCALLS",
+    "__ARCH_WANT_SYS_CLONE3",
+    "__ARCH_WANT_MEMFD_SECRET",
+]
+
+
+def tracefs():
+    for path in TRACEFS:
+        if os.path.isdir(os.path.join(path, "events")):
+            return path
+    path = tempfile.mkdtemp()
+    subprocess.check_call(["mount", "-t", "tracefs", "nodev", path])
+    return path
+
+
+def trace_args(events):
+    args = {}
+    for entry in os.listdir(events):
+        if not entry.startswith("sys_enter_"):
+            continue
+        with open(os.path.join(events, entry, "format")) as f:
+            fields = re.findall(r"field:[^;]*?(\w+);", f.read())
+        fields = [f for f in fields if not f.startswith("common_")]
+        args[entry[len("sys_enter_"):]] = len([f for f in fields if f != "__syscall_nr"])
+    return args
+
+
+def parse_defines(text):
+    values = dict(re.findall(r"#define (__NR\w+)\s+(.+)", text))
+    table = {}
+    for macro, value in values.items():
+        if not macro.startswith("__NR_"):
+            continue
+        # The generic table defines some numbers through `__NR3264_*`.
+        while value in values:
+            value = values[value]
+        value = re.sub(r"\(__X32_SYSCALL_BIT \+ (\d+)\)", r"\1", value).strip()
+        if value.isdigit():
+            table[int(value)] = macro[len("__NR_"):]
+    return table
+
+
+def generic_table(include):
+    header = os.path.join(include, "asm-generic/unistd.h")
+    defines = ["-D%s" % want for want in ARM64_WANTS] + ["-D__BITS_PER_LONG=64"]
+    text = subprocess.check_output(
+        ["cpp", "-dM", "-I", include] + defines + [header], universal_newlines=True
+    )
+    table = parse_defines(text)
+    # These are limits and aliases rather than system calls.
+    return {n: name for n, name in table.items() if name not in ("syscalls", "arch_specific_syscall")}
+
+
+def resolve_args(name, args, overrides):
+    if name in overrides:
+        return overrides[name]
+    event = ALIASES.get(name, name)
+    if event in args:
+        return args[event]
+    if name in EXTRA_ARGS:
+        return EXTRA_ARGS[name]
+    sys.exit("No argument count for %s; add it to EXTRA_ARGS" % name)
+
+
+def write_table(path, table, args, source, overrides={}):
+    with open(path, "w") as out:
+        out.write("// Generated by scripts/gen-syscall-tables.py from %s.\n" % source)
+        out.write("// Do not edit.\n\n")
+        out.write("pub(super) const SYSCALLS: &[(u64, &str, u8)] = &[\n")
+        for number in sorted(table):
+            name = table[number]
+            out.write('    (%d, "%s", %d),\n' % (number, name, resolve_args(name, args, overrides)))
+        out.write("];\n")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--include", default="/usr/include")
+    options = parser.parse_args()
+    include = options.include
+    asm = os.path.join(include, "x86_64-linux-gnu/asm")
+    if not os.path.isdir(asm):
+        asm = os.path.join(include, "asm")
+    with open(os.path.join(include, "linux/version.h")) as f:
+        match = re.search(r"LINUX_VERSION_MAJOR (\d+)\s+#define LINUX_VERSION_PATCHLEVEL (\d+)", f.read())
+    source = "the Linux %s.%s uapi headers" % match.groups()
+    args = trace_args(os.path.join(tracefs(), "events/syscalls"))
+    out = os.path.join(os.path.dirname(os.path.abspath(__file__)), "../src/syscall_table")
+    for arch, header in [("x86_64", "unistd_64.h"), ("i386", "unistd_32.h"), ("x32", "unistd_x32.h")]:
+        with open(os.path.join(asm, header)) as f:
+            table = parse_defines(f.read())
+        overrides = I386_ARGS if arch == "i386" else {}
+        write_table(os.path.join(out, arch + ".rs"), table, args, source, overrides)
+    write_table(os.path.join(out, "aarch64.rs"), generic_table(include), args, source)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,189 @@
+//! Telling which of the x86 ABIs a tracee runs under.
+
+use crate::{nix_error, SyscallTable};
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+use std::fs::File;
+use std::io::{self, Read};
+
+const EM_386: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+/// The code segment selector of 64-bit code, including x32.
+const USER_CS: u64 = 0x33;
+/// The code segment selector of 32-bit compatibility mode code.
+const USER32_CS: u64 = 0x23;
+
+/// System call numbers with this bit set use the x32 ABI.
+const X32_SYSCALL_BIT: u64 = 0x4000_0000;
+
+/// The ABI a tracee runs under, from [`Tracee::abi`].
+///
+/// A 64-bit tracer always sees a tracee's registers in the 64-bit
+/// `user_regs_struct` layout, but what they hold depends on the ABI: 32-bit
+/// tracees pass system call arguments in other registers, and pointers in
+/// x32 and 32-bit tracees are 4 bytes long. System call numbers differ
+/// between all three, so look them up in the table for the right one.
+///
+/// [`Tracee::abi`]: struct.Tracee.html#method.abi
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Abi {
+    /// The native 64-bit ABI.
+    X86_64,
+    /// The x32 ABI, which runs 64-bit code with 32-bit pointers.
+    X32,
+    /// The 32-bit i386 ABI, run in compatibility mode.
+    I386,
+}
+
+impl Abi {
+    /// The ABI of a program from the start of its ELF header, or `None` if
+    /// it isn't an x86 ELF image.
+    pub fn from_elf_header(header: &[u8]) -> Option<Abi> {
+        if header.len() < 20 || &header[..4] != b"\x7fELF" || header[5] != 1 {
+            return None;
+        }
+        let machine = u16::from_le_bytes([header[18], header[19]]);
+        match (header[4], machine) {
+            (2, EM_X86_64) => Some(Abi::X86_64),
+            (1, EM_X86_64) => Some(Abi::X32),
+            (1, EM_386) => Some(Abi::I386),
+            _ => None,
+        }
+    }
+
+    /// The size of a pointer, and of a `long`, in bytes.
+    pub fn word_size(self) -> usize {
+        match self {
+            Abi::X86_64 => 8,
+            Abi::X32 | Abi::I386 => 4,
+        }
+    }
+
+    /// The `AUDIT_ARCH_*` value the kernel reports for system calls made
+    /// under this ABI, as in `Notification::arch`.
+    ///
+    /// x32 system calls are reported as x86-64 ones, with the x32 bit set in
+    /// the number.
+    pub fn audit_arch(self) -> u32 {
+        match self {
+            Abi::X86_64 | Abi::X32 => 0xc000_003e,
+            Abi::I386 => 0x4000_0003,
+        }
+    }
+
+    /// The table of this ABI's system calls, for looking up the numbers
+    /// from [`syscall_number`](#method.syscall_number).
+    pub fn syscall_table(self) -> SyscallTable {
+        match self {
+            Abi::X86_64 => SyscallTable::X86_64,
+            Abi::X32 => SyscallTable::X32,
+            Abi::I386 => SyscallTable::I386,
+        }
+    }
+
+    /// The number of the system call a tracee with these registers is
+    /// making, in this ABI's table. This is `orig_rax`, without the x32 bit.
+    pub fn syscall_number(self, regs: &libc::user_regs_struct) -> u64 {
+        match self {
+            Abi::X32 => regs.orig_rax & !X32_SYSCALL_BIT,
+            Abi::X86_64 | Abi::I386 => regs.orig_rax,
+        }
+    }
+
+    /// The arguments of the system call a tracee with these registers is
+    /// making, from the registers this ABI passes them in.
+    ///
+    /// Arguments of 32-bit system calls are truncated to 32 bits.
+    pub fn syscall_args(self, regs: &libc::user_regs_struct) -> [u64; 6] {
+        match self {
+            Abi::X86_64 | Abi::X32 => [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9],
+            Abi::I386 => [regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp]
+                .map(|arg| arg & 0xffff_ffff),
+        }
+    }
+}
+
+/// Find the ABI of the stopped tracee `pid`.
+///
+/// The code segment tells 32-bit code apart, and the executable's ELF header
+/// tells x32 from x86-64, since both run 64-bit code.
+pub(crate) fn abi(pid: Pid) -> io::Result<Abi> {
+    let regs = ptrace::getregs(pid).map_err(nix_error)?;
+    match regs.cs {
+        USER32_CS => return Ok(Abi::I386),
+        USER_CS => {}
+        cs => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown code segment {:#x}", cs),
+            ))
+        }
+    }
+    let mut header = [0; 20];
+    File::open(format!("/proc/{}/exe", pid))?.read_exact(&mut header)?;
+    match Abi::from_elf_header(&header) {
+        Some(Abi::X32) => Ok(Abi::X32),
+        // x32 processes can still make 64-bit system calls, and the
+        // reverse, but the executable decides the rest of the layout.
+        _ => Ok(Abi::X86_64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::mem;
+    use std::process::Command;
+
+    fn header(class: u8, machine: u16) -> [u8; 20] {
+        let mut header = [0; 20];
+        header[..4].copy_from_slice(b"\x7fELF");
+        header[4] = class;
+        header[5] = 1;
+        header[18..].copy_from_slice(&machine.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn test_from_elf_header() {
+        assert_eq!(
+            Abi::from_elf_header(&header(2, EM_X86_64)),
+            Some(Abi::X86_64)
+        );
+        assert_eq!(Abi::from_elf_header(&header(1, EM_X86_64)), Some(Abi::X32));
+        assert_eq!(Abi::from_elf_header(&header(1, EM_386)), Some(Abi::I386));
+        // AArch64.
+        assert_eq!(Abi::from_elf_header(&header(2, 183)), None);
+        assert_eq!(Abi::from_elf_header(b"#!/bin/sh\n"), None);
+    }
+
+    #[test]
+    fn test_syscall_args() {
+        let mut regs: libc::user_regs_struct = unsafe { mem::zeroed() };
+        regs.orig_rax = X32_SYSCALL_BIT | 1;
+        regs.rdi = 1;
+        regs.rbx = 0x1_0000_0002;
+        assert_eq!(Abi::X32.syscall_number(&regs), 1);
+        assert_eq!(Abi::X86_64.syscall_args(&regs)[0], 1);
+        assert_eq!(Abi::I386.syscall_args(&regs)[0], 2);
+        assert_eq!(Abi::I386.syscall_args(&regs)[4], 1);
+        assert_eq!(Abi::X32.syscall_table().name(1), Some("write"));
+    }
+
+    #[test]
+    fn test_abi() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let abi = tracee.abi().expect("Error finding ABI");
+        assert_eq!(abi, Abi::X86_64);
+        assert_eq!(abi.word_size(), mem::size_of::<usize>());
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
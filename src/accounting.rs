@@ -0,0 +1,338 @@
+use crate::{Event, SyscallInfo, TraceSession, Tracee};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Which way data moved in an [`IoEvent`].
+///
+/// [`IoEvent`]: struct.IoEvent.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IoDirection {
+    /// Data was read from the file descriptor.
+    Read,
+    /// Data was written to the file descriptor.
+    Write,
+}
+
+/// A single completed transfer recorded by [`IoAccounting`].
+///
+/// [`IoAccounting`]: struct.IoAccounting.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IoEvent {
+    /// When the transfer completed, relative to when accounting started.
+    pub time: Duration,
+    /// The process whose file descriptor table `fd` belongs to.
+    pub pid: Pid,
+    /// The thread that made the system call.
+    pub tid: Pid,
+    /// The file descriptor.
+    pub fd: i32,
+    /// Whether data was read or written.
+    pub direction: IoDirection,
+    /// The number of bytes transferred.
+    pub bytes: u64,
+}
+
+/// Totals for one file descriptor, from when it was first used until it was
+/// closed.
+///
+/// A descriptor number that is closed and reused gets a separate entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FdStats {
+    /// The process whose file descriptor table `fd` belongs to.
+    pub pid: Pid,
+    /// The file descriptor.
+    pub fd: i32,
+    /// What the descriptor referred to, as reported by `/proc/<pid>/fd`.
+    ///
+    /// Sockets, pipes and other non-file descriptors look like
+    /// `socket:[1234]`.
+    pub target: Option<PathBuf>,
+    /// When the descriptor was first seen, relative to when accounting started.
+    pub first_seen: Duration,
+    /// When the descriptor was closed, if it was.
+    pub closed: Option<Duration>,
+    /// The total number of bytes read.
+    pub bytes_read: u64,
+    /// The total number of bytes written.
+    pub bytes_written: u64,
+    /// The number of successful reads.
+    pub reads: usize,
+    /// The number of successful writes.
+    pub writes: usize,
+}
+
+/// What an I/O system call in progress will do once it returns.
+#[derive(Clone, Copy, Debug)]
+enum Pending {
+    Transfer(i32, IoDirection),
+    /// Data moves from the first descriptor to the second, as in `sendfile`.
+    Copy(i32, i32),
+    Close(i32),
+}
+
+/// Accounts for the bytes tracees read and write through each file descriptor.
+///
+/// Feed every syscall stop to [`record`], or let [`run`] drive a whole
+/// [`TraceSession`]. Successful `read`, `write` and related system calls,
+/// including vectored, positional, socket and `sendfile`-style copies, are
+/// attributed to the file descriptor they used. [`fds`] gives per-descriptor
+/// totals and [`timeline`] lists every individual transfer in order.
+///
+/// Descriptors are tracked per process, so threads sharing a descriptor
+/// table are accounted together.
+///
+/// [`record`]: #method.record
+/// [`run`]: #method.run
+/// [`fds`]: #method.fds
+/// [`timeline`]: #method.timeline
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug)]
+pub struct IoAccounting {
+    start: Instant,
+    in_flight: HashMap<Pid, Pending>,
+    tgids: HashMap<Pid, Pid>,
+    open: HashMap<(Pid, i32), usize>,
+    fds: Vec<FdStats>,
+    timeline: Vec<IoEvent>,
+}
+
+impl Default for IoAccounting {
+    fn default() -> IoAccounting {
+        IoAccounting {
+            start: Instant::now(),
+            in_flight: HashMap::new(),
+            tgids: HashMap::new(),
+            open: HashMap::new(),
+            fds: vec![],
+            timeline: vec![],
+        }
+    }
+}
+
+impl IoAccounting {
+    /// Create an empty accounting, with times measured from now.
+    pub fn new() -> IoAccounting {
+        IoAccounting::default()
+    }
+
+    /// Record a syscall stop of `tracee`.
+    ///
+    /// Call this each time the tracee reports `Event::Syscall`.
+    pub fn record(&mut self, tracee: &Tracee) -> io::Result<()> {
+        let tid = tracee.pid();
+        match tracee.syscall_info()? {
+            SyscallInfo::Entry { number, args } => match pending(number as i64, &args) {
+                Some(pending) => {
+                    self.in_flight.insert(tid, pending);
+                }
+                None => {
+                    self.in_flight.remove(&tid);
+                }
+            },
+            SyscallInfo::Exit { value, is_error } => {
+                let pending = match self.in_flight.remove(&tid) {
+                    Some(pending) if !is_error => pending,
+                    _ => return Ok(()),
+                };
+                let pid = self.tgid(tid);
+                match pending {
+                    Pending::Transfer(fd, direction) => {
+                        self.transfer(pid, tid, fd, direction, value as u64)
+                    }
+                    Pending::Copy(from, to) => {
+                        self.transfer(pid, tid, from, IoDirection::Read, value as u64);
+                        self.transfer(pid, tid, to, IoDirection::Write, value as u64);
+                    }
+                    Pending::Close(fd) => self.close(pid, fd),
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Forget any syscall in progress for `pid`, for example because it exited.
+    pub fn forget(&mut self, pid: Pid) {
+        self.in_flight.remove(&pid);
+        self.tgids.remove(&pid);
+    }
+
+    /// Mark every descriptor of process `pid` as closed, for example because
+    /// it has called `exec` or exited.
+    ///
+    /// Later use of the same descriptor numbers starts new entries.
+    pub fn close_all(&mut self, pid: Pid) {
+        let fds: Vec<i32> = self
+            .open
+            .keys()
+            .filter(|(p, _)| *p == pid)
+            .map(|&(_, fd)| fd)
+            .collect();
+        for fd in fds {
+            self.close(pid, fd);
+        }
+    }
+
+    /// Account for the I/O of the tracees in `session` with
+    /// [`TraceSession::run`] until they have all exited.
+    ///
+    /// The session should trace system calls, with [`trace_syscalls`].
+    ///
+    /// [`TraceSession::run`]: struct.TraceSession.html#method.run
+    /// [`trace_syscalls`]: struct.TraceSession.html#method.trace_syscalls
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        session.run(|session, pid, event| {
+            match event {
+                Event::Syscall => {
+                    if let Some(tracee) = session.get(pid) {
+                        self.record(tracee)?;
+                    }
+                }
+                Event::Exec(_) => {
+                    // Descriptors may have been closed on exec.
+                    let tgid = self.tgid(pid);
+                    self.close_all(tgid);
+                }
+                Event::Exited(_) | Event::Signaled(..) => {
+                    if self.tgid(pid) == pid {
+                        self.close_all(pid);
+                    }
+                    self.forget(pid);
+                }
+                _ => {}
+            }
+            Ok(())
+        })
+    }
+
+    /// Per-descriptor totals, ordered by descending bytes transferred.
+    pub fn fds(&self) -> Vec<FdStats> {
+        let mut fds = self.fds.clone();
+        fds.sort_by(|a, b| {
+            (b.bytes_read + b.bytes_written)
+                .cmp(&(a.bytes_read + a.bytes_written))
+                .then(a.first_seen.cmp(&b.first_seen))
+        });
+        fds
+    }
+
+    /// Every transfer recorded so far, in the order they completed.
+    pub fn timeline(&self) -> &[IoEvent] {
+        &self.timeline
+    }
+
+    /// The total number of bytes read through all descriptors.
+    pub fn total_read(&self) -> u64 {
+        self.fds.iter().map(|s| s.bytes_read).sum()
+    }
+
+    /// The total number of bytes written through all descriptors.
+    pub fn total_written(&self) -> u64 {
+        self.fds.iter().map(|s| s.bytes_written).sum()
+    }
+
+    fn transfer(&mut self, pid: Pid, tid: Pid, fd: i32, direction: IoDirection, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let time = self.start.elapsed();
+        let index = match self.open.get(&(pid, fd)) {
+            Some(&index) => index,
+            None => {
+                self.fds.push(FdStats {
+                    pid,
+                    fd,
+                    target: fs::read_link(format!("/proc/{}/fd/{}", tid, fd)).ok(),
+                    first_seen: time,
+                    closed: None,
+                    bytes_read: 0,
+                    bytes_written: 0,
+                    reads: 0,
+                    writes: 0,
+                });
+                self.open.insert((pid, fd), self.fds.len() - 1);
+                self.fds.len() - 1
+            }
+        };
+        let stats = &mut self.fds[index];
+        match direction {
+            IoDirection::Read => {
+                stats.bytes_read += bytes;
+                stats.reads += 1;
+            }
+            IoDirection::Write => {
+                stats.bytes_written += bytes;
+                stats.writes += 1;
+            }
+        }
+        self.timeline.push(IoEvent {
+            time,
+            pid,
+            tid,
+            fd,
+            direction,
+            bytes,
+        });
+    }
+
+    fn close(&mut self, pid: Pid, fd: i32) {
+        if let Some(index) = self.open.remove(&(pid, fd)) {
+            self.fds[index].closed = Some(self.start.elapsed());
+        }
+    }
+
+    /// The process that thread `tid` belongs to.
+    fn tgid(&mut self, tid: Pid) -> Pid {
+        *self
+            .tgids
+            .entry(tid)
+            .or_insert_with(|| read_tgid(tid).unwrap_or(tid))
+    }
+}
+
+/// Decide what the system call `number` with `args` will do to which
+/// descriptors, if it is one that is accounted for.
+fn pending(number: i64, args: &[u64; 6]) -> Option<Pending> {
+    let fd = args[0] as i32;
+    let pending = match number {
+        libc::SYS_read
+        | libc::SYS_pread64
+        | libc::SYS_readv
+        | libc::SYS_preadv
+        | libc::SYS_preadv2
+        | libc::SYS_recvfrom
+        | libc::SYS_recvmsg => Pending::Transfer(fd, IoDirection::Read),
+        libc::SYS_write
+        | libc::SYS_pwrite64
+        | libc::SYS_writev
+        | libc::SYS_pwritev
+        | libc::SYS_pwritev2
+        | libc::SYS_sendto
+        | libc::SYS_sendmsg => Pending::Transfer(fd, IoDirection::Write),
+        // sendfile(out_fd, in_fd, ...)
+        libc::SYS_sendfile => Pending::Copy(args[1] as i32, fd),
+        // splice(fd_in, off_in, fd_out, ...) and copy_file_range likewise.
+        libc::SYS_splice | libc::SYS_copy_file_range => Pending::Copy(fd, args[2] as i32),
+        libc::SYS_close => Pending::Close(fd),
+        // dup2 and dup3 silently close their target descriptor.
+        libc::SYS_dup3 => Pending::Close(args[1] as i32),
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_dup2 => Pending::Close(args[1] as i32),
+        _ => return None,
+    };
+    Some(pending)
+}
+
+/// Read the thread group id of `tid` from `/proc`.
+pub(crate) fn read_tgid(tid: Pid) -> Option<Pid> {
+    let status = fs::read_to_string(format!("/proc/{}/status", tid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))
+        .and_then(|tgid| tgid.trim().parse().ok())
+        .map(Pid::from_raw)
+}
@@ -0,0 +1,482 @@
+//! A model of each tracee process's memory mappings.
+
+use crate::accounting::read_tgid;
+use crate::maps::{self, MemoryMap};
+use crate::{Event, SyscallInfo, TraceSession, Tracee};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+
+/// `MREMAP_DONTUNMAP`, which leaves the old mapping in place.
+const MREMAP_DONTUNMAP: u64 = 4;
+
+/// What a system call in progress will do to the address space once it
+/// returns.
+#[derive(Clone, Copy, Debug)]
+enum Pending {
+    /// `mmap(addr, len, prot, flags, fd, offset)`, which returns where it
+    /// mapped.
+    Map {
+        len: u64,
+        prot: u64,
+        flags: u64,
+        fd: i32,
+        offset: u64,
+    },
+    Unmap(u64, u64),
+    /// `mremap(old, old_len, new_len, flags, ...)`, which returns where the
+    /// mapping moved.
+    Remap(u64, u64, u64, u64),
+    Protect(u64, u64, u64),
+    Brk,
+}
+
+/// The address space of one process.
+#[derive(Clone, Debug, Default)]
+struct Space {
+    /// Sorted, and never overlapping.
+    maps: Vec<MemoryMap>,
+    /// The program break, once a `brk` has been seen.
+    brk: Option<u64>,
+}
+
+/// Keeps the memory mappings of each tracee process up to date from the
+/// system calls that change them, so that they don't have to be read from
+/// `/proc/<pid>/maps` again at every stop.
+///
+/// Feed every stop to [`record`], or let [`run`] drive a whole
+/// [`TraceSession`]. Each process's mappings are read from `/proc` the
+/// first time it is seen and after each `exec`, and then follow its
+/// `mmap`, `munmap`, `mremap`, `mprotect`, `pkey_mprotect` and `brk` calls.
+/// Children of `fork` start with a copy of their parent's mappings, and
+/// threads share those of their process, so every thread that maps memory
+/// must be traced.
+///
+/// The mappings can drift from what `/proc` shows: the kernel merges
+/// adjacent mappings that are alike, and grows the main thread's stack on
+/// its own. System V shared memory and the other ways of mapping memory,
+/// such as `io_uring` rings, aren't followed. [`reload`] reads the mappings
+/// again.
+///
+/// [`record`]: #method.record
+/// [`run`]: #method.run
+/// [`reload`]: #method.reload
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug, Default)]
+pub struct AddressSpace {
+    in_flight: HashMap<Pid, Pending>,
+    tgids: HashMap<Pid, Pid>,
+    spaces: HashMap<Pid, Space>,
+}
+
+impl AddressSpace {
+    /// Create a model that knows no processes.
+    pub fn new() -> AddressSpace {
+        AddressSpace::default()
+    }
+
+    /// Update the mappings for `event`, reported by `tracee`.
+    ///
+    /// Call this for every stop, before resuming the tracee.
+    pub fn record(&mut self, tracee: &Tracee, event: Event) -> io::Result<()> {
+        let tid = tracee.pid();
+        match event {
+            Event::Syscall => self.record_syscall(tracee)?,
+            Event::Fork(child) | Event::Vfork(child) | Event::Clone(child) => {
+                let pid = self.tgid(tid);
+                let child_pid = self.tgid(child);
+                if child_pid != pid {
+                    let space = self.space(pid).clone();
+                    self.spaces.insert(child_pid, space);
+                }
+            }
+            Event::Exec(former) => {
+                self.forget(former);
+                self.in_flight.remove(&tid);
+                let pid = self.tgid(tid);
+                self.reload(pid)?;
+            }
+            Event::Exited(_) | Event::Signaled(..) => {
+                if self.tgid(tid) == tid {
+                    self.spaces.remove(&tid);
+                }
+                self.forget(tid);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Forget any system call in progress for thread `pid`, for example
+    /// because it exited.
+    pub fn forget(&mut self, pid: Pid) {
+        self.in_flight.remove(&pid);
+        self.tgids.remove(&pid);
+    }
+
+    /// Read the mappings of process `pid` from `/proc` again.
+    pub fn reload(&mut self, pid: Pid) -> io::Result<()> {
+        let maps = maps::read_maps(pid)?;
+        self.spaces.insert(pid, Space { maps, brk: None });
+        Ok(())
+    }
+
+    /// Follow the mappings of the tracees in `session` with
+    /// [`TraceSession::run`] until they have all exited.
+    ///
+    /// The session should trace system calls, with [`trace_syscalls`].
+    ///
+    /// [`TraceSession::run`]: struct.TraceSession.html#method.run
+    /// [`trace_syscalls`]: struct.TraceSession.html#method.trace_syscalls
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        session.run(|session, pid, event| match session.get(pid) {
+            Some(tracee) => self.record(tracee, event),
+            None => {
+                if self.tgids.get(&pid) == Some(&pid) {
+                    self.spaces.remove(&pid);
+                }
+                self.forget(pid);
+                Ok(())
+            }
+        })
+    }
+
+    /// The mappings of process `pid`, in address order, if it has been seen.
+    pub fn maps(&self, pid: Pid) -> Option<&[MemoryMap]> {
+        self.spaces.get(&pid).map(|space| space.maps.as_slice())
+    }
+
+    /// The mapping of process `pid` that contains `addr`, if any.
+    pub fn find(&self, pid: Pid, addr: u64) -> Option<&MemoryMap> {
+        let maps = self.maps(pid)?;
+        let i = maps.partition_point(|map| map.end <= addr);
+        maps.get(i).filter(|map| map.contains(addr))
+    }
+
+    fn record_syscall(&mut self, tracee: &Tracee) -> io::Result<()> {
+        let tid = tracee.pid();
+        let pid = self.tgid(tid);
+        // Read the mappings before the call changes them.
+        self.space(pid);
+        match tracee.syscall_info()? {
+            SyscallInfo::Entry { number, args } => match pending(number as i64, &args) {
+                Some(pending) => {
+                    self.in_flight.insert(tid, pending);
+                }
+                None => {
+                    self.in_flight.remove(&tid);
+                }
+            },
+            SyscallInfo::Exit { value, is_error } => {
+                let pending = match self.in_flight.remove(&tid) {
+                    Some(pending) if !is_error => pending,
+                    _ => return Ok(()),
+                };
+                let value = value as u64;
+                match pending {
+                    Pending::Map {
+                        len,
+                        prot,
+                        flags,
+                        fd,
+                        offset,
+                    } => {
+                        let anonymous = flags & libc::MAP_ANONYMOUS as u64 != 0;
+                        let (inode, pathname) = if anonymous {
+                            (0, None)
+                        } else {
+                            mapped_file(tid, fd)
+                        };
+                        let map = MemoryMap {
+                            start: value,
+                            end: value + page_align(len),
+                            readable: prot & libc::PROT_READ as u64 != 0,
+                            writable: prot & libc::PROT_WRITE as u64 != 0,
+                            executable: prot & libc::PROT_EXEC as u64 != 0,
+                            shared: flags & libc::MAP_SHARED as u64 != 0,
+                            offset: if anonymous { 0 } else { offset },
+                            inode,
+                            pathname,
+                        };
+                        self.space(pid).map(map);
+                    }
+                    Pending::Unmap(addr, len) => {
+                        self.space(pid).unmap(addr..addr + page_align(len));
+                    }
+                    Pending::Remap(old, old_len, new_len, flags) => {
+                        self.space(pid).remap(old, old_len, value, new_len, flags);
+                    }
+                    Pending::Protect(addr, len, prot) => {
+                        self.space(pid).protect(addr..addr + page_align(len), prot);
+                    }
+                    Pending::Brk => self.space(pid).brk(value),
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The address space of process `pid`, read from `/proc` if it hasn't
+    /// been seen.
+    fn space(&mut self, pid: Pid) -> &mut Space {
+        self.spaces.entry(pid).or_insert_with(|| Space {
+            maps: maps::read_maps(pid).unwrap_or_default(),
+            brk: None,
+        })
+    }
+
+    /// The process that thread `tid` belongs to.
+    fn tgid(&mut self, tid: Pid) -> Pid {
+        *self
+            .tgids
+            .entry(tid)
+            .or_insert_with(|| read_tgid(tid).unwrap_or(tid))
+    }
+}
+
+impl Space {
+    /// Add `map`, replacing whatever it overlaps.
+    fn map(&mut self, map: MemoryMap) {
+        self.unmap(map.start..map.end);
+        let i = self.maps.partition_point(|m| m.end <= map.start);
+        self.maps.insert(i, map);
+    }
+
+    /// Remove `range`, cutting the mappings at its edges.
+    fn unmap(&mut self, range: Range<u64>) {
+        self.split(range.start);
+        self.split(range.end);
+        self.maps
+            .retain(|map| map.end <= range.start || map.start >= range.end);
+    }
+
+    /// Move the `old_len` bytes at `old` to `new_len` bytes at `new`, or
+    /// resize them in place.
+    fn remap(&mut self, old: u64, old_len: u64, new: u64, new_len: u64, flags: u64) {
+        let template = match self.maps.iter().find(|map| map.contains(old)) {
+            Some(map) => map.clone(),
+            None => return,
+        };
+        if flags & MREMAP_DONTUNMAP == 0 {
+            self.unmap(old..old + page_align(old_len));
+        }
+        let offset = match template.inode {
+            0 => template.offset,
+            _ => template.offset + (old - template.start),
+        };
+        self.map(MemoryMap {
+            start: new,
+            end: new + page_align(new_len),
+            offset,
+            ..template
+        });
+    }
+
+    /// Change the protection of `range` to `prot`.
+    fn protect(&mut self, range: Range<u64>, prot: u64) {
+        self.split(range.start);
+        self.split(range.end);
+        for map in &mut self.maps {
+            if map.start >= range.start && map.end <= range.end {
+                map.readable = prot & libc::PROT_READ as u64 != 0;
+                map.writable = prot & libc::PROT_WRITE as u64 != 0;
+                map.executable = prot & libc::PROT_EXEC as u64 != 0;
+            }
+        }
+    }
+
+    /// Move the program break to `brk`, growing or shrinking the heap.
+    fn brk(&mut self, brk: u64) {
+        let end = page_align(brk);
+        let heap = self
+            .maps
+            .iter()
+            .position(|map| map.pathname.as_deref() == Some("[heap]"));
+        match (heap, self.brk) {
+            (Some(i), _) if end <= self.maps[i].start => {
+                self.maps.remove(i);
+            }
+            (Some(i), _) => self.maps[i].end = end,
+            // The first break seen is where the heap starts.
+            (None, Some(start)) if end > page_align(start) => self.map(MemoryMap {
+                start: page_align(start),
+                end,
+                readable: true,
+                writable: true,
+                executable: false,
+                shared: false,
+                offset: 0,
+                inode: 0,
+                pathname: Some("[heap]".to_owned()),
+            }),
+            _ => {}
+        }
+        if heap.is_some() || self.brk.is_none() {
+            self.brk = Some(brk);
+        }
+    }
+
+    /// Cut the mapping containing `addr` in two there, if it doesn't start
+    /// there.
+    fn split(&mut self, addr: u64) {
+        let i = self.maps.partition_point(|map| map.end <= addr);
+        let map = match self.maps.get_mut(i) {
+            Some(map) if map.start < addr => map,
+            _ => return,
+        };
+        let mut upper = map.clone();
+        map.end = addr;
+        if upper.inode != 0 {
+            upper.offset += addr - upper.start;
+        }
+        upper.start = addr;
+        self.maps.insert(i + 1, upper);
+    }
+}
+
+/// Decide what the system call `number` with `args` will do to the address
+/// space, if it changes it.
+fn pending(number: i64, args: &[u64; 6]) -> Option<Pending> {
+    let pending = match number {
+        libc::SYS_mmap => Pending::Map {
+            len: args[1],
+            prot: args[2],
+            flags: args[3],
+            fd: args[4] as i32,
+            offset: args[5],
+        },
+        libc::SYS_munmap => Pending::Unmap(args[0], args[1]),
+        libc::SYS_mremap => Pending::Remap(args[0], args[1], args[2], args[3]),
+        libc::SYS_mprotect | libc::SYS_pkey_mprotect => Pending::Protect(args[0], args[1], args[2]),
+        libc::SYS_brk => Pending::Brk,
+        _ => return None,
+    };
+    Some(pending)
+}
+
+/// The inode and path of the file open as `fd` in thread `tid`.
+fn mapped_file(tid: Pid, fd: i32) -> (u64, Option<String>) {
+    let path = format!("/proc/{}/fd/{}", tid, fd);
+    let inode = fs::metadata(&path).map_or(0, |metadata| metadata.ino());
+    let target = fs::read_link(&path)
+        .ok()
+        .map(|target| target.to_string_lossy().into_owned());
+    (inode, target)
+}
+
+/// Round `len` up to a whole number of pages.
+fn page_align(len: u64) -> u64 {
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    (len + page - 1) & !(page - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use nix::sys::ptrace;
+    use std::process::Command;
+
+    /// Merge adjacent mappings that are alike, as the kernel does, leaving
+    /// out offsets and inodes, and the stack, which grows on its own.
+    fn normalize(maps: &[MemoryMap]) -> Vec<(Range<u64>, String, Option<String>)> {
+        let mut merged: Vec<(Range<u64>, String, Option<String>)> = vec![];
+        for map in maps {
+            if map.pathname.as_deref() == Some("[stack]") {
+                continue;
+            }
+            let perms = format!(
+                "{}{}{}{}",
+                map.readable as u8, map.writable as u8, map.executable as u8, map.shared as u8
+            );
+            match merged.last_mut() {
+                Some((range, p, name))
+                    if range.end == map.start && *p == perms && *name == map.pathname =>
+                {
+                    range.end = map.end;
+                }
+                _ => merged.push((map.start..map.end, perms, map.pathname.clone())),
+            }
+        }
+        merged
+    }
+
+    #[test]
+    fn test_space() {
+        let map = |start, end, offset| MemoryMap {
+            start,
+            end,
+            readable: true,
+            writable: false,
+            executable: false,
+            shared: false,
+            offset,
+            inode: 7,
+            pathname: Some("/lib".to_owned()),
+        };
+        let mut space = Space::default();
+        space.map(map(0x1000, 0x5000, 0));
+        space.protect(
+            0x2000..0x3000,
+            libc::PROT_READ as u64 | libc::PROT_WRITE as u64,
+        );
+        assert_eq!(space.maps.len(), 3);
+        assert!(space.maps[1].writable && space.maps[1].offset == 0x1000);
+        assert_eq!(space.maps[2].offset, 0x2000);
+        space.unmap(0x2800..0x4000);
+        let ranges: Vec<_> = space.maps.iter().map(|m| m.start..m.end).collect();
+        assert_eq!(ranges, [0x1000..0x2000, 0x2000..0x2800, 0x4000..0x5000]);
+        space.remap(0x4000, 0x1000, 0x9000, 0x2000, 0);
+        assert_eq!(
+            space.maps.last().unwrap().start..space.maps.last().unwrap().end,
+            0x9000..0xb000
+        );
+        assert_eq!(space.maps.last().unwrap().offset, 0x3000);
+        assert_eq!(space.maps.len(), 3);
+    }
+
+    #[test]
+    fn test_address_space() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        let pid = session
+            .spawn(Command::new(&path).arg("alloc"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut space = AddressSpace::new();
+        let mut checked = false;
+        ptrace::syscall(pid, None).unwrap();
+        loop {
+            let (_, event) = session.wait_for(pid).unwrap();
+            let signal = match event {
+                Event::Exited(code) => {
+                    assert_eq!(code, 0);
+                    break;
+                }
+                Event::Signal(signal) => Some(signal),
+                _ => None,
+            };
+            let tracee = session.get(pid).unwrap();
+            space.record(tracee, event).unwrap();
+            if let Ok(SyscallInfo::Entry { number, .. }) = tracee.syscall_info() {
+                if number as i64 == libc::SYS_exit_group {
+                    let actual = maps::read_maps(pid).unwrap();
+                    assert_eq!(normalize(space.maps(pid).unwrap()), normalize(&actual));
+                    let here = actual.iter().find(|map| map.executable).unwrap();
+                    assert_eq!(
+                        space.find(pid, here.start).map(|m| m.executable),
+                        Some(true)
+                    );
+                    checked = true;
+                }
+            }
+            ptrace::syscall(pid, signal).unwrap();
+        }
+        assert!(checked);
+    }
+}
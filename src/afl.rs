@@ -0,0 +1,127 @@
+//! The shared memory AFL fuzzers collect coverage in.
+
+use std::env;
+use std::io;
+use std::ptr;
+use std::slice;
+
+/// The environment variable AFL passes the shared memory's id in.
+pub const AFL_SHM_ENV: &str = "__AFL_SHM_ID";
+
+/// The System V shared memory segment an AFL or libAFL fuzzer reads a run's
+/// edge coverage from, attached to the tracer.
+///
+/// Pass it to [`Coverage::afl_map`] to have the edges the tracee takes
+/// counted in it, so that a tracer run as the fuzzer's target can stand in
+/// for a target built with AFL's instrumentation. Run the fuzzer with
+/// `AFL_NO_FORKSRV=1`, since the tracer doesn't speak the fork server
+/// protocol, and make the tracer end the way the tracee did, for example
+/// by raising the signal that killed it, since that is how the fuzzer
+/// recognizes crashes.
+///
+/// The segment is detached when this is dropped.
+///
+/// [`Coverage::afl_map`]: struct.Coverage.html#method.afl_map
+#[derive(Debug)]
+pub struct AflMap {
+    id: i32,
+    addr: *mut u8,
+    len: usize,
+}
+
+impl AflMap {
+    /// Attach the segment whose id is in the `__AFL_SHM_ID` environment
+    /// variable, or return `None` if it isn't set, because the tracer isn't
+    /// being run by a fuzzer.
+    pub fn from_env() -> io::Result<Option<AflMap>> {
+        let id = match env::var(AFL_SHM_ENV) {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+        let id = id.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a shared memory id", AFL_SHM_ENV),
+            )
+        })?;
+        AflMap::attach(id).map(Some)
+    }
+
+    /// Attach the segment with id `id`, which is as large as the fuzzer's
+    /// map, 64 KiB by default.
+    pub fn attach(id: i32) -> io::Result<AflMap> {
+        let mut info: libc::shmid_ds = unsafe { std::mem::zeroed() };
+        if unsafe { libc::shmctl(id, libc::IPC_STAT, &mut info) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let addr = unsafe { libc::shmat(id, ptr::null(), 0) };
+        if addr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(AflMap {
+            id,
+            addr: addr as *mut u8,
+            len: info.shm_segsz,
+        })
+    }
+
+    /// The segment's id.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// The edge counters.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.addr, self.len) }
+    }
+
+    /// The edge counters, for writing.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.addr, self.len) }
+    }
+}
+
+impl Drop for AflMap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::shmdt(self.addr as *const libc::c_void);
+        }
+    }
+}
+
+// The segment is only reached through `&self` and `&mut self`.
+unsafe impl Send for AflMap {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Create a private segment of `len` bytes, like a fuzzer does.
+    pub(crate) fn create_segment(len: usize) -> i32 {
+        let id = unsafe { libc::shmget(libc::IPC_PRIVATE, len, libc::IPC_CREAT | 0o600) };
+        assert!(id >= 0, "shmget failed: {}", io::Error::last_os_error());
+        id
+    }
+
+    pub(crate) fn remove_segment(id: i32) {
+        unsafe {
+            libc::shmctl(id, libc::IPC_RMID, ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_afl_map() {
+        let id = create_segment(1 << 16);
+        let mut map = AflMap::attach(id).expect("Error attaching segment");
+        assert_eq!(map.id(), id);
+        assert_eq!(map.as_slice().len(), 1 << 16);
+        map.as_mut_slice()[7] = 3;
+        // A second attachment sees the same memory, as the fuzzer would.
+        let other = AflMap::attach(id).unwrap();
+        assert_eq!(other.as_slice()[7], 3);
+        drop(map);
+        drop(other);
+        remove_segment(id);
+        assert!(AflMap::attach(id).is_err());
+    }
+}
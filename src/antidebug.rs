@@ -0,0 +1,154 @@
+//! Recognizing the system calls programs use to check for a debugger.
+
+use crate::accounting::read_tgid;
+use crate::memory;
+use nix::unistd::Pid;
+use std::io;
+
+/// A debugger check made by a tracee, reported as `Event::AntiDebug` once
+/// [`TraceSession::detect_anti_debug`] is set.
+///
+/// [`TraceSession::detect_anti_debug`]: struct.TraceSession.html#method.detect_anti_debug
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AntiDebugProbe {
+    /// The tracee called `ptrace(PTRACE_TRACEME)`, which fails if it is
+    /// already traced.
+    TraceMe,
+    /// The tracee tried to attach to the given process with `PTRACE_ATTACH`
+    /// or `PTRACE_SEIZE`, as programs do to their own parent or child to
+    /// keep debuggers out.
+    Attach(Pid),
+    /// The tracee opened its own `/proc` `status` or `stat` file, which
+    /// shows its tracer's pid and whether it is in a tracing stop.
+    ProcStatus,
+    /// The tracee sent itself `SIGTRAP`, to see whether a debugger takes it
+    /// before its handler runs.
+    SelfTrap,
+    /// The tracee called `prctl(PR_SET_DUMPABLE, 0)`, which stops
+    /// unprivileged debuggers from attaching to it.
+    NotDumpable,
+}
+
+/// The most bytes of a path that are read to compare it.
+const MAX_PATH: usize = 64;
+
+/// Classify the system call `number` with `args` that thread `tid` is
+/// entering, if it is a debugger check.
+pub(crate) fn classify(
+    tid: Pid,
+    number: u64,
+    args: &[u64; 6],
+) -> io::Result<Option<AntiDebugProbe>> {
+    let own_process = |pid: i32| pid == tid.as_raw() || Some(Pid::from_raw(pid)) == read_tgid(tid);
+    let probe = match number as i64 {
+        libc::SYS_ptrace => match args[0] as libc::c_uint {
+            libc::PTRACE_TRACEME => AntiDebugProbe::TraceMe,
+            libc::PTRACE_ATTACH | libc::PTRACE_SEIZE => {
+                AntiDebugProbe::Attach(Pid::from_raw(args[1] as i32))
+            }
+            _ => return Ok(None),
+        },
+        libc::SYS_openat if own_proc_file(tid, args[1], STATUS_FILES) => AntiDebugProbe::ProcStatus,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_open if own_proc_file(tid, args[0], STATUS_FILES) => AntiDebugProbe::ProcStatus,
+        libc::SYS_kill if args[1] as i32 == libc::SIGTRAP => {
+            let pid = args[0] as i32;
+            if pid != 0 && !own_process(pid) {
+                return Ok(None);
+            }
+            AntiDebugProbe::SelfTrap
+        }
+        libc::SYS_tkill if args[1] as i32 == libc::SIGTRAP && own_process(args[0] as i32) => {
+            AntiDebugProbe::SelfTrap
+        }
+        libc::SYS_tgkill if args[2] as i32 == libc::SIGTRAP && own_process(args[0] as i32) => {
+            AntiDebugProbe::SelfTrap
+        }
+        libc::SYS_prctl if args[0] as i32 == libc::PR_SET_DUMPABLE && args[1] == 0 => {
+            AntiDebugProbe::NotDumpable
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(probe))
+}
+
+/// The `/proc` files of a process that show whether it is traced.
+const STATUS_FILES: &[&str] = &["status", "stat"];
+
+/// Whether the path at `addr` in the memory of `tid` names one of the
+/// `/proc` files called `names` of its own process or one of its threads.
+pub(crate) fn own_proc_file(tid: Pid, addr: u64, names: &[&str]) -> bool {
+    // A path that can't be read will fail the system call anyway.
+    let path = match memory::read_cstring(tid, addr, MAX_PATH) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let path = String::from_utf8_lossy(&path);
+    let mut parts: Vec<&str> = match path.strip_prefix("/proc/") {
+        Some(rest) => rest.split('/').collect(),
+        None => return false,
+    };
+    if !parts.pop().is_some_and(|name| names.contains(&name)) {
+        return false;
+    }
+    let tgid = read_tgid(tid).unwrap_or(tid);
+    let own = |part: &str| match part {
+        "self" | "thread-self" => true,
+        part => part.parse().ok().map(Pid::from_raw) == Some(tgid),
+    };
+    match parts[..] {
+        [process] => own(process),
+        [process, "task", _] => own(process),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{Event, SpawnOptions, TraceSession};
+    use nix::sys::ptrace;
+    use nix::sys::signal::Signal;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_detect_anti_debug() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.detect_anti_debug();
+        let pid = session
+            .spawn(
+                Command::new(&path).arg("antidebug").stdout(Stdio::null()),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut probes = vec![];
+        let mut signal = None;
+        loop {
+            ptrace::syscall(pid, signal).expect("Error resuming tracee");
+            signal = None;
+            match session.wait_for(pid).expect("Error waiting for tracee") {
+                (_, Event::AntiDebug(probe)) => probes.push(probe),
+                // Swallow the trap, as a debugger would.
+                (_, Event::Signal(Signal::SIGTRAP)) => {}
+                (_, Event::Signal(sig)) => signal = Some(sig),
+                (_, Event::Exited(code)) => {
+                    assert_eq!(code, 0);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(
+            probes,
+            [
+                AntiDebugProbe::TraceMe,
+                AntiDebugProbe::ProcStatus,
+                AntiDebugProbe::ProcStatus,
+                AntiDebugProbe::SelfTrap,
+                AntiDebugProbe::NotDumpable,
+            ]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Tracing children that were spawned without ptrace.
+
+use crate::{nix_error, wait_error, Error, Tracee};
+use nix::errno::Errno;
+use nix::sys::ptrace::{self, Options};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+use std::io;
+use std::process::Child;
+
+/// A Unix-specific extension to `std::process::Child` to start tracing a
+/// child that was spawned normally, for example by a library that only
+/// hands out the `Child`.
+pub trait ChildAttachExt {
+    /// Attach to the child with `PTRACE_SEIZE` and stop it with
+    /// `PTRACE_INTERRUPT`, returning a [`Tracee`] that takes over the
+    /// `Child`.
+    ///
+    /// The returned tracee is stopped in `PTRACE_EVENT_STOP`, or for a
+    /// signal that arrived first, which its [`initial_status`] returns. Once
+    /// the child is traced, waiting for it also reports its stops, which
+    /// `Child::wait` can't make sense of, so it must be waited for and
+    /// reaped through the `Tracee`, as with a spawned one, until it is
+    /// detached and [`into_child`] gives the `Child` back.
+    ///
+    /// Only the thread group leader is traced. The child must be one this
+    /// process may trace, and not have exited yet; if it can't be attached,
+    /// the `Child` is returned with the error, still untraced and unreaped.
+    /// If it exits before stopping it has been reaped, so waiting for the
+    /// returned `Child` fails.
+    ///
+    /// [`Tracee`]: struct.Tracee.html
+    /// [`initial_status`]: struct.Tracee.html#method.initial_status
+    /// [`into_child`]: struct.Tracee.html#method.into_child
+    fn attach_tracee(self) -> Result<Tracee, (io::Error, Child)>;
+}
+
+impl ChildAttachExt for Child {
+    fn attach_tracee(self) -> Result<Tracee, (io::Error, Child)> {
+        let pid = Pid::from_raw(self.id() as i32);
+        if let Err(e) = ptrace::seize(pid, Options::empty()) {
+            let e: io::Error = match e {
+                nix::Error::Sys(Errno::EIO) => Error::Unsupported("PTRACE_SEIZE").into(),
+                e => nix_error(e),
+            };
+            return Err((e, self));
+        }
+        if unsafe { libc::ptrace(libc::PTRACE_INTERRUPT, pid.as_raw(), 0, 0) } < 0 {
+            let e = io::Error::last_os_error();
+            let _ = ptrace::detach(pid, None);
+            return Err((e, self));
+        }
+        match waitpid(pid, None) {
+            Ok(status @ WaitStatus::Exited(..)) | Ok(status @ WaitStatus::Signaled(..)) => Err((
+                io::Error::other(format!("Child exited before stopping: {:?}", status)),
+                self,
+            )),
+            Ok(status) => Ok(Tracee::new(self, status)),
+            Err(e) => Err((wait_error(e), self)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_attach_tracee() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let child = Command::new(&path)
+            .args(["sleep", "50"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Error spawning test process");
+        let pid = Pid::from_raw(child.id() as i32);
+        let tracee = child.attach_tracee().expect("Error attaching child");
+        assert_eq!(tracee.pid(), pid);
+        assert!(matches!(
+            tracee.initial_status(),
+            WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_STOP)
+        ));
+        // The tracee can be inspected, and reaps the child.
+        #[cfg(target_arch = "x86_64")]
+        tracee.registers().expect("Error reading registers");
+        let output = tracee.wait_with_output().expect("Error collecting output");
+        assert_eq!(output.status, WaitStatus::Exited(pid, 0));
+
+        // An exited child is handed back, still to be reaped.
+        let child = Command::new(&path).stdout(Stdio::null()).spawn().unwrap();
+        while std::fs::read_to_string(format!("/proc/{}/stat", child.id()))
+            .is_ok_and(|stat| !stat.contains(") Z "))
+        {
+            std::thread::yield_now();
+        }
+        let (_, mut child) = child.attach_tracee().unwrap_err();
+        assert!(child.wait().unwrap().success());
+    }
+}
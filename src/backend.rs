@@ -0,0 +1,87 @@
+//! The system calls a `TraceSession` waits for and resumes its tracees
+//! with, behind a trait so that they can be made by something other than
+//! nix.
+
+use nix::errno::Errno;
+use nix::sys::ptrace::{self, Options, Request};
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::fmt;
+
+/// The system calls a [`TraceSession`] makes to wait for its tracees and
+/// resume them, set with [`TraceSession::set_backend`].
+///
+/// [`NixBackend`], which makes them with nix, is the default. Another
+/// implementation, such as one built on rustix, replaces it for those
+/// calls; the session's public API stays the same either way. Errors are
+/// reported as nix errors, so an implementation converts its own with
+/// `nix::Error::Sys(Errno::from_i32(errno))`.
+///
+/// [`TraceSession`]: struct.TraceSession.html
+/// [`TraceSession::set_backend`]: struct.TraceSession.html#method.set_backend
+/// [`NixBackend`]: struct.NixBackend.html
+pub trait Backend {
+    /// Wait for a state change of `pid`, or of any child if it is `None`,
+    /// as `waitpid` does with `flags`.
+    fn waitpid(&mut self, pid: Option<Pid>, flags: WaitPidFlag) -> nix::Result<WaitStatus>;
+
+    /// Resume the stopped tracee `pid` with `request`, which is
+    /// `PTRACE_CONT`, `PTRACE_SYSCALL`, `PTRACE_SINGLESTEP` or
+    /// `PTRACE_LISTEN`, delivering `signal`.
+    fn resume(&mut self, pid: Pid, request: Request, signal: Option<Signal>) -> nix::Result<()>;
+
+    /// Set the ptrace options of the stopped tracee `pid`.
+    fn set_options(&mut self, pid: Pid, options: Options) -> nix::Result<()>;
+
+    /// Get the message of the ptrace event `pid` is stopped at, with
+    /// `PTRACE_GETEVENTMSG`.
+    fn event_message(&mut self, pid: Pid) -> nix::Result<libc::c_long>;
+}
+
+/// The default [`Backend`], which makes its system calls with nix.
+///
+/// [`Backend`]: trait.Backend.html
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NixBackend;
+
+impl Backend for NixBackend {
+    fn waitpid(&mut self, pid: Option<Pid>, flags: WaitPidFlag) -> nix::Result<WaitStatus> {
+        waitpid(pid, Some(flags))
+    }
+
+    fn resume(&mut self, pid: Pid, request: Request, signal: Option<Signal>) -> nix::Result<()> {
+        match request {
+            Request::PTRACE_SYSCALL => ptrace::syscall(pid, signal),
+            Request::PTRACE_SINGLESTEP => ptrace::step(pid, signal),
+            Request::PTRACE_LISTEN => {
+                let ret = unsafe { libc::ptrace(libc::PTRACE_LISTEN, pid.as_raw(), 0, 0) };
+                Errno::result(ret).map(drop)
+            }
+            _ => ptrace::cont(pid, signal),
+        }
+    }
+
+    fn set_options(&mut self, pid: Pid, options: Options) -> nix::Result<()> {
+        ptrace::setoptions(pid, options)
+    }
+
+    fn event_message(&mut self, pid: Pid) -> nix::Result<libc::c_long> {
+        ptrace::getevent(pid)
+    }
+}
+
+/// The backend of a `TraceSession`.
+pub(crate) struct BoxedBackend(pub(crate) Box<dyn Backend>);
+
+impl Default for BoxedBackend {
+    fn default() -> BoxedBackend {
+        BoxedBackend(Box::new(NixBackend))
+    }
+}
+
+impl fmt::Debug for BoxedBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BoxedBackend").finish_non_exhaustive()
+    }
+}
@@ -1,3 +1,308 @@
+use nix::sys::wait::waitpid;
+use nix::unistd::{fork, ForkResult};
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
 fn main() {
-    println!("hello");
+    match env::args().nth(1).as_deref() {
+        // Fork a child that exits immediately, and wait for it.
+        Some("fork") => match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => process::exit(0),
+            ForkResult::Parent { child } => {
+                waitpid(child, None).expect("waitpid failed");
+            }
+        },
+        // Run this program again with the remaining arguments in a child,
+        // and wait for it.
+        Some("spawn") => {
+            process::Command::new(env::current_exe().expect("no current exe"))
+                .args(env::args().skip(2))
+                .status()
+                .expect("spawn failed");
+        }
+        // Vfork a child that exits immediately, and wait for it.
+        Some("vfork") => {
+            // Safe enough here, since the child does nothing but exit.
+            #[allow(deprecated)]
+            let child = unsafe { libc::vfork() };
+            if child == 0 {
+                unsafe { libc::_exit(0) };
+            }
+            assert!(child > 0, "vfork failed");
+            waitpid(nix::unistd::Pid::from_raw(child), None).expect("waitpid failed");
+        }
+        // Run two threads that both update a shared counter.
+        Some("threads") => {
+            let counter = Arc::new(AtomicUsize::new(0));
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let counter = counter.clone();
+                    thread::spawn(move || {
+                        for _ in 0..100 {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().expect("join failed");
+            }
+            assert_eq!(counter.load(Ordering::SeqCst), 200);
+        }
+        // Keep a few threads running while creating short-lived ones for
+        // the given number of milliseconds.
+        Some("churn") => {
+            let ms = env::args()
+                .nth(2)
+                .and_then(|ms| ms.parse().ok())
+                .unwrap_or(100);
+            let end = Instant::now() + Duration::from_millis(ms);
+            for _ in 0..3 {
+                thread::spawn(move || {
+                    while Instant::now() < end {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                });
+            }
+            while Instant::now() < end {
+                thread::spawn(|| black_box(0)).join().expect("join failed");
+            }
+        }
+        // Answer lines from stdin after a prompt, raising SIGWINCH for
+        // "signal", until end-of-file.
+        Some("prompt") => {
+            let mut line = String::new();
+            loop {
+                print!("ready> ");
+                io::stdout().flush().expect("flush failed");
+                line.clear();
+                if io::stdin().read_line(&mut line).expect("read failed") == 0 {
+                    println!("bye");
+                    break;
+                }
+                if line.trim() == "signal" {
+                    unsafe { libc::raise(libc::SIGWINCH) };
+                }
+                println!("got {}", line.trim());
+            }
+        }
+        // Busy-loop for the given number of milliseconds.
+        Some("spin") => {
+            let ms = env::args()
+                .nth(2)
+                .and_then(|ms| ms.parse().ok())
+                .unwrap_or(100);
+            let end = Instant::now() + Duration::from_millis(ms);
+            while Instant::now() < end {}
+        }
+        // Leak one allocation and free another.
+        Some("alloc") => {
+            mem::forget(black_box(Vec::<u8>::with_capacity(12345)));
+            drop(black_box(Vec::<u8>::with_capacity(54321)));
+        }
+        // Call a couple of libc functions directly.
+        Some("libcalls") => unsafe {
+            let name = b"SPAWN_PTRACE_LIBCALL\0";
+            black_box(libc::getenv(
+                black_box(name.as_ptr() as *const libc::c_char),
+            ));
+            black_box(libc::strlen(black_box(
+                b"hello\0".as_ptr() as *const libc::c_char
+            )));
+        },
+        // Run a system call from generated code, then one on a stack on the
+        // heap.
+        #[cfg(target_arch = "x86_64")]
+        Some("jit") => unsafe {
+            let prot = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            let code = libc::mmap(std::ptr::null_mut(), 4096, prot, flags, -1, 0);
+            assert_ne!(code, libc::MAP_FAILED, "mmap failed");
+            // mov eax, SYS_getpid; syscall; ret
+            let insns = [0xb8, 39, 0, 0, 0, 0x0f, 0x05, 0xc3];
+            std::ptr::copy_nonoverlapping(insns.as_ptr(), code as *mut u8, insns.len());
+            let getpid: extern "C" fn() -> i64 = mem::transmute(code);
+            assert_eq!(getpid(), process::id() as i64);
+            let stack = vec![0u8; 1 << 16];
+            let top = (stack.as_ptr() as usize + stack.len()) & !0xf;
+            std::arch::asm!(
+                "mov {old}, rsp",
+                "mov rsp, {new}",
+                "syscall",
+                "mov rsp, {old}",
+                old = out(reg) _,
+                new = in(reg) top,
+                inlateout("rax") libc::SYS_getpid => _,
+                out("rcx") _,
+                out("r11") _,
+            );
+        },
+        // Make some classic checks for a debugger.
+        Some("antidebug") => unsafe {
+            libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+            fs::read_to_string("/proc/self/status").expect("read failed");
+            let pid = process::id();
+            fs::read_to_string(format!("/proc/{}/task/{}/stat", pid, pid)).expect("read failed");
+            libc::signal(libc::SIGTRAP, libc::SIG_IGN);
+            libc::raise(libc::SIGTRAP);
+            libc::prctl(libc::PR_SET_DUMPABLE, 0);
+        },
+        // Exit with 1 if PTRACE_TRACEME fails, plus 2 if TracerPid isn't 0.
+        Some("debugged") => {
+            let traceme = unsafe { libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0) };
+            let status = fs::read_to_string("/proc/self/status").expect("read failed");
+            let tracer = status
+                .lines()
+                .find_map(|line| line.strip_prefix("TracerPid:"))
+                .expect("No TracerPid");
+            let code = (traceme != 0) as i32 + 2 * (tracer.trim() != "0") as i32;
+            process::exit(code);
+        }
+        // Write 1000 bytes to the given file in ten chunks, then read them back.
+        Some("io") => {
+            let path = env::args().nth(2).expect("No path given");
+            let mut file = fs::File::create(&path).expect("create failed");
+            for _ in 0..10 {
+                file.write_all(&[0; 100]).expect("write failed");
+            }
+            drop(file);
+            let data = fs::read(&path).expect("read failed");
+            assert_eq!(data.len(), 1000);
+        }
+        // Write a byte to a pipe through a duplicate at descriptor 10.
+        Some("pipe") => unsafe {
+            let mut fds = [0; 2];
+            assert_eq!(libc::pipe(fds.as_mut_ptr()), 0, "pipe failed");
+            assert_eq!(libc::dup2(fds[1], 10), 10, "dup2 failed");
+            assert_eq!(libc::write(10, b"x".as_ptr() as *const libc::c_void, 1), 1);
+            libc::close(10);
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        },
+        // Change to the given directory, and create and remove a file there.
+        Some("chdir") => {
+            let dir = env::args().nth(2).expect("No directory given");
+            env::set_current_dir(dir).expect("chdir failed");
+            fs::write("spawn-ptrace-cwd", b"").expect("write failed");
+            fs::remove_file("spawn-ptrace-cwd").expect("remove failed");
+        }
+        // Write more to stdout and stderr than fits in a pipe.
+        Some("flood") => {
+            let data = vec![b'x'; 1 << 20];
+            io::stdout().write_all(&data).expect("write failed");
+            io::stderr().write_all(&data).expect("write failed");
+        }
+        // Print the credential lines of our status.
+        Some("creds") => {
+            let status = fs::read_to_string("/proc/self/status").expect("read failed");
+            for line in status.lines() {
+                if ["Uid:", "Gid:", "Groups:", "NoNewPrivs:"]
+                    .iter()
+                    .any(|prefix| line.starts_with(prefix))
+                {
+                    println!("{}", line);
+                }
+            }
+        }
+        // Print the numbers of our open file descriptors.
+        Some("fds") => {
+            for entry in fs::read_dir("/proc/self/fd").expect("read_dir failed") {
+                println!(
+                    "{}",
+                    entry
+                        .expect("read_dir failed")
+                        .file_name()
+                        .to_string_lossy()
+                );
+            }
+        }
+        // Print our pid, process group, session and controlling terminal.
+        Some("session") => {
+            let stat = fs::read_to_string("/proc/self/stat").expect("read failed");
+            // Skip past the command name, which may contain spaces.
+            let fields: Vec<&str> = stat[stat.rfind(')').expect("bad stat") + 2..]
+                .split(' ')
+                .collect();
+            println!(
+                "{} {} {} {}",
+                process::id(),
+                fields[2],
+                fields[3],
+                fields[4]
+            );
+        }
+        // Print our scheduling policy and nice value.
+        Some("sched") => {
+            let policy = unsafe { libc::sched_getscheduler(0) };
+            let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+            println!("{} {}", policy, nice);
+        }
+        // Call getppid, which a seccomp filter should fail, and getuid,
+        // which it should trace.
+        Some("seccomp") => {
+            let ppid = unsafe { libc::syscall(libc::SYS_getppid) };
+            let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+            unsafe { libc::getuid() };
+            println!("{} {}", ppid, errno);
+        }
+        // Sleep for the given number of milliseconds.
+        Some("sleep") => {
+            let ms = env::args()
+                .nth(2)
+                .and_then(|ms| ms.parse().ok())
+                .unwrap_or(100);
+            thread::sleep(Duration::from_millis(ms));
+        }
+        // Call a function that calls a long-running one.
+        Some("calls") => {
+            black_box(spawn_ptrace_caller());
+        }
+        // Handle a signal raised at ourselves.
+        Some("handler") => unsafe {
+            let handler: extern "C" fn(libc::c_int) = spawn_ptrace_handler;
+            libc::signal(libc::SIGUSR1, handler as libc::sighandler_t);
+            libc::raise(libc::SIGUSR1);
+        },
+        // Run this program again with the remaining arguments.
+        Some("exec") => {
+            let e = process::Command::new(env::current_exe().expect("no current exe"))
+                .args(env::args().skip(2))
+                .exec();
+            panic!("exec failed: {}", e);
+        }
+        _ => println!("hello"),
+    }
+}
+
+/// Loop `n` times, for a debugger to step over rather than through.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn spawn_ptrace_leaf(n: u64) -> u64 {
+    let mut total = 0;
+    for i in 0..n {
+        total = black_box(total + i);
+    }
+    total
+}
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn spawn_ptrace_caller() -> u64 {
+    black_box(spawn_ptrace_leaf(black_box(100_000))) + 1
+}
+
+/// A signal handler that does nothing.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn spawn_ptrace_handler(signal: libc::c_int) {
+    black_box(signal);
 }
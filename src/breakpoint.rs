@@ -0,0 +1,660 @@
+#[cfg(all(feature = "symbolication", target_arch = "x86_64"))]
+use crate::dwarf::LineTable;
+#[cfg(target_arch = "x86_64")]
+use crate::inject;
+#[cfg(target_arch = "x86_64")]
+use crate::unwind;
+use crate::{memory, nix_error, Event, Resume, TraceSession};
+use nix::sys::ptrace;
+#[cfg(target_arch = "x86_64")]
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::io;
+
+/// The x86 `int3` instruction.
+#[cfg(target_arch = "x86_64")]
+const BREAKPOINT_INSN: u8 = 0xcc;
+
+#[derive(Debug)]
+struct Site {
+    original: u8,
+    refs: usize,
+}
+
+/// A breakpoint inserted by function name, which is found again after `exec`.
+#[derive(Debug)]
+struct Symbolic {
+    name: String,
+    /// Where it is inserted in the current image, if the function is there.
+    addr: Option<u64>,
+}
+
+/// Software breakpoints in the address space of a single traced process.
+///
+/// Breakpoints are implemented by replacing the first byte of the
+/// instruction at each address with `int3`, so a tracee thread that reaches
+/// one stops with `SIGTRAP`. Use [`hit`] to recognize these stops and
+/// [`step_over`] to execute the original instruction before resuming.
+///
+/// Inserting a breakpoint at an address that already has one increments a
+/// reference count, and it is only removed from memory once it has been
+/// removed as many times as it was inserted.
+///
+/// While a thread is being stepped over a breakpoint, the breakpoint is
+/// briefly removed from memory, so other threads that are running at the
+/// time may pass it without stopping.
+///
+/// A successful `exec` replaces the address space, taking every breakpoint
+/// with it. Pass each event to [`handle_event`] so that the breakpoints are
+/// forgotten at `Event::Exec`, and those inserted with [`insert_symbol`]
+/// are inserted again wherever their functions are in the new program.
+///
+/// [`step_over_call`] and [`step_out`] build the stepping a debugger offers
+/// on top of these, with temporary breakpoints at return addresses, and
+/// with the `symbolication` feature [`step_line`] steps by source line.
+///
+/// [`hit`]: #method.hit
+/// [`step_over`]: #method.step_over
+/// [`handle_event`]: #method.handle_event
+/// [`insert_symbol`]: #method.insert_symbol
+/// [`step_over_call`]: #method.step_over_call
+/// [`step_out`]: #method.step_out
+/// [`step_line`]: #method.step_line
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    sites: HashMap<u64, Site>,
+    symbolic: Vec<Symbolic>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Breakpoints {
+    /// Create an empty set of breakpoints.
+    pub fn new() -> Breakpoints {
+        Breakpoints::default()
+    }
+
+    /// Insert a breakpoint at `addr` in the memory of the stopped tracee `pid`.
+    pub fn insert(&mut self, pid: Pid, addr: u64) -> io::Result<()> {
+        if let Some(site) = self.sites.get_mut(&addr) {
+            site.refs += 1;
+            return Ok(());
+        }
+        let mut original = [0];
+        memory::read(pid, addr, &mut original)?;
+        memory::write(pid, addr, &[BREAKPOINT_INSN])?;
+        self.sites.insert(
+            addr,
+            Site {
+                original: original[0],
+                refs: 1,
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove a breakpoint at `addr` from the memory of the stopped tracee `pid`.
+    ///
+    /// Returns `false` if there was no breakpoint at `addr`.
+    pub fn remove(&mut self, pid: Pid, addr: u64) -> io::Result<bool> {
+        let site = match self.sites.get_mut(&addr) {
+            Some(site) => site,
+            None => return Ok(false),
+        };
+        site.refs -= 1;
+        if site.refs == 0 {
+            memory::write(pid, addr, &[site.original])?;
+            self.sites.remove(&addr);
+        }
+        Ok(true)
+    }
+
+    /// Insert a breakpoint at the function `name` in the stopped tracee
+    /// `pid`, returning its address.
+    ///
+    /// The function is looked up in the symbol tables of the executable and
+    /// the shared objects currently mapped, and is looked up again after
+    /// each `exec` seen by [`handle_event`]. Fails with
+    /// `ErrorKind::NotFound` if no mapped object defines it.
+    ///
+    /// [`handle_event`]: #method.handle_event
+    pub fn insert_symbol(&mut self, pid: Pid, name: &str) -> io::Result<u64> {
+        let addr = inject::find_function(pid, name)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No function {}", name))
+        })?;
+        self.insert(pid, addr)?;
+        self.symbolic.push(Symbolic {
+            name: name.to_string(),
+            addr: Some(addr),
+        });
+        Ok(addr)
+    }
+
+    /// Remove a breakpoint inserted with [`insert_symbol`] from the stopped
+    /// tracee `pid`.
+    ///
+    /// Returns `false` if there was no breakpoint on the function `name`.
+    ///
+    /// [`insert_symbol`]: #method.insert_symbol
+    pub fn remove_symbol(&mut self, pid: Pid, name: &str) -> io::Result<bool> {
+        let index = match self.symbolic.iter().position(|s| s.name == name) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        if let Some(addr) = self.symbolic.remove(index).addr {
+            self.remove(pid, addr)?;
+        }
+        Ok(true)
+    }
+
+    /// The functions with breakpoints inserted by name that aren't in the
+    /// current program, for example after an `exec`, or because they are in
+    /// a library that hasn't been loaded yet.
+    pub fn pending(&self) -> impl Iterator<Item = &str> + '_ {
+        self.symbolic
+            .iter()
+            .filter(|s| s.addr.is_none())
+            .map(|s| s.name.as_str())
+    }
+
+    /// Try to insert the [`pending`] breakpoints into the stopped tracee
+    /// `pid` again, returning how many were inserted.
+    ///
+    /// This can be used once a library that defines them has been loaded.
+    ///
+    /// [`pending`]: #method.pending
+    pub fn resolve_pending(&mut self, pid: Pid) -> io::Result<usize> {
+        let mut resolved = 0;
+        for i in 0..self.symbolic.len() {
+            if self.symbolic[i].addr.is_some() {
+                continue;
+            }
+            if let Some(addr) = inject::find_function(pid, &self.symbolic[i].name)? {
+                self.insert(pid, addr)?;
+                self.symbolic[i].addr = Some(addr);
+                resolved += 1;
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Keep the breakpoints up to date with `event`, reported by the stopped
+    /// tracee `pid`.
+    ///
+    /// At `Event::Exec` every breakpoint is forgotten, since its address
+    /// space is gone, and breakpoints inserted with [`insert_symbol`] are
+    /// inserted again into the new program. At that point only the program
+    /// and its dynamic loader are mapped, so functions in shared libraries
+    /// stay [`pending`] until [`resolve_pending`] finds them. Other events
+    /// are ignored.
+    ///
+    /// [`insert_symbol`]: #method.insert_symbol
+    /// [`pending`]: #method.pending
+    /// [`resolve_pending`]: #method.resolve_pending
+    pub fn handle_event(&mut self, pid: Pid, event: &Event) -> io::Result<()> {
+        if let Event::Exec(_) = event {
+            self.forget_all();
+            self.resolve_pending(pid)?;
+        }
+        Ok(())
+    }
+
+    /// Whether there is a breakpoint at `addr`.
+    pub fn contains(&self, addr: u64) -> bool {
+        self.sites.contains_key(&addr)
+    }
+
+    /// The addresses of all breakpoints.
+    pub fn addresses(&self) -> impl Iterator<Item = u64> + '_ {
+        self.sites.keys().cloned()
+    }
+
+    /// Check whether thread `pid`, which has stopped with `SIGTRAP`, hit one of
+    /// these breakpoints.
+    ///
+    /// If it did, the thread's program counter is moved back to the
+    /// breakpoint address, which is returned.
+    pub fn hit(&self, pid: Pid) -> io::Result<Option<u64>> {
+        let mut regs = ptrace::getregs(pid).map_err(nix_error)?;
+        // The program counter is left just after the `int3`.
+        let addr = regs.rip.wrapping_sub(1);
+        if !self.sites.contains_key(&addr) {
+            return Ok(None);
+        }
+        regs.rip = addr;
+        ptrace::setregs(pid, regs).map_err(nix_error)?;
+        Ok(Some(addr))
+    }
+
+    /// Single-step thread `pid` over the breakpoint at its program counter.
+    ///
+    /// The original instruction is executed, the breakpoint is put back and
+    /// the event reported by the step is returned, which is normally
+    /// `Event::Signal(SIGTRAP)`. If the thread isn't at a breakpoint it is
+    /// simply stepped.
+    pub fn step_over(&mut self, session: &mut TraceSession, pid: Pid) -> io::Result<Event> {
+        let regs = ptrace::getregs(pid).map_err(nix_error)?;
+        let original = self.sites.get(&regs.rip).map(|site| site.original);
+        if let Some(original) = original {
+            memory::write(pid, regs.rip, &[original])?;
+        }
+        session.step(pid, None)?;
+        let (_, event) = session.wait_for(pid)?;
+        if original.is_some() {
+            match event {
+                Event::Exited(_) | Event::Signaled(..) => {}
+                _ => memory::write(pid, regs.rip, &[BREAKPOINT_INSN])?,
+            }
+        }
+        Ok(event)
+    }
+
+    /// Execute the next instruction of thread `pid` like [`step_over`],
+    /// except that a called function runs until it returns, as a single
+    /// step.
+    ///
+    /// A call is recognized after the step by the return address it pushed,
+    /// and the thread is resumed until it gets back there in the same stack
+    /// frame, so recursive calls that return through the same address don't
+    /// end the step. Returns the event that ended the step, normally
+    /// `Event::Signal(SIGTRAP)`. Any other event ends it early, such as a
+    /// signal or another breakpoint, whose `SIGTRAP` is returned after
+    /// [`hit`] has moved the thread back to it. A breakpoint at the called
+    /// function ends the step at the function's start.
+    ///
+    /// [`step_over`]: #method.step_over
+    /// [`hit`]: #method.hit
+    pub fn step_over_call(&mut self, session: &mut TraceSession, pid: Pid) -> io::Result<Event> {
+        let before = ptrace::getregs(pid).map_err(nix_error)?;
+        let event = self.step_over(session, pid)?;
+        if event != Event::Signal(Signal::SIGTRAP) {
+            return Ok(event);
+        }
+        let after = ptrace::getregs(pid).map_err(nix_error)?;
+        let ret = match self.called(pid, &before, &after)? {
+            Some(ret) => ret,
+            None => return Ok(event),
+        };
+        // A breakpoint at the start of the function ends the step there.
+        if self.contains(after.rip) {
+            return Ok(event);
+        }
+        self.run_to_return(session, pid, ret, before.rsp)
+    }
+
+    /// Execute thread `pid` until it gets to a different source line from
+    /// the one it is on, according to `lines`, like a debugger's `step`.
+    ///
+    /// Instructions are stepped one at a time with [`step_over`], and the
+    /// steps that stay on the same line, or go to code the compiler gave no
+    /// line, don't end the step. A call to a function with lines ends it at
+    /// the function's first line, while functions without, like PLT stubs
+    /// and those from libraries built without debug info, are run until they
+    /// return, as with [`step_over_call`]. Returns the event that ended the
+    /// step, normally `Event::Signal(SIGTRAP)`, and other events end it
+    /// early as they do `step_over_call`.
+    ///
+    /// [`step_over`]: #method.step_over
+    /// [`step_over_call`]: #method.step_over_call
+    #[cfg(feature = "symbolication")]
+    pub fn step_line(
+        &mut self,
+        session: &mut TraceSession,
+        pid: Pid,
+        lines: &LineTable,
+    ) -> io::Result<Event> {
+        let rip = ptrace::getregs(pid).map_err(nix_error)?.rip;
+        let start = lines.lookup(rip).map(|line| (line.file, line.line));
+        loop {
+            let before = ptrace::getregs(pid).map_err(nix_error)?;
+            let event = self.step_over(session, pid)?;
+            if event != Event::Signal(Signal::SIGTRAP) {
+                return Ok(event);
+            }
+            let mut after = ptrace::getregs(pid).map_err(nix_error)?;
+            let mut line = lines.lookup(after.rip);
+            if line.is_none() && !self.contains(after.rip) {
+                if let Some(ret) = self.called(pid, &before, &after)? {
+                    let event = self.run_to_return(session, pid, ret, before.rsp)?;
+                    after = ptrace::getregs(pid).map_err(nix_error)?;
+                    if event != Event::Signal(Signal::SIGTRAP) || after.rip != ret {
+                        return Ok(event);
+                    }
+                    line = lines.lookup(after.rip);
+                }
+            }
+            match line {
+                Some(line) if Some((line.file, line.line)) != start => return Ok(event),
+                _ => {}
+            }
+        }
+    }
+
+    /// The return address that the instruction stepped from `before` to
+    /// `after` pushed, if it was a call.
+    fn called(
+        &self,
+        pid: Pid,
+        before: &libc::user_regs_struct,
+        after: &libc::user_regs_struct,
+    ) -> io::Result<Option<u64>> {
+        if after.rsp != before.rsp.wrapping_sub(8) {
+            return Ok(None);
+        }
+        // A call pushes the address of the instruction after it, which is
+        // at most 15 bytes long, and jumps somewhere else.
+        let ret = memory::read_u64(pid, after.rsp)?;
+        if ret <= before.rip || ret > before.rip + 15 || after.rip == ret {
+            return Ok(None);
+        }
+        Ok(Some(ret))
+    }
+
+    /// Resume thread `pid` until the function it is in returns to its
+    /// caller, and return the event that ended the run, normally
+    /// `Event::Signal(SIGTRAP)` with the thread just after the call.
+    ///
+    /// The return address is found by the unwinder, which follows frame
+    /// pointers like [`Tracee::backtrace`], except where the frame isn't
+    /// set up: at a function's first instruction, which includes the
+    /// functions with breakpoints from [`insert_symbol`], after its `push
+    /// rbp`, or at a `ret`. Fails with `ErrorKind::NotFound` if there is no
+    /// caller to be found. As with [`step_over_call`], other events end the
+    /// run early.
+    ///
+    /// [`Tracee::backtrace`]: struct.Tracee.html#method.backtrace
+    /// [`insert_symbol`]: #method.insert_symbol
+    /// [`step_over_call`]: #method.step_over_call
+    pub fn step_out(&mut self, session: &mut TraceSession, pid: Pid) -> io::Result<Event> {
+        let regs = ptrace::getregs(pid).map_err(nix_error)?;
+        let (ret, sp) = self.return_address(pid, &regs)?;
+        self.run_to_return(session, pid, ret, sp)
+    }
+
+    /// Where the function thread `pid` is in returns to, and the stack
+    /// pointer once it has.
+    fn return_address(&self, pid: Pid, regs: &libc::user_regs_struct) -> io::Result<(u64, u64)> {
+        let entry = self.symbolic.iter().any(|s| s.addr == Some(regs.rip));
+        let mut code = [0; 7];
+        self.read_code(pid, regs.rip, &mut code)?;
+        // Skip an `endbr64`.
+        let insn = match code {
+            [0xf3, 0x0f, 0x1e, 0xfa, ..] => &code[4..],
+            _ => &code[..],
+        };
+        let slot = match insn {
+            // `push rbp` or `ret`, with the return address on top.
+            _ if entry => regs.rsp,
+            [0x55, ..] | [0xc3, ..] => regs.rsp,
+            // `mov rbp, rsp`, just after `push rbp`.
+            [0x48, 0x89, 0xe5, ..] => regs.rsp + 8,
+            _ => {
+                let frames = unwind::frame_pointer_backtrace(pid, regs, 2)?;
+                return match frames.get(1) {
+                    Some(&ret) => Ok((ret, regs.rbp + 16)),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "No caller found from the frame pointer",
+                    )),
+                };
+            }
+        };
+        Ok((memory::read_u64(pid, slot)?, slot + 8))
+    }
+
+    /// Read the tracee's code at `addr`, with the original bytes in place of
+    /// breakpoints.
+    fn read_code(&self, pid: Pid, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        memory::read(pid, addr, buf)?;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            if let Some(site) = self.sites.get(&(addr + i as u64)) {
+                *byte = site.original;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resume thread `pid` until it reaches `ret` with its stack pointer
+    /// back at `sp`, with a temporary breakpoint there.
+    fn run_to_return(
+        &mut self,
+        session: &mut TraceSession,
+        pid: Pid,
+        ret: u64,
+        sp: u64,
+    ) -> io::Result<Event> {
+        self.insert(pid, ret)?;
+        let result = self.run_to(session, pid, ret, sp);
+        match &result {
+            Ok(Event::Exited(_)) | Ok(Event::Signaled(..)) => {
+                // The memory is gone along with the breakpoint.
+                if let Some(site) = self.sites.get_mut(&ret) {
+                    site.refs -= 1;
+                    if site.refs == 0 {
+                        self.sites.remove(&ret);
+                    }
+                }
+            }
+            Ok(event @ Event::Exec(_)) => self.handle_event(pid, event)?,
+            Ok(_) => {
+                self.remove(pid, ret)?;
+            }
+            Err(_) => {
+                let _ = self.remove(pid, ret);
+            }
+        }
+        result
+    }
+
+    fn run_to(
+        &mut self,
+        session: &mut TraceSession,
+        pid: Pid,
+        ret: u64,
+        sp: u64,
+    ) -> io::Result<Event> {
+        if self.contains(ptrace::getregs(pid).map_err(nix_error)?.rip) {
+            let event = self.step_over(session, pid)?;
+            if event != Event::Signal(Signal::SIGTRAP) {
+                return Ok(event);
+            }
+        }
+        loop {
+            session.resume_as(pid, Resume::Continue(None))?;
+            let (_, event) = session.wait_for(pid)?;
+            if event != Event::Signal(Signal::SIGTRAP) || self.hit(pid)? != Some(ret) {
+                return Ok(event);
+            }
+            // A deeper call returning through the same address.
+            if ptrace::getregs(pid).map_err(nix_error)?.rsp < sp {
+                let event = self.step_over(session, pid)?;
+                if event != Event::Signal(Signal::SIGTRAP) {
+                    return Ok(event);
+                }
+                continue;
+            }
+            return Ok(event);
+        }
+    }
+
+    /// Remove every breakpoint from the memory of the stopped tracee `pid`,
+    /// without forgetting them.
+    ///
+    /// This is useful for cleaning up a forked child, which inherits the
+    /// breakpoints in its copy of its parent's memory.
+    pub fn clear_from(&self, pid: Pid) -> io::Result<()> {
+        for (&addr, site) in &self.sites {
+            memory::write(pid, addr, &[site.original])?;
+        }
+        Ok(())
+    }
+
+    /// Forget every breakpoint without touching the tracee's memory.
+    ///
+    /// This is for when the address space the breakpoints were inserted in
+    /// no longer exists, for example after `exec`. Breakpoints inserted with
+    /// [`insert_symbol`] become [`pending`].
+    ///
+    /// [`insert_symbol`]: #method.insert_symbol
+    /// [`pending`]: #method.pending
+    pub fn forget_all(&mut self) {
+        self.sites.clear();
+        for symbolic in &mut self.symbolic {
+            symbolic.addr = None;
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use nix::sys::signal::Signal;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_breakpoints_across_exec() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.ptrace_options(ptrace::Options::PTRACE_O_TRACEEXEC);
+        let pid = session
+            .spawn(
+                Command::new(&path).arg("exec").stdout(Stdio::null()),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut breakpoints = Breakpoints::new();
+        let e = breakpoints
+            .insert_symbol(pid, "no_such_function")
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        let main = breakpoints
+            .insert_symbol(pid, "main")
+            .expect("Error inserting breakpoint");
+        let mut hits = 0;
+        let mut execs = 0;
+        let mut signal = None;
+        loop {
+            ptrace::cont(pid, signal).expect("Error continuing child");
+            signal = None;
+            let (_, event) = session.wait_for(pid).expect("Error waiting");
+            breakpoints
+                .handle_event(pid, &event)
+                .expect("Error handling event");
+            match event {
+                Event::Signal(Signal::SIGTRAP) => {
+                    let addr = breakpoints.hit(pid).unwrap().expect("Unexpected SIGTRAP");
+                    if hits == 0 {
+                        assert_eq!(addr, main);
+                    }
+                    hits += 1;
+                    breakpoints.step_over(&mut session, pid).unwrap();
+                }
+                Event::Exec(_) => {
+                    execs += 1;
+                    assert_eq!(breakpoints.pending().count(), 0);
+                    assert_eq!(breakpoints.addresses().count(), 1);
+                }
+                Event::Signal(sig) => signal = Some(sig),
+                Event::Exited(code) => {
+                    assert_eq!(code, 0);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(execs, 1);
+        // main ran in the program before and after the exec.
+        assert_eq!(hits, 2);
+        breakpoints.forget_all();
+        assert_eq!(breakpoints.pending().collect::<Vec<_>>(), ["main"]);
+    }
+
+    /// Run the test program's `calls` mode to a breakpoint on `function`.
+    fn run_to_function(name: &str) -> (TraceSession, Pid, Breakpoints) {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        let pid = session
+            .spawn(Command::new(&path).arg("calls"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut breakpoints = Breakpoints::new();
+        let addr = breakpoints
+            .insert_symbol(pid, name)
+            .expect("Error inserting breakpoint");
+        ptrace::cont(pid, None).unwrap();
+        assert_eq!(
+            session.wait_for(pid).unwrap(),
+            (pid, Event::Signal(Signal::SIGTRAP))
+        );
+        assert_eq!(breakpoints.hit(pid).unwrap(), Some(addr));
+        (session, pid, breakpoints)
+    }
+
+    #[test]
+    fn test_step_over_call_and_out() {
+        let (mut session, pid, mut breakpoints) = run_to_function("spawn_ptrace_caller");
+        let entry = ptrace::getregs(pid).unwrap();
+        let ret = memory::read_u64(pid, entry.rsp).unwrap();
+        // Stepping into the leaf function would take hundreds of thousands
+        // of steps.
+        let mut steps = 0;
+        while ptrace::getregs(pid).unwrap().rip != ret {
+            let event = breakpoints.step_over_call(&mut session, pid).unwrap();
+            assert_eq!(event, Event::Signal(Signal::SIGTRAP));
+            steps += 1;
+            assert!(steps < 1000, "Stepped into a call");
+        }
+        assert_eq!(ptrace::getregs(pid).unwrap().rsp, entry.rsp + 8);
+
+        let (mut session, pid, mut breakpoints) = run_to_function("spawn_ptrace_leaf");
+        let entry = ptrace::getregs(pid).unwrap();
+        let ret = memory::read_u64(pid, entry.rsp).unwrap();
+        let event = breakpoints.step_out(&mut session, pid).unwrap();
+        assert_eq!(event, Event::Signal(Signal::SIGTRAP));
+        let regs = ptrace::getregs(pid).unwrap();
+        assert_eq!((regs.rip, regs.rsp), (ret, entry.rsp + 8));
+        // The temporary breakpoint is gone.
+        assert_eq!(breakpoints.addresses().count(), 1);
+        ptrace::cont(pid, None).unwrap();
+        let (_, event) = session.wait_for(pid).unwrap();
+        assert_eq!(event, Event::Exited(0));
+    }
+
+    #[cfg(feature = "symbolication")]
+    #[test]
+    fn test_step_line() {
+        let (mut session, pid, mut breakpoints) = run_to_function("spawn_ptrace_caller");
+        let lines = LineTable::load(pid).expect("Error loading line tables");
+        let location = |pid| {
+            let rip = ptrace::getregs(pid).unwrap().rip;
+            let line = lines.lookup(rip).expect("No line at the program counter");
+            (line.file.to_owned(), line.line)
+        };
+        let (file, entry) = location(pid);
+        assert!(file.ends_with("src/bin/test.rs"), "{:?}", file);
+        // Step into the leaf function, through `black_box`.
+        let mut last = (file.clone(), entry);
+        for _ in 0..100 {
+            let event = breakpoints
+                .step_line(&mut session, pid, &lines)
+                .expect("Error stepping");
+            assert_eq!(event, Event::Signal(Signal::SIGTRAP));
+            let here = location(pid);
+            assert_ne!(here, last);
+            let done = here.0 == file && here.1 < entry;
+            last = here;
+            if done {
+                break;
+            }
+        }
+        assert!(
+            last.0 == file && last.1 < entry,
+            "Never got to the leaf function"
+        );
+        ptrace::cont(pid, None).unwrap();
+        let (_, event) = session.wait_for(pid).unwrap();
+        assert_eq!(event, Event::Exited(0));
+    }
+}
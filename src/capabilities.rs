@@ -0,0 +1,156 @@
+//! Probing which tracing features the running kernel supports.
+
+use nix::sys::ptrace;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::{fork, getpid, ForkResult, Pid};
+use std::mem;
+
+/// Which optional features the running kernel supports, as found by
+/// [`capabilities`].
+///
+/// [`capabilities`]: fn.capabilities.html
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// `PTRACE_SEIZE` and `PTRACE_INTERRUPT`, from Linux 3.4.
+    pub seize: bool,
+    /// `PTRACE_GET_SYSCALL_INFO`, used by `Tracee::syscall_info`, from Linux
+    /// 5.3.
+    pub get_syscall_info: bool,
+    /// `PTRACE_PEEKSIGINFO`, for reading a tracee's pending signals, from
+    /// Linux 3.10.
+    pub peeksiginfo: bool,
+    /// The `SECCOMP_RET_USER_NOTIF` action, used by
+    /// `CommandPtraceSpawn::spawn_with_notifier`, from Linux 5.0.
+    pub seccomp_user_notif: bool,
+    /// `pidfd_open`, used by `Tracee::pidfd`, from Linux 5.3.
+    pub pidfd: bool,
+    /// `process_vm_readv`, the fastest way to read a tracee's memory, from
+    /// Linux 3.2.
+    pub process_vm_readv: bool,
+}
+
+/// Find out which optional features the running kernel supports, so that
+/// a tracer can avoid the ones that would fail.
+///
+/// The ptrace requests are tried on a short-lived child process that is
+/// forked for the purpose. If this process isn't allowed to trace its own
+/// children, for example with Yama's `ptrace_scope` set to 3 or under a
+/// seccomp filter, they are reported as unsupported, since they can't be
+/// used anyway.
+pub fn capabilities() -> Capabilities {
+    let mut caps = Capabilities {
+        seccomp_user_notif: probe_seccomp_user_notif(),
+        pidfd: probe_pidfd(),
+        process_vm_readv: probe_process_vm_readv(),
+        ..Capabilities::default()
+    };
+    probe_ptrace(&mut caps);
+    caps
+}
+
+fn probe_seccomp_user_notif() -> bool {
+    let action = libc::SECCOMP_RET_USER_NOTIF;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_GET_ACTION_AVAIL,
+            0,
+            &action as *const libc::c_uint,
+        )
+    };
+    ret == 0
+}
+
+pub(crate) fn probe_pidfd() -> bool {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, getpid().as_raw(), 0) };
+    if fd < 0 {
+        return false;
+    }
+    unsafe { libc::close(fd as libc::c_int) };
+    true
+}
+
+fn probe_process_vm_readv() -> bool {
+    let source = [1u8; 8];
+    let mut dest = [0u8; 8];
+    let local = libc::iovec {
+        iov_base: dest.as_mut_ptr() as *mut libc::c_void,
+        iov_len: dest.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: source.as_ptr() as *mut libc::c_void,
+        iov_len: source.len(),
+    };
+    let ret = unsafe { libc::process_vm_readv(getpid().as_raw(), &local, 1, &remote, 1, 0) };
+    ret == source.len() as isize && dest == source
+}
+
+/// Try the ptrace requests on a child that waits to be killed.
+fn probe_ptrace(caps: &mut Capabilities) {
+    let child = match unsafe { fork() } {
+        Ok(ForkResult::Child) => loop {
+            // Only async-signal-safe calls are allowed after forking.
+            unsafe { libc::pause() };
+        },
+        Ok(ForkResult::Parent { child }) => child,
+        Err(_) => return,
+    };
+    if ptrace::seize(child, ptrace::Options::empty()).is_ok() {
+        caps.seize = true;
+        let ret = unsafe { libc::ptrace(libc::PTRACE_INTERRUPT, child.as_raw(), 0, 0) };
+        if ret == 0 && waitpid(child, Some(WaitPidFlag::__WALL)).is_ok() {
+            probe_stopped(child, caps);
+        }
+    }
+    let _ = kill(child, Signal::SIGKILL);
+    while waitpid(child, Some(WaitPidFlag::__WALL)).is_ok() {}
+}
+
+/// Try the requests that need the stopped tracee `child`.
+fn probe_stopped(child: Pid, caps: &mut Capabilities) {
+    let mut info = [0u8; 128];
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GET_SYSCALL_INFO,
+            child.as_raw(),
+            info.len(),
+            info.as_mut_ptr(),
+        )
+    };
+    caps.get_syscall_info = ret > 0;
+    let args = libc::ptrace_peeksiginfo_args {
+        off: 0,
+        flags: 0,
+        nr: 1,
+    };
+    let mut siginfo: libc::siginfo_t = unsafe { mem::zeroed() };
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_PEEKSIGINFO,
+            child.as_raw(),
+            &args as *const libc::ptrace_peeksiginfo_args,
+            &mut siginfo as *mut libc::siginfo_t,
+        )
+    };
+    // The child has no pending signals, so nothing is copied.
+    caps.peeksiginfo = ret >= 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let caps = capabilities();
+        // The other tests already rely on all of these.
+        assert!(caps.seize);
+        assert!(caps.get_syscall_info);
+        assert!(caps.peeksiginfo);
+        assert!(caps.seccomp_user_notif);
+        assert!(caps.pidfd);
+        assert!(caps.process_vm_readv);
+    }
+}
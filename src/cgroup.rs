@@ -0,0 +1,209 @@
+//! Freezing groups of tracees with the cgroup v2 freezer.
+
+use crate::Error;
+use nix::unistd::Pid;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// A cgroup v2 control group, for freezing and thawing every process in it
+/// at once.
+///
+/// Stopping a process tree with `SIGSTOP` races with the processes that
+/// fork meanwhile, and the stops show up as events of every tracee. The
+/// cgroup freezer instead stops everything in the group atomically, new
+/// children included, as they are created in their parent's group, and
+/// without any signal or wait being involved. Put a tracee in the group
+/// before it runs with [`SpawnOptions::cgroup`], so that its whole tree
+/// follows, or move processes in with [`add`].
+///
+/// While the group is frozen its tracees don't run or report events, but
+/// they can still be killed. Tracees that are in a ptrace stop stay in it
+/// until they are thawed and resumed.
+///
+/// [`SpawnOptions::cgroup`]: struct.SpawnOptions.html#method.cgroup
+/// [`add`]: #method.add
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    /// Whether the group was created by `create`, and should be removed.
+    created: bool,
+}
+
+impl Cgroup {
+    /// Create a new group at `path`, a directory that doesn't exist yet in a
+    /// cgroup v2 hierarchy.
+    ///
+    /// The group is removed when this is dropped, if no processes are left
+    /// in it by then. Fails with `Error::Unsupported` if the hierarchy lacks
+    /// the freezer, which needs Linux 5.2.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Cgroup> {
+        let path = path.as_ref().to_owned();
+        fs::create_dir(&path)?;
+        let group = Cgroup {
+            path,
+            created: true,
+        };
+        group.check_freezer()?;
+        Ok(group)
+    }
+
+    /// Create a new group called `name` inside the tracer's own cgroup v2
+    /// group, as found from `/proc/self/cgroup`.
+    ///
+    /// Creating groups needs write access to the tracer's group, which
+    /// systemd grants to services with `Delegate=yes`.
+    pub fn create_child(name: &str) -> io::Result<Cgroup> {
+        let own = fs::read_to_string("/proc/self/cgroup")?;
+        let own = own
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or(Error::Unsupported("cgroup v2"))?;
+        let mount = hierarchy()?.ok_or(Error::Unsupported("cgroup v2"))?;
+        Cgroup::create(mount.join(own.trim_start_matches('/')).join(name))
+    }
+
+    /// Use the existing group at `path`, which is left in place when this
+    /// is dropped.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Cgroup> {
+        let group = Cgroup {
+            path: path.as_ref().to_owned(),
+            created: false,
+        };
+        group.check_freezer()?;
+        Ok(group)
+    }
+
+    /// The group's directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move process `pid`, with all of its threads, into the group.
+    ///
+    /// Children it creates afterwards start in the group too.
+    pub fn add(&self, pid: Pid) -> io::Result<()> {
+        add(&self.path, pid)
+    }
+
+    /// The processes in the group.
+    pub fn pids(&self) -> io::Result<Vec<Pid>> {
+        let procs = fs::read_to_string(self.path.join("cgroup.procs"))?;
+        Ok(procs
+            .lines()
+            .filter_map(|pid| pid.parse().ok())
+            .map(Pid::from_raw)
+            .collect())
+    }
+
+    /// Freeze every process in the group, and wait until all of them are
+    /// frozen.
+    pub fn freeze(&self) -> io::Result<()> {
+        self.set_frozen(true)
+    }
+
+    /// Thaw the group, letting its processes run again, and wait until all
+    /// of them are thawed.
+    pub fn thaw(&self) -> io::Result<()> {
+        self.set_frozen(false)
+    }
+
+    /// Whether every process in the group is frozen, from `cgroup.events`.
+    pub fn is_frozen(&self) -> io::Result<bool> {
+        let events = fs::read_to_string(self.path.join("cgroup.events"))?;
+        Ok(events.lines().any(|line| line == "frozen 1"))
+    }
+
+    fn set_frozen(&self, frozen: bool) -> io::Result<()> {
+        fs::write(
+            self.path.join("cgroup.freeze"),
+            if frozen { "1" } else { "0" },
+        )?;
+        // The kernel changes `cgroup.events` once the last process is done.
+        while self.is_frozen()? != frozen {
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    fn check_freezer(&self) -> io::Result<()> {
+        if self.path.join("cgroup.freeze").exists() {
+            return Ok(());
+        }
+        if self.created {
+            let _ = fs::remove_dir(&self.path);
+        }
+        Err(Error::Unsupported("the cgroup v2 freezer").into())
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if self.created {
+            let _ = fs::remove_dir(&self.path);
+        }
+    }
+}
+
+/// Move process `pid` into the group at `path`.
+pub(crate) fn add(path: &Path, pid: Pid) -> io::Result<()> {
+    fs::write(path.join("cgroup.procs"), pid.to_string())
+}
+
+/// Where the cgroup v2 hierarchy is mounted, if it is, from `/proc/mounts`.
+fn hierarchy() -> io::Result<Option<PathBuf>> {
+    let mounts = fs::read_to_string("/proc/mounts")?;
+    Ok(mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mount = fields.nth(1)?;
+        match fields.next() {
+            Some("cgroup2") => Some(PathBuf::from(mount)),
+            _ => None,
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{Event, SpawnOptions, TraceSession};
+    use nix::sys::ptrace;
+    use std::process::Command;
+
+    #[test]
+    fn test_cgroup_freeze() {
+        let name = format!("spawn-ptrace-{}", std::process::id());
+        let group = match Cgroup::create_child(&name) {
+            Ok(group) => group,
+            // Without cgroup v2 or the permission to create groups.
+            Err(_) => return,
+        };
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.follow_forks();
+        let mut options = SpawnOptions::new();
+        options.cgroup(&group);
+        let pid = session
+            .spawn(Command::new(&path).args(["sleep", "200"]), options)
+            .expect("Error spawning test process");
+        assert_eq!(group.pids().unwrap(), [pid]);
+        ptrace::cont(pid, None).unwrap();
+        group.freeze().expect("Error freezing");
+        assert!(group.is_frozen().unwrap());
+        // Nothing happens while the group is frozen, even once the tracee
+        // would have finished sleeping.
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(session.try_wait_for(pid).unwrap(), None);
+        group.thaw().expect("Error thawing");
+        match session.wait_for(pid).expect("Error waiting") {
+            (_, Event::Exited(0)) => {}
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        let group_path = group.path().to_owned();
+        drop(group);
+        assert!(!group_path.exists());
+    }
+}
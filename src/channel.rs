@@ -0,0 +1,262 @@
+//! Delivering a session's events over channels from a tracer thread.
+
+use crate::{Event, TraceSession};
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// An event delivered by an [`EventChannel`].
+///
+/// [`EventChannel`]: struct.EventChannel.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelEvent {
+    /// The tracee the event is from.
+    pub pid: Pid,
+    /// What happened.
+    pub event: Event,
+    /// When the event's status was collected, as from
+    /// [`TraceSession::event_time`].
+    ///
+    /// [`TraceSession::event_time`]: struct.TraceSession.html#method.event_time
+    pub time: Instant,
+}
+
+/// How an [`EventChannel`], a [`TracePool`] or [`TraceSession::run_with`]
+/// should resume a stopped tracee.
+///
+/// [`EventChannel`]: struct.EventChannel.html
+/// [`TracePool`]: struct.TracePool.html
+/// [`TraceSession::run_with`]: struct.TraceSession.html#method.run_with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resume {
+    /// Resume with `PTRACE_CONT`, delivering the signal if there is one.
+    Continue(Option<Signal>),
+    /// Resume with `PTRACE_SYSCALL`, delivering the signal if there is one.
+    Syscall(Option<Signal>),
+    /// Keep a tracee in a group-stop stopped with `PTRACE_LISTEN`.
+    Listen,
+    /// Remove the tracee from the session and detach from it, delivering the
+    /// signal if there is one.
+    Detach(Option<Signal>),
+    /// Kill the tracee with `SIGKILL`. Its death is delivered as a last
+    /// `Event::Signaled`.
+    Kill,
+}
+
+/// A [`TraceSession`] run by a tracer thread of its own, which delivers
+/// the session's events over a channel and takes back a decision for each.
+///
+/// This lets application code consume trace events like any other stream
+/// of messages. Since ptrace requests must come from the thread that
+/// attached, the session is set up on the tracer thread by the closure
+/// given to [`start`], which should configure it and spawn or attach its
+/// tracees.
+///
+/// The tracees that are in the session once it has been set up are
+/// delivered first, each as an `Event::Attached` from its initial stop.
+/// After every event except a tracee's `Event::Exited` or
+/// `Event::Signaled`, the tracer thread waits for a [`Resume`] from
+/// [`resume`] before it waits for the next event, so at most one event is
+/// outstanding at a time. [`recv`] returns `None` once every tracee has
+/// exited, and an error ends the trace after being delivered.
+///
+/// Dropping the channel ends the trace at the next event, when the tracees
+/// that are left are dropped with their [`DropPolicy`]. The tracer thread
+/// isn't joined, since tracees that never stop would keep it waiting.
+///
+/// [`TraceSession`]: struct.TraceSession.html
+/// [`start`]: #method.start
+/// [`Resume`]: enum.Resume.html
+/// [`resume`]: #method.resume
+/// [`recv`]: #method.recv
+/// [`DropPolicy`]: enum.DropPolicy.html
+#[derive(Debug)]
+pub struct EventChannel {
+    events: Receiver<io::Result<ChannelEvent>>,
+    control: Sender<Resume>,
+}
+
+impl EventChannel {
+    /// Start a tracer thread, which creates a session, sets it up with
+    /// `setup` and then delivers its events.
+    ///
+    /// Returns the error from `setup` if it fails.
+    pub fn start<F>(setup: F) -> io::Result<EventChannel>
+    where
+        F: FnOnce(&mut TraceSession) -> io::Result<()> + Send + 'static,
+    {
+        let (ready_sender, ready) = mpsc::channel();
+        let (event_sender, events) = mpsc::channel();
+        let (control, control_receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("tracer".to_string())
+            .spawn(move || {
+                let mut session = TraceSession::new();
+                let setup = setup(&mut session);
+                let ok = setup.is_ok();
+                if ready_sender.send(setup).is_err() || !ok {
+                    return;
+                }
+                let tracer = Tracer {
+                    session,
+                    control: control_receiver,
+                    events: event_sender,
+                };
+                tracer.run();
+            })?;
+        match ready.recv() {
+            Ok(setup) => setup.map(|()| EventChannel { events, control }),
+            Err(_) => Err(io::Error::other("The tracer thread panicked")),
+        }
+    }
+
+    /// Wait for the next event, or return `None` once the trace is over.
+    pub fn recv(&self) -> Option<io::Result<ChannelEvent>> {
+        self.events.recv().ok()
+    }
+
+    /// Wait up to `timeout` for the next event.
+    ///
+    /// Returns `Ok(None)` if the timeout expired, and `Err` with
+    /// `RecvTimeoutError::Disconnected` once the trace is over.
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<io::Result<ChannelEvent>>, RecvTimeoutError> {
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => Ok(Some(event)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Resume the tracee of the last event delivered as `decision` says.
+    ///
+    /// Fails with `ErrorKind::BrokenPipe` if the trace is over.
+    pub fn resume(&self, decision: Resume) -> io::Result<()> {
+        self.control.send(decision).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "The tracer thread has finished")
+        })
+    }
+}
+
+/// The state of the tracer thread.
+///
+/// The fields are dropped in order, so the session's tracees are dropped and
+/// `resume` fails before `recv` reports the end of the trace.
+struct Tracer {
+    session: TraceSession,
+    control: Receiver<Resume>,
+    events: Sender<io::Result<ChannelEvent>>,
+}
+
+impl Tracer {
+    fn run(mut self) {
+        let pids: Vec<Pid> = self.session.pids().collect();
+        for pid in pids {
+            let event = ChannelEvent {
+                pid,
+                event: Event::Attached,
+                time: Instant::now(),
+            };
+            if !self.deliver(event) {
+                return;
+            }
+        }
+        while !self.session.is_empty() {
+            let event = match self.session.wait_any() {
+                Ok((pid, event)) => ChannelEvent {
+                    pid,
+                    event,
+                    time: self.session.event_time().unwrap_or_else(Instant::now),
+                },
+                Err(e) => {
+                    let _ = self.events.send(Err(e));
+                    return;
+                }
+            };
+            if !self.deliver(event) {
+                return;
+            }
+        }
+    }
+
+    /// Send `event` and carry out the decision for it, returning whether the
+    /// trace should go on.
+    fn deliver(&mut self, event: ChannelEvent) -> bool {
+        if self.events.send(Ok(event)).is_err() {
+            return false;
+        }
+        if let Event::Exited(_) | Event::Signaled(..) = event.event {
+            return true;
+        }
+        let decision = match self.control.recv() {
+            Ok(decision) => decision,
+            Err(_) => return false,
+        };
+        match self.session.resume_as(event.pid, decision) {
+            Ok(()) => true,
+            Err(e) => {
+                let _ = self.events.send(Err(e));
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_event_channel() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let channel = EventChannel::start(move |session| {
+            session.trace_syscalls();
+            session.spawn(
+                Command::new(&path).stdout(Stdio::null()),
+                SpawnOptions::new(),
+            )?;
+            Ok(())
+        })
+        .expect("Error starting channel");
+        let mut events = vec![];
+        while let Some(event) = channel.recv() {
+            let event = event.expect("Error tracing");
+            events.push(event.event);
+            match event.event {
+                Event::Signal(signal) => channel.resume(Resume::Syscall(Some(signal))),
+                Event::Exited(_) => Ok(()),
+                _ => channel.resume(Resume::Syscall(None)),
+            }
+            .expect("Error resuming");
+        }
+        assert_eq!(events.first(), Some(&Event::Attached));
+        assert_eq!(events.last(), Some(&Event::Exited(0)));
+        assert!(events.iter().filter(|&&e| e == Event::Syscall).count() > 10);
+        // The trace is over.
+        assert_eq!(
+            channel.resume(Resume::Continue(None)).unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn test_event_channel_setup_error() {
+        let e = EventChannel::start(|session| {
+            session.spawn(
+                &mut Command::new("/nonexistent/program"),
+                SpawnOptions::new(),
+            )?;
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+}
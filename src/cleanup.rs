@@ -0,0 +1,238 @@
+//! Cleaning up tracees when a `Tracee` is dropped or the tracer panics.
+
+use nix::errno::Errno;
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{gettid, Pid};
+use std::os::unix::io::RawFd;
+use std::panic;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// What happens to a tracee when its [`Tracee`] is dropped, set with
+/// [`SpawnOptions::drop_policy`] or [`Tracee::set_drop_policy`].
+///
+/// [`Tracee`]: struct.Tracee.html
+/// [`SpawnOptions::drop_policy`]: struct.SpawnOptions.html#method.drop_policy
+/// [`Tracee::set_drop_policy`]: struct.Tracee.html#method.set_drop_policy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Leave the tracee as it is. If it is stopped, it stays stopped until
+    /// the tracer exits, when the kernel detaches it.
+    #[default]
+    Leave,
+    /// Detach from the tracee and let it carry on running. This only works
+    /// from the thread that traces it.
+    Detach,
+    /// Kill the tracee with `SIGKILL` and reap it. This needs a pidfd, so
+    /// that a pid that has been reused is never killed, and does nothing on
+    /// kernels older than 5.3.
+    Kill,
+}
+
+/// A tracee whose policy should also be applied by the panic hook.
+struct Guarded {
+    pid: Pid,
+    /// The thread that traces it.
+    tracer: Pid,
+    pidfd: Option<RawFd>,
+    policy: DropPolicy,
+}
+
+static GUARDED: Mutex<Vec<Guarded>> = Mutex::new(Vec::new());
+static HOOK_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+fn guarded() -> MutexGuard<'static, Vec<Guarded>> {
+    GUARDED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Install a panic hook that applies the drop policy of every live
+/// [`Tracee`] traced by the panicking thread before the panic goes on.
+///
+/// Unwinding drops tracees anyway, but not ones that are leaked, held
+/// elsewhere, or in a program built with `panic = "abort"`, which would
+/// otherwise be left stopped. Tracees of other threads are left alone, since
+/// those threads may carry on. The previous hook is called afterwards, and
+/// installing again does nothing.
+///
+/// [`Tracee`]: struct.Tracee.html
+pub fn install_panic_hook() {
+    if HOOK_INSTALLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let tracer = gettid();
+        let mut tracees = vec![];
+        guarded().retain(|tracee| {
+            if tracee.tracer != tracer {
+                return true;
+            }
+            tracees.push((tracee.pid, tracee.pidfd, tracee.policy));
+            false
+        });
+        for (pid, pidfd, policy) in tracees {
+            apply(pid, pidfd, policy);
+        }
+        previous(info);
+    }));
+}
+
+/// Let the panic hook apply `policy` to `pid`, which is traced by this
+/// thread, or forget it with `DropPolicy::Leave`.
+pub(crate) fn guard(pid: Pid, pidfd: Option<RawFd>, policy: DropPolicy) {
+    let mut tracees = guarded();
+    tracees.retain(|tracee| tracee.pid != pid);
+    if policy != DropPolicy::Leave {
+        tracees.push(Guarded {
+            pid,
+            tracer: gettid(),
+            pidfd,
+            policy,
+        });
+    }
+}
+
+/// Apply `policy` to tracee `pid`, ignoring errors, since this runs while
+/// cleaning up.
+pub(crate) fn apply(pid: Pid, pidfd: Option<RawFd>, policy: DropPolicy) {
+    match policy {
+        DropPolicy::Leave => {}
+        DropPolicy::Detach => detach(pid),
+        DropPolicy::Kill => {
+            if let Some(pidfd) = pidfd {
+                // Only a process that hasn't been reaped can be signalled,
+                // so it is safe to wait for it afterwards.
+                if send_signal(pidfd, Signal::SIGKILL) {
+                    reap(pid);
+                }
+            }
+        }
+    }
+}
+
+fn detach(pid: Pid) {
+    match ptrace::detach(pid, None) {
+        // A running tracee has to be stopped first.
+        Err(nix::Error::Sys(Errno::ESRCH)) => {}
+        _ => return,
+    }
+    if nix::sys::signal::kill(pid, Signal::SIGSTOP).is_err() {
+        return;
+    }
+    loop {
+        match waitpid(pid, Some(WaitPidFlag::__WALL)) {
+            Ok(WaitStatus::Stopped(_, Signal::SIGSTOP)) => break,
+            // Let other signals through while waiting for ours.
+            Ok(WaitStatus::Stopped(_, signal)) => {
+                if ptrace::cont(pid, signal).is_err() {
+                    return;
+                }
+            }
+            Ok(WaitStatus::Exited(..)) | Ok(WaitStatus::Signaled(..)) | Err(_) => return,
+            Ok(_) => {
+                if ptrace::cont(pid, None).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+    if ptrace::detach(pid, None).is_ok() {
+        let _ = nix::sys::signal::kill(pid, Signal::SIGCONT);
+    }
+}
+
+fn send_signal(pidfd: RawFd, signal: Signal) -> bool {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd,
+            signal as libc::c_int,
+            ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    ret == 0
+}
+
+fn reap(pid: Pid) {
+    while let Ok(status) = waitpid(pid, Some(WaitPidFlag::__WALL)) {
+        if let WaitStatus::Exited(..) | WaitStatus::Signaled(..) = status {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::fs;
+    use std::mem;
+    use std::process::Command;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn tracer_pid(pid: Pid) -> i32 {
+        let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+        let line = status
+            .lines()
+            .find(|l| l.starts_with("TracerPid:"))
+            .unwrap();
+        line["TracerPid:".len()..].trim().parse().unwrap()
+    }
+
+    #[test]
+    fn test_drop_policy() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut options = SpawnOptions::new();
+        options.drop_policy(DropPolicy::Kill);
+        let tracee = Command::new(&path)
+            .spawn_tracee(options)
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        drop(tracee);
+        assert_eq!(waitpid(pid, None), Err(nix::Error::Sys(Errno::ECHILD)));
+
+        let mut tracee = Command::new(&path)
+            .args(["sleep", "5000"])
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        tracee.set_drop_policy(DropPolicy::Detach);
+        ptrace::cont(pid, None).expect("Error continuing child");
+        drop(tracee);
+        assert_eq!(tracer_pid(pid), 0);
+        nix::sys::signal::kill(pid, Signal::SIGKILL).unwrap();
+        assert_eq!(
+            waitpid(pid, None),
+            Ok(WaitStatus::Signaled(pid, Signal::SIGKILL, false))
+        );
+    }
+
+    #[test]
+    fn test_panic_hook() {
+        install_panic_hook();
+        let (sender, receiver) = mpsc::channel();
+        let tracer = thread::spawn(move || {
+            let path = test_process_path().expect("Failed to get test process path");
+            let mut options = SpawnOptions::new();
+            options.drop_policy(DropPolicy::Kill);
+            let tracee = Command::new(&path)
+                .spawn_tracee(options)
+                .expect("Error spawning test process");
+            let pid = tracee.pid();
+            sender.send(pid).unwrap();
+            // Leaked tracees are never dropped, so only the hook sees them.
+            mem::forget(tracee);
+            panic!("Tracer panicked while tracing {}", pid);
+        });
+        assert!(tracer.join().is_err());
+        let pid = receiver.recv().unwrap();
+        assert!(guarded().iter().all(|tracee| tracee.pid != pid));
+        assert_eq!(waitpid(pid, None), Err(nix::Error::Sys(Errno::ECHILD)));
+    }
+}
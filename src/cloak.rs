@@ -0,0 +1,237 @@
+//! Hiding the tracer from a tracee's simple debugger checks.
+
+use crate::accounting::read_tgid;
+use crate::antidebug::own_proc_file;
+use crate::{memory, Event, SyscallInfo, TraceSession, Tracee};
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+const TRACER_PID: &[u8] = b"TracerPid:\t";
+
+/// What a system call in progress will need done at its exit.
+#[derive(Clone, Copy, Debug)]
+enum Pending {
+    /// A skipped `ptrace(PTRACE_TRACEME)`, which should seem to succeed.
+    TraceMe,
+    /// An `open` of the tracee's own `status` file.
+    OpenStatus,
+    /// A read from an open `status` file into the buffer at this address.
+    ReadStatus(u64),
+    Close(i32),
+}
+
+/// Fakes the results of the checks programs make to see whether they are
+/// being debugged, so that they carry on as they would untraced.
+///
+/// Feed every syscall stop to [`record`], or let [`run`] drive a whole
+/// [`TraceSession`]. Two checks are defeated:
+///
+/// - `ptrace(PTRACE_TRACEME)` is skipped and made to return 0, as it would
+///   for a process that isn't traced yet.
+/// - The `TracerPid` line a tracee reads from its own `/proc` `status` file
+///   with `read` or `pread64` is rewritten to show 0.
+///
+/// Only descriptors returned by `open` and `openat` are followed, so copies
+/// made with `dup` and files read with `readv` or through `mmap` are left
+/// alone, as is a `TracerPid` line split across two reads. This is a
+/// research tool for observing software that hides from analysis, and is
+/// only built with the `anti-anti-debug` feature.
+///
+/// [`record`]: #method.record
+/// [`run`]: #method.run
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug, Default)]
+pub struct DebuggerCloak {
+    in_flight: HashMap<Pid, Pending>,
+    tgids: HashMap<Pid, Pid>,
+    /// The descriptors of open `status` files, by process.
+    status_fds: HashSet<(Pid, i32)>,
+}
+
+impl DebuggerCloak {
+    /// Create a cloak with no open `status` files.
+    pub fn new() -> DebuggerCloak {
+        DebuggerCloak::default()
+    }
+
+    /// Handle a syscall stop of `tracee`, changing the system call or its
+    /// result if it is a debugger check.
+    ///
+    /// Call this each time the tracee reports `Event::Syscall`, before
+    /// resuming it.
+    pub fn record(&mut self, tracee: &Tracee) -> io::Result<()> {
+        let tid = tracee.pid();
+        match tracee.syscall_info()? {
+            SyscallInfo::Entry { number, args } => {
+                let pid = self.tgid(tid);
+                let pending = match number as i64 {
+                    libc::SYS_ptrace if args[0] as libc::c_uint == libc::PTRACE_TRACEME => {
+                        let mut regs = tracee.registers()?;
+                        // A system call number of -1 makes the kernel skip it.
+                        regs.orig_rax = u64::MAX;
+                        tracee.set_registers(regs)?;
+                        Pending::TraceMe
+                    }
+                    libc::SYS_openat if own_proc_file(tid, args[1], &["status"]) => {
+                        Pending::OpenStatus
+                    }
+                    libc::SYS_open if own_proc_file(tid, args[0], &["status"]) => {
+                        Pending::OpenStatus
+                    }
+                    libc::SYS_read | libc::SYS_pread64
+                        if self.status_fds.contains(&(pid, args[0] as i32)) =>
+                    {
+                        Pending::ReadStatus(args[1])
+                    }
+                    libc::SYS_close => Pending::Close(args[0] as i32),
+                    _ => {
+                        self.in_flight.remove(&tid);
+                        return Ok(());
+                    }
+                };
+                self.in_flight.insert(tid, pending);
+            }
+            SyscallInfo::Exit { value, is_error } => {
+                let pending = match self.in_flight.remove(&tid) {
+                    Some(pending) => pending,
+                    None => return Ok(()),
+                };
+                let pid = self.tgid(tid);
+                match pending {
+                    Pending::TraceMe => {
+                        let mut regs = tracee.registers()?;
+                        regs.rax = 0;
+                        tracee.set_registers(regs)?;
+                    }
+                    Pending::OpenStatus if !is_error => {
+                        self.status_fds.insert((pid, value as i32));
+                    }
+                    Pending::ReadStatus(buf) if !is_error && value > 0 => {
+                        let mut data = vec![0; value as usize];
+                        memory::read(tid, buf, &mut data)?;
+                        if hide_tracer(&mut data) {
+                            memory::write(tid, buf, &data)?;
+                            let mut regs = tracee.registers()?;
+                            regs.rax = data.len() as u64;
+                            tracee.set_registers(regs)?;
+                        }
+                    }
+                    Pending::Close(fd) => {
+                        self.status_fds.remove(&(pid, fd));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Forget any system call in progress for `pid`, for example because it
+    /// exited.
+    pub fn forget(&mut self, pid: Pid) {
+        self.in_flight.remove(&pid);
+        if self.tgids.remove(&pid) == Some(pid) {
+            self.status_fds.retain(|&(p, _)| p != pid);
+        }
+    }
+
+    /// Hide the tracer from the tracees in `session`, running it with
+    /// [`TraceSession::run`] until they have all exited.
+    ///
+    /// The session should trace system calls, with [`trace_syscalls`].
+    ///
+    /// [`TraceSession::run`]: struct.TraceSession.html#method.run
+    /// [`trace_syscalls`]: struct.TraceSession.html#method.trace_syscalls
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        session.run(|session, pid, event| {
+            match event {
+                Event::Syscall | Event::AntiDebug(_) => {
+                    if let Some(tracee) = session.get(pid) {
+                        self.record(tracee)?;
+                    }
+                }
+                Event::Exited(_) | Event::Signaled(..) => self.forget(pid),
+                _ => {}
+            }
+            Ok(())
+        })
+    }
+
+    /// The process that thread `tid` belongs to.
+    fn tgid(&mut self, tid: Pid) -> Pid {
+        *self
+            .tgids
+            .entry(tid)
+            .or_insert_with(|| read_tgid(tid).unwrap_or(tid))
+    }
+}
+
+/// Replace the pid on a `TracerPid` line in `data` with 0, returning whether
+/// it changed.
+fn hide_tracer(data: &mut Vec<u8>) -> bool {
+    let start = match data.windows(TRACER_PID.len()).position(|w| w == TRACER_PID) {
+        Some(at) => at + TRACER_PID.len(),
+        None => return false,
+    };
+    let len = data[start..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    if len == 0 || &data[start..start + len] == b"0" {
+        return false;
+    }
+    data.splice(start..start + len, b"0".iter().cloned());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use nix::sys::ptrace;
+    use std::process::Command;
+
+    #[test]
+    fn test_hide_tracer() {
+        let mut data = b"TracerPid:\t1234\nUid:\t0".to_vec();
+        assert!(hide_tracer(&mut data));
+        assert_eq!(data, b"TracerPid:\t0\nUid:\t0");
+        assert!(!hide_tracer(&mut data));
+    }
+
+    fn run_debugged(mut cloak: Option<DebuggerCloak>) -> i32 {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        let pid = session
+            .spawn(Command::new(&path).arg("debugged"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut signal = None;
+        loop {
+            ptrace::syscall(pid, signal).expect("Error resuming tracee");
+            signal = None;
+            match session.wait_for(pid).expect("Error waiting for tracee") {
+                (_, Event::Syscall) => {
+                    if let Some(cloak) = cloak.as_mut() {
+                        cloak
+                            .record(session.get(pid).unwrap())
+                            .expect("Error cloaking");
+                    }
+                }
+                (_, Event::Signal(sig)) => signal = Some(sig),
+                (_, Event::Exited(code)) => return code,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_debugger_cloak() {
+        // Both checks see the tracer, until it is hidden.
+        assert_eq!(run_debugged(None), 3);
+        assert_eq!(run_debugged(Some(DebuggerCloak::new())), 0);
+    }
+}
@@ -0,0 +1,413 @@
+//! Instruction coverage of a tracee, collected by single-stepping it.
+
+use crate::{maps, nix_error, AflMap, Event, TraceSession};
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The size of the edge map, which is the default size of AFL's.
+pub const EDGE_MAP_SIZE: usize = 1 << 16;
+
+/// The instructions of one mapped object that a tracee executed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModuleCoverage {
+    path: PathBuf,
+    base: u64,
+    /// A bit for each byte offset from `base`.
+    bits: Vec<u64>,
+}
+
+impl ModuleCoverage {
+    /// The object's file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The address its first byte was mapped at, which offsets are from.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Whether an instruction at `offset` from the base was executed.
+    pub fn contains(&self, offset: u64) -> bool {
+        let word = (offset / 64) as usize;
+        word < self.bits.len() && self.bits[word] & (1 << (offset % 64)) != 0
+    }
+
+    /// The offsets of the executed instructions, in increasing order.
+    pub fn offsets(&self) -> impl Iterator<Item = u64> + '_ {
+        self.bits.iter().enumerate().flat_map(|(word, &bits)| {
+            (0..64)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| word as u64 * 64 + bit)
+        })
+    }
+
+    /// The bitmap of executed instructions, with bit `i % 64` of word
+    /// `i / 64` set if the instruction at offset `i` was executed.
+    pub fn bitmap(&self) -> &[u64] {
+        &self.bits
+    }
+
+    fn insert(&mut self, offset: u64) {
+        let word = (offset / 64) as usize;
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        self.bits[word] |= 1 << (offset % 64);
+    }
+}
+
+/// Where an executable mapping is, and which module it belongs to, if it
+/// belongs to a file.
+#[derive(Debug)]
+struct Region {
+    start: u64,
+    end: u64,
+    module: Option<usize>,
+}
+
+/// Collects the instructions a tracee thread executes, by single-stepping
+/// it.
+///
+/// Each instruction is recorded in the [`ModuleCoverage`] of the object it
+/// is in, as an offset from where the object is mapped, so runs with
+/// different load addresses can be compared. Transfers of control are also
+/// counted in an edge map like AFL's, indexed by the hashes of the
+/// instructions before and after, where an instruction that doesn't follow
+/// the one before within the length of an x86 instruction counts as the
+/// start of a block. Code outside of mapped files, like JIT-compiled code,
+/// is stepped through but isn't recorded.
+///
+/// Single-stepping makes the tracee run thousands of times slower than
+/// normal, so trace just the part of interest, between [`stop_at`]
+/// addresses or for a number of [`max_steps`]. Software breakpoints are
+/// executed like any other instruction, so remove them first.
+///
+/// [`ModuleCoverage`]: struct.ModuleCoverage.html
+/// [`stop_at`]: #method.stop_at
+/// [`max_steps`]: #method.max_steps
+#[derive(Debug)]
+pub struct Coverage {
+    modules: Vec<ModuleCoverage>,
+    regions: Vec<Region>,
+    edges: Edges,
+    /// The previous instruction, and its block's hash shifted right by one.
+    previous: Option<(u64, u64)>,
+    steps: u64,
+    max_steps: Option<u64>,
+    stop_at: Vec<u64>,
+}
+
+impl Coverage {
+    /// Create a collector with nothing recorded, and no stop conditions.
+    pub fn new() -> Coverage {
+        Coverage {
+            modules: vec![],
+            regions: vec![],
+            edges: Edges::Own(vec![0; EDGE_MAP_SIZE]),
+            previous: None,
+            steps: 0,
+            max_steps: None,
+            stop_at: vec![],
+        }
+    }
+
+    /// Stop [`trace`] after `steps` more instructions, counted from each
+    /// call.
+    ///
+    /// [`trace`]: #method.trace
+    pub fn max_steps(&mut self, steps: u64) -> &mut Coverage {
+        self.max_steps = Some(steps);
+        self
+    }
+
+    /// Stop [`trace`] when the thread gets to `addr`, before it executes
+    /// the instruction there.
+    ///
+    /// [`trace`]: #method.trace
+    pub fn stop_at(&mut self, addr: u64) -> &mut Coverage {
+        self.stop_at.push(addr);
+        self
+    }
+
+    /// Single-step the stopped thread `pid` of `session` and record each
+    /// instruction it executes, until a stop condition is met or another
+    /// event is reported.
+    ///
+    /// Returns `Event::Signal(SIGTRAP)` once the thread is stopped at a
+    /// [`stop_at`] address or has taken [`max_steps`] steps, or the event
+    /// that ended the trace early, such as a signal or the tracee's exit.
+    /// Call it again to continue collecting after handling the event, for
+    /// example by delivering the signal. Without stop conditions it runs
+    /// until another event.
+    ///
+    /// [`stop_at`]: #method.stop_at
+    /// [`max_steps`]: #method.max_steps
+    pub fn trace(&mut self, session: &mut TraceSession, pid: Pid) -> io::Result<Event> {
+        let mut steps = 0;
+        loop {
+            let rip = ptrace::getregs(pid).map_err(nix_error)?.rip;
+            if steps > 0 && self.stop_at.contains(&rip) {
+                return Ok(Event::Signal(Signal::SIGTRAP));
+            }
+            self.record(pid, rip)?;
+            session.step(pid, None)?;
+            let (_, event) = session.wait_for(pid)?;
+            steps += 1;
+            self.steps += 1;
+            match event {
+                Event::Signal(Signal::SIGTRAP) => {}
+                // The address space is new.
+                Event::Exec(_) => {
+                    self.regions.clear();
+                    self.previous = None;
+                    return Ok(event);
+                }
+                _ => return Ok(event),
+            }
+            if Some(steps) == self.max_steps {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Count edges in `map`, the shared memory of an AFL fuzzer, instead
+    /// of in a map of their own, replacing any that were counted before.
+    ///
+    /// The edges are spread over however large the fuzzer made the map.
+    pub fn afl_map(&mut self, map: AflMap) -> &mut Coverage {
+        self.edges = Edges::Afl(map);
+        self.previous = None;
+        self
+    }
+
+    /// The objects that instructions were executed in.
+    pub fn modules(&self) -> &[ModuleCoverage] {
+        &self.modules
+    }
+
+    /// The edge map, which has `EDGE_MAP_SIZE` counters that wrap around
+    /// like AFL's, or is the [`afl_map`].
+    ///
+    /// [`afl_map`]: #method.afl_map
+    pub fn edges(&self) -> &[u8] {
+        match self.edges {
+            Edges::Own(ref edges) => edges,
+            Edges::Afl(ref map) => map.as_slice(),
+        }
+    }
+
+    /// The number of instructions stepped in total.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn record(&mut self, pid: Pid, rip: u64) -> io::Result<()> {
+        let region = match self.region(rip) {
+            Some(region) => region,
+            None => {
+                // Code that was mapped since the mappings were last read.
+                self.read_regions(pid)?;
+                self.region(rip).flatten()
+            }
+        };
+        let region = match region {
+            Some(region) => region,
+            None => {
+                self.previous = None;
+                return Ok(());
+            }
+        };
+        let module = &mut self.modules[region];
+        let offset = rip - module.base;
+        module.insert(offset);
+        // A block starts wherever the instruction before doesn't fall
+        // through to this one.
+        let follows = |(previous, _): (u64, u64)| rip > previous && rip - previous <= 15;
+        if self.previous.is_some_and(follows) {
+            self.previous = self.previous.map(|(_, hash)| (rip, hash));
+            return Ok(());
+        }
+        let edges = match self.edges {
+            Edges::Own(ref mut edges) => &mut edges[..],
+            Edges::Afl(ref mut map) => map.as_mut_slice(),
+        };
+        if edges.is_empty() {
+            return Ok(());
+        }
+        let hash = block_hash(region, offset, edges.len());
+        let previous_hash = self.previous.map_or(0, |(_, hash)| hash);
+        let edge = &mut edges[(hash ^ previous_hash) as usize % edges.len()];
+        *edge = edge.wrapping_add(1);
+        self.previous = Some((rip, hash >> 1));
+        Ok(())
+    }
+
+    /// The module of the region `addr` is in, if it is in a known one.
+    fn region(&self, addr: u64) -> Option<Option<usize>> {
+        self.regions
+            .iter()
+            .find(|region| region.start <= addr && addr < region.end)
+            .map(|region| region.module)
+    }
+
+    fn read_regions(&mut self, pid: Pid) -> io::Result<()> {
+        self.regions.clear();
+        let maps = maps::read_maps(pid)?;
+        for map in maps.iter().filter(|m| m.executable) {
+            let path = match map.pathname {
+                Some(ref path) if path.starts_with('/') => path,
+                _ => {
+                    self.regions.push(Region {
+                        start: map.start,
+                        end: map.end,
+                        module: None,
+                    });
+                    continue;
+                }
+            };
+            // Objects are based at their first mapping.
+            let base = maps
+                .iter()
+                .filter(|m| m.pathname.as_ref() == Some(path) && m.start <= map.start)
+                .map(|m| m.start)
+                .min()
+                .unwrap_or(map.start);
+            let module = match self
+                .modules
+                .iter()
+                .position(|m| m.base == base && m.path == Path::new(path))
+            {
+                Some(module) => module,
+                None => {
+                    self.modules.push(ModuleCoverage {
+                        path: PathBuf::from(path),
+                        base,
+                        bits: vec![],
+                    });
+                    self.modules.len() - 1
+                }
+            };
+            self.regions.push(Region {
+                start: map.start,
+                end: map.end,
+                module: Some(module),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+/// Where the edges are counted.
+#[derive(Debug)]
+enum Edges {
+    Own(Vec<u8>),
+    Afl(AflMap),
+}
+
+/// A hash of the block at `offset` in module number `module`, spread over
+/// an edge map of `size` counters.
+fn block_hash(module: usize, offset: u64, size: usize) -> u64 {
+    let key = offset ^ (module as u64).rotate_left(40);
+    // The finalizer of MurmurHash3.
+    let mut hash = key ^ (key >> 33);
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash % size as u64
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::afl::tests::{create_segment, remove_segment};
+    use crate::inject;
+    use crate::tests::test_process_path;
+    use crate::{Breakpoints, SpawnOptions};
+    use std::process::Command;
+
+    /// Spawn the test program and run it to the `spawn_ptrace_caller`
+    /// function, which is returned with the leaf function it calls.
+    pub(crate) fn run_to_caller() -> (TraceSession, Pid, u64, u64) {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        let pid = session
+            .spawn(Command::new(&path).arg("calls"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut breakpoints = Breakpoints::new();
+        let caller = breakpoints
+            .insert_symbol(pid, "spawn_ptrace_caller")
+            .expect("Error inserting breakpoint");
+        ptrace::cont(pid, None).unwrap();
+        session.wait_for(pid).unwrap();
+        assert_eq!(breakpoints.hit(pid).unwrap(), Some(caller));
+        breakpoints.remove(pid, caller).unwrap();
+        let leaf = inject::find_function(pid, "spawn_ptrace_leaf")
+            .unwrap()
+            .expect("No leaf function");
+        (session, pid, caller, leaf)
+    }
+
+    #[test]
+    fn test_coverage() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let (mut session, pid, caller, leaf) = run_to_caller();
+        let mut coverage = Coverage::new();
+        coverage.stop_at(leaf);
+        let event = coverage.trace(&mut session, pid).unwrap();
+        assert_eq!(event, Event::Signal(Signal::SIGTRAP));
+        assert_eq!(ptrace::getregs(pid).unwrap().rip, leaf);
+        let steps = coverage.steps();
+        assert!(steps > 1);
+
+        // The loop runs the same instructions over and over.
+        coverage.max_steps(2000);
+        coverage.trace(&mut session, pid).unwrap();
+        assert_eq!(coverage.steps(), steps + 2000);
+        let module = coverage
+            .modules()
+            .iter()
+            .find(|m| m.path() == path)
+            .expect("No coverage of the test program");
+        assert!(module.contains(caller - module.base()));
+        assert!(module.contains(leaf - module.base()));
+        let count = module.offsets().count();
+        assert!(count > 10 && (count as u64) < steps + 2000, "{}", count);
+        assert!(coverage.edges().iter().any(|&hits| hits > 1));
+
+        ptrace::cont(pid, None).unwrap();
+        let (_, event) = session.wait_for(pid).unwrap();
+        assert_eq!(event, Event::Exited(0));
+    }
+
+    #[test]
+    fn test_coverage_afl_map() {
+        let id = create_segment(1 << 12);
+        let (mut session, pid, _, _) = run_to_caller();
+        let mut coverage = Coverage::new();
+        coverage
+            .afl_map(AflMap::attach(id).unwrap())
+            .max_steps(1000);
+        coverage.trace(&mut session, pid).unwrap();
+        // The fuzzer sees the edges in its own attachment.
+        let map = AflMap::attach(id).unwrap();
+        assert_eq!(coverage.edges(), map.as_slice());
+        assert_eq!(map.as_slice().len(), 1 << 12);
+        assert!(map.as_slice().iter().any(|&hits| hits > 0));
+        drop(map);
+        drop(coverage);
+        remove_segment(id);
+
+        ptrace::cont(pid, None).unwrap();
+        let (_, event) = session.wait_for(pid).unwrap();
+        assert_eq!(event, Event::Exited(0));
+    }
+}
@@ -0,0 +1,254 @@
+//! The working directory of each tracee process.
+
+use crate::accounting::read_tgid;
+use crate::{Event, FdTable, SyscallInfo, TraceSession, Tracee};
+use nix::unistd::Pid;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Keeps the working directory of each tracee process up to date, so that
+/// the relative paths system calls are given can be resolved as they were
+/// when the call was made, rather than when the trace is read.
+///
+/// Feed every stop to [`record`], or let [`run`] drive a whole
+/// [`TraceSession`]. Each process's directory is read from
+/// `/proc/<pid>/cwd` the first time it is seen, and again after each
+/// `chdir` or `fchdir` that succeeds. Children of `fork` start in their
+/// parent's directory, and threads share their process's, as with
+/// `CLONE_FS`, so every thread that changes directory must be traced.
+///
+/// [`resolve`] and [`resolve_at`] only join paths, without following
+/// symbolic links or removing `..`, which would need the filesystem as it
+/// was at the call. A `chroot` isn't followed, so absolute paths are taken
+/// to be in the tracer's root.
+///
+/// [`record`]: #method.record
+/// [`run`]: #method.run
+/// [`resolve`]: #method.resolve
+/// [`resolve_at`]: #method.resolve_at
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug, Default)]
+pub struct WorkingDirs {
+    /// The threads with a `chdir` or `fchdir` in progress.
+    in_flight: HashSet<Pid>,
+    tgids: HashMap<Pid, Pid>,
+    dirs: HashMap<Pid, PathBuf>,
+}
+
+impl WorkingDirs {
+    /// Create a model that knows no processes.
+    pub fn new() -> WorkingDirs {
+        WorkingDirs::default()
+    }
+
+    /// Update the working directories for `event`, reported by `tracee`.
+    ///
+    /// Call this for every stop, before resuming the tracee.
+    pub fn record(&mut self, tracee: &Tracee, event: Event) -> io::Result<()> {
+        let tid = tracee.pid();
+        match event {
+            Event::Syscall => self.record_syscall(tracee)?,
+            Event::Fork(child) | Event::Vfork(child) | Event::Clone(child) => {
+                let pid = self.tgid(tid);
+                let child_pid = self.tgid(child);
+                if child_pid != pid {
+                    if let Some(dir) = self.cwd_of(pid).map(Path::to_owned) {
+                        self.dirs.insert(child_pid, dir);
+                    }
+                }
+            }
+            Event::Exec(former) => {
+                self.forget(former);
+                self.in_flight.remove(&tid);
+            }
+            Event::Exited(_) | Event::Signaled(..) => {
+                if self.tgid(tid) == tid {
+                    self.dirs.remove(&tid);
+                }
+                self.forget(tid);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Forget any system call in progress for thread `pid`, for example
+    /// because it exited.
+    pub fn forget(&mut self, pid: Pid) {
+        self.in_flight.remove(&pid);
+        self.tgids.remove(&pid);
+    }
+
+    /// Read the working directory of process `pid` from `/proc` again.
+    pub fn reload(&mut self, pid: Pid) -> io::Result<()> {
+        let dir = fs::read_link(format!("/proc/{}/cwd", pid))?;
+        self.dirs.insert(pid, dir);
+        Ok(())
+    }
+
+    /// Follow the working directories of the tracees in `session` with
+    /// [`TraceSession::run`] until they have all exited.
+    ///
+    /// The session should trace system calls, with [`trace_syscalls`].
+    ///
+    /// [`TraceSession::run`]: struct.TraceSession.html#method.run
+    /// [`trace_syscalls`]: struct.TraceSession.html#method.trace_syscalls
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        session.run(|session, pid, event| match session.get(pid) {
+            Some(tracee) => self.record(tracee, event),
+            None => {
+                if self.tgids.get(&pid) == Some(&pid) {
+                    self.dirs.remove(&pid);
+                }
+                self.forget(pid);
+                Ok(())
+            }
+        })
+    }
+
+    /// The working directory of process `pid`, if it has been seen.
+    pub fn cwd(&self, pid: Pid) -> Option<&Path> {
+        self.dirs.get(&pid).map(PathBuf::as_path)
+    }
+
+    /// Resolve `path`, given to a system call by process `pid`, against its
+    /// working directory.
+    ///
+    /// Absolute paths are returned as they are.
+    pub fn resolve<P: AsRef<Path>>(&self, pid: Pid, path: P) -> Option<PathBuf> {
+        let path = path.as_ref();
+        if path.is_absolute() {
+            return Some(path.to_owned());
+        }
+        self.cwd(pid).map(|dir| dir.join(path))
+    }
+
+    /// Resolve `path`, given to a system call such as `openat` by process
+    /// `pid` along with `dirfd`, looking the directory up in `fds` unless
+    /// it is `AT_FDCWD`.
+    pub fn resolve_at<P: AsRef<Path>>(
+        &self,
+        pid: Pid,
+        dirfd: i32,
+        path: P,
+        fds: &FdTable,
+    ) -> Option<PathBuf> {
+        let path = path.as_ref();
+        if path.is_absolute() || dirfd == libc::AT_FDCWD {
+            return self.resolve(pid, path);
+        }
+        fds.target(pid, dirfd).map(|dir| dir.join(path))
+    }
+
+    fn record_syscall(&mut self, tracee: &Tracee) -> io::Result<()> {
+        let tid = tracee.pid();
+        let pid = self.tgid(tid);
+        // Read the directory before the call changes it.
+        self.cwd_of(pid);
+        match tracee.syscall_info()? {
+            SyscallInfo::Entry { number, .. } => match number as i64 {
+                libc::SYS_chdir | libc::SYS_fchdir => {
+                    self.in_flight.insert(tid);
+                }
+                _ => {
+                    self.in_flight.remove(&tid);
+                }
+            },
+            SyscallInfo::Exit { is_error, .. } => {
+                let changed = self.in_flight.remove(&tid);
+                if changed && !is_error {
+                    // The kernel has resolved the new directory already.
+                    let dir = fs::read_link(format!("/proc/{}/cwd", tid))?;
+                    self.dirs.insert(pid, dir);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The working directory of process `pid`, read from `/proc` if it
+    /// hasn't been seen.
+    fn cwd_of(&mut self, pid: Pid) -> Option<&Path> {
+        if let Entry::Vacant(entry) = self.dirs.entry(pid) {
+            entry.insert(fs::read_link(format!("/proc/{}/cwd", pid)).ok()?);
+        }
+        self.cwd(pid)
+    }
+
+    /// The process that thread `tid` belongs to.
+    fn tgid(&mut self, tid: Pid) -> Pid {
+        *self
+            .tgids
+            .entry(tid)
+            .or_insert_with(|| read_tgid(tid).unwrap_or(tid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use nix::sys::ptrace;
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    use std::process::Command;
+
+    #[test]
+    fn test_working_dirs() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let start = std::env::temp_dir();
+        let dir = start.join(format!("spawn-ptrace-cwd-{}", std::process::id()));
+        fs::create_dir(&dir).expect("Error creating directory");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        let pid = session
+            .spawn(
+                Command::new(&path)
+                    .arg("chdir")
+                    .arg(dir.file_name().unwrap())
+                    .current_dir(&start),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut dirs = WorkingDirs::new();
+        let mut fds = FdTable::new();
+        let mut created = vec![];
+        let mut last = None;
+        ptrace::syscall(pid, None).unwrap();
+        loop {
+            let (_, event) = session.wait_for(pid).unwrap();
+            let signal = match event {
+                Event::Exited(code) => {
+                    assert_eq!(code, 0);
+                    break;
+                }
+                Event::Signal(signal) => Some(signal),
+                _ => None,
+            };
+            let tracee = session.get(pid).unwrap();
+            dirs.record(tracee, event).unwrap();
+            fds.record(tracee, event).unwrap();
+            if let Ok(SyscallInfo::Entry { number, args }) = tracee.syscall_info() {
+                let path = |addr| OsString::from_vec(tracee.read_cstring(addr, 4096).unwrap());
+                match number as i64 {
+                    libc::SYS_openat if args[2] as i32 & libc::O_CREAT != 0 => {
+                        let name = path(args[1]);
+                        created.push(dirs.resolve_at(pid, args[0] as i32, name, &fds).unwrap());
+                    }
+                    libc::SYS_exit_group => last = dirs.cwd(pid).map(Path::to_owned),
+                    _ => {}
+                }
+            }
+            ptrace::syscall(pid, signal).unwrap();
+        }
+        let dir = fs::canonicalize(&dir).unwrap();
+        assert_eq!(created, [dir.join("spawn-ptrace-cwd")]);
+        assert_eq!(last, Some(dir.clone()));
+        fs::remove_dir(&dir).expect("Error removing directory");
+    }
+}
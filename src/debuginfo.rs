@@ -0,0 +1,854 @@
+//! Functions and their local variables from the DWARF debug info in
+//! `.debug_info`.
+
+use crate::dwarf::{
+    for_each_object, invalid, Reader, Sections, DW_FORM_ADDR, DW_FORM_ADDRX, DW_FORM_ADDRX1,
+    DW_FORM_ADDRX4, DW_FORM_BLOCK, DW_FORM_BLOCK1, DW_FORM_BLOCK2, DW_FORM_BLOCK4, DW_FORM_DATA1,
+    DW_FORM_DATA16, DW_FORM_DATA2, DW_FORM_DATA4, DW_FORM_DATA8, DW_FORM_EXPRLOC, DW_FORM_FLAG,
+    DW_FORM_FLAG_PRESENT, DW_FORM_IMPLICIT_CONST, DW_FORM_INDIRECT, DW_FORM_LINE_STRP,
+    DW_FORM_LOCLISTX, DW_FORM_REF1, DW_FORM_REF2, DW_FORM_REF4, DW_FORM_REF8, DW_FORM_REF_ADDR,
+    DW_FORM_REF_SIG8, DW_FORM_REF_SUP4, DW_FORM_REF_SUP8, DW_FORM_REF_UDATA, DW_FORM_RNGLISTX,
+    DW_FORM_SDATA, DW_FORM_SEC_OFFSET, DW_FORM_STRING, DW_FORM_STRP, DW_FORM_STRP_SUP,
+    DW_FORM_STRX, DW_FORM_STRX1, DW_FORM_STRX4, DW_FORM_UDATA,
+};
+use crate::memory;
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::io;
+
+// Tags.
+const DW_TAG_ARRAY_TYPE: u64 = 0x01;
+const DW_TAG_FORMAL_PARAMETER: u64 = 0x05;
+const DW_TAG_LEXICAL_BLOCK: u64 = 0x0b;
+const DW_TAG_POINTER_TYPE: u64 = 0x0f;
+const DW_TAG_REFERENCE_TYPE: u64 = 0x10;
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_TAG_TYPEDEF: u64 = 0x16;
+const DW_TAG_INLINED_SUBROUTINE: u64 = 0x1d;
+const DW_TAG_CONST_TYPE: u64 = 0x26;
+const DW_TAG_SUBPROGRAM: u64 = 0x2e;
+const DW_TAG_VARIABLE: u64 = 0x34;
+const DW_TAG_VOLATILE_TYPE: u64 = 0x35;
+const DW_TAG_PARTIAL_UNIT: u64 = 0x3c;
+
+// Attributes.
+const DW_AT_LOCATION: u64 = 0x02;
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_BYTE_SIZE: u64 = 0x0b;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_AT_FRAME_BASE: u64 = 0x40;
+const DW_AT_TYPE: u64 = 0x49;
+const DW_AT_RANGES: u64 = 0x55;
+
+// Location expression operations.
+const DW_OP_ADDR: u8 = 0x03;
+const DW_OP_DEREF: u8 = 0x06;
+const DW_OP_PLUS_UCONST: u8 = 0x23;
+const DW_OP_REG0: u8 = 0x50;
+const DW_OP_REG31: u8 = 0x6f;
+const DW_OP_BREG0: u8 = 0x70;
+const DW_OP_BREG31: u8 = 0x8f;
+const DW_OP_REGX: u8 = 0x90;
+const DW_OP_FBREG: u8 = 0x91;
+const DW_OP_BREGX: u8 = 0x92;
+const DW_OP_CALL_FRAME_CFA: u8 = 0x9c;
+const DW_OP_STACK_VALUE: u8 = 0x9f;
+
+/// A local variable or parameter of a function in the tracee, with its
+/// value, as found by [`DebugInfo::variables`].
+///
+/// [`DebugInfo::variables`]: struct.DebugInfo.html#method.variables
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    /// The name of its type, as the debug info gives it, like `u64` or
+    /// `const char *`.
+    pub type_name: Option<String>,
+    /// Whether it is one of the function's parameters.
+    pub parameter: bool,
+    /// Where it is in the tracee's memory, unless it is in a register or
+    /// was optimized into a constant.
+    pub address: Option<u64>,
+    /// The bytes of its value, in the tracee's byte order.
+    pub value: Vec<u8>,
+}
+
+/// A variable as described by the debug info.
+#[derive(Debug)]
+struct Local {
+    name: String,
+    parameter: bool,
+    /// The location expression, which is evaluated each time.
+    location: Vec<u8>,
+    /// The offset of its type's entry, until the types are resolved.
+    type_offset: Option<u64>,
+    type_name: Option<String>,
+    size: Option<u64>,
+    /// Where in the function it is in scope, or everywhere if empty.
+    scope: Vec<(u64, u64)>,
+}
+
+#[derive(Debug)]
+struct Function {
+    name: Option<String>,
+    start: u64,
+    end: u64,
+    frame_base: Vec<u8>,
+    bias: u64,
+    locals: Vec<Local>,
+}
+
+/// What a type entry says about the type.
+#[derive(Debug, Default)]
+struct Type {
+    tag: u64,
+    name: Option<String>,
+    size: Option<u64>,
+    inner: Option<u64>,
+}
+
+/// Where a variable's value is.
+enum Location {
+    Memory(u64),
+    Register(u64),
+    Value(u64),
+}
+
+/// The functions of the code mapped into a tracee and their variables,
+/// from the DWARF debug info of its executable and shared objects.
+///
+/// Only what unoptimized code needs is supported: variables whose location
+/// is a single expression made of registers, offsets from the frame base
+/// and fixed addresses, and types whose size the debug info records, so
+/// variables in location lists, split into pieces or of array types are
+/// left out. As with a [`LineTable`], load the debug info again after
+/// `exec`, or once more shared libraries have been loaded.
+///
+/// [`LineTable`]: struct.LineTable.html
+#[derive(Debug, Default)]
+pub struct DebugInfo {
+    /// Sorted by start address.
+    functions: Vec<Function>,
+}
+
+impl DebugInfo {
+    /// Load the debug info of every object mapped into process `pid`, at the
+    /// addresses they are loaded at.
+    pub fn load(pid: Pid) -> io::Result<DebugInfo> {
+        let mut debug_info = DebugInfo::default();
+        for_each_object(pid, |sections, bias| {
+            let count = debug_info.functions.len();
+            let mut types = HashMap::new();
+            if debug_info.add(sections, bias, &mut types).is_err() {
+                debug_info.functions.truncate(count);
+            }
+            for function in &mut debug_info.functions[count..] {
+                for local in &mut function.locals {
+                    if let Some(offset) = local.type_offset.take() {
+                        local.type_name = type_name(&types, offset, 0);
+                        local.size = type_size(&types, offset, 0);
+                    }
+                }
+            }
+        })?;
+        debug_info.functions.sort_by_key(|function| function.start);
+        Ok(debug_info)
+    }
+
+    /// The name of the function with the instruction at `addr`, which for
+    /// Rust and C++ leaves out its namespaces.
+    pub fn function(&self, addr: u64) -> Option<&str> {
+        self.lookup(addr)?.name.as_deref()
+    }
+
+    /// The variables in scope of the function thread `pid` is stopped in,
+    /// with their values read from memory and `regs`, its registers.
+    ///
+    /// Variables are only where the debug info says once the function's
+    /// prologue has run, so at a breakpoint on a function's first
+    /// instruction, step to its first line before reading them. Functions
+    /// whose frame base is the canonical frame address are assumed to keep
+    /// a frame pointer. Returns nothing if there is no debug info for the
+    /// function.
+    pub fn variables(&self, pid: Pid, regs: &libc::user_regs_struct) -> io::Result<Vec<Variable>> {
+        let function = match self.lookup(regs.rip) {
+            Some(function) => function,
+            None => return Ok(vec![]),
+        };
+        let mut variables = vec![];
+        for local in &function.locals {
+            let in_scope = local.scope.is_empty()
+                || local
+                    .scope
+                    .iter()
+                    .any(|&(start, end)| (start..end).contains(&regs.rip));
+            let size = match local.size {
+                Some(size) if in_scope => size as usize,
+                _ => continue,
+            };
+            let location = match evaluate(pid, &local.location, function, regs)? {
+                Some(location) => location,
+                None => continue,
+            };
+            let (address, value) = match location {
+                Location::Memory(addr) => {
+                    let mut value = vec![0; size];
+                    memory::read(pid, addr, &mut value)?;
+                    (Some(addr), value)
+                }
+                Location::Register(value) | Location::Value(value) => {
+                    let bytes = value.to_le_bytes();
+                    (None, bytes[..size.min(bytes.len())].to_vec())
+                }
+            };
+            variables.push(Variable {
+                name: local.name.clone(),
+                type_name: local.type_name.clone(),
+                parameter: local.parameter,
+                address,
+                value,
+            });
+        }
+        Ok(variables)
+    }
+
+    fn lookup(&self, addr: u64) -> Option<&Function> {
+        let index = self
+            .functions
+            .partition_point(|function| function.start <= addr);
+        self.functions[..index]
+            .iter()
+            .rev()
+            .take_while(|function| function.start <= addr)
+            .find(|function| addr < function.end)
+    }
+
+    /// Add the functions of every compilation unit in `sections`, for an
+    /// object loaded with `bias`, and collect their types in `types`.
+    fn add(
+        &mut self,
+        sections: &Sections<'_>,
+        bias: u64,
+        types: &mut HashMap<u64, Type>,
+    ) -> io::Result<()> {
+        let mut reader = match sections.info {
+            Some(info) => Reader::new(info),
+            None => return Ok(()),
+        };
+        while !reader.is_empty() {
+            let (unit, offset_size) = match reader.u32()? {
+                0xffff_ffff => {
+                    let len = reader.u64()?;
+                    (reader.split(len)?, 8)
+                }
+                len => (reader.split(len as u64)?, 4),
+            };
+            // Units that can't be read, such as type units, are skipped.
+            let _ = self.add_unit(unit, offset_size, sections, bias, types);
+        }
+        Ok(())
+    }
+
+    fn add_unit<'a>(
+        &mut self,
+        mut unit: Reader<'a>,
+        offset_size: u8,
+        sections: &'a Sections<'a>,
+        bias: u64,
+        types: &mut HashMap<u64, Type>,
+    ) -> io::Result<()> {
+        // The offset of the unit header, which references are relative to.
+        let base = unit.offset() - if offset_size == 8 { 12 } else { 4 };
+        let version = unit.u16()?;
+        let (abbrev_offset, address_size) = match version {
+            2..=4 => (unit.section_offset(offset_size)?, unit.u8()?),
+            5 => {
+                let unit_type = unit.u8()?;
+                // Only compile units and partial units have code.
+                if unit_type != 1 && unit_type != 3 {
+                    return Ok(());
+                }
+                let address_size = unit.u8()?;
+                (unit.section_offset(offset_size)?, address_size)
+            }
+            _ => return Err(invalid("Unsupported debug info version")),
+        };
+        let abbrevs = abbreviations(sections, abbrev_offset)?;
+        let mut context = Context {
+            sections,
+            version,
+            offset_size,
+            address_size,
+            base,
+            unit_base: 0,
+        };
+        let mut scopes: Vec<Scope> = vec![];
+
+        while !unit.is_empty() {
+            let offset = unit.offset();
+            let code = unit.uleb()?;
+            if code == 0 {
+                scopes.pop();
+                continue;
+            }
+            let abbrev = abbrevs
+                .get(&code)
+                .ok_or_else(|| invalid("Bad abbreviation code"))?;
+            let mut entry = Entry::default();
+            for &(name, form, implicit) in &abbrev.attributes {
+                let value = read_value(&mut unit, form, implicit, &context)?;
+                entry.set(name, form, value);
+            }
+
+            let scope = match abbrev.tag {
+                DW_TAG_COMPILE_UNIT | DW_TAG_PARTIAL_UNIT => {
+                    context.unit_base = entry.low_pc.unwrap_or(0);
+                    Scope::Other
+                }
+                DW_TAG_SUBPROGRAM => match entry.pc_range() {
+                    Some((start, end)) => {
+                        self.functions.push(Function {
+                            name: entry.name.clone(),
+                            start: bias.wrapping_add(start),
+                            end: bias.wrapping_add(end),
+                            frame_base: entry.frame_base.clone().unwrap_or_default(),
+                            bias,
+                            locals: vec![],
+                        });
+                        Scope::Function(self.functions.len() - 1)
+                    }
+                    None => Scope::NoCode,
+                },
+                DW_TAG_LEXICAL_BLOCK | DW_TAG_INLINED_SUBROUTINE => {
+                    let ranges = match (entry.pc_range(), entry.ranges) {
+                        (Some(range), _) => Some(vec![range]),
+                        (None, Some(ranges)) => range_list(&context, ranges).ok(),
+                        (None, None) => None,
+                    };
+                    let ranges = ranges.map(|ranges| {
+                        ranges
+                            .into_iter()
+                            .map(|(start, end)| (bias.wrapping_add(start), bias.wrapping_add(end)))
+                            .collect()
+                    });
+                    Scope::Block(ranges)
+                }
+                DW_TAG_VARIABLE | DW_TAG_FORMAL_PARAMETER => {
+                    self.add_local(&scopes, &entry, abbrev.tag);
+                    Scope::Other
+                }
+                tag => {
+                    if is_type(tag) {
+                        types.insert(
+                            offset,
+                            Type {
+                                tag,
+                                name: entry.name.clone(),
+                                size: entry.byte_size,
+                                inner: entry.type_offset,
+                            },
+                        );
+                    }
+                    Scope::Other
+                }
+            };
+            if abbrev.children {
+                scopes.push(scope);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the variable described by `entry` to the function it is in, if
+    /// it is in one and can be read.
+    fn add_local(&mut self, scopes: &[Scope], entry: &Entry, tag: u64) {
+        let (name, location) = match (&entry.name, &entry.location) {
+            (Some(name), Some(location)) => (name, location),
+            _ => return,
+        };
+        let mut scope = None;
+        for outer in scopes.iter().rev() {
+            match outer {
+                Scope::Function(index) => {
+                    self.functions[*index].locals.push(Local {
+                        name: name.clone(),
+                        parameter: tag == DW_TAG_FORMAL_PARAMETER,
+                        location: location.clone(),
+                        type_offset: entry.type_offset,
+                        type_name: None,
+                        size: None,
+                        scope: scope.unwrap_or_default(),
+                    });
+                    return;
+                }
+                // Blocks whose ranges weren't found leave the variable in
+                // scope throughout the block around them.
+                Scope::Block(Some(ranges)) if scope.is_none() => scope = Some(ranges.clone()),
+                Scope::Block(_) | Scope::Other => {}
+                Scope::NoCode => return,
+            }
+        }
+    }
+}
+
+/// An entry that can have variables in it.
+enum Scope {
+    /// A function, by index.
+    Function(usize),
+    /// A block, with the ranges of code in it if they are known.
+    Block(Option<Vec<(u64, u64)>>),
+    /// A function without code, such as an abstract instance of one that
+    /// was inlined.
+    NoCode,
+    Other,
+}
+
+/// Whether entries with `tag` describe types: arrays, classes, enums,
+/// pointers, references, structs, functions, typedefs, unions, base types
+/// and qualified types.
+fn is_type(tag: u64) -> bool {
+    matches!(
+        tag,
+        0x01 | 0x02
+            | 0x04
+            | 0x0f
+            | 0x10
+            | 0x13
+            | 0x15
+            | 0x16
+            | 0x17
+            | 0x24
+            | 0x26
+            | 0x35
+            | 0x37
+            | 0x42
+            | 0x47
+    )
+}
+
+fn type_name(types: &HashMap<u64, Type>, offset: u64, depth: usize) -> Option<String> {
+    let ty = types.get(&offset)?;
+    if let Some(ref name) = ty.name {
+        return Some(name.clone());
+    }
+    // Follow chains of pointers and qualifiers, which C leaves unnamed.
+    let inner = match ty.inner {
+        Some(inner) if depth < 16 => type_name(types, inner, depth + 1),
+        _ => None,
+    };
+    match ty.tag {
+        DW_TAG_POINTER_TYPE => Some(format!("{} *", inner.as_deref().unwrap_or("void"))),
+        DW_TAG_REFERENCE_TYPE => Some(format!("{} &", inner?)),
+        DW_TAG_CONST_TYPE => Some(format!("const {}", inner.as_deref().unwrap_or("void"))),
+        DW_TAG_VOLATILE_TYPE => Some(format!("volatile {}", inner?)),
+        _ => None,
+    }
+}
+
+fn type_size(types: &HashMap<u64, Type>, offset: u64, depth: usize) -> Option<u64> {
+    let ty = types.get(&offset)?;
+    match (ty.size, ty.tag) {
+        (Some(size), _) => Some(size),
+        (None, DW_TAG_POINTER_TYPE) | (None, DW_TAG_REFERENCE_TYPE) => Some(8),
+        (None, DW_TAG_TYPEDEF) | (None, DW_TAG_CONST_TYPE) | (None, DW_TAG_VOLATILE_TYPE)
+            if depth < 16 =>
+        {
+            type_size(types, ty.inner?, depth + 1)
+        }
+        // The size of an array is in its subranges, which aren't read.
+        (None, DW_TAG_ARRAY_TYPE) => None,
+        (None, _) => None,
+    }
+}
+
+/// Evaluate the location expression `expr` of a variable of `function`.
+///
+/// Returns `None` for expressions that use anything else than what is
+/// listed at `DebugInfo`.
+fn evaluate(
+    pid: Pid,
+    expr: &[u8],
+    function: &Function,
+    regs: &libc::user_regs_struct,
+) -> io::Result<Option<Location>> {
+    let mut reader = Reader::new(expr);
+    let mut stack: Vec<u64> = vec![];
+    while !reader.is_empty() {
+        let op = reader.u8()?;
+        match op {
+            DW_OP_ADDR => stack.push(function.bias.wrapping_add(reader.u64()?)),
+            DW_OP_DEREF => match stack.pop() {
+                Some(addr) => stack.push(memory::read_u64(pid, addr)?),
+                None => return Ok(None),
+            },
+            DW_OP_PLUS_UCONST => match stack.last_mut() {
+                Some(top) => *top = top.wrapping_add(reader.uleb()?),
+                None => return Ok(None),
+            },
+            DW_OP_REG0..=DW_OP_REG31 | DW_OP_REGX => {
+                let number = match op {
+                    DW_OP_REGX => reader.uleb()?,
+                    _ => (op - DW_OP_REG0) as u64,
+                };
+                return Ok(register(regs, number).map(Location::Register));
+            }
+            DW_OP_BREG0..=DW_OP_BREG31 | DW_OP_BREGX => {
+                let number = match op {
+                    DW_OP_BREGX => reader.uleb()?,
+                    _ => (op - DW_OP_BREG0) as u64,
+                };
+                let offset = reader.sleb()?;
+                match register(regs, number) {
+                    Some(value) => stack.push(value.wrapping_add(offset as u64)),
+                    None => return Ok(None),
+                }
+            }
+            DW_OP_FBREG => {
+                let offset = reader.sleb()?;
+                let base = match evaluate(pid, &function.frame_base, function, regs)? {
+                    Some(Location::Memory(base)) | Some(Location::Register(base)) => base,
+                    _ => return Ok(None),
+                };
+                stack.push(base.wrapping_add(offset as u64));
+            }
+            // The frame pointer points 16 bytes below the canonical frame
+            // address, past the return address and the saved frame pointer.
+            DW_OP_CALL_FRAME_CFA => stack.push(regs.rbp.wrapping_add(16)),
+            DW_OP_STACK_VALUE => return Ok(stack.pop().map(Location::Value)),
+            _ => return Ok(None),
+        }
+    }
+    Ok(stack.pop().map(Location::Memory))
+}
+
+/// The value of the register with DWARF number `number`.
+fn register(regs: &libc::user_regs_struct, number: u64) -> Option<u64> {
+    Some(match number {
+        0 => regs.rax,
+        1 => regs.rdx,
+        2 => regs.rcx,
+        3 => regs.rbx,
+        4 => regs.rsi,
+        5 => regs.rdi,
+        6 => regs.rbp,
+        7 => regs.rsp,
+        8 => regs.r8,
+        9 => regs.r9,
+        10 => regs.r10,
+        11 => regs.r11,
+        12 => regs.r12,
+        13 => regs.r13,
+        14 => regs.r14,
+        15 => regs.r15,
+        16 => regs.rip,
+        _ => return None,
+    })
+}
+
+/// What is needed to read the entries of a compilation unit.
+struct Context<'a> {
+    sections: &'a Sections<'a>,
+    version: u16,
+    offset_size: u8,
+    address_size: u8,
+    /// The offset of the unit in `.debug_info`.
+    base: u64,
+    /// The unit's base address, which range lists are relative to.
+    unit_base: u64,
+}
+
+/// The abbreviation of an entry, which describes its attributes.
+struct Abbreviation {
+    tag: u64,
+    children: bool,
+    /// The name, form and, for `DW_FORM_implicit_const`, value of each
+    /// attribute.
+    attributes: Vec<(u64, u64, i64)>,
+}
+
+fn abbreviations(sections: &Sections<'_>, offset: u64) -> io::Result<HashMap<u64, Abbreviation>> {
+    let mut reader = Reader::at(sections.abbrev, offset)?;
+    let mut abbrevs = HashMap::new();
+    loop {
+        let code = reader.uleb()?;
+        if code == 0 {
+            return Ok(abbrevs);
+        }
+        let tag = reader.uleb()?;
+        let children = reader.u8()? != 0;
+        let mut attributes = vec![];
+        loop {
+            let name = reader.uleb()?;
+            let form = reader.uleb()?;
+            if name == 0 && form == 0 {
+                break;
+            }
+            let implicit = match form {
+                DW_FORM_IMPLICIT_CONST => reader.sleb()?,
+                _ => 0,
+            };
+            attributes.push((name, form, implicit));
+        }
+        abbrevs.insert(
+            code,
+            Abbreviation {
+                tag,
+                children,
+                attributes,
+            },
+        );
+    }
+}
+
+/// An attribute's value, by the class of its form.
+enum Value<'a> {
+    Address(u64),
+    Block(&'a [u8]),
+    Constant(u64),
+    Reference(u64),
+    SectionOffset(u64),
+    String(&'a str),
+    /// Something that isn't needed, or can't be read without more of the
+    /// debug info, such as string indices.
+    Other,
+}
+
+fn read_value<'a>(
+    unit: &mut Reader<'a>,
+    form: u64,
+    implicit: i64,
+    context: &Context<'a>,
+) -> io::Result<Value<'a>> {
+    let offset_size = context.offset_size as u64;
+    Ok(match form {
+        DW_FORM_ADDR => Value::Address(match context.address_size {
+            4 => unit.u32()? as u64,
+            _ => unit.u64()?,
+        }),
+        DW_FORM_BLOCK1 => {
+            let len = unit.u8()? as u64;
+            Value::Block(unit.bytes(len)?)
+        }
+        DW_FORM_BLOCK2 => {
+            let len = unit.u16()? as u64;
+            Value::Block(unit.bytes(len)?)
+        }
+        DW_FORM_BLOCK4 => {
+            let len = unit.u32()? as u64;
+            Value::Block(unit.bytes(len)?)
+        }
+        DW_FORM_BLOCK | DW_FORM_EXPRLOC => {
+            let len = unit.uleb()?;
+            Value::Block(unit.bytes(len)?)
+        }
+        DW_FORM_DATA1 | DW_FORM_FLAG => Value::Constant(unit.u8()? as u64),
+        DW_FORM_DATA2 => Value::Constant(unit.u16()? as u64),
+        DW_FORM_DATA4 => Value::Constant(unit.u32()? as u64),
+        DW_FORM_DATA8 => Value::Constant(unit.u64()?),
+        DW_FORM_DATA16 => Value::Block(unit.bytes(16)?),
+        DW_FORM_SDATA => Value::Constant(unit.sleb()? as u64),
+        DW_FORM_UDATA => Value::Constant(unit.uleb()?),
+        DW_FORM_IMPLICIT_CONST => Value::Constant(implicit as u64),
+        DW_FORM_FLAG_PRESENT => Value::Constant(1),
+        DW_FORM_STRING => Value::String(unit.str()?),
+        DW_FORM_STRP => Value::String(
+            context
+                .sections
+                .str(unit.section_offset(context.offset_size)?)?,
+        ),
+        DW_FORM_LINE_STRP => Value::String(
+            context
+                .sections
+                .line_str(unit.section_offset(context.offset_size)?)?,
+        ),
+        DW_FORM_REF1 => Value::Reference(context.base + unit.u8()? as u64),
+        DW_FORM_REF2 => Value::Reference(context.base + unit.u16()? as u64),
+        DW_FORM_REF4 => Value::Reference(context.base + unit.u32()? as u64),
+        DW_FORM_REF8 => Value::Reference(context.base + unit.u64()?),
+        DW_FORM_REF_UDATA => Value::Reference(context.base + unit.uleb()?),
+        // DWARF 2 made these address sized.
+        DW_FORM_REF_ADDR if context.version == 2 => Value::Reference(match context.address_size {
+            4 => unit.u32()? as u64,
+            _ => unit.u64()?,
+        }),
+        DW_FORM_REF_ADDR => Value::Reference(unit.section_offset(context.offset_size)?),
+        DW_FORM_SEC_OFFSET => Value::SectionOffset(unit.section_offset(context.offset_size)?),
+        DW_FORM_INDIRECT => {
+            let form = unit.uleb()?;
+            return read_value(unit, form, implicit, context);
+        }
+        DW_FORM_REF_SIG8 | DW_FORM_REF_SUP8 => {
+            unit.skip(8)?;
+            Value::Other
+        }
+        DW_FORM_REF_SUP4 => {
+            unit.skip(4)?;
+            Value::Other
+        }
+        DW_FORM_STRP_SUP => {
+            unit.skip(offset_size)?;
+            Value::Other
+        }
+        DW_FORM_STRX | DW_FORM_ADDRX | DW_FORM_LOCLISTX | DW_FORM_RNGLISTX => {
+            unit.uleb()?;
+            Value::Other
+        }
+        DW_FORM_STRX1..=DW_FORM_STRX4 => {
+            unit.skip(form - DW_FORM_STRX1 + 1)?;
+            Value::Other
+        }
+        DW_FORM_ADDRX1..=DW_FORM_ADDRX4 => {
+            unit.skip(form - DW_FORM_ADDRX1 + 1)?;
+            Value::Other
+        }
+        _ => return Err(invalid("Unsupported attribute form")),
+    })
+}
+
+/// The attributes of an entry that are used.
+#[derive(Default)]
+struct Entry {
+    name: Option<String>,
+    low_pc: Option<u64>,
+    high_pc: Option<u64>,
+    /// Whether `high_pc` is an offset from `low_pc`.
+    high_pc_offset: bool,
+    ranges: Option<u64>,
+    frame_base: Option<Vec<u8>>,
+    location: Option<Vec<u8>>,
+    type_offset: Option<u64>,
+    byte_size: Option<u64>,
+}
+
+impl Entry {
+    fn set(&mut self, name: u64, form: u64, value: Value<'_>) {
+        match (name, value) {
+            (DW_AT_NAME, Value::String(s)) => self.name = Some(s.to_string()),
+            (DW_AT_LOW_PC, Value::Address(addr)) => self.low_pc = Some(addr),
+            (DW_AT_HIGH_PC, Value::Address(addr)) => self.high_pc = Some(addr),
+            (DW_AT_HIGH_PC, Value::Constant(len)) => {
+                self.high_pc = Some(len);
+                self.high_pc_offset = form != DW_FORM_ADDR;
+            }
+            (DW_AT_RANGES, Value::SectionOffset(offset))
+            | (DW_AT_RANGES, Value::Constant(offset)) => self.ranges = Some(offset),
+            (DW_AT_FRAME_BASE, Value::Block(expr)) => self.frame_base = Some(expr.to_vec()),
+            (DW_AT_LOCATION, Value::Block(expr)) => self.location = Some(expr.to_vec()),
+            (DW_AT_TYPE, Value::Reference(offset)) => self.type_offset = Some(offset),
+            (DW_AT_BYTE_SIZE, Value::Constant(size)) => self.byte_size = Some(size),
+            _ => {}
+        }
+    }
+
+    /// The entry's code, from `DW_AT_low_pc` and `DW_AT_high_pc`.
+    fn pc_range(&self) -> Option<(u64, u64)> {
+        let low = self.low_pc?;
+        let high = self.high_pc?;
+        let high = if self.high_pc_offset {
+            low.wrapping_add(high)
+        } else {
+            high
+        };
+        // Functions the linker discarded are left at 0.
+        if low == 0 || high <= low {
+            return None;
+        }
+        Some((low, high))
+    }
+}
+
+/// Read the range list at `offset`, in `.debug_ranges` before DWARF 5 and
+/// in `.debug_rnglists` since.
+fn range_list(context: &Context<'_>, offset: u64) -> io::Result<Vec<(u64, u64)>> {
+    let mut ranges = vec![];
+    let mut base = context.unit_base;
+    if context.version < 5 {
+        let mut reader = Reader::at(context.sections.ranges, offset)?;
+        loop {
+            let (start, end) = (reader.u64()?, reader.u64()?);
+            match (start, end) {
+                (0, 0) => return Ok(ranges),
+                (u64::MAX, _) => base = end,
+                _ => ranges.push((base.wrapping_add(start), base.wrapping_add(end))),
+            }
+        }
+    }
+    let mut reader = Reader::at(context.sections.rnglists, offset)?;
+    loop {
+        match reader.u8()? {
+            // DW_RLE_end_of_list
+            0 => return Ok(ranges),
+            // DW_RLE_offset_pair
+            4 => {
+                let (start, end) = (reader.uleb()?, reader.uleb()?);
+                ranges.push((base.wrapping_add(start), base.wrapping_add(end)));
+            }
+            // DW_RLE_base_address
+            5 => base = reader.u64()?,
+            // DW_RLE_start_end
+            6 => ranges.push((reader.u64()?, reader.u64()?)),
+            // DW_RLE_start_length
+            7 => {
+                let start = reader.u64()?;
+                ranges.push((start, start.wrapping_add(reader.uleb()?)));
+            }
+            // The entries with indices into `.debug_addr`.
+            _ => return Err(invalid("Unsupported range list entry")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{Breakpoints, Event, LineTable, SpawnOptions, TraceSession};
+    use nix::sys::ptrace;
+    use std::process::Command;
+
+    #[test]
+    fn test_debug_info_function() {
+        let debug_info = DebugInfo::load(Pid::this()).expect("Error loading debug info");
+        let here = test_debug_info_function as *const () as u64;
+        assert_eq!(debug_info.function(here), Some("test_debug_info_function"));
+        assert_eq!(debug_info.function(0), None);
+    }
+
+    #[test]
+    fn test_variables() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        let pid = session
+            .spawn(Command::new(&path).arg("calls"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut breakpoints = Breakpoints::new();
+        breakpoints
+            .insert_symbol(pid, "spawn_ptrace_leaf")
+            .expect("Error inserting breakpoint");
+        ptrace::cont(pid, None).unwrap();
+        session.wait_for(pid).unwrap();
+        assert!(breakpoints.hit(pid).unwrap().is_some());
+        let lines = LineTable::load(pid).expect("Error loading line tables");
+        let debug_info = DebugInfo::load(pid).expect("Error loading debug info");
+        // Past the prologue, which stores the parameter.
+        breakpoints.step_line(&mut session, pid, &lines).unwrap();
+        let regs = ptrace::getregs(pid).unwrap();
+        assert_eq!(debug_info.function(regs.rip), Some("spawn_ptrace_leaf"));
+        let variables = debug_info.variables(pid, &regs).unwrap();
+        let n = variables
+            .iter()
+            .find(|v| v.name == "n")
+            .expect("No parameter n");
+        assert!(n.parameter);
+        assert_eq!(n.type_name.as_deref(), Some("u64"));
+        assert_eq!(n.value, 100_000u64.to_le_bytes());
+        assert!(n.address.is_some());
+        // The loop variable isn't in scope before the loop.
+        assert!(variables.iter().all(|v| v.name != "i"));
+        breakpoints.remove_symbol(pid, "spawn_ptrace_leaf").unwrap();
+        ptrace::cont(pid, None).unwrap();
+        let (_, event) = session.wait_for(pid).unwrap();
+        assert_eq!(event, Event::Exited(0));
+    }
+}
@@ -0,0 +1,2135 @@
+//! Decoding the mnemonic and operands of x86-64 instructions.
+
+use crate::insn::{decode, evex_scale, Decoded, OpcodeMap, Prefixes};
+use std::borrow::Cow;
+use std::fmt;
+
+static GPR64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+static GPR32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+static GPR16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w",
+    "r14w", "r15w",
+];
+static GPR8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+/// The byte registers 4 to 7 without a `REX` prefix.
+static GPR8_HIGH: [&str; 4] = ["ah", "ch", "dh", "bh"];
+static XMM: [&str; 32] = [
+    "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7", "xmm8", "xmm9", "xmm10",
+    "xmm11", "xmm12", "xmm13", "xmm14", "xmm15", "xmm16", "xmm17", "xmm18", "xmm19", "xmm20",
+    "xmm21", "xmm22", "xmm23", "xmm24", "xmm25", "xmm26", "xmm27", "xmm28", "xmm29", "xmm30",
+    "xmm31",
+];
+static YMM: [&str; 32] = [
+    "ymm0", "ymm1", "ymm2", "ymm3", "ymm4", "ymm5", "ymm6", "ymm7", "ymm8", "ymm9", "ymm10",
+    "ymm11", "ymm12", "ymm13", "ymm14", "ymm15", "ymm16", "ymm17", "ymm18", "ymm19", "ymm20",
+    "ymm21", "ymm22", "ymm23", "ymm24", "ymm25", "ymm26", "ymm27", "ymm28", "ymm29", "ymm30",
+    "ymm31",
+];
+static ZMM: [&str; 32] = [
+    "zmm0", "zmm1", "zmm2", "zmm3", "zmm4", "zmm5", "zmm6", "zmm7", "zmm8", "zmm9", "zmm10",
+    "zmm11", "zmm12", "zmm13", "zmm14", "zmm15", "zmm16", "zmm17", "zmm18", "zmm19", "zmm20",
+    "zmm21", "zmm22", "zmm23", "zmm24", "zmm25", "zmm26", "zmm27", "zmm28", "zmm29", "zmm30",
+    "zmm31",
+];
+static MM: [&str; 8] = ["mm0", "mm1", "mm2", "mm3", "mm4", "mm5", "mm6", "mm7"];
+static ST: [&str; 8] = [
+    "st(0)", "st(1)", "st(2)", "st(3)", "st(4)", "st(5)", "st(6)", "st(7)",
+];
+static MASK: [&str; 8] = ["k0", "k1", "k2", "k3", "k4", "k5", "k6", "k7"];
+static SEGMENT: [&str; 8] = ["es", "cs", "ss", "ds", "fs", "gs", "?", "?"];
+static CR: [&str; 16] = [
+    "cr0", "cr1", "cr2", "cr3", "cr4", "cr5", "cr6", "cr7", "cr8", "cr9", "cr10", "cr11", "cr12",
+    "cr13", "cr14", "cr15",
+];
+static DR: [&str; 16] = [
+    "dr0", "dr1", "dr2", "dr3", "dr4", "dr5", "dr6", "dr7", "dr8", "dr9", "dr10", "dr11", "dr12",
+    "dr13", "dr14", "dr15",
+];
+
+/// The predicates of `cmpps` and its kin, of which the SSE forms have the
+/// first 8.
+static COMPARE: [&str; 32] = [
+    "eq", "lt", "le", "unord", "neq", "nlt", "nle", "ord", "eq_uq", "nge", "ngt", "false",
+    "neq_oq", "ge", "gt", "true", "eq_os", "lt_oq", "le_oq", "unord_s", "neq_us", "nlt_uq",
+    "nle_uq", "ord_s", "eq_us", "nge_uq", "ngt_uq", "false_os", "neq_os", "ge_oq", "gt_oq",
+    "true_us",
+];
+
+static ARITH: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+static SHIFT: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "shl", "sar"];
+static UNARY: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+static JCC: [&str; 16] = [
+    "jo", "jno", "jb", "jae", "je", "jne", "jbe", "ja", "js", "jns", "jp", "jnp", "jl", "jge",
+    "jle", "jg",
+];
+static SETCC: [&str; 16] = [
+    "seto", "setno", "setb", "setae", "sete", "setne", "setbe", "seta", "sets", "setns", "setp",
+    "setnp", "setl", "setge", "setle", "setg",
+];
+static CMOVCC: [&str; 16] = [
+    "cmovo", "cmovno", "cmovb", "cmovae", "cmove", "cmovne", "cmovbe", "cmova", "cmovs", "cmovns",
+    "cmovp", "cmovnp", "cmovl", "cmovge", "cmovle", "cmovg",
+];
+
+/// The x87 instructions with a memory operand, by opcode from `d8` and
+/// ModRM `reg`, with the size of the operand.
+static X87_MEMORY: [[(&str, usize); 8]; 8] = [
+    [
+        ("fadd", 4),
+        ("fmul", 4),
+        ("fcom", 4),
+        ("fcomp", 4),
+        ("fsub", 4),
+        ("fsubr", 4),
+        ("fdiv", 4),
+        ("fdivr", 4),
+    ],
+    [
+        ("fld", 4),
+        ("", 0),
+        ("fst", 4),
+        ("fstp", 4),
+        ("fldenv", 0),
+        ("fldcw", 2),
+        ("fnstenv", 0),
+        ("fnstcw", 2),
+    ],
+    [
+        ("fiadd", 4),
+        ("fimul", 4),
+        ("ficom", 4),
+        ("ficomp", 4),
+        ("fisub", 4),
+        ("fisubr", 4),
+        ("fidiv", 4),
+        ("fidivr", 4),
+    ],
+    [
+        ("fild", 4),
+        ("fisttp", 4),
+        ("fist", 4),
+        ("fistp", 4),
+        ("", 0),
+        ("fld", 10),
+        ("", 0),
+        ("fstp", 10),
+    ],
+    [
+        ("fadd", 8),
+        ("fmul", 8),
+        ("fcom", 8),
+        ("fcomp", 8),
+        ("fsub", 8),
+        ("fsubr", 8),
+        ("fdiv", 8),
+        ("fdivr", 8),
+    ],
+    [
+        ("fld", 8),
+        ("fisttp", 8),
+        ("fst", 8),
+        ("fstp", 8),
+        ("frstor", 0),
+        ("", 0),
+        ("fnsave", 0),
+        ("fnstsw", 2),
+    ],
+    [
+        ("fiadd", 2),
+        ("fimul", 2),
+        ("ficom", 2),
+        ("ficomp", 2),
+        ("fisub", 2),
+        ("fisubr", 2),
+        ("fidiv", 2),
+        ("fidivr", 2),
+    ],
+    [
+        ("fild", 2),
+        ("fisttp", 2),
+        ("fist", 2),
+        ("fistp", 2),
+        ("fbld", 10),
+        ("fild", 8),
+        ("fbstp", 10),
+        ("fistp", 8),
+    ],
+];
+
+/// The `d9` x87 instructions without operands, from ModRM `e0` on.
+static X87_D9: [&str; 32] = [
+    "fchs", "fabs", "", "", "ftst", "fxam", "", "", "fld1", "fldl2t", "fldl2e", "fldpi", "fldlg2",
+    "fldln2", "fldz", "", "f2xm1", "fyl2x", "fptan", "fpatan", "fxtract", "fprem1", "fdecstp",
+    "fincstp", "fprem", "fyl2xp1", "fsqrt", "fsincos", "frndint", "fscale", "fsin", "fcos",
+];
+
+/// An operand of an [`Instruction`].
+///
+/// [`Instruction`]: struct.Instruction.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operand {
+    /// A register, named as in Intel syntax, such as `eax`, `xmm3`, `st(1)`
+    /// or `fs`.
+    Register(&'static str),
+    /// An immediate value, as wide as the operand it stands for, so that
+    /// for example `add eax, -1` has `0xffffffff`.
+    Immediate(u64),
+    /// The target of a relative jump or call, as an offset from the end of
+    /// the instruction. See [`Instruction::target`].
+    ///
+    /// [`Instruction::target`]: struct.Instruction.html#method.target
+    Relative(i64),
+    /// An operand in memory.
+    Memory(MemoryOperand),
+}
+
+/// A memory operand of an [`Instruction`], at
+/// `segment:[base + index * scale + displacement]`.
+///
+/// [`Instruction`]: struct.Instruction.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MemoryOperand {
+    /// The number of bytes accessed, or 0 if that isn't a fixed size, as
+    /// for `lea` or `fxsave`.
+    pub size: usize,
+    /// The `fs` or `gs` segment override, whose base is added to the
+    /// address.
+    pub segment: Option<&'static str>,
+    /// The base register, which is `rip` for addresses relative to the end
+    /// of the instruction.
+    pub base: Option<&'static str>,
+    /// The index register, which is multiplied by `scale`.
+    pub index: Option<&'static str>,
+    pub scale: u8,
+    pub displacement: i64,
+}
+
+/// An x86-64 instruction decoded from its machine code, with
+/// [`Instruction::decode`] or [`Tracee::instruction_at`].
+///
+/// Every instruction is decoded far enough to know its length and opcode,
+/// which is enough to step over it or recognize calls and returns. The
+/// mnemonic and operands are decoded for the general-purpose, x87, MMX and
+/// SSE instructions, their `VEX` and `EVEX` forms, and the common AVX2,
+/// FMA and BMI instructions; for the rest, [`mnemonic`] is `None`. The
+/// `Display` implementation writes the instruction in Intel syntax. A
+/// software breakpoint decodes as `int3`, so read the code before planting
+/// breakpoints in it.
+///
+/// [`Instruction::decode`]: #method.decode
+/// [`Tracee::instruction_at`]: struct.Tracee.html#method.instruction_at
+/// [`mnemonic`]: #method.mnemonic
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Instruction {
+    bytes: Vec<u8>,
+    map: OpcodeMap,
+    opcode: u8,
+    modrm: Option<u8>,
+    prefix: Option<&'static str>,
+    mnemonic: Option<Cow<'static, str>>,
+    operands: Vec<Operand>,
+    mask: Option<(&'static str, bool)>,
+}
+
+impl Instruction {
+    /// Decode the instruction at the start of `code`, or return `None` if it
+    /// is cut off or isn't a valid 64-bit instruction.
+    pub fn decode(code: &[u8]) -> Option<Instruction> {
+        let decoded = decode(code)?;
+        let operands = Operands { code, d: &decoded };
+        let (mnemonic, operands, mask) = match operands.disassemble() {
+            Some((mnemonic, operands)) => {
+                let p = &decoded.prefixes;
+                let mask = match p.evex {
+                    Some(_) if p.mask != 0 => Some((MASK[p.mask as usize], p.zeroing)),
+                    _ => None,
+                };
+                (Some(mnemonic), operands, mask)
+            }
+            None => (None, vec![], None),
+        };
+        Some(Instruction {
+            bytes: code[..decoded.len].to_vec(),
+            map: decoded.map,
+            opcode: decoded.opcode,
+            modrm: decoded.modrm,
+            prefix: mnemonic.as_ref().and_then(|_| prefix(&decoded)),
+            mnemonic,
+            operands,
+            mask,
+        })
+    }
+
+    /// The instruction's machine code.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The instruction's length in bytes, from 1 to 15.
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// The map the instruction's opcode is from.
+    pub fn map(&self) -> OpcodeMap {
+        self.map
+    }
+
+    /// The instruction's opcode byte, without the prefixes and escapes that
+    /// select its map.
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    /// The instruction's ModRM byte, if it has one. For some opcodes, such
+    /// as `ff`, its `reg` field extends the opcode.
+    pub fn modrm(&self) -> Option<u8> {
+        self.modrm
+    }
+
+    /// The instruction's mnemonic, in Intel syntax, such as `mov` or
+    /// `vpaddd`, or `None` if it isn't one this decoder knows.
+    pub fn mnemonic(&self) -> Option<&str> {
+        self.mnemonic.as_deref()
+    }
+
+    /// The `lock`, `rep`, `repe` or `repne` prefix of the instruction, if
+    /// it has one that applies to it.
+    pub fn prefix(&self) -> Option<&'static str> {
+        self.prefix
+    }
+
+    /// The instruction's explicit operands, destination first as in Intel
+    /// syntax. Operands the instruction implies, such as the `rsp` of a
+    /// `push` or the `rdi` and `rcx` of `rep stosb`, aren't listed.
+    pub fn operands(&self) -> &[Operand] {
+        &self.operands
+    }
+
+    /// The `AVX-512` opmask register that selects the elements written,
+    /// and whether the others are zeroed rather than left unchanged, if
+    /// the instruction is masked.
+    pub fn mask(&self) -> Option<(&'static str, bool)> {
+        self.mask
+    }
+
+    /// Where a relative jump or call goes, if the instruction is one and
+    /// starts at `address`.
+    pub fn target(&self, address: u64) -> Option<u64> {
+        self.operands.iter().find_map(|operand| match operand {
+            Operand::Relative(offset) => Some(
+                address
+                    .wrapping_add(self.bytes.len() as u64)
+                    .wrapping_add(*offset as u64),
+            ),
+            _ => None,
+        })
+    }
+
+    /// Whether this is a `call`, which pushes the address of the next
+    /// instruction.
+    pub fn is_call(&self) -> bool {
+        match (self.map, self.opcode) {
+            (OpcodeMap::Primary, 0xe8) => true,
+            (OpcodeMap::Primary, 0xff) => {
+                matches!(self.modrm.map(|modrm| modrm >> 3 & 7), Some(2) | Some(3))
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether this is a return, `ret`, `retf` or `iret`.
+    pub fn is_return(&self) -> bool {
+        self.map == OpcodeMap::Primary && matches!(self.opcode, 0xc2 | 0xc3 | 0xca | 0xcb | 0xcf)
+    }
+
+    /// Whether this enters the kernel like a system call does, which is
+    /// `syscall`, `sysenter` and `int`.
+    pub fn is_syscall(&self) -> bool {
+        matches!(
+            (self.map, self.opcode),
+            (OpcodeMap::Primary, 0xcd)
+                | (OpcodeMap::Secondary, 0x05)
+                | (OpcodeMap::Secondary, 0x34)
+        )
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mnemonic = match &self.mnemonic {
+            Some(mnemonic) => mnemonic,
+            None => {
+                write!(f, "db ")?;
+                for (i, byte) in self.bytes.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{:#04x}", separator, byte)?;
+                }
+                return Ok(());
+            }
+        };
+        if let Some(prefix) = self.prefix {
+            write!(f, "{} ", prefix)?;
+        }
+        write!(f, "{}", mnemonic)?;
+        for (i, operand) in self.operands.iter().enumerate() {
+            f.write_str(if i == 0 { " " } else { ", " })?;
+            match operand {
+                Operand::Register(name) => f.write_str(name)?,
+                Operand::Immediate(value) => write!(f, "{:#x}", value)?,
+                Operand::Relative(offset) => {
+                    signed(f, "$", offset.wrapping_add(self.bytes.len() as i64))?
+                }
+                Operand::Memory(memory) => write!(f, "{}", memory)?,
+            }
+            if let (0, Some((mask, zeroing))) = (i, self.mask) {
+                write!(f, "{{{}}}", mask)?;
+                if zeroing {
+                    f.write_str("{z}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MemoryOperand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = match self.size {
+            1 => "byte ptr ",
+            2 => "word ptr ",
+            4 => "dword ptr ",
+            8 => "qword ptr ",
+            6 => "fword ptr ",
+            10 => "tbyte ptr ",
+            16 => "xmmword ptr ",
+            32 => "ymmword ptr ",
+            64 => "zmmword ptr ",
+            _ => "",
+        };
+        f.write_str(size)?;
+        if let Some(segment) = self.segment {
+            write!(f, "{}:", segment)?;
+        }
+        f.write_str("[")?;
+        let mut first = true;
+        if let Some(base) = self.base {
+            f.write_str(base)?;
+            first = false;
+        }
+        if let Some(index) = self.index {
+            if !first {
+                f.write_str("+")?;
+            }
+            write!(f, "{}*{}", index, self.scale)?;
+            first = false;
+        }
+        if first {
+            write!(f, "{:#x}", self.displacement)?;
+        } else if self.displacement != 0 {
+            signed(f, "", self.displacement)?;
+        }
+        f.write_str("]")
+    }
+}
+
+/// Write `value` after `before` in hex, with its sign.
+fn signed(f: &mut fmt::Formatter, before: &str, value: i64) -> fmt::Result {
+    if value < 0 {
+        write!(f, "{}-{:#x}", before, value.unsigned_abs())
+    } else {
+        write!(f, "{}+{:#x}", before, value)
+    }
+}
+
+/// The prefix of `d` that applies to it, if it has one.
+fn prefix(d: &Decoded) -> Option<&'static str> {
+    let p = &d.prefixes;
+    if p.lock {
+        return Some("lock");
+    }
+    if d.map != OpcodeMap::Primary {
+        return None;
+    }
+    match (p.rep, d.opcode) {
+        (Some(0xf3), 0x6c..=0x6f) | (Some(0xf3), 0xa4..=0xa5) | (Some(0xf3), 0xaa..=0xad) => {
+            Some("rep")
+        }
+        (Some(0xf3), 0xa6..=0xa7) | (Some(0xf3), 0xae..=0xaf) => Some("repe"),
+        (Some(0xf2), 0xa6..=0xa7) | (Some(0xf2), 0xae..=0xaf) => Some("repne"),
+        _ => None,
+    }
+}
+
+/// How an SSE instruction is encoded with `VEX` or `EVEX`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Vex {
+    /// It has no such form.
+    No,
+    /// With a `v` before the mnemonic, and the same operands.
+    Same,
+    /// With a `v` before the mnemonic, and the `vvvv` register as an extra
+    /// source after the destination.
+    Nds,
+    /// Only with `VEX`, with the mnemonic and operands as given.
+    Only,
+}
+
+/// The kinds of operand, named as in the opcode tables of the Intel
+/// manual. `E` is a general-purpose register or memory from the ModRM
+/// `rm` field, `G` a general-purpose register from `reg`, `V` and `W` the
+/// same for vector registers, `P` and `Q` for MMX registers, `H` and `B`
+/// the `vvvv` register, `I` an immediate and `J` a relative offset.
+#[derive(Clone, Copy, Debug)]
+enum Spec {
+    Eb,
+    Ew,
+    Ed,
+    Ev,
+    Ey,
+    /// 64 bits unless there is an operand size prefix, as for `push`.
+    Eq,
+    Gb,
+    Gd,
+    Gv,
+    Gy,
+    /// The register in the low bits of the opcode.
+    Zb,
+    Zv,
+    Zq,
+    By,
+    /// Memory of the size given, and of the operand size.
+    M(usize),
+    Mv,
+    Mx,
+    Ib,
+    /// A byte, sign-extended to the operand size, or to 64 bits.
+    Ibs,
+    Ibq,
+    /// The byte after a word, for `enter`.
+    Ib2,
+    Iw,
+    /// A word or doubleword, sign-extended to the operand size, or to 64
+    /// bits.
+    Iz,
+    Izq,
+    Iv,
+    Jb,
+    Jz,
+    Al,
+    /// `ax`, `eax` or `rax` by operand size, and `ax` or `eax`.
+    Ax,
+    Az,
+    Cl,
+    Dx,
+    One,
+    /// A byte or operand-sized value at the absolute address in the
+    /// immediate.
+    Ob,
+    Ov,
+    Sw,
+    Cr,
+    Dr,
+    Rq,
+    Fs,
+    Gs,
+    Vx,
+    /// Always an `xmm` register.
+    Vdq,
+    Hx,
+    Wx,
+    /// An `xmm` register, or memory of the size given.
+    W(usize),
+    Pq,
+    Qq,
+    Nq,
+    /// An `AVX-512` opmask register from `reg`, from `vvvv`, and from `rm`
+    /// or memory of the size given.
+    K,
+    Kv,
+    Km(usize),
+    /// A 32-bit register, or memory of the size given.
+    R(usize),
+    /// A far pointer in memory, an offset of the operand size and a
+    /// selector.
+    Mp,
+    /// A vector register from the high bits of the immediate.
+    Lx,
+    /// The implied `xmm0` of some SSE instructions.
+    Xmm0,
+}
+
+type Entry = (Cow<'static, str>, &'static [Spec], Vex);
+
+/// An instruction's operands, decoded from `code` as `d` describes.
+struct Operands<'a> {
+    code: &'a [u8],
+    d: &'a Decoded,
+}
+
+impl Operands<'_> {
+    fn prefixes(&self) -> &Prefixes {
+        &self.d.prefixes
+    }
+
+    /// The operand size of a general-purpose instruction, in bytes.
+    fn size(&self) -> usize {
+        let p = self.prefixes();
+        if p.rex_w {
+            8
+        } else if p.operand16 {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// The operand size of instructions that default to 64 bits.
+    fn size64(&self) -> usize {
+        if self.prefixes().operand16 {
+            2
+        } else {
+            8
+        }
+    }
+
+    /// The vector length, in bytes.
+    fn vector(&self) -> usize {
+        self.prefixes().vector.unwrap_or(16)
+    }
+
+    fn mode(&self) -> u8 {
+        self.d.modrm.map_or(0, |modrm| modrm >> 6)
+    }
+
+    /// ModRM's `reg` field, extended by the prefixes.
+    fn reg(&self) -> usize {
+        let p = self.prefixes();
+        let reg = self.d.modrm.map_or(0, |modrm| modrm >> 3 & 7) | p.rex_r << 3 | p.rex_r2 << 4;
+        reg as usize
+    }
+
+    /// ModRM's `rm` field as a register, extended by the prefixes.
+    fn rm(&self) -> usize {
+        let p = self.prefixes();
+        let mut rm = self.d.modrm.map_or(0, |modrm| modrm & 7) | p.rex_b << 3;
+        if p.evex.is_some() {
+            rm |= p.rex_x << 4;
+        }
+        rm as usize
+    }
+
+    fn gpr(&self, n: usize, size: usize) -> Operand {
+        Operand::Register(match size {
+            1 if n & 0xc == 4 && !self.prefixes().rex => GPR8_HIGH[n & 3],
+            1 => GPR8[n & 15],
+            2 => GPR16[n & 15],
+            4 => GPR32[n & 15],
+            _ => GPR64[n & 15],
+        })
+    }
+
+    fn vector_register(&self, n: usize, size: usize) -> Operand {
+        Operand::Register(match size {
+            64 => ZMM[n & 31],
+            32 => YMM[n & 31],
+            _ => XMM[n & 31],
+        })
+    }
+
+    /// The memory operand from ModRM, SIB and the displacement.
+    fn memory(&self, size: usize) -> Operand {
+        let d = self.d;
+        let p = self.prefixes();
+        let names = if p.address32 { &GPR32 } else { &GPR64 };
+        let modrm = d.modrm.unwrap_or(0);
+        let mode = modrm >> 6;
+        let (mut base, mut index, mut scale) = (None, None, 1);
+        if let Some(sib) = d.sib {
+            let n = (sib >> 3) & 7 | p.rex_x << 3;
+            if n != 4 {
+                index = Some(names[n as usize]);
+                scale = 1 << (sib >> 6);
+            }
+            if sib & 7 != 5 || mode != 0 {
+                base = Some(names[(sib & 7 | p.rex_b << 3) as usize]);
+            }
+        } else if modrm & 7 == 5 && mode == 0 {
+            base = Some(if p.address32 { "eip" } else { "rip" });
+        } else {
+            base = Some(names[(modrm & 7 | p.rex_b << 3) as usize]);
+        }
+        let factor = if mode == 1 {
+            evex_scale(p, d.map, d.opcode) as i64
+        } else {
+            1
+        };
+        Operand::Memory(MemoryOperand {
+            size,
+            segment: p.segment.map(|s| if s == 0x64 { "fs" } else { "gs" }),
+            base,
+            index,
+            scale,
+            displacement: d.disp.wrapping_mul(factor),
+        })
+    }
+
+    /// A general-purpose register or memory from ModRM.
+    fn e(&self, size: usize) -> Operand {
+        if self.mode() == 3 {
+            self.gpr(self.rm(), size)
+        } else {
+            self.memory(size)
+        }
+    }
+
+    /// A vector register of `size` bytes, or memory of `memory` bytes, from
+    /// ModRM.
+    fn w(&self, size: usize, memory: usize) -> Operand {
+        if self.mode() == 3 {
+            self.vector_register(self.rm(), size)
+        } else {
+            self.memory(memory)
+        }
+    }
+
+    fn immediate(&self, size: usize, extend_to: usize) -> Operand {
+        let d = self.d;
+        let raw = d.immediate(self.code);
+        let shift = 64 - 8 * size as u32;
+        let value = ((raw << shift) as i64 >> shift) as u64;
+        Operand::Immediate(truncate(value, extend_to))
+    }
+
+    fn operand(&self, spec: Spec) -> Operand {
+        use Spec::*;
+        let d = self.d;
+        let p = self.prefixes();
+        let y = if p.rex_w { 8 } else { 4 };
+        match spec {
+            Eb => self.e(1),
+            Ew => self.e(2),
+            Ed => self.e(4),
+            Ev => self.e(self.size()),
+            Ey => self.e(y),
+            Eq => self.e(self.size64()),
+            Gb => self.gpr(self.reg(), 1),
+            Gd => self.gpr(self.reg(), 4),
+            Gv => self.gpr(self.reg(), self.size()),
+            Gy => self.gpr(self.reg(), y),
+            Zb => self.gpr((d.opcode & 7 | p.rex_b << 3) as usize, 1),
+            Zv => self.gpr((d.opcode & 7 | p.rex_b << 3) as usize, self.size()),
+            Zq => self.gpr((d.opcode & 7 | p.rex_b << 3) as usize, self.size64()),
+            By => self.gpr(p.vvvv as usize, y),
+            M(size) => self.memory(size),
+            Mv => self.memory(self.size()),
+            Mx => self.memory(self.vector()),
+            Ib => Operand::Immediate(self.code[d.imm] as u64),
+            Ibs => self.immediate(1, self.size()),
+            Ibq => self.immediate(1, self.size64()),
+            Ib2 => Operand::Immediate(self.code[d.imm + 2] as u64),
+            Iw => Operand::Immediate(d.immediate(self.code) & 0xffff),
+            Iz => self.immediate(d.imm_size, self.size()),
+            Izq => self.immediate(d.imm_size, self.size64()),
+            Iv => Operand::Immediate(d.immediate(self.code)),
+            Jb => Operand::Relative(self.code[d.imm] as i8 as i64),
+            Jz => Operand::Relative(d.immediate(self.code) as u32 as i32 as i64),
+            Al => Operand::Register("al"),
+            Ax => self.gpr(0, self.size()),
+            Az => self.gpr(0, self.size().min(4)),
+            Cl => Operand::Register("cl"),
+            Dx => Operand::Register("dx"),
+            One => Operand::Immediate(1),
+            Ob | Ov => Operand::Memory(MemoryOperand {
+                size: if let Ob = spec { 1 } else { self.size() },
+                segment: p.segment.map(|s| if s == 0x64 { "fs" } else { "gs" }),
+                base: None,
+                index: None,
+                scale: 1,
+                displacement: d.immediate(self.code) as i64,
+            }),
+            Sw => Operand::Register(SEGMENT[self.reg() & 7]),
+            Cr => Operand::Register(CR[self.reg() & 15]),
+            Dr => Operand::Register(DR[self.reg() & 15]),
+            Rq => self.gpr(self.rm(), 8),
+            Fs => Operand::Register("fs"),
+            Gs => Operand::Register("gs"),
+            Vx => self.vector_register(self.reg(), self.vector()),
+            Vdq => self.vector_register(self.reg(), 16),
+            Hx => self.vector_register(p.vvvv as usize, self.vector()),
+            Wx => self.w(self.vector(), self.vector()),
+            W(size) => self.w(size.max(16), size),
+            Pq => Operand::Register(MM[self.reg() & 7]),
+            Qq | Nq if self.mode() == 3 => Operand::Register(MM[self.rm() & 7]),
+            Qq | Nq => self.memory(8),
+            K => Operand::Register(MASK[self.reg() & 7]),
+            Kv => Operand::Register(MASK[p.vvvv as usize & 7]),
+            Km(_) if self.mode() == 3 => Operand::Register(MASK[self.rm() & 7]),
+            Km(size) => self.memory(size),
+            R(size) => {
+                if self.mode() == 3 {
+                    self.gpr(self.rm(), 4)
+                } else {
+                    self.memory(size)
+                }
+            }
+            Mp => self.memory(2 + self.size()),
+            Lx => self.vector_register((self.code[d.imm] >> 4) as usize, self.vector()),
+            Xmm0 => Operand::Register("xmm0"),
+        }
+    }
+
+    /// The instruction's mnemonic and operands, if it is one it knows.
+    fn disassemble(&self) -> Option<(Cow<'static, str>, Vec<Operand>)> {
+        let d = self.d;
+        let vex = d.prefixes.vector.is_some();
+        if d.map == OpcodeMap::Primary && (0xd8..=0xdf).contains(&d.opcode) {
+            return self.x87();
+        }
+        let (name, specs, form) = match d.map {
+            OpcodeMap::Primary => {
+                let (name, specs) = self.primary()?;
+                (Cow::Borrowed(name), specs, Vex::No)
+            }
+            OpcodeMap::Secondary => self.secondary()?,
+            OpcodeMap::Escape38 => self.escape38()?,
+            OpcodeMap::Escape3a => self.escape3a()?,
+        };
+        let memory_only = |spec: &Spec| matches!(spec, Spec::M(_) | Spec::Mv | Spec::Mx | Spec::Mp);
+        if self.mode() == 3 && specs.iter().any(memory_only) {
+            return None;
+        }
+        let name = match (vex, form) {
+            (false, Vex::Only) | (true, Vex::No) => return None,
+            (true, Vex::Same) | (true, Vex::Nds) => Cow::Owned(format!("v{}", name)),
+            _ => name,
+        };
+        let mut operands: Vec<Operand> = specs.iter().map(|&spec| self.operand(spec)).collect();
+        if vex && form == Vex::Nds {
+            operands.insert(1, self.operand(Spec::Hx));
+        }
+        Some((name, operands))
+    }
+
+    fn primary(&self) -> Option<(&'static str, &'static [Spec])> {
+        use Spec::*;
+        let d = self.d;
+        let p = self.prefixes();
+        let op = d.opcode;
+        let reg = self.reg() & 7;
+        let by_size = |names: [&'static str; 3]| match self.size() {
+            2 => names[0],
+            4 => names[1],
+            _ => names[2],
+        };
+        let entry: (&'static str, &'static [Spec]) = match op {
+            0x00..=0x3f => {
+                let name = ARITH[op as usize >> 3];
+                match op & 7 {
+                    0 => (name, &[Eb, Gb]),
+                    1 => (name, &[Ev, Gv]),
+                    2 => (name, &[Gb, Eb]),
+                    3 => (name, &[Gv, Ev]),
+                    4 => (name, &[Al, Ib]),
+                    5 => (name, &[Ax, Iz]),
+                    _ => return None,
+                }
+            }
+            0x50..=0x57 => ("push", &[Zq]),
+            0x58..=0x5f => ("pop", &[Zq]),
+            0x63 => ("movsxd", &[Gv, Ed]),
+            0x68 => ("push", &[Izq]),
+            0x69 => ("imul", &[Gv, Ev, Iz]),
+            0x6a => ("push", &[Ibq]),
+            0x6b => ("imul", &[Gv, Ev, Ibs]),
+            0x6c => ("insb", &[]),
+            0x6d => (if p.operand16 { "insw" } else { "insd" }, &[]),
+            0x6e => ("outsb", &[]),
+            0x6f => (if p.operand16 { "outsw" } else { "outsd" }, &[]),
+            0x70..=0x7f => (JCC[op as usize & 15], &[Jb]),
+            0x80 => (ARITH[reg], &[Eb, Ib]),
+            0x81 => (ARITH[reg], &[Ev, Iz]),
+            0x83 => (ARITH[reg], &[Ev, Ibs]),
+            0x84 => ("test", &[Eb, Gb]),
+            0x85 => ("test", &[Ev, Gv]),
+            0x86 => ("xchg", &[Eb, Gb]),
+            0x87 => ("xchg", &[Ev, Gv]),
+            0x88 => ("mov", &[Eb, Gb]),
+            0x89 => ("mov", &[Ev, Gv]),
+            0x8a => ("mov", &[Gb, Eb]),
+            0x8b => ("mov", &[Gv, Ev]),
+            0x8c => ("mov", &[Ew, Sw]),
+            0x8d => ("lea", &[Gv, M(0)]),
+            0x8e => ("mov", &[Sw, Ew]),
+            0x8f if reg == 0 => ("pop", &[Eq]),
+            0x90 if p.rex_b != 0 => ("xchg", &[Zv, Ax]),
+            0x90 if p.rep == Some(0xf3) => ("pause", &[]),
+            0x90 => ("nop", &[]),
+            0x91..=0x97 => ("xchg", &[Zv, Ax]),
+            0x98 => (by_size(["cbw", "cwde", "cdqe"]), &[]),
+            0x99 => (by_size(["cwd", "cdq", "cqo"]), &[]),
+            0x9b => ("fwait", &[]),
+            0x9c => (if p.operand16 { "pushfw" } else { "pushfq" }, &[]),
+            0x9d => (if p.operand16 { "popfw" } else { "popfq" }, &[]),
+            0x9e => ("sahf", &[]),
+            0x9f => ("lahf", &[]),
+            0xa0 => ("movabs", &[Al, Ob]),
+            0xa1 => ("movabs", &[Ax, Ov]),
+            0xa2 => ("movabs", &[Ob, Al]),
+            0xa3 => ("movabs", &[Ov, Ax]),
+            0xa4 => ("movsb", &[]),
+            0xa5 => (by_size(["movsw", "movsd", "movsq"]), &[]),
+            0xa6 => ("cmpsb", &[]),
+            0xa7 => (by_size(["cmpsw", "cmpsd", "cmpsq"]), &[]),
+            0xa8 => ("test", &[Al, Ib]),
+            0xa9 => ("test", &[Ax, Iz]),
+            0xaa => ("stosb", &[]),
+            0xab => (by_size(["stosw", "stosd", "stosq"]), &[]),
+            0xac => ("lodsb", &[]),
+            0xad => (by_size(["lodsw", "lodsd", "lodsq"]), &[]),
+            0xae => ("scasb", &[]),
+            0xaf => (by_size(["scasw", "scasd", "scasq"]), &[]),
+            0xb0..=0xb7 => ("mov", &[Zb, Ib]),
+            0xb8..=0xbf if p.rex_w => ("movabs", &[Zv, Iv]),
+            0xb8..=0xbf => ("mov", &[Zv, Iv]),
+            0xc0 => (SHIFT[reg], &[Eb, Ib]),
+            0xc1 => (SHIFT[reg], &[Ev, Ib]),
+            0xc2 => ("ret", &[Iw]),
+            0xc3 => ("ret", &[]),
+            0xc6 if reg == 0 => ("mov", &[Eb, Ib]),
+            0xc6 if d.modrm == Some(0xf8) => ("xabort", &[Ib]),
+            0xc7 if reg == 0 => ("mov", &[Ev, Iz]),
+            0xc7 if d.modrm == Some(0xf8) => ("xbegin", &[Jz]),
+            0xc8 => ("enter", &[Iw, Ib2]),
+            0xc9 => ("leave", &[]),
+            0xca => ("retf", &[Iw]),
+            0xcb => ("retf", &[]),
+            0xcc => ("int3", &[]),
+            0xcd => ("int", &[Ib]),
+            0xcf => (by_size(["iretw", "iretd", "iretq"]), &[]),
+            0xd0 => (SHIFT[reg], &[Eb, One]),
+            0xd1 => (SHIFT[reg], &[Ev, One]),
+            0xd2 => (SHIFT[reg], &[Eb, Cl]),
+            0xd3 => (SHIFT[reg], &[Ev, Cl]),
+            0xd7 => ("xlat", &[]),
+            0xe0 => ("loopne", &[Jb]),
+            0xe1 => ("loope", &[Jb]),
+            0xe2 => ("loop", &[Jb]),
+            0xe3 => (if p.address32 { "jecxz" } else { "jrcxz" }, &[Jb]),
+            0xe4 => ("in", &[Al, Ib]),
+            0xe5 => ("in", &[Az, Ib]),
+            0xe6 => ("out", &[Ib, Al]),
+            0xe7 => ("out", &[Ib, Az]),
+            0xe8 => ("call", &[Jz]),
+            0xe9 => ("jmp", &[Jz]),
+            0xeb => ("jmp", &[Jb]),
+            0xec => ("in", &[Al, Dx]),
+            0xed => ("in", &[Az, Dx]),
+            0xee => ("out", &[Dx, Al]),
+            0xef => ("out", &[Dx, Az]),
+            0xf1 => ("int1", &[]),
+            0xf4 => ("hlt", &[]),
+            0xf5 => ("cmc", &[]),
+            0xf6 if reg < 2 => ("test", &[Eb, Ib]),
+            0xf6 => (UNARY[reg], &[Eb]),
+            0xf7 if reg < 2 => ("test", &[Ev, Iz]),
+            0xf7 => (UNARY[reg], &[Ev]),
+            0xf8 => ("clc", &[]),
+            0xf9 => ("stc", &[]),
+            0xfa => ("cli", &[]),
+            0xfb => ("sti", &[]),
+            0xfc => ("cld", &[]),
+            0xfd => ("std", &[]),
+            0xfe if reg == 0 => ("inc", &[Eb]),
+            0xfe if reg == 1 => ("dec", &[Eb]),
+            0xff => match reg {
+                0 => ("inc", &[Ev]),
+                1 => ("dec", &[Ev]),
+                2 => ("call", &[Eq]),
+                3 => ("call", &[Mp]),
+                4 => ("jmp", &[Eq]),
+                5 => ("jmp", &[Mp]),
+                6 => ("push", &[Eq]),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(entry)
+    }
+
+    fn x87(&self) -> Option<(Cow<'static, str>, Vec<Operand>)> {
+        let d = self.d;
+        let modrm = d.modrm?;
+        let reg = (modrm >> 3 & 7) as usize;
+        let row = (d.opcode - 0xd8) as usize;
+        let st0 = Operand::Register(ST[0]);
+        let sti = Operand::Register(ST[(modrm & 7) as usize]);
+        if modrm >> 6 != 3 {
+            let (name, size) = X87_MEMORY[row][reg];
+            if name.is_empty() {
+                return None;
+            }
+            return Some((Cow::Borrowed(name), vec![self.memory(size)]));
+        }
+        let (name, operands) = match (d.opcode, reg, modrm) {
+            (0xd8, _, _) => (X87_MEMORY[0][reg].0, vec![st0, sti]),
+            (0xd9, 0, _) => ("fld", vec![sti]),
+            (0xd9, 1, _) => ("fxch", vec![sti]),
+            (0xd9, _, 0xd0) => ("fnop", vec![]),
+            (0xd9, _, 0xe0..=0xff) => (X87_D9[(modrm - 0xe0) as usize], vec![]),
+            (0xda, 0..=3, _) => (
+                ["fcmovb", "fcmove", "fcmovbe", "fcmovu"][reg],
+                vec![st0, sti],
+            ),
+            (0xda, _, 0xe9) => ("fucompp", vec![]),
+            (0xdb, 0..=3, _) => (
+                ["fcmovnb", "fcmovne", "fcmovnbe", "fcmovnu"][reg],
+                vec![st0, sti],
+            ),
+            (0xdb, _, 0xe2) => ("fnclex", vec![]),
+            (0xdb, _, 0xe3) => ("fninit", vec![]),
+            (0xdb, 5, _) => ("fucomi", vec![st0, sti]),
+            (0xdb, 6, _) => ("fcomi", vec![st0, sti]),
+            (0xdc, 2..=3, _) => return None,
+            (0xdc, _, _) => (
+                ["fadd", "fmul", "", "", "fsubr", "fsub", "fdivr", "fdiv"][reg],
+                vec![sti, st0],
+            ),
+            (0xdd, 0, _) => ("ffree", vec![sti]),
+            (0xdd, 2, _) => ("fst", vec![sti]),
+            (0xdd, 3, _) => ("fstp", vec![sti]),
+            (0xdd, 4, _) => ("fucom", vec![sti]),
+            (0xdd, 5, _) => ("fucomp", vec![sti]),
+            (0xde, _, 0xd9) => ("fcompp", vec![]),
+            (0xde, 2..=3, _) => return None,
+            (0xde, _, _) => (
+                [
+                    "faddp", "fmulp", "", "", "fsubrp", "fsubp", "fdivrp", "fdivp",
+                ][reg],
+                vec![sti, st0],
+            ),
+            (0xdf, 0, _) => ("ffreep", vec![sti]),
+            (0xdf, _, 0xe0) => ("fnstsw", vec![Operand::Register("ax")]),
+            (0xdf, 5, _) => ("fucomip", vec![st0, sti]),
+            (0xdf, 6, _) => ("fcomip", vec![st0, sti]),
+            _ => return None,
+        };
+        if name.is_empty() {
+            return None;
+        }
+        Some((Cow::Borrowed(name), operands))
+    }
+
+    /// Which of no prefix, `66`, `f3` and `f2` selects an SSE instruction,
+    /// from 0 to 3.
+    fn sse_prefix(&self) -> u8 {
+        let p = self.prefixes();
+        match (p.vector, p.rep) {
+            (Some(_), _) => p.pp,
+            (None, Some(0xf3)) => 2,
+            (None, Some(_)) => 3,
+            (None, None) if p.operand16 => 1,
+            (None, None) => 0,
+        }
+    }
+
+    fn secondary(&self) -> Option<Entry> {
+        use Spec::*;
+        use Vex::*;
+        let d = self.d;
+        let p = self.prefixes();
+        let op = d.opcode;
+        let reg = self.reg() & 7;
+        let modrm = d.modrm.unwrap_or(0);
+        let memory = self.mode() != 3;
+        let sp = self.sse_prefix();
+        let general = |name: &'static str, specs: &'static [Spec]| Some((name, specs, No));
+        // The four forms of an SSE arithmetic instruction.
+        let arith = |names: [&'static str; 4]| {
+            let specs: &'static [Spec] = match sp {
+                0 | 1 => &[Vx, Wx],
+                2 => &[Vx, W(4)],
+                _ => &[Vx, W(8)],
+            };
+            Some((names[sp as usize], specs, Nds))
+        };
+        let packed = |names: [&'static str; 2], specs: &'static [Spec], form: Vex| match sp {
+            0 | 1 => Some((names[sp as usize], specs, form)),
+            _ => None,
+        };
+        let wide = |narrow: &'static str, wide: &'static str| if p.rex_w { wide } else { narrow };
+        let entry: Option<(&'static str, &'static [Spec], Vex)> = match op {
+            0x00 => match reg {
+                0..=5 => general(["sldt", "str", "lldt", "ltr", "verr", "verw"][reg], &[Ew]),
+                _ => None,
+            },
+            0x01 if memory => match reg {
+                5 => None,
+                4 | 6 => general(["smsw", "lmsw"][reg / 2 - 2], &[Ew]),
+                _ => general(
+                    ["sgdt", "sidt", "lgdt", "lidt", "", "", "", "invlpg"][reg],
+                    &[M(0)],
+                ),
+            },
+            0x01 => match modrm {
+                0xc8 => general("monitor", &[]),
+                0xc9 => general("mwait", &[]),
+                0xca => general("clac", &[]),
+                0xcb => general("stac", &[]),
+                0xd0 => general("xgetbv", &[]),
+                0xd1 => general("xsetbv", &[]),
+                0xd5 => general("xend", &[]),
+                0xd6 => general("xtest", &[]),
+                0xee => general("rdpkru", &[]),
+                0xef => general("wrpkru", &[]),
+                0xf8 => general("swapgs", &[]),
+                0xf9 => general("rdtscp", &[]),
+                _ => None,
+            },
+            0x05 => general("syscall", &[]),
+            0x06 => general("clts", &[]),
+            0x07 => general("sysret", &[]),
+            0x08 => general("invd", &[]),
+            0x09 => general("wbinvd", &[]),
+            0x0b => general("ud2", &[]),
+            0x0d if memory => general(if reg == 1 { "prefetchw" } else { "prefetch" }, &[M(1)]),
+            0x18 if memory && reg < 4 => general(
+                ["prefetchnta", "prefetcht0", "prefetcht1", "prefetcht2"][reg],
+                &[M(1)],
+            ),
+            0x1e if p.rep == Some(0xf3) && modrm == 0xfa => general("endbr64", &[]),
+            0x1e if p.rep == Some(0xf3) && modrm == 0xfb => general("endbr32", &[]),
+            0x18..=0x1f => general("nop", &[Ev]),
+            0x20 => general("mov", &[Rq, Cr]),
+            0x21 => general("mov", &[Rq, Dr]),
+            0x22 => general("mov", &[Cr, Rq]),
+            0x23 => general("mov", &[Dr, Rq]),
+            0x30 => general("wrmsr", &[]),
+            0x31 => general("rdtsc", &[]),
+            0x32 => general("rdmsr", &[]),
+            0x33 => general("rdpmc", &[]),
+            0x34 => general("sysenter", &[]),
+            0x35 => general("sysexit", &[]),
+            0x41..=0x4b | 0x90..=0x93 | 0x98..=0x99 if p.vector.is_some() => {
+                return self.opmask();
+            }
+            0x40..=0x4f => general(CMOVCC[op as usize & 15], &[Gv, Ev]),
+            0x80..=0x8f => general(JCC[op as usize & 15], &[Jz]),
+            0x90..=0x9f => general(SETCC[op as usize & 15], &[Eb]),
+            0xa0 => general("push", &[Fs]),
+            0xa1 => general("pop", &[Fs]),
+            0xa2 => general("cpuid", &[]),
+            0xa3 => general("bt", &[Ev, Gv]),
+            0xa4 => general("shld", &[Ev, Gv, Ib]),
+            0xa5 => general("shld", &[Ev, Gv, Cl]),
+            0xa8 => general("push", &[Gs]),
+            0xa9 => general("pop", &[Gs]),
+            0xab => general("bts", &[Ev, Gv]),
+            0xac => general("shrd", &[Ev, Gv, Ib]),
+            0xad => general("shrd", &[Ev, Gv, Cl]),
+            0xae if memory => match (sp, reg) {
+                (1, 6) => general("clwb", &[M(1)]),
+                (1, 7) => general("clflushopt", &[M(1)]),
+                (0, 2) => Some(("ldmxcsr", &[M(4)][..], Same)),
+                (0, 3) => Some(("stmxcsr", &[M(4)][..], Same)),
+                (0, _) => general(
+                    [
+                        wide("fxsave", "fxsave64"),
+                        wide("fxrstor", "fxrstor64"),
+                        "",
+                        "",
+                        wide("xsave", "xsave64"),
+                        wide("xrstor", "xrstor64"),
+                        wide("xsaveopt", "xsaveopt64"),
+                        "clflush",
+                    ][reg],
+                    &[M(0)],
+                ),
+                _ => None,
+            },
+            0xae => match (sp, reg) {
+                (2, 0..=3) => general(["rdfsbase", "rdgsbase", "wrfsbase", "wrgsbase"][reg], &[Ey]),
+                (0, 5) => general("lfence", &[]),
+                (0, 6) => general("mfence", &[]),
+                (0, 7) => general("sfence", &[]),
+                _ => None,
+            },
+            0xaf => general("imul", &[Gv, Ev]),
+            0xb0 => general("cmpxchg", &[Eb, Gb]),
+            0xb1 => general("cmpxchg", &[Ev, Gv]),
+            0xb3 => general("btr", &[Ev, Gv]),
+            0xb6 => general("movzx", &[Gv, Eb]),
+            0xb7 => general("movzx", &[Gv, Ew]),
+            0xb8 if sp == 2 => general("popcnt", &[Gv, Ev]),
+            0xba if reg >= 4 => general(["bt", "bts", "btr", "btc"][reg - 4], &[Ev, Ib]),
+            0xbb => general("btc", &[Ev, Gv]),
+            0xbc => general(if sp == 2 { "tzcnt" } else { "bsf" }, &[Gv, Ev]),
+            0xbd => general(if sp == 2 { "lzcnt" } else { "bsr" }, &[Gv, Ev]),
+            0xbe => general("movsx", &[Gv, Eb]),
+            0xbf => general("movsx", &[Gv, Ew]),
+            0xc0 => general("xadd", &[Eb, Gb]),
+            0xc1 => general("xadd", &[Ev, Gv]),
+            0xc3 if memory => general("movnti", &[Ey, Gy]),
+            0xc7 if memory => match reg {
+                1 if p.rex_w => general("cmpxchg16b", &[M(16)]),
+                1 => general("cmpxchg8b", &[M(8)]),
+                3 => general(wide("xrstors", "xrstors64"), &[M(0)]),
+                4 => general(wide("xsavec", "xsavec64"), &[M(0)]),
+                5 => general(wide("xsaves", "xsaves64"), &[M(0)]),
+                _ => None,
+            },
+            0xc7 => match (sp, reg) {
+                (2, 7) => general("rdpid", &[Rq]),
+                (_, 6) => general("rdrand", &[Ev]),
+                (_, 7) => general("rdseed", &[Ev]),
+                _ => None,
+            },
+            0xc8..=0xcf => general("bswap", &[Zv]),
+
+            0x10 | 0x11 => {
+                let name = ["movups", "movupd", "movss", "movsd"][sp as usize];
+                let specs: &'static [Spec] = match (op, sp) {
+                    (0x10, 0..=1) => &[Vx, Wx],
+                    (0x10, 2) => &[Vx, W(4)],
+                    (0x10, _) => &[Vx, W(8)],
+                    (_, 0..=1) => &[Wx, Vx],
+                    (_, 2) => &[W(4), Vx],
+                    _ => &[W(8), Vx],
+                };
+                let form = if sp >= 2 && !memory { Nds } else { Same };
+                Some((name, specs, form))
+            }
+            0x12 => match sp {
+                0 if !memory => Some(("movhlps", &[Vx, W(16)][..], Nds)),
+                0 => Some(("movlps", &[Vx, M(8)][..], Nds)),
+                1 => Some(("movlpd", &[Vx, M(8)][..], Nds)),
+                2 => Some(("movsldup", &[Vx, Wx][..], Same)),
+                _ => Some(("movddup", &[Vx, W(8)][..], Same)),
+            },
+            0x13 if memory => packed(["movlps", "movlpd"], &[M(8), Vx], Same),
+            0x14 => packed(["unpcklps", "unpcklpd"], &[Vx, Wx], Nds),
+            0x15 => packed(["unpckhps", "unpckhpd"], &[Vx, Wx], Nds),
+            0x16 => match sp {
+                0 if !memory => Some(("movlhps", &[Vx, W(16)][..], Nds)),
+                0 => Some(("movhps", &[Vx, M(8)][..], Nds)),
+                1 => Some(("movhpd", &[Vx, M(8)][..], Nds)),
+                2 => Some(("movshdup", &[Vx, Wx][..], Same)),
+                _ => None,
+            },
+            0x17 if memory => packed(["movhps", "movhpd"], &[M(8), Vx], Same),
+            0x28 => packed(["movaps", "movapd"], &[Vx, Wx], Same),
+            0x29 => packed(["movaps", "movapd"], &[Wx, Vx], Same),
+            0x2a => match sp {
+                2 => Some(("cvtsi2ss", &[Vx, Ey][..], Nds)),
+                3 => Some(("cvtsi2sd", &[Vx, Ey][..], Nds)),
+                _ => Some((["cvtpi2ps", "cvtpi2pd"][sp as usize], &[Vx, Qq][..], No)),
+            },
+            0x2b if memory => packed(["movntps", "movntpd"], &[Mx, Vx], Same),
+            0x2c | 0x2d => {
+                let truncate = op == 0x2c;
+                match sp {
+                    2 => Some((
+                        if truncate { "cvttss2si" } else { "cvtss2si" },
+                        &[Gy, W(4)][..],
+                        Same,
+                    )),
+                    3 => Some((
+                        if truncate { "cvttsd2si" } else { "cvtsd2si" },
+                        &[Gy, W(8)][..],
+                        Same,
+                    )),
+                    0 => Some((
+                        if truncate { "cvttps2pi" } else { "cvtps2pi" },
+                        &[Pq, W(8)][..],
+                        No,
+                    )),
+                    _ => Some((
+                        if truncate { "cvttpd2pi" } else { "cvtpd2pi" },
+                        &[Pq, Wx][..],
+                        No,
+                    )),
+                }
+            }
+            0x2e => packed(
+                ["ucomiss", "ucomisd"],
+                [&[Vx, W(4)][..], &[Vx, W(8)]][sp.min(1) as usize],
+                Same,
+            ),
+            0x2f => packed(
+                ["comiss", "comisd"],
+                [&[Vx, W(4)][..], &[Vx, W(8)]][sp.min(1) as usize],
+                Same,
+            ),
+            0x50 if !memory => packed(["movmskps", "movmskpd"], &[Gd, Wx], Same),
+            0x51 => match sp {
+                0 | 1 => Some((["sqrtps", "sqrtpd"][sp as usize], &[Vx, Wx][..], Same)),
+                2 => Some(("sqrtss", &[Vx, W(4)][..], Nds)),
+                _ => Some(("sqrtsd", &[Vx, W(8)][..], Nds)),
+            },
+            0x52 | 0x53 => {
+                let names = if op == 0x52 {
+                    ["rsqrtps", "rsqrtss"]
+                } else {
+                    ["rcpps", "rcpss"]
+                };
+                match sp {
+                    0 => Some((names[0], &[Vx, Wx][..], Same)),
+                    2 => Some((names[1], &[Vx, W(4)][..], Nds)),
+                    _ => None,
+                }
+            }
+            0x54 => packed(["andps", "andpd"], &[Vx, Wx], Nds),
+            0x55 => packed(["andnps", "andnpd"], &[Vx, Wx], Nds),
+            0x56 => packed(["orps", "orpd"], &[Vx, Wx], Nds),
+            0x57 => packed(["xorps", "xorpd"], &[Vx, Wx], Nds),
+            0x58 => arith(["addps", "addpd", "addss", "addsd"]),
+            0x59 => arith(["mulps", "mulpd", "mulss", "mulsd"]),
+            0x5a => match sp {
+                0 => Some(("cvtps2pd", &[Vx, W(8)][..], Same)),
+                1 => Some(("cvtpd2ps", &[Vx, Wx][..], Same)),
+                2 => Some(("cvtss2sd", &[Vx, W(4)][..], Nds)),
+                _ => Some(("cvtsd2ss", &[Vx, W(8)][..], Nds)),
+            },
+            0x5b => match sp {
+                0..=2 => Some((
+                    ["cvtdq2ps", "cvtps2dq", "cvttps2dq"][sp as usize],
+                    &[Vx, Wx][..],
+                    Same,
+                )),
+                _ => None,
+            },
+            0x5c => arith(["subps", "subpd", "subss", "subsd"]),
+            0x5d => arith(["minps", "minpd", "minss", "minsd"]),
+            0x5e => arith(["divps", "divpd", "divss", "divsd"]),
+            0x5f => arith(["maxps", "maxpd", "maxss", "maxsd"]),
+            0x6e => match sp {
+                0 => Some((wide("movd", "movq"), &[Pq, Ey][..], No)),
+                1 => Some((wide("movd", "movq"), &[Vdq, Ey][..], Same)),
+                _ => None,
+            },
+            0x6f | 0x7f => {
+                let specs: &'static [Spec] = match (op, sp) {
+                    (0x6f, 0) => &[Pq, Qq],
+                    (0x6f, _) => &[Vx, Wx],
+                    (_, 0) => &[Qq, Pq],
+                    _ => &[Wx, Vx],
+                };
+                match (sp, p.evex) {
+                    (0, _) => Some(("movq", specs, No)),
+                    (1, Some(_)) => Some((wide("vmovdqa32", "vmovdqa64"), specs, Only)),
+                    (2, Some(_)) => Some((wide("vmovdqu32", "vmovdqu64"), specs, Only)),
+                    (3, Some(_)) => Some((wide("vmovdqu8", "vmovdqu16"), specs, Only)),
+                    (1, None) => Some(("movdqa", specs, Same)),
+                    (2, None) => Some(("movdqu", specs, Same)),
+                    _ => None,
+                }
+            }
+            0x70 => match sp {
+                0 => Some(("pshufw", &[Pq, Qq, Ib][..], No)),
+                _ => Some((
+                    ["", "pshufd", "pshufhw", "pshuflw"][sp as usize],
+                    &[Vx, Wx, Ib][..],
+                    Same,
+                )),
+            },
+            0x71..=0x73 if !memory => {
+                let names = match op {
+                    0x71 => ["", "", "psrlw", "", "psraw", "", "psllw", ""],
+                    0x72 => ["", "", "psrld", "", "psrad", "", "pslld", ""],
+                    _ => ["", "", "psrlq", "psrldq", "", "", "psllq", "pslldq"],
+                };
+                let name = match (p.evex, op, reg) {
+                    (Some(_), 0x72, 0) => wide("prord", "prorq"),
+                    (Some(_), 0x72, 1) => wide("prold", "prolq"),
+                    (Some(_), 0x72, 4) => wide("psrad", "psraq"),
+                    _ => names[reg],
+                };
+                match sp {
+                    _ if name.is_empty() => None,
+                    0 if reg == 3 || reg == 7 => None,
+                    0 => Some((name, &[Nq, Ib][..], No)),
+                    1 if p.vector.is_some() => {
+                        return Some((Cow::Owned(format!("v{}", name)), &[Hx, Wx, Ib], Only))
+                    }
+                    1 => Some((name, &[Wx, Ib][..], Same)),
+                    _ => None,
+                }
+            }
+            0x77 => match (p.vector, sp) {
+                (Some(16), 0) => Some(("vzeroupper", &[][..], Only)),
+                (Some(_), 0) => Some(("vzeroall", &[][..], Only)),
+                (None, 0) => Some(("emms", &[][..], No)),
+                _ => None,
+            },
+            0x7c => match sp {
+                1 => Some(("haddpd", &[Vx, Wx][..], Nds)),
+                3 => Some(("haddps", &[Vx, Wx][..], Nds)),
+                _ => None,
+            },
+            0x7d => match sp {
+                1 => Some(("hsubpd", &[Vx, Wx][..], Nds)),
+                3 => Some(("hsubps", &[Vx, Wx][..], Nds)),
+                _ => None,
+            },
+            0x7e => match sp {
+                0 => Some((wide("movd", "movq"), &[Ey, Pq][..], No)),
+                1 => Some((wide("movd", "movq"), &[Ey, Vdq][..], Same)),
+                2 => Some(("movq", &[Vdq, W(8)][..], Same)),
+                _ => None,
+            },
+            0xc2 => {
+                let suffix = ["ps", "pd", "ss", "sd"][sp as usize];
+                let specs: &'static [Spec] = match sp {
+                    0 | 1 => &[Vx, Wx],
+                    2 => &[Vx, W(4)],
+                    _ => &[Vx, W(8)],
+                };
+                // The predicate is written as part of the mnemonic, as in
+                // `cmpltsd`, where it has a name.
+                let limit = if p.vector.is_some() { 32 } else { 8 };
+                let predicate = self.code[d.imm] as usize;
+                if predicate < limit {
+                    let name = format!("cmp{}{}", COMPARE[predicate], suffix);
+                    return Some((Cow::Owned(name), specs, Nds));
+                }
+                let specs: &'static [Spec] = match sp {
+                    0 | 1 => &[Vx, Wx, Ib],
+                    2 => &[Vx, W(4), Ib],
+                    _ => &[Vx, W(8), Ib],
+                };
+                Some((
+                    ["cmpps", "cmppd", "cmpss", "cmpsd"][sp as usize],
+                    specs,
+                    Nds,
+                ))
+            }
+            0xc4 => match sp {
+                0 => Some(("pinsrw", &[Pq, R(2), Ib][..], No)),
+                1 => Some(("pinsrw", &[Vdq, R(2), Ib][..], Nds)),
+                _ => None,
+            },
+            0xc5 if !memory => match sp {
+                0 => Some(("pextrw", &[Gd, Nq, Ib][..], No)),
+                1 => Some(("pextrw", &[Gd, W(16), Ib][..], Same)),
+                _ => None,
+            },
+            0xc6 => packed(["shufps", "shufpd"], &[Vx, Wx, Ib], Nds),
+            0xd0 => match sp {
+                1 => Some(("addsubpd", &[Vx, Wx][..], Nds)),
+                3 => Some(("addsubps", &[Vx, Wx][..], Nds)),
+                _ => None,
+            },
+            0xd6 if sp == 1 => Some(("movq", &[W(8), Vdq][..], Same)),
+            0xd7 if !memory => match sp {
+                0 => Some(("pmovmskb", &[Gd, Nq][..], No)),
+                1 => Some(("pmovmskb", &[Gd, Wx][..], Same)),
+                _ => None,
+            },
+            0xe6 => match sp {
+                1 => Some(("cvttpd2dq", &[Vx, Wx][..], Same)),
+                2 => Some(("cvtdq2pd", &[Vx, W(8)][..], Same)),
+                3 => Some(("cvtpd2dq", &[Vx, Wx][..], Same)),
+                _ => None,
+            },
+            0xe7 if memory => match sp {
+                0 => Some(("movntq", &[M(8), Pq][..], No)),
+                1 => Some(("movntdq", &[Mx, Vx][..], Same)),
+                _ => None,
+            },
+            0xf0 if memory && sp == 3 => Some(("lddqu", &[Vx, Mx][..], Same)),
+            0xf7 if !memory => match sp {
+                0 => Some(("maskmovq", &[Pq, Nq][..], No)),
+                1 => Some(("maskmovdqu", &[Vdq, W(16)][..], Same)),
+                _ => None,
+            },
+            _ => {
+                let name = simd_integer(op)?;
+                if let (Some(_), 0x64..=0x66) | (Some(_), 0x74..=0x76) = (p.evex, op) {
+                    // The `EVEX` compares set an opmask.
+                    return Some((Cow::Owned(format!("v{}", name)), &[K, Hx, Wx], Only));
+                }
+                let name = match (name, p.evex) {
+                    ("pand", Some(_)) => wide("pandd", "pandq"),
+                    ("pandn", Some(_)) => wide("pandnd", "pandnq"),
+                    ("por", Some(_)) => wide("pord", "porq"),
+                    ("pxor", Some(_)) => wide("pxord", "pxorq"),
+                    (name, _) => name,
+                };
+                match sp {
+                    // There are no MMX forms of these.
+                    0 if op == 0x6c || op == 0x6d => None,
+                    0 => Some((name, &[Pq, Qq][..], No)),
+                    1 => Some((name, &[Vx, Wx][..], Nds)),
+                    _ => None,
+                }
+            }
+        };
+        entry.map(|(name, specs, form)| (Cow::Borrowed(name), specs, form))
+    }
+
+    fn escape38(&self) -> Option<Entry> {
+        use Spec::*;
+        use Vex::*;
+        let d = self.d;
+        let p = self.prefixes();
+        let op = d.opcode;
+        let sp = self.sse_prefix();
+        let vex = p.vector.is_some();
+        let reg = self.reg() & 7;
+        let memory = self.mode() != 3;
+        let wide = |narrow: &'static str, wide: &'static str| if p.rex_w { wide } else { narrow };
+        let entry: (&'static str, &'static [Spec], Vex) = match (op, sp) {
+            (0xf0, 0) | (0xf1, 0) if !vex && memory => {
+                let specs: &'static [Spec] = if op == 0xf0 { &[Gv, Mv] } else { &[Mv, Gv] };
+                ("movbe", specs, No)
+            }
+            (0xf0, 3) if !vex => ("crc32", &[Gy, Eb], No),
+            (0xf6, 1) if !vex => ("adcx", &[Gy, Ey], No),
+            (0xf6, 2) if !vex => ("adox", &[Gy, Ey], No),
+            (0xc8, 0) => ("sha1nexte", &[Vdq, W(16)], No),
+            (0xc9, 0) => ("sha1msg1", &[Vdq, W(16)], No),
+            (0xca, 0) => ("sha1msg2", &[Vdq, W(16)], No),
+            (0xcb, 0) => ("sha256rnds2", &[Vdq, W(16), Xmm0], No),
+            (0xcc, 0) => ("sha256msg1", &[Vdq, W(16)], No),
+            (0xcd, 0) => ("sha256msg2", &[Vdq, W(16)], No),
+            (0xf1, 3) if !vex => ("crc32", &[Gy, Ev], No),
+            (0xf2, 0) if vex => ("andn", &[Gy, By, Ey], Only),
+            (0xf3, 0) if vex => match reg {
+                1 => ("blsr", &[By, Ey], Only),
+                2 => ("blsmsk", &[By, Ey], Only),
+                3 => ("blsi", &[By, Ey], Only),
+                _ => return None,
+            },
+            (0xf5, 0) if vex => ("bzhi", &[Gy, Ey, By], Only),
+            (0xf5, 2) if vex => ("pext", &[Gy, By, Ey], Only),
+            (0xf5, 3) if vex => ("pdep", &[Gy, By, Ey], Only),
+            (0xf6, 3) if vex => ("mulx", &[Gy, By, Ey], Only),
+            (0xf7, _) if vex => (
+                ["bextr", "shlx", "sarx", "shrx"][sp as usize],
+                &[Gy, Ey, By],
+                Only,
+            ),
+            (0x96..=0x9f, 1) | (0xa6..=0xaf, 1) | (0xb6..=0xbf, 1) if vex => {
+                return self.fma();
+            }
+            (0x00..=0x0b, 0) | (0x1c..=0x1e, 0) => (ssse3(op)?, &[Pq, Qq], No),
+            (0x26..=0x27, 1) | (0x26..=0x27, 2) if p.evex.is_some() => {
+                let name = if sp == 1 { "vptestm" } else { "vptestnm" };
+                let suffix = match (op, p.rex_w) {
+                    (0x26, false) => "b",
+                    (0x26, true) => "w",
+                    (_, false) => "d",
+                    (_, true) => "q",
+                };
+                return Some((
+                    Cow::Owned(format!("{}{}", name, suffix)),
+                    &[K, Hx, Wx],
+                    Only,
+                ));
+            }
+            (0x29, 1) if p.evex.is_some() => ("vpcmpeqq", &[K, Hx, Wx], Only),
+            (0x37, 1) if p.evex.is_some() => ("vpcmpgtq", &[K, Hx, Wx], Only),
+            (0x7a, 1) if p.evex.is_some() => ("vpbroadcastb", &[Vx, Ed], Only),
+            (0x7b, 1) if p.evex.is_some() => ("vpbroadcastw", &[Vx, Ed], Only),
+            (0x7c, 1) if p.evex.is_some() => {
+                (wide("vpbroadcastd", "vpbroadcastq"), &[Vx, Ey], Only)
+            }
+            (_, 1) => match op {
+                0x00..=0x0b => (ssse3(op)?, &[Vx, Wx], Nds),
+                0x0c => ("vpermilps", &[Vx, Hx, Wx], Only),
+                0x0d => ("vpermilpd", &[Vx, Hx, Wx], Only),
+                0x10 => ("pblendvb", &[Vx, Wx, Xmm0], No),
+                0x14 => ("blendvps", &[Vx, Wx, Xmm0], No),
+                0x15 => ("blendvpd", &[Vx, Wx, Xmm0], No),
+                0x16 => ("vpermps", &[Vx, Hx, Wx], Only),
+                0x17 => ("ptest", &[Vx, Wx], Same),
+                0x18 => ("vbroadcastss", &[Vx, W(4)], Only),
+                0x19 => ("vbroadcastsd", &[Vx, W(8)], Only),
+                0x1a if memory && p.evex.is_some() => (
+                    wide("vbroadcastf32x4", "vbroadcastf64x2"),
+                    &[Vx, M(16)],
+                    Only,
+                ),
+                0x1a if memory => ("vbroadcastf128", &[Vx, M(16)], Only),
+                0x1c..=0x1e => (ssse3(op)?, &[Vx, Wx], Same),
+                0x20 | 0x23 | 0x25 => (pmov(op)?, &[Vx, W(8)], Same),
+                0x21 | 0x24 => (pmov(op)?, &[Vx, W(4)], Same),
+                0x22 => (pmov(op)?, &[Vx, W(2)], Same),
+                0x30 | 0x33 | 0x35 => (pmov(op)?, &[Vx, W(8)], Same),
+                0x31 | 0x34 => (pmov(op)?, &[Vx, W(4)], Same),
+                0x32 => (pmov(op)?, &[Vx, W(2)], Same),
+                0x28 => ("pmuldq", &[Vx, Wx], Nds),
+                0x29 => ("pcmpeqq", &[Vx, Wx], Nds),
+                0x2a if memory => ("movntdqa", &[Vx, Mx], Same),
+                0x2b => ("packusdw", &[Vx, Wx], Nds),
+                0x36 => ("vpermd", &[Vx, Hx, Wx], Only),
+                0x37 => ("pcmpgtq", &[Vx, Wx], Nds),
+                0x38..=0x40 => (
+                    [
+                        "pminsb", "pminsd", "pminuw", "pminud", "pmaxsb", "pmaxsd", "pmaxuw",
+                        "pmaxud", "pmulld",
+                    ][op as usize - 0x38],
+                    &[Vx, Wx],
+                    Nds,
+                ),
+                0x41 => ("phminposuw", &[Vx, Wx], Same),
+                0x45 => (wide("vpsrlvd", "vpsrlvq"), &[Vx, Hx, Wx], Only),
+                0x46 => ("vpsravd", &[Vx, Hx, Wx], Only),
+                0x47 => (wide("vpsllvd", "vpsllvq"), &[Vx, Hx, Wx], Only),
+                0x58 => ("vpbroadcastd", &[Vx, W(4)], Only),
+                0x59 => ("vpbroadcastq", &[Vx, W(8)], Only),
+                0x5a if memory && p.evex.is_some() => (
+                    wide("vbroadcasti32x4", "vbroadcasti64x2"),
+                    &[Vx, M(16)],
+                    Only,
+                ),
+                0x5a if memory => ("vbroadcasti128", &[Vx, M(16)], Only),
+                0xb4 => ("vpmadd52luq", &[Vx, Hx, Wx], Only),
+                0xb5 => ("vpmadd52huq", &[Vx, Hx, Wx], Only),
+                0x78 => ("vpbroadcastb", &[Vx, W(1)], Only),
+                0x79 => ("vpbroadcastw", &[Vx, W(2)], Only),
+                0xdb => ("aesimc", &[Vx, Wx], Same),
+                0xdc..=0xdf => (
+                    ["aesenc", "aesenclast", "aesdec", "aesdeclast"][op as usize - 0xdc],
+                    &[Vx, Wx],
+                    Nds,
+                ),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some((Cow::Borrowed(entry.0), entry.1, entry.2))
+    }
+
+    /// The `VEX` encoded instructions on `AVX-512` opmask registers, whose
+    /// suffix is the size of the mask, from `pp` and `VEX.W`.
+    fn opmask(&self) -> Option<Entry> {
+        use Spec::*;
+        let d = self.d;
+        let p = self.prefixes();
+        let memory = self.mode() != 3;
+        let (suffix, size) = match (p.pp, p.rex_w) {
+            (0, false) => ("w", 2),
+            (0, true) => ("q", 8),
+            (1, false) => ("b", 1),
+            (1, true) => ("d", 4),
+            // The moves to and from general-purpose registers.
+            (2, _) => return None,
+            (_, false) => ("d", 4),
+            (_, true) => ("q", 8),
+        };
+        let gpr = p.pp == 3;
+        let (name, specs): (&str, &'static [Spec]) = match d.opcode {
+            0x41 => ("kand", &[K, Kv, Km(0)]),
+            0x42 => ("kandn", &[K, Kv, Km(0)]),
+            0x44 => ("knot", &[K, Km(0)]),
+            0x45 => ("kor", &[K, Kv, Km(0)]),
+            0x46 => ("kxnor", &[K, Kv, Km(0)]),
+            0x47 => ("kxor", &[K, Kv, Km(0)]),
+            0x4a => ("kadd", &[K, Kv, Km(0)]),
+            0x4b => {
+                let name = match (p.pp, p.rex_w) {
+                    (1, false) => "kunpckbw",
+                    (0, false) => "kunpckwd",
+                    (0, true) => "kunpckdq",
+                    _ => return None,
+                };
+                return Some((Cow::Borrowed(name), &[K, Kv, Km(0)], Vex::Only));
+            }
+            0x90 if !gpr => match size {
+                1 => ("kmov", &[K, Km(1)]),
+                2 => ("kmov", &[K, Km(2)]),
+                4 => ("kmov", &[K, Km(4)]),
+                _ => ("kmov", &[K, Km(8)]),
+            },
+            0x91 if !gpr && memory => match size {
+                1 => ("kmov", &[Km(1), K]),
+                2 => ("kmov", &[Km(2), K]),
+                4 => ("kmov", &[Km(4), K]),
+                _ => ("kmov", &[Km(8), K]),
+            },
+            0x92 if !memory && (gpr || !p.rex_w) => ("kmov", &[K, Ey]),
+            0x93 if !memory && (gpr || !p.rex_w) => ("kmov", &[Gy, Km(0)]),
+            0x98 if !memory => ("kortest", &[K, Km(0)]),
+            0x99 if !memory => ("ktest", &[K, Km(0)]),
+            _ => return None,
+        };
+        if !memory || d.opcode >= 0x90 {
+            Some((Cow::Owned(format!("{}{}", name, suffix)), specs, Vex::Only))
+        } else {
+            None
+        }
+    }
+
+    /// The FMA instructions, which are all `VEX` encoded with the order of
+    /// their operands in the opcode.
+    fn fma(&self) -> Option<Entry> {
+        use Spec::*;
+        let d = self.d;
+        let p = self.prefixes();
+        let op = d.opcode;
+        let order = ["132", "213", "231"][(op >> 4) as usize - 9];
+        let (base, scalar) = match op & 15 {
+            6 => ("fmaddsub", false),
+            7 => ("fmsubadd", false),
+            8 => ("fmadd", false),
+            9 => ("fmadd", true),
+            10 => ("fmsub", false),
+            11 => ("fmsub", true),
+            12 => ("fnmadd", false),
+            13 => ("fnmadd", true),
+            14 => ("fnmsub", false),
+            _ => ("fnmsub", true),
+        };
+        let suffix = match (scalar, p.rex_w) {
+            (false, false) => "ps",
+            (false, true) => "pd",
+            (true, false) => "ss",
+            (true, true) => "sd",
+        };
+        let specs: &'static [Spec] = match (scalar, p.rex_w) {
+            (false, _) => &[Vx, Hx, Wx],
+            (true, false) => &[Vx, Hx, W(4)],
+            (true, true) => &[Vx, Hx, W(8)],
+        };
+        let name = format!("v{}{}{}", base, order, suffix);
+        Some((Cow::Owned(name), specs, Vex::Only))
+    }
+
+    fn escape3a(&self) -> Option<Entry> {
+        use Spec::*;
+        use Vex::*;
+        let d = self.d;
+        let p = self.prefixes();
+        let op = d.opcode;
+        let sp = self.sse_prefix();
+        let wide = |narrow: &'static str, wide: &'static str| if p.rex_w { wide } else { narrow };
+        let entry: (&'static str, &'static [Spec], Vex) = match (op, sp) {
+            (0x0f, 0) => ("palignr", &[Pq, Qq, Ib], No),
+            (0x1e..=0x1f, 1) | (0x3e..=0x3f, 1) if p.evex.is_some() => {
+                let suffix = match (op, p.rex_w) {
+                    (0x1e, false) => "ud",
+                    (0x1e, true) => "uq",
+                    (0x1f, false) => "d",
+                    (0x1f, true) => "q",
+                    (0x3e, false) => "ub",
+                    (0x3e, true) => "uw",
+                    (_, false) => "b",
+                    (_, true) => "w",
+                };
+                let predicate = ["eq", "lt", "le", "", "neq", "nlt", "nle", ""];
+                let name = predicate[self.code[d.imm] as usize & 7];
+                if self.code[d.imm] > 7 || name.is_empty() {
+                    return Some((
+                        Cow::Owned(format!("vpcmp{}", suffix)),
+                        &[K, Hx, Wx, Ib],
+                        Only,
+                    ));
+                }
+                return Some((
+                    Cow::Owned(format!("vpcmp{}{}", name, suffix)),
+                    &[K, Hx, Wx],
+                    Only,
+                ));
+            }
+            (0x25, 1) if p.evex.is_some() => {
+                (wide("vpternlogd", "vpternlogq"), &[Vx, Hx, Wx, Ib], Only)
+            }
+            (0xf0, 3) => ("rorx", &[Gy, Ey, Ib], Only),
+            (0xcc, 0) => ("sha1rnds4", &[Vdq, W(16), Ib], No),
+            (0x44, 1) if self.code[d.imm] & 0xee == 0 => {
+                // `pclmulqdq` with the halves it multiplies in the mnemonic.
+                let half = |bit: u8| {
+                    if self.code[d.imm] & bit == 0 {
+                        "lq"
+                    } else {
+                        "hq"
+                    }
+                };
+                let name = format!("pclmul{}{}dq", half(0x01), half(0x10));
+                return Some((Cow::Owned(name), &[Vx, Wx], Nds));
+            }
+            (0x03, 1) if p.evex.is_some() => (wide("valignd", "valignq"), &[Vx, Hx, Wx, Ib], Only),
+            (0x18..=0x1b, 1) | (0x38..=0x3b, 1) if p.evex.is_some() => {
+                let kind = if op < 0x38 { "f" } else { "i" };
+                let (name, lanes) = match (op & 3, p.rex_w) {
+                    (0, false) => ("vinsert", "32x4"),
+                    (0, true) => ("vinsert", "64x2"),
+                    (1, false) => ("vextract", "32x4"),
+                    (1, true) => ("vextract", "64x2"),
+                    (2, false) => ("vinsert", "32x8"),
+                    (2, true) => ("vinsert", "64x4"),
+                    (_, false) => ("vextract", "32x8"),
+                    (_, true) => ("vextract", "64x4"),
+                };
+                let specs: &'static [Spec] = match op & 3 {
+                    0 => &[Vx, Hx, W(16), Ib],
+                    1 => &[W(16), Vx, Ib],
+                    2 => &[Vx, Hx, W(32), Ib],
+                    _ => &[W(32), Vx, Ib],
+                };
+                let name = format!("{}{}{}", name, kind, lanes);
+                return Some((Cow::Owned(name), specs, Only));
+            }
+            (0x23, 1) if p.evex.is_some() => {
+                (wide("vshuff32x4", "vshuff64x2"), &[Vx, Hx, Wx, Ib], Only)
+            }
+            (0x43, 1) if p.evex.is_some() => {
+                (wide("vshufi32x4", "vshufi64x2"), &[Vx, Hx, Wx, Ib], Only)
+            }
+            (_, 1) => match op {
+                0x00 => ("vpermq", &[Vx, Wx, Ib], Only),
+                0x01 => ("vpermpd", &[Vx, Wx, Ib], Only),
+                0x02 => ("vpblendd", &[Vx, Hx, Wx, Ib], Only),
+                0x04 => ("vpermilps", &[Vx, Wx, Ib], Only),
+                0x05 => ("vpermilpd", &[Vx, Wx, Ib], Only),
+                0x06 => ("vperm2f128", &[Vx, Hx, Wx, Ib], Only),
+                0x08 => ("roundps", &[Vx, Wx, Ib], Same),
+                0x09 => ("roundpd", &[Vx, Wx, Ib], Same),
+                0x0a => ("roundss", &[Vx, W(4), Ib], Nds),
+                0x0b => ("roundsd", &[Vx, W(8), Ib], Nds),
+                0x0c => ("blendps", &[Vx, Wx, Ib], Nds),
+                0x0d => ("blendpd", &[Vx, Wx, Ib], Nds),
+                0x0e => ("pblendw", &[Vx, Wx, Ib], Nds),
+                0x0f => ("palignr", &[Vx, Wx, Ib], Nds),
+                0x14 => ("pextrb", &[R(1), Vdq, Ib], Same),
+                0x15 => ("pextrw", &[R(2), Vdq, Ib], Same),
+                0x16 => (wide("pextrd", "pextrq"), &[Ey, Vdq, Ib], Same),
+                0x17 => ("extractps", &[Ed, Vdq, Ib], Same),
+                0x18 => ("vinsertf128", &[Vx, Hx, W(16), Ib], Only),
+                0x19 => ("vextractf128", &[W(16), Vx, Ib], Only),
+                0x20 => ("pinsrb", &[Vdq, R(1), Ib], Nds),
+                0x21 => ("insertps", &[Vdq, W(4), Ib], Nds),
+                0x22 => (wide("pinsrd", "pinsrq"), &[Vdq, Ey, Ib], Nds),
+                0x38 => ("vinserti128", &[Vx, Hx, W(16), Ib], Only),
+                0x39 => ("vextracti128", &[W(16), Vx, Ib], Only),
+                0x40 => ("dpps", &[Vx, Wx, Ib], Nds),
+                0x41 => ("dppd", &[Vx, Wx, Ib], Nds),
+                0x42 => ("mpsadbw", &[Vx, Wx, Ib], Nds),
+                0x44 => ("pclmulqdq", &[Vx, Wx, Ib], Nds),
+                0x46 => ("vperm2i128", &[Vx, Hx, Wx, Ib], Only),
+                0x4a => ("vblendvps", &[Vx, Hx, Wx, Lx], Only),
+                0x4b => ("vblendvpd", &[Vx, Hx, Wx, Lx], Only),
+                0x4c => ("vpblendvb", &[Vx, Hx, Wx, Lx], Only),
+                0x60 => ("pcmpestrm", &[Vdq, Wx, Ib], Same),
+                0x61 => ("pcmpestri", &[Vdq, Wx, Ib], Same),
+                0x62 => ("pcmpistrm", &[Vdq, Wx, Ib], Same),
+                0x63 => ("pcmpistri", &[Vdq, Wx, Ib], Same),
+                0xdf => ("aeskeygenassist", &[Vx, Wx, Ib], Same),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some((Cow::Borrowed(entry.0), entry.1, entry.2))
+    }
+}
+
+/// Truncate `value` to `size` bytes.
+fn truncate(value: u64, size: usize) -> u64 {
+    if size >= 8 {
+        value
+    } else {
+        value & ((1 << (8 * size)) - 1)
+    }
+}
+
+/// The MMX and SSE2 integer instructions of the secondary map.
+fn simd_integer(opcode: u8) -> Option<&'static str> {
+    let name = match opcode {
+        0x60 => "punpcklbw",
+        0x61 => "punpcklwd",
+        0x62 => "punpckldq",
+        0x63 => "packsswb",
+        0x64 => "pcmpgtb",
+        0x65 => "pcmpgtw",
+        0x66 => "pcmpgtd",
+        0x67 => "packuswb",
+        0x68 => "punpckhbw",
+        0x69 => "punpckhwd",
+        0x6a => "punpckhdq",
+        0x6b => "packssdw",
+        0x6c => "punpcklqdq",
+        0x6d => "punpckhqdq",
+        0x74 => "pcmpeqb",
+        0x75 => "pcmpeqw",
+        0x76 => "pcmpeqd",
+        0xd1 => "psrlw",
+        0xd2 => "psrld",
+        0xd3 => "psrlq",
+        0xd4 => "paddq",
+        0xd5 => "pmullw",
+        0xd8 => "psubusb",
+        0xd9 => "psubusw",
+        0xda => "pminub",
+        0xdb => "pand",
+        0xdc => "paddusb",
+        0xdd => "paddusw",
+        0xde => "pmaxub",
+        0xdf => "pandn",
+        0xe0 => "pavgb",
+        0xe1 => "psraw",
+        0xe2 => "psrad",
+        0xe3 => "pavgw",
+        0xe4 => "pmulhuw",
+        0xe5 => "pmulhw",
+        0xe8 => "psubsb",
+        0xe9 => "psubsw",
+        0xea => "pminsw",
+        0xeb => "por",
+        0xec => "paddsb",
+        0xed => "paddsw",
+        0xee => "pmaxsw",
+        0xef => "pxor",
+        0xf1 => "psllw",
+        0xf2 => "pslld",
+        0xf3 => "psllq",
+        0xf4 => "pmuludq",
+        0xf5 => "pmaddwd",
+        0xf6 => "psadbw",
+        0xf8 => "psubb",
+        0xf9 => "psubw",
+        0xfa => "psubd",
+        0xfb => "psubq",
+        0xfc => "paddb",
+        0xfd => "paddw",
+        0xfe => "paddd",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// The SSSE3 instructions of the `0f 38` map.
+fn ssse3(opcode: u8) -> Option<&'static str> {
+    let name = match opcode {
+        0x00 => "pshufb",
+        0x01 => "phaddw",
+        0x02 => "phaddd",
+        0x03 => "phaddsw",
+        0x04 => "pmaddubsw",
+        0x05 => "phsubw",
+        0x06 => "phsubd",
+        0x07 => "phsubsw",
+        0x08 => "psignb",
+        0x09 => "psignw",
+        0x0a => "psignd",
+        0x0b => "pmulhrsw",
+        0x1c => "pabsb",
+        0x1d => "pabsw",
+        0x1e => "pabsd",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// The SSE4.1 sign and zero extending moves.
+fn pmov(opcode: u8) -> Option<&'static str> {
+    let name = match opcode {
+        0x20 => "pmovsxbw",
+        0x21 => "pmovsxbd",
+        0x22 => "pmovsxbq",
+        0x23 => "pmovsxwd",
+        0x24 => "pmovsxwq",
+        0x25 => "pmovsxdq",
+        0x30 => "pmovzxbw",
+        0x31 => "pmovzxbd",
+        0x32 => "pmovzxbq",
+        0x33 => "pmovzxwd",
+        0x34 => "pmovzxwq",
+        0x35 => "pmovzxdq",
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::io;
+    use std::process::Command;
+
+    #[test]
+    fn test_decode() {
+        let size = |code: &[u8]| Instruction::decode(code).map(|insn| insn.size());
+        assert_eq!(size(&[0xc3, 0xcc]), Some(1));
+        // push %rbp; mov %rsp, %rbp
+        assert_eq!(size(&[0x55, 0x48, 0x89, 0xe5]), Some(1));
+        assert_eq!(size(&[0x48, 0x89, 0xe5]), Some(3));
+        // movabs $0x1122334455667788, %rax, and mov $1, %eax and %ax.
+        assert_eq!(size(&[0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8, 0xc3]), Some(10));
+        assert_eq!(size(&[0xb8, 1, 0, 0, 0]), Some(5));
+        assert_eq!(size(&[0x66, 0xb8, 1, 0]), Some(4));
+        // addl $5, -4(%rbp,%rcx,4), and movl $1, 0x10(%rip).
+        assert_eq!(size(&[0x83, 0x44, 0x8d, 0xfc, 0x05]), Some(5));
+        assert_eq!(size(&[0xc7, 0x05, 0x10, 0, 0, 0, 1, 0, 0, 0]), Some(10));
+        // testb $1, (%rax) has an immediate, and notb (%rax) doesn't.
+        assert_eq!(size(&[0xf6, 0x00, 0x01]), Some(3));
+        assert_eq!(size(&[0xf6, 0x10]), Some(2));
+        // je .+0x100 and jmp .+2
+        assert_eq!(size(&[0x0f, 0x84, 0, 1, 0, 0]), Some(6));
+        assert_eq!(size(&[0xeb, 0x00]), Some(2));
+        // pshufd $0x1b, %xmm1, %xmm0, and vpermq $0x1b, %ymm1, %ymm0.
+        assert_eq!(size(&[0x66, 0x0f, 0x70, 0xc1, 0x1b]), Some(5));
+        assert_eq!(size(&[0xc4, 0xe3, 0xfd, 0x00, 0xc1, 0x1b]), Some(6));
+        // vmovdqu64 %zmm16, 0x40(%rdi)
+        assert_eq!(size(&[0x62, 0xe1, 0xfe, 0x48, 0x7f, 0x47, 0x01]), Some(7));
+        // enter $0x10, $0, and mov 0x1000(,%r9,8), %eax.
+        assert_eq!(size(&[0xc8, 0x10, 0, 0]), Some(4));
+        assert_eq!(size(&[0x42, 0x8b, 0x04, 0xcd, 0, 0x10, 0, 0]), Some(8));
+
+        // Cut off instructions, those invalid in 64-bit mode and those
+        // longer than 15 bytes can't be decoded.
+        assert_eq!(size(&[0xe8, 0, 0]), None);
+        assert_eq!(size(&[0x06]), None);
+        assert_eq!(size(&[0x66; 15]), None);
+
+        let call = Instruction::decode(&[0xe8, 0, 0, 0, 0]).unwrap();
+        assert!(call.is_call());
+        assert_eq!(
+            (call.map(), call.opcode(), call.modrm()),
+            (OpcodeMap::Primary, 0xe8, None)
+        );
+        // call *%rax, and jmp *%rax, which isn't a call.
+        assert!(Instruction::decode(&[0xff, 0xd0]).unwrap().is_call());
+        assert!(!Instruction::decode(&[0xff, 0xe0]).unwrap().is_call());
+        assert!(Instruction::decode(&[0xc3]).unwrap().is_return());
+        let syscall = Instruction::decode(&[0x0f, 0x05, 0xc3]).unwrap();
+        assert!(syscall.is_syscall());
+        assert_eq!(syscall.bytes(), &[0x0f, 0x05]);
+        assert_eq!(syscall.map(), OpcodeMap::Secondary);
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        let text = |code: &[u8]| Instruction::decode(code).unwrap().to_string();
+        assert_eq!(
+            text(&[0x48, 0x89, 0x44, 0x24, 0x08]),
+            "mov qword ptr [rsp+0x8], rax"
+        );
+        assert_eq!(
+            text(&[0x48, 0x83, 0xc4, 0x80]),
+            "add rsp, 0xffffffffffffff80"
+        );
+        assert_eq!(text(&[0x83, 0xc0, 0xff]), "add eax, 0xffffffff");
+        assert_eq!(
+            text(&[0x0f, 0x1f, 0x44, 0x00, 0x00]),
+            "nop dword ptr [rax+rax*1]"
+        );
+        assert_eq!(
+            text(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0, 0, 0]),
+            "mov rax, qword ptr fs:[0x28]"
+        );
+        assert_eq!(
+            text(&[0x48, 0x8d, 0x05, 0xf9, 0xff, 0xff, 0xff]),
+            "lea rax, [rip-0x7]"
+        );
+        assert_eq!(
+            text(&[0x48, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]),
+            "movabs rax, 0x1122334455667788"
+        );
+        assert_eq!(text(&[0xf3, 0x48, 0xab]), "rep stosq");
+        assert_eq!(
+            text(&[0xf0, 0x48, 0x0f, 0xb1, 0x0a]),
+            "lock cmpxchg qword ptr [rdx], rcx"
+        );
+        assert_eq!(text(&[0xd9, 0xe8]), "fld1");
+        assert_eq!(text(&[0xdd, 0x5c, 0x24, 0x08]), "fstp qword ptr [rsp+0x8]");
+        assert_eq!(text(&[0xf2, 0x0f, 0xc2, 0xc1, 0x01]), "cmpltsd xmm0, xmm1");
+        assert_eq!(
+            text(&[0xc5, 0xfd, 0x6f, 0x06]),
+            "vmovdqa ymm0, ymmword ptr [rsi]"
+        );
+        assert_eq!(
+            text(&[0xc4, 0xe2, 0x7d, 0x00, 0xc1]),
+            "vpshufb ymm0, ymm0, ymm1"
+        );
+        assert_eq!(
+            text(&[0x62, 0xe1, 0xfe, 0x48, 0x7f, 0x47, 0x01]),
+            "vmovdqu64 zmmword ptr [rdi+0x40], zmm16"
+        );
+        assert_eq!(
+            text(&[0x62, 0xf1, 0x7d, 0x49, 0x74, 0xc1]),
+            "vpcmpeqb k0{k1}, zmm0, zmm1"
+        );
+        assert_eq!(
+            text(&[0x62, 0xf1, 0x7e, 0xc9, 0x6f, 0xc1]),
+            "vmovdqu32 zmm0{k1}{z}, zmm1"
+        );
+        // An AMD FMA4 instruction can be stepped over, but not named.
+        let fma4 = Instruction::decode(&[0xc4, 0xe3, 0xf9, 0x6b, 0xc2, 0x10]).unwrap();
+        assert_eq!(fma4.mnemonic(), None);
+        assert!(fma4.operands().is_empty());
+        assert_eq!(fma4.to_string(), "db 0xc4, 0xe3, 0xf9, 0x6b, 0xc2, 0x10");
+
+        let mov = Instruction::decode(&[0x48, 0x89, 0x44, 0x24, 0x08]).unwrap();
+        assert_eq!(mov.mnemonic(), Some("mov"));
+        assert_eq!(
+            mov.operands(),
+            &[
+                Operand::Memory(MemoryOperand {
+                    size: 8,
+                    segment: None,
+                    base: Some("rsp"),
+                    index: None,
+                    scale: 1,
+                    displacement: 8,
+                }),
+                Operand::Register("rax"),
+            ]
+        );
+        let rep = Instruction::decode(&[0xf3, 0x48, 0xab]).unwrap();
+        assert_eq!((rep.prefix(), rep.mnemonic()), (Some("rep"), Some("stosq")));
+        let masked = Instruction::decode(&[0x62, 0xf1, 0x7e, 0xc9, 0x6f, 0xc1]).unwrap();
+        assert_eq!(masked.mask(), Some(("k1", true)));
+
+        // call .+5, and jne .-0x10
+        let call = Instruction::decode(&[0xe8, 0, 0, 0, 0]).unwrap();
+        assert_eq!(call.operands(), &[Operand::Relative(0)]);
+        assert_eq!(call.target(0x1000), Some(0x1005));
+        assert_eq!(call.to_string(), "call $+0x5");
+        let jne = Instruction::decode(&[0x75, 0xee]).unwrap();
+        assert_eq!(jne.target(0x1000), Some(0xff0));
+        assert_eq!(jne.to_string(), "jne $-0x10");
+        assert_eq!(mov.target(0x1000), None);
+    }
+
+    #[test]
+    fn test_instruction_at() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let page = tracee.allocate(8192, prot).expect("Error allocating");
+        // A `mov` that can be read, and one cut off by the end of the
+        // mapping.
+        let code = [0x48, 0x89, 0x44, 0x24, 0x08];
+        tracee.write_memory(page + 100, &code).unwrap();
+        tracee.write_memory(page + 4093, &code).unwrap();
+        tracee.free(page + 4096, 4096).unwrap();
+        let insn = tracee.instruction_at(page + 100).unwrap();
+        assert_eq!(insn.bytes(), &code);
+        let err = tracee.instruction_at(page + 4093).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        tracee.instruction_at(page + 4096).unwrap_err();
+
+        let insn = tracee.next_instruction().unwrap();
+        let rip = tracee.registers().unwrap().rip;
+        let mut code = vec![0; insn.size()];
+        tracee.read_memory(rip, &mut code).unwrap();
+        assert_eq!(insn.bytes(), &code[..]);
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
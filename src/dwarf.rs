@@ -0,0 +1,672 @@
+//! Source lines of tracee code from the DWARF line tables in `.debug_line`.
+
+use crate::elf::Elf;
+use crate::maps;
+use nix::unistd::Pid;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Line number program content types of DWARF 5 headers.
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+// Attribute forms.
+pub(crate) const DW_FORM_ADDR: u64 = 0x01;
+pub(crate) const DW_FORM_BLOCK2: u64 = 0x03;
+pub(crate) const DW_FORM_BLOCK4: u64 = 0x04;
+pub(crate) const DW_FORM_DATA2: u64 = 0x05;
+pub(crate) const DW_FORM_DATA4: u64 = 0x06;
+pub(crate) const DW_FORM_DATA8: u64 = 0x07;
+pub(crate) const DW_FORM_STRING: u64 = 0x08;
+pub(crate) const DW_FORM_BLOCK: u64 = 0x09;
+pub(crate) const DW_FORM_BLOCK1: u64 = 0x0a;
+pub(crate) const DW_FORM_DATA1: u64 = 0x0b;
+pub(crate) const DW_FORM_FLAG: u64 = 0x0c;
+pub(crate) const DW_FORM_SDATA: u64 = 0x0d;
+pub(crate) const DW_FORM_STRP: u64 = 0x0e;
+pub(crate) const DW_FORM_UDATA: u64 = 0x0f;
+pub(crate) const DW_FORM_REF_ADDR: u64 = 0x10;
+pub(crate) const DW_FORM_REF1: u64 = 0x11;
+pub(crate) const DW_FORM_REF2: u64 = 0x12;
+pub(crate) const DW_FORM_REF4: u64 = 0x13;
+pub(crate) const DW_FORM_REF8: u64 = 0x14;
+pub(crate) const DW_FORM_REF_UDATA: u64 = 0x15;
+pub(crate) const DW_FORM_INDIRECT: u64 = 0x16;
+pub(crate) const DW_FORM_SEC_OFFSET: u64 = 0x17;
+pub(crate) const DW_FORM_EXPRLOC: u64 = 0x18;
+pub(crate) const DW_FORM_FLAG_PRESENT: u64 = 0x19;
+pub(crate) const DW_FORM_STRX: u64 = 0x1a;
+pub(crate) const DW_FORM_ADDRX: u64 = 0x1b;
+pub(crate) const DW_FORM_REF_SUP4: u64 = 0x1c;
+pub(crate) const DW_FORM_STRP_SUP: u64 = 0x1d;
+pub(crate) const DW_FORM_DATA16: u64 = 0x1e;
+pub(crate) const DW_FORM_LINE_STRP: u64 = 0x1f;
+pub(crate) const DW_FORM_REF_SIG8: u64 = 0x20;
+pub(crate) const DW_FORM_IMPLICIT_CONST: u64 = 0x21;
+pub(crate) const DW_FORM_LOCLISTX: u64 = 0x22;
+pub(crate) const DW_FORM_RNGLISTX: u64 = 0x23;
+pub(crate) const DW_FORM_REF_SUP8: u64 = 0x24;
+pub(crate) const DW_FORM_STRX1: u64 = 0x25;
+pub(crate) const DW_FORM_STRX4: u64 = 0x28;
+pub(crate) const DW_FORM_ADDRX1: u64 = 0x29;
+pub(crate) const DW_FORM_ADDRX4: u64 = 0x2c;
+
+/// A source location, as found by [`LineTable::lookup`].
+///
+/// [`LineTable::lookup`]: struct.LineTable.html#method.lookup
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceLine<'a> {
+    /// The source file, as the compiler named it, which is relative to the
+    /// directory it was run in if the line table doesn't say more.
+    pub file: &'a Path,
+    /// The line number, starting at 1.
+    pub line: u64,
+    /// The column, starting at 1, or 0 if the compiler didn't record one.
+    pub column: u64,
+}
+
+/// The instructions from `start` to `end` that belong to one source line.
+#[derive(Debug)]
+struct Range {
+    start: u64,
+    end: u64,
+    file: usize,
+    line: u64,
+    column: u64,
+}
+
+/// The source lines of the code mapped into a tracee, from the DWARF line
+/// tables of its executable and shared objects.
+///
+/// Objects built without debug info, or whose debug info has been stripped
+/// or split into separate files, contribute no lines. A table is only good
+/// for the address space it was loaded from, so load it again after
+/// `exec`, and once shared libraries have been loaded.
+#[derive(Debug, Default)]
+pub struct LineTable {
+    /// Sorted by start address.
+    ranges: Vec<Range>,
+    files: Vec<PathBuf>,
+}
+
+impl LineTable {
+    /// Load the line tables of every object mapped into process `pid`, at
+    /// the addresses they are loaded at.
+    pub fn load(pid: Pid) -> io::Result<LineTable> {
+        let mut table = LineTable::default();
+        for_each_object(pid, |sections, bias| {
+            let count = table.ranges.len();
+            if table.add(sections, bias).is_err() {
+                table.ranges.truncate(count);
+            }
+        })?;
+        table.ranges.sort_by_key(|range| range.start);
+        Ok(table)
+    }
+
+    /// The source line that the instruction at `addr` belongs to, or `None`
+    /// if no line table covers it or the compiler made it up, as it does
+    /// for code with line 0.
+    pub fn lookup(&self, addr: u64) -> Option<SourceLine<'_>> {
+        let index = self.ranges.partition_point(|range| range.start <= addr);
+        // Ranges don't overlap, so only the last one that starts at or
+        // before `addr` can hold it.
+        let range = self.ranges[..index].last()?;
+        if addr >= range.end || range.line == 0 {
+            return None;
+        }
+        Some(SourceLine {
+            file: &self.files[range.file],
+            line: range.line,
+            column: range.column,
+        })
+    }
+
+    /// Whether the line table is empty, because no mapped object has one.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Add the ranges of every line number program in `sections`, for an
+    /// object loaded with `bias`.
+    fn add(&mut self, sections: &Sections<'_>, bias: u64) -> io::Result<()> {
+        let mut reader = Reader::new(sections.line.unwrap_or_default());
+        while !reader.is_empty() {
+            self.add_unit(&mut reader, sections, bias)?;
+        }
+        Ok(())
+    }
+
+    fn add_unit<'a>(
+        &mut self,
+        reader: &mut Reader<'a>,
+        sections: &Sections<'a>,
+        bias: u64,
+    ) -> io::Result<()> {
+        let (mut unit, offset_size) = match reader.u32()? {
+            0xffff_ffff => {
+                let len = reader.u64()?;
+                (reader.split(len)?, 8)
+            }
+            len => (reader.split(len as u64)?, 4),
+        };
+        let version = unit.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(invalid("Unsupported line table version"));
+        }
+        if version >= 5 {
+            // The address and segment selector sizes.
+            unit.skip(2)?;
+        }
+        let header_len = unit.section_offset(offset_size)?;
+        let mut program = unit.clone();
+        program.skip(header_len)?;
+        let min_insn_len = unit.u8()? as u64;
+        if version >= 4 {
+            // The maximum operations per instruction, which is only more
+            // than 1 on VLIW machines.
+            unit.skip(1)?;
+        }
+        // Whether rows are statements by default, which isn't used.
+        unit.skip(1)?;
+        let line_base = unit.u8()? as i8 as i64;
+        let line_range = unit.u8()?;
+        let opcode_base = unit.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return Err(invalid("Malformed line table header"));
+        }
+        let opcode_lengths = unit.bytes(opcode_base as u64 - 1)?;
+        let files = if version >= 5 {
+            self.files_v5(&mut unit, sections, offset_size)?
+        } else {
+            self.files_v4(&mut unit)?
+        };
+
+        let mut rows = Rows::new(files, bias);
+        while !program.is_empty() {
+            let opcode = program.u8()?;
+            if opcode >= opcode_base {
+                let adjusted = opcode - opcode_base;
+                rows.advance((adjusted / line_range) as u64 * min_insn_len);
+                rows.line = rows
+                    .line
+                    .wrapping_add((line_base + (adjusted % line_range) as i64) as u64);
+                rows.emit(self);
+                continue;
+            }
+            match opcode {
+                0 => {
+                    let len = program.uleb()?;
+                    let mut extended = program.split(len)?;
+                    match extended.u8()? {
+                        // DW_LNE_end_sequence
+                        1 => {
+                            rows.end_sequence(self);
+                            rows = Rows::new(rows.files, bias);
+                        }
+                        // DW_LNE_set_address
+                        2 => {
+                            rows.address = match len - 1 {
+                                4 => extended.u32()? as u64,
+                                8 => extended.u64()?,
+                                _ => return Err(invalid("Unsupported address size")),
+                            }
+                        }
+                        // DW_LNE_define_file, DW_LNE_set_discriminator and
+                        // vendor extensions.
+                        _ => {}
+                    }
+                }
+                // DW_LNS_copy
+                1 => rows.emit(self),
+                // DW_LNS_advance_pc
+                2 => rows.advance(program.uleb()?.wrapping_mul(min_insn_len)),
+                // DW_LNS_advance_line
+                3 => rows.line = rows.line.wrapping_add(program.sleb()? as u64),
+                // DW_LNS_set_file
+                4 => rows.file = program.uleb()?,
+                // DW_LNS_set_column
+                5 => rows.column = program.uleb()?,
+                // DW_LNS_const_add_pc
+                8 => rows.advance(((255 - opcode_base) / line_range) as u64 * min_insn_len),
+                // DW_LNS_fixed_advance_pc
+                9 => rows.advance(program.u16()? as u64),
+                // DW_LNS_negate_stmt, DW_LNS_set_basic_block,
+                // DW_LNS_set_prologue_end, DW_LNS_set_epilogue_begin,
+                // DW_LNS_set_isa and any opcodes newer than these, whose
+                // operands are all LEB128 numbers.
+                _ => {
+                    for _ in 0..opcode_lengths[opcode as usize - 1] {
+                        program.uleb()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the directories and files of a DWARF 2 to 4 header, in which
+    /// the files are numbered from 1.
+    fn files_v4(&mut self, unit: &mut Reader<'_>) -> io::Result<Vec<usize>> {
+        let mut dirs = vec![PathBuf::new()];
+        loop {
+            let dir = unit.str()?;
+            if dir.is_empty() {
+                break;
+            }
+            dirs.push(PathBuf::from(dir));
+        }
+        let mut files = vec![self.intern(PathBuf::new())];
+        loop {
+            let name = unit.str()?;
+            if name.is_empty() {
+                break;
+            }
+            let dir = unit.uleb()? as usize;
+            // The modification time and length.
+            unit.uleb()?;
+            unit.uleb()?;
+            let dir = dirs
+                .get(dir)
+                .ok_or_else(|| invalid("Bad directory index"))?;
+            let path = dir.join(name);
+            files.push(self.intern(path));
+        }
+        Ok(files)
+    }
+
+    /// Read the directories and files of a DWARF 5 header, in which both
+    /// are described by entry formats and numbered from 0.
+    fn files_v5<'a>(
+        &mut self,
+        unit: &mut Reader<'a>,
+        sections: &Sections<'a>,
+        offset_size: u8,
+    ) -> io::Result<Vec<usize>> {
+        let mut dirs = vec![];
+        for (path, _) in entries(unit, sections, offset_size)? {
+            dirs.push(PathBuf::from(path));
+        }
+        let mut files = vec![];
+        for (name, dir) in entries(unit, sections, offset_size)? {
+            let path = match dirs.get(dir as usize) {
+                Some(dir) => dir.join(name),
+                None => PathBuf::from(name),
+            };
+            files.push(self.intern(path));
+        }
+        Ok(files)
+    }
+
+    fn intern(&mut self, path: PathBuf) -> usize {
+        match self.files.iter().position(|file| *file == path) {
+            Some(index) => index,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+}
+
+/// Read the directory or file entries of a DWARF 5 header, as their paths
+/// and directory indices.
+fn entries<'a>(
+    unit: &mut Reader<'a>,
+    sections: &Sections<'a>,
+    offset_size: u8,
+) -> io::Result<Vec<(&'a str, u64)>> {
+    let mut formats = vec![];
+    for _ in 0..unit.u8()? {
+        formats.push((unit.uleb()?, unit.uleb()?));
+    }
+    let mut entries = vec![];
+    for _ in 0..unit.uleb()? {
+        let mut path = "";
+        let mut dir = 0;
+        for &(content, form) in &formats {
+            match (content, form) {
+                (DW_LNCT_PATH, DW_FORM_STRING) => path = unit.str()?,
+                (DW_LNCT_PATH, DW_FORM_LINE_STRP) => {
+                    path = sections.line_str(unit.section_offset(offset_size)?)?
+                }
+                (DW_LNCT_PATH, DW_FORM_STRP) => {
+                    path = sections.str(unit.section_offset(offset_size)?)?
+                }
+                (DW_LNCT_DIRECTORY_INDEX, DW_FORM_DATA1) => dir = unit.u8()? as u64,
+                (DW_LNCT_DIRECTORY_INDEX, DW_FORM_DATA2) => dir = unit.u16()? as u64,
+                (DW_LNCT_DIRECTORY_INDEX, DW_FORM_UDATA) => dir = unit.uleb()?,
+                // Anything else, such as timestamps and MD5 sums, is skipped.
+                (_, DW_FORM_STRING) => {
+                    unit.str()?;
+                }
+                (_, DW_FORM_LINE_STRP) | (_, DW_FORM_STRP) => unit.skip(offset_size as u64)?,
+                (_, DW_FORM_DATA1) => unit.skip(1)?,
+                (_, DW_FORM_DATA2) => unit.skip(2)?,
+                (_, DW_FORM_DATA4) => unit.skip(4)?,
+                (_, DW_FORM_DATA8) => unit.skip(8)?,
+                (_, DW_FORM_DATA16) => unit.skip(16)?,
+                (_, DW_FORM_UDATA) => {
+                    unit.uleb()?;
+                }
+                (_, DW_FORM_BLOCK) => {
+                    let len = unit.uleb()?;
+                    unit.skip(len)?;
+                }
+                (_, DW_FORM_BLOCK1) => {
+                    let len = unit.u8()? as u64;
+                    unit.skip(len)?;
+                }
+                (_, DW_FORM_BLOCK2) => {
+                    let len = unit.u16()? as u64;
+                    unit.skip(len)?;
+                }
+                (_, DW_FORM_BLOCK4) => {
+                    let len = unit.u32()? as u64;
+                    unit.skip(len)?;
+                }
+                _ => return Err(invalid("Unsupported line table entry form")),
+            }
+        }
+        entries.push((path, dir));
+    }
+    Ok(entries)
+}
+
+/// The state machine of a line number program, which makes a range of each
+/// row up to the next one.
+struct Rows {
+    /// The program's file numbers, as indices into `LineTable::files`.
+    files: Vec<usize>,
+    bias: u64,
+    address: u64,
+    file: u64,
+    line: u64,
+    column: u64,
+    /// The last row emitted, whose range ends at the next one.
+    last: Option<Range>,
+}
+
+impl Rows {
+    fn new(files: Vec<usize>, bias: u64) -> Rows {
+        Rows {
+            files,
+            bias,
+            address: 0,
+            file: 1,
+            line: 1,
+            column: 0,
+            last: None,
+        }
+    }
+
+    fn emit(&mut self, table: &mut LineTable) {
+        // Rows for the same line as the one before, such as those that only
+        // move to another column, extend its range.
+        if let Some(ref last) = self.last {
+            if (last.file, last.line) == (self.file as usize, self.line) {
+                return;
+            }
+        }
+        let address = self.bias.wrapping_add(self.address);
+        self.finish(table, address);
+        self.last = Some(Range {
+            start: address,
+            end: address,
+            file: self.file as usize,
+            line: self.line,
+            column: self.column,
+        });
+    }
+
+    fn advance(&mut self, len: u64) {
+        self.address = self.address.wrapping_add(len);
+    }
+
+    fn end_sequence(&mut self, table: &mut LineTable) {
+        let address = self.bias.wrapping_add(self.address);
+        self.finish(table, address);
+        self.last = None;
+    }
+
+    /// End the last row's range at `end`.
+    fn finish(&mut self, table: &mut LineTable, end: u64) {
+        let mut last = match self.last.take() {
+            Some(last) => last,
+            None => return,
+        };
+        // Sequences of functions the linker discarded are left at address
+        // 0 or at a tombstone value.
+        if last.start == self.bias || last.start.wrapping_sub(self.bias) >= u64::MAX - 1 {
+            return;
+        }
+        if end <= last.start {
+            return;
+        }
+        last.end = end;
+        last.file = match self.files.get(last.file) {
+            Some(&file) => file,
+            None => return,
+        };
+        table.ranges.push(last);
+    }
+}
+
+/// Call `f` with the debug info sections of every object mapped into
+/// process `pid`, and the bias it is loaded with.
+pub(crate) fn for_each_object<F>(pid: Pid, mut f: F) -> io::Result<()>
+where
+    F: FnMut(&Sections<'_>, u64),
+{
+    let mut seen = HashSet::new();
+    for map in maps::read_maps(pid)?.iter().filter(|m| m.offset == 0) {
+        let path = match map.pathname {
+            Some(ref path) if path.starts_with('/') => path,
+            _ => continue,
+        };
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        // Like symbols, debug info can't come from files that are gone, and
+        // objects whose debug info is compressed are skipped.
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        let elf = match Elf::parse(&data) {
+            Ok(elf) => elf,
+            Err(_) => continue,
+        };
+        let bias = map.start.wrapping_sub(elf.first_load_delta()?);
+        if let Ok(sections) = Sections::find(&elf) {
+            f(&sections, bias);
+        }
+    }
+    Ok(())
+}
+
+/// The sections an object's debug info is in, where it has them.
+#[derive(Default)]
+pub(crate) struct Sections<'a> {
+    pub abbrev: Option<&'a [u8]>,
+    pub info: Option<&'a [u8]>,
+    pub line: Option<&'a [u8]>,
+    pub line_str: Option<&'a [u8]>,
+    pub ranges: Option<&'a [u8]>,
+    pub rnglists: Option<&'a [u8]>,
+    pub str: Option<&'a [u8]>,
+}
+
+impl<'a> Sections<'a> {
+    fn find(elf: &Elf<'a>) -> io::Result<Sections<'a>> {
+        Ok(Sections {
+            abbrev: elf.section(".debug_abbrev")?,
+            info: elf.section(".debug_info")?,
+            line: elf.section(".debug_line")?,
+            line_str: elf.section(".debug_line_str")?,
+            ranges: elf.section(".debug_ranges")?,
+            rnglists: elf.section(".debug_rnglists")?,
+            str: elf.section(".debug_str")?,
+        })
+    }
+
+    pub fn line_str(&self, offset: u64) -> io::Result<&'a str> {
+        Reader::at(self.line_str, offset)?.str()
+    }
+
+    pub fn str(&self, offset: u64) -> io::Result<&'a str> {
+        Reader::at(self.str, offset)?.str()
+    }
+}
+
+pub(crate) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A cursor over little-endian DWARF data, which knows its offset in the
+/// section it reads.
+#[derive(Clone)]
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    offset: u64,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, offset: 0 }
+    }
+
+    /// A reader of `section` from `offset`.
+    pub fn at(section: Option<&'a [u8]>, offset: u64) -> io::Result<Reader<'a>> {
+        let data = section.ok_or_else(|| invalid("Missing debug info section"))?;
+        let data = data
+            .get(offset as usize..)
+            .ok_or_else(|| invalid("Bad debug info offset"))?;
+        Ok(Reader { data, offset })
+    }
+
+    /// The offset of the next byte in the section.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn bytes(&mut self, len: u64) -> io::Result<&'a [u8]> {
+        if len > self.data.len() as u64 {
+            return Err(invalid("Truncated debug info"));
+        }
+        let (bytes, rest) = self.data.split_at(len as usize);
+        self.data = rest;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    /// A reader of the next `len` bytes, which are skipped.
+    pub fn split(&mut self, len: u64) -> io::Result<Reader<'a>> {
+        let offset = self.offset;
+        let data = self.bytes(len)?;
+        Ok(Reader { data, offset })
+    }
+
+    pub fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A section offset, which is 4 or 8 bytes long.
+    pub fn section_offset(&mut self, size: u8) -> io::Result<u64> {
+        match size {
+            4 => self.u32().map(u64::from),
+            _ => self.u64(),
+        }
+    }
+
+    pub fn uleb(&mut self) -> io::Result<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    pub fn sleb(&mut self) -> io::Result<i64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    pub fn str(&mut self) -> io::Result<&'a str> {
+        let len = self
+            .data
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| invalid("Unterminated string"))?;
+        let bytes = self.bytes(len as u64 + 1)?;
+        std::str::from_utf8(&bytes[..len]).map_err(|_| invalid("Path is not UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_table_lookup() {
+        let table = LineTable::load(Pid::this()).expect("Error loading line tables");
+        assert!(!table.is_empty());
+        let here = test_line_table_lookup as *const () as u64;
+        let line = table.lookup(here).expect("No line for this test");
+        assert!(line.file.ends_with("src/dwarf.rs"), "{:?}", line);
+        assert!(line.line > 1);
+        assert_eq!(table.lookup(0), None);
+    }
+
+    #[test]
+    fn test_leb128() {
+        let mut reader = Reader::new(&[0xe5, 0x8e, 0x26, 0x7f, 0x80, 0x7f]);
+        assert_eq!(reader.uleb().unwrap(), 624_485);
+        assert_eq!(reader.sleb().unwrap(), -1);
+        assert_eq!(reader.sleb().unwrap(), -128);
+        assert!(reader.uleb().is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! A minimal reader for the parts of 64-bit little-endian ELF images needed
+//! to resolve symbols in a tracee.
+
+use std::convert::TryInto;
+use std::io;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_DYNSYM: u32 = 11;
+const STT_FUNC: u8 = 2;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+
+/// A symbol from an ELF symbol table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Symbol {
+    pub name: String,
+    /// The symbol's virtual address, before adding the load bias.
+    pub value: u64,
+    pub size: u64,
+    pub is_function: bool,
+}
+
+/// A function imported from a shared library, found in the image's dynamic
+/// relocations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Import {
+    pub name: String,
+    /// The address of the GOT slot the dynamic loader stores the function's
+    /// address in, before adding the load bias.
+    pub got: u64,
+}
+
+/// A parsed ELF image.
+#[derive(Debug)]
+pub(crate) struct Elf<'a> {
+    data: &'a [u8],
+    phoff: u64,
+    phentsize: u16,
+    phnum: u16,
+    shoff: u64,
+    shentsize: u16,
+    shnum: u16,
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Malformed ELF image")
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> io::Result<Elf<'a>> {
+        // Only ELFCLASS64 / ELFDATA2LSB images are supported.
+        if data.len() < 64 || &data[..4] != b"\x7fELF" || data[4] != 2 || data[5] != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a 64-bit little-endian ELF image",
+            ));
+        }
+        let elf = Elf {
+            data,
+            phoff: read_u64(data, 0x20)?,
+            phentsize: read_u16(data, 0x36)?,
+            phnum: read_u16(data, 0x38)?,
+            shoff: read_u64(data, 0x28)?,
+            shentsize: read_u16(data, 0x3a)?,
+            shnum: read_u16(data, 0x3c)?,
+        };
+        Ok(elf)
+    }
+
+    /// The entry point's virtual address, before adding the load bias.
+    pub fn entry(&self) -> io::Result<u64> {
+        read_u64(self.data, 0x18)
+    }
+
+    /// The difference between the virtual address and file offset of the
+    /// first `PT_LOAD` segment.
+    ///
+    /// Subtracting this from the address at which file offset 0 is mapped
+    /// gives the image's load bias.
+    pub fn first_load_delta(&self) -> io::Result<u64> {
+        for i in 0..self.phnum as u64 {
+            let ph = self.phoff + i * self.phentsize as u64;
+            if read_u32(self.data, ph)? == PT_LOAD {
+                let offset = read_u64(self.data, ph + 8)?;
+                let vaddr = read_u64(self.data, ph + 16)?;
+                return Ok(vaddr.wrapping_sub(offset));
+            }
+        }
+        Err(malformed())
+    }
+
+    /// The file offset that virtual address `vaddr` is loaded from, before
+    /// adding the load bias.
+    pub fn file_offset(&self, vaddr: u64) -> io::Result<Option<u64>> {
+        for i in 0..self.phnum as u64 {
+            let ph = self.phoff + i * self.phentsize as u64;
+            if read_u32(self.data, ph)? != PT_LOAD {
+                continue;
+            }
+            let offset = read_u64(self.data, ph + 8)?;
+            let start = read_u64(self.data, ph + 16)?;
+            let filesz = read_u64(self.data, ph + 32)?;
+            if vaddr >= start && vaddr - start < filesz {
+                return Ok(Some(offset + (vaddr - start)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The contents of the section called `name`, such as `.debug_line`, or
+    /// `None` if there is no such section or it takes no space in the file.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if the section is compressed.
+    #[cfg(feature = "symbolication")]
+    pub fn section(&self, name: &str) -> io::Result<Option<&'a [u8]>> {
+        const SHT_NOBITS: u32 = 8;
+        const SHF_COMPRESSED: u64 = 0x800;
+        let shstrndx = read_u16(self.data, 0x3e)?;
+        if shstrndx >= self.shnum {
+            return Ok(None);
+        }
+        let shstrtab_sh = self.shoff + shstrndx as u64 * self.shentsize as u64;
+        let shstrtab = read_u64(self.data, shstrtab_sh + 0x18)?;
+        for i in 0..self.shnum as u64 {
+            let sh = self.shoff + i * self.shentsize as u64;
+            let sh_name = read_u32(self.data, sh)? as u64;
+            if read_str(self.data, shstrtab + sh_name)? != name {
+                continue;
+            }
+            if read_u32(self.data, sh + 4)? == SHT_NOBITS {
+                return Ok(None);
+            }
+            if read_u64(self.data, sh + 8)? & SHF_COMPRESSED != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Section {} is compressed", name),
+                ));
+            }
+            let offset = read_u64(self.data, sh + 0x18)?;
+            let size = read_u64(self.data, sh + 0x20)?;
+            return read_bytes(self.data, offset, size as usize).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// All defined symbols from the `.symtab` and `.dynsym` sections.
+    pub fn symbols(&self) -> io::Result<Vec<Symbol>> {
+        let mut symbols = vec![];
+        for i in 0..self.shnum as u64 {
+            let sh = self.shoff + i * self.shentsize as u64;
+            let sh_type = read_u32(self.data, sh + 4)?;
+            if sh_type != SHT_SYMTAB && sh_type != SHT_DYNSYM {
+                continue;
+            }
+            let offset = read_u64(self.data, sh + 0x18)?;
+            let size = read_u64(self.data, sh + 0x20)?;
+            let link = read_u32(self.data, sh + 0x28)? as u64;
+            let entsize = read_u64(self.data, sh + 0x38)?;
+            if entsize == 0 || link >= self.shnum as u64 {
+                return Err(malformed());
+            }
+            let strtab_sh = self.shoff + link * self.shentsize as u64;
+            let strtab = read_u64(self.data, strtab_sh + 0x18)?;
+            for j in 0..size / entsize {
+                let sym = offset + j * entsize;
+                let name = read_u32(self.data, sym)? as u64;
+                let info = *self.data.get(sym as usize + 4).ok_or_else(malformed)?;
+                let shndx = read_u16(self.data, sym + 6)?;
+                // Skip undefined symbols, which are imports.
+                if shndx == 0 || name == 0 {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    name: read_str(self.data, strtab + name)?,
+                    value: read_u64(self.data, sym + 8)?,
+                    size: read_u64(self.data, sym + 16)?,
+                    is_function: info & 0xf == STT_FUNC,
+                });
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// The functions the image imports through its GOT, from the x86-64
+    /// `JUMP_SLOT` relocations that PLT stubs jump through and the
+    /// `GLOB_DAT` ones that calls like those built with `-fno-plt` use.
+    pub fn imports(&self) -> io::Result<Vec<Import>> {
+        let mut imports = vec![];
+        for i in 0..self.shnum as u64 {
+            let sh = self.shoff + i * self.shentsize as u64;
+            if read_u32(self.data, sh + 4)? != SHT_RELA {
+                continue;
+            }
+            let offset = read_u64(self.data, sh + 0x18)?;
+            let size = read_u64(self.data, sh + 0x20)?;
+            let link = read_u32(self.data, sh + 0x28)? as u64;
+            let entsize = read_u64(self.data, sh + 0x38)?;
+            if entsize == 0 || link >= self.shnum as u64 {
+                return Err(malformed());
+            }
+            let symtab_sh = self.shoff + link * self.shentsize as u64;
+            if read_u32(self.data, symtab_sh + 4)? != SHT_DYNSYM {
+                continue;
+            }
+            let symtab = read_u64(self.data, symtab_sh + 0x18)?;
+            let sym_entsize = read_u64(self.data, symtab_sh + 0x38)?;
+            let strtab_link = read_u32(self.data, symtab_sh + 0x28)? as u64;
+            let strtab_sh = self.shoff + strtab_link * self.shentsize as u64;
+            let strtab = read_u64(self.data, strtab_sh + 0x18)?;
+            for j in 0..size / entsize {
+                let rela = offset + j * entsize;
+                let info = read_u64(self.data, rela + 8)?;
+                let kind = info as u32;
+                if kind != R_X86_64_JUMP_SLOT && kind != R_X86_64_GLOB_DAT {
+                    continue;
+                }
+                let sym = symtab + (info >> 32) * sym_entsize;
+                let name = read_u32(self.data, sym)? as u64;
+                let sym_info = *self.data.get(sym as usize + 4).ok_or_else(malformed)?;
+                // Only undefined functions come from other objects.
+                if sym_info & 0xf != STT_FUNC || read_u16(self.data, sym + 6)? != 0 {
+                    continue;
+                }
+                imports.push(Import {
+                    name: read_str(self.data, strtab + name)?,
+                    got: read_u64(self.data, rela)?,
+                });
+            }
+        }
+        Ok(imports)
+    }
+}
+
+fn read_bytes(data: &[u8], offset: u64, len: usize) -> io::Result<&[u8]> {
+    let start = offset as usize;
+    data.get(start..start.checked_add(len).ok_or_else(malformed)?)
+        .ok_or_else(malformed)
+}
+
+fn read_u16(data: &[u8], offset: u64) -> io::Result<u16> {
+    Ok(u16::from_le_bytes(
+        read_bytes(data, offset, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(data: &[u8], offset: u64) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(
+        read_bytes(data, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(data: &[u8], offset: u64) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(
+        read_bytes(data, offset, 8)?.try_into().unwrap(),
+    ))
+}
+
+fn read_str(data: &[u8], offset: u64) -> io::Result<String> {
+    let bytes = data.get(offset as usize..).ok_or_else(malformed)?;
+    let len = bytes.iter().position(|&b| b == 0).ok_or_else(malformed)?;
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_symbols() {
+        let exe = fs::read(env::current_exe().expect("No current exe")).expect("Error reading exe");
+        let elf = Elf::parse(&exe).expect("Error parsing ELF");
+        assert!(elf.first_load_delta().is_ok());
+        let symbols = elf.symbols().expect("Error reading symbols");
+        let main = symbols
+            .iter()
+            .find(|s| s.name == "main" && s.is_function)
+            .expect("No main symbol");
+        assert!(elf.file_offset(main.value).unwrap().is_some());
+        assert_eq!(elf.file_offset(u64::MAX).unwrap(), None);
+        assert!(elf.file_offset(elf.entry().unwrap()).unwrap().is_some());
+        #[cfg(feature = "symbolication")]
+        {
+            assert!(elf.section(".text").unwrap().is_some());
+            assert_eq!(elf.section(".no-such-section").unwrap(), None);
+        }
+        let imports = elf.imports().expect("Error reading imports");
+        assert!(imports.iter().any(|i| i.name == "memcpy"));
+        assert!(imports.iter().all(|i| i.name != "main"));
+        assert!(Elf::parse(b"not an elf file").is_err());
+    }
+}
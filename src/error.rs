@@ -0,0 +1,87 @@
+use nix::unistd::Pid;
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Errors specific to this crate.
+///
+/// These are always returned wrapped in an `io::Error`, so that every API
+/// in this crate can keep returning `io::Result`. Use [`from_io`] to check
+/// whether an `io::Error` is one of these.
+///
+/// [`from_io`]: #method.from_io
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The tracer lacks the named capability, which the operation requires.
+    MissingCapability(&'static str),
+    /// The tracer is itself confined by seccomp, so it can't suspend a
+    /// tracee's seccomp filters.
+    TracerSeccomp,
+    /// The running kernel doesn't support the named feature.
+    Unsupported(&'static str),
+    /// A tracee was reaped by a wait outside this crate, such as
+    /// `Child::wait` or a `waitpid` in a `SIGCHLD` handler, so its state
+    /// changes can't be collected any more.
+    ChildReaped,
+    /// `SIGCHLD` is ignored or has `SA_NOCLDWAIT` set, so the kernel reaps
+    /// the tracer's children itself and they can't be waited for.
+    SigchldIgnored,
+    /// The tracee shares its memory with the given process because of a
+    /// `vfork`, so writing it would change both. The child's memory becomes
+    /// its own once it calls `exec`.
+    VforkShared(Pid),
+    /// The given tracee gained credentials by executing a setuid or setgid
+    /// program, which made it non-dumpable, and the tracer lacks
+    /// `CAP_SYS_PTRACE`, so reading its memory through `/proc/<pid>/mem` or
+    /// `process_vm_readv` is refused.
+    NotDumpable(Pid),
+    /// `SpawnOptions` contradict each other or hold a value out of range,
+    /// as described.
+    InvalidOptions(&'static str),
+}
+
+impl Error {
+    /// Get the `Error` wrapped in `e`, if it contains one.
+    pub fn from_io(e: &io::Error) -> Option<&Error> {
+        e.get_ref().and_then(|e| e.downcast_ref())
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::MissingCapability(_) | Error::TracerSeccomp | Error::NotDumpable(_) => {
+                io::ErrorKind::PermissionDenied
+            }
+            Error::Unsupported(_) => io::ErrorKind::Unsupported,
+            Error::InvalidOptions(_) => io::ErrorKind::InvalidInput,
+            Error::ChildReaped | Error::SigchldIgnored | Error::VforkShared(_) => {
+                io::ErrorKind::Other
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MissingCapability(cap) => write!(f, "The tracer lacks {}", cap),
+            Error::TracerSeccomp => write!(f, "The tracer is confined by seccomp"),
+            Error::Unsupported(feature) => write!(f, "The kernel does not support {}", feature),
+            Error::ChildReaped => write!(f, "The tracee was reaped outside this crate"),
+            Error::SigchldIgnored => {
+                write!(f, "SIGCHLD is ignored, so children can't be waited for")
+            }
+            Error::VforkShared(pid) => write!(f, "The tracee shares its memory with {}", pid),
+            Error::NotDumpable(pid) => write!(f, "Tracee {} became non-dumpable at exec", pid),
+            Error::InvalidOptions(problem) => write!(f, "Invalid spawn options: {}", problem),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(e.kind(), e)
+    }
+}
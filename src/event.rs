@@ -0,0 +1,70 @@
+use crate::AntiDebugProbe;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+
+/// A state change of a tracee, as reported by a [`TraceSession`].
+///
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The tracee exited normally with the given exit code.
+    Exited(i32),
+    /// The tracee was killed by the given signal. The second field indicates
+    /// whether a core dump was produced.
+    Signaled(Signal, bool),
+    /// A new tracee was automatically attached (for example a forked child)
+    /// and is in its initial stop. It should be resumed without a signal.
+    Attached,
+    /// The tracee is about to receive the given signal. Pass the signal when
+    /// resuming the tracee to deliver it, or resume without it to suppress it.
+    Signal(Signal),
+    /// The tracee stopped at a system call entry or exit, after being
+    /// resumed with `PTRACE_SYSCALL`.
+    ///
+    /// With `PTRACE_O_TRACESYSGOOD`, which `TraceSession::trace_syscalls`
+    /// sets, the kernel marks these stops as `SIGTRAP | 0x80`. Without it
+    /// they look like `SIGTRAP` signals, and are told apart from the
+    /// `SIGTRAP`s of breakpoints with `PTRACE_GETSIGINFO`, an extra system
+    /// call for each `SIGTRAP`, and `Tracee::syscall_info` can't say which
+    /// system call they are for.
+    Syscall,
+    /// The tracee stopped at entry to a system call that checks for a
+    /// debugger, which is reported this way instead of as `Event::Syscall`
+    /// once `TraceSession::detect_anti_debug` is set. Resume it the same
+    /// way; the system call's exit is reported as `Event::Syscall`.
+    AntiDebug(AntiDebugProbe),
+    /// The tracee called `fork`, creating the given child process.
+    Fork(Pid),
+    /// The tracee called `vfork`, creating the given child process.
+    Vfork(Pid),
+    /// The tracee called `clone`, creating the given thread or process.
+    Clone(Pid),
+    /// The tracee successfully called `exec`. The field holds the thread ID
+    /// that called `exec`, which differs from the tracee's pid if a thread
+    /// other than the thread group leader called it.
+    ///
+    /// The session checks the tracee's credentials with
+    /// `Tracee::check_exec` first, and its wait fails with
+    /// `Error::NotDumpable` instead of reporting this if the new program made
+    /// the tracee's memory unreadable. The tracee stays stopped at the
+    /// `exec` then, for the caller to detach or kill it.
+    Exec(Pid),
+    /// The tracee's `vfork` child has exited or called `exec`, and it is about
+    /// to continue execution.
+    VforkDone(Pid),
+    /// The tracee is about to exit with the given `waitpid`-style status.
+    Exit(i32),
+    /// A seccomp filter rule returned `SECCOMP_RET_TRACE` with the given data.
+    Seccomp(u16),
+    /// The tracee entered a group-stop because of the given stop signal.
+    /// Group-stops are only reported this way for tracees attached with
+    /// `PTRACE_SEIZE`; others report the stopping signal as `Event::Signal`,
+    /// and then stay stopped without another report.
+    ///
+    /// Resuming the tracee ends the group-stop, while `PTRACE_LISTEN` keeps
+    /// it stopped until it gets `SIGCONT`.
+    GroupStop(Signal),
+    /// The tracee stopped because of `PTRACE_INTERRUPT`, which is only
+    /// possible for tracees attached with `PTRACE_SEIZE`.
+    InterruptStop,
+}
@@ -0,0 +1,340 @@
+//! Driving an interactive tracee through its stdin and stdout.
+
+use crate::{nix_error, wait_error, Tracee};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+/// How long to wait for output between checks for stops of the tracee.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+type StopHandler<'a> = Box<dyn FnMut(&Tracee, WaitStatus) -> io::Result<Option<Signal>> + 'a>;
+
+/// Sends input to a tracee and waits for patterns in its output, in the
+/// style of `expect`, while handling the tracee's ptrace stops.
+///
+/// A traced program that stops while the tracer is blocked reading its
+/// output, or writing input it isn't reading, hangs both of them. `Expect`
+/// never blocks on the tracee: while it waits for output, or for room to
+/// write input, it also collects the tracee's stops and resumes it with
+/// `PTRACE_CONT`, passing on the signals it receives unless a handler set
+/// with [`on_stop`] says otherwise. Every wait is bounded by the
+/// [`timeout`], ten seconds by default.
+///
+/// The input and output are the tracee's pseudo-terminal, if it was spawned
+/// with `SpawnOptions::pty`, or else its piped stdin and stdout. As with
+/// `Tracee::wait_with_output`, only the tracee itself is waited for.
+///
+/// [`on_stop`]: #method.on_stop
+/// [`timeout`]: #method.timeout
+pub struct Expect<'a> {
+    tracee: &'a Tracee,
+    input: Option<File>,
+    output: File,
+    /// Output read but not yet consumed by a match.
+    buffer: Vec<u8>,
+    output_closed: bool,
+    status: Option<WaitStatus>,
+    timeout: Duration,
+    on_stop: Option<StopHandler<'a>>,
+}
+
+impl<'a> Expect<'a> {
+    /// Start interacting with the stopped `tracee`, resuming it.
+    ///
+    /// This takes the tracee's piped stdin and stdout from its `Child`, or
+    /// uses its pseudo-terminal, which is made non-blocking. It fails if the
+    /// tracee has neither a pseudo-terminal nor a piped stdout; a missing
+    /// stdin only makes [`send`] fail.
+    ///
+    /// [`send`]: #method.send
+    pub fn new(tracee: &'a mut Tracee) -> io::Result<Expect<'a>> {
+        let (input, output) = match tracee.pty_master() {
+            Some(master) => (Some(master.try_clone()?), master.try_clone()?),
+            None => {
+                let child = tracee
+                    .child_mut()
+                    .ok_or_else(|| io::Error::other("Tracee was not spawned by this crate"))?;
+                let output = child
+                    .stdout
+                    .take()
+                    .ok_or_else(|| io::Error::other("Tracee has no piped stdout"))?;
+                let input = child
+                    .stdin
+                    .take()
+                    .map(|stdin| File::from(OwnedFd::from(stdin)));
+                (input, File::from(OwnedFd::from(output)))
+            }
+        };
+        for file in input.iter().chain(Some(&output)) {
+            fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(nix_error)?;
+        }
+        ptrace::cont(tracee.pid(), None).map_err(nix_error)?;
+        Ok(Expect {
+            tracee,
+            input,
+            output,
+            buffer: vec![],
+            output_closed: false,
+            status: None,
+            timeout: Duration::from_secs(10),
+            on_stop: None,
+        })
+    }
+
+    /// Set how long each of the other methods waits before failing with
+    /// `io::ErrorKind::TimedOut`.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Expect<'a> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Call `f` with each stop of the tracee while waiting, instead of
+    /// resuming it with the signal it stopped for.
+    ///
+    /// The tracee is resumed with `PTRACE_CONT` and the signal `f` returns,
+    /// if any, once it returns `Ok`. Errors from `f` are returned from the
+    /// method that was waiting, leaving the tracee stopped.
+    pub fn on_stop<F>(&mut self, f: F) -> &mut Expect<'a>
+    where
+        F: FnMut(&Tracee, WaitStatus) -> io::Result<Option<Signal>> + 'a,
+    {
+        self.on_stop = Some(Box::new(f));
+        self
+    }
+
+    /// Write `data` to the tracee's input, waiting for it to read enough of
+    /// it if necessary.
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let deadline = Instant::now() + self.timeout;
+        let mut sent = 0;
+        while sent < data.len() {
+            let input = self
+                .input
+                .as_mut()
+                .ok_or_else(|| io::Error::other("Tracee has no piped stdin"))?;
+            match input.write(&data[sent..]) {
+                Ok(n) => sent += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.step(deadline, true)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `line` and a newline to the tracee's input.
+    pub fn send_line(&mut self, line: &str) -> io::Result<()> {
+        self.send(format!("{}\n", line).as_bytes())
+    }
+
+    /// Close the tracee's input, so that it reads end-of-file once it has
+    /// read everything sent. This does nothing with a pseudo-terminal, whose
+    /// master side is also the output; send `^D` instead.
+    pub fn close_input(&mut self) {
+        if self.tracee.pty_master().is_none() {
+            self.input = None;
+        }
+    }
+
+    /// Wait for `pattern` to appear in the tracee's output, returning the
+    /// output before it. Both are consumed, so the next call only searches
+    /// what comes after.
+    ///
+    /// Fails with `io::ErrorKind::UnexpectedEof` if the output is closed
+    /// first, and with `io::ErrorKind::TimedOut` if it doesn't appear in
+    /// time; the output read so far is kept for the next call either way.
+    pub fn expect<P: AsRef<[u8]>>(&mut self, pattern: P) -> io::Result<Vec<u8>> {
+        let pattern = pattern.as_ref();
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(start) = find(&self.buffer, pattern) {
+                let before = self.buffer[..start].to_vec();
+                self.buffer.drain(..start + pattern.len());
+                return Ok(before);
+            }
+            if self.output_closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "Output closed before {:?}",
+                        String::from_utf8_lossy(pattern)
+                    ),
+                ));
+            }
+            self.step(deadline, false)?;
+        }
+    }
+
+    /// Wait for the tracee to exit and its output to be closed, returning
+    /// the rest of the output with the tracee's final status.
+    pub fn expect_exit(&mut self) -> io::Result<(Vec<u8>, WaitStatus)> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let (true, Some(status)) = (self.output_closed, self.status) {
+                return Ok((self.buffer.split_off(0), status));
+            }
+            self.step(deadline, false)?;
+        }
+    }
+
+    /// The output that has been read but not consumed by a match.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// The tracee's final status, once it has exited.
+    pub fn status(&self) -> Option<WaitStatus> {
+        self.status
+    }
+
+    /// Handle the tracee's pending stops, then wait until its output is
+    /// readable, or its input writable if `writing`, for up to a short
+    /// interval, and read any output.
+    fn step(&mut self, deadline: Instant, writing: bool) -> io::Result<()> {
+        self.handle_stops()?;
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for the tracee",
+            ));
+        }
+        let interval = POLL_INTERVAL.min(deadline - now);
+        let mut fds = vec![];
+        if !self.output_closed {
+            fds.push(PollFd::new(self.output.as_raw_fd(), PollFlags::POLLIN));
+        }
+        if let (true, Some(input)) = (writing, &self.input) {
+            fds.push(PollFd::new(input.as_raw_fd(), PollFlags::POLLOUT));
+        }
+        if fds.is_empty() {
+            std::thread::sleep(interval);
+        } else {
+            match poll(&mut fds, interval.as_millis() as libc::c_int) {
+                Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => {}
+                Err(e) => return Err(nix_error(e)),
+            }
+        }
+        self.read_output()
+    }
+
+    /// Read all of the output that is available without blocking.
+    fn read_output(&mut self) -> io::Result<()> {
+        let mut chunk = [0; 4096];
+        while !self.output_closed {
+            match self.output.read(&mut chunk) {
+                Ok(0) => self.output_closed = true,
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // A pseudo-terminal's master fails with EIO once every
+                // process using the terminal is gone.
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO) => self.output_closed = true,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect and resume every stop of the tracee without blocking.
+    fn handle_stops(&mut self) -> io::Result<()> {
+        let pid = self.tracee.pid();
+        while self.status.is_none() {
+            let status = waitpid(pid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL))
+                .map_err(wait_error)?;
+            let signal = match status {
+                WaitStatus::StillAlive => break,
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                    self.status = Some(status);
+                    break;
+                }
+                status => match &mut self.on_stop {
+                    Some(on_stop) => on_stop(self.tracee, status)?,
+                    None => match status {
+                        WaitStatus::Stopped(_, signal) => Some(signal),
+                        _ => None,
+                    },
+                },
+            };
+            ptrace::cont(pid, signal).map_err(nix_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Expect<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Expect")
+            .field("pid", &self.tracee.pid())
+            .field("buffer", &String::from_utf8_lossy(&self.buffer))
+            .field("output_closed", &self.output_closed)
+            .field("status", &self.status)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+/// The position of the first occurrence of `pattern` in `haystack`.
+fn find(haystack: &[u8], pattern: &[u8]) -> Option<usize> {
+    if pattern.is_empty() {
+        return Some(0);
+    }
+    haystack
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_expect() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .arg("prompt")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        let mut signals = vec![];
+        let mut expect = Expect::new(&mut tracee).expect("Error starting expect");
+        expect.on_stop(|_, status| match status {
+            WaitStatus::Stopped(_, signal) => {
+                signals.push(signal);
+                Ok(Some(signal))
+            }
+            _ => Ok(None),
+        });
+        expect.expect("ready> ").unwrap();
+        expect.send_line("hello").unwrap();
+        assert_eq!(expect.expect("ready> ").unwrap(), b"got hello\n");
+        // The tracee stops for a signal before it answers.
+        expect.send_line("signal").unwrap();
+        expect.expect("got signal\n").unwrap();
+        expect.timeout(Duration::from_millis(100));
+        let e = expect.expect("never").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(expect.buffer(), b"ready> ");
+        expect.close_input();
+        expect.timeout(Duration::from_secs(10));
+        let (rest, status) = expect.expect_exit().unwrap();
+        assert_eq!(rest, b"ready> bye\n");
+        assert_eq!(status, WaitStatus::Exited(pid, 0));
+        drop(expect);
+        assert_eq!(signals, [Signal::SIGWINCH]);
+    }
+}
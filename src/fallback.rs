@@ -0,0 +1,68 @@
+//! Recording the fallbacks used for features the running kernel lacks.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// A feature that turned out to be missing, and was replaced by an older
+/// mechanism from then on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Fallback {
+    /// `PTRACE_ATTACH` for `PTRACE_SEIZE`.
+    Attach = 1,
+    /// `PTRACE_PEEKDATA` for `process_vm_readv`.
+    PeekData = 2,
+    /// Registers for `PTRACE_GET_SYSCALL_INFO`.
+    SyscallRegisters = 4,
+}
+
+static USED: AtomicU8 = AtomicU8::new(0);
+
+/// Remember that `fallback` was needed, so that the missing feature isn't
+/// tried again.
+pub(crate) fn record(fallback: Fallback) {
+    USED.fetch_or(fallback as u8, Ordering::Relaxed);
+}
+
+/// Whether `fallback` has been needed before.
+pub(crate) fn used(fallback: Fallback) -> bool {
+    USED.load(Ordering::Relaxed) & fallback as u8 != 0
+}
+
+/// Whether a request failed because the kernel doesn't know it, which older
+/// kernels report as `EIO`, `EINVAL` or `ENOSYS`.
+pub(crate) fn is_unknown(errno: i32) -> bool {
+    errno == libc::EIO || errno == libc::EINVAL || errno == libc::ENOSYS
+}
+
+/// Which fallbacks this process has used so far, as found by
+/// [`fallbacks`].
+///
+/// [`fallbacks`]: fn.fallbacks.html
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Fallbacks {
+    /// `TraceSession::attach` used `PTRACE_ATTACH`, because `PTRACE_SEIZE`
+    /// needs Linux 3.4.
+    pub attach: bool,
+    /// Tracee memory was read with `PTRACE_PEEKDATA`, because
+    /// `process_vm_readv` needs Linux 3.2.
+    pub peek_data: bool,
+    /// `Tracee::syscall_info` decoded the tracee's registers, because
+    /// `PTRACE_GET_SYSCALL_INFO` needs Linux 5.3.
+    pub syscall_registers: bool,
+}
+
+/// Which older mechanisms this process has fallen back to so far, because
+/// the running kernel lacked the newer ones.
+///
+/// Missing features are found when they are first used, and the fallback
+/// is used from then on without trying them again. See
+/// [`capabilities`] for finding out in advance.
+///
+/// [`capabilities`]: fn.capabilities.html
+pub fn fallbacks() -> Fallbacks {
+    Fallbacks {
+        attach: used(Fallback::Attach),
+        peek_data: used(Fallback::PeekData),
+        syscall_registers: used(Fallback::SyscallRegisters),
+    }
+}
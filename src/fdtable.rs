@@ -0,0 +1,385 @@
+//! A model of each tracee process's file descriptor table.
+
+use crate::accounting::read_tgid;
+use crate::{Event, SyscallInfo, TraceSession, Tracee};
+use nix::unistd::Pid;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The `O_CLOEXEC` bit of the `flags` in `/proc/<pid>/fdinfo`, in octal.
+const FDINFO_CLOEXEC: u32 = 0o2000000;
+/// `CLOSE_RANGE_CLOEXEC`, which marks the range close-on-exec instead.
+const CLOSE_RANGE_CLOEXEC: u64 = 1 << 2;
+
+/// An open file descriptor in an [`FdTable`].
+///
+/// [`FdTable`]: struct.FdTable.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FdEntry {
+    /// What the descriptor refers to, as reported by `/proc/<pid>/fd` when
+    /// it was opened. Sockets, pipes and other non-file descriptors look
+    /// like `socket:[1234]`.
+    pub target: PathBuf,
+    /// Whether the descriptor is closed on `exec`.
+    pub cloexec: bool,
+}
+
+/// What a system call in progress will do to the table once it returns.
+#[derive(Clone, Copy, Debug)]
+enum Pending {
+    /// Its return value is a new descriptor.
+    New,
+    /// It writes two new descriptors to the `int[2]` at this address.
+    Pair(u64),
+    /// It changes the flags of this descriptor.
+    Refresh(i32),
+    Close(i32),
+    /// `close_range(first, last, flags)`.
+    CloseRange(u64, u64, u64),
+}
+
+/// Keeps the file descriptor table of each tracee process up to date from
+/// the system calls that change it, so that a descriptor used long after
+/// it was opened can still be described, as in "fd 5 = /var/log/app.log".
+///
+/// Feed every stop to [`record`], or let [`run`] drive a whole
+/// [`TraceSession`]. Each process's table is seeded from `/proc` the first
+/// time it is seen, and then follows the `open`, `close`, `dup`, `pipe`,
+/// `socket`, `accept` and `fcntl` families of system calls, `close_range`,
+/// and the other calls that return new descriptors, such as `eventfd` and
+/// `memfd_create`. Descriptors marked close-on-exec are dropped at `exec`,
+/// and children of `fork` start with a copy of their parent's table.
+///
+/// Descriptors received over a Unix socket, or opened by `io_uring`, aren't
+/// seen until the table is seeded again with [`reload`].
+///
+/// [`record`]: #method.record
+/// [`run`]: #method.run
+/// [`reload`]: #method.reload
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug, Default)]
+pub struct FdTable {
+    in_flight: HashMap<Pid, Pending>,
+    tgids: HashMap<Pid, Pid>,
+    tables: HashMap<Pid, BTreeMap<i32, FdEntry>>,
+}
+
+impl FdTable {
+    /// Create a model that knows no processes.
+    pub fn new() -> FdTable {
+        FdTable::default()
+    }
+
+    /// Update the tables for `event`, reported by `tracee`.
+    ///
+    /// Call this for every stop, before resuming the tracee.
+    pub fn record(&mut self, tracee: &Tracee, event: Event) -> io::Result<()> {
+        let tid = tracee.pid();
+        match event {
+            Event::Syscall => self.record_syscall(tracee)?,
+            Event::Fork(child) | Event::Vfork(child) | Event::Clone(child) => {
+                let pid = self.tgid(tid);
+                let child_pid = self.tgid(child);
+                if child_pid != pid {
+                    let table = self.table(pid).clone();
+                    self.tables.insert(child_pid, table);
+                }
+            }
+            Event::Exec(former) => {
+                self.forget(former);
+                self.in_flight.remove(&tid);
+                let pid = self.tgid(tid);
+                self.table(pid).retain(|_, entry| !entry.cloexec);
+            }
+            Event::Exited(_) | Event::Signaled(..) => {
+                if self.tgid(tid) == tid {
+                    self.tables.remove(&tid);
+                }
+                self.forget(tid);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Forget any system call in progress for thread `pid`, for example
+    /// because it exited.
+    pub fn forget(&mut self, pid: Pid) {
+        self.in_flight.remove(&pid);
+        self.tgids.remove(&pid);
+    }
+
+    /// Read the table of process `pid` from `/proc` again.
+    pub fn reload(&mut self, pid: Pid) -> io::Result<()> {
+        let table = read_table(pid)?;
+        self.tables.insert(pid, table);
+        Ok(())
+    }
+
+    /// Follow the descriptor tables of the tracees in `session` with
+    /// [`TraceSession::run`] until they have all exited.
+    ///
+    /// The session should trace system calls, with [`trace_syscalls`].
+    ///
+    /// [`TraceSession::run`]: struct.TraceSession.html#method.run
+    /// [`trace_syscalls`]: struct.TraceSession.html#method.trace_syscalls
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        session.run(|session, pid, event| match session.get(pid) {
+            Some(tracee) => self.record(tracee, event),
+            None => {
+                self.record_exit(pid);
+                Ok(())
+            }
+        })
+    }
+
+    /// The open descriptors of process `pid`, if it has been seen.
+    pub fn fds(&self, pid: Pid) -> Option<&BTreeMap<i32, FdEntry>> {
+        self.tables.get(&pid)
+    }
+
+    /// The descriptor `fd` of process `pid`, if it is open.
+    pub fn get(&self, pid: Pid, fd: i32) -> Option<&FdEntry> {
+        self.tables.get(&pid)?.get(&fd)
+    }
+
+    /// What descriptor `fd` of process `pid` refers to, if it is open.
+    pub fn target(&self, pid: Pid, fd: i32) -> Option<&Path> {
+        self.get(pid, fd).map(|entry| entry.target.as_path())
+    }
+
+    fn record_syscall(&mut self, tracee: &Tracee) -> io::Result<()> {
+        let tid = tracee.pid();
+        let pid = self.tgid(tid);
+        // Seed the table before the call changes it.
+        self.table(pid);
+        match tracee.syscall_info()? {
+            SyscallInfo::Entry { number, args } => match pending(number as i64, &args) {
+                Some(pending) => {
+                    self.in_flight.insert(tid, pending);
+                }
+                None => {
+                    self.in_flight.remove(&tid);
+                }
+            },
+            SyscallInfo::Exit { value, is_error } => {
+                let pending = match self.in_flight.remove(&tid) {
+                    Some(pending) if !is_error => pending,
+                    _ => return Ok(()),
+                };
+                match pending {
+                    Pending::New => self.open(pid, tid, value as i32),
+                    Pending::Pair(addr) => {
+                        let [first, second] = tracee.read_value::<[i32; 2]>(addr)?;
+                        self.open(pid, tid, first);
+                        self.open(pid, tid, second);
+                    }
+                    Pending::Refresh(fd) => self.open(pid, tid, fd),
+                    Pending::Close(fd) => {
+                        self.table(pid).remove(&fd);
+                    }
+                    Pending::CloseRange(first, last, flags) => {
+                        let table = self.table(pid);
+                        let range =
+                            first.min(i32::MAX as u64) as i32..=last.min(i32::MAX as u64) as i32;
+                        if flags & CLOSE_RANGE_CLOEXEC != 0 {
+                            for (_, entry) in table.range_mut(range) {
+                                entry.cloexec = true;
+                            }
+                        } else {
+                            let closed: Vec<i32> = table.range(range).map(|(&fd, _)| fd).collect();
+                            for fd in closed {
+                                table.remove(&fd);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Forget the table of `pid` once it has exited and left the session.
+    fn record_exit(&mut self, pid: Pid) {
+        if self.tgids.get(&pid) == Some(&pid) {
+            self.tables.remove(&pid);
+        }
+        self.forget(pid);
+    }
+
+    /// Add descriptor `fd`, which thread `tid` of `pid` just opened.
+    fn open(&mut self, pid: Pid, tid: Pid, fd: i32) {
+        if let Some(entry) = read_entry(tid, fd) {
+            self.table(pid).insert(fd, entry);
+        }
+    }
+
+    /// The table of process `pid`, read from `/proc` if it hasn't been seen.
+    fn table(&mut self, pid: Pid) -> &mut BTreeMap<i32, FdEntry> {
+        self.tables
+            .entry(pid)
+            .or_insert_with(|| read_table(pid).unwrap_or_default())
+    }
+
+    /// The process that thread `tid` belongs to.
+    fn tgid(&mut self, tid: Pid) -> Pid {
+        *self
+            .tgids
+            .entry(tid)
+            .or_insert_with(|| read_tgid(tid).unwrap_or(tid))
+    }
+}
+
+/// Decide what the system call `number` with `args` will do to the table,
+/// if it changes it.
+fn pending(number: i64, args: &[u64; 6]) -> Option<Pending> {
+    let pending = match number {
+        libc::SYS_openat
+        | libc::SYS_openat2
+        | libc::SYS_open_by_handle_at
+        | libc::SYS_dup
+        | libc::SYS_dup3
+        | libc::SYS_socket
+        | libc::SYS_accept
+        | libc::SYS_accept4
+        | libc::SYS_eventfd2
+        | libc::SYS_epoll_create1
+        | libc::SYS_timerfd_create
+        | libc::SYS_signalfd4
+        | libc::SYS_inotify_init1
+        | libc::SYS_fanotify_init
+        | libc::SYS_memfd_create
+        | libc::SYS_userfaultfd
+        | libc::SYS_perf_event_open
+        | libc::SYS_pidfd_open
+        | libc::SYS_pidfd_getfd
+        | libc::SYS_io_uring_setup => Pending::New,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_open
+        | libc::SYS_creat
+        | libc::SYS_dup2
+        | libc::SYS_eventfd
+        | libc::SYS_epoll_create
+        | libc::SYS_signalfd
+        | libc::SYS_inotify_init => Pending::New,
+        libc::SYS_pipe2 => Pending::Pair(args[0]),
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_pipe => Pending::Pair(args[0]),
+        libc::SYS_socketpair => Pending::Pair(args[3]),
+        libc::SYS_fcntl => match args[1] as i32 {
+            libc::F_DUPFD | libc::F_DUPFD_CLOEXEC => Pending::New,
+            libc::F_SETFD => Pending::Refresh(args[0] as i32),
+            _ => return None,
+        },
+        libc::SYS_close => Pending::Close(args[0] as i32),
+        libc::SYS_close_range => Pending::CloseRange(args[0], args[1], args[2]),
+        _ => return None,
+    };
+    Some(pending)
+}
+
+/// Read the open descriptors of `pid` from `/proc`.
+pub(crate) fn read_table(pid: Pid) -> io::Result<BTreeMap<i32, FdEntry>> {
+    let mut table = BTreeMap::new();
+    for entry in fs::read_dir(format!("/proc/{}/fd", pid))? {
+        let fd = entry?.file_name().to_str().and_then(|fd| fd.parse().ok());
+        if let Some(entry) = fd.and_then(|fd| read_entry(pid, fd).map(|entry| (fd, entry))) {
+            table.insert(entry.0, entry.1);
+        }
+    }
+    Ok(table)
+}
+
+/// Read descriptor `fd` of thread `tid` from `/proc`, if it is open.
+fn read_entry(tid: Pid, fd: i32) -> Option<FdEntry> {
+    let target = fs::read_link(format!("/proc/{}/fd/{}", tid, fd)).ok()?;
+    let flags = fs::read_to_string(format!("/proc/{}/fdinfo/{}", tid, fd))
+        .ok()
+        .and_then(|info| {
+            info.lines()
+                .find_map(|line| line.strip_prefix("flags:"))
+                .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+        })
+        .unwrap_or(0);
+    Some(FdEntry {
+        target,
+        cloexec: flags & FDINFO_CLOEXEC != 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use nix::sys::ptrace;
+    use std::process::Command;
+
+    /// Trace the test process with `args`, returning the targets of the
+    /// descriptors above stdio it writes to, and the table at its exit.
+    fn trace(args: &[&str]) -> (Vec<PathBuf>, BTreeMap<i32, FdEntry>) {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        let pid = session
+            .spawn(Command::new(&path).args(args), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut table = FdTable::new();
+        let mut written = vec![];
+        let mut last = None;
+        ptrace::syscall(pid, None).unwrap();
+        loop {
+            let (_, event) = session.wait_for(pid).unwrap();
+            let signal = match event {
+                Event::Exited(code) => {
+                    assert_eq!(code, 0);
+                    break;
+                }
+                Event::Signal(signal) => Some(signal),
+                _ => None,
+            };
+            let tracee = session.get(pid).unwrap();
+            table.record(tracee, event).unwrap();
+            if let Ok(SyscallInfo::Entry { number, args }) = tracee.syscall_info() {
+                if number as i64 == libc::SYS_write && args[0] > 2 {
+                    let target = table
+                        .target(pid, args[0] as i32)
+                        .expect("Unknown descriptor");
+                    written.push(target.to_owned());
+                }
+                if number as i64 == libc::SYS_exit_group {
+                    last = table.fds(pid).cloned();
+                }
+            }
+            ptrace::syscall(pid, signal).unwrap();
+        }
+        (written, last.expect("No table recorded"))
+    }
+
+    #[test]
+    fn test_fd_table() {
+        let file = std::env::temp_dir().join(format!("spawn-ptrace-fds-{}", std::process::id()));
+        let (written, last) = trace(&["io", file.to_str().unwrap()]);
+        let _ = fs::remove_file(&file);
+        assert_eq!(written.len(), 10);
+        assert!(
+            written.iter().all(|target| *target == file),
+            "{:?}",
+            written
+        );
+        // Only the seeded stdio is left.
+        assert_eq!(last.keys().cloned().collect::<Vec<_>>(), [0, 1, 2]);
+
+        let (written, last) = trace(&["pipe"]);
+        assert_eq!(written.len(), 1);
+        assert!(
+            written[0].to_string_lossy().starts_with("pipe:"),
+            "{:?}",
+            written
+        );
+        assert!(!last.contains_key(&10));
+    }
+}
@@ -0,0 +1,114 @@
+//! Choosing which events a `TraceSession` reports.
+
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::collections::HashSet;
+
+/// The events a [`TraceSession`] reports, set with
+/// [`TraceSession::set_filter`].
+///
+/// Each kind of restriction is only applied once something has been added to
+/// it: a filter with no pids matches every tracee, and one with no system
+/// calls matches every system call. Stops that don't match are resumed by the
+/// session without being reported, and the exits of tracees that don't match
+/// are only used to update the session.
+///
+/// [`TraceSession`]: struct.TraceSession.html
+/// [`TraceSession::set_filter`]: struct.TraceSession.html#method.set_filter
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    pids: HashSet<Pid>,
+    syscalls: HashSet<u64>,
+    signals: HashSet<Signal>,
+}
+
+impl EventFilter {
+    /// Create a filter that matches every event.
+    pub fn new() -> EventFilter {
+        EventFilter::default()
+    }
+
+    /// Report events from tracee `pid`, leaving out other tracees unless
+    /// they are added too.
+    pub fn pid(&mut self, pid: Pid) -> &mut EventFilter {
+        self.pids.insert(pid);
+        self
+    }
+
+    /// Report the entry and exit stops of system call `number`, leaving out
+    /// other system calls unless they are added too.
+    ///
+    /// Leaving out system calls needs `PTRACE_GET_SYSCALL_INFO`, from Linux
+    /// 5.3, to tell the stops apart; without it every system call stop is
+    /// reported.
+    pub fn syscall(&mut self, number: u64) -> &mut EventFilter {
+        self.syscalls.insert(number);
+        self
+    }
+
+    /// Report signals `signal` is about to be delivered, leaving out other
+    /// signals unless they are added too. Signals that are left out are
+    /// delivered when the session resumes the tracee.
+    pub fn signal(&mut self, signal: Signal) -> &mut EventFilter {
+        self.signals.insert(signal);
+        self
+    }
+
+    pub(crate) fn wants_pid(&self, pid: Pid) -> bool {
+        self.pids.is_empty() || self.pids.contains(&pid)
+    }
+
+    pub(crate) fn wants_syscall(&self, number: u64) -> bool {
+        self.syscalls.is_empty() || self.syscalls.contains(&number)
+    }
+
+    pub(crate) fn wants_signal(&self, signal: Signal) -> bool {
+        self.signals.is_empty() || self.signals.contains(&signal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{Event, SpawnOptions, SyscallInfo, TraceSession};
+    use nix::sys::ptrace;
+    use std::process::Command;
+
+    #[test]
+    fn test_event_filter() {
+        assert!(EventFilter::new().wants_syscall(1));
+        assert!(!EventFilter::new()
+            .signal(Signal::SIGUSR1)
+            .wants_signal(Signal::SIGUSR2));
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        let pid = session
+            .spawn(&mut Command::new(&path), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut filter = EventFilter::new();
+        filter.syscall(libc::SYS_write as u64);
+        session.set_filter(filter);
+        ptrace::syscall(pid, None).expect("Error resuming tracee");
+        let mut stops = vec![];
+        loop {
+            match session.wait_for(pid).expect("Error waiting for tracee") {
+                (_, Event::Syscall) => {
+                    stops.push(session.get(pid).unwrap().syscall_info().unwrap());
+                    ptrace::syscall(pid, None).expect("Error resuming tracee");
+                }
+                (_, Event::Exited(0)) => break,
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        // It prints one line, and makes plenty of other system calls.
+        assert_eq!(stops.len(), 2);
+        match stops[0] {
+            SyscallInfo::Entry { number, .. } => assert_eq!(number, libc::SYS_write as u64),
+            ref info => panic!("Unexpected stop {:?}", info),
+        }
+        assert!(matches!(stops[1], SyscallInfo::Exit { value: 6, .. }));
+    }
+}
@@ -0,0 +1,365 @@
+#[cfg(target_arch = "x86_64")]
+use crate::Breakpoints;
+use crate::{inject, nix_error, wait_error, CommandPtraceSpawn, SpawnOptions, Tracee};
+use nix::errno::Errno;
+use nix::sys::ptrace::{self, Options};
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::io;
+#[cfg(target_arch = "x86_64")]
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How a copy forked by a [`ForkServer`] finished.
+///
+/// [`ForkServer`]: struct.ForkServer.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForkOutcome {
+    /// The copy exited with the given status.
+    Exited(i32),
+    /// The copy was killed by a signal, and whether it dumped core.
+    Crashed(Signal, bool),
+    /// The copy didn't finish in time and was killed.
+    TimedOut,
+}
+
+/// Forks fresh copies of a traced template process on demand.
+///
+/// Like the fork server used by AFL, this avoids paying for `exec` and
+/// program initialization on every run. Bring the template to the point it
+/// should be copied from, for example by running it to a breakpoint after
+/// its setup code, and hand it to [`new`], or let [`warm`] spawn it and run
+/// it to a function. Each call to [`fork`] then makes the template call
+/// `fork` from that point, and returns a handle to the traced copy, stopped
+/// exactly where the template is.
+///
+/// The template is never resumed by the server, so it can serve copies for
+/// as long as it lives. Children that a copy creates itself are not traced.
+///
+/// [`new`]: #method.new
+/// [`warm`]: #method.warm
+/// [`fork`]: #method.fork
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug)]
+pub struct ForkServer {
+    template: Tracee,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl ForkServer {
+    /// Use the stopped `template` as the process to make copies of.
+    ///
+    /// This replaces the template's ptrace options with
+    /// `PTRACE_O_TRACEFORK`, so that its forks are traced.
+    pub fn new(template: Tracee) -> io::Result<ForkServer> {
+        ptrace::setoptions(template.pid(), Options::PTRACE_O_TRACEFORK).map_err(nix_error)?;
+        Ok(ForkServer { template })
+    }
+
+    /// Spawn `command` and run it to the start of the function `symbol` in
+    /// its executable, then use it as the template.
+    ///
+    /// Pick a function that runs once the program's expensive setup is
+    /// done, so that every copy starts from there. Signals the template gets
+    /// on the way are delivered. To have the template killed along with the
+    /// server, give `options` a `DropPolicy::Kill`.
+    pub fn warm(
+        command: &mut Command,
+        options: SpawnOptions,
+        symbol: &str,
+    ) -> io::Result<ForkServer> {
+        let template = command.spawn_tracee(options)?;
+        let pid = template.pid();
+        if let Err(e) = run_to_symbol(pid, symbol) {
+            let _ = signal::kill(pid, Signal::SIGKILL);
+            let _ = waitpid(pid, Some(WaitPidFlag::__WALL));
+            return Err(e);
+        }
+        ForkServer::new(template)
+    }
+
+    /// The template process.
+    pub fn template(&self) -> &Tracee {
+        &self.template
+    }
+
+    /// Consume the server, returning the template process, which is left stopped.
+    pub fn into_template(self) -> Tracee {
+        self.template
+    }
+
+    /// Fork a new copy of the template.
+    ///
+    /// The copy is stopped with the same registers and memory as the
+    /// template, so it can be modified before it is started with
+    /// [`ForkChild::run`].
+    ///
+    /// [`ForkChild::run`]: struct.ForkChild.html#method.run
+    pub fn fork(&mut self) -> io::Result<ForkChild> {
+        let template = self.template.pid();
+        let ret = inject::syscall(template, libc::SYS_fork as u64, [0; 6])?;
+        if ret < 0 {
+            return Err(io::Error::from_raw_os_error(-ret as i32));
+        }
+        let pid = Pid::from_raw(ret as i32);
+        let status = initial_stop(pid)?;
+        let child = ForkChild {
+            tracee: Tracee::attached(pid, status),
+            template,
+            finished: false,
+        };
+        inject::restore_forked(template, pid)?;
+        // Don't trace the copy's own children.
+        ptrace::setoptions(pid, Options::empty()).map_err(nix_error)?;
+        Ok(child)
+    }
+}
+
+/// A copy of a [`ForkServer`]'s template.
+///
+/// If the copy hasn't finished when its handle is dropped, it is killed.
+///
+/// Once the copy has finished it is reaped by making the template call
+/// `wait4`, so the template must still be stopped at that point.
+///
+/// [`ForkServer`]: struct.ForkServer.html
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug)]
+pub struct ForkChild {
+    tracee: Tracee,
+    template: Pid,
+    finished: bool,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl ForkChild {
+    /// The process ID of the copy.
+    pub fn pid(&self) -> Pid {
+        self.tracee.pid()
+    }
+
+    /// The copy, for reading and changing its registers and memory before
+    /// it is run.
+    pub fn tracee(&self) -> &Tracee {
+        &self.tracee
+    }
+
+    /// Resume the copy and wait for it to finish.
+    ///
+    /// Signals sent to the copy are delivered to it, so a crash is reported
+    /// as `ForkOutcome::Crashed`.
+    pub fn run(&mut self) -> io::Result<ForkOutcome> {
+        self.run_until(None)
+    }
+
+    /// Resume the copy and wait up to `timeout` for it to finish, killing it
+    /// if it doesn't.
+    pub fn run_with_timeout(&mut self, timeout: Duration) -> io::Result<ForkOutcome> {
+        self.run_until(Some(Instant::now() + timeout))
+    }
+
+    fn run_until(&mut self, deadline: Option<Instant>) -> io::Result<ForkOutcome> {
+        if self.finished {
+            return Err(io::Error::other("Forked copy has already finished"));
+        }
+        let mut signal = None;
+        loop {
+            match ptrace::cont(self.pid(), signal) {
+                Ok(()) | Err(nix::Error::Sys(Errno::ESRCH)) => {}
+                Err(e) => return Err(nix_error(e)),
+            }
+            let status = match deadline {
+                None => waitpid(self.pid(), Some(WaitPidFlag::__WALL)).map_err(wait_error)?,
+                Some(deadline) => match self.wait_until(deadline)? {
+                    Some(status) => status,
+                    None => {
+                        self.kill()?;
+                        return Ok(ForkOutcome::TimedOut);
+                    }
+                },
+            };
+            match status {
+                WaitStatus::Exited(_, code) => {
+                    self.reap()?;
+                    return Ok(ForkOutcome::Exited(code));
+                }
+                WaitStatus::Signaled(_, signal, core) => {
+                    self.reap()?;
+                    return Ok(ForkOutcome::Crashed(signal, core));
+                }
+                WaitStatus::Stopped(_, sig) => signal = Some(sig),
+                _ => signal = None,
+            }
+        }
+    }
+
+    fn wait_until(&self, deadline: Instant) -> io::Result<Option<WaitStatus>> {
+        loop {
+            let flags = WaitPidFlag::WNOHANG | WaitPidFlag::__WALL;
+            match waitpid(self.pid(), Some(flags)).map_err(wait_error)? {
+                WaitStatus::StillAlive => {}
+                status => return Ok(Some(status)),
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Kill the copy and reap it.
+    pub fn kill(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        match signal::kill(self.pid(), Signal::SIGKILL) {
+            Ok(()) | Err(nix::Error::Sys(Errno::ESRCH)) => {}
+            Err(e) => return Err(nix_error(e)),
+        }
+        loop {
+            match waitpid(self.pid(), Some(WaitPidFlag::__WALL)).map_err(wait_error)? {
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => break,
+                _ => {}
+            }
+        }
+        self.reap()
+    }
+
+    /// Have the template collect the exit status of the finished copy, which
+    /// is its child.
+    fn reap(&mut self) -> io::Result<()> {
+        self.finished = true;
+        let options = (libc::WNOHANG | libc::__WALL) as u64;
+        let ret = inject::syscall(
+            self.template,
+            libc::SYS_wait4 as u64,
+            [self.pid().as_raw() as u64, 0, options, 0, 0, 0],
+        )?;
+        if ret < 0 {
+            return Err(io::Error::from_raw_os_error(-ret as i32));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Drop for ForkChild {
+    fn drop(&mut self) {
+        let _ = self.kill();
+    }
+}
+
+/// Resume the stopped tracee `pid` until it reaches the function `symbol`.
+#[cfg(target_arch = "x86_64")]
+fn run_to_symbol(pid: Pid, symbol: &str) -> io::Result<()> {
+    let mut breakpoints = Breakpoints::new();
+    let addr = breakpoints.insert_symbol(pid, symbol)?;
+    let mut signal = None;
+    loop {
+        ptrace::cont(pid, signal).map_err(nix_error)?;
+        match waitpid(pid, Some(WaitPidFlag::__WALL)).map_err(wait_error)? {
+            WaitStatus::Stopped(_, Signal::SIGTRAP) if breakpoints.hit(pid)? == Some(addr) => break,
+            WaitStatus::Stopped(_, sig) => signal = Some(sig),
+            WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                return Err(io::Error::other(format!(
+                    "Template exited before reaching {}",
+                    symbol
+                )))
+            }
+            _ => signal = None,
+        }
+    }
+    breakpoints.remove(pid, addr)?;
+    Ok(())
+}
+
+/// Collect the initial stop of `pid`, a process that was just created
+/// traced by an injected `fork` or `clone`.
+#[cfg(target_arch = "x86_64")]
+fn initial_stop(pid: Pid) -> io::Result<WaitStatus> {
+    loop {
+        match waitpid(pid, Some(WaitPidFlag::__WALL)).map_err(wait_error)? {
+            status @ WaitStatus::Stopped(..) | status @ WaitStatus::PtraceEvent(..) => {
+                return Ok(status)
+            }
+            WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                return Err(io::Error::other("Forked copy exited before it started"))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A frozen copy of a tracee, made with [`Tracee::checkpoint`], that fresh
+/// copies can be restored from any number of times.
+///
+/// The checkpoint is taken by making the tracee call `clone` with
+/// `CLONE_PARENT`, like `fork` except that the snapshot becomes a child of
+/// the tracee's parent rather than the tracee itself, so the tracee's own
+/// children are left as they were. Only the calling thread is copied, as
+/// with `fork`. The snapshot is traced and kept stopped, and each call to
+/// [`restore`] forks it the way a [`ForkServer`] forks its template, so it
+/// stays exactly as it was when the checkpoint was taken while the tracee
+/// and the restored copies carry on.
+///
+/// The snapshot is killed when the checkpoint is dropped.
+///
+/// [`Tracee::checkpoint`]: struct.Tracee.html#method.checkpoint
+/// [`restore`]: #method.restore
+/// [`ForkServer`]: struct.ForkServer.html
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug)]
+pub struct Checkpoint {
+    server: ForkServer,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Checkpoint {
+    pub(crate) fn new(tracee: Pid) -> io::Result<Checkpoint> {
+        let flags = (libc::CLONE_PARENT | libc::CLONE_PTRACE | libc::SIGCHLD) as u64;
+        let ret = inject::syscall(tracee, libc::SYS_clone as u64, [flags, 0, 0, 0, 0, 0])?;
+        if ret < 0 {
+            return Err(io::Error::from_raw_os_error(-ret as i32));
+        }
+        let pid = Pid::from_raw(ret as i32);
+        let status = initial_stop(pid)?;
+        let snapshot = Tracee::attached(pid, status);
+        let checkpoint = Checkpoint {
+            server: ForkServer { template: snapshot },
+        };
+        inject::restore_forked(tracee, pid)?;
+        ptrace::setoptions(pid, Options::PTRACE_O_TRACEFORK).map_err(nix_error)?;
+        Ok(checkpoint)
+    }
+
+    /// The stopped snapshot. Changing its registers or memory changes every
+    /// copy restored afterwards.
+    pub fn snapshot(&self) -> &Tracee {
+        self.server.template()
+    }
+
+    /// Fork a fresh copy of the snapshot, stopped with the registers and
+    /// memory the tracee had when the checkpoint was taken.
+    pub fn restore(&mut self) -> io::Result<ForkChild> {
+        self.server.fork()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Drop for Checkpoint {
+    fn drop(&mut self) {
+        let pid = self.snapshot().pid();
+        // The snapshot hasn't been reaped, so its pid can't have been reused.
+        if signal::kill(pid, Signal::SIGKILL).is_err() {
+            return;
+        }
+        loop {
+            match waitpid(pid, Some(WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::Exited(..)) | Ok(WaitStatus::Signaled(..)) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    }
+}
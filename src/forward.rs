@@ -0,0 +1,185 @@
+//! Forwarding termination signals from the tracer to a tracee.
+
+use crate::nix_error;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::Pid;
+use std::convert::TryFrom;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+/// The signals a [`SignalForwarder`] forwards.
+///
+/// [`SignalForwarder`]: struct.SignalForwarder.html
+const FORWARDED: [Signal; 3] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGQUIT];
+
+/// Whether a `SignalForwarder` exists, since signal handlers are global.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+/// Where the handler sends signals, as the argument to `kill`.
+static TARGET: AtomicI32 = AtomicI32::new(0);
+/// The last signal the handler received, or 0.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+/// Where a [`SignalForwarder`] sends the signals it receives.
+///
+/// [`SignalForwarder`]: struct.SignalForwarder.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardTarget {
+    /// Send signals to the tracee only.
+    Process,
+    /// Send signals to the tracee's process group, which is the tracee and
+    /// its children if it was spawned with [`SpawnOptions::setsid`].
+    ///
+    /// [`SpawnOptions::setsid`]: struct.SpawnOptions.html#method.setsid
+    ProcessGroup,
+}
+
+/// Forwards `SIGINT`, `SIGTERM` and `SIGQUIT` sent to the tracer on to a
+/// tracee, instead of letting them kill the tracer and leave the tracee
+/// stopped.
+///
+/// While it exists, the tracer handles those signals by sending them to
+/// the tracee with `kill`, and remembering them for [`received`]. The
+/// tracee then reports them as `Event::Signal` stops like any other
+/// signal, and delivering them lets it shut down in its own way, after
+/// which the tracer's event loop sees it exit and can finish normally. The
+/// handlers use `SA_RESTART`, so waits in progress aren't interrupted.
+///
+/// Signals a terminal sends for Ctrl-C or Ctrl-\ already reach the tracee
+/// if it is in the tracer's process group, and those aren't sent again.
+/// Only one forwarder can exist at a time, and the previous handlers are
+/// restored when it is dropped.
+///
+/// [`received`]: #method.received
+#[derive(Debug)]
+pub struct SignalForwarder {
+    previous: Vec<(Signal, SigAction)>,
+}
+
+impl SignalForwarder {
+    /// Start forwarding signals to `target` of tracee `pid`.
+    ///
+    /// Fails with `ErrorKind::AlreadyExists` if another forwarder exists.
+    pub fn new(pid: Pid, target: ForwardTarget) -> io::Result<SignalForwarder> {
+        if INSTALLED.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "A SignalForwarder already exists",
+            ));
+        }
+        let target = match target {
+            ForwardTarget::Process => pid.as_raw(),
+            ForwardTarget::ProcessGroup => {
+                let pgid = unsafe { libc::getpgid(pid.as_raw()) };
+                if pgid < 0 {
+                    INSTALLED.store(false, Ordering::SeqCst);
+                    return Err(io::Error::last_os_error());
+                }
+                -pgid
+            }
+        };
+        TARGET.store(target, Ordering::SeqCst);
+        RECEIVED.store(0, Ordering::SeqCst);
+        let action = SigAction::new(
+            SigHandler::SigAction(forward),
+            SaFlags::SA_RESTART | SaFlags::SA_SIGINFO,
+            SigSet::empty(),
+        );
+        let mut forwarder = SignalForwarder { previous: vec![] };
+        for &signal in &FORWARDED {
+            // If this fails, dropping the forwarder restores what was set.
+            let previous = unsafe { signal::sigaction(signal, &action) }.map_err(nix_error)?;
+            forwarder.previous.push((signal, previous));
+        }
+        Ok(forwarder)
+    }
+
+    /// The last signal that was forwarded, or `None` if there hasn't been
+    /// one.
+    ///
+    /// A tracer can check this after each event to start shutting down, for
+    /// example if the tracee handles the signal without exiting.
+    pub fn received(&self) -> Option<Signal> {
+        match RECEIVED.load(Ordering::SeqCst) {
+            0 => None,
+            signal => Signal::try_from(signal).ok(),
+        }
+    }
+}
+
+impl Drop for SignalForwarder {
+    fn drop(&mut self) {
+        for (signal, previous) in self.previous.drain(..) {
+            let _ = unsafe { signal::sigaction(signal, &previous) };
+        }
+        TARGET.store(0, Ordering::SeqCst);
+        INSTALLED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// The signal handler, which only makes async-signal-safe calls.
+extern "C" fn forward(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    RECEIVED.store(signal, Ordering::SeqCst);
+    let target = TARGET.load(Ordering::SeqCst);
+    if target == 0 {
+        return;
+    }
+    let errno = unsafe { *libc::__errno_location() };
+    // The terminal sends its signals to the whole foreground process group.
+    let from_terminal = unsafe { (*info).si_code } == libc::SI_KERNEL;
+    let group = if target < 0 {
+        -target
+    } else {
+        unsafe { libc::getpgid(target) }
+    };
+    if !(from_terminal && group == unsafe { libc::getpgrp() }) {
+        unsafe { libc::kill(target, signal) };
+    }
+    unsafe { *libc::__errno_location() = errno };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use nix::sys::ptrace;
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use std::process::Command;
+
+    #[test]
+    fn test_signal_forwarder() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let tracee = Command::new(&path)
+            .args(["sleep", "5000"])
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        let forwarder =
+            SignalForwarder::new(pid, ForwardTarget::Process).expect("Error forwarding signals");
+        let e = SignalForwarder::new(pid, ForwardTarget::Process).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(forwarder.received(), None);
+        ptrace::cont(pid, None).expect("Error continuing child");
+        // Handled in this thread, so it doesn't disturb other tests.
+        signal::raise(Signal::SIGTERM).expect("Error raising signal");
+        assert_eq!(forwarder.received(), Some(Signal::SIGTERM));
+        assert_eq!(
+            waitpid(pid, None),
+            Ok(WaitStatus::Stopped(pid, Signal::SIGTERM))
+        );
+        ptrace::cont(pid, Signal::SIGTERM).expect("Error continuing child");
+        assert_eq!(
+            waitpid(pid, None),
+            Ok(WaitStatus::Signaled(pid, Signal::SIGTERM, false))
+        );
+        drop(forwarder);
+        let action = unsafe {
+            signal::sigaction(
+                Signal::SIGTERM,
+                &SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty()),
+            )
+        }
+        .unwrap();
+        assert_eq!(action.handler(), SigHandler::SigDfl);
+    }
+}
@@ -0,0 +1,422 @@
+use crate::elf::Elf;
+use crate::{maps, memory, nix_error, Breakpoints, Event, Resume, SyscallInfo, TraceSession};
+use nix::sys::ptrace::{self, Options};
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AllocFunction {
+    Malloc,
+    Calloc,
+    Realloc,
+    Free,
+}
+
+const FUNCTIONS: &[(&str, AllocFunction)] = &[
+    ("malloc", AllocFunction::Malloc),
+    ("calloc", AllocFunction::Calloc),
+    ("realloc", AllocFunction::Realloc),
+    ("free", AllocFunction::Free),
+];
+
+/// An allocation function call that hasn't returned yet.
+#[derive(Debug)]
+struct PendingCall {
+    function: AllocFunction,
+    size: u64,
+    old_address: u64,
+    return_address: u64,
+    /// The stack pointer once the call has returned.
+    stack_pointer: u64,
+}
+
+/// A live heap allocation in the tracee.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    /// The address returned by the allocator.
+    pub address: u64,
+    /// The requested size in bytes.
+    pub size: u64,
+    /// The return address of the allocation call.
+    pub callsite: u64,
+}
+
+/// Allocations that were still live when a [`HeapTracer`] finished, grouped by callsite.
+///
+/// [`HeapTracer`]: struct.HeapTracer.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeakSummary {
+    /// Leaks for each callsite, ordered by descending number of bytes.
+    pub sites: Vec<LeakSite>,
+}
+
+impl LeakSummary {
+    /// The total number of bytes leaked.
+    pub fn total_bytes(&self) -> u64 {
+        self.sites.iter().map(|s| s.bytes).sum()
+    }
+
+    /// The total number of allocations leaked.
+    pub fn total_count(&self) -> usize {
+        self.sites.iter().map(|s| s.count).sum()
+    }
+}
+
+/// Leaked allocations from a single callsite.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeakSite {
+    /// The return address of the allocation calls.
+    pub callsite: u64,
+    /// How many allocations from this callsite were leaked.
+    pub count: usize,
+    /// The total size of the leaked allocations.
+    pub bytes: u64,
+}
+
+/// Traces a tracee's heap allocations by setting breakpoints on `malloc`,
+/// `calloc`, `realloc` and `free`.
+///
+/// The tracee's syscalls are traced until its libc has been mapped, then the
+/// allocation functions are resolved from libc's symbol tables and
+/// breakpoints are set on them. Statically linked programs that define these
+/// functions are supported as well. Forked children have the breakpoints
+/// removed and are detached, and the breakpoints are resolved again if the
+/// tracee calls `exec`.
+///
+/// Allocations made through other allocator entry points such as
+/// `posix_memalign` are not recorded.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Default)]
+pub struct HeapTracer {
+    breakpoints: Breakpoints,
+    functions: HashMap<u64, AllocFunction>,
+    pending: HashMap<Pid, Vec<PendingCall>>,
+    live: HashMap<u64, Allocation>,
+    allocation_count: usize,
+    free_count: usize,
+    syscall_entries: HashMap<Pid, u64>,
+    examined: HashSet<String>,
+    threads: HashSet<Pid>,
+    forks: HashSet<Pid>,
+    vforks: HashSet<Pid>,
+    unclassified: HashSet<Pid>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl HeapTracer {
+    /// Create a heap tracer with no recorded allocations.
+    pub fn new() -> HeapTracer {
+        HeapTracer::default()
+    }
+
+    /// Resume the single stopped tracee in `session` and trace its heap
+    /// allocations until it exits.
+    ///
+    /// This adds the ptrace options it needs to the session's.
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        let pids: Vec<Pid> = session.pids().collect();
+        let pid = match pids[..] {
+            [pid] => pid,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "HeapTracer requires a session with exactly one tracee",
+                ))
+            }
+        };
+        let options = Options::PTRACE_O_TRACESYSGOOD
+            | Options::PTRACE_O_TRACEEXEC
+            | Options::PTRACE_O_TRACECLONE
+            | Options::PTRACE_O_TRACEFORK
+            | Options::PTRACE_O_TRACEVFORK;
+        session.add_ptrace_options(options)?;
+        // Statically linked programs already have their allocator mapped.
+        self.resolve(pid)?;
+        session.run_with(|session, pid, event| self.handle_event(session, pid, event))
+    }
+
+    /// The number of allocations recorded.
+    pub fn allocation_count(&self) -> usize {
+        self.allocation_count
+    }
+
+    /// The number of calls to `free` with a non-null pointer recorded.
+    pub fn free_count(&self) -> usize {
+        self.free_count
+    }
+
+    /// The allocations that are currently live.
+    pub fn live_allocations(&self) -> impl Iterator<Item = &Allocation> {
+        self.live.values()
+    }
+
+    /// Summarize the currently live allocations by callsite.
+    ///
+    /// Once [`run`](#method.run) has returned these are the allocations that
+    /// were never freed.
+    pub fn leak_summary(&self) -> LeakSummary {
+        let mut sites: HashMap<u64, LeakSite> = HashMap::new();
+        for allocation in self.live.values() {
+            let site = sites
+                .entry(allocation.callsite)
+                .or_insert_with(|| LeakSite {
+                    callsite: allocation.callsite,
+                    count: 0,
+                    bytes: 0,
+                });
+            site.count += 1;
+            site.bytes += allocation.size;
+        }
+        let mut sites: Vec<LeakSite> = sites.into_values().collect();
+        sites.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.callsite.cmp(&b.callsite)));
+        LeakSummary { sites }
+    }
+
+    /// How to resume a tracee, tracing syscalls until the allocator has been
+    /// found.
+    fn resume(&self, signal: Option<Signal>) -> Resume {
+        if self.functions.is_empty() {
+            Resume::Syscall(signal)
+        } else {
+            Resume::Continue(signal)
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        session: &mut TraceSession,
+        pid: Pid,
+        event: Event,
+    ) -> io::Result<Option<Resume>> {
+        match event {
+            Event::Syscall => {
+                if self.functions.is_empty() {
+                    match session.get(pid).map(|t| t.syscall_info()).transpose()? {
+                        Some(SyscallInfo::Entry { number, .. }) => {
+                            self.syscall_entries.insert(pid, number);
+                        }
+                        Some(SyscallInfo::Exit { .. }) => {
+                            let number = self.syscall_entries.remove(&pid);
+                            if number == Some(libc::SYS_mmap as u64) {
+                                self.resolve(pid)?;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(Some(self.resume(None)))
+            }
+            Event::Signal(Signal::SIGTRAP) => match self.breakpoints.hit(pid)? {
+                Some(addr) => self.handle_breakpoint(session, pid, addr),
+                None => Ok(Some(self.resume(Some(Signal::SIGTRAP)))),
+            },
+            Event::Signal(signal) => Ok(Some(self.resume(Some(signal)))),
+            Event::Clone(child) | Event::Fork(child) | Event::Vfork(child) => {
+                match event {
+                    Event::Clone(_) => self.threads.insert(child),
+                    Event::Fork(_) => self.forks.insert(child),
+                    _ => self.vforks.insert(child),
+                };
+                if self.unclassified.remove(&child) {
+                    let decision = self.handle_new_tracee(child)?;
+                    session.resume_as(child, decision)?;
+                }
+                Ok(Some(self.resume(None)))
+            }
+            Event::Attached => {
+                if self.threads.contains(&pid)
+                    || self.forks.contains(&pid)
+                    || self.vforks.contains(&pid)
+                {
+                    self.handle_new_tracee(pid).map(Some)
+                } else {
+                    // Wait for the parent's event to learn what this is.
+                    self.unclassified.insert(pid);
+                    Ok(None)
+                }
+            }
+            Event::Exec(_) if self.vforks.remove(&pid) => {
+                // The vfork child no longer shares its parent's memory.
+                Ok(Some(Resume::Detach(None)))
+            }
+            Event::Exec(_) => {
+                // The old address space, and every breakpoint in it, is gone.
+                self.breakpoints.forget_all();
+                self.functions.clear();
+                self.examined.clear();
+                self.pending.clear();
+                self.resolve(pid)?;
+                Ok(Some(self.resume(None)))
+            }
+            Event::Exited(_) | Event::Signaled(..) => {
+                self.pending.remove(&pid);
+                self.syscall_entries.remove(&pid);
+                self.threads.remove(&pid);
+                self.vforks.remove(&pid);
+                Ok(None)
+            }
+            _ => Ok(Some(self.resume(None))),
+        }
+    }
+
+    /// How to resume the new tracee `pid`, once it is known what it is.
+    fn handle_new_tracee(&mut self, pid: Pid) -> io::Result<Resume> {
+        if self.forks.remove(&pid) {
+            // A forked child has its own copy of the breakpoints, which would
+            // kill it once it is no longer traced.
+            self.breakpoints.clear_from(pid)?;
+            return Ok(Resume::Detach(None));
+        }
+        // Threads and vfork children share the tracee's memory, so they are
+        // traced like the tracee itself.
+        Ok(self.resume(None))
+    }
+
+    fn handle_breakpoint(
+        &mut self,
+        session: &mut TraceSession,
+        pid: Pid,
+        addr: u64,
+    ) -> io::Result<Option<Resume>> {
+        let regs = ptrace::getregs(pid).map_err(nix_error)?;
+        if let Some(&function) = self.functions.get(&addr) {
+            if function == AllocFunction::Free {
+                if regs.rdi != 0 && self.live.remove(&regs.rdi).is_some() {
+                    self.free_count += 1;
+                }
+            } else {
+                let (size, old_address) = match function {
+                    AllocFunction::Calloc => (regs.rdi.saturating_mul(regs.rsi), 0),
+                    AllocFunction::Realloc => (regs.rsi, regs.rdi),
+                    _ => (regs.rdi, 0),
+                };
+                let return_address = memory::read_u64(pid, regs.rsp)?;
+                self.breakpoints.insert(pid, return_address)?;
+                self.pending.entry(pid).or_default().push(PendingCall {
+                    function,
+                    size,
+                    old_address,
+                    return_address,
+                    stack_pointer: regs.rsp + 8,
+                });
+            }
+        }
+        let calls = self.pending.entry(pid).or_default();
+        if let Some(i) = calls
+            .iter()
+            .rposition(|c| c.return_address == addr && c.stack_pointer == regs.rsp)
+        {
+            let call = calls.remove(i);
+            self.breakpoints.remove(pid, addr)?;
+            self.record_return(call, regs.rax);
+        }
+        if !self.breakpoints.contains(addr) {
+            return Ok(Some(self.resume(None)));
+        }
+        match self.breakpoints.step_over(session, pid)? {
+            Event::Signal(Signal::SIGTRAP) => Ok(Some(self.resume(None))),
+            event => self.handle_event(session, pid, event),
+        }
+    }
+
+    fn record_return(&mut self, call: PendingCall, result: u64) {
+        if call.function == AllocFunction::Realloc && call.old_address != 0 {
+            // realloc frees the old block on success, and when the new size is 0.
+            if (result != 0 || call.size == 0) && self.live.remove(&call.old_address).is_some() {
+                self.free_count += 1;
+            }
+        }
+        if result != 0 {
+            self.allocation_count += 1;
+            self.live.insert(
+                result,
+                Allocation {
+                    address: result,
+                    size: call.size,
+                    callsite: call.return_address,
+                },
+            );
+        }
+    }
+
+    /// Look for the allocation functions in the tracee's main executable and libc.
+    fn resolve(&mut self, pid: Pid) -> io::Result<()> {
+        if !self.functions.is_empty() {
+            return Ok(());
+        }
+        let maps = maps::read_maps(pid)?;
+        let main = maps.first().and_then(|m| m.pathname.clone());
+        for map in maps.iter().filter(|m| m.offset == 0) {
+            let path = match map.pathname {
+                Some(ref path) if path.starts_with('/') => path,
+                _ => continue,
+            };
+            let name = Path::new(path)
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("");
+            let is_libc = name.starts_with("libc.so") || name.starts_with("libc-");
+            if self.examined.contains(path) || !(is_libc || main.as_ref() == Some(path)) {
+                continue;
+            }
+            let data = fs::read(path)?;
+            let elf = Elf::parse(&data)?;
+            let bias = map.start.wrapping_sub(elf.first_load_delta()?);
+            let symbols = elf.symbols()?;
+            let mut found = vec![];
+            for &(name, function) in FUNCTIONS {
+                if let Some(symbol) = symbols.iter().find(|s| s.name == name && s.is_function) {
+                    found.push((bias.wrapping_add(symbol.value), function));
+                }
+            }
+            // The loader maps libc's segments one at a time, so wait until
+            // its code is in place before patching it.
+            let mapped = |addr: u64| {
+                maps.iter()
+                    .any(|m| m.contains(addr) && m.executable && m.pathname.as_ref() == Some(path))
+            };
+            if !found.iter().all(|&(addr, _)| mapped(addr)) {
+                continue;
+            }
+            self.examined.insert(path.clone());
+            for (addr, function) in found {
+                self.breakpoints.insert(pid, addr)?;
+                self.functions.insert(addr, function);
+            }
+            if !self.functions.is_empty() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use std::process::Command;
+
+    #[test]
+    fn test_heap_tracer() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session
+            .spawn(Command::new(&path).arg("alloc"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut tracer = HeapTracer::new();
+        tracer.run(&mut session).expect("Error tracing heap");
+        assert!(tracer.allocation_count() >= 2);
+        assert!(tracer.free_count() >= 1);
+        let leaks = tracer.leak_summary();
+        assert!(tracer.live_allocations().any(|a| a.size == 12345));
+        assert!(!tracer.live_allocations().any(|a| a.size == 54321));
+        assert!(leaks.total_bytes() >= 12345);
+        assert_eq!(leaks.total_count(), tracer.live_allocations().count());
+    }
+}
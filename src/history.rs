@@ -0,0 +1,253 @@
+//! Stepping a tracee thread backwards over the instructions it single-stepped.
+
+use crate::insn::{memory_writes, MAX_INSN_LEN};
+use crate::xstate::XState;
+use crate::{memory, nix_error, Event, TraceSession};
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::collections::VecDeque;
+use std::io;
+
+/// A thread's state before one instruction, and the old contents of the
+/// memory the instruction could write, or `None` if that isn't known.
+#[derive(Debug)]
+struct Step {
+    pid: Pid,
+    regs: libc::user_regs_struct,
+    xstate: XState,
+    memory: Option<Vec<(u64, Vec<u8>)>>,
+}
+
+/// A bounded window of the instructions that tracee threads single-stepped,
+/// which can be undone one at a time with [`step_back`].
+///
+/// Before each instruction, [`record`] saves the thread's registers, both
+/// general-purpose and its `XSAVE` state, and the memory the instruction
+/// may write, which is worked out by decoding it. [`step`] records and
+/// steps in one go, and `record` can be used before other ways of stepping,
+/// such as [`Breakpoints::step_over`]. Once the window is full, the oldest
+/// step is dropped for each new one.
+///
+/// The kernel's writes can't be known, so a step that executes a system
+/// call, or an instruction that can't be decoded, clears the history, and
+/// stepping back over it fails. Other writes the history doesn't see, by the
+/// tracee's other threads, by processes sharing its memory or by a signal
+/// frame pushed for a handler, are overwritten with older contents when
+/// stepping back over them, so the other threads should be stopped while
+/// stepping. An `exec` clears the history too. Software breakpoints are
+/// decoded as `int3`, so remove them from the instructions being stepped.
+///
+/// [`step_back`]: #method.step_back
+/// [`record`]: #method.record
+/// [`step`]: #method.step
+/// [`Breakpoints::step_over`]: struct.Breakpoints.html#method.step_over
+#[derive(Debug)]
+pub struct StepHistory {
+    capacity: usize,
+    steps: VecDeque<Step>,
+}
+
+impl StepHistory {
+    /// Create an empty history that keeps the last `capacity` steps.
+    pub fn new(capacity: usize) -> StepHistory {
+        StepHistory {
+            capacity,
+            steps: VecDeque::new(),
+        }
+    }
+
+    /// Save the state of the stopped thread `pid` before it executes its
+    /// next instruction, and return whether it can be stepped back over.
+    ///
+    /// If it can't, because the instruction is a system call or can't be
+    /// decoded, the history is cleared instead, and [`step_back`] fails
+    /// when it reaches this step.
+    ///
+    /// [`step_back`]: #method.step_back
+    pub fn record(&mut self, pid: Pid) -> io::Result<bool> {
+        let regs = ptrace::getregs(pid).map_err(nix_error)?;
+        let code = memory::read_available(pid, regs.rip, MAX_INSN_LEN).unwrap_or_default();
+        let memory = memory_writes(&code, &regs).map(|writes| {
+            writes
+                .into_iter()
+                .map(|(addr, len)| {
+                    (
+                        addr,
+                        memory::read_available(pid, addr, len).unwrap_or_default(),
+                    )
+                })
+                .filter(|(_, bytes)| !bytes.is_empty())
+                .collect()
+        });
+        let known = memory.is_some();
+        if !known {
+            self.steps.clear();
+        }
+        let step = Step {
+            pid,
+            regs,
+            xstate: XState::read(pid)?,
+            memory,
+        };
+        if self.capacity == 0 {
+            return Ok(known);
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+        Ok(known)
+    }
+
+    /// Record the state of thread `pid` of `session` with [`record`], then
+    /// single-step it and return the event the step reported, normally
+    /// `Event::Signal(SIGTRAP)`.
+    ///
+    /// If the thread exits, its steps are dropped, and if it execs, the
+    /// whole history is.
+    ///
+    /// [`record`]: #method.record
+    pub fn step(&mut self, session: &mut TraceSession, pid: Pid) -> io::Result<Event> {
+        self.record(pid)?;
+        session.step(pid, None)?;
+        let (_, event) = session.wait_for(pid)?;
+        match event {
+            Event::Signal(Signal::SIGTRAP) => {}
+            Event::Exec(_) => self.steps.clear(),
+            Event::Exited(_) | Event::Signaled(..) => self.forget(pid),
+            _ => {}
+        }
+        Ok(event)
+    }
+
+    /// Undo the most recent step, restoring the memory it could have
+    /// written and its thread's registers, and return the thread, or `None`
+    /// if there are no steps left.
+    ///
+    /// The thread must be stopped, and is left at the instruction it
+    /// stepped. Nothing is restored if the step can't be undone whole:
+    /// this fails with `ErrorKind::InvalidData` for a step whose writes
+    /// aren't known, which is dropped, and for one whose `XSAVE` state is a
+    /// different size from the thread's now, which is kept.
+    pub fn step_back(&mut self) -> io::Result<Option<Pid>> {
+        let step = match self.steps.pop_back() {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+        let memory = match &step.memory {
+            Some(memory) => memory,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Can't step back over a system call or unknown instruction",
+                ))
+            }
+        };
+        let len = XState::read(step.pid)?.as_bytes().len();
+        if step.xstate.as_bytes().len() != len {
+            self.steps.push_back(step);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "XSAVE state size changed since the step",
+            ));
+        }
+        for (addr, bytes) in memory {
+            memory::write(step.pid, *addr, bytes)?;
+        }
+        ptrace::setregs(step.pid, step.regs).map_err(nix_error)?;
+        step.xstate.write(step.pid)?;
+        Ok(Some(step.pid))
+    }
+
+    /// Drop the steps of thread `pid`, for example because it exited.
+    pub fn forget(&mut self, pid: Pid) {
+        self.steps.retain(|step| step.pid != pid);
+    }
+
+    /// Drop every step.
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+
+    /// The number of steps kept, the oldest of which may be one that can't
+    /// be stepped back over.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Whether there are no steps to step back over.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// The most steps the history keeps.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coverage::tests::run_to_caller;
+
+    /// The registers and top of the stack of thread `pid`.
+    fn state(pid: Pid) -> (u64, u64, u64, Vec<u8>) {
+        let regs = ptrace::getregs(pid).unwrap();
+        let mut stack = vec![0; 512];
+        memory::read(pid, regs.rsp - 256, &mut stack).unwrap();
+        (regs.rip, regs.rsp, regs.rax, stack)
+    }
+
+    #[test]
+    fn test_step_history() {
+        let (mut session, pid, _, _) = run_to_caller();
+        let start = state(pid);
+        let mut history = StepHistory::new(400);
+        for _ in 0..100 {
+            let event = history.step(&mut session, pid).unwrap();
+            assert_eq!(event, Event::Signal(Signal::SIGTRAP));
+        }
+        let middle = state(pid);
+        for _ in 0..200 {
+            history.step(&mut session, pid).unwrap();
+        }
+        assert_eq!(history.len(), 300);
+        assert_ne!(state(pid), middle);
+        for _ in 0..200 {
+            assert_eq!(history.step_back().unwrap(), Some(pid));
+        }
+        assert_eq!(state(pid), middle);
+        for _ in 0..100 {
+            history.step_back().unwrap();
+        }
+        assert_eq!(state(pid), start);
+        assert_eq!(history.step_back().unwrap(), None);
+
+        // Only the last steps are kept, and the program runs on as before
+        // from where it was stepped back to.
+        for _ in 0..500 {
+            history.step(&mut session, pid).unwrap();
+        }
+        assert_eq!(history.len(), 400);
+
+        // A system call can't be stepped back over, and stepping back to it
+        // fails without touching the thread.
+        let rip = ptrace::getregs(pid).unwrap().rip;
+        let mut code = [0; 2];
+        memory::read(pid, rip, &mut code).unwrap();
+        memory::write(pid, rip, &[0x0f, 0x05]).unwrap();
+        assert!(!history.record(pid).unwrap());
+        memory::write(pid, rip, &code).unwrap();
+        assert_eq!(history.len(), 1);
+        let before = state(pid);
+        let err = history.step_back().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(state(pid), before);
+        assert_eq!(history.step_back().unwrap(), None);
+        ptrace::cont(pid, None).unwrap();
+        let (_, event) = session.wait_for(pid).unwrap();
+        assert_eq!(event, Event::Exited(0));
+    }
+}
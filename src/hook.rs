@@ -0,0 +1,52 @@
+//! A callback told what a `TraceSession` does with its tracees.
+
+use crate::Event;
+use nix::errno::Errno;
+use nix::sys::ptrace::Request;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::fmt;
+
+/// Something a [`TraceSession`] did with a tracee, or saw of it, as passed
+/// to the hook set with [`TraceSession::set_hook`].
+///
+/// This is for seeing what the library is doing, for example by logging
+/// every activity when a tracer wedges. Only what goes through the session
+/// is reported, so resume tracees with [`TraceSession::resume`] rather
+/// than `ptrace` itself for their resumes to be seen.
+///
+/// [`TraceSession`]: struct.TraceSession.html
+/// [`TraceSession::set_hook`]: struct.TraceSession.html#method.set_hook
+/// [`TraceSession::resume`]: struct.TraceSession.html#method.resume
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Activity {
+    /// A tracee was spawned, added or attached.
+    Added(Pid),
+    /// A wait collected `event` of tracee `pid`. This is before the session
+    /// decides whether to report it, so events that it leaves out, and
+    /// resumes the tracee from itself, are seen too.
+    Stopped(Pid, Event),
+    /// The session resumed tracee `pid` with `request`, delivering `signal`.
+    Resumed {
+        pid: Pid,
+        request: Request,
+        signal: Option<Signal>,
+    },
+    /// A ptrace `request` the session made for tracee `pid` failed with
+    /// `errno`.
+    Failed {
+        pid: Pid,
+        request: Request,
+        errno: Errno,
+    },
+}
+
+/// The hook from `TraceSession::set_hook`.
+pub(crate) struct Hook(pub(crate) Box<dyn FnMut(&Activity)>);
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hook").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,574 @@
+//! Hardware breakpoints and watchpoints, using the x86 debug registers or
+//! the aarch64 `NT_ARM_HW_BREAK` and `NT_ARM_HW_WATCH` register sets.
+
+use crate::Event;
+use nix::unistd::Pid;
+use std::collections::HashSet;
+use std::io;
+
+/// What kind of access triggers an [`HwBreakpoint`].
+///
+/// [`HwBreakpoint`]: struct.HwBreakpoint.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HwTrigger {
+    /// Executing the instruction at the address.
+    Execute,
+    /// Reading the watched bytes. x86 can't watch reads alone, so this is
+    /// only supported on aarch64.
+    Read,
+    /// Writing to the watched bytes.
+    Write,
+    /// Reading or writing the watched bytes.
+    ReadWrite,
+}
+
+/// A hardware breakpoint or watchpoint.
+///
+/// Unlike a software breakpoint this doesn't modify the tracee's memory,
+/// so it works on read-only code and can watch data accesses, but only a
+/// few can be set at once. A thread that triggers one stops with `SIGTRAP`.
+/// Breakpoints on execution stop before the instruction runs, and
+/// watchpoints stop just after the instruction that made the access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HwBreakpoint {
+    /// The first address covered.
+    pub addr: u64,
+    /// The number of bytes covered.
+    ///
+    /// Watchpoints can cover 1, 2, 4 or 8 bytes. On x86 `addr` must be a
+    /// multiple of the length, while on aarch64 the bytes only need to lie
+    /// within one aligned 8-byte word. Execution breakpoints cover one
+    /// instruction: 1 byte on x86, 4 on aarch64.
+    pub len: usize,
+    /// What kind of access triggers the breakpoint.
+    pub trigger: HwTrigger,
+}
+
+impl HwBreakpoint {
+    /// A breakpoint on executing the instruction at `addr`.
+    pub fn execute(addr: u64) -> HwBreakpoint {
+        HwBreakpoint {
+            addr,
+            len: arch::INSN_LEN,
+            trigger: HwTrigger::Execute,
+        }
+    }
+
+    /// A watchpoint on `trigger` accesses to the `len` bytes at `addr`.
+    pub fn watch(addr: u64, len: usize, trigger: HwTrigger) -> HwBreakpoint {
+        HwBreakpoint { addr, len, trigger }
+    }
+
+    /// Check that a debug register slot can hold this breakpoint.
+    fn check(&self) -> io::Result<()> {
+        let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        match self.trigger {
+            HwTrigger::Execute if self.len != arch::INSN_LEN => {
+                invalid("Execution breakpoints must cover one instruction")
+            }
+            HwTrigger::Execute if !self.addr.is_multiple_of(arch::INSN_LEN as u64) => {
+                invalid("Execution breakpoint address is misaligned")
+            }
+            HwTrigger::Execute => Ok(()),
+            HwTrigger::Read if !arch::WATCH_READ => {
+                invalid("Read-only watchpoints aren't supported on this architecture")
+            }
+            _ if !matches!(self.len, 1 | 2 | 4 | 8) => invalid("Unsupported watchpoint length"),
+            _ if !arch::watch_aligned(self.addr, self.len) => {
+                invalid("Watchpoint address is misaligned for its length")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The hardware breakpoints set in every thread of a traced process.
+///
+/// Debug registers belong to a single thread and aren't inherited by new
+/// threads or child processes, so every thread the breakpoints should apply
+/// to must be added with [`add_thread`], or by passing each event from a
+/// session with `follow_forks` to [`handle_event`]. The kernel clears them
+/// on `exec`.
+///
+/// Each breakpoint takes up a slot, and the number of slots is fixed by the
+/// CPU: x86 has four shared by every kind of breakpoint, while aarch64 has
+/// separate slots for execution breakpoints and watchpoints.
+///
+/// [`add_thread`]: #method.add_thread
+/// [`handle_event`]: #method.handle_event
+#[derive(Debug)]
+pub struct HwBreakpoints {
+    /// Breakpoint slots, grouped by the register set they live in, with
+    /// the class each belongs to.
+    slots: Vec<(arch::Class, Option<HwBreakpoint>)>,
+    threads: HashSet<Pid>,
+}
+
+impl HwBreakpoints {
+    /// Create an empty set of breakpoints applying to the stopped thread `tid`.
+    ///
+    /// The number of slots is read from `tid`.
+    pub fn new(tid: Pid) -> io::Result<HwBreakpoints> {
+        let mut slots = vec![];
+        for (class, count) in arch::slot_counts(tid)? {
+            slots.extend((0..count).map(|_| (class, None)));
+        }
+        let mut threads = HashSet::new();
+        threads.insert(tid);
+        Ok(HwBreakpoints { slots, threads })
+    }
+
+    /// Set `breakpoint` in every thread, which must all be stopped,
+    /// returning the slot it was put in.
+    pub fn insert(&mut self, breakpoint: HwBreakpoint) -> io::Result<usize> {
+        breakpoint.check()?;
+        let class = arch::class(breakpoint.trigger);
+        let slot = self
+            .slots
+            .iter()
+            .position(|&(c, bp)| c == class && bp.is_none())
+            .ok_or_else(|| io::Error::other("No free hardware breakpoint slot"))?;
+        self.slots[slot].1 = Some(breakpoint);
+        if let Err(e) = self.apply_all() {
+            self.slots[slot].1 = None;
+            let _ = self.apply_all();
+            return Err(e);
+        }
+        Ok(slot)
+    }
+
+    /// Clear the breakpoint in `slot` from every thread, which must all be
+    /// stopped, returning it.
+    pub fn remove(&mut self, slot: usize) -> io::Result<Option<HwBreakpoint>> {
+        let breakpoint = match self.slots.get_mut(slot) {
+            Some((_, bp)) => bp.take(),
+            None => None,
+        };
+        if breakpoint.is_some() {
+            self.apply_all()?;
+        }
+        Ok(breakpoint)
+    }
+
+    /// Check whether thread `tid`, which has stopped with `SIGTRAP`, did so
+    /// because it triggered one of these breakpoints, returning its slot.
+    pub fn hit(&self, tid: Pid) -> io::Result<Option<usize>> {
+        let slot = arch::hit(tid, &self.slots)?;
+        Ok(slot.filter(|&slot| self.get(slot).is_some()))
+    }
+
+    /// The breakpoint in `slot`, if any.
+    pub fn get(&self, slot: usize) -> Option<&HwBreakpoint> {
+        self.slots.get(slot).and_then(|(_, bp)| bp.as_ref())
+    }
+
+    /// The breakpoints that are set, with their slots.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &HwBreakpoint)> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, (_, bp))| bp.as_ref().map(|bp| (slot, bp)))
+    }
+
+    /// Apply the breakpoints to the stopped thread `tid` too.
+    pub fn add_thread(&mut self, tid: Pid) -> io::Result<()> {
+        arch::apply(tid, &self.slots)?;
+        self.threads.insert(tid);
+        Ok(())
+    }
+
+    /// Stop tracking thread `tid`, for example because it has exited,
+    /// without touching its registers.
+    pub fn remove_thread(&mut self, tid: Pid) {
+        self.threads.remove(&tid);
+    }
+
+    /// Keep the set of threads up to date with an event from a session.
+    ///
+    /// New tracees reported as `Event::Attached` are added, and tracees that
+    /// have exited are removed.
+    pub fn handle_event(&mut self, pid: Pid, event: &Event) -> io::Result<()> {
+        match event {
+            Event::Attached => self.add_thread(pid),
+            Event::Exited(_) | Event::Signaled(..) => {
+                self.remove_thread(pid);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn apply_all(&self) -> io::Result<()> {
+        for &tid in &self.threads {
+            arch::apply(tid, &self.slots)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use super::{HwBreakpoint, HwTrigger};
+    use nix::unistd::Pid;
+    use std::io;
+    use std::mem;
+
+    pub(super) const INSN_LEN: usize = 1;
+
+    pub(super) const WATCH_READ: bool = false;
+
+    /// Debug register 6 holds the status of the last debug exception.
+    const DR6: usize = 6;
+    const DR7: usize = 7;
+
+    /// Every breakpoint uses one of the same four debug registers.
+    pub(super) type Class = u8;
+
+    pub(super) fn class(_trigger: HwTrigger) -> Class {
+        0
+    }
+
+    pub(super) fn slot_counts(_tid: Pid) -> io::Result<Vec<(Class, usize)>> {
+        Ok(vec![(0, 4)])
+    }
+
+    pub(super) fn watch_aligned(addr: u64, len: usize) -> bool {
+        addr.is_multiple_of(len as u64)
+    }
+
+    /// Read which slot triggered from DR6, clearing it for the next exception.
+    pub(super) fn hit(
+        tid: Pid,
+        _slots: &[(Class, Option<HwBreakpoint>)],
+    ) -> io::Result<Option<usize>> {
+        let dr6 = peek_debugreg(tid, DR6)?;
+        let slot = (0..4).find(|slot| dr6 & (1 << slot) != 0);
+        if slot.is_some() {
+            poke_debugreg(tid, DR6, 0)?;
+        }
+        Ok(slot)
+    }
+
+    /// Program `tid`'s debug registers with `slots`.
+    pub(super) fn apply(tid: Pid, slots: &[(Class, Option<HwBreakpoint>)]) -> io::Result<()> {
+        // Disable everything first so that the kernel never sees a
+        // half-updated breakpoint enabled.
+        poke_debugreg(tid, DR7, 0)?;
+        let mut dr7 = 0;
+        for (slot, &(_, bp)) in slots.iter().enumerate() {
+            if let Some(bp) = bp {
+                poke_debugreg(tid, slot, bp.addr)?;
+                dr7 |= dr7_bits(slot, &bp);
+            }
+        }
+        poke_debugreg(tid, DR7, dr7)
+    }
+
+    /// The DR7 bits that locally enable `bp` in `slot`.
+    fn dr7_bits(slot: usize, bp: &HwBreakpoint) -> u64 {
+        let rw = match bp.trigger {
+            HwTrigger::Execute => 0b00,
+            HwTrigger::Write => 0b01,
+            // Rejected by `check`.
+            HwTrigger::Read | HwTrigger::ReadWrite => 0b11,
+        };
+        let len = match bp.len {
+            1 => 0b00,
+            2 => 0b01,
+            8 => 0b10,
+            _ => 0b11,
+        };
+        (1 << (slot * 2)) | (rw << (16 + slot * 4)) | (len << (18 + slot * 4))
+    }
+
+    fn debugreg_offset(index: usize) -> usize {
+        mem::offset_of!(libc::user, u_debugreg) + index * mem::size_of::<u64>()
+    }
+
+    pub(super) fn poke_debugreg(tid: Pid, index: usize, value: u64) -> io::Result<()> {
+        let ret = unsafe {
+            libc::ptrace(
+                libc::PTRACE_POKEUSER,
+                tid.as_raw(),
+                debugreg_offset(index),
+                value,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub(super) fn peek_debugreg(tid: Pid, index: usize) -> io::Result<u64> {
+        nix::errno::Errno::clear();
+        let ret = unsafe {
+            libc::ptrace(
+                libc::PTRACE_PEEKUSER,
+                tid.as_raw(),
+                debugreg_offset(index),
+                0,
+            )
+        };
+        if ret == -1 && nix::errno::errno() != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as u64)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use super::{HwBreakpoint, HwTrigger};
+    use nix::unistd::Pid;
+    use std::io;
+    use std::mem;
+
+    pub(super) const INSN_LEN: usize = 4;
+
+    pub(super) const WATCH_READ: bool = true;
+
+    /// `si_code` of a `SIGTRAP` caused by a hardware breakpoint.
+    const TRAP_HWBKPT: i32 = 4;
+
+    const NT_ARM_HW_BREAK: libc::c_int = 0x402;
+    const NT_ARM_HW_WATCH: libc::c_int = 0x403;
+
+    /// Execution breakpoints and watchpoints have separate register sets,
+    /// which are identified by their note type.
+    pub(super) type Class = libc::c_int;
+
+    /// `struct user_hwdebug_state` from the kernel's ptrace ABI.
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct HwDebugState {
+        dbg_info: u32,
+        pad: u32,
+        dbg_regs: [HwDebugReg; 16],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct HwDebugReg {
+        addr: u64,
+        ctrl: u32,
+        pad: u32,
+    }
+
+    pub(super) fn class(trigger: HwTrigger) -> Class {
+        match trigger {
+            HwTrigger::Execute => NT_ARM_HW_BREAK,
+            HwTrigger::Read | HwTrigger::Write | HwTrigger::ReadWrite => NT_ARM_HW_WATCH,
+        }
+    }
+
+    pub(super) fn watch_aligned(addr: u64, len: usize) -> bool {
+        (addr % 8) as usize + len <= 8
+    }
+
+    /// Decode which slot triggered from the `SIGTRAP`'s siginfo.
+    ///
+    /// The kernel puts the register number in `si_errno`: `2 * i + 1` for
+    /// breakpoint register `i`, and the negation of that for watchpoint
+    /// register `i`.
+    pub(super) fn hit(
+        tid: Pid,
+        slots: &[(Class, Option<HwBreakpoint>)],
+    ) -> io::Result<Option<usize>> {
+        let info = nix::sys::ptrace::getsiginfo(tid).map_err(crate::nix_error)?;
+        if info.si_signo != libc::SIGTRAP || info.si_code != TRAP_HWBKPT || info.si_errno == 0 {
+            return Ok(None);
+        }
+        let (class, index) = if info.si_errno > 0 {
+            (NT_ARM_HW_BREAK, (info.si_errno - 1) / 2)
+        } else {
+            (NT_ARM_HW_WATCH, (-info.si_errno - 1) / 2)
+        };
+        Ok(slots
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(c, _))| c == class)
+            .nth(index as usize)
+            .map(|(slot, _)| slot))
+    }
+
+    pub(super) fn slot_counts(tid: Pid) -> io::Result<Vec<(Class, usize)>> {
+        let mut counts = vec![];
+        for class in [NT_ARM_HW_BREAK, NT_ARM_HW_WATCH] {
+            let mut state = HwDebugState {
+                dbg_info: 0,
+                pad: 0,
+                dbg_regs: [HwDebugReg::default(); 16],
+            };
+            regset(libc::PTRACE_GETREGSET, tid, class, &mut state, 16)?;
+            counts.push((class, (state.dbg_info & 0xff) as usize));
+        }
+        Ok(counts)
+    }
+
+    /// Program `tid`'s breakpoint and watchpoint registers with `slots`.
+    pub(super) fn apply(tid: Pid, slots: &[(Class, Option<HwBreakpoint>)]) -> io::Result<()> {
+        for class in [NT_ARM_HW_BREAK, NT_ARM_HW_WATCH] {
+            let mut state = HwDebugState {
+                dbg_info: 0,
+                pad: 0,
+                dbg_regs: [HwDebugReg::default(); 16],
+            };
+            let mut count = 0;
+            for &(_, bp) in slots.iter().filter(|&&(c, _)| c == class) {
+                if let Some(bp) = bp {
+                    state.dbg_regs[count] = HwDebugReg {
+                        addr: bp.addr,
+                        ctrl: ctrl(&bp),
+                        pad: 0,
+                    };
+                }
+                count += 1;
+            }
+            regset(libc::PTRACE_SETREGSET, tid, class, &mut state, count)?;
+        }
+        Ok(())
+    }
+
+    /// The control register value enabling `bp` for user space.
+    fn ctrl(bp: &HwBreakpoint) -> u32 {
+        const ENABLE: u32 = 1;
+        const PRIVILEGE_EL0: u32 = 0b10 << 1;
+        let access = match bp.trigger {
+            HwTrigger::Execute => 0,
+            HwTrigger::Read => 0b01,
+            HwTrigger::Write => 0b10,
+            HwTrigger::ReadWrite => 0b11,
+        };
+        // One byte address select bit per byte covered.
+        let bas = (1u32 << bp.len) - 1;
+        ENABLE | PRIVILEGE_EL0 | (access << 3) | (bas << 5)
+    }
+
+    fn regset(
+        request: libc::c_uint,
+        tid: Pid,
+        class: Class,
+        state: &mut HwDebugState,
+        count: usize,
+    ) -> io::Result<()> {
+        let mut iov = libc::iovec {
+            iov_base: state as *mut HwDebugState as *mut libc::c_void,
+            iov_len: mem::size_of::<u64>() + count * mem::size_of::<HwDebugReg>(),
+        };
+        let ret = unsafe {
+            libc::ptrace(
+                request,
+                tid.as_raw(),
+                class as usize,
+                &mut iov as *mut libc::iovec,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{SpawnOptions, TraceSession};
+    use nix::sys::ptrace;
+    use nix::sys::signal::Signal;
+    use std::process::Command;
+
+    #[test]
+    fn test_watchpoint() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        let pid = session
+            .spawn(&mut Command::new(&path), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let regs = session.get(pid).unwrap().registers().unwrap();
+        let mut breakpoints = HwBreakpoints::new(pid).expect("Error reading slots");
+        for invalid in [
+            HwBreakpoint::watch(regs.rsp, 3, HwTrigger::Write),
+            HwBreakpoint::watch(regs.rsp + 2, 4, HwTrigger::Write),
+            HwBreakpoint::watch(regs.rsp, 8, HwTrigger::Read),
+            HwBreakpoint {
+                addr: regs.rip,
+                len: 4,
+                trigger: HwTrigger::Execute,
+            },
+        ] {
+            let e = breakpoints.insert(invalid).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+        breakpoints
+            .insert(HwBreakpoint::watch(regs.rsp + 8, 2, HwTrigger::Write))
+            .expect("Error inserting watchpoint");
+        assert_eq!(breakpoints.remove(0).unwrap().map(|bp| bp.len), Some(2));
+        // The dynamic loader reads argc at the top of the stack first thing.
+        let slot = breakpoints
+            .insert(HwBreakpoint::watch(regs.rsp, 8, HwTrigger::ReadWrite))
+            .expect("Error inserting watchpoint");
+        assert_eq!(slot, 0);
+        assert_eq!(breakpoints.iter().count(), 1);
+        assert_eq!(arch::peek_debugreg(pid, 0).unwrap(), regs.rsp);
+        ptrace::cont(pid, None).expect("Error resuming tracee");
+        let (_, event) = session.wait_for(pid).expect("Error waiting for tracee");
+        assert_eq!(event, Event::Signal(Signal::SIGTRAP));
+        assert_eq!(breakpoints.hit(pid).unwrap(), Some(slot));
+        assert_eq!(breakpoints.hit(pid).unwrap(), None);
+        assert_eq!(
+            breakpoints.remove(slot).expect("Error removing watchpoint"),
+            Some(HwBreakpoint::watch(regs.rsp, 8, HwTrigger::ReadWrite))
+        );
+        assert_eq!(arch::peek_debugreg(pid, 7).unwrap() & 0xff, 0);
+        ptrace::cont(pid, None).expect("Error resuming tracee");
+        loop {
+            match session.wait_for(pid).expect("Error waiting for tracee") {
+                (_, Event::Exited(code)) => {
+                    assert_eq!(code, 0);
+                    break;
+                }
+                (pid, _) => ptrace::cont(pid, None).expect("Error resuming tracee"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_threads() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.follow_forks();
+        let pid = session
+            .spawn(Command::new(&path).arg("threads"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut breakpoints = HwBreakpoints::new(pid).expect("Error reading slots");
+        let regs = session.get(pid).unwrap().registers().unwrap();
+        breakpoints
+            .insert(HwBreakpoint::execute(regs.rip - 1))
+            .expect("Error inserting breakpoint");
+        let dr7 = arch::peek_debugreg(pid, 7).unwrap();
+        ptrace::cont(pid, None).expect("Error resuming tracee");
+        let mut threads = 0;
+        while !session.is_empty() {
+            let (tid, event) = session.wait_any().expect("Error waiting for tracee");
+            breakpoints
+                .handle_event(tid, &event)
+                .expect("Error updating threads");
+            let signal = match event {
+                Event::Attached => {
+                    assert_eq!(arch::peek_debugreg(tid, 7).unwrap(), dr7);
+                    threads += 1;
+                    None
+                }
+                Event::Signal(signal) => Some(signal),
+                Event::Exited(_) | Event::Signaled(..) => continue,
+                _ => None,
+            };
+            ptrace::cont(tid, signal).expect("Error resuming tracee");
+        }
+        assert_eq!(threads, 2);
+        assert!(breakpoints.threads.is_empty());
+    }
+}
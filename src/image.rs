@@ -0,0 +1,314 @@
+//! Exporting the state of a stopped tracee, for example as CRIU images.
+
+use crate::fdtable::read_table;
+use crate::memory;
+use crate::perf::page_size;
+use crate::{FdEntry, MemoryMap, Tracee, XState};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// How much memory is read from the tracee at a time.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// The mappings whose contents the kernel provides, and that can't be read
+/// or restored as memory.
+const KERNEL_MAPPINGS: [&str; 3] = ["[vvar]", "[vvar_vclock]", "[vsyscall]"];
+
+/// The magic numbers at the start of a CRIU image file, from CRIU's
+/// `magic.h`: the one common to per-process images, and the pagemap's.
+const IMG_COMMON_MAGIC: u32 = 0x5456_4319;
+const PAGEMAP_MAGIC: u32 = 0x5608_4025;
+/// The `PE_PRESENT` flag of a pagemap entry, for pages in the pages image.
+const PE_PRESENT: u64 = 1 << 2;
+
+/// Where [`Tracee::export_image`] writes the state of a stopped tracee.
+///
+/// Each method takes one part of the state, and does nothing by default,
+/// so a sink only implements the parts it stores. They are called in the
+/// order listed: the registers, each memory mapping followed by its
+/// contents, and then the file descriptors. An error from the sink ends
+/// the export.
+///
+/// [`CriuPages`] writes the memory as CRIU's pagemap and pages images;
+/// other formats, or the rest of a CRIU image set, can be written by
+/// implementing this.
+///
+/// [`Tracee::export_image`]: struct.Tracee.html#method.export_image
+/// [`CriuPages`]: struct.CriuPages.html
+pub trait ImageSink {
+    /// Take the general-purpose registers, and the FPU and SIMD state if
+    /// the CPU has `XSAVE`.
+    fn registers(
+        &mut self,
+        _regs: &libc::user_regs_struct,
+        _xstate: Option<&XState>,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Take a memory mapping, from `/proc/<pid>/maps`.
+    fn mapping(&mut self, _map: &MemoryMap) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Take the contents of `map` from `addr` on, which is page aligned, as
+    /// is the length of `data`. The contents of a mapping come in order,
+    /// and may be split into several calls, or stop short of the end of the
+    /// mapping where its pages can't be read, such as past the end of a
+    /// mapped file.
+    fn pages(&mut self, _map: &MemoryMap, _addr: u64, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Take open file descriptor `fd`, from `/proc/<pid>/fd`.
+    fn fd(&mut self, _fd: i32, _entry: &FdEntry) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Pass the state of the stopped tracee to `sink`, as described for
+/// [`ImageSink`].
+pub(crate) fn export<S: ImageSink>(tracee: &Tracee, sink: &mut S) -> io::Result<()> {
+    let pid = tracee.pid();
+    let xstate = match tracee.xstate() {
+        Ok(xstate) => Some(xstate),
+        Err(ref e) if e.kind() == io::ErrorKind::Unsupported => None,
+        Err(e) => return Err(e),
+    };
+    sink.registers(&tracee.registers()?, xstate.as_ref())?;
+    let page = page_size();
+    for map in tracee.memory_maps()? {
+        sink.mapping(&map)?;
+        let kernel = map
+            .pathname
+            .as_deref()
+            .is_some_and(|path| KERNEL_MAPPINGS.contains(&path));
+        if !map.readable || kernel {
+            continue;
+        }
+        let mut addr = map.start;
+        while addr < map.end {
+            let len = CHUNK_SIZE.min((map.end - addr) as usize);
+            let data = match memory::read_available(pid, addr, len) {
+                Ok(data) => data,
+                Err(_) => break,
+            };
+            let whole = data.len() - data.len() % page;
+            if whole == 0 {
+                break;
+            }
+            sink.pages(&map, addr, &data[..whole])?;
+            if whole < len {
+                break;
+            }
+            addr += whole as u64;
+        }
+    }
+    for (fd, entry) in read_table(pid)? {
+        sink.fd(fd, &entry)?;
+    }
+    Ok(())
+}
+
+/// An [`ImageSink`] that writes a tracee's memory as CRIU's
+/// `pagemap-<pid>.img` and `pages-<id>.img` images.
+///
+/// The pagemap image lists the runs of pages that were read, and the pages
+/// image holds their contents in the same order, as `criu dump` writes
+/// them. The pages of every readable mapping are written, including those
+/// of files mapped read-only, which CRIU itself would map from the file
+/// again instead. The other images of a restorable set, such as `core`,
+/// `mm` and `files`, aren't written.
+///
+/// [`ImageSink`]: trait.ImageSink.html
+#[derive(Debug)]
+pub struct CriuPages {
+    pagemap: BufWriter<File>,
+    pages: BufWriter<File>,
+}
+
+impl CriuPages {
+    /// Create the images for tracee `pid` in `dir`, with pages image ID
+    /// `pages_id`, replacing any that are there.
+    pub fn create<P: AsRef<Path>>(dir: P, pid: i32, pages_id: u32) -> io::Result<CriuPages> {
+        let dir = dir.as_ref();
+        let mut pagemap = BufWriter::new(File::create(dir.join(format!("pagemap-{}.img", pid)))?);
+        let pages = BufWriter::new(File::create(dir.join(format!("pages-{}.img", pages_id)))?);
+        pagemap.write_all(&IMG_COMMON_MAGIC.to_le_bytes())?;
+        pagemap.write_all(&PAGEMAP_MAGIC.to_le_bytes())?;
+        // A `pagemap_head` naming the pages image.
+        let mut head = vec![];
+        field(&mut head, 1, pages_id as u64);
+        entry(&mut pagemap, &head)?;
+        Ok(CriuPages { pagemap, pages })
+    }
+
+    /// Write out everything buffered.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.pagemap.flush()?;
+        self.pages.flush()
+    }
+}
+
+impl ImageSink for CriuPages {
+    fn pages(&mut self, _map: &MemoryMap, addr: u64, data: &[u8]) -> io::Result<()> {
+        // A `pagemap_entry` for the run.
+        let mut message = vec![];
+        field(&mut message, 1, addr);
+        field(&mut message, 2, (data.len() / page_size()) as u64);
+        field(&mut message, 4, PE_PRESENT);
+        entry(&mut self.pagemap, &message)?;
+        self.pages.write_all(data)
+    }
+}
+
+/// Append protobuf varint field `number` with `value` to `message`.
+fn field(message: &mut Vec<u8>, number: u64, value: u64) {
+    varint(message, number << 3);
+    varint(message, value);
+}
+
+fn varint(message: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        message.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    message.push(value as u8);
+}
+
+/// Write `message` as an entry of a CRIU image, after its size.
+fn entry<W: Write>(image: &mut W, message: &[u8]) -> io::Result<()> {
+    image.write_all(&(message.len() as u32).to_le_bytes())?;
+    image.write_all(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::convert::TryInto;
+    use std::env;
+    use std::fs;
+    use std::process::Command;
+
+    #[derive(Default)]
+    struct Recorder {
+        rip: Option<u64>,
+        maps: Vec<MemoryMap>,
+        pages: Vec<(u64, Vec<u8>)>,
+        fds: Vec<i32>,
+    }
+
+    impl ImageSink for Recorder {
+        fn registers(
+            &mut self,
+            regs: &libc::user_regs_struct,
+            _xstate: Option<&XState>,
+        ) -> io::Result<()> {
+            self.rip = Some(regs.rip);
+            Ok(())
+        }
+
+        fn mapping(&mut self, map: &MemoryMap) -> io::Result<()> {
+            self.maps.push(map.clone());
+            Ok(())
+        }
+
+        fn pages(&mut self, map: &MemoryMap, addr: u64, data: &[u8]) -> io::Result<()> {
+            assert_eq!(self.maps.last(), Some(map));
+            assert!(map.start <= addr && addr + data.len() as u64 <= map.end);
+            self.pages.push((addr, data.to_vec()));
+            Ok(())
+        }
+
+        fn fd(&mut self, fd: i32, _entry: &FdEntry) -> io::Result<()> {
+            self.fds.push(fd);
+            Ok(())
+        }
+    }
+
+    /// Read a varint at the start of `bytes`, returning it and its length.
+    fn read_varint(bytes: &[u8]) -> (u64, usize) {
+        let mut value = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return (value, i + 1);
+            }
+        }
+        panic!("Truncated varint");
+    }
+
+    #[test]
+    fn test_export_image() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut recorder = Recorder::default();
+        tracee
+            .export_image(&mut recorder)
+            .expect("Error exporting image");
+        let rip = tracee.registers().unwrap().rip;
+        assert_eq!(recorder.rip, Some(rip));
+        assert_eq!(recorder.maps, tracee.memory_maps().unwrap());
+        let (addr, data) = recorder
+            .pages
+            .iter()
+            .find(|(addr, data)| *addr <= rip && rip < addr + data.len() as u64)
+            .expect("No pages with the code");
+        let mut code = [0; 16];
+        tracee.read_memory(rip, &mut code).unwrap();
+        let offset = (rip - addr) as usize;
+        assert_eq!(&data[offset..offset + 16], &code);
+        assert!(recorder.fds.starts_with(&[0, 1, 2]));
+
+        let dir = env::temp_dir().join(format!("spawn-ptrace-criu-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pid = tracee.pid().as_raw();
+        let mut images = CriuPages::create(&dir, pid, 7).unwrap();
+        tracee.export_image(&mut images).unwrap();
+        images.flush().unwrap();
+        let pagemap = fs::read(dir.join(format!("pagemap-{}.img", pid))).unwrap();
+        let pages = fs::read(dir.join("pages-7.img")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(pagemap[..4], IMG_COMMON_MAGIC.to_le_bytes());
+        assert_eq!(pagemap[4..8], PAGEMAP_MAGIC.to_le_bytes());
+        // Each entry's fields, in order, after the head's pages ID.
+        let mut entries = vec![];
+        let mut at = 8;
+        while at < pagemap.len() {
+            let size = u32::from_le_bytes(pagemap[at..at + 4].try_into().unwrap()) as usize;
+            let mut message = &pagemap[at + 4..at + 4 + size];
+            let mut fields = vec![];
+            while !message.is_empty() {
+                let (key, n) = read_varint(message);
+                let (value, m) = read_varint(&message[n..]);
+                fields.push((key >> 3, value));
+                message = &message[n + m..];
+            }
+            entries.push(fields);
+            at += 4 + size;
+        }
+        assert_eq!(entries[0], vec![(1, 7)]);
+        let mut total = 0;
+        for fields in &entries[1..] {
+            assert_eq!(fields.len(), 3);
+            assert_eq!(fields[2], (4, PE_PRESENT));
+            total += fields[1].1 as usize * page_size();
+        }
+        assert_eq!(entries.len() - 1, recorder.pages.len());
+        assert_eq!(pages.len(), total);
+        // The pages come in the order of the pagemap.
+        let (first, data) = &recorder.pages[0];
+        assert_eq!(entries[1][0], (1, *first));
+        assert_eq!(pages[..data.len()], data[..]);
+
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
@@ -0,0 +1,503 @@
+//! Running code on behalf of the tracer inside a stopped tracee.
+
+use crate::elf::Elf;
+use crate::{maps, memory, nix_error, tkill, Tracee};
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::fs;
+use std::io;
+#[cfg(target_arch = "x86_64")]
+use std::mem;
+#[cfg(target_arch = "x86_64")]
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// The x86-64 `syscall` instruction.
+#[cfg(target_arch = "x86_64")]
+const SYSCALL_INSN: [u8; 2] = [0x0f, 0x05];
+
+/// The x86-64 `int3` instruction.
+#[cfg(target_arch = "x86_64")]
+const INT3: u8 = 0xcc;
+
+/// The size of the x86-64 red zone below the stack pointer, which leaf
+/// functions may use without moving the stack pointer.
+#[cfg(target_arch = "x86_64")]
+const RED_ZONE: u64 = 128;
+
+/// The size of a thread name in the kernel, including the NUL terminator.
+#[cfg(target_arch = "x86_64")]
+const TASK_COMM_LEN: usize = 16;
+
+/// The flag `dlopen` passes to glibc's internal `__libc_dlopen_mode`.
+#[cfg(target_arch = "x86_64")]
+const RTLD_DLOPEN: libc::c_int = 0x8000_0000u32 as libc::c_int;
+
+/// The longest `dlerror` message that is read from the tracee.
+#[cfg(target_arch = "x86_64")]
+const MAX_ERROR_LEN: usize = 1024;
+
+#[cfg(target_arch = "x86_64")]
+const PAGE_SIZE: u64 = 4096;
+
+/// Make the stopped tracee `pid` execute system call `number` with `args`,
+/// returning its raw result, which is a negated errno on failure.
+///
+/// The tracee's registers and the code at its program counter are restored
+/// afterwards, and the tracee is left stopped. Any `PTRACE_EVENT` stops the
+/// system call causes are stepped through, and signals that arrive meanwhile
+/// are re-sent to the tracee once it has been restored.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn syscall(pid: Pid, number: u64, args: [u64; 6]) -> io::Result<i64> {
+    let saved = ptrace::getregs(pid).map_err(nix_error)?;
+    let mut code = [0; 2];
+    memory::read(pid, saved.rip, &mut code)?;
+    memory::write(pid, saved.rip, &SYSCALL_INSN)?;
+    let mut regs = saved;
+    regs.rax = number;
+    // Stop the kernel from treating this as a restarted system call.
+    regs.orig_rax = u64::MAX;
+    regs.rdi = args[0];
+    regs.rsi = args[1];
+    regs.rdx = args[2];
+    regs.r10 = args[3];
+    regs.r8 = args[4];
+    regs.r9 = args[5];
+    let result = ptrace::setregs(pid, regs)
+        .map_err(nix_error)
+        .and_then(|()| step_syscall(pid));
+    // Put the tracee back the way it was even if the system call failed.
+    memory::write(pid, saved.rip, &code)?;
+    ptrace::setregs(pid, saved).map_err(nix_error)?;
+    let (value, signals) = result?;
+    for signal in signals {
+        tkill(pid, signal)?;
+    }
+    Ok(value)
+}
+
+/// Turn the raw result of an injected system call into an `io::Result`.
+#[cfg(target_arch = "x86_64")]
+fn syscall_result(ret: i64) -> io::Result<u64> {
+    if (-4095..0).contains(&ret) {
+        return Err(io::Error::from_raw_os_error(-ret as i32));
+    }
+    Ok(ret as u64)
+}
+
+/// Change the protection of the pages covering `range` in the stopped
+/// tracee `pid` to `prot` with an injected `mprotect`.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn protect(pid: Pid, range: Range<u64>, prot: libc::c_int) -> io::Result<()> {
+    if range.start >= range.end {
+        return Ok(());
+    }
+    let start = range.start & !(PAGE_SIZE - 1);
+    let end = range
+        .end
+        .checked_add(PAGE_SIZE - 1)
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOMEM))?
+        & !(PAGE_SIZE - 1);
+    let args = [start, end - start, prot as u64, 0, 0, 0];
+    syscall_result(syscall(pid, libc::SYS_mprotect as u64, args)?).map(drop)
+}
+
+/// Map `len` bytes of anonymous memory with protection `prot` into the
+/// stopped tracee `pid` with an injected `mmap`, returning its address.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn allocate(pid: Pid, len: u64, prot: libc::c_int) -> io::Result<u64> {
+    let args = [
+        0,
+        len,
+        prot as u64,
+        (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64,
+        u64::MAX,
+        0,
+    ];
+    syscall_result(syscall(pid, libc::SYS_mmap as u64, args)?)
+}
+
+/// Unmap `len` bytes at `addr` in the stopped tracee `pid` with an
+/// injected `munmap`.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn deallocate(pid: Pid, addr: u64, len: u64) -> io::Result<()> {
+    let args = [addr, len, 0, 0, 0, 0];
+    syscall_result(syscall(pid, libc::SYS_munmap as u64, args)?).map(drop)
+}
+
+/// Memory mapped into a tracee by [`Tracee::allocate_scoped`], which is
+/// unmapped again when this is dropped.
+///
+/// The tracee must be stopped when this is dropped, since unmapping makes
+/// it call `munmap`. Otherwise, or if that fails, the memory is leaked; use
+/// [`free`] to see the error instead.
+///
+/// [`Tracee::allocate_scoped`]: struct.Tracee.html#method.allocate_scoped
+/// [`free`]: #method.free
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug)]
+pub struct RemoteAllocation<'a> {
+    tracee: &'a Tracee,
+    addr: u64,
+    len: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl<'a> RemoteAllocation<'a> {
+    pub(crate) fn new(tracee: &'a Tracee, addr: u64, len: u64) -> RemoteAllocation<'a> {
+        RemoteAllocation { tracee, addr, len }
+    }
+
+    /// The address of the memory in the tracee.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// The length of the memory that was asked for.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether no memory was asked for, which `mmap` doesn't allow.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Unmap the memory now, returning any error.
+    pub fn free(self) -> io::Result<()> {
+        let result = self.tracee.free(self.addr, self.len);
+        mem::forget(self);
+        result
+    }
+
+    /// Keep the memory mapped, returning its address.
+    pub fn leak(self) -> u64 {
+        let addr = self.addr;
+        mem::forget(self);
+        addr
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Drop for RemoteAllocation<'_> {
+    fn drop(&mut self) {
+        let _ = self.tracee.free(self.addr, self.len);
+    }
+}
+
+/// Single-step tracee `pid` over a `syscall` instruction, returning `rax`
+/// and any signals that interrupted the step.
+#[cfg(target_arch = "x86_64")]
+fn step_syscall(pid: Pid) -> io::Result<(i64, Vec<Signal>)> {
+    let mut signals = vec![];
+    loop {
+        ptrace::step(pid, None).map_err(nix_error)?;
+        match waitpid(pid, Some(WaitPidFlag::__WALL)).map_err(nix_error)? {
+            WaitStatus::Stopped(_, Signal::SIGTRAP) => break,
+            WaitStatus::Stopped(_, signal) => signals.push(signal),
+            WaitStatus::PtraceEvent(..) | WaitStatus::PtraceSyscall(_) => {}
+            WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                return Err(io::Error::other(
+                    "Tracee exited during injected system call",
+                ))
+            }
+            _ => {}
+        }
+    }
+    let regs = ptrace::getregs(pid).map_err(nix_error)?;
+    Ok((regs.rax as i64, signals))
+}
+
+/// Restore what [`syscall`] left behind in a child that was forked by an
+/// injected `fork`: its copy of the tracee's memory still contains the
+/// injected instruction, and its registers are just past it.
+///
+/// `template` is the tracee that forked, which must be stopped.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn restore_forked(template: Pid, child: Pid) -> io::Result<()> {
+    let regs = ptrace::getregs(template).map_err(nix_error)?;
+    let mut code = [0; 2];
+    memory::read(template, regs.rip, &mut code)?;
+    memory::write(child, regs.rip, &code)?;
+    ptrace::setregs(child, regs).map_err(nix_error)
+}
+
+/// Make the stopped tracee `pid` call the function at `addr` with up to six
+/// integer `args`, returning the value it returns.
+///
+/// The function returns to address 0, and the resulting fault marks the end
+/// of the call. Registers are restored afterwards and signals are handled
+/// the same way as in [`syscall`].
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn call(pid: Pid, addr: u64, args: &[u64]) -> io::Result<u64> {
+    if args.len() > 6 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "At most six arguments can be passed",
+        ));
+    }
+    let saved = ptrace::getregs(pid).map_err(nix_error)?;
+    // Leave the red zone alone, and align the stack as it would be just
+    // after a call instruction pushed the return address.
+    let sp = ((saved.rsp - RED_ZONE) & !0xf) - 8;
+    memory::write(pid, sp, &0u64.to_ne_bytes())?;
+    let mut regs = saved;
+    regs.rsp = sp;
+    regs.rip = addr;
+    // No vector registers are used by variadic arguments.
+    regs.rax = 0;
+    regs.orig_rax = u64::MAX;
+    let mut arg_regs = [
+        &mut regs.rdi,
+        &mut regs.rsi,
+        &mut regs.rdx,
+        &mut regs.rcx,
+        &mut regs.r8,
+        &mut regs.r9,
+    ];
+    for (reg, &arg) in arg_regs.iter_mut().zip(args) {
+        **reg = arg;
+    }
+    let result = ptrace::setregs(pid, regs)
+        .map_err(nix_error)
+        .and_then(|()| run_call(pid));
+    ptrace::setregs(pid, saved).map_err(nix_error)?;
+    let (value, signals) = result?;
+    for signal in signals {
+        tkill(pid, signal)?;
+    }
+    Ok(value)
+}
+
+/// Resume tracee `pid` until it traps or faults, returning the signal
+/// that stopped it and any other signals that arrived meanwhile.
+#[cfg(target_arch = "x86_64")]
+fn run_to_fault(pid: Pid) -> io::Result<(Signal, Vec<Signal>)> {
+    let mut signals = vec![];
+    loop {
+        ptrace::cont(pid, None).map_err(nix_error)?;
+        match waitpid(pid, Some(WaitPidFlag::__WALL)).map_err(nix_error)? {
+            WaitStatus::Stopped(
+                _,
+                signal @ (Signal::SIGTRAP
+                | Signal::SIGSEGV
+                | Signal::SIGBUS
+                | Signal::SIGILL
+                | Signal::SIGFPE),
+            ) => return Ok((signal, signals)),
+            WaitStatus::Stopped(_, signal) => signals.push(signal),
+            WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                return Err(io::Error::other("Tracee exited during injected code"))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Run the function call [`call`] set up in tracee `pid`, returning `rax`
+/// and any signals that arrived meanwhile.
+#[cfg(target_arch = "x86_64")]
+fn run_call(pid: Pid) -> io::Result<(u64, Vec<Signal>)> {
+    let (signal, signals) = run_to_fault(pid)?;
+    let regs = ptrace::getregs(pid).map_err(nix_error)?;
+    match signal {
+        Signal::SIGSEGV if regs.rip == 0 => Ok((regs.rax, signals)),
+        Signal::SIGTRAP => Err(io::Error::other("Tracee trapped in injected function call")),
+        _ => Err(io::Error::other("Tracee crashed in injected function call")),
+    }
+}
+
+/// Copy `code` into a new executable mapping in the stopped tracee `pid`
+/// and run it until it traps, returning the registers at the trap.
+///
+/// An `int3` is appended to `code`, so that running off its end traps.
+/// Registers and signals are handled the same way as in [`call`], and the
+/// mapping is removed afterwards.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn run_code(pid: Pid, code: &[u8]) -> io::Result<libc::user_regs_struct> {
+    let len = code.len() as u64 + 1;
+    let prot = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+    let buf = allocate(pid, len, prot)?;
+    let mut scratch = code.to_vec();
+    scratch.push(INT3);
+    let saved = ptrace::getregs(pid).map_err(nix_error)?;
+    let mut regs = saved;
+    regs.rsp = (saved.rsp - RED_ZONE) & !0xf;
+    regs.rip = buf;
+    regs.orig_rax = u64::MAX;
+    let result = memory::write(pid, buf, &scratch)
+        .and_then(|()| ptrace::setregs(pid, regs).map_err(nix_error))
+        .and_then(|()| run_to_fault(pid))
+        .and_then(|(signal, signals)| {
+            let regs = ptrace::getregs(pid).map_err(nix_error)?;
+            match signal {
+                Signal::SIGTRAP => Ok((regs, signals)),
+                _ => Err(io::Error::other("Tracee crashed in injected code")),
+            }
+        });
+    ptrace::setregs(pid, saved).map_err(nix_error)?;
+    deallocate(pid, buf, len)?;
+    let (regs, signals) = result?;
+    for signal in signals {
+        tkill(pid, signal)?;
+    }
+    Ok(regs)
+}
+
+/// Rename the stopped tracee thread `pid` by making it call `prctl` with
+/// `PR_SET_NAME`, since only a thread's own process can write its `comm`.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn set_name(pid: Pid, name: &str) -> io::Result<()> {
+    if name.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Thread names can't contain NUL",
+        ));
+    }
+    // The kernel truncates the name to 15 bytes, so it fits in a buffer on
+    // the tracee's stack below the red zone.
+    let mut buf = [0; TASK_COMM_LEN];
+    let len = name.len().min(TASK_COMM_LEN - 1);
+    buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+    let regs = ptrace::getregs(pid).map_err(nix_error)?;
+    let addr = (regs.rsp - RED_ZONE - TASK_COMM_LEN as u64) & !0xf;
+    let mut saved = [0; TASK_COMM_LEN];
+    memory::read(pid, addr, &mut saved)?;
+    memory::write(pid, addr, &buf)?;
+    let args = [libc::PR_SET_NAME as u64, addr, 0, 0, 0, 0];
+    let result = syscall(pid, libc::SYS_prctl as u64, args);
+    memory::write(pid, addr, &saved)?;
+    match result? {
+        ret if ret < 0 => Err(io::Error::from_raw_os_error(-ret as i32)),
+        _ => Ok(()),
+    }
+}
+
+/// Find the function `name` among the shared objects and executable mapped
+/// into process `pid`, returning the address it is loaded at.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn find_function(pid: Pid, name: &str) -> io::Result<Option<u64>> {
+    for map in maps::read_maps(pid)?.iter().filter(|m| m.offset == 0) {
+        let path = match map.pathname {
+            Some(ref path) if path.starts_with('/') => path,
+            _ => continue,
+        };
+        // Deleted files and inaccessible ones can't define anything we can call.
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        let elf = match Elf::parse(&data) {
+            Ok(elf) => elf,
+            Err(_) => continue,
+        };
+        let symbols = elf.symbols()?;
+        if let Some(symbol) = symbols.iter().find(|s| s.name == name && s.is_function) {
+            let bias = map.start.wrapping_sub(elf.first_load_delta()?);
+            return Ok(Some(bias.wrapping_add(symbol.value)));
+        }
+    }
+    Ok(None)
+}
+
+/// Load the shared library at `path` into the stopped tracee `pid` by
+/// calling its `dlopen`, returning the address the library is loaded at.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn load_library(pid: Pid, path: &Path) -> io::Result<u64> {
+    let path = fs::canonicalize(path)?;
+    // glibc before 2.34 only has `dlopen` in libdl, which isn't always
+    // loaded, but libc has its own entry point into the loader.
+    let (dlopen, mode) = match find_function(pid, "dlopen")? {
+        Some(dlopen) => (dlopen, libc::RTLD_NOW),
+        None => match find_function(pid, "__libc_dlopen_mode")? {
+            Some(dlopen) => (dlopen, libc::RTLD_NOW | RTLD_DLOPEN),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The tracee has no dlopen",
+                ))
+            }
+        },
+    };
+    let mut name = path.as_os_str().as_bytes().to_vec();
+    name.push(0);
+    let mmap_args = [
+        0,
+        name.len() as u64,
+        (libc::PROT_READ | libc::PROT_WRITE) as u64,
+        (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64,
+        u64::MAX,
+        0,
+    ];
+    let buf = syscall(pid, libc::SYS_mmap as u64, mmap_args)?;
+    if (-4095..0).contains(&buf) {
+        return Err(io::Error::from_raw_os_error(-buf as i32));
+    }
+    let buf = buf as u64;
+    let handle =
+        memory::write(pid, buf, &name).and_then(|()| call(pid, dlopen, &[buf, mode as u64]));
+    syscall(
+        pid,
+        libc::SYS_munmap as u64,
+        [buf, name.len() as u64, 0, 0, 0, 0],
+    )?;
+    if handle? == 0 {
+        let message = match find_function(pid, "dlerror")? {
+            Some(dlerror) => read_string(pid, call(pid, dlerror, &[])?)?,
+            None => String::from("unknown error"),
+        };
+        return Err(io::Error::other(format!("dlopen failed: {}", message)));
+    }
+    maps::read_maps(pid)?
+        .into_iter()
+        .find(|m| m.offset == 0 && m.pathname.as_deref().map(Path::new) == Some(&path))
+        .map(|m| m.start)
+        .ok_or_else(|| io::Error::other("Loaded library is not mapped"))
+}
+
+/// Read the NUL-terminated string at `addr` in process `pid`, up to
+/// `MAX_ERROR_LEN` bytes of it.
+#[cfg(target_arch = "x86_64")]
+fn read_string(pid: Pid, addr: u64) -> io::Result<String> {
+    if addr == 0 {
+        return Ok(String::new());
+    }
+    let bytes = memory::read_cstring(pid, addr, MAX_ERROR_LEN)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::tests::spawn_running;
+
+    #[test]
+    fn test_load_library() {
+        // Load a library from libc's directory, which nothing links.
+        let libc = maps::read_maps(Pid::this())
+            .expect("Error reading maps")
+            .into_iter()
+            .filter_map(|m| m.pathname)
+            .find(|p| p.contains("/libc.so") || p.contains("/libc-"))
+            .expect("libc is not mapped");
+        let libm = Path::new(&libc).with_file_name("libm.so.6");
+
+        let mut tracee = spawn_running();
+        let pid = tracee.pid();
+        let regs = ptrace::getregs(pid).expect("Error reading registers");
+        let base = load_library(pid, &libm).expect("Error loading library");
+        let mut magic = [0; 4];
+        memory::read(pid, base, &mut magic).expect("Error reading library");
+        assert_eq!(&magic, b"\x7fELF");
+        let after = ptrace::getregs(pid).expect("Error reading registers");
+        assert_eq!((regs.rip, regs.rsp), (after.rip, after.rsp));
+        assert!(load_library(pid, Path::new("/no/such/library.so")).is_err());
+
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
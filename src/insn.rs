@@ -0,0 +1,630 @@
+//! Just enough x86-64 instruction decoding to find an instruction's length
+//! and opcode, and the memory it writes.
+
+use std::arch::x86_64::__cpuid_count;
+
+/// The longest an x86 instruction can be.
+pub(crate) const MAX_INSN_LEN: usize = 15;
+
+/// The most bytes an `XSAVE` family instruction can write: the size of the
+/// area for every state component that `CPUID` leaf `0xd` lists as
+/// supported, or enabled in `XCR0` and `IA32_XSS`, which includes the 8 KiB
+/// of AMX tile data on CPUs that have it. This is at least the 512 bytes
+/// `FXSAVE` writes.
+fn xsave_size() -> usize {
+    let standard = __cpuid_count(0xd, 0);
+    let compacted = __cpuid_count(0xd, 1);
+    let size = standard.ebx.max(standard.ecx).max(compacted.ebx);
+    (size as usize).max(512)
+}
+
+/// The general-purpose register with number `n`, as encoded in ModRM and
+/// SIB bytes.
+fn register(regs: &libc::user_regs_struct, n: u8) -> u64 {
+    match n {
+        0 => regs.rax,
+        1 => regs.rcx,
+        2 => regs.rdx,
+        3 => regs.rbx,
+        4 => regs.rsp,
+        5 => regs.rbp,
+        6 => regs.rsi,
+        7 => regs.rdi,
+        8 => regs.r8,
+        9 => regs.r9,
+        10 => regs.r10,
+        11 => regs.r11,
+        12 => regs.r12,
+        13 => regs.r13,
+        14 => regs.r14,
+        _ => regs.r15,
+    }
+}
+
+/// The opcode map an x86-64 instruction's opcode is from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OpcodeMap {
+    /// The one-byte opcodes.
+    Primary,
+    /// `0f xx`, and map 1 of `VEX` and `EVEX` instructions.
+    Secondary,
+    /// `0f 38 xx`, and map 2.
+    Escape38,
+    /// `0f 3a xx`, and map 3.
+    Escape3a,
+}
+
+/// What `decode` learned from an instruction's prefixes.
+#[derive(Debug, Default)]
+pub(crate) struct Prefixes {
+    pub(crate) operand16: bool,
+    pub(crate) address32: bool,
+    /// The `fs` or `gs` segment override, `0x64` or `0x65`.
+    pub(crate) segment: Option<u8>,
+    pub(crate) lock: bool,
+    /// The last `repne` or `rep` prefix, `0xf2` or `0xf3`.
+    pub(crate) rep: Option<u8>,
+    /// Whether there is a `REX` prefix, which makes byte registers 4 to 7
+    /// `spl` to `dil` rather than `ah` to `bh`.
+    pub(crate) rex: bool,
+    /// The `REX` bits that select 64-bit operands and extend the ModRM and
+    /// SIB fields, or the same bits of a `VEX` or `EVEX` prefix.
+    pub(crate) rex_w: bool,
+    pub(crate) rex_r: u8,
+    pub(crate) rex_x: u8,
+    pub(crate) rex_b: u8,
+    /// The vector length of a `VEX` or `EVEX` instruction, in bytes.
+    pub(crate) vector: Option<usize>,
+    /// The `pp` field of a `VEX` or `EVEX` prefix, which stands for no
+    /// prefix, `66`, `f3` or `f2`.
+    pub(crate) pp: u8,
+    /// The extra register operand of a `VEX` or `EVEX` instruction.
+    pub(crate) vvvv: u8,
+    /// For `EVEX`, the `pp` field and whether it broadcasts, which give the
+    /// scale of compressed displacements.
+    pub(crate) evex: Option<(u8, bool, bool)>,
+    /// For `EVEX`, the bit extending the ModRM `reg` field to 32 registers,
+    /// the opmask register, and whether masking zeroes.
+    pub(crate) rex_r2: u8,
+    pub(crate) mask: u8,
+    pub(crate) zeroing: bool,
+}
+
+/// The parts of a decoded instruction.
+#[derive(Debug)]
+pub(crate) struct Decoded {
+    pub(crate) prefixes: Prefixes,
+    pub(crate) map: OpcodeMap,
+    pub(crate) opcode: u8,
+    pub(crate) modrm: Option<u8>,
+    pub(crate) sib: Option<u8>,
+    /// The displacement, sign-extended but not scaled.
+    pub(crate) disp: i64,
+    /// Where the immediate operand starts, and its size.
+    pub(crate) imm: usize,
+    pub(crate) imm_size: usize,
+    pub(crate) len: usize,
+}
+
+impl Decoded {
+    /// The immediate operand, zero-extended.
+    pub(crate) fn immediate(&self, code: &[u8]) -> u64 {
+        let mut bytes = [0; 8];
+        bytes[..self.imm_size].copy_from_slice(&code[self.imm..self.imm + self.imm_size]);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// Decode the instruction at the start of `code`, or return `None` if `code`
+/// is too short to hold it or it isn't valid in 64-bit mode.
+pub(crate) fn decode(code: &[u8]) -> Option<Decoded> {
+    let byte = |i: usize| code.get(i).copied();
+    let mut prefixes = Prefixes::default();
+    let mut i = 0;
+    loop {
+        match byte(i)? {
+            0x66 => prefixes.operand16 = true,
+            0x67 => prefixes.address32 = true,
+            segment @ 0x64..=0x65 => prefixes.segment = Some(segment),
+            0xf0 => prefixes.lock = true,
+            rep @ 0xf2..=0xf3 => prefixes.rep = Some(rep),
+            // Segments that are ignored in 64-bit mode.
+            0x26 | 0x2e | 0x36 | 0x3e => {}
+            _ => break,
+        }
+        i += 1;
+    }
+    if let rex @ 0x40..=0x4f = byte(i)? {
+        prefixes.rex = true;
+        prefixes.rex_w = rex & 8 != 0;
+        prefixes.rex_r = (rex >> 2) & 1;
+        prefixes.rex_x = (rex >> 1) & 1;
+        prefixes.rex_b = rex & 1;
+        i += 1;
+    }
+    let (map, opcode) = match byte(i)? {
+        0xc4 | 0xc5 | 0x62 => {
+            let prefix = byte(i)?;
+            let p0 = byte(i + 1)?;
+            prefixes.rex_r = !p0 >> 7 & 1;
+            let (map, len) = match prefix {
+                0xc5 => {
+                    prefixes.vector = Some(if p0 & 4 != 0 { 32 } else { 16 });
+                    prefixes.pp = p0 & 3;
+                    prefixes.vvvv = !p0 >> 3 & 0xf;
+                    (1, 2)
+                }
+                0xc4 => {
+                    let p1 = byte(i + 2)?;
+                    prefixes.vector = Some(if p1 & 4 != 0 { 32 } else { 16 });
+                    prefixes.pp = p1 & 3;
+                    prefixes.vvvv = !p1 >> 3 & 0xf;
+                    prefixes.rex_w = p1 & 0x80 != 0;
+                    (p0 & 0x1f, 3)
+                }
+                _ => {
+                    let p1 = byte(i + 2)?;
+                    let p2 = byte(i + 3)?;
+                    prefixes.vector = Some(16 << ((p2 >> 5) & 3));
+                    prefixes.pp = p1 & 3;
+                    prefixes.vvvv = (!p1 >> 3 & 0xf) | (!p2 >> 3 & 1) << 4;
+                    prefixes.rex_w = p1 & 0x80 != 0;
+                    prefixes.rex_r2 = !p0 >> 4 & 1;
+                    prefixes.mask = p2 & 7;
+                    prefixes.zeroing = p2 & 0x80 != 0;
+                    prefixes.evex = Some((p1 & 3, p1 & 0x80 != 0, p2 & 0x10 != 0));
+                    (p0 & 7, 4)
+                }
+            };
+            if prefix != 0xc5 {
+                prefixes.rex_x = !p0 >> 6 & 1;
+                prefixes.rex_b = !p0 >> 5 & 1;
+            }
+            let map = match map {
+                1 => OpcodeMap::Secondary,
+                2 => OpcodeMap::Escape38,
+                3 => OpcodeMap::Escape3a,
+                _ => return None,
+            };
+            i += len;
+            (map, byte(i)?)
+        }
+        0x0f => match byte(i + 1)? {
+            0x38 => {
+                i += 2;
+                (OpcodeMap::Escape38, byte(i)?)
+            }
+            0x3a => {
+                i += 2;
+                (OpcodeMap::Escape3a, byte(i)?)
+            }
+            opcode => {
+                i += 1;
+                (OpcodeMap::Secondary, opcode)
+            }
+        },
+        opcode => (OpcodeMap::Primary, opcode),
+    };
+    i += 1;
+    if map == OpcodeMap::Primary && invalid(opcode) {
+        return None;
+    }
+
+    let mut modrm = None;
+    let mut sib = None;
+    let mut disp = 0;
+    if has_modrm(map, opcode, prefixes.vector.is_some()) {
+        let value = byte(i)?;
+        modrm = Some(value);
+        i += 1;
+        let mode = value >> 6;
+        let rm = value & 7;
+        if mode != 3 && rm == 4 {
+            sib = Some(byte(i)?);
+            i += 1;
+        }
+        let base = sib.map_or(rm, |sib| sib & 7);
+        match mode {
+            1 => {
+                disp = byte(i)? as i8 as i64;
+                i += 1;
+            }
+            2 => {
+                disp = disp32(code, i)?;
+                i += 4;
+            }
+            0 if base == 5 => {
+                disp = disp32(code, i)?;
+                i += 4;
+            }
+            _ => {}
+        }
+    }
+    let reg = modrm.map_or(0, |modrm| modrm >> 3 & 7);
+    let imm = i;
+    let imm_size = immediate_size(map, opcode, reg, &prefixes);
+    let len = imm + imm_size;
+    if len > MAX_INSN_LEN || len > code.len() {
+        return None;
+    }
+    Some(Decoded {
+        prefixes,
+        map,
+        opcode,
+        modrm,
+        sib,
+        disp,
+        imm,
+        imm_size,
+        len,
+    })
+}
+
+/// The memory ranges, as an address and a length, that the instruction at
+/// the start of `code` may write when executed with `regs`, or `None` if
+/// they can't be known.
+///
+/// The ranges may be larger than what is written, and may include memory
+/// that is only read, so restoring their old contents always undoes the
+/// instruction's writes. The kernel's writes in system calls can't be
+/// known, nor can those of scatter instructions, whose addresses are in
+/// vector registers, so these return `None`, as do instructions `code` is
+/// too short to hold.
+pub(crate) fn memory_writes(
+    code: &[u8],
+    regs: &libc::user_regs_struct,
+) -> Option<Vec<(u64, usize)>> {
+    let insn = decode(code)?;
+    let prefixes = &insn.prefixes;
+    let (map, opcode) = (insn.map, insn.opcode);
+    let segment = match prefixes.segment {
+        Some(0x64) => regs.fs_base,
+        Some(_) => regs.gs_base,
+        None => 0,
+    };
+    let mut writes = vec![];
+    let push = |writes: &mut Vec<(u64, usize)>, len: usize| {
+        writes.push((regs.rsp.wrapping_sub(len as u64), len));
+    };
+    let vsib = prefixes.vector.is_some() && map == OpcodeMap::Escape38;
+    match (map, opcode) {
+        // System calls.
+        (OpcodeMap::Primary, 0xcd)
+        | (OpcodeMap::Secondary, 0x05)
+        | (OpcodeMap::Secondary, 0x34) => return None,
+        (OpcodeMap::Escape38, 0xa0..=0xa3) if vsib => return None,
+        (OpcodeMap::Primary, 0x50..=0x57)
+        | (OpcodeMap::Primary, 0x68)
+        | (OpcodeMap::Primary, 0x6a)
+        | (OpcodeMap::Primary, 0x9c)
+        | (OpcodeMap::Primary, 0xe8)
+        | (OpcodeMap::Secondary, 0xa0)
+        | (OpcodeMap::Secondary, 0xa8) => push(&mut writes, 8),
+        // `enter`, which pushes `rbp` and a frame pointer for each level.
+        (OpcodeMap::Primary, 0xc8) => {
+            let level = code[insn.imm + 2] as usize & 31;
+            push(&mut writes, 8 * (level + 1));
+        }
+        // `movs` and `stos` write one element at `rdi` each step, even with
+        // a `rep` prefix.
+        (OpcodeMap::Primary, 0xa4)
+        | (OpcodeMap::Primary, 0xa5)
+        | (OpcodeMap::Primary, 0xaa)
+        | (OpcodeMap::Primary, 0xab) => {
+            writes.push((regs.rdi, 8));
+        }
+        // `mov` from `al` or `rax` to an absolute address.
+        (OpcodeMap::Primary, 0xa2) | (OpcodeMap::Primary, 0xa3) => {
+            writes.push((insn.immediate(code).wrapping_add(segment), 8));
+        }
+        // `maskmovq` and `maskmovdqu`.
+        (OpcodeMap::Secondary, 0xf7) => writes.push((regs.rdi, 16)),
+        _ => {}
+    }
+    let modrm = match insn.modrm {
+        Some(modrm) => modrm,
+        None => return Some(writes),
+    };
+    let mode = modrm >> 6;
+    let reg = (modrm >> 3) & 7;
+    let rm = modrm & 7;
+    if map == OpcodeMap::Primary && opcode == 0xff {
+        match reg {
+            2 => push(&mut writes, 8),
+            3 => push(&mut writes, 16),
+            6 => push(&mut writes, 8),
+            _ => {}
+        }
+    }
+    if mode == 3 {
+        return Some(writes);
+    }
+
+    let mut addr = 0u64;
+    if let Some(sib) = insn.sib {
+        let index = (sib >> 3) & 7 | prefixes.rex_x << 3;
+        let base = sib & 7;
+        if index != 4 {
+            addr = register(regs, index) << (sib >> 6);
+        }
+        if base != 5 || mode != 0 {
+            addr = addr.wrapping_add(register(regs, base | prefixes.rex_b << 3));
+        }
+    } else if rm == 5 && mode == 0 {
+        // Relative to the end of the instruction.
+        addr = regs.rip.wrapping_add(insn.len as u64);
+    } else {
+        addr = register(regs, rm | prefixes.rex_b << 3);
+    }
+    let scale = if mode == 1 {
+        evex_scale(prefixes, map, opcode)
+    } else {
+        1
+    };
+    addr = addr.wrapping_add((insn.disp * scale as i64) as u64);
+    if prefixes.address32 {
+        addr &= 0xffff_ffff;
+    }
+    addr = addr.wrapping_add(segment);
+
+    match (map, opcode) {
+        // `lea`, `nop` and the prefetches don't touch memory, and the
+        // addresses of gathers are in vector registers.
+        (OpcodeMap::Primary, 0x8d)
+        | (OpcodeMap::Secondary, 0x0d)
+        | (OpcodeMap::Secondary, 0x18..=0x1f) => return Some(writes),
+        (OpcodeMap::Escape38, 0x90..=0x93) | (OpcodeMap::Escape38, 0xc6..=0xc7) if vsib => {
+            return Some(writes)
+        }
+        _ => {}
+    }
+    let len = match (map, opcode, reg) {
+        _ if prefixes.vector.is_some() => prefixes.vector.unwrap_or(16),
+        // `fnstenv` and `fnsave`.
+        (OpcodeMap::Primary, 0xd9, 6) | (OpcodeMap::Primary, 0xdd, 6) => 128,
+        (OpcodeMap::Primary, _, _) => 16,
+        // `fxsave`, `xsave` and `xsaveopt`, and `xsavec` and `xsaves`.
+        (OpcodeMap::Secondary, 0xae, 0)
+        | (OpcodeMap::Secondary, 0xae, 4)
+        | (OpcodeMap::Secondary, 0xae, 6) => xsave_size(),
+        (OpcodeMap::Secondary, 0xc7, 4) | (OpcodeMap::Secondary, 0xc7, 5) => xsave_size(),
+        _ => 16,
+    };
+    writes.push((addr, len));
+    Some(writes)
+}
+
+/// Whether the one-byte `opcode` is invalid in 64-bit mode.
+fn invalid(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        0x06 | 0x07
+            | 0x0e
+            | 0x16
+            | 0x17
+            | 0x1e
+            | 0x1f
+            | 0x27
+            | 0x2f
+            | 0x37
+            | 0x3f
+            | 0x60
+            | 0x61
+            | 0x82
+            | 0x9a
+            | 0xce
+            | 0xd4..=0xd6 | 0xea
+    )
+}
+
+/// Whether `opcode` from `map` is followed by a ModRM byte.
+fn has_modrm(map: OpcodeMap, opcode: u8, vex: bool) -> bool {
+    match map {
+        OpcodeMap::Primary => {
+            matches!(
+                opcode,
+                0x00..=0x3f if opcode & 7 < 4
+            ) || matches!(
+                opcode,
+                0x63 | 0x69 | 0x6b | 0x80..=0x8f | 0xc0 | 0xc1 | 0xc6 | 0xc7 | 0xd0..=0xd3 | 0xd8..=0xdf | 0xf6 | 0xf7 | 0xfe | 0xff
+            )
+        }
+        // `vzeroupper` and `vzeroall`.
+        OpcodeMap::Secondary if vex => opcode != 0x77,
+        OpcodeMap::Secondary => !matches!(
+            opcode,
+            0x04..=0x0c | 0x0e | 0x30..=0x3f | 0x77 | 0x80..=0x8f | 0xa0..=0xa2 | 0xa8..=0xaa | 0xc8..=0xcf
+        ),
+        OpcodeMap::Escape38 | OpcodeMap::Escape3a => true,
+    }
+}
+
+/// The size of the immediate operand at the end of an instruction, where
+/// `reg` is the `reg` field of its ModRM byte.
+fn immediate_size(map: OpcodeMap, opcode: u8, reg: u8, prefixes: &Prefixes) -> usize {
+    let full = if prefixes.operand16 { 2 } else { 4 };
+    match (map, opcode) {
+        // The `al` and `eax` forms of the arithmetic instructions.
+        (OpcodeMap::Primary, 0x00..=0x3f) if opcode & 7 == 4 => 1,
+        (OpcodeMap::Primary, 0x00..=0x3f) if opcode & 7 == 5 => full,
+        (OpcodeMap::Primary, 0x68)
+        | (OpcodeMap::Primary, 0x69)
+        | (OpcodeMap::Primary, 0x81)
+        | (OpcodeMap::Primary, 0xa9)
+        | (OpcodeMap::Primary, 0xc7) => full,
+        (OpcodeMap::Primary, 0xf7) if reg < 2 => full,
+        (OpcodeMap::Primary, 0xb8..=0xbf) if prefixes.rex_w => 8,
+        (OpcodeMap::Primary, 0xb8..=0xbf) => full,
+        // The absolute address of a `mov` to or from `al` or `rax`.
+        (OpcodeMap::Primary, 0xa0..=0xa3) if prefixes.address32 => 4,
+        (OpcodeMap::Primary, 0xa0..=0xa3) => 8,
+        // Relative calls and jumps, whose offset stays 32 bits with an
+        // operand size prefix on Intel CPUs.
+        (OpcodeMap::Primary, 0xe8)
+        | (OpcodeMap::Primary, 0xe9)
+        | (OpcodeMap::Secondary, 0x80..=0x8f) => 4,
+        (OpcodeMap::Primary, 0xc2) | (OpcodeMap::Primary, 0xca) => 2,
+        // `enter`, with a 16-bit frame size and an 8-bit level.
+        (OpcodeMap::Primary, 0xc8) => 3,
+        (OpcodeMap::Primary, 0x6a)
+        | (OpcodeMap::Primary, 0x6b)
+        | (OpcodeMap::Primary, 0x70..=0x7f)
+        | (OpcodeMap::Primary, 0x80)
+        | (OpcodeMap::Primary, 0x83)
+        | (OpcodeMap::Primary, 0xa8)
+        | (OpcodeMap::Primary, 0xb0..=0xb7)
+        | (OpcodeMap::Primary, 0xc0)
+        | (OpcodeMap::Primary, 0xc1)
+        | (OpcodeMap::Primary, 0xc6)
+        | (OpcodeMap::Primary, 0xcd)
+        | (OpcodeMap::Primary, 0xe0..=0xe7)
+        | (OpcodeMap::Primary, 0xeb) => 1,
+        (OpcodeMap::Primary, 0xf6) if reg < 2 => 1,
+        // 3DNow!, whose opcode is in the immediate.
+        (OpcodeMap::Secondary, 0x0f)
+        | (OpcodeMap::Secondary, 0x70..=0x73)
+        | (OpcodeMap::Secondary, 0xa4)
+        | (OpcodeMap::Secondary, 0xac) => 1,
+        (OpcodeMap::Secondary, 0xba)
+        | (OpcodeMap::Secondary, 0xc2)
+        | (OpcodeMap::Secondary, 0xc4..=0xc6) => 1,
+        (OpcodeMap::Escape3a, _) => 1,
+        _ => 0,
+    }
+}
+
+/// The factor an `EVEX` instruction's 8-bit displacement is scaled by,
+/// which is 1 for other instructions.
+///
+/// This is the vector length for full-vector operands, like the moves
+/// `memcpy` and `memset` use, the element size for broadcasts and scalar
+/// moves, and is wrong for the rarer tuple types.
+pub(crate) fn evex_scale(prefixes: &Prefixes, map: OpcodeMap, opcode: u8) -> usize {
+    let (pp, w, broadcast) = match prefixes.evex {
+        Some(evex) => evex,
+        None => return 1,
+    };
+    let element = if w { 8 } else { 4 };
+    match (map, opcode, pp) {
+        // `vmovss` and `vmovsd`.
+        (OpcodeMap::Secondary, 0x10..=0x11, 2) => 4,
+        (OpcodeMap::Secondary, 0x10..=0x11, 3) => 8,
+        _ if broadcast => element,
+        _ => prefixes.vector.unwrap_or(16),
+    }
+}
+
+/// The sign-extended 32-bit displacement at `i`.
+fn disp32(code: &[u8], i: usize) -> Option<i64> {
+    let bytes = code.get(i..i + 4)?;
+    Some(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::process::Command;
+
+    fn regs() -> libc::user_regs_struct {
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        regs.rax = 0x1000;
+        regs.rcx = 3;
+        regs.rbp = 0x2000;
+        regs.rsp = 0x3000;
+        regs.rdi = 0x4000;
+        regs.r9 = 2;
+        regs.r12 = 0x5000;
+        regs.rip = 0x400000;
+        regs.fs_base = 0x7000;
+        regs
+    }
+
+    #[test]
+    fn test_memory_writes() {
+        let regs = regs();
+        let writes = |code: &[u8]| memory_writes(code, &regs);
+        // mov %rax, 8(%rsp)
+        assert_eq!(
+            writes(&[0x48, 0x89, 0x44, 0x24, 0x08]),
+            Some(vec![(0x3008, 16)])
+        );
+        // movl $1, 0x10(%rip), where the instruction is 10 bytes long.
+        assert_eq!(
+            writes(&[0xc7, 0x05, 0x10, 0, 0, 0, 1, 0, 0, 0]),
+            Some(vec![(0x40001a, 16)])
+        );
+        // call .+5
+        assert_eq!(writes(&[0xe8, 0, 0, 0, 0]), Some(vec![(0x2ff8, 8)]));
+        // push %rbx
+        assert_eq!(writes(&[0x53]), Some(vec![(0x2ff8, 8)]));
+        // pushq 8(%rax)
+        assert_eq!(
+            writes(&[0xff, 0x70, 0x08]),
+            Some(vec![(0x2ff8, 8), (0x1008, 16)])
+        );
+        assert_eq!(writes(&[0x0f, 0x05]), None);
+        // lea (%rsp), %rax
+        assert_eq!(writes(&[0x48, 0x8d, 0x04, 0x24]), Some(vec![]));
+        // vmovdqa %ymm0, (%rdi)
+        assert_eq!(writes(&[0xc5, 0xfd, 0x7f, 0x07]), Some(vec![(0x4000, 32)]));
+        // mov %rax, %fs:0x28
+        assert_eq!(
+            writes(&[0x64, 0x48, 0x89, 0x04, 0x25, 0x28, 0, 0, 0]),
+            Some(vec![(0x7028, 16)])
+        );
+        // vmovdqu64 %zmm16, 0x40(%rdi)
+        assert_eq!(
+            writes(&[0x62, 0xe1, 0xfe, 0x48, 0x7f, 0x47, 0x01]),
+            Some(vec![(0x4040, 64)])
+        );
+        // vmovss %xmm17, 8(%rax)
+        assert_eq!(
+            writes(&[0x62, 0xe1, 0x7e, 0x08, 0x11, 0x48, 0x02]),
+            Some(vec![(0x1008, 16)])
+        );
+        // rep stos %rax, %es:(%rdi)
+        assert_eq!(writes(&[0xf3, 0x48, 0xab]), Some(vec![(0x4000, 8)]));
+        // addl $5, -4(%rbp,%rcx,4)
+        assert_eq!(
+            writes(&[0x83, 0x44, 0x8d, 0xfc, 0x05]),
+            Some(vec![(0x2008, 16)])
+        );
+        // movb $7, (%r12)
+        assert_eq!(
+            writes(&[0x41, 0xc6, 0x04, 0x24, 0x07]),
+            Some(vec![(0x5000, 16)])
+        );
+        // xsavec 0x40(%rsp)
+        assert_eq!(
+            writes(&[0x0f, 0xc7, 0x64, 0x24, 0x40]),
+            Some(vec![(0x3040, xsave_size())])
+        );
+        // The area is at least as large as the one the kernel gives for a
+        // tracee, with the AMX state if the CPU has it.
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let xstate = tracee.xstate().expect("Error reading xstate");
+        assert!(xsave_size() >= xstate.as_bytes().len());
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+        // mov %eax, 0x100(,%r9,8)
+        assert_eq!(
+            writes(&[0x42, 0x89, 0x04, 0xcd, 0x00, 0x01, 0, 0]),
+            Some(vec![(0x110, 16)])
+        );
+        // movabs %rax, 0x5000
+        assert_eq!(
+            writes(&[0x48, 0xa3, 0, 0x50, 0, 0, 0, 0, 0, 0]),
+            Some(vec![(0x5000, 8)])
+        );
+        // mov %rax, %rbx touches no memory, and a cut off instruction can't
+        // be decoded.
+        assert_eq!(writes(&[0x48, 0x89, 0xc3]), Some(vec![]));
+        assert_eq!(writes(&[0x48, 0x89]), None);
+    }
+}
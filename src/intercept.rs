@@ -0,0 +1,149 @@
+//! Picking the cheapest way to intercept a set of system calls.
+
+use crate::seccomp::{self, SeccompAction, SeccompFilter};
+
+/// A mechanism for intercepting system calls, chosen by
+/// [`SyscallInterest::select`].
+///
+/// [`SyscallInterest::select`]: struct.SyscallInterest.html#method.select
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interception {
+    /// Resume the tracee with `PTRACE_SYSCALL`, stopping it at the entry and
+    /// exit of every system call. This works everywhere, but is the slowest.
+    PtraceSyscall,
+    /// Install a seccomp filter that stops the tracee with
+    /// `PTRACE_EVENT_SECCOMP` before the selected system calls only, with
+    /// [`SpawnOptions::seccomp_filter`], and resume it with `PTRACE_CONT`.
+    ///
+    /// [`SpawnOptions::seccomp_filter`]: struct.SpawnOptions.html#method.seccomp_filter
+    SeccompTrace,
+    /// Install a seccomp filter that sends the selected system calls to a
+    /// [`SeccompNotifier`], with [`spawn_with_notifier`]. There are no ptrace
+    /// stops at all, but only the arguments can be inspected, and the only
+    /// change that can be made is to the result.
+    ///
+    /// [`SeccompNotifier`]: struct.SeccompNotifier.html
+    /// [`spawn_with_notifier`]: trait.CommandPtraceSpawn.html#tymethod.spawn_with_notifier
+    UserNotif,
+}
+
+/// The system calls a tracer wants to intercept, and what it needs to do
+/// with them, used to pick an [`Interception`] mechanism.
+///
+/// ```
+/// use spawn_ptrace::{Interception, SyscallInterest};
+///
+/// let mut interest = SyscallInterest::new();
+/// interest.syscall(libc::SYS_openat).rewrite_args();
+/// assert_ne!(interest.select(), Interception::UserNotif);
+/// ```
+///
+/// [`Interception`]: enum.Interception.html
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyscallInterest {
+    syscalls: Vec<i64>,
+    registers: bool,
+    rewrite_args: bool,
+}
+
+impl SyscallInterest {
+    /// Create an interest in every system call, needing only the arguments.
+    pub fn new() -> SyscallInterest {
+        SyscallInterest::default()
+    }
+
+    /// Only intercept system call `number`, such as `libc::SYS_openat`,
+    /// along with any others added this way.
+    pub fn syscall(&mut self, number: i64) -> &mut SyscallInterest {
+        if !self.syscalls.contains(&number) {
+            self.syscalls.push(number);
+        }
+        self
+    }
+
+    /// The tracer needs to read the tracee's registers, beyond the system
+    /// call arguments.
+    pub fn registers(&mut self) -> &mut SyscallInterest {
+        self.registers = true;
+        self
+    }
+
+    /// The tracer needs to change system call arguments, or the system call
+    /// number, before the system call runs.
+    pub fn rewrite_args(&mut self) -> &mut SyscallInterest {
+        self.rewrite_args = true;
+        self
+    }
+
+    /// Pick the cheapest mechanism the running kernel supports that can do
+    /// everything this interest needs.
+    ///
+    /// Seccomp filters only pay off for a subset of system calls, so an
+    /// interest in every system call always uses `PtraceSyscall`. Otherwise
+    /// `UserNotif` is preferred when nothing but the arguments are needed,
+    /// then `SeccompTrace`.
+    pub fn select(&self) -> Interception {
+        if self.syscalls.is_empty() {
+            return Interception::PtraceSyscall;
+        }
+        let needs_ptrace = self.registers || self.rewrite_args;
+        if !needs_ptrace && seccomp::action_available(SeccompAction::Notify) {
+            Interception::UserNotif
+        } else if seccomp::action_available(SeccompAction::Trace(0)) {
+            Interception::SeccompTrace
+        } else {
+            Interception::PtraceSyscall
+        }
+    }
+
+    /// The seccomp filter to install for `interception`, which allows every
+    /// system call this interest is not in.
+    ///
+    /// Returns `None` for `PtraceSyscall`, or if this is an interest in
+    /// every system call.
+    pub fn filter(&self, interception: Interception) -> Option<SeccompFilter> {
+        let action = match interception {
+            Interception::PtraceSyscall => return None,
+            Interception::SeccompTrace => SeccompAction::Trace(0),
+            Interception::UserNotif => SeccompAction::Notify,
+        };
+        if self.syscalls.is_empty() {
+            return None;
+        }
+        let mut filter = SeccompFilter::new(SeccompAction::Allow);
+        for &number in &self.syscalls {
+            filter.rule(number, action);
+        }
+        Some(filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let mut interest = SyscallInterest::new();
+        assert_eq!(interest.select(), Interception::PtraceSyscall);
+        assert_eq!(interest.filter(Interception::SeccompTrace), None);
+
+        interest.syscall(libc::SYS_openat);
+        let notify = seccomp::action_available(SeccompAction::Notify);
+        let trace = seccomp::action_available(SeccompAction::Trace(0));
+        let expected = match (notify, trace) {
+            (true, _) => Interception::UserNotif,
+            (false, true) => Interception::SeccompTrace,
+            (false, false) => Interception::PtraceSyscall,
+        };
+        assert_eq!(interest.select(), expected);
+
+        interest.registers();
+        assert_ne!(interest.select(), Interception::UserNotif);
+
+        let mut expected = SeccompFilter::new(SeccompAction::Allow);
+        expected.rule(libc::SYS_openat, SeccompAction::Notify);
+        assert_eq!(interest.filter(Interception::UserNotif), Some(expected));
+        assert_eq!(interest.filter(Interception::PtraceSyscall), None);
+    }
+}
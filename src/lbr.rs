@@ -0,0 +1,171 @@
+//! Last Branch Record capture for a tracee, using perf branch stack samples.
+
+use crate::perf::{
+    self, PerfEventAttr, RingBuffer, ATTR_EXCLUDE_HV, ATTR_EXCLUDE_KERNEL, ATTR_WRITE_BACKWARD,
+    PERF_TYPE_HARDWARE,
+};
+use crate::Error;
+use nix::unistd::Pid;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+
+/// `PERF_COUNT_HW_BRANCH_INSTRUCTIONS`.
+const BRANCH_INSTRUCTIONS: u64 = 4;
+const PERF_SAMPLE_BRANCH_STACK: u64 = 1 << 11;
+const PERF_SAMPLE_BRANCH_USER: u64 = 1 << 0;
+const PERF_SAMPLE_BRANCH_ANY: u64 = 1 << 3;
+const PERF_RECORD_SAMPLE: u32 = 9;
+/// Enough for a few samples of the deepest branch stacks, of 32 entries.
+const RING_PAGES: usize = 4;
+
+/// A branch the tracee took, from a [`LastBranches`] sample.
+///
+/// [`LastBranches`]: struct.LastBranches.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Branch {
+    /// The address of the branch instruction.
+    pub from: u64,
+    /// Where it went.
+    pub to: u64,
+    /// Whether the CPU mispredicted it.
+    pub mispredicted: bool,
+}
+
+/// The CPU's Last Branch Record stack for one tracee thread, which holds
+/// the last 8 to 32 branches it took, depending on the CPU.
+///
+/// The stack can't be read directly from user space, so perf samples it
+/// every `period` branches. [`read`] returns the newest sample, which at a
+/// crash stop ends with the branches that led to the crash, however
+/// corrupted the stack is. With a `period` of 1 every branch is sampled,
+/// so the sample is right up to date, but the tracee runs much slower, and
+/// the kernel throttles events that interrupt too often, which can leave
+/// the newest sample behind. Larger periods leave out up to `period - 1`
+/// of the latest branches.
+///
+/// Only user-space branches are recorded. The LBR is an Intel feature,
+/// which newer AMD CPUs emulate, and most virtual machines don't expose.
+///
+/// [`read`]: #method.read
+#[derive(Debug)]
+pub struct LastBranches {
+    // The buffer must be dropped before the event is closed.
+    ring: RingBuffer,
+    _event: File,
+}
+
+impl LastBranches {
+    /// Start sampling the branch stack of thread `tid` every `period`
+    /// branches it takes.
+    ///
+    /// Fails with `Error::Unsupported` if the CPU has no LBR, or it isn't
+    /// exposed.
+    pub fn open(tid: Pid, period: u64) -> io::Result<LastBranches> {
+        let mut attr = PerfEventAttr::new(PERF_TYPE_HARDWARE, BRANCH_INSTRUCTIONS);
+        attr.sample_period = period.max(1);
+        attr.sample_type = PERF_SAMPLE_BRANCH_STACK;
+        attr.branch_sample_type = PERF_SAMPLE_BRANCH_USER | PERF_SAMPLE_BRANCH_ANY;
+        attr.flags = ATTR_EXCLUDE_KERNEL | ATTR_EXCLUDE_HV | ATTR_WRITE_BACKWARD;
+        let event = perf::perf_event_open(&attr, tid).map_err(|e| match e.raw_os_error() {
+            Some(libc::ENOENT) | Some(libc::EOPNOTSUPP) | Some(libc::ENODEV) => {
+                Error::Unsupported("the Last Branch Record").into()
+            }
+            _ => e,
+        })?;
+        let ring = RingBuffer::overwritable(&event, RING_PAGES)?;
+        Ok(LastBranches {
+            ring,
+            _event: event,
+        })
+    }
+
+    /// The branches of the newest sample, newest first, or nothing if the
+    /// thread hasn't been sampled yet.
+    pub fn read(&self) -> Vec<Branch> {
+        // Other records, such as those for throttling, come between samples.
+        self.ring
+            .newest(16)
+            .into_iter()
+            .find(|(kind, _)| *kind == PERF_RECORD_SAMPLE)
+            .map_or_else(Vec::new, |(_, body)| parse_branch_stack(&body))
+    }
+}
+
+/// Parse the body of a sample of just `PERF_SAMPLE_BRANCH_STACK`, which is
+/// the number of branches followed by a `struct perf_branch_entry` for each.
+fn parse_branch_stack(body: &[u8]) -> Vec<Branch> {
+    let word = |i: usize| {
+        body.get(i * 8..i * 8 + 8)
+            .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+    };
+    let count = word(0).unwrap_or(0) as usize;
+    (0..count)
+        .map_while(|i| {
+            Some(Branch {
+                from: word(1 + i * 3)?,
+                to: word(2 + i * 3)?,
+                mispredicted: word(3 + i * 3)? & 1 != 0,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use nix::sys::wait::WaitStatus;
+    use std::process::Command;
+
+    #[test]
+    fn test_parse_branch_stack() {
+        let mut body = vec![];
+        for word in &[2u64, 0x1000, 0x2000, 0, 0x3000, 0x4000, 1] {
+            body.extend_from_slice(&word.to_ne_bytes());
+        }
+        let branches = parse_branch_stack(&body);
+        assert_eq!(
+            branches,
+            [
+                Branch {
+                    from: 0x1000,
+                    to: 0x2000,
+                    mispredicted: false,
+                },
+                Branch {
+                    from: 0x3000,
+                    to: 0x4000,
+                    mispredicted: true,
+                },
+            ]
+        );
+        // A truncated entry is left out.
+        assert_eq!(parse_branch_stack(&body[..40]).len(), 1);
+        assert!(parse_branch_stack(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_last_branches() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .args(["spin", "10"])
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let lbr = match LastBranches::open(tracee.pid(), 1000) {
+            Ok(lbr) => lbr,
+            Err(e) => {
+                assert!(matches!(Error::from_io(&e), Some(Error::Unsupported(_))));
+                let child = tracee.child_mut().unwrap();
+                child.kill().expect("Error killing child");
+                child.wait().expect("Error waiting for child");
+                return;
+            }
+        };
+        assert!(lbr.read().is_empty());
+        let output = tracee.wait_with_output().expect("Error waiting for tracee");
+        assert!(matches!(output.status, WaitStatus::Exited(_, 0)));
+        assert!(!lbr.read().is_empty());
+    }
+}
@@ -21,21 +21,238 @@
 //! # }
 //! ```
 //!
+//! If you need to run your own code in the child before it `exec`s, use
+//! [`SpawnOptions`] with [`spawn_ptrace_with_options`] to control whether it
+//! runs before or after the child opts in to being traced.
+//!
+//! To trace several processes at once, including children the tracee forks,
+//! use a [`TraceSession`].
+//!
 //! [`ptrace`]: https://man7.org/linux/man-pages/man2/ptrace.2.html
 //! [`CommandPtraceSpawn`]: trait.CommandPtraceSpawn.html
 //! [`spawn_ptrace`]: trait.CommandPtraceSpawn.html#tymethod.spawn_ptrace
+//! [`SpawnOptions`]: struct.SpawnOptions.html
+//! [`spawn_ptrace_with_options`]: trait.CommandPtraceSpawn.html#tymethod.spawn_ptrace_with_options
+//! [`TraceSession`]: struct.TraceSession.html
 #![cfg(unix)]
 
 #[cfg(doctest)]
 doc_comment::doctest!("../README.md");
 
+#[cfg(target_arch = "x86_64")]
+mod abi;
+mod accounting;
+mod addrspace;
+#[cfg(target_arch = "x86_64")]
+mod afl;
+mod antidebug;
+mod attach;
+mod backend;
+mod breakpoint;
+mod capabilities;
+mod cgroup;
+mod channel;
+mod cleanup;
+#[cfg(all(feature = "anti-anti-debug", target_arch = "x86_64"))]
+mod cloak;
+#[cfg(target_arch = "x86_64")]
+mod coverage;
+mod cwd;
+#[cfg(all(feature = "symbolication", target_arch = "x86_64"))]
+mod debuginfo;
+#[cfg(all(feature = "disassembly", target_arch = "x86_64"))]
+mod disasm;
+#[cfg(feature = "symbolication")]
+mod dwarf;
+mod elf;
+mod error;
+mod event;
+mod expect;
+mod fallback;
+mod fdtable;
+mod filter;
+mod forkserver;
+mod forward;
+mod heap;
+#[cfg(target_arch = "x86_64")]
+mod history;
+#[cfg(feature = "hooks")]
+mod hook;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod hwbreakpoint;
+#[cfg(all(feature = "image-export", target_arch = "x86_64"))]
+mod image;
+mod inject;
+#[cfg(target_arch = "x86_64")]
+mod insn;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod intercept;
+#[cfg(target_arch = "x86_64")]
+mod lbr;
+#[cfg(target_arch = "x86_64")]
+mod ltrace;
+mod maps;
+mod memcache;
+mod memexec;
+mod memory;
+mod monitor;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod notify;
+mod options;
+mod outcome;
+mod output;
+mod perf;
+mod permission;
+mod pidfd;
+mod pod;
+mod pool;
+mod profile;
+#[cfg(all(feature = "intel-pt", target_arch = "x86_64"))]
+mod pt;
+mod scheduler;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod seccomp;
+mod session;
+mod sigchld;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod sighandler;
+mod spawn;
+mod stats;
+mod syscall;
+mod syscall_table;
+pub mod testing;
+#[cfg(target_arch = "x86_64")]
+mod thread_area;
+mod timeline;
+mod tracee;
+mod tree;
+mod unwind;
+mod uprobe;
+mod vdso;
+mod waitid;
+#[cfg(target_arch = "x86_64")]
+mod xstate;
+mod yama;
+
+#[cfg(target_arch = "x86_64")]
+pub use crate::abi::Abi;
+pub use crate::accounting::{FdStats, IoAccounting, IoDirection, IoEvent};
+pub use crate::addrspace::AddressSpace;
+#[cfg(target_arch = "x86_64")]
+pub use crate::afl::{AflMap, AFL_SHM_ENV};
+pub use crate::antidebug::AntiDebugProbe;
+pub use crate::attach::ChildAttachExt;
+#[cfg(feature = "backend")]
+pub use crate::backend::{Backend, NixBackend};
+#[cfg(target_arch = "x86_64")]
+pub use crate::breakpoint::Breakpoints;
+pub use crate::capabilities::{capabilities, Capabilities};
+pub use crate::cgroup::Cgroup;
+pub use crate::channel::{ChannelEvent, EventChannel, Resume};
+pub use crate::cleanup::{install_panic_hook, DropPolicy};
+#[cfg(all(feature = "anti-anti-debug", target_arch = "x86_64"))]
+pub use crate::cloak::DebuggerCloak;
+#[cfg(target_arch = "x86_64")]
+pub use crate::coverage::{Coverage, ModuleCoverage, EDGE_MAP_SIZE};
+pub use crate::cwd::WorkingDirs;
+#[cfg(all(feature = "symbolication", target_arch = "x86_64"))]
+pub use crate::debuginfo::{DebugInfo, Variable};
+#[cfg(all(feature = "disassembly", target_arch = "x86_64"))]
+pub use crate::disasm::{Instruction, MemoryOperand, Operand};
+#[cfg(feature = "symbolication")]
+pub use crate::dwarf::{LineTable, SourceLine};
+pub use crate::error::Error;
+pub use crate::event::Event;
+pub use crate::expect::Expect;
+pub use crate::fallback::{fallbacks, Fallbacks};
+pub use crate::fdtable::{FdEntry, FdTable};
+pub use crate::filter::EventFilter;
+pub use crate::forkserver::ForkOutcome;
+#[cfg(target_arch = "x86_64")]
+pub use crate::forkserver::{Checkpoint, ForkChild, ForkServer};
+pub use crate::forward::{ForwardTarget, SignalForwarder};
+#[cfg(target_arch = "x86_64")]
+pub use crate::heap::HeapTracer;
+pub use crate::heap::{Allocation, LeakSite, LeakSummary};
+#[cfg(target_arch = "x86_64")]
+pub use crate::history::StepHistory;
+#[cfg(feature = "hooks")]
+pub use crate::hook::Activity;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub use crate::hwbreakpoint::{HwBreakpoint, HwBreakpoints, HwTrigger};
+#[cfg(all(feature = "image-export", target_arch = "x86_64"))]
+pub use crate::image::{CriuPages, ImageSink};
+#[cfg(target_arch = "x86_64")]
+pub use crate::inject::RemoteAllocation;
+#[cfg(all(feature = "disassembly", target_arch = "x86_64"))]
+pub use crate::insn::OpcodeMap;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub use crate::intercept::{Interception, SyscallInterest};
+#[cfg(target_arch = "x86_64")]
+pub use crate::lbr::{Branch, LastBranches};
+#[cfg(target_arch = "x86_64")]
+pub use crate::ltrace::{CallArg, LibraryCall, LibraryTracer};
+pub use crate::maps::MemoryMap;
+pub use crate::memcache::MemoryCache;
+pub use crate::memexec::MemoryExecutable;
+pub use crate::memory::MemoryStrategy;
+pub use crate::monitor::{Alert, AlertKind, SecurityMonitor};
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub use crate::notify::{Notification, NotifyResponse, SeccompNotifier};
+pub use crate::options::{InitialStop, SchedPolicy, SpawnOptions};
+pub use crate::outcome::Outcome;
+pub use crate::output::{OutputCapture, TracedOutput};
+pub use crate::perf::{PerfCounter, PerfCounters, PerfValue};
+pub use crate::permission::{
+    can_spawn_traced, can_trace, ExecCredentials, TraceCheck, TraceDenial,
+};
+pub use crate::pidfd::PidFd;
+pub use crate::pod::Pod;
+pub use crate::pool::{PoolTracee, TracePool};
+#[cfg(target_arch = "x86_64")]
+pub use crate::profile::SamplingProfiler;
+pub use crate::profile::{SyscallProfiler, SyscallReport, SyscallStats};
+#[cfg(all(feature = "intel-pt", target_arch = "x86_64"))]
+pub use crate::pt::IntelPt;
+pub use crate::scheduler::SerialScheduler;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub use crate::seccomp::{SeccompAction, SeccompFilter};
+pub use crate::session::TraceSession;
+pub use crate::sigchld::SigchldFd;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub use crate::sighandler::{HandlerEvent, HandlerEventKind, SignalHandler, SignalHandlers};
+pub use crate::spawn::spawn_raw;
+pub use crate::stats::Stats;
+pub use crate::syscall::SyscallInfo;
+pub use crate::syscall_table::{syscall_name, syscall_number, SyscallTable};
+#[cfg(target_arch = "x86_64")]
+pub use crate::thread_area::ThreadArea;
+pub use crate::timeline::{Span, SpanKind, SpanSink, Timeline};
+pub use crate::tracee::Tracee;
+pub use crate::tree::{ExecRecord, Exit, Origin, ProcessTree, TaskNode};
+pub use crate::uprobe::{UprobeHit, Uprobes};
+pub use crate::vdso::{Vdso, VdsoSymbol};
+pub use crate::waitid::{WaitCode, WaitInfo};
+#[cfg(target_arch = "x86_64")]
+pub use crate::xstate::XState;
+pub use crate::yama::{ptrace_scope, set_ptracer, Ptracer};
+
+use crate::options::Handshake;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
 use nix::sys::ptrace;
 use nix::sys::signal::Signal;
 use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::Pid;
-use std::io::{self, Result};
+use nix::unistd::{self, Pid};
+use std::fs::File;
+use std::io::{self, Read, Result, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
 use std::os::unix::process::CommandExt;
-use std::process::{Child, Command};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 
 /// A Unix-specific extension to `std::process::Command` to spawn a process with `ptrace` enabled.
 ///
@@ -47,28 +264,425 @@ pub trait CommandPtraceSpawn {
     /// to execute the specified command. You can continue it with
     /// `PTRACE_CONT`.
     fn spawn_ptrace(&mut self) -> Result<Child>;
+
+    /// Executes the command as a child process with ptrace enabled, running
+    /// the hooks in `options` around the `PTRACE_TRACEME` call.
+    ///
+    /// The child process is stopped in the same state as with
+    /// [`spawn_ptrace`](#tymethod.spawn_ptrace).
+    fn spawn_ptrace_with_options(&mut self, options: SpawnOptions) -> Result<Child>;
+
+    /// Executes the command as a child process with ptrace enabled, returning
+    /// a [`Tracee`] that also records the status of the initial stop.
+    ///
+    /// The child process is stopped in the same state as with
+    /// [`spawn_ptrace`](#tymethod.spawn_ptrace).
+    ///
+    /// [`Tracee`]: struct.Tracee.html
+    fn spawn_tracee(&mut self, options: SpawnOptions) -> Result<Tracee>;
+
+    /// Executes the command as a child process with ptrace enabled, without
+    /// waiting for it to stop.
+    ///
+    /// The child process will still stop with a `SIGTRAP` when it calls
+    /// `exec`, but it is up to the caller to observe that stop with `waitpid`
+    /// before issuing any other ptrace requests. This is useful if you run
+    /// your own wait loop and don't want this crate to consume the initial
+    /// stop notification.
+    fn spawn_ptrace_nowait(&mut self) -> Result<Child>;
+
+    /// Executes the command as a child process with ptrace enabled, hands
+    /// the stopped [`Tracee`] to `f`, and then runs it to completion.
+    ///
+    /// This suits setting a few things up, such as breakpoints or memory
+    /// patches, before letting the child run. Once `f` returns `Ok`, the
+    /// child is resumed and waited for, passing on any signals it receives,
+    /// and the closure's result is returned along with the child's final
+    /// status. If `f` fails or panics, the child is killed and reaped
+    /// instead, so it is never left stopped.
+    ///
+    /// `f` may resume the child itself, but must leave it stopped when it
+    /// returns.
+    ///
+    /// [`Tracee`]: struct.Tracee.html
+    fn spawn_ptrace_with<F, T>(&mut self, f: F) -> Result<(T, WaitStatus)>
+    where
+        F: FnOnce(&mut Tracee) -> Result<T>,
+        Self: Sized;
+
+    /// Executes the command as a child process that is *not* traced, with
+    /// `filter` installed using a seccomp user-notification listener.
+    ///
+    /// System calls the filter handles with `SeccompAction::Notify` block
+    /// until they are answered through the returned [`SeccompNotifier`],
+    /// which is much cheaper than ptrace syscall stops, and leaves the child
+    /// free to be traced by something else. All of `options` apply except
+    /// the ptrace-specific ones, and `filter` is installed after any filter
+    /// set with [`SpawnOptions::seccomp_filter`].
+    ///
+    /// Nothing can answer notifications until this returns, which happens
+    /// once the child has called `exec`, so `filter` must not notify
+    /// `sendmsg`, `close` or `execve`, which the child uses to get there.
+    ///
+    /// [`SeccompNotifier`]: struct.SeccompNotifier.html
+    /// [`SpawnOptions::seccomp_filter`]: struct.SpawnOptions.html#method.seccomp_filter
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn spawn_with_notifier(
+        &mut self,
+        options: SpawnOptions,
+        filter: &SeccompFilter,
+    ) -> Result<(Child, SeccompNotifier)>;
 }
 
 impl CommandPtraceSpawn for Command {
     fn spawn_ptrace(&mut self) -> Result<Child> {
-        let child = unsafe {
-            self.pre_exec(|| {
-                // Opt-in to ptrace.
-                ptrace::traceme().map_err(|e| match e {
-                    nix::Error::Sys(e) => io::Error::from_raw_os_error(e as i32),
-                    _ => io::Error::new(io::ErrorKind::Other, "unknown PTRACE_TRACEME error"),
-                })
+        self.spawn_ptrace_with_options(SpawnOptions::new())
+    }
+
+    fn spawn_ptrace_with_options(&mut self, options: SpawnOptions) -> Result<Child> {
+        self.spawn_tracee(options)
+            .map(|tracee| tracee.into_child().expect("Spawned tracee has a Child"))
+    }
+
+    fn spawn_tracee(&mut self, options: SpawnOptions) -> Result<Tracee> {
+        let seized = options.initial_stop != InitialStop::ExecTrap;
+        let mut ptrace_options = options.ptrace_options;
+        if seized {
+            // Replacing the options mustn't drop the one the child was seized with.
+            ptrace_options = ptrace_options.map(|o| o | ptrace::Options::PTRACE_O_TRACEEXEC);
+        }
+        let suspend_seccomp = options.suspend_seccomp;
+        let oom_score_adj = options.oom_score_adj;
+        let cgroup = options.cgroup.clone();
+        let perf_counters = options.perf_counters.clone();
+        let drop_policy = options.drop_policy;
+        if suspend_seccomp {
+            options::check_suspend_seccomp()?;
+        }
+        let foreground = match options.foreground {
+            Some(fd) => Some((fd, unistd::tcgetpgrp(fd).map_err(nix_error)?)),
+            None => None,
+        };
+        let pty_master = if options.pty {
+            Some(open_pty(self)?)
+        } else {
+            None
+        };
+        let preload = options::set_preload(self, &options.preload)?;
+        let spawned = if seized {
+            spawn_seized(self, options)
+        } else {
+            spawn_traced(self, options).and_then(|child| {
+                let pid = Pid::from_raw(child.id() as i32);
+                // Ensure that the child is stopped in exec before returning.
+                match waitpid(Some(pid), None) {
+                    Ok(status @ WaitStatus::Stopped(_, Signal::SIGTRAP)) => Ok((child, status)),
+                    Err(e) => Err(wait_error(e)),
+                    _ => Err(io::Error::other("Child state not correct")),
+                }
             })
-            .spawn()?
         };
-        // Ensure that the child is stopped in exec before returning.
-        match waitpid(Some(Pid::from_raw(child.id() as i32)), None) {
-            Ok(WaitStatus::Stopped(_, Signal::SIGTRAP)) => Ok(child),
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Child state not correct",
-            )),
+        options::restore_preload(self, preload);
+        if pty_master.is_some() {
+            // Drop the command's copies of the slave side, so that reads from
+            // the master fail once the child is gone.
+            self.stdin(Stdio::inherit())
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit());
+        }
+        let (mut child, status) = spawned?;
+        let pid = Pid::from_raw(child.id() as i32);
+        let setup = (|| {
+            if ptrace_options.is_some() || suspend_seccomp {
+                let ptrace_options = ptrace_options.unwrap_or_else(ptrace::Options::empty);
+                options::set_options(pid, ptrace_options, suspend_seccomp)?;
+            }
+            if let Some(adj) = oom_score_adj {
+                set_oom_score_adj(pid, adj)?;
+            }
+            if let Some(cgroup) = &cgroup {
+                cgroup::add(cgroup, pid)?;
+            }
+            if perf_counters.is_empty() {
+                return Ok(None);
+            }
+            PerfCounters::open(pid, &perf_counters).map(Some)
+        })();
+        let perf_counters = match setup {
+            Ok(perf_counters) => perf_counters,
+            Err(e) => {
+                // Don't leave a stopped child behind.
+                let _ = child.kill();
+                let _ = waitpid(Some(pid), None);
+                return Err(e);
+            }
+        };
+        let mut tracee = Tracee::new(child, status);
+        if let Some(master) = pty_master {
+            tracee.set_pty_master(master);
+        }
+        if let Some(perf_counters) = perf_counters {
+            tracee.set_perf_counters(perf_counters);
+        }
+        if let Some((fd, pgrp)) = foreground {
+            tracee.set_previous_foreground(fd, pgrp);
         }
+        tracee.set_drop_policy(drop_policy);
+        Ok(tracee)
+    }
+
+    fn spawn_ptrace_nowait(&mut self) -> Result<Child> {
+        spawn_traced(self, SpawnOptions::new())
+    }
+
+    fn spawn_ptrace_with<F, T>(&mut self, f: F) -> Result<(T, WaitStatus)>
+    where
+        F: FnOnce(&mut Tracee) -> Result<T>,
+    {
+        let mut options = SpawnOptions::new();
+        // Dropping the tracee, which happens if `f` panics, kills it.
+        options.drop_policy(DropPolicy::Kill);
+        let mut tracee = self.spawn_tracee(options)?;
+        let value = f(&mut tracee)?;
+        let (status, _) = tracee.run_to_status(|_, signal| Ok(Some(signal)))?;
+        Ok((value, status))
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn spawn_with_notifier(
+        &mut self,
+        mut options: SpawnOptions,
+        filter: &SeccompFilter,
+    ) -> Result<(Child, SeccompNotifier)> {
+        let preload = options::set_preload(self, &options.preload)?;
+        let (socket, child_socket) = UnixStream::pair()?;
+        let child_socket_fd = child_socket.as_raw_fd();
+        let program = filter.compile();
+        let flags = libc::SECCOMP_FILTER_FLAG_NEW_LISTENER;
+        let spawned = (|| {
+            options.prepare_exec(self)?;
+            unsafe {
+                spawn_once(self, move || {
+                    options.pre_exec(false)?;
+                    let listener = seccomp::install(&program, flags)?;
+                    notify::send_fd(child_socket_fd, listener)?;
+                    libc::close(listener);
+                    options.exec()
+                })
+            }
+        })();
+        options::restore_preload(self, preload);
+        let child = spawned?;
+        drop(child_socket);
+        let listener = notify::recv_fd(socket.as_raw_fd())?;
+        Ok((child, SeccompNotifier::from_fd(listener)))
+    }
+}
+
+/// Spawn `command` with `PTRACE_TRACEME` set up, without waiting for it.
+fn spawn_traced(command: &mut Command, mut options: SpawnOptions) -> Result<Child> {
+    options.prepare_exec(command)?;
+    unsafe {
+        spawn_once(command, move || {
+            options.pre_exec(true)?;
+            options.exec()
+        })
+    }
+}
+
+/// Spawn `command` with `f` added to its `pre_exec` closures for this spawn
+/// only.
+///
+/// `Command` keeps its closures after spawning, with no way to take them
+/// off, so each one is disarmed once its spawn returns, and does nothing in
+/// the children of later spawns of the same `Command`.
+///
+/// # Safety
+///
+/// As for `CommandExt::pre_exec`.
+unsafe fn spawn_once<F>(command: &mut Command, mut f: F) -> Result<Child>
+where
+    F: FnMut() -> Result<()> + Send + Sync + 'static,
+{
+    let armed = Arc::new(AtomicBool::new(true));
+    let in_child = armed.clone();
+    command.pre_exec(move || {
+        if in_child.load(Ordering::Relaxed) {
+            f()
+        } else {
+            Ok(())
+        }
+    });
+    let child = command.spawn();
+    armed.store(false, Ordering::Relaxed);
+    child
+}
+
+/// Spawn `command` to be attached with `PTRACE_SEIZE` and stopped as
+/// `options.initial_stop` asks for, and return it with the status of its
+/// `PTRACE_EVENT_EXEC` stop.
+///
+/// The thread that seizes the child becomes its tracer, but `Command::spawn`
+/// only returns once the child has called `exec`, so the child is spawned
+/// from another thread, and seized from this one once it reports its PID.
+fn spawn_seized(command: &mut Command, mut options: SpawnOptions) -> Result<(Child, WaitStatus)> {
+    let raised = options.initial_stop == InitialStop::RaisedStop;
+    let (pid_read, pid_write) = unistd::pipe2(OFlag::O_CLOEXEC).map_err(nix_error)?;
+    let (resume_read, resume_write) = match unistd::pipe2(OFlag::O_CLOEXEC) {
+        Ok(fds) => fds,
+        Err(e) => {
+            let _ = unistd::close(pid_read);
+            let _ = unistd::close(pid_write);
+            return Err(nix_error(e));
+        }
+    };
+    options.handshake = Some(Handshake {
+        pid: pid_write,
+        resume: resume_read,
+        tracer: [pid_read, resume_write],
+    });
+    let pid_read = unsafe { File::from_raw_fd(pid_read) };
+    let resume_write = unsafe { File::from_raw_fd(resume_write) };
+    let (seized, spawned) = thread::scope(|scope| {
+        let spawner = scope.spawn(move || {
+            let child = spawn_traced(command, options);
+            // Once the child is gone or has called `exec`, nothing uses these,
+            // and the child's PID pipe must close if it failed early.
+            let _ = unistd::close(pid_write);
+            let _ = unistd::close(resume_read);
+            child
+        });
+        let seized = seize_child(pid_read, resume_write, raised);
+        let spawned = spawner
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        (seized, spawned)
+    });
+    let (mut child, pid) = match (seized, spawned) {
+        (Ok(Some(pid)), Ok(child)) => (child, pid),
+        // The child failed on its own, and the spawn's error says why.
+        (Ok(_), Err(e)) => return Err(e),
+        (Err(e), Err(_)) => return Err(e),
+        (seized, Ok(mut child)) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(seized
+                .err()
+                .unwrap_or_else(|| io::Error::other("Child wasn't seized")));
+        }
+    };
+    match waitpid(Some(pid), None) {
+        Ok(status @ WaitStatus::PtraceEvent(_, Signal::SIGTRAP, libc::PTRACE_EVENT_EXEC)) => {
+            Ok((child, status))
+        }
+        Err(e) => Err(wait_error(e)),
+        _ => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(io::Error::other("Child state not correct"))
+        }
+    }
+}
+
+/// Seize the child that reports its PID on `pid_read`, let it go on to
+/// `exec` through `resume_write`, and return its PID, or `None` if it
+/// failed before reporting it.
+///
+/// If `raised`, the child's `SIGSTOP` is waited for and suppressed. The
+/// child isn't reaped if it exits instead, since `Command::spawn` does.
+fn seize_child(mut pid_read: File, resume_write: File, raised: bool) -> Result<Option<Pid>> {
+    let mut bytes = [0; 4];
+    match pid_read.read_exact(&mut bytes) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let pid = Pid::from_raw(i32::from_ne_bytes(bytes));
+    // Dropping `resume_write` on failure makes the child give up.
+    ptrace::seize(pid, ptrace::Options::PTRACE_O_TRACEEXEC).map_err(|e| match e {
+        nix::Error::Sys(Errno::EIO) => Error::Unsupported("PTRACE_SEIZE").into(),
+        e => nix_error(e),
+    })?;
+    (&resume_write).write_all(&[0])?;
+    drop(resume_write);
+    if raised {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WSTOPPED | libc::WNOWAIT | libc::__WALL;
+        let ret =
+            unsafe { libc::waitid(libc::P_PID, pid.as_raw() as libc::id_t, &mut info, flags) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if info.si_code == libc::CLD_TRAPPED {
+            match waitpid(Some(pid), None) {
+                Ok(WaitStatus::Stopped(_, Signal::SIGSTOP)) => {}
+                Ok(_) => return Err(io::Error::other("Child state not correct")),
+                Err(e) => return Err(wait_error(e)),
+            }
+            ptrace::cont(pid, None).map_err(nix_error)?;
+        }
+    }
+    Ok(Some(pid))
+}
+
+/// Open a pseudo-terminal and make its slave side the stdio of `command`,
+/// returning the master side.
+fn open_pty(command: &mut Command) -> Result<File> {
+    let pty = nix::pty::openpty(None, None).map_err(nix_error)?;
+    // Neither side should leak into the child beyond its stdio.
+    let (master, slave) = unsafe { (File::from_raw_fd(pty.master), File::from_raw_fd(pty.slave)) };
+    for fd in [pty.master, pty.slave] {
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(nix_error)?;
+    }
+    command
+        .stdin(slave.try_clone()?)
+        .stdout(slave.try_clone()?)
+        .stderr(slave);
+    Ok(master)
+}
+
+/// Write `adj` to `/proc/<pid>/oom_score_adj`.
+pub(crate) fn set_oom_score_adj(pid: Pid, adj: i32) -> Result<()> {
+    std::fs::write(format!("/proc/{}/oom_score_adj", pid), adj.to_string())
+}
+
+/// Read the name of thread `tid` from `/proc/<tid>/task/<tid>/comm`, which
+/// is there whichever process the thread belongs to.
+pub(crate) fn thread_name(tid: Pid) -> Result<String> {
+    let name = std::fs::read_to_string(format!("/proc/{0}/task/{0}/comm", tid))?;
+    Ok(name.trim_end_matches('\n').to_string())
+}
+
+/// Send `signal` to the single thread `tid`.
+pub(crate) fn tkill(tid: Pid, signal: Signal) -> Result<()> {
+    let ret = unsafe { libc::syscall(libc::SYS_tkill, tid.as_raw(), signal as libc::c_int) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Convert a `nix::Error` into an `io::Error`, preserving the errno if there is one.
+pub(crate) fn nix_error(e: nix::Error) -> io::Error {
+    match e {
+        nix::Error::Sys(e) => io::Error::from_raw_os_error(e as i32),
+        e => io::Error::other(e),
+    }
+}
+
+/// Convert an error from waiting for a tracee, explaining `ECHILD`, which
+/// means it was reaped by someone else.
+pub(crate) fn wait_error(e: nix::Error) -> io::Error {
+    if e != nix::Error::Sys(nix::errno::Errno::ECHILD) {
+        return nix_error(e);
+    }
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    let ignored = unsafe { libc::sigaction(libc::SIGCHLD, std::ptr::null(), &mut action) } == 0
+        && (action.sa_sigaction == libc::SIG_IGN || action.sa_flags & libc::SA_NOCLDWAIT != 0);
+    if ignored {
+        Error::SigchldIgnored.into()
+    } else {
+        Error::ChildReaped.into()
     }
 }
 
@@ -79,7 +693,7 @@ mod tests {
     use std::env;
     use std::path::PathBuf;
 
-    fn test_process_path() -> Option<PathBuf> {
+    pub(crate) fn test_process_path() -> Option<PathBuf> {
         env::current_exe().ok().and_then(|p| {
             p.parent().map(|p| {
                 p.with_file_name("test")
@@ -89,6 +703,8 @@ mod tests {
     }
 
     #[test]
+    // The child is reaped with `waitpid` rather than `Child::wait`.
+    #[allow(clippy::zombie_processes)]
     fn test_spawn_ptrace() {
         let path = test_process_path().expect("Failed to get test process path");
         let child = Command::new(&path)
@@ -104,4 +720,1573 @@ mod tests {
             Err(e) => panic!("Unexpected waitpid error: {:?}", e),
         }
     }
+
+    #[test]
+    fn test_spawn_ptrace_with_options_hook_order() {
+        let path = test_process_path().expect("Failed to get test process path");
+        // A hook that fails before TRACEME should abort the spawn with its error.
+        let mut options = SpawnOptions::new();
+        unsafe {
+            options.pre_exec_before_traceme(|| {
+                Err(io::Error::from_raw_os_error(
+                    nix::errno::Errno::EINVAL as i32,
+                ))
+            });
+        }
+        let e = Command::new(&path)
+            .spawn_ptrace_with_options(options)
+            .expect_err("Spawn should fail");
+        assert_eq!(e.raw_os_error(), Some(nix::errno::Errno::EINVAL as i32));
+        // After TRACEME the child is already traced, so a second TRACEME fails.
+        let mut options = SpawnOptions::new();
+        unsafe {
+            options.pre_exec_after_traceme(|| ptrace::traceme().map_err(nix_error));
+        }
+        let e = Command::new(&path)
+            .spawn_ptrace_with_options(options)
+            .expect_err("Spawn should fail");
+        assert_eq!(e.raw_os_error(), Some(nix::errno::Errno::EPERM as i32));
+    }
+
+    #[test]
+    fn test_spawn_ptrace_nowait() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut child = Command::new(&path)
+            .spawn_ptrace_nowait()
+            .expect("Error spawning test process");
+        let pid = Pid::from_raw(child.id() as i32);
+        // The initial stop is left for us to collect.
+        match waitpid(pid, None) {
+            Ok(WaitStatus::Stopped(_, Signal::SIGTRAP)) => {}
+            s => panic!("Unexpected initial status: {:?}", s),
+        }
+        ptrace::cont(pid, None).expect("Error continuing child process");
+        let status = child.wait().expect("Error waiting for child");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_spawn_tracee() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        assert_eq!(
+            tracee.initial_status(),
+            WaitStatus::Stopped(pid, Signal::SIGTRAP)
+        );
+        ptrace::cont(pid, None).expect("Error continuing child process");
+        let status = tracee
+            .into_child()
+            .expect("No Child for tracee")
+            .wait()
+            .expect("Error waiting for child");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_initial_stop() {
+        let path = test_process_path().expect("Failed to get test process path");
+        for stop in [InitialStop::RaisedStop, InitialStop::ExecEvent] {
+            let mut options = SpawnOptions::new();
+            options.initial_stop(stop);
+            let tracee = Command::new(&path)
+                .stdout(Stdio::null())
+                .spawn_tracee(options)
+                .expect("Error spawning test process");
+            let pid = tracee.pid();
+            assert_eq!(
+                tracee.initial_status(),
+                WaitStatus::PtraceEvent(pid, Signal::SIGTRAP, libc::PTRACE_EVENT_EXEC),
+                "{:?}",
+                stop
+            );
+            // The child has already called `exec`.
+            let exe = std::fs::read_link(format!("/proc/{}/exe", pid)).unwrap();
+            assert_eq!(exe, path.canonicalize().unwrap());
+            let outcome = tracee.run_to_exit().expect("Error running tracee");
+            assert_eq!(outcome.exit_code, Some(0));
+            assert!(outcome.signals.is_empty(), "{:?}", outcome.signals);
+
+            // A child that fails before it is seized fails the spawn.
+            let mut options = SpawnOptions::new();
+            options.initial_stop(stop);
+            unsafe {
+                options.pre_exec_before_traceme(|| Err(io::Error::from_raw_os_error(libc::EINVAL)));
+            }
+            let e = Command::new(&path)
+                .spawn_tracee(options)
+                .expect_err("Spawn should fail");
+            assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+        }
+    }
+
+    #[test]
+    // The child is reaped by the session rather than `Child::wait`.
+    #[allow(clippy::zombie_processes)]
+    fn test_seized_stops() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut child = Command::new(&path)
+            .args(["sleep", "5000"])
+            .spawn()
+            .expect("Error spawning test process");
+        let pid = Pid::from_raw(child.id() as i32);
+        ptrace::seize(pid, ptrace::Options::empty()).expect("Error seizing child");
+        let mut session = TraceSession::new();
+        session
+            .add(Tracee::attached(pid, WaitStatus::StillAlive))
+            .expect("Error adding tracee");
+        let interrupt = |pid: Pid| {
+            let ret = unsafe { libc::ptrace(libc::PTRACE_INTERRUPT, pid.as_raw(), 0, 0) };
+            assert_eq!(ret, 0, "Error interrupting child");
+        };
+        interrupt(pid);
+        assert_eq!(session.wait_for(pid).unwrap(), (pid, Event::InterruptStop));
+        ptrace::cont(pid, None).expect("Error continuing child");
+        nix::sys::signal::kill(pid, Signal::SIGSTOP).expect("Error stopping child");
+        assert_eq!(
+            session.wait_for(pid).unwrap(),
+            (pid, Event::Signal(Signal::SIGSTOP))
+        );
+        ptrace::cont(pid, Signal::SIGSTOP).expect("Error continuing child");
+        assert_eq!(
+            session.wait_for(pid).unwrap(),
+            (pid, Event::GroupStop(Signal::SIGSTOP))
+        );
+        child.kill().expect("Error killing child");
+        assert_eq!(
+            session.wait_for(pid).unwrap(),
+            (pid, Event::Signaled(Signal::SIGKILL, false))
+        );
+    }
+
+    #[test]
+    // The process is reaped by the session rather than `Child::wait`.
+    #[allow(clippy::zombie_processes)]
+    fn test_session_attach() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let child = Command::new(&path)
+            .args(["churn", "5000"])
+            .spawn()
+            .expect("Error spawning test process");
+        let pid = Pid::from_raw(child.id() as i32);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let mut session = TraceSession::new();
+        session.follow_forks();
+        let tids = session.attach(pid).expect("Error attaching");
+        assert_eq!(tids[0], pid);
+        assert!(tids.len() >= 4, "Only attached {:?}", tids);
+        let task = format!("/proc/{}/task", pid);
+        let mut listed: Vec<Pid> = std::fs::read_dir(&task)
+            .unwrap()
+            .map(|entry| {
+                Pid::from_raw(
+                    entry
+                        .unwrap()
+                        .file_name()
+                        .to_str()
+                        .unwrap()
+                        .parse()
+                        .unwrap(),
+                )
+            })
+            .collect();
+        listed.sort();
+        let mut attached = tids.clone();
+        attached.sort();
+        assert_eq!(listed, attached);
+        for tid in &tids {
+            let stat = std::fs::read_to_string(format!("{}/{}/stat", task, tid)).unwrap();
+            let state = stat[stat.rfind(')').unwrap() + 2..].chars().next();
+            assert_eq!(state, Some('t'), "Thread {} isn't stopped", tid);
+            assert!(session.get(*tid).is_some());
+        }
+        nix::sys::signal::kill(pid, Signal::SIGKILL).expect("Error killing child");
+        while !session.is_empty() {
+            session.wait_any().expect("Error waiting for tracees");
+        }
+    }
+
+    #[test]
+    fn test_validate_options() {
+        let invalid = |options: &SpawnOptions| match options.validate() {
+            Err(e) => match Error::from_io(&e) {
+                Some(Error::InvalidOptions(problem)) => *problem,
+                _ => panic!("Unexpected error {}", e),
+            },
+            Ok(()) => panic!("{:?} is valid", options),
+        };
+        SpawnOptions::new()
+            .validate()
+            .expect("Default options are invalid");
+        let mut options = SpawnOptions::new();
+        options.setsid().foreground(0);
+        assert!(invalid(&options).contains("foreground"));
+        let mut options = SpawnOptions::new();
+        options.scheduler(SchedPolicy::Fifo, 0);
+        assert!(invalid(&options).contains("priorities"));
+        let mut options = SpawnOptions::new();
+        options.scheduler(SchedPolicy::Batch, 0).nice(20);
+        assert!(invalid(&options).contains("nice"));
+        let mut options = SpawnOptions::new();
+        options.oom_score_adj(1001);
+        assert!(invalid(&options).contains("oom_score_adj"));
+        let mut options = SpawnOptions::new();
+        options.preload("/nonexistent/libfoo.so");
+        let e = options.validate().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_reaped_elsewhere() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+        let e = tracee.try_wait().unwrap_err();
+        assert_eq!(Error::from_io(&e), Some(&Error::ChildReaped));
+        let mut session = TraceSession::new();
+        let pid = tracee.pid();
+        session.add(tracee).expect("Error adding tracee");
+        let e = session.wait_any().unwrap_err();
+        assert_eq!(Error::from_io(&e), Some(&Error::ChildReaped));
+        session.remove(pid);
+    }
+
+    #[test]
+    fn test_tracee_pidfd() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pidfd = tracee.pidfd().expect("No pidfd for tracee");
+        pidfd
+            .send_signal(Signal::SIGKILL)
+            .expect("Error sending signal");
+        match waitpid(tracee.pid(), None) {
+            Ok(WaitStatus::Signaled(_, Signal::SIGKILL, _)) => {}
+            s => panic!("Unexpected status: {:?}", s),
+        }
+    }
+
+    #[test]
+    fn test_sigchld_fd_try_wait() {
+        use nix::poll::{poll, PollFd, PollFlags};
+        use nix::sys::signal::SigSet;
+        use std::os::unix::io::AsRawFd;
+
+        let blocked = SigSet::thread_get_mask().unwrap().contains(Signal::SIGCHLD);
+        let mut sigchld = SigchldFd::new().expect("Error creating SigchldFd");
+        assert!(SigSet::thread_get_mask().unwrap().contains(Signal::SIGCHLD));
+        let path = test_process_path().expect("Failed to get test process path");
+        let tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        // The initial stop has already been collected.
+        assert_eq!(tracee.try_wait().expect("Error in try_wait"), None);
+        ptrace::cont(tracee.pid(), None).expect("Error continuing child process");
+        loop {
+            // Other test threads may not block SIGCHLD, so don't rely on
+            // the notification arriving here.
+            let mut fds = [PollFd::new(sigchld.as_raw_fd(), PollFlags::POLLIN)];
+            poll(&mut fds, 100).expect("Error polling");
+            sigchld.clear().expect("Error clearing SigchldFd");
+            match tracee.try_wait().expect("Error in try_wait") {
+                None => continue,
+                Some(WaitStatus::Exited(_, 0)) => break,
+                Some(s) => panic!("Unexpected status: {:?}", s),
+            }
+        }
+        drop(sigchld);
+        let mask = SigSet::thread_get_mask().unwrap();
+        assert_eq!(mask.contains(Signal::SIGCHLD), blocked);
+    }
+
+    #[test]
+    fn test_sigchld_self_pipe() {
+        use nix::poll::{poll, PollFd, PollFlags};
+        use std::os::unix::io::AsRawFd;
+
+        let mut sigchld = SigchldFd::self_pipe().expect("Error creating self-pipe");
+        let e = SigchldFd::self_pipe().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        let path = test_process_path().expect("Failed to get test process path");
+        let tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        ptrace::cont(tracee.pid(), None).expect("Error continuing child process");
+        let mut notified = 0;
+        loop {
+            // Other tests' children also notify the pipe, and the exit may
+            // come before the wait, so poll with a timeout.
+            let mut fds = [PollFd::new(sigchld.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, 100) {
+                Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => {}
+                Err(e) => panic!("Error polling: {}", e),
+            }
+            notified += sigchld.clear().expect("Error clearing self-pipe");
+            match tracee.try_wait().expect("Error in try_wait") {
+                None => continue,
+                Some(WaitStatus::Exited(_, 0)) => break,
+                Some(s) => panic!("Unexpected status: {:?}", s),
+            }
+        }
+        notified += sigchld.clear().expect("Error clearing self-pipe");
+        assert!(notified > 0);
+    }
+
+    #[test]
+    fn test_session_event_time() {
+        use std::time::Instant;
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        let pid = session
+            .spawn(
+                Command::new(&path).stdout(Stdio::null()),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        assert_eq!(session.event_time(), None);
+        let mut last = Instant::now();
+        loop {
+            ptrace::syscall(pid, None).expect("Error resuming child");
+            let event = session.wait_for(pid).expect("Error waiting");
+            let time = session.event_time().expect("No event time");
+            assert!(time >= last && time <= Instant::now());
+            last = time;
+            if let (_, Event::Exited(_)) = event {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_session_follow_forks() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.follow_forks();
+        let parent = session
+            .spawn(Command::new(&path).arg("fork"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        ptrace::cont(parent, None).expect("Error continuing child process");
+        let mut forked = None;
+        let mut attached = None;
+        let mut exited = vec![];
+        while !session.is_empty() {
+            let (pid, event) = session.wait_any().expect("Error waiting for session");
+            let signal = match event {
+                Event::Fork(child) => {
+                    assert_eq!(pid, parent);
+                    forked = Some(child);
+                    None
+                }
+                Event::Attached => {
+                    assert!(session.get(pid).is_some());
+                    attached = Some(pid);
+                    None
+                }
+                Event::Exited(code) => {
+                    assert_eq!(code, 0);
+                    assert!(session.get(pid).is_none());
+                    exited.push(pid);
+                    continue;
+                }
+                Event::Signal(signal) => Some(signal),
+                e => panic!("Unexpected event: {:?}", e),
+            };
+            ptrace::cont(pid, signal).expect("Error continuing tracee");
+        }
+        let forked = forked.expect("No fork event");
+        assert_eq!(attached, Some(forked));
+        assert_eq!(exited.len(), 2);
+        assert!(exited.contains(&parent) && exited.contains(&forked));
+    }
+
+    #[test]
+    fn test_session_vfork() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.follow_forks();
+        let parent = session
+            .spawn(Command::new(&path).arg("vfork"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        ptrace::cont(parent, None).expect("Error continuing child process");
+        let mut vforked = None;
+        let mut done = false;
+        while !session.is_empty() {
+            let (pid, event) = session.wait_any().expect("Error waiting for session");
+            let signal = match event {
+                Event::Vfork(child) => {
+                    vforked = Some(child);
+                    let tracee = session.get(pid).unwrap();
+                    assert_eq!(tracee.vfork_shared(), Some(child));
+                    let e = tracee.write_memory(0, &[0]).unwrap_err();
+                    assert_eq!(Error::from_io(&e), Some(&Error::VforkShared(child)));
+                    None
+                }
+                Event::Attached => {
+                    assert_eq!(session.get(pid).unwrap().vfork_shared(), Some(parent));
+                    None
+                }
+                Event::VforkDone(child) => {
+                    assert_eq!(Some(child), vforked);
+                    assert_eq!(session.get(pid).unwrap().vfork_shared(), None);
+                    done = true;
+                    None
+                }
+                Event::Exited(_) => continue,
+                Event::Signal(signal) => Some(signal),
+                e => panic!("Unexpected event: {:?}", e),
+            };
+            ptrace::cont(pid, signal).expect("Error continuing tracee");
+        }
+        assert!(vforked.is_some() && done);
+    }
+
+    #[test]
+    fn test_serial_scheduler() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.follow_forks();
+        let pid = session
+            .spawn(Command::new(&path).arg("threads"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut scheduler = SerialScheduler::new(100);
+        let mut clones = 0;
+        let mut exit_code = None;
+        while !session.is_empty() {
+            for (event_pid, event) in scheduler
+                .run_quantum(&mut session)
+                .expect("Error running quantum")
+            {
+                match event {
+                    Event::Clone(_) => clones += 1,
+                    Event::Exited(code) if event_pid == pid => exit_code = Some(code),
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(clones, 2);
+        assert_eq!(exit_code, Some(0));
+    }
+
+    #[test]
+    fn test_syscall_profiler() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        session
+            .spawn(
+                Command::new(&path).stdout(Stdio::null()),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut profiler = SyscallProfiler::new();
+        profiler.run(&mut session).expect("Error profiling");
+        let report = profiler.report();
+        let write = report
+            .get(libc::SYS_write as u64)
+            .expect("No write syscalls recorded");
+        assert!(write.count >= 1);
+        assert!(write.min <= write.p50 && write.p50 <= write.p99 && write.p99 <= write.max);
+        assert!(report.total() >= write.total);
+    }
+
+    #[test]
+    fn test_sampled_syscall_profiler() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        session
+            .spawn(
+                Command::new(&path).args(["churn", "200"]),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut profiler = SyscallProfiler::new();
+        profiler.sampling_interval(std::time::Duration::from_millis(20));
+        profiler.run(&mut session).expect("Error profiling");
+        // The main thread makes thousands of calls starting threads.
+        let sampled: usize = profiler.report().syscalls.iter().map(|s| s.count).sum();
+        assert!((1..=15).contains(&sampled), "{} calls sampled", sampled);
+    }
+
+    #[test]
+    fn test_syscall_restarts() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let sleeps = [libc::SYS_nanosleep as u64, libc::SYS_clock_nanosleep as u64];
+        // Interrupt a sleep with a suppressed SIGSTOP, which restarts it.
+        let run = |report_restarts: bool| {
+            let mut session = TraceSession::new();
+            session.trace_syscalls();
+            if report_restarts {
+                session.report_syscall_restarts();
+            }
+            let pid = session
+                .spawn(
+                    Command::new(&path).args(["sleep", "300"]),
+                    SpawnOptions::new(),
+                )
+                .expect("Error spawning test process");
+            ptrace::syscall(pid, None).expect("Error resuming child");
+            let (mut stopped, mut sleep_exits, mut restarts) = (false, vec![], 0);
+            let mut in_sleep = false;
+            loop {
+                let (pid, event) = session.wait_any().expect("Error waiting");
+                let signal = match event {
+                    Event::Syscall => {
+                        match session.get(pid).unwrap().syscall_info().unwrap() {
+                            SyscallInfo::Entry { number, .. } => {
+                                in_sleep = sleeps.contains(&number);
+                                if number == libc::SYS_restart_syscall as u64 {
+                                    restarts += 1;
+                                }
+                            }
+                            info @ SyscallInfo::Exit { .. } => {
+                                if info.is_restart() {
+                                    restarts += 1;
+                                } else if in_sleep {
+                                    sleep_exits.push(info);
+                                }
+                            }
+                            _ => {}
+                        }
+                        None
+                    }
+                    Event::Signal(Signal::SIGSTOP) => None,
+                    Event::Signal(signal) => Some(signal),
+                    Event::Exited(code) => {
+                        assert_eq!(code, 0);
+                        break;
+                    }
+                    _ => None,
+                };
+                ptrace::syscall(pid, signal).expect("Error resuming child");
+                if in_sleep && !stopped {
+                    stopped = true;
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                    tkill(pid, Signal::SIGSTOP).expect("Error stopping child");
+                }
+            }
+            (sleep_exits, restarts)
+        };
+        let (sleep_exits, restarts) = run(false);
+        assert_eq!(restarts, 0);
+        let done = SyscallInfo::Exit {
+            value: 0,
+            is_error: false,
+        };
+        assert_eq!(sleep_exits, vec![done]);
+        let (_, restarts) = run(true);
+        assert!(restarts >= 1);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_syscall_stops_without_sysgood() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.ptrace_options(ptrace::Options::PTRACE_O_TRACEEXEC);
+        let pid = session
+            .spawn(
+                Command::new(&path).stdout(Stdio::null()),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut breakpoints = Breakpoints::new();
+        let main = breakpoints.insert_symbol(pid, "main").unwrap();
+        let (mut syscalls, mut hits) = (0, 0);
+        loop {
+            ptrace::syscall(pid, None).expect("Error resuming child");
+            match session.wait_for(pid).expect("Error waiting") {
+                (_, Event::Syscall) => {
+                    // The kernel only gives details of syscall stops marked
+                    // by `PTRACE_O_TRACESYSGOOD`.
+                    let info = session.get(pid).unwrap().syscall_info().unwrap();
+                    assert_eq!(info, SyscallInfo::None);
+                    syscalls += 1;
+                }
+                (_, Event::Signal(Signal::SIGTRAP)) => {
+                    assert_eq!(breakpoints.hit(pid).unwrap(), Some(main));
+                    hits += 1;
+                    breakpoints.remove(pid, main).unwrap();
+                }
+                (_, Event::Exited(code)) => {
+                    assert_eq!(code, 0);
+                    break;
+                }
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        assert_eq!(hits, 1);
+        assert!(syscalls > 10);
+    }
+
+    #[test]
+    fn test_io_accounting() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let file = env::temp_dir().join(format!("spawn-ptrace-io-{}", std::process::id()));
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        session
+            .spawn(
+                Command::new(&path).arg("io").arg(&file),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut accounting = IoAccounting::new();
+        accounting.run(&mut session).expect("Error accounting");
+        let _ = std::fs::remove_file(&file);
+        let fds: Vec<FdStats> = accounting
+            .fds()
+            .into_iter()
+            .filter(|s| s.target.as_deref() == Some(file.as_path()))
+            .collect();
+        assert_eq!(fds.len(), 2, "Expected one entry per open: {:?}", fds);
+        assert_eq!(fds.iter().map(|s| s.bytes_written).sum::<u64>(), 1000);
+        assert_eq!(fds.iter().map(|s| s.writes).sum::<usize>(), 10);
+        assert_eq!(fds.iter().map(|s| s.bytes_read).sum::<u64>(), 1000);
+        assert!(fds.iter().all(|s| s.closed.is_some()));
+        let written: u64 = accounting
+            .timeline()
+            .iter()
+            .filter(|e| e.direction == IoDirection::Write && fds.iter().any(|s| s.fd == e.fd))
+            .map(|e| e.bytes)
+            .sum();
+        assert!(written >= 1000);
+        assert!(accounting.total_read() >= 1000);
+    }
+
+    #[test]
+    fn test_wait_with_output() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let tracee = Command::new(&path)
+            .arg("flood")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        let output = tracee.wait_with_output().expect("Error collecting output");
+        assert_eq!(output.status, WaitStatus::Exited(pid, 0));
+        assert!(output.outcome.success());
+        assert_eq!(output.stdout.len(), 1 << 20);
+        assert_eq!(output.stderr.len(), 1 << 20);
+    }
+
+    #[test]
+    fn test_run_to_exit_outcome() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let tracee = Command::new(&path)
+            .args(["sleep", "200"])
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        nix::sys::signal::kill(tracee.pid(), Signal::SIGUSR1).unwrap();
+        let outcome = tracee.run_to_exit().expect("Error running tracee");
+        assert_eq!(outcome.exit_code, None);
+        assert_eq!(outcome.signal, Some(Signal::SIGUSR1));
+        assert!(!outcome.killed_by_tracer);
+        assert_eq!(outcome.signals, [Signal::SIGUSR1]);
+        assert_eq!(outcome.stops, 1);
+        assert_eq!(outcome.exit_status().signal(), Some(libc::SIGUSR1));
+    }
+
+    #[test]
+    fn test_run_to_exit_with() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let tracee = Command::new(&path)
+            .args(["sleep", "10"])
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        nix::sys::signal::kill(tracee.pid(), Signal::SIGUSR1).unwrap();
+        let mut seen = vec![];
+        let outcome = tracee
+            .run_to_exit_with(|_, signal| {
+                seen.push(signal);
+                Ok(None)
+            })
+            .expect("Error running tracee");
+        assert!(outcome.success());
+        assert_eq!(seen, [Signal::SIGUSR1]);
+        assert_eq!(outcome.signals, [Signal::SIGUSR1]);
+        // The trap after the `exec` isn't passed on.
+        let tracee = Command::new(&path)
+            .arg("exec")
+            .stdout(Stdio::null())
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let outcome = tracee.run_to_exit().expect("Error running tracee");
+        assert!(outcome.success());
+        assert_eq!(outcome.stops, 1);
+        assert!(outcome.signals.is_empty());
+    }
+
+    #[test]
+    fn test_spawn_ptrace_with() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let (pid, status) = Command::new(&path)
+            .stdout(Stdio::null())
+            .spawn_ptrace_with(|tracee| Ok(tracee.pid()))
+            .expect("Error running test process");
+        assert_eq!(status, WaitStatus::Exited(pid, 0));
+
+        let mut pid = None;
+        let e = Command::new(&path)
+            .args(["sleep", "5000"])
+            .spawn_ptrace_with(|tracee| -> Result<()> {
+                pid = Some(tracee.pid());
+                Err(io::Error::other("Setup failed"))
+            })
+            .unwrap_err();
+        assert_eq!(e.to_string(), "Setup failed");
+        let pid = pid.unwrap();
+        assert_eq!(
+            waitpid(pid, None),
+            Err(nix::Error::Sys(nix::errno::Errno::ECHILD))
+        );
+
+        let pid = std::cell::Cell::new(None);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Command::new(&path)
+                .args(["sleep", "5000"])
+                .spawn_ptrace_with(|tracee| -> Result<()> {
+                    pid.set(Some(tracee.pid()));
+                    panic!("Setup panicked");
+                })
+        }));
+        assert!(result.is_err());
+        let pid = pid.get().unwrap();
+        assert_eq!(
+            waitpid(pid, None),
+            Err(nix::Error::Sys(nix::errno::Errno::ECHILD))
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_fork_server() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let template = |arg: &str| {
+            let tracee = Command::new(&path)
+                .args(["spin", arg])
+                .spawn_tracee(SpawnOptions::new())
+                .expect("Error spawning test process");
+            ForkServer::new(tracee).expect("Error creating fork server")
+        };
+        let mut server = template("0");
+        for _ in 0..3 {
+            let mut child = server.fork().expect("Error forking copy");
+            let pid = child.pid();
+            assert_ne!(pid, server.template().pid());
+            assert_eq!(
+                child.run().expect("Error running copy"),
+                ForkOutcome::Exited(0)
+            );
+            // The template should have reaped the copy.
+            assert!(!PathBuf::from(format!("/proc/{}", pid)).exists());
+        }
+        let mut server = template("10000");
+        let mut child = server.fork().expect("Error forking copy");
+        assert_eq!(
+            child
+                .run_with_timeout(std::time::Duration::from_millis(50))
+                .expect("Error running copy"),
+            ForkOutcome::TimedOut
+        );
+        let template = server.into_template();
+        nix::sys::signal::kill(template.pid(), Signal::SIGKILL).expect("Error killing template");
+        waitpid(template.pid(), None).expect("Error reaping template");
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_fork_server_warm() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut options = SpawnOptions::new();
+        options.drop_policy(DropPolicy::Kill);
+        let mut server =
+            ForkServer::warm(Command::new(&path).stdout(Stdio::null()), options, "main")
+                .expect("Error warming fork server");
+        let template = server.template().pid();
+        for _ in 0..3 {
+            let mut child = server.fork().expect("Error forking copy");
+            assert_eq!(
+                child.run().expect("Error running copy"),
+                ForkOutcome::Exited(0)
+            );
+        }
+        drop(server);
+        assert_eq!(
+            waitpid(template, None),
+            Err(nix::Error::Sys(nix::errno::Errno::ECHILD))
+        );
+        let e = ForkServer::warm(&mut Command::new(&path), SpawnOptions::new(), "no_such_fn")
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_checkpoint() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .stdout(Stdio::null())
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let buf = tracee.allocate(4096, prot).expect("Error allocating");
+        tracee.write_value(buf, &1u64).unwrap();
+        let mut checkpoint = tracee.checkpoint().expect("Error taking checkpoint");
+        tracee.write_value(buf, &2u64).unwrap();
+        // The snapshot is a sibling of the tracee, not its child.
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", checkpoint.snapshot().pid()))
+            .expect("Error reading snapshot stat");
+        let ppid = stat.rsplit(')').next().unwrap().split_whitespace().nth(1);
+        assert_eq!(ppid, Some(nix::unistd::getpid().to_string().as_str()));
+        for _ in 0..2 {
+            let mut copy = checkpoint.restore().expect("Error restoring checkpoint");
+            assert_eq!(copy.tracee().read_value::<u64>(buf).unwrap(), 1);
+            copy.tracee().write_value(buf, &3u64).unwrap();
+            assert_eq!(
+                copy.run().expect("Error running copy"),
+                ForkOutcome::Exited(0)
+            );
+        }
+        let snapshot = checkpoint.snapshot().pid();
+        drop(checkpoint);
+        assert!(!PathBuf::from(format!("/proc/{}", snapshot)).exists());
+        assert_eq!(tracee.read_value::<u64>(buf).unwrap(), 2);
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    fn test_suspend_seccomp() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut options = SpawnOptions::new();
+        options.suspend_seccomp();
+        let result = Command::new(&path)
+            .stdout(Stdio::null())
+            .spawn_tracee(options);
+        match result {
+            Ok(tracee) => {
+                let pid = tracee.pid();
+                ptrace::cont(pid, None).expect("Error continuing child");
+                assert_eq!(waitpid(pid, None), Ok(WaitStatus::Exited(pid, 0)));
+            }
+            // Running without the privileges needed is fine, as long as that
+            // is reported properly.
+            Err(e) => assert!(Error::from_io(&e).is_some(), "Untyped error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_drop_privileges() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut options = SpawnOptions::new();
+        options.no_new_privs();
+        let root = nix::unistd::Uid::current().is_root();
+        let gid = nix::unistd::getgid().as_raw();
+        if root {
+            // Stay as the same user, so the test binary can still be run.
+            options
+                .uid(nix::unistd::getuid().as_raw())
+                .gid(gid)
+                .groups(&[gid]);
+        }
+        let tracee = Command::new(&path)
+            .arg("creds")
+            .stdout(Stdio::piped())
+            .spawn_tracee(options)
+            .expect("Error spawning test process");
+        let output = tracee.wait_with_output().expect("Error collecting output");
+        let output = String::from_utf8(output.stdout).expect("Output is not UTF-8");
+        let field = |name: &str| {
+            output
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .map(str::trim)
+                .map(String::from)
+        };
+        assert_eq!(field("NoNewPrivs:").as_deref(), Some("1"));
+        if root {
+            assert_eq!(field("Groups:"), Some(gid.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_change_root() {
+        if !nix::unistd::Uid::current().is_root() {
+            return;
+        }
+        let path = test_process_path().expect("Failed to get test process path");
+        // Build a root from bind mounts of the directories the test binary
+        // needs, made inside the child's own mount namespace.
+        let root = env::temp_dir().join(format!("spawn-ptrace-root-{}", std::process::id()));
+        let top = path.iter().nth(1).expect("Test path has no directories");
+        let mut binds = vec![];
+        for dir in ["usr".as_ref(), "lib".as_ref(), "lib64".as_ref(), top] {
+            let source = std::path::Path::new("/").join(dir);
+            if source.exists() {
+                std::fs::create_dir_all(root.join(dir)).expect("Error creating directory");
+                binds.push((source, root.join(dir)));
+            }
+        }
+        let mut pivot_root = SpawnOptions::new();
+        unsafe {
+            pivot_root.pre_exec_after_traceme(move || {
+                use nix::mount::{mount, MsFlags};
+                let none: Option<&str> = None;
+                nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWNS).map_err(nix_error)?;
+                mount(none, "/", none, MsFlags::MS_REC | MsFlags::MS_PRIVATE, none)
+                    .map_err(nix_error)?;
+                for (source, target) in &binds {
+                    mount(Some(source), target, none, MsFlags::MS_BIND, none).map_err(nix_error)?;
+                }
+                Ok(())
+            });
+        }
+        pivot_root.pivot_root(&root);
+        // A chroot into the existing root leaves everything in place.
+        let mut chroot = SpawnOptions::new();
+        chroot.chroot("/");
+        for options in [chroot, pivot_root] {
+            let output = Command::new(&path)
+                .stdout(Stdio::piped())
+                .spawn_tracee(options)
+                .expect("Error spawning test process")
+                .wait_with_output()
+                .expect("Error collecting output");
+            assert_eq!(output.stdout, b"hello\n");
+        }
+        std::fs::remove_dir_all(&root).expect("Error removing root");
+    }
+
+    #[test]
+    fn test_close_fds_from() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let file = std::fs::File::open(&path).expect("Error opening file");
+        // Unlike std, `dup` doesn't set close-on-exec.
+        let leaked = unsafe { libc::dup(std::os::unix::io::AsRawFd::as_raw_fd(&file)) };
+        let kept = unsafe { libc::dup(std::os::unix::io::AsRawFd::as_raw_fd(&file)) };
+        let mut options = SpawnOptions::new();
+        options.close_fds_from(3).keep_fd(kept);
+        let output = Command::new(&path)
+            .arg("fds")
+            .stdout(Stdio::piped())
+            .spawn_tracee(options)
+            .expect("Error spawning test process")
+            .wait_with_output()
+            .expect("Error collecting output");
+        unsafe {
+            libc::close(leaked);
+            libc::close(kept);
+        }
+        let fds: Vec<i32> = String::from_utf8(output.stdout)
+            .expect("Output is not UTF-8")
+            .lines()
+            .map(|fd| fd.parse().expect("Not a file descriptor"))
+            .collect();
+        assert!(fds.contains(&kept), "{:?}", fds);
+        assert!(!fds.contains(&leaked), "{:?}", fds);
+        // Only stdio, the kept descriptor and the one used to read the list.
+        assert!(fds.len() <= 5, "{:?}", fds);
+    }
+
+    #[test]
+    fn test_close_fds_from_keeping_stdin() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let file = std::fs::File::open(&path).expect("Error opening file");
+        let leaked = unsafe { libc::dup(std::os::unix::io::AsRawFd::as_raw_fd(&file)) };
+        let mut options = SpawnOptions::new();
+        options.close_fds_from(0).keep_fd(0).keep_fd(1);
+        let output = Command::new(&path)
+            .arg("fds")
+            .stdout(Stdio::piped())
+            .spawn_tracee(options)
+            .expect("Error spawning test process")
+            .wait_with_output()
+            .expect("Error collecting output");
+        unsafe { libc::close(leaked) };
+        let fds: Vec<i32> = String::from_utf8(output.stdout)
+            .expect("Output is not UTF-8")
+            .lines()
+            .map(|fd| fd.parse().expect("Not a file descriptor"))
+            .collect();
+        assert!(fds.contains(&0) && fds.contains(&1), "{:?}", fds);
+        assert!(!fds.contains(&leaked), "{:?}", fds);
+    }
+
+    #[test]
+    fn test_exec_at() {
+        use std::os::unix::fs::OpenOptionsExt;
+        let path = test_process_path().expect("Failed to get test process path");
+        let open_path = |path: &std::path::Path| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH)
+                .open(path)
+                .expect("Error opening path")
+        };
+        let dir = open_path(path.parent().unwrap());
+        let file = open_path(&path);
+        let name = path.file_name().unwrap();
+        let targets = [(dir.as_raw_fd(), name), (file.as_raw_fd(), "".as_ref())];
+        for &(dirfd, relative) in &targets {
+            let mut options = SpawnOptions::new();
+            options.exec_at(dirfd, relative);
+            // The program isn't looked up.
+            let tracee = Command::new("no-such-program")
+                .env("SPAWN_PTRACE_EXEC_AT", "1")
+                .stdout(Stdio::piped())
+                .spawn_tracee(options)
+                .expect("Error spawning test process");
+            let pid = tracee.pid();
+            let environ = std::fs::read(format!("/proc/{}/environ", pid)).unwrap();
+            assert!(environ
+                .split(|&b| b == 0)
+                .any(|var| var == b"SPAWN_PTRACE_EXEC_AT=1"));
+            let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap();
+            assert_eq!(cmdline, b"no-such-program\0");
+            let output = tracee.wait_with_output().expect("Error collecting output");
+            assert_eq!(output.status, WaitStatus::Exited(pid, 0));
+            assert_eq!(output.stdout, b"hello\n");
+        }
+        let mut options = SpawnOptions::new();
+        options.exec_at(dir.as_raw_fd(), "no-such-file");
+        let err = Command::new(&path).spawn_tracee(options).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    /// Run the test process in `session` mode, returning its pid, process
+    /// group, session and controlling terminal.
+    fn session_ids(options: SpawnOptions) -> [i64; 4] {
+        let path = test_process_path().expect("Failed to get test process path");
+        let output = Command::new(&path)
+            .arg("session")
+            .stdout(Stdio::piped())
+            .spawn_tracee(options)
+            .expect("Error spawning test process")
+            .wait_with_output()
+            .expect("Error collecting output");
+        let output = String::from_utf8(output.stdout).expect("Output is not UTF-8");
+        let ids: Vec<i64> = output
+            .split_whitespace()
+            .map(|id| id.parse().expect("Not a number"))
+            .collect();
+        [ids[0], ids[1], ids[2], ids[3]]
+    }
+
+    #[test]
+    fn test_setsid() {
+        let mut options = SpawnOptions::new();
+        options.setsid();
+        let [pid, pgid, sid, tty] = session_ids(options);
+        assert_eq!(pgid, pid);
+        assert_eq!(sid, pid);
+        assert_eq!(tty, 0);
+    }
+
+    #[test]
+    fn test_foreground_not_a_terminal() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let null = File::open("/dev/null").expect("Error opening /dev/null");
+        let mut options = SpawnOptions::new();
+        options.foreground(null.as_raw_fd());
+        let e = Command::new(&path).spawn_tracee(options).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOTTY));
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        tracee
+            .restore_foreground()
+            .expect("Error restoring foreground");
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    fn test_pty() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut options = SpawnOptions::new();
+        options.pty();
+        let mut tracee = Command::new(&path)
+            .arg("session")
+            .spawn_tracee(options)
+            .expect("Error spawning test process");
+        let mut master = tracee.take_pty_master().expect("No pty master");
+        let pid = tracee.pid();
+        ptrace::cont(pid, None).expect("Error continuing child");
+        assert_eq!(waitpid(pid, None), Ok(WaitStatus::Exited(pid, 0)));
+        let mut output = vec![];
+        // The master reports EIO once the buffered output has been read.
+        if let Err(e) = std::io::Read::read_to_end(&mut master, &mut output) {
+            assert_eq!(e.raw_os_error(), Some(libc::EIO));
+        }
+        let output = String::from_utf8(output).expect("Output is not UTF-8");
+        let ids: Vec<i64> = output
+            .split_whitespace()
+            .map(|id| id.parse().expect("Not a number"))
+            .collect();
+        assert_eq!(ids[2], pid.as_raw() as i64);
+        assert_ne!(ids[3], 0, "No controlling terminal");
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_name() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let expected: String = name.chars().take(15).collect();
+        assert_eq!(tracee.name().unwrap(), expected);
+        tracee.set_name("renamed-tracee").unwrap();
+        assert_eq!(tracee.name().unwrap(), "renamed-tracee");
+        tracee.set_name("a-much-longer-thread-name").unwrap();
+        assert_eq!(tracee.name().unwrap(), "a-much-longer-t");
+        let e = tracee.set_name("nul\0").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    fn test_raw_request() {
+        use std::ptr;
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let map = tracee
+            .memory_maps()
+            .unwrap()
+            .into_iter()
+            .find(|m| m.pathname.as_deref() == path.to_str())
+            .expect("Executable not mapped");
+        let mut expected = [0; 8];
+        tracee.read_memory(map.start, &mut expected).unwrap();
+        let word = unsafe {
+            tracee.raw_request(
+                libc::PTRACE_PEEKDATA,
+                map.start as *mut libc::c_void,
+                ptr::null_mut(),
+            )
+        }
+        .expect("Error peeking memory");
+        assert_eq!(word.to_ne_bytes(), expected);
+        let e =
+            unsafe { tracee.raw_request(0xdead, ptr::null_mut(), ptr::null_mut()) }.unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EIO));
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    fn test_preload() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let libc = maps::read_maps(Pid::this())
+            .expect("Error reading maps")
+            .into_iter()
+            .filter_map(|m| m.pathname)
+            .find(|p| p.contains("/libc.so") || p.contains("/libc-"))
+            .expect("libc is not mapped");
+        let libc = std::fs::canonicalize(libc).expect("Error resolving libc");
+
+        let mut options = SpawnOptions::new();
+        options.preload("/no/such/library.so");
+        let e = Command::new(&path).spawn_tracee(options).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        let mut options = SpawnOptions::new();
+        options.preload(&libc);
+        let mut tracee = Command::new(&path)
+            .env("LD_PRELOAD", "existing.so")
+            .spawn_tracee(options)
+            .expect("Error spawning test process");
+        let environ = std::fs::read(format!("/proc/{}/environ", tracee.pid()))
+            .expect("Error reading environment");
+        let expected = format!("LD_PRELOAD={}:existing.so", libc.display());
+        assert!(environ
+            .split(|&b| b == 0)
+            .any(|var| var == expected.as_bytes()));
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+
+        // Spawning the same command again preloads the libraries once.
+        let mut command = Command::new(&path);
+        command.arg("sleep").arg("10");
+        for _ in 0..2 {
+            let mut options = SpawnOptions::new();
+            options.preload(&libc);
+            let mut tracee = command
+                .spawn_tracee(options)
+                .expect("Error spawning test process");
+            let environ = std::fs::read(format!("/proc/{}/environ", tracee.pid()))
+                .expect("Error reading environment");
+            let preload = environ
+                .split(|&b| b == 0)
+                .find_map(|var| var.strip_prefix(b"LD_PRELOAD=".as_ref()))
+                .expect("No LD_PRELOAD");
+            match std::env::var_os("LD_PRELOAD").filter(|v| !v.is_empty()) {
+                Some(inherited) => assert_eq!(
+                    preload,
+                    format!("{}:{}", libc.display(), inherited.to_string_lossy()).as_bytes()
+                ),
+                None => assert_eq!(preload, libc.display().to_string().as_bytes()),
+            }
+            let child = tracee.child_mut().unwrap();
+            child.kill().expect("Error killing child");
+            child.wait().expect("Error waiting for child");
+        }
+    }
+
+    #[test]
+    fn test_scheduling() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut options = SpawnOptions::new();
+        options.scheduler(SchedPolicy::Batch, 0).nice(5);
+        let output = Command::new(&path)
+            .arg("sched")
+            .stdout(Stdio::piped())
+            .spawn_tracee(options)
+            .expect("Error spawning test process")
+            .wait_with_output()
+            .expect("Error collecting output");
+        assert_eq!(
+            output.stdout,
+            format!("{} 5\n", libc::SCHED_BATCH).as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_oom_score_adj() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut options = SpawnOptions::new();
+        options.oom_score_adj(500);
+        let tracee = Command::new(&path)
+            .stdout(Stdio::null())
+            .spawn_tracee(options)
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        let read = || {
+            std::fs::read_to_string(format!("/proc/{}/oom_score_adj", pid))
+                .expect("Error reading oom_score_adj")
+        };
+        assert_eq!(read().trim(), "500");
+        tracee
+            .set_oom_score_adj(1000)
+            .expect("Error setting oom_score_adj");
+        assert_eq!(read().trim(), "1000");
+        ptrace::cont(pid, None).expect("Error continuing child");
+        assert_eq!(waitpid(pid, None), Ok(WaitStatus::Exited(pid, 0)));
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn test_seccomp_filter() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut filter = SeccompFilter::new(SeccompAction::Allow);
+        filter
+            .rule(libc::SYS_getppid, SeccompAction::Errno(libc::EPERM as u16))
+            .rule(libc::SYS_getuid, SeccompAction::Trace(42));
+        let mut options = SpawnOptions::new();
+        options.seccomp_filter(&filter);
+        let mut session = TraceSession::new();
+        let pid = session
+            .spawn(
+                Command::new(&path).arg("seccomp").stdout(Stdio::piped()),
+                options,
+            )
+            .expect("Error spawning test process");
+        let capture = session
+            .get_mut(pid)
+            .and_then(Tracee::capture_output)
+            .expect("No output to capture");
+        let mut traced = 0;
+        let status = loop {
+            ptrace::cont(pid, None).expect("Error continuing child");
+            match session.wait_for(pid).expect("Error waiting for child") {
+                (_, Event::Seccomp(42)) => traced += 1,
+                (_, Event::Exited(code)) => break WaitStatus::Exited(pid, code),
+                (_, event) => panic!("Unexpected event {:?}", event),
+            }
+        };
+        let output = capture.finish(status).expect("Error collecting output");
+        assert_eq!(output.status, WaitStatus::Exited(pid, 0));
+        assert_eq!(traced, 1);
+        assert_eq!(output.stdout, format!("-1 {}\n", libc::EPERM).as_bytes());
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn test_spawn_with_notifier() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut filter = SeccompFilter::new(SeccompAction::Allow);
+        filter
+            .rule(libc::SYS_getppid, SeccompAction::Notify)
+            .rule(libc::SYS_getuid, SeccompAction::Notify);
+        let (child, notifier) = Command::new(&path)
+            .arg("seccomp")
+            .stdout(Stdio::piped())
+            .spawn_with_notifier(SpawnOptions::new(), &filter)
+            .expect("Error spawning test process");
+        let getppid = notifier.recv().expect("Error receiving notification");
+        assert_eq!(getppid.number, libc::SYS_getppid);
+        assert_eq!(getppid.pid.as_raw() as u32, child.id());
+        assert!(!notifier
+            .memory_maps(&getppid)
+            .expect("Error reading maps")
+            .is_empty());
+        notifier
+            .respond(getppid.id, NotifyResponse::Error(libc::EPERM))
+            .expect("Error responding");
+        let getuid = notifier.recv().expect("Error receiving notification");
+        assert_eq!(getuid.number, libc::SYS_getuid);
+        notifier
+            .respond(getuid.id, NotifyResponse::Continue)
+            .expect("Error responding");
+        let output = child.wait_with_output().expect("Error waiting for child");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, format!("-1 {}\n", libc::EPERM).as_bytes());
+    }
+
+    /// Spawn the test process spinning, and stop it once its libc is loaded.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn spawn_running() -> Tracee {
+        let path = test_process_path().expect("Failed to get test process path");
+        let tracee = Command::new(&path)
+            .args(["spin", "5000"])
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        ptrace::cont(pid, None).expect("Error continuing child");
+        // Give the dynamic loader time to map libc.
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        nix::sys::signal::kill(pid, Signal::SIGSTOP).expect("Error stopping child");
+        assert_eq!(
+            waitpid(pid, None),
+            Ok(WaitStatus::Stopped(pid, Signal::SIGSTOP))
+        );
+        tracee
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_call_function() {
+        let mut tracee = spawn_running();
+        let pid = tracee.pid();
+        let regs = tracee.registers().expect("Error reading registers");
+        let getpid = inject::find_function(pid, "getpid")
+            .expect("Error reading symbols")
+            .expect("No getpid");
+        let abs = inject::find_function(pid, "abs")
+            .expect("Error reading symbols")
+            .expect("No abs");
+        assert_eq!(
+            tracee.call_function(getpid, &[]).unwrap(),
+            pid.as_raw() as u64
+        );
+        let result = tracee.call_function(abs, &[-5i64 as u64]).unwrap();
+        assert_eq!(result as u32, 5);
+        let e = tracee.call_function(abs, &[0; 7]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(tracee.registers().unwrap(), regs);
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_run_code() {
+        let mut tracee = spawn_running();
+        let regs = tracee.registers().expect("Error reading registers");
+        // mov rax, 42; push rax; pop rbx
+        let code = [0x48, 0xc7, 0xc0, 0x2a, 0, 0, 0, 0x50, 0x5b];
+        let result = tracee.run_code(&code).expect("Error running code");
+        assert_eq!((result.rax, result.rbx), (42, 42));
+        assert_eq!(result.rsp % 16, 0);
+        assert_eq!(tracee.registers().unwrap(), regs);
+        // ud2
+        assert!(tracee.run_code(&[0x0f, 0x0b]).is_err());
+        assert_eq!(tracee.registers().unwrap(), regs);
+        let maps = tracee.memory_maps().expect("Error reading maps");
+        assert!(!maps.iter().any(|m| m.executable && m.writable));
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_protect_memory() {
+        let mut tracee = spawn_running();
+        let regs = tracee.registers().expect("Error reading registers");
+        let path = test_process_path().expect("Failed to get test process path");
+        let maps = tracee.memory_maps().expect("Error reading maps");
+        let code = maps
+            .iter()
+            .find(|m| m.executable && m.pathname == path.to_str().map(String::from))
+            .expect("No code mapping")
+            .start;
+        let map_at = |tracee: &Tracee, addr| {
+            let maps = tracee.memory_maps().expect("Error reading maps");
+            maps.into_iter()
+                .find(|m| m.start <= addr && addr < m.end)
+                .expect("No mapping")
+        };
+        let rwx = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+        tracee
+            .protect_memory(code + 8..code + 9, rwx)
+            .expect("Error changing protection");
+        let map = map_at(&tracee, code);
+        assert!(map.writable && map.executable);
+        // Only the page holding the range changed.
+        assert_eq!((map.start, map.end), (code, code + 0x1000));
+        tracee
+            .protect_memory(code..code + 1, libc::PROT_READ | libc::PROT_EXEC)
+            .expect("Error changing protection");
+        assert!(!map_at(&tracee, code).writable);
+        assert_eq!(tracee.registers().unwrap(), regs);
+        let e = tracee.protect_memory(0..1, libc::PROT_READ).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOMEM));
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_allocate() {
+        let mut tracee = spawn_running();
+        let regs = tracee.registers().expect("Error reading registers");
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let addr = tracee.allocate(10000, prot).expect("Error allocating");
+        assert_eq!(addr % 0x1000, 0);
+        let maps = tracee.memory_maps().expect("Error reading maps");
+        let map = maps
+            .iter()
+            .find(|m| m.start <= addr && addr < m.end)
+            .expect("No mapping");
+        assert!(map.readable && map.writable && !map.executable);
+        assert!(map.end - addr >= 10000);
+        let mut buf = [1; 16];
+        tracee.read_memory(addr + 9000, &mut buf).unwrap();
+        assert_eq!(buf, [0; 16]);
+        tracee.write_memory(addr, b"hello").unwrap();
+        tracee.free(addr, 10000).expect("Error freeing");
+        let maps = tracee.memory_maps().expect("Error reading maps");
+        assert!(!maps.iter().any(|m| m.start <= addr && addr < m.end));
+        assert_eq!(tracee.registers().unwrap(), regs);
+        let e = tracee.allocate(0, prot).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+
+        let mapped = |tracee: &Tracee, addr| {
+            let maps = tracee.memory_maps().expect("Error reading maps");
+            maps.iter().any(|m| m.start <= addr && addr < m.end)
+        };
+        let scratch = tracee.allocate_scoped(100, prot).expect("Error allocating");
+        let addr = scratch.addr();
+        assert!(mapped(&tracee, addr));
+        drop(scratch);
+        assert!(!mapped(&tracee, addr));
+        let scratch = tracee.allocate_scoped(100, prot).expect("Error allocating");
+        let addr = scratch.leak();
+        assert!(mapped(&tracee, addr));
+        let scratch = tracee.allocate_scoped(100, prot).expect("Error allocating");
+        scratch.free().expect("Error freeing");
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_snapshot_registers() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        let running = session
+            .spawn(
+                Command::new(&path).args(["spin", "5000"]),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let stopped = session
+            .spawn(
+                Command::new(&path).stdout(Stdio::null()),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        ptrace::cont(running, None).expect("Error resuming tracee");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let snapshot = session.snapshot_registers().expect("Error taking snapshot");
+        assert_eq!(snapshot.len(), 2);
+        let regs = session.get(stopped).unwrap().registers().unwrap();
+        assert_eq!(snapshot[&stopped].rip, regs.rip);
+        assert_ne!(snapshot[&running].rip, 0);
+        // The spinning tracee was resumed.
+        assert!(session.get(running).unwrap().registers().is_err());
+        assert_eq!(session.try_wait_for(running).unwrap(), None);
+        for pid in [running, stopped] {
+            tkill(pid, Signal::SIGKILL).expect("Error killing tracee");
+            loop {
+                match session.wait_for(pid).expect("Error waiting for tracee") {
+                    (_, Event::Signaled(..)) => break,
+                    (_, Event::Exited(..)) => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_perf_counters() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut options = SpawnOptions::new();
+        options.perf_counters(&[PerfCounter::TaskClock, PerfCounter::PageFaults]);
+        let mut tracee = Command::new(&path)
+            .args(["spin", "50"])
+            .spawn_tracee(options)
+            .expect("Error spawning test process");
+        let counters = tracee
+            .take_perf_counters()
+            .expect("Tracee has no perf counters");
+        let before = counters.read().expect("Error reading counters");
+        assert_eq!(before.len(), 2);
+        assert_eq!(before[0].counter, PerfCounter::TaskClock);
+        let output = tracee.wait_with_output().expect("Error waiting for tracee");
+        assert!(matches!(output.status, WaitStatus::Exited(_, 0)));
+        let task_clock = counters
+            .get(PerfCounter::TaskClock)
+            .expect("Error reading counters")
+            .unwrap();
+        // The tracee spun for 50ms.
+        assert!(task_clock.count >= 40_000_000);
+        assert!(task_clock.count > before[0].count);
+        assert!(task_clock.scaled() >= task_clock.count);
+        counters.reset().expect("Error resetting counters");
+        assert_eq!(counters.read().unwrap()[0].count, 0);
+
+        // Hardware counters aren't available everywhere.
+        match PerfCounters::open(Pid::this(), &[PerfCounter::Instructions]) {
+            Ok(counters) => assert!(counters.read().unwrap()[0].count > 0),
+            Err(e) => assert!(matches!(Error::from_io(&e), Some(Error::Unsupported(_)))),
+        }
+    }
 }
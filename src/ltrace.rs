@@ -0,0 +1,439 @@
+//! Tracing the calls a program makes to shared library functions, like
+//! `ltrace`.
+
+use crate::elf::Elf;
+use crate::{maps, memory, nix_error, Breakpoints, Event, Resume, TraceSession};
+use nix::sys::ptrace::{self, Options};
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::ops::Range;
+
+/// The most bytes of a string argument that are read.
+const MAX_STRING: usize = 64;
+
+/// How an argument of a known function is decoded.
+#[derive(Clone, Copy, Debug)]
+enum ArgKind {
+    /// A 32-bit `int`, whose register's upper half is undefined.
+    Int,
+    /// A 64-bit integer such as `size_t` or `long`.
+    Long,
+    Pointer,
+    String,
+}
+
+use self::ArgKind::{Int, Long, Pointer, String as Str};
+
+/// The arguments of the common libc functions that calls are decoded for.
+/// Only the fixed arguments of variadic functions are listed.
+const PROTOTYPES: &[(&str, &[ArgKind])] = &[
+    ("abort", &[]),
+    ("access", &[Str, Int]),
+    ("atoi", &[Str]),
+    ("atol", &[Str]),
+    ("calloc", &[Long, Long]),
+    ("chdir", &[Str]),
+    ("close", &[Int]),
+    ("dlopen", &[Str, Int]),
+    ("dlsym", &[Pointer, Str]),
+    ("exit", &[Int]),
+    ("_exit", &[Int]),
+    ("fclose", &[Pointer]),
+    ("fopen", &[Str, Str]),
+    ("fopen64", &[Str, Str]),
+    ("fprintf", &[Pointer, Str]),
+    ("free", &[Pointer]),
+    ("fwrite", &[Pointer, Long, Long, Pointer]),
+    ("getenv", &[Str]),
+    ("malloc", &[Long]),
+    ("memcmp", &[Pointer, Pointer, Long]),
+    ("memcpy", &[Pointer, Pointer, Long]),
+    ("memmove", &[Pointer, Pointer, Long]),
+    ("memset", &[Pointer, Int, Long]),
+    ("mkdir", &[Str, Int]),
+    ("open", &[Str, Int, Int]),
+    ("open64", &[Str, Int, Int]),
+    ("opendir", &[Str]),
+    ("printf", &[Str]),
+    ("putchar", &[Int]),
+    ("puts", &[Str]),
+    ("read", &[Int, Pointer, Long]),
+    ("realloc", &[Pointer, Long]),
+    ("setenv", &[Str, Str, Int]),
+    ("snprintf", &[Pointer, Long, Str]),
+    ("sprintf", &[Pointer, Str]),
+    ("strcat", &[Pointer, Str]),
+    ("strchr", &[Str, Int]),
+    ("strcmp", &[Str, Str]),
+    ("strcpy", &[Pointer, Str]),
+    ("strdup", &[Str]),
+    ("strlen", &[Str]),
+    ("strncmp", &[Str, Str, Long]),
+    ("strncpy", &[Pointer, Str, Long]),
+    ("strrchr", &[Str, Int]),
+    ("strstr", &[Str, Str]),
+    ("strtol", &[Str, Pointer, Int]),
+    ("strtoul", &[Str, Pointer, Int]),
+    ("unlink", &[Str]),
+    ("unsetenv", &[Str]),
+    ("write", &[Int, Pointer, Long]),
+];
+
+/// A decoded argument of a [`LibraryCall`].
+///
+/// [`LibraryCall`]: struct.LibraryCall.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CallArg {
+    /// An integer, sign-extended from 32 bits for an `int`.
+    Int(i64),
+    /// A pointer, including a string pointer that is null or couldn't be
+    /// read.
+    Pointer(u64),
+    /// The string a `char *` argument pointed to when the function was
+    /// called, without its NUL and cut off after 64 bytes.
+    String(Vec<u8>),
+}
+
+/// A call from the program to a library function, recorded by a
+/// [`LibraryTracer`].
+///
+/// [`LibraryTracer`]: struct.LibraryTracer.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LibraryCall {
+    /// The thread that made the call.
+    pub tid: Pid,
+    /// The imported function that was called.
+    pub function: String,
+    /// The arguments, decoded for the common libc functions this knows the
+    /// prototypes of, and empty for others.
+    pub args: Vec<CallArg>,
+    /// The six integer argument registers, for decoding other functions.
+    pub raw_args: [u64; 6],
+    /// The return address of the call, in the program.
+    pub callsite: u64,
+    /// What the function returned in `rax`, or `None` if it hasn't returned,
+    /// like `exit`.
+    pub return_value: Option<u64>,
+}
+
+/// A library call that hasn't returned yet.
+#[derive(Debug)]
+struct PendingCall {
+    /// The index of the call in `LibraryTracer::calls`.
+    index: usize,
+    return_address: u64,
+    /// The stack pointer once the call has returned.
+    stack_pointer: u64,
+}
+
+/// Traces the calls a tracee's program makes to functions imported from
+/// shared libraries, by setting breakpoints on them.
+///
+/// The imported functions are read from the program's dynamic relocations,
+/// which name each function along with the GOT slot the dynamic loader
+/// stores its address in. Once the program reaches its entry point the
+/// loader has filled the slots, and a breakpoint is set wherever each one
+/// points: the function itself, or the program's PLT stub if it is bound
+/// lazily. Only calls whose return address is in the program are recorded,
+/// so calls that libraries make to each other are not.
+///
+/// Functions that libraries import, and those in libraries the program
+/// loads with `dlopen`, are not traced. Forked children have the
+/// breakpoints removed and are detached, and the program run by `exec` is
+/// traced in the same way.
+#[derive(Debug, Default)]
+pub struct LibraryTracer {
+    selected: HashSet<String>,
+    breakpoints: Breakpoints,
+    /// The program's entry point, until it is reached.
+    entry: Option<u64>,
+    /// The address of each import's GOT slot, until the entry point.
+    imports: Vec<(String, u64)>,
+    functions: HashMap<u64, String>,
+    program: Vec<Range<u64>>,
+    pending: HashMap<Pid, Vec<PendingCall>>,
+    calls: Vec<LibraryCall>,
+    threads: HashSet<Pid>,
+    forks: HashSet<Pid>,
+    vforks: HashSet<Pid>,
+    unclassified: HashSet<Pid>,
+}
+
+impl LibraryTracer {
+    /// Create a tracer that traces every imported function.
+    pub fn new() -> LibraryTracer {
+        LibraryTracer::default()
+    }
+
+    /// Trace calls to the function `name`, leaving out other functions
+    /// unless they are added too.
+    pub fn function(&mut self, name: &str) -> &mut LibraryTracer {
+        self.selected.insert(name.to_string());
+        self
+    }
+
+    /// Resume the single tracee in `session`, which must be stopped at its
+    /// initial `exec` trap, and trace its library calls until it exits.
+    ///
+    /// This adds the ptrace options it needs to the session's.
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        let pids: Vec<Pid> = session.pids().collect();
+        let pid = match pids[..] {
+            [pid] => pid,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "LibraryTracer requires a session with exactly one tracee",
+                ))
+            }
+        };
+        let options = Options::PTRACE_O_TRACEEXEC
+            | Options::PTRACE_O_TRACECLONE
+            | Options::PTRACE_O_TRACEFORK
+            | Options::PTRACE_O_TRACEVFORK;
+        session.add_ptrace_options(options)?;
+        self.prepare(pid)?;
+        session.run_with(|session, pid, event| self.handle_event(session, pid, event))
+    }
+
+    /// The calls recorded so far, in the order they were made.
+    pub fn calls(&self) -> &[LibraryCall] {
+        &self.calls
+    }
+
+    /// Find the program's imports, and set a breakpoint on its entry point
+    /// to resolve them once the dynamic loader has.
+    fn prepare(&mut self, pid: Pid) -> io::Result<()> {
+        let exe_link = format!("/proc/{}/exe", pid);
+        let exe = fs::read_link(&exe_link)?;
+        let data = fs::read(&exe_link)?;
+        let elf = Elf::parse(&data)?;
+        let maps = maps::read_maps(pid)?;
+        let exe_maps: Vec<_> = maps
+            .iter()
+            .filter(|m| m.pathname.as_deref() == exe.to_str())
+            .collect();
+        let bias = exe_maps
+            .iter()
+            .find(|m| m.offset == 0)
+            .map(|m| m.start.wrapping_sub(elf.first_load_delta().unwrap_or(0)))
+            .ok_or_else(|| io::Error::other("Executable is not mapped"))?;
+        self.program = exe_maps.iter().map(|m| m.start..m.end).collect();
+        self.imports = elf
+            .imports()?
+            .into_iter()
+            .filter(|i| self.selected.is_empty() || self.selected.contains(&i.name))
+            .map(|i| (i.name, bias.wrapping_add(i.got)))
+            .collect();
+        let entry = bias.wrapping_add(elf.entry()?);
+        self.breakpoints.insert(pid, entry)?;
+        self.entry = Some(entry);
+        Ok(())
+    }
+
+    /// Set breakpoints wherever the program's GOT slots point, now that the
+    /// dynamic loader has filled them.
+    fn resolve(&mut self, pid: Pid) -> io::Result<()> {
+        for (name, got) in self.imports.drain(..) {
+            let addr = memory::read_u64(pid, got)?;
+            // Weak imports that nothing defines are left null.
+            if addr == 0 || self.functions.contains_key(&addr) {
+                continue;
+            }
+            self.breakpoints.insert(pid, addr)?;
+            self.functions.insert(addr, name);
+        }
+        Ok(())
+    }
+
+    fn handle_event(
+        &mut self,
+        session: &mut TraceSession,
+        pid: Pid,
+        event: Event,
+    ) -> io::Result<Option<Resume>> {
+        match event {
+            Event::Signal(Signal::SIGTRAP) => match self.breakpoints.hit(pid)? {
+                Some(addr) => self.handle_breakpoint(session, pid, addr),
+                None => Ok(Some(Resume::Continue(Some(Signal::SIGTRAP)))),
+            },
+            Event::Signal(signal) => Ok(Some(Resume::Continue(Some(signal)))),
+            Event::Clone(child) | Event::Fork(child) | Event::Vfork(child) => {
+                match event {
+                    Event::Clone(_) => self.threads.insert(child),
+                    Event::Fork(_) => self.forks.insert(child),
+                    _ => self.vforks.insert(child),
+                };
+                if self.unclassified.remove(&child) {
+                    let decision = self.handle_new_tracee(child)?;
+                    session.resume_as(child, decision)?;
+                }
+                Ok(Some(Resume::Continue(None)))
+            }
+            Event::Attached => {
+                if self.threads.contains(&pid)
+                    || self.forks.contains(&pid)
+                    || self.vforks.contains(&pid)
+                {
+                    self.handle_new_tracee(pid).map(Some)
+                } else {
+                    // Wait for the parent's event to learn what this is.
+                    self.unclassified.insert(pid);
+                    Ok(None)
+                }
+            }
+            Event::Exec(_) if self.vforks.remove(&pid) => {
+                // The vfork child no longer shares its parent's memory.
+                Ok(Some(Resume::Detach(None)))
+            }
+            Event::Exec(_) => {
+                // The old address space, and every breakpoint in it, is gone.
+                self.breakpoints.forget_all();
+                self.functions.clear();
+                self.pending.clear();
+                self.prepare(pid)?;
+                Ok(Some(Resume::Continue(None)))
+            }
+            Event::Exited(_) | Event::Signaled(..) => {
+                self.pending.remove(&pid);
+                self.threads.remove(&pid);
+                self.vforks.remove(&pid);
+                Ok(None)
+            }
+            _ => Ok(Some(Resume::Continue(None))),
+        }
+    }
+
+    /// How to resume the new tracee `pid`, once it is known what it is.
+    fn handle_new_tracee(&mut self, pid: Pid) -> io::Result<Resume> {
+        if self.forks.remove(&pid) {
+            // A forked child has its own copy of the breakpoints, which would
+            // kill it once it is no longer traced.
+            self.breakpoints.clear_from(pid)?;
+            return Ok(Resume::Detach(None));
+        }
+        // Threads and vfork children share the tracee's memory, so they are
+        // traced like the tracee itself.
+        Ok(Resume::Continue(None))
+    }
+
+    fn handle_breakpoint(
+        &mut self,
+        session: &mut TraceSession,
+        pid: Pid,
+        addr: u64,
+    ) -> io::Result<Option<Resume>> {
+        if self.entry == Some(addr) {
+            self.entry = None;
+            self.breakpoints.remove(pid, addr)?;
+            self.resolve(pid)?;
+        }
+        let regs = ptrace::getregs(pid).map_err(nix_error)?;
+        if let Some(function) = self.functions.get(&addr) {
+            let return_address = memory::read_u64(pid, regs.rsp)?;
+            if self.program.iter().any(|r| r.contains(&return_address)) {
+                let raw_args = [regs.rdi, regs.rsi, regs.rdx, regs.rcx, regs.r8, regs.r9];
+                let call = LibraryCall {
+                    tid: pid,
+                    function: function.clone(),
+                    args: decode_args(pid, function, &raw_args),
+                    raw_args,
+                    callsite: return_address,
+                    return_value: None,
+                };
+                self.breakpoints.insert(pid, return_address)?;
+                self.pending.entry(pid).or_default().push(PendingCall {
+                    index: self.calls.len(),
+                    return_address,
+                    stack_pointer: regs.rsp + 8,
+                });
+                self.calls.push(call);
+            }
+        }
+        let calls = self.pending.entry(pid).or_default();
+        if let Some(i) = calls
+            .iter()
+            .rposition(|c| c.return_address == addr && c.stack_pointer == regs.rsp)
+        {
+            let call = calls.remove(i);
+            self.breakpoints.remove(pid, addr)?;
+            self.calls[call.index].return_value = Some(regs.rax);
+        }
+        if !self.breakpoints.contains(addr) {
+            return Ok(Some(Resume::Continue(None)));
+        }
+        match self.breakpoints.step_over(session, pid)? {
+            Event::Signal(Signal::SIGTRAP) => Ok(Some(Resume::Continue(None))),
+            event => self.handle_event(session, pid, event),
+        }
+    }
+}
+
+/// Decode the arguments of a call to `function`, if its prototype is known.
+fn decode_args(pid: Pid, function: &str, raw_args: &[u64; 6]) -> Vec<CallArg> {
+    let kinds = match PROTOTYPES.iter().find(|&&(name, _)| name == function) {
+        Some(&(_, kinds)) => kinds,
+        None => return vec![],
+    };
+    kinds
+        .iter()
+        .zip(raw_args)
+        .map(|(kind, &raw)| match kind {
+            Int => CallArg::Int(raw as i32 as i64),
+            Long => CallArg::Int(raw as i64),
+            Pointer => CallArg::Pointer(raw),
+            Str if raw == 0 => CallArg::Pointer(0),
+            Str => match memory::read_cstring(pid, raw, MAX_STRING) {
+                Ok(bytes) => CallArg::String(bytes),
+                Err(_) => CallArg::Pointer(raw),
+            },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_library_tracer() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session
+            .spawn(
+                Command::new(&path)
+                    .arg("libcalls")
+                    .env("SPAWN_PTRACE_LIBCALL", "yes")
+                    .stdout(Stdio::null()),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut tracer = LibraryTracer::new();
+        tracer.function("getenv").function("strlen");
+        tracer
+            .run(&mut session)
+            .expect("Error tracing library calls");
+        let calls = tracer.calls();
+        assert!(calls
+            .iter()
+            .all(|c| c.function == "getenv" || c.function == "strlen"));
+        let getenv = calls
+            .iter()
+            .find(|c| c.args == [CallArg::String(b"SPAWN_PTRACE_LIBCALL".to_vec())])
+            .expect("No getenv call");
+        assert_eq!(getenv.function, "getenv");
+        assert!(getenv.return_value.is_some_and(|v| v != 0));
+        let strlen = calls
+            .iter()
+            .find(|c| c.args == [CallArg::String(b"hello".to_vec())])
+            .expect("No strlen call");
+        assert_eq!(strlen.function, "strlen");
+        assert_eq!(strlen.return_value, Some(5));
+    }
+}
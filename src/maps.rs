@@ -0,0 +1,114 @@
+use nix::unistd::Pid;
+use std::fs;
+use std::io;
+
+/// A single memory mapping of a tracee, from `/proc/<pid>/maps`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryMap {
+    /// The start address of the mapping.
+    pub start: u64,
+    /// The address just past the end of the mapping.
+    pub end: u64,
+    /// Whether the mapping is readable.
+    pub readable: bool,
+    /// Whether the mapping is writable.
+    pub writable: bool,
+    /// Whether the mapping is executable.
+    pub executable: bool,
+    /// Whether the mapping is shared rather than private.
+    pub shared: bool,
+    /// The offset into the mapped file.
+    pub offset: u64,
+    /// The inode of the mapped file, or 0 for anonymous mappings.
+    pub inode: u64,
+    /// The mapped file, or a pseudo-path such as `[heap]` or `[vdso]`.
+    pub pathname: Option<String>,
+}
+
+impl MemoryMap {
+    /// Whether `addr` lies within this mapping.
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Parse a line of `/proc/<pid>/maps`.
+    fn parse(line: &str) -> Option<MemoryMap> {
+        let mut fields = line.splitn(6, ' ');
+        let mut range = fields.next()?.splitn(2, '-');
+        let start = u64::from_str_radix(range.next()?, 16).ok()?;
+        let end = u64::from_str_radix(range.next()?, 16).ok()?;
+        let perms = fields.next()?.as_bytes();
+        if perms.len() != 4 {
+            return None;
+        }
+        let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+        let _device = fields.next()?;
+        let inode = fields.next()?.parse().ok()?;
+        let pathname = fields
+            .next()
+            .map(|p| p.trim_start().to_owned())
+            .filter(|p| !p.is_empty());
+        Some(MemoryMap {
+            start,
+            end,
+            readable: perms[0] == b'r',
+            writable: perms[1] == b'w',
+            executable: perms[2] == b'x',
+            shared: perms[3] == b's',
+            offset,
+            inode,
+            pathname,
+        })
+    }
+}
+
+/// Read the memory mappings of process `pid`.
+pub(crate) fn read_maps(pid: Pid) -> io::Result<Vec<MemoryMap>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    maps.lines()
+        .map(|line| {
+            MemoryMap::parse(line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Malformed memory map line")
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let map = MemoryMap::parse(
+            "7f1c2a000000-7f1c2a028000 r-xp 00001000 fd:01 1316150                    /usr/lib/x86_64-linux-gnu/libc.so.6",
+        )
+        .expect("Failed to parse map");
+        assert_eq!(
+            map,
+            MemoryMap {
+                start: 0x7f1c2a000000,
+                end: 0x7f1c2a028000,
+                readable: true,
+                writable: false,
+                executable: true,
+                shared: false,
+                offset: 0x1000,
+                inode: 1316150,
+                pathname: Some("/usr/lib/x86_64-linux-gnu/libc.so.6".to_owned()),
+            }
+        );
+        let anon = MemoryMap::parse("7ffd1000-7ffd3000 rw-s 00000000 00:00 0 ")
+            .expect("Failed to parse map");
+        assert!(anon.shared && anon.pathname.is_none());
+        assert!(anon.contains(0x7ffd1000) && !anon.contains(0x7ffd3000));
+        assert_eq!(MemoryMap::parse("garbage"), None);
+    }
+
+    #[test]
+    fn test_read_maps() {
+        let maps = read_maps(Pid::this()).expect("Failed to read maps");
+        let here = test_read_maps as *const () as u64;
+        assert!(maps.iter().any(|m| m.contains(here) && m.executable));
+    }
+}
@@ -0,0 +1,287 @@
+//! Batched access to tracee memory through a page cache.
+
+use crate::memory::{self, MemFile};
+use nix::sys::uio::{IoVec, RemoteIoVec};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::io;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The most iovecs the kernel accepts in one `process_vm_readv` call.
+const IOV_MAX: usize = 1024;
+
+/// A cache of a stopped tracee's memory, for tools that make many small
+/// reads and writes, such as ones managing lots of breakpoints.
+///
+/// Reads are served from whole pages that are fetched on first use, and
+/// every page a read is missing is fetched with a single
+/// `process_vm_readv`, falling back to `/proc/<pid>/mem` for pages it
+/// can't read, such as code mapped without read permission. Writes are
+/// applied to the cached pages straight away, so later reads see them, but
+/// are only made to the tracee by [`flush`], which merges overlapping and
+/// adjacent writes into one transfer each.
+///
+/// The cache only stays valid while the tracee is stopped. Call [`flush`]
+/// before resuming it, and [`invalidate`] once it has run, since it may
+/// have changed its memory in the meantime.
+///
+/// [`flush`]: #method.flush
+/// [`invalidate`]: #method.invalidate
+#[derive(Debug)]
+pub struct MemoryCache {
+    pid: Pid,
+    pages: HashMap<u64, Box<[u8]>>,
+    /// Writes not yet made to the tracee, in the order they were made.
+    pending: Vec<(u64, Vec<u8>)>,
+    mem: MemFile,
+    /// The number of system calls made to fetch pages.
+    fetches: usize,
+}
+
+impl MemoryCache {
+    /// Create an empty cache of the memory of tracee `pid`.
+    pub fn new(pid: Pid) -> MemoryCache {
+        MemoryCache {
+            pid,
+            pages: HashMap::new(),
+            pending: vec![],
+            mem: MemFile::default(),
+            fetches: 0,
+        }
+    }
+
+    /// Read `buf.len()` bytes starting at `addr`, including any writes that
+    /// haven't been flushed.
+    pub fn read(&mut self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.read_many(&mut [(addr, buf)])
+    }
+
+    /// Make several reads, fetching all the pages they need at once.
+    pub fn read_many(&mut self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        let mut missing: Vec<u64> = reads
+            .iter()
+            .flat_map(|(addr, buf)| pages(*addr, buf.len()))
+            .filter(|page| !self.pages.contains_key(page))
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        self.fetch(&missing)?;
+        for (addr, buf) in reads.iter_mut() {
+            let mut done = 0;
+            while done < buf.len() {
+                let at = *addr + done as u64;
+                let page = at & !(PAGE_SIZE - 1);
+                let offset = (at - page) as usize;
+                let n = (PAGE_SIZE as usize - offset).min(buf.len() - done);
+                buf[done..done + n].copy_from_slice(&self.pages[&page][offset..offset + n]);
+                done += n;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `data` starting at `addr`.
+    ///
+    /// The write is visible to reads from the cache immediately, and is
+    /// made to the tracee by the next [`flush`].
+    ///
+    /// [`flush`]: #method.flush
+    pub fn write(&mut self, addr: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        for page in pages(addr, data.len()) {
+            if let Some(contents) = self.pages.get_mut(&page) {
+                overlay(page, contents, addr, data);
+            }
+        }
+        self.pending.push((addr, data.to_vec()));
+    }
+
+    /// Make every pending write to the tracee, merging overlapping and
+    /// adjacent ones so each run of bytes is written once.
+    ///
+    /// Writes go through `/proc/<pid>/mem` where possible, so like
+    /// `Tracee::write_memory` they can patch read-only code.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let mut runs: Vec<(u64, u64)> = self
+            .pending
+            .iter()
+            .map(|(addr, data)| (*addr, *addr + data.len() as u64))
+            .collect();
+        runs.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for (start, end) in runs {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        let mut buffers: Vec<Vec<u8>> = merged
+            .iter()
+            .map(|(start, end)| vec![0; (end - start) as usize])
+            .collect();
+        // Later writes win where they overlap, as they would have in memory.
+        for (addr, data) in &self.pending {
+            let run = merged.partition_point(|&(_, end)| end <= *addr);
+            let offset = (addr - merged[run].0) as usize;
+            buffers[run][offset..offset + data.len()].copy_from_slice(data);
+        }
+        for (&(start, _), buffer) in merged.iter().zip(&buffers) {
+            self.mem.write(self.pid, start, buffer)?;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Forget every cached page, so that the next reads fetch them again.
+    ///
+    /// Pending writes are kept, and are applied to the pages fetched again.
+    pub fn invalidate(&mut self) {
+        self.pages.clear();
+    }
+
+    /// Fetch the sorted, distinct `pages` into the cache.
+    fn fetch(&mut self, pages: &[u64]) -> io::Result<()> {
+        // Each run of consecutive pages is read into one buffer.
+        let mut runs: Vec<(u64, Vec<u8>)> = vec![];
+        for &page in pages {
+            match runs.last_mut() {
+                Some((start, buf)) if *start + buf.len() as u64 == page => {
+                    buf.resize(buf.len() + PAGE_SIZE as usize, 0)
+                }
+                _ => runs.push((page, vec![0; PAGE_SIZE as usize])),
+            }
+        }
+        for chunk in runs.chunks_mut(IOV_MAX) {
+            let remote: Vec<RemoteIoVec> = chunk
+                .iter()
+                .map(|(start, buf)| RemoteIoVec {
+                    base: *start as usize,
+                    len: buf.len(),
+                })
+                .collect();
+            let mut done = {
+                let local: Vec<IoVec<&mut [u8]>> = chunk
+                    .iter_mut()
+                    .map(|(_, buf)| IoVec::from_mut_slice(&mut buf[..]))
+                    .collect();
+                memory::readv(self.pid, &local, &remote)
+            };
+            self.fetches += 1;
+            // The kernel stops at the first page it can't read, so the
+            // rest of the chunk is read separately with what can.
+            for (start, buf) in chunk.iter_mut() {
+                let read = done.min(buf.len());
+                done -= read;
+                if read < buf.len() {
+                    self.mem
+                        .read(self.pid, *start + read as u64, &mut buf[read..])?;
+                    self.fetches += 1;
+                }
+            }
+        }
+        for (start, buf) in runs {
+            for (i, contents) in buf.chunks(PAGE_SIZE as usize).enumerate() {
+                let page = start + i as u64 * PAGE_SIZE;
+                let mut contents: Box<[u8]> = contents.into();
+                for (addr, data) in &self.pending {
+                    overlay(page, &mut contents, *addr, data);
+                }
+                self.pages.insert(page, contents);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The addresses of the pages covering `len` bytes at `addr`.
+fn pages(addr: u64, len: usize) -> impl Iterator<Item = u64> {
+    let first = addr & !(PAGE_SIZE - 1);
+    let end = addr + len as u64;
+    (0..)
+        .map(move |i| first + i * PAGE_SIZE)
+        .take_while(move |&page| page < end)
+}
+
+/// Copy the part of the write of `data` at `addr` that falls in the page at
+/// `page` into its `contents`.
+fn overlay(page: u64, contents: &mut [u8], addr: u64, data: &[u8]) {
+    let start = addr.max(page);
+    let end = (addr + data.len() as u64).min(page + PAGE_SIZE);
+    if start < end {
+        contents[(start - page) as usize..(end - page) as usize]
+            .copy_from_slice(&data[(start - addr) as usize..(end - addr) as usize]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::process::Command;
+
+    #[test]
+    fn test_memory_cache() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let rip = tracee.registers().expect("Error reading registers").rip;
+        let page = rip & !(PAGE_SIZE - 1);
+        let mut expected = vec![0; 2 * PAGE_SIZE as usize];
+        tracee
+            .read_memory(page, &mut expected)
+            .expect("Error reading memory");
+        let mut cache = tracee.memory_cache();
+        // Many reads across two pages cost a single system call.
+        let (mut a, mut b, mut c) = ([0; 8], [0; 8], [0; 8]);
+        cache
+            .read_many(&mut [
+                (page + 8, &mut a[..]),
+                (page + PAGE_SIZE - 4, &mut b[..]),
+                (page + PAGE_SIZE + 100, &mut c[..]),
+            ])
+            .expect("Error reading cache");
+        assert_eq!(cache.fetches, 1);
+        assert_eq!(a, expected[8..16]);
+        assert_eq!(b, expected[PAGE_SIZE as usize - 4..PAGE_SIZE as usize + 4]);
+        for offset in (0..2 * PAGE_SIZE).step_by(64) {
+            let mut word = [0; 8];
+            cache.read(page + offset, &mut word).expect("Error reading");
+            let offset = offset as usize;
+            assert_eq!(word, expected[offset..offset + 8]);
+        }
+        assert_eq!(cache.fetches, 1);
+
+        // Overlapping writes are seen at once, and the later one wins.
+        cache.write(rip, &[0xcc, 0xcc]);
+        cache.write(rip + 1, &[0x90, 0x90]);
+        let mut buf = [0; 3];
+        cache.read(rip, &mut buf).unwrap();
+        assert_eq!(buf, [0xcc, 0x90, 0x90]);
+        let mut actual = [0; 3];
+        tracee.read_memory(rip, &mut actual).unwrap();
+        assert_eq!(&actual[..], &expected[(rip - page) as usize..][..3]);
+        cache.flush().expect("Error flushing writes");
+        tracee.read_memory(rip, &mut actual).unwrap();
+        assert_eq!(actual, [0xcc, 0x90, 0x90]);
+
+        // Pages fetched again after invalidating see the tracee's memory.
+        tracee.write_memory(rip, &[0xf4]).unwrap();
+        cache.read(rip, &mut buf).unwrap();
+        assert_eq!(buf[0], 0xcc);
+        cache.invalidate();
+        cache.read(rip, &mut buf).unwrap();
+        assert_eq!(buf, [0xf4, 0x90, 0x90]);
+        assert_eq!(cache.fetches, 2);
+
+        let e = cache.read(0, &mut buf).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EIO));
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
@@ -0,0 +1,123 @@
+//! Executables that only exist in memory.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The seals that keep anyone from changing the image once it is written.
+const SEALS: libc::c_int =
+    libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+
+/// An executable image held in a sealed `memfd`, for spawning programs that
+/// were generated in memory, such as by a fuzzer or a JIT test suite,
+/// without writing them to the filesystem.
+///
+/// [`command`] returns a `Command` that runs the image, which can be spawned
+/// with [`spawn_tracee`] like any other. The image is executed through
+/// `/proc/self/fd`, as `fexecve(3)` does, so `/proc` must be mounted in the
+/// child: this doesn't work with [`SpawnOptions::chroot`] or
+/// [`SpawnOptions::pivot_root`] into a root without it. The image must be
+/// an ELF executable, since the `memfd` is closed on `exec`, before a
+/// script's interpreter could open it.
+///
+/// In the tracee, `/proc/<pid>/exe` and the image's mappings in
+/// `/proc/<pid>/maps` are named `/memfd:<name> (deleted)`.
+///
+/// [`command`]: #method.command
+/// [`spawn_tracee`]: trait.CommandPtraceSpawn.html#tymethod.spawn_tracee
+/// [`SpawnOptions::chroot`]: struct.SpawnOptions.html#method.chroot
+/// [`SpawnOptions::pivot_root`]: struct.SpawnOptions.html#method.pivot_root
+#[derive(Debug)]
+pub struct MemoryExecutable {
+    file: File,
+    name: String,
+}
+
+impl MemoryExecutable {
+    /// Copy `image` into a new `memfd` named `name`, and seal it.
+    ///
+    /// This requires Linux 3.17 or newer.
+    pub fn new(name: &str, image: &[u8]) -> io::Result<MemoryExecutable> {
+        let c_name = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL"))?;
+        let flags = libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING;
+        let fd = unsafe { libc::memfd_create(c_name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(image)?;
+        if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, SEALS) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(MemoryExecutable {
+            file,
+            name: name.to_owned(),
+        })
+    }
+
+    /// The path the image is executed through, which is only valid in this
+    /// process and its children until it execs.
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/self/fd/{}", self.file.as_raw_fd()))
+    }
+
+    /// A `Command` that runs the image, with `argv[0]` set to its name.
+    ///
+    /// The image must outlive the spawn, but can be dropped as soon as the
+    /// child has exec'd, which it has once [`spawn_tracee`] returns.
+    ///
+    /// [`spawn_tracee`]: trait.CommandPtraceSpawn.html#tymethod.spawn_tracee
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(self.path());
+        command.arg0(&self.name);
+        command
+    }
+}
+
+impl AsFd for MemoryExecutable {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl AsRawFd for MemoryExecutable {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use nix::sys::wait::WaitStatus;
+    use std::fs;
+    use std::process::Stdio;
+
+    #[test]
+    fn test_memory_executable() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let image = fs::read(&path).expect("Error reading test process");
+        let exe = MemoryExecutable::new("test", &image).expect("Error creating memfd");
+        // The seals keep the image from changing.
+        assert!((&exe.file).write_all(b"\x7fELF").is_err());
+        let mut command = exe.command();
+        let tracee = command
+            .stdout(Stdio::piped())
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        let link = fs::read_link(format!("/proc/{}/exe", pid)).unwrap();
+        assert_eq!(link.to_str(), Some("/memfd:test (deleted)"));
+        drop(exe);
+        let output = tracee.wait_with_output().expect("Error collecting output");
+        assert_eq!(output.status, WaitStatus::Exited(pid, 0));
+        assert_eq!(output.stdout, b"hello\n");
+    }
+}
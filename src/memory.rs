@@ -0,0 +1,433 @@
+use crate::fallback::{self, Fallback};
+use crate::nix_error;
+use nix::errno::Errno;
+use nix::sys::ptrace;
+use nix::sys::uio::{process_vm_readv, IoVec, RemoteIoVec};
+use nix::unistd::Pid;
+use std::ffi::c_void;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileExt;
+
+const WORD_SIZE: usize = mem::size_of::<libc::c_long>();
+
+/// Reads at least this long go through `/proc/<pid>/mem`. Shorter ones are
+/// just as cheap with `process_vm_readv`.
+const PROC_MEM_MIN_READ: usize = 4096;
+
+const PAGE_SIZE: usize = 4096;
+
+/// How tracee memory was last accessed, for diagnostics.
+///
+/// See [`Tracee::memory_strategy`].
+///
+/// [`Tracee::memory_strategy`]: struct.Tracee.html#method.memory_strategy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryStrategy {
+    /// `pread` or `pwrite` on `/proc/<pid>/mem`.
+    ProcMem,
+    /// `process_vm_readv`.
+    ProcessVm,
+    /// `PTRACE_PEEKDATA` and `PTRACE_POKEDATA`, a word at a time.
+    Ptrace,
+}
+
+/// A tracee's `/proc/<pid>/mem`, opened on first use, along with the
+/// strategy that was last used to access its memory.
+#[derive(Debug, Default)]
+pub(crate) struct MemFile {
+    file: Option<File>,
+    unavailable: bool,
+    last: Option<MemoryStrategy>,
+}
+
+impl MemFile {
+    /// The strategy that completed the last transfer.
+    pub(crate) fn last_strategy(&self) -> Option<MemoryStrategy> {
+        self.last
+    }
+
+    /// Read tracee memory, preferring `/proc/<pid>/mem` for large reads and
+    /// falling back to [`read`].
+    pub(crate) fn read(&mut self, pid: Pid, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        let done = if buf.len() >= PROC_MEM_MIN_READ {
+            self.transfer(pid, buf.len(), |file, done| {
+                file.read_at(&mut buf[done..], addr + done as u64)
+            })
+        } else {
+            0
+        };
+        self.last = Some(if done == buf.len() {
+            MemoryStrategy::ProcMem
+        } else {
+            read(pid, addr + done as u64, &mut buf[done..])?
+        });
+        Ok(())
+    }
+
+    /// Write tracee memory, preferring `/proc/<pid>/mem` and falling back to
+    /// [`write`].
+    pub(crate) fn write(&mut self, pid: Pid, addr: u64, data: &[u8]) -> io::Result<()> {
+        let done = self.transfer(pid, data.len(), |file, done| {
+            file.write_at(&data[done..], addr + done as u64)
+        });
+        if done < data.len() {
+            write(pid, addr + done as u64, &data[done..])?;
+            self.last = Some(MemoryStrategy::Ptrace);
+        } else {
+            self.last = Some(MemoryStrategy::ProcMem);
+        }
+        Ok(())
+    }
+
+    /// Move up to `len` bytes with `op`, which is given the file and the
+    /// number of bytes already moved, returning how many were moved before
+    /// `op` failed.
+    ///
+    /// Once the tracee has called `exec`, the descriptor refers to memory
+    /// that no longer exists and every transfer returns 0, so it is reopened
+    /// once in that case.
+    fn transfer<F>(&mut self, pid: Pid, len: usize, mut op: F) -> usize
+    where
+        F: FnMut(&File, usize) -> io::Result<usize>,
+    {
+        let mut done = 0;
+        let mut reopened = false;
+        while done < len {
+            let file = match self.file(pid) {
+                Some(file) => file,
+                None => break,
+            };
+            match op(file, done) {
+                Ok(0) if done == 0 && !reopened => {
+                    self.file = None;
+                    reopened = true;
+                }
+                Ok(0) | Err(_) => break,
+                Ok(n) => done += n,
+            }
+        }
+        done
+    }
+
+    fn file(&mut self, pid: Pid) -> Option<&File> {
+        if self.file.is_none() && !self.unavailable {
+            let path = format!("/proc/{}/mem", pid);
+            match OpenOptions::new().read(true).write(true).open(path) {
+                Ok(file) => self.file = Some(file),
+                Err(_) => self.unavailable = true,
+            }
+        }
+        self.file.as_ref()
+    }
+}
+
+/// Read `buf.len()` bytes from the memory of tracee `pid`, starting at `addr`.
+///
+/// This uses `process_vm_readv` where possible, falling back to
+/// `PTRACE_PEEKDATA` for anything it couldn't read, and returns the strategy
+/// that finished the read.
+pub(crate) fn read(pid: Pid, addr: u64, buf: &mut [u8]) -> io::Result<MemoryStrategy> {
+    if buf.is_empty() {
+        return Ok(MemoryStrategy::ProcessVm);
+    }
+    let remote = [RemoteIoVec {
+        base: addr as usize,
+        len: buf.len(),
+    }];
+    let done = readv(pid, &[IoVec::from_mut_slice(buf)], &remote);
+    if done < buf.len() {
+        read_peek(pid, addr + done as u64, &mut buf[done..])?;
+        return Ok(MemoryStrategy::Ptrace);
+    }
+    Ok(MemoryStrategy::ProcessVm)
+}
+
+/// Read from the memory of tracee `pid` into `local` with
+/// `process_vm_readv`, returning how many bytes were read, which is 0 if it
+/// failed.
+///
+/// Kernels without `process_vm_readv` are remembered, so that it isn't
+/// tried again and the callers only read with their ptrace fallback.
+pub(crate) fn readv(pid: Pid, local: &[IoVec<&mut [u8]>], remote: &[RemoteIoVec]) -> usize {
+    if fallback::used(Fallback::PeekData) {
+        return 0;
+    }
+    match process_vm_readv(pid, local, remote) {
+        Ok(done) => done,
+        Err(nix::Error::Sys(Errno::ENOSYS)) => {
+            fallback::record(Fallback::PeekData);
+            0
+        }
+        Err(_) => 0,
+    }
+}
+
+/// Read `buf.len()` bytes from the memory of process `pid` with
+/// `process_vm_readv` alone, which doesn't require the process to be traced.
+pub(crate) fn read_vm(pid: Pid, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+    let len = buf.len();
+    let remote = [RemoteIoVec {
+        base: addr as usize,
+        len,
+    }];
+    let done = process_vm_readv(pid, &[IoVec::from_mut_slice(buf)], &remote).map_err(nix_error)?;
+    if done < len {
+        return Err(io::Error::from_raw_os_error(libc::EFAULT));
+    }
+    Ok(())
+}
+
+/// Read tracee memory a word at a time with `PTRACE_PEEKDATA`.
+pub(crate) fn read_peek(pid: Pid, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+    // Peeks must be word-aligned, so start at the word containing `addr`.
+    let offset = (addr % WORD_SIZE as u64) as usize;
+    let mut word_addr = addr - offset as u64;
+    let mut skip = offset;
+    let mut filled = 0;
+    while filled < buf.len() {
+        let word = ptrace::read(pid, word_addr as *mut c_void).map_err(nix_error)?;
+        let bytes = word.to_ne_bytes();
+        let n = (WORD_SIZE - skip).min(buf.len() - filled);
+        buf[filled..filled + n].copy_from_slice(&bytes[skip..skip + n]);
+        filled += n;
+        skip = 0;
+        word_addr += WORD_SIZE as u64;
+    }
+    Ok(())
+}
+
+/// Write `data` to the memory of tracee `pid`, starting at `addr`.
+///
+/// This uses `PTRACE_POKEDATA`, which can write to read-only mappings such
+/// as code. Pokes always write a whole aligned word, so words that are only
+/// partially covered by `data`, at an unaligned start or a short end, are
+/// read first and only the covered bytes are replaced, leaving the
+/// neighbouring bytes as they were.
+pub(crate) fn write(pid: Pid, addr: u64, data: &[u8]) -> io::Result<()> {
+    let offset = (addr % WORD_SIZE as u64) as usize;
+    let mut word_addr = addr - offset as u64;
+    let mut skip = offset;
+    let mut written = 0;
+    while written < data.len() {
+        let n = (WORD_SIZE - skip).min(data.len() - written);
+        let mut bytes = [0; WORD_SIZE];
+        if n < WORD_SIZE {
+            bytes = ptrace::read(pid, word_addr as *mut c_void)
+                .map_err(nix_error)?
+                .to_ne_bytes();
+        }
+        bytes[skip..skip + n].copy_from_slice(&data[written..written + n]);
+        let word = libc::c_long::from_ne_bytes(bytes);
+        unsafe {
+            ptrace::write(pid, word_addr as *mut c_void, word as *mut c_void).map_err(nix_error)?;
+        }
+        written += n;
+        skip = 0;
+        word_addr += WORD_SIZE as u64;
+    }
+    Ok(())
+}
+
+/// Read the NUL-terminated string at `addr` in the memory of tracee `pid`,
+/// up to `max_len` bytes of it, without the NUL.
+///
+/// The string is read a page at a time, so only the pages it covers are
+/// touched. If it runs into memory that can't be read before a NUL, what
+/// was read so far is returned, unless that is nothing.
+pub(crate) fn read_cstring(pid: Pid, addr: u64, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    while bytes.len() < max_len {
+        let at = addr + bytes.len() as u64;
+        let to_page_end = PAGE_SIZE - (at as usize % PAGE_SIZE);
+        let mut chunk = vec![0; to_page_end.min(max_len - bytes.len())];
+        match read(pid, at, &mut chunk) {
+            Ok(_) => {}
+            Err(e) if bytes.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+        match chunk.iter().position(|&b| b == 0) {
+            Some(nul) => {
+                bytes.extend_from_slice(&chunk[..nul]);
+                break;
+            }
+            None => bytes.extend_from_slice(&chunk),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Read up to `max_len` bytes at `addr` in the memory of tracee `pid`, a
+/// page at a time, stopping at the first page that can't be read.
+///
+/// What was read before that page is returned, unless that is nothing.
+pub(crate) fn read_available(pid: Pid, addr: u64, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    while bytes.len() < max_len {
+        let at = addr.wrapping_add(bytes.len() as u64);
+        let to_page_end = PAGE_SIZE - (at as usize % PAGE_SIZE);
+        let mut chunk = vec![0; to_page_end.min(max_len - bytes.len())];
+        match read(pid, at, &mut chunk) {
+            Ok(_) => bytes.extend_from_slice(&chunk),
+            Err(e) if bytes.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(bytes)
+}
+
+/// Read a native-endian `u64` from the memory of tracee `pid`.
+pub(crate) fn read_u64(pid: Pid, addr: u64) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    read(pid, addr, &mut buf)?;
+    Ok(u64::from_ne_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::process::Command;
+
+    #[test]
+    fn test_read_memory() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        let rip = tracee.registers().expect("Error reading registers").rip;
+        let mem = File::open(format!("/proc/{}/mem", pid)).expect("Error opening mem");
+        let mut expected = [0; 13];
+        mem.read_exact_at(&mut expected, rip)
+            .expect("Error reading mem");
+        let mut buf = [0; 13];
+        tracee
+            .read_memory(rip, &mut buf)
+            .expect("Error reading memory");
+        assert_eq!(buf, expected);
+        // Unaligned reads through the fallback path.
+        for offset in 0..WORD_SIZE as u64 {
+            let mut buf = [0; 5];
+            read_peek(pid, rip + offset, &mut buf).expect("Error peeking memory");
+            assert_eq!(&buf[..], &expected[offset as usize..offset as usize + 5]);
+        }
+        tracee
+            .child_mut()
+            .unwrap()
+            .kill()
+            .expect("Error killing child");
+        tracee
+            .child_mut()
+            .unwrap()
+            .wait()
+            .expect("Error waiting for child");
+    }
+
+    #[test]
+    fn test_read_cstring() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let page = PAGE_SIZE as u64;
+        let buf = tracee.allocate(2 * page, prot).expect("Error allocating");
+        // A string across the page boundary.
+        let start = buf + page - 3;
+        tracee.write_memory(start, b"hello\0world").unwrap();
+        assert_eq!(tracee.read_cstring(start, 100).unwrap(), b"hello");
+        assert_eq!(tracee.read_cstring(start, 4).unwrap(), b"hell");
+        assert_eq!(tracee.read_cstring(start, 0).unwrap(), b"");
+        tracee.write_memory(start, &[0xff]).unwrap();
+        assert_eq!(
+            tracee.read_string_lossy(start, 100).unwrap(),
+            "\u{fffd}ello"
+        );
+        // A string running into unmapped memory is cut off there.
+        let end = buf + 2 * page;
+        tracee.free(end - page, page).unwrap();
+        let start = end - page - 3;
+        tracee.write_memory(start, b"xyz").unwrap();
+        assert_eq!(read_cstring(pid, start, 100).unwrap(), b"xyz");
+        assert!(tracee.read_cstring(end - page, 100).is_err());
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    fn test_write_poke() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let buf = tracee.allocate(PAGE_SIZE as u64, prot).unwrap();
+        let pattern: Vec<u8> = (0..4 * WORD_SIZE as u8).collect();
+        let data: Vec<u8> = (0..2 * WORD_SIZE as u8 + 1).map(|b| 0xf0 | b).collect();
+        for offset in 0..WORD_SIZE {
+            for len in 0..=data.len() {
+                tracee.write_memory(buf, &pattern).unwrap();
+                let at = buf + WORD_SIZE as u64 / 2 + offset as u64;
+                write(pid, at, &data[..len]).expect("Error poking memory");
+                let mut expected = pattern.clone();
+                let start = WORD_SIZE / 2 + offset;
+                expected[start..start + len].copy_from_slice(&data[..len]);
+                let mut actual = vec![0; pattern.len()];
+                read_peek(pid, buf, &mut actual).unwrap();
+                assert_eq!(actual, expected, "offset {} len {}", offset, len);
+            }
+        }
+        // A single breakpoint byte in read-only code.
+        let rip = tracee.registers().expect("Error reading registers").rip;
+        let mut before = [0; 2 * WORD_SIZE];
+        read_peek(pid, rip - 3, &mut before).unwrap();
+        write(pid, rip, &[0xcc]).expect("Error poking memory");
+        let mut after = [0; 2 * WORD_SIZE];
+        read_peek(pid, rip - 3, &mut after).unwrap();
+        before[3] = 0xcc;
+        assert_eq!(after, before);
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    fn test_memory_strategy() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        assert_eq!(tracee.memory_strategy(), None);
+        let rip = tracee.registers().expect("Error reading registers").rip;
+        let mut small = [0; 16];
+        tracee
+            .read_memory(rip, &mut small)
+            .expect("Error reading memory");
+        assert_eq!(tracee.memory_strategy(), Some(MemoryStrategy::ProcessVm));
+        let mut large = vec![0; PROC_MEM_MIN_READ];
+        tracee
+            .read_memory(rip & !0xfff, &mut large)
+            .expect("Error reading memory");
+        assert_eq!(tracee.memory_strategy(), Some(MemoryStrategy::ProcMem));
+        let offset = (rip & 0xfff) as usize;
+        assert_eq!(&large[offset..offset + 16], &small[..]);
+        // Code is read-only, but writes through /proc/<pid>/mem are forced.
+        let patched = [!small[0]];
+        tracee
+            .write_memory(rip, &patched)
+            .expect("Error writing memory");
+        assert_eq!(tracee.memory_strategy(), Some(MemoryStrategy::ProcMem));
+        let mut buf = [0; 1];
+        read_peek(tracee.pid(), rip, &mut buf).expect("Error peeking memory");
+        assert_eq!(buf, patched);
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
@@ -0,0 +1,383 @@
+use crate::accounting::read_tgid;
+use crate::maps::{self, MemoryMap};
+use crate::{syscall, Event, SyscallInfo, TraceSession, Tracee};
+use nix::unistd::Pid;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// The kind of suspicious behavior an [`Alert`] reports.
+///
+/// [`Alert`]: struct.Alert.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    /// Memory was mapped, or its protection changed, to be both writable
+    /// and executable.
+    WritableExecutable,
+    /// Anonymous memory, which no file backs, was mapped executable or made
+    /// executable.
+    ExecutableAnonymous,
+    /// A system call was made with the stack pointer in a different mapping
+    /// from the thread's stack.
+    StackPivot,
+    /// A system call instruction ran from memory that isn't part of a file
+    /// on disk, such as code generated at run time.
+    UnbackedSyscall,
+}
+
+/// Suspicious behavior flagged by a [`SecurityMonitor`].
+///
+/// [`SecurityMonitor`]: struct.SecurityMonitor.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alert {
+    /// When the behavior was seen, relative to when monitoring started.
+    pub time: Duration,
+    /// The process the thread belongs to.
+    pub pid: Pid,
+    /// The thread that made the system call.
+    pub tid: Pid,
+    /// What was flagged.
+    pub kind: AlertKind,
+    /// The system call number.
+    pub syscall: u64,
+    /// The system call arguments.
+    pub args: [u64; 6],
+    /// The address just past the system call instruction.
+    pub instruction_pointer: u64,
+    /// The stack pointer at the system call.
+    pub stack_pointer: u64,
+    /// The memory that was mapped or protected, for `WritableExecutable`
+    /// and `ExecutableAnonymous`.
+    pub range: Option<Range<u64>>,
+}
+
+/// A system call that entered, with the alerts to raise if it succeeds.
+#[derive(Debug)]
+struct Pending {
+    number: u64,
+    args: [u64; 6],
+    pointers: (u64, u64),
+    kinds: Vec<AlertKind>,
+}
+
+/// Watches tracees for memory behavior typical of exploits and of code that
+/// hides what it runs.
+///
+/// Feed every syscall stop to [`record`], or let [`run`] drive a whole
+/// [`TraceSession`]. These are flagged, as [`alerts`]:
+///
+/// - `mmap`, `mprotect` and `pkey_mprotect` calls that succeed in making
+///   memory both writable and executable, or anonymous memory executable.
+/// - System calls made with the stack pointer outside the thread's stack,
+///   taken to be the mapping its stack pointer was in at the first system
+///   call seen from it.
+/// - System calls made from executable memory that isn't a file on disk:
+///   anonymous memory, the heap, or a `memfd`. The vDSO is allowed.
+///
+/// These are heuristics, and some legitimate programs trip them: JIT
+/// compilers map code anonymously, and signal handlers running on an
+/// alternate stack from `sigaltstack` look like stack pivots.
+///
+/// [`record`]: #method.record
+/// [`run`]: #method.run
+/// [`alerts`]: #method.alerts
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug)]
+pub struct SecurityMonitor {
+    start: Instant,
+    in_flight: HashMap<Pid, Pending>,
+    tgids: HashMap<Pid, Pid>,
+    /// The memory maps of each process, read when first needed after they
+    /// may have changed.
+    maps: HashMap<Pid, Vec<MemoryMap>>,
+    /// An address in each thread's stack.
+    stacks: HashMap<Pid, u64>,
+    alerts: Vec<Alert>,
+}
+
+impl Default for SecurityMonitor {
+    fn default() -> SecurityMonitor {
+        SecurityMonitor {
+            start: Instant::now(),
+            in_flight: HashMap::new(),
+            tgids: HashMap::new(),
+            maps: HashMap::new(),
+            stacks: HashMap::new(),
+            alerts: vec![],
+        }
+    }
+}
+
+impl SecurityMonitor {
+    /// Create a monitor with no alerts, with times measured from now.
+    pub fn new() -> SecurityMonitor {
+        SecurityMonitor::default()
+    }
+
+    /// Record a syscall stop of `tracee`.
+    ///
+    /// Call this each time the tracee reports `Event::Syscall`.
+    pub fn record(&mut self, tracee: &Tracee) -> io::Result<()> {
+        let tid = tracee.pid();
+        let (info, pointers) = syscall::syscall_stop(tid)?;
+        match info {
+            SyscallInfo::Entry { number, args } => {
+                let pid = self.tgid(tid);
+                let (ip, sp) = pointers;
+                let stack = *self.stacks.entry(tid).or_insert(sp);
+                let maps = match self.maps.entry(pid) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(maps::read_maps(pid)?),
+                };
+                let mut now = vec![];
+                if !is_file_code(maps, ip.wrapping_sub(1)) {
+                    now.push(AlertKind::UnbackedSyscall);
+                }
+                let mapping = |addr: u64| maps.iter().position(|m| m.contains(addr));
+                if mapping(sp) != mapping(stack) {
+                    now.push(AlertKind::StackPivot);
+                }
+                let kinds = protection_alerts(maps, number, &args);
+                for kind in now {
+                    self.alert(pid, tid, kind, number, args, pointers, None);
+                }
+                self.in_flight.insert(
+                    tid,
+                    Pending {
+                        number,
+                        args,
+                        pointers,
+                        kinds,
+                    },
+                );
+            }
+            SyscallInfo::Exit { value, is_error } => {
+                let pending = match self.in_flight.remove(&tid) {
+                    Some(pending) => pending,
+                    None => return Ok(()),
+                };
+                let pid = self.tgid(tid);
+                if changes_maps(pending.number) {
+                    self.maps.remove(&pid);
+                }
+                if is_error {
+                    return Ok(());
+                }
+                let (start, len) = if pending.number == libc::SYS_mmap as u64 {
+                    (value as u64, pending.args[1])
+                } else {
+                    (pending.args[0], pending.args[1])
+                };
+                for kind in pending.kinds {
+                    self.alert(
+                        pid,
+                        tid,
+                        kind,
+                        pending.number,
+                        pending.args,
+                        pending.pointers,
+                        Some(start..start.wrapping_add(len)),
+                    );
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Forget what is known about thread `pid`, for example because it
+    /// exited.
+    pub fn forget(&mut self, pid: Pid) {
+        self.in_flight.remove(&pid);
+        self.stacks.remove(&pid);
+        if self.tgids.remove(&pid) == Some(pid) {
+            self.maps.remove(&pid);
+        }
+    }
+
+    /// Forget the memory of process `pid` and the stacks of its threads,
+    /// because it has called `exec`.
+    pub fn reset(&mut self, pid: Pid) {
+        self.maps.remove(&pid);
+        let tgids = &self.tgids;
+        self.stacks.retain(|tid, _| tgids.get(tid) != Some(&pid));
+        self.in_flight.retain(|tid, _| tgids.get(tid) != Some(&pid));
+    }
+
+    /// Monitor the tracees in `session` with [`TraceSession::run`] until
+    /// they have all exited.
+    ///
+    /// The session should trace system calls, with [`trace_syscalls`].
+    ///
+    /// [`TraceSession::run`]: struct.TraceSession.html#method.run
+    /// [`trace_syscalls`]: struct.TraceSession.html#method.trace_syscalls
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        session.run(|session, pid, event| {
+            match event {
+                Event::Syscall => {
+                    if let Some(tracee) = session.get(pid) {
+                        self.record(tracee)?;
+                    }
+                }
+                Event::Exec(_) => {
+                    let tgid = self.tgid(pid);
+                    self.reset(tgid);
+                }
+                Event::Exited(_) | Event::Signaled(..) => self.forget(pid),
+                _ => {}
+            }
+            Ok(())
+        })
+    }
+
+    /// Every alert raised so far, in the order they were raised.
+    pub fn alerts(&self) -> &[Alert] {
+        &self.alerts
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn alert(
+        &mut self,
+        pid: Pid,
+        tid: Pid,
+        kind: AlertKind,
+        syscall: u64,
+        args: [u64; 6],
+        (instruction_pointer, stack_pointer): (u64, u64),
+        range: Option<Range<u64>>,
+    ) {
+        self.alerts.push(Alert {
+            time: self.start.elapsed(),
+            pid,
+            tid,
+            kind,
+            syscall,
+            args,
+            instruction_pointer,
+            stack_pointer,
+            range,
+        });
+    }
+
+    /// The process that thread `tid` belongs to.
+    fn tgid(&mut self, tid: Pid) -> Pid {
+        *self
+            .tgids
+            .entry(tid)
+            .or_insert_with(|| read_tgid(tid).unwrap_or(tid))
+    }
+}
+
+/// Whether `addr` is in executable memory mapped from a file on disk, or in
+/// the vDSO.
+fn is_file_code(maps: &[MemoryMap], addr: u64) -> bool {
+    maps.iter().any(|m| {
+        m.contains(addr)
+            && m.executable
+            && match m.pathname {
+                Some(ref path) if path.starts_with('/') => !path.starts_with("/memfd:"),
+                Some(ref path) => path == "[vdso]" || path == "[vsyscall]",
+                None => false,
+            }
+    })
+}
+
+/// The alerts that system call `number` with `args` raises if it succeeds.
+fn protection_alerts(maps: &[MemoryMap], number: u64, args: &[u64; 6]) -> Vec<AlertKind> {
+    let number = number as i64;
+    let prot = args[2] as i32;
+    let anonymous = match number {
+        libc::SYS_mmap => args[3] as i32 & libc::MAP_ANONYMOUS != 0,
+        libc::SYS_mprotect | libc::SYS_pkey_mprotect => {
+            let range = args[0]..args[0].wrapping_add(args[1]);
+            maps.iter().any(|m| {
+                m.start < range.end
+                    && range.start < m.end
+                    && !m.pathname.as_ref().is_some_and(|p| p.starts_with('/'))
+            })
+        }
+        _ => return vec![],
+    };
+    let mut kinds = vec![];
+    if prot & libc::PROT_EXEC != 0 {
+        if prot & libc::PROT_WRITE != 0 {
+            kinds.push(AlertKind::WritableExecutable);
+        }
+        if anonymous {
+            kinds.push(AlertKind::ExecutableAnonymous);
+        }
+    }
+    kinds
+}
+
+/// Whether system call `number` can change a process's memory maps.
+fn changes_maps(number: u64) -> bool {
+    [
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mprotect,
+        libc::SYS_pkey_mprotect,
+        libc::SYS_mremap,
+        libc::SYS_brk,
+        libc::SYS_shmat,
+        libc::SYS_shmdt,
+        libc::SYS_madvise,
+        // Threads get their stacks mapped by a parent that may be untraced.
+        libc::SYS_clone,
+        libc::SYS_clone3,
+    ]
+    .contains(&(number as i64))
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use std::process::{Command, Stdio};
+
+    fn monitor(arg: Option<&str>) -> SecurityMonitor {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        let mut command = Command::new(&path);
+        command.args(arg).stdout(Stdio::null());
+        session
+            .spawn(&mut command, SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut monitor = SecurityMonitor::new();
+        monitor.run(&mut session).expect("Error monitoring");
+        monitor
+    }
+
+    #[test]
+    fn test_security_monitor() {
+        // An ordinary program raises nothing.
+        assert_eq!(monitor(None).alerts(), &[]);
+
+        let monitor = monitor(Some("jit"));
+        let kinds: Vec<_> = monitor
+            .alerts()
+            .iter()
+            .map(|a| (a.kind, a.syscall))
+            .collect();
+        let mmap = libc::SYS_mmap as u64;
+        let getpid = libc::SYS_getpid as u64;
+        assert_eq!(
+            kinds,
+            [
+                (AlertKind::WritableExecutable, mmap),
+                (AlertKind::ExecutableAnonymous, mmap),
+                (AlertKind::UnbackedSyscall, getpid),
+                (AlertKind::StackPivot, getpid),
+            ]
+        );
+        let alerts = monitor.alerts();
+        let range = alerts[0].range.clone().expect("No range");
+        assert_eq!(range.end - range.start, 4096);
+        assert!(range.contains(&(alerts[2].instruction_pointer - 1)));
+        assert_eq!(alerts[3].range, None);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{maps, memory, MemoryMap};
+use nix::unistd::Pid;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+/// A system call made by a supervised process, received from a
+/// [`SeccompNotifier`].
+///
+/// [`SeccompNotifier`]: struct.SeccompNotifier.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Notification {
+    /// The notification's ID, used to respond to it.
+    pub id: u64,
+    /// The thread that made the system call.
+    pub pid: Pid,
+    /// The system call number.
+    pub number: i64,
+    /// The `AUDIT_ARCH_*` value for the system call's calling convention.
+    pub arch: u32,
+    /// The address of the system call instruction.
+    pub instruction_pointer: u64,
+    /// The system call arguments.
+    pub args: [u64; 6],
+}
+
+/// How a [`SeccompNotifier`] resolves a system call.
+///
+/// [`SeccompNotifier`]: struct.SeccompNotifier.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotifyResponse {
+    /// Let the system call run as normal.
+    ///
+    /// The arguments may have changed since they were inspected, so this
+    /// must not be used to enforce a security policy based on memory the
+    /// arguments point to.
+    Continue,
+    /// Return `value` from the system call without running it.
+    Return(i64),
+    /// Fail the system call with the given errno without running it.
+    Error(i32),
+}
+
+/// The listening side of a seccomp filter using `SECCOMP_RET_USER_NOTIF`.
+///
+/// The supervised process blocks in each system call the filter sends here
+/// until it is answered with [`respond`], without any ptrace stops, so the
+/// process can still be traced by someone else. Get one by spawning a child
+/// with [`spawn_with_notifier`]. The descriptor becomes readable when a
+/// notification is pending, so it can be registered with `poll` or `epoll`.
+///
+/// [`respond`]: #method.respond
+/// [`spawn_with_notifier`]: trait.CommandPtraceSpawn.html#tymethod.spawn_with_notifier
+#[derive(Debug)]
+pub struct SeccompNotifier(OwnedFd);
+
+impl SeccompNotifier {
+    pub(crate) fn from_fd(fd: OwnedFd) -> SeccompNotifier {
+        SeccompNotifier(fd)
+    }
+
+    /// Wait for the next system call that needs a response.
+    pub fn recv(&self) -> io::Result<Notification> {
+        let mut notif: libc::seccomp_notif = unsafe { mem::zeroed() };
+        let ret = unsafe {
+            libc::ioctl(
+                self.0.as_raw_fd(),
+                libc::SECCOMP_IOCTL_NOTIF_RECV,
+                &mut notif as *mut libc::seccomp_notif,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Notification {
+            id: notif.id,
+            pid: Pid::from_raw(notif.pid as i32),
+            number: notif.data.nr as i64,
+            arch: notif.data.arch,
+            instruction_pointer: notif.data.instruction_pointer,
+            args: notif.data.args,
+        })
+    }
+
+    /// Whether notification `id` is still waiting for a response.
+    ///
+    /// Check this after reading the caller's memory, since the thread may
+    /// have been killed and its pid reused in the meantime.
+    pub fn is_valid(&self, id: u64) -> bool {
+        let ret = unsafe {
+            libc::ioctl(
+                self.0.as_raw_fd(),
+                libc::SECCOMP_IOCTL_NOTIF_ID_VALID,
+                &id as *const u64,
+            )
+        };
+        ret == 0
+    }
+
+    /// Read the memory of the process that made `notification`, for example
+    /// to look at a path passed to `openat`.
+    ///
+    /// This uses `process_vm_readv`, so it works without ptrace. Fails with
+    /// `ENOENT` if the notification is no longer valid once the read is done,
+    /// in which case the data can't be trusted.
+    pub fn read_memory(
+        &self,
+        notification: &Notification,
+        addr: u64,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        memory::read_vm(notification.pid, addr, buf)?;
+        if !self.is_valid(notification.id) {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        Ok(())
+    }
+
+    /// The memory map of the process that made `notification`.
+    pub fn memory_maps(&self, notification: &Notification) -> io::Result<Vec<MemoryMap>> {
+        maps::read_maps(notification.pid)
+    }
+
+    /// Answer notification `id`, letting the caller continue.
+    pub fn respond(&self, id: u64, response: NotifyResponse) -> io::Result<()> {
+        let (val, error, flags) = match response {
+            NotifyResponse::Continue => (0, 0, libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32),
+            NotifyResponse::Return(value) => (value, 0, 0),
+            NotifyResponse::Error(errno) => (0, -errno, 0),
+        };
+        let mut resp = libc::seccomp_notif_resp {
+            id,
+            val,
+            error,
+            flags,
+        };
+        let ret = unsafe {
+            libc::ioctl(
+                self.0.as_raw_fd(),
+                libc::SECCOMP_IOCTL_NOTIF_SEND,
+                &mut resp as *mut libc::seccomp_notif_resp,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for SeccompNotifier {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl AsFd for SeccompNotifier {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+/// Send `fd` over the Unix socket `socket` with `SCM_RIGHTS`.
+///
+/// This doesn't allocate, so it is safe to call between `fork` and `exec`.
+pub(crate) fn send_fd(socket: RawFd, fd: RawFd) -> io::Result<()> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    // Room for one cmsghdr and a descriptor, suitably aligned.
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+    if unsafe { libc::sendmsg(socket, &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive a descriptor sent with [`send_fd`] from the Unix socket `socket`.
+pub(crate) fn recv_fd(socket: RawFd) -> io::Result<OwnedFd> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    if unsafe { libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    if cmsg.is_null() || unsafe { (*cmsg).cmsg_type } != libc::SCM_RIGHTS {
+        return Err(io::Error::other("No file descriptor received"));
+    }
+    let fd = unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd) };
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
@@ -0,0 +1,1098 @@
+use crate::{capabilities, nix_error, yama, Cgroup, DropPolicy, Error, PerfCounter, Ptracer};
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::{seccomp, SeccompFilter};
+use nix::mount::{self, MntFlags, MsFlags};
+use nix::sched::{self, CloneFlags};
+use nix::sys::ptrace::{self, Options};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{self, Gid, Pid, Uid};
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+
+/// Bits of capabilities in a capability set.
+const CAP_SETGID: u64 = 1 << 6;
+const CAP_SETUID: u64 = 1 << 7;
+const CAP_SYS_CHROOT: u64 = 1 << 18;
+const CAP_SYS_ADMIN: u64 = 1 << 21;
+const CAP_SYS_NICE: u64 = 1 << 23;
+const CAP_SYS_RESOURCE: u64 = 1 << 24;
+
+type PreExecHook = Box<dyn FnMut() -> io::Result<()> + Send + Sync>;
+
+/// A Linux scheduling policy, for [`SpawnOptions::scheduler`].
+///
+/// [`SpawnOptions::scheduler`]: struct.SpawnOptions.html#method.scheduler
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// `SCHED_OTHER`, the default time-sharing policy.
+    Other,
+    /// `SCHED_BATCH`, for CPU-bound work that shouldn't preempt others.
+    Batch,
+    /// `SCHED_IDLE`, for work that should only run when nothing else will.
+    Idle,
+    /// `SCHED_FIFO`, the first-in first-out real-time policy.
+    Fifo,
+    /// `SCHED_RR`, the round-robin real-time policy.
+    RoundRobin,
+}
+
+impl SchedPolicy {
+    fn as_raw(self) -> libc::c_int {
+        match self {
+            SchedPolicy::Other => libc::SCHED_OTHER,
+            SchedPolicy::Batch => libc::SCHED_BATCH,
+            SchedPolicy::Idle => libc::SCHED_IDLE,
+            SchedPolicy::Fifo => libc::SCHED_FIFO,
+            SchedPolicy::RoundRobin => libc::SCHED_RR,
+        }
+    }
+}
+
+/// How a spawned child is first stopped, for [`SpawnOptions::initial_stop`].
+///
+/// However it is produced, the initial stop comes after the child has
+/// called `exec` and before it runs the program's first instruction.
+///
+/// [`SpawnOptions::initial_stop`]: struct.SpawnOptions.html#method.initial_stop
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InitialStop {
+    /// The child calls `PTRACE_TRACEME`, so the kernel sends it a `SIGTRAP`
+    /// when it calls `exec`, which it stops for. This is the default.
+    #[default]
+    ExecTrap,
+    /// The child is attached with `PTRACE_SEIZE` and raises a `SIGSTOP`
+    /// once it is set up, just before `exec`. The tracer suppresses the
+    /// `SIGSTOP` and resumes the child to its `PTRACE_EVENT_EXEC` stop.
+    RaisedStop,
+    /// The child is attached with `PTRACE_SEIZE` and stops in
+    /// `PTRACE_EVENT_EXEC` when it calls `exec`, without any signal.
+    ExecEvent,
+}
+
+/// The pipes a child that is to be seized waits for its tracer with.
+#[derive(Debug)]
+pub(crate) struct Handshake {
+    /// The child writes its PID to this.
+    pub(crate) pid: RawFd,
+    /// The tracer writes a byte to this once it has seized the child.
+    pub(crate) resume: RawFd,
+    /// The tracer's ends of both pipes.
+    pub(crate) tracer: [RawFd; 2],
+}
+
+impl Handshake {
+    /// Run in the child: tell the tracer its PID and wait to be seized.
+    fn wait_for_tracer(&self) -> io::Result<()> {
+        for &fd in &self.tracer {
+            unsafe { libc::close(fd) };
+        }
+        let pid = unistd::getpid().as_raw().to_ne_bytes();
+        unistd::write(self.pid, &pid).map_err(nix_error)?;
+        let mut resume = [0];
+        // The tracer closes its end instead if it can't seize the child,
+        // which mustn't carry on untraced.
+        if unistd::read(self.resume, &mut resume).map_err(nix_error)? == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        Ok(())
+    }
+}
+
+/// How the child's root directory is changed.
+#[derive(Debug)]
+enum NewRoot {
+    Chroot(PathBuf),
+    PivotRoot(PathBuf),
+}
+
+/// A file for the child to `execveat`, instead of the `Command`'s program.
+#[derive(Debug)]
+struct ExecAt {
+    dirfd: RawFd,
+    path: PathBuf,
+    /// The C strings of the child's arguments and environment, which `argv`
+    /// and `envp` point into.
+    strings: Vec<CString>,
+    argv: Vec<*const libc::c_char>,
+    envp: Vec<*const libc::c_char>,
+}
+
+// The pointers only refer to the strings owned alongside them.
+unsafe impl Send for ExecAt {}
+unsafe impl Sync for ExecAt {}
+
+/// Options controlling how a child process is set up before it is traced.
+///
+/// Pass these to [`spawn_ptrace_with_options`] to run your own code in the
+/// child between `fork` and `exec` with a well-defined ordering relative to
+/// the `PTRACE_TRACEME` call made by this crate.
+///
+/// Any `pre_exec` closures registered directly on the `Command` run before
+/// all hooks registered here. After the hooks, the child applies the other
+/// settings made here, dropping privileges last, and then calls `exec`.
+///
+/// [`spawn_ptrace_with_options`]: trait.CommandPtraceSpawn.html#tymethod.spawn_ptrace_with_options
+#[derive(Default)]
+pub struct SpawnOptions {
+    before_traceme: Vec<PreExecHook>,
+    after_traceme: Vec<PreExecHook>,
+    pub(crate) ptrace_options: Option<Options>,
+    pub(crate) suspend_seccomp: bool,
+    new_root: Option<NewRoot>,
+    pub(crate) pty: bool,
+    pub(crate) oom_score_adj: Option<i32>,
+    pub(crate) cgroup: Option<PathBuf>,
+    pub(crate) perf_counters: Vec<PerfCounter>,
+    pub(crate) preload: Vec<PathBuf>,
+    pub(crate) drop_policy: DropPolicy,
+    pub(crate) initial_stop: InitialStop,
+    pub(crate) handshake: Option<Handshake>,
+    setsid: bool,
+    pub(crate) foreground: Option<RawFd>,
+    controlling_terminal: Option<RawFd>,
+    close_fds_from: Option<RawFd>,
+    keep_fds: Vec<RawFd>,
+    exec_at: Option<ExecAt>,
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    seccomp: Option<(SeccompFilter, Vec<libc::sock_filter>)>,
+    scheduler: Option<(SchedPolicy, i32)>,
+    nice: Option<i32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    groups: Option<Vec<libc::gid_t>>,
+    no_new_privs: bool,
+}
+
+impl SpawnOptions {
+    /// Create a new set of options with no hooks registered.
+    pub fn new() -> SpawnOptions {
+        SpawnOptions::default()
+    }
+
+    /// Schedules a closure to be run in the child before it calls `PTRACE_TRACEME`.
+    ///
+    /// Signals raised by the closure are delivered normally, since the child
+    /// is not yet traced. Closures run in the order they were registered.
+    ///
+    /// # Safety
+    ///
+    /// This has the same safety requirements as
+    /// `std::os::unix::process::CommandExt::pre_exec`.
+    pub unsafe fn pre_exec_before_traceme<F>(&mut self, f: F) -> &mut SpawnOptions
+    where
+        F: FnMut() -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.before_traceme.push(Box::new(f));
+        self
+    }
+
+    /// Schedules a closure to be run in the child after it calls `PTRACE_TRACEME`.
+    ///
+    /// The child is already traced at this point, so any signal the closure
+    /// raises will be reported to the tracer as a signal-delivery-stop.
+    /// Closures run in the order they were registered.
+    ///
+    /// # Safety
+    ///
+    /// This has the same safety requirements as
+    /// `std::os::unix::process::CommandExt::pre_exec`.
+    pub unsafe fn pre_exec_after_traceme<F>(&mut self, f: F) -> &mut SpawnOptions
+    where
+        F: FnMut() -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.after_traceme.push(Box::new(f));
+        self
+    }
+
+    /// Set ptrace options on the child with `PTRACE_SETOPTIONS` once it has stopped.
+    ///
+    /// These are combined with any options previously set.
+    pub fn ptrace_options(&mut self, options: Options) -> &mut SpawnOptions {
+        self.ptrace_options = Some(self.ptrace_options.unwrap_or_else(Options::empty) | options);
+        self
+    }
+
+    /// Set `PTRACE_O_SUSPEND_SECCOMP` on the child, so that seccomp filters
+    /// it installs or inherits don't apply while it is traced.
+    ///
+    /// This is useful for injecting system calls into sandboxed processes.
+    /// The kernel only allows it if the tracer has `CAP_SYS_ADMIN` and isn't
+    /// confined by seccomp itself. These requirements are checked before the
+    /// child is spawned, and spawning fails with an [`Error`] wrapped in the
+    /// returned `io::Error` if they aren't met.
+    ///
+    /// [`Error`]: enum.Error.html
+    pub fn suspend_seccomp(&mut self) -> &mut SpawnOptions {
+        self.suspend_seccomp = true;
+        self
+    }
+
+    /// Have the child declare which process may attach to it under Yama,
+    /// as if it had called [`set_ptracer`] before `exec`.
+    ///
+    /// This lets another tool attach to the child once this crate has
+    /// detached from it, even when Yama's `ptrace_scope` is 1. It does
+    /// nothing if the kernel wasn't built with Yama.
+    ///
+    /// [`set_ptracer`]: fn.set_ptracer.html
+    pub fn allow_ptracer(&mut self, ptracer: Ptracer) -> &mut SpawnOptions {
+        self.before_traceme.push(Box::new(move || {
+            match yama::set_ptracer(ptracer) {
+                // Without Yama there are no restrictions to make an exception to.
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(()),
+                result => result,
+            }
+        }));
+        self
+    }
+
+    /// Change the child's root directory to `root` with `chroot`.
+    ///
+    /// The child's working directory is changed to the new root, and the
+    /// program to run is looked up inside it. This requires
+    /// `CAP_SYS_CHROOT`, and is applied before privileges are dropped.
+    /// Replaces any earlier call to this or [`pivot_root`].
+    ///
+    /// [`pivot_root`]: #method.pivot_root
+    pub fn chroot<P: AsRef<Path>>(&mut self, root: P) -> &mut SpawnOptions {
+        self.new_root = Some(NewRoot::Chroot(root.as_ref().to_owned()));
+        self
+    }
+
+    /// Move the child into a new mount namespace and make `root` its root
+    /// filesystem with `pivot_root`.
+    ///
+    /// Unlike [`chroot`], the old root is unmounted in the child's
+    /// namespace, so it can't be escaped back into. Mount propagation is made
+    /// private first so nothing leaks back to the parent's namespace, and
+    /// `root` is bind-mounted onto itself, so it doesn't need to be a mount
+    /// point already. This requires `CAP_SYS_ADMIN`. Otherwise it behaves
+    /// like [`chroot`].
+    ///
+    /// [`chroot`]: #method.chroot
+    pub fn pivot_root<P: AsRef<Path>>(&mut self, root: P) -> &mut SpawnOptions {
+        self.new_root = Some(NewRoot::PivotRoot(root.as_ref().to_owned()));
+        self
+    }
+
+    /// Start a new session in the child with `setsid`, detaching it from the
+    /// tracer's controlling terminal.
+    ///
+    /// A traced child that shares the tracer's terminal but isn't in its
+    /// foreground process group gets `SIGTTIN` or `SIGTTOU` when it uses the
+    /// terminal. Those arrive as signal-delivery-stops, and passing them on
+    /// puts the child in a group-stop that nothing will end, which looks
+    /// like a hang. Running the child in its own session avoids this.
+    pub fn setsid(&mut self) -> &mut SpawnOptions {
+        self.setsid = true;
+        self
+    }
+
+    /// Make the terminal open as `fd` in the child its controlling terminal.
+    ///
+    /// This implies [`setsid`], since only a session leader without a
+    /// controlling terminal can acquire one. The child becomes the
+    /// terminal's foreground process group, so it can read from and write
+    /// to it without job control stops.
+    ///
+    /// [`setsid`]: #method.setsid
+    pub fn controlling_terminal(&mut self, fd: RawFd) -> &mut SpawnOptions {
+        self.setsid = true;
+        self.controlling_terminal = Some(fd);
+        self
+    }
+
+    /// Run the child in a new process group and make that the foreground
+    /// process group of the tracer's controlling terminal, open as `fd`.
+    ///
+    /// This lets an interactive tracee read from the terminal without
+    /// `SIGTTIN` stops, while it stays in the tracer's session. The child
+    /// ignores `SIGTTOU` while it calls `tcsetpgrp`, which would otherwise
+    /// stop it for changing the terminal from the background. The tracer is
+    /// in the background afterwards, so once the tracee is done it should
+    /// take the terminal back with [`Tracee::restore_foreground`].
+    ///
+    /// Spawning fails with `ENOTTY` if `fd` isn't the tracer's controlling
+    /// terminal. It has no effect with [`setsid`], which leaves the terminal.
+    ///
+    /// [`Tracee::restore_foreground`]: struct.Tracee.html#method.restore_foreground
+    /// [`setsid`]: #method.setsid
+    pub fn foreground(&mut self, fd: RawFd) -> &mut SpawnOptions {
+        self.foreground = Some(fd);
+        self
+    }
+
+    /// Set what happens to the tracee when its `Tracee` is dropped, as with
+    /// [`Tracee::set_drop_policy`]. The default is `DropPolicy::Leave`.
+    ///
+    /// [`Tracee::set_drop_policy`]: struct.Tracee.html#method.set_drop_policy
+    pub fn drop_policy(&mut self, policy: DropPolicy) -> &mut SpawnOptions {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Choose how the child is first stopped. The default is
+    /// `InitialStop::ExecTrap`.
+    ///
+    /// The other mechanisms suit programs that use `SIGTRAP` themselves,
+    /// since no `SIGTRAP` is ever sent, and the tracee's initial status is a
+    /// `PTRACE_EVENT_EXEC` stop instead of a `SIGTRAP` stop. They attach
+    /// with `PTRACE_SEIZE`, which needs Linux 3.4, and which Yama's
+    /// `ptrace_scope` of 2 only allows with `CAP_SYS_PTRACE`, and they add
+    /// `PTRACE_O_TRACEEXEC` to the [`ptrace_options`].
+    ///
+    /// Since `Command::spawn` only returns once the child has called `exec`,
+    /// a seized child is spawned from another thread, while the thread
+    /// spawning the tracee seizes it, so that it is the tracer.
+    ///
+    /// [`ptrace_options`]: #method.ptrace_options
+    pub fn initial_stop(&mut self, stop: InitialStop) -> &mut SpawnOptions {
+        self.initial_stop = stop;
+        self
+    }
+
+    /// Run the child on a new pseudo-terminal.
+    ///
+    /// The terminal's slave side becomes the child's stdin, stdout and
+    /// stderr, replacing any stdio configured on the `Command`, and its
+    /// controlling terminal. The master side is available from
+    /// [`Tracee::pty_master`], for driving programs that behave differently
+    /// when they aren't attached to a terminal.
+    ///
+    /// [`Tracee::pty_master`]: struct.Tracee.html#method.pty_master
+    pub fn pty(&mut self) -> &mut SpawnOptions {
+        self.pty = true;
+        self.controlling_terminal(0)
+    }
+
+    /// Close every file descriptor numbered `min_fd` or higher when the
+    /// child calls `exec`, except those passed to [`keep_fd`].
+    ///
+    /// This stops the child inheriting descriptors the tracer didn't open
+    /// with `O_CLOEXEC`, such as those from libraries or other threads. The
+    /// descriptors are marked close-on-exec with `close_range` where the
+    /// kernel supports it, so that descriptors `std` needs until `exec` stay
+    /// open. Use 3 to keep only stdin, stdout and stderr.
+    ///
+    /// [`keep_fd`]: #method.keep_fd
+    pub fn close_fds_from(&mut self, min_fd: RawFd) -> &mut SpawnOptions {
+        self.close_fds_from = Some(min_fd);
+        self
+    }
+
+    /// Pass `fd` on to the child, even if it is marked close-on-exec or
+    /// [`close_fds_from`] would otherwise close it.
+    ///
+    /// [`close_fds_from`]: #method.close_fds_from
+    pub fn keep_fd(&mut self, fd: RawFd) -> &mut SpawnOptions {
+        if let Err(i) = self.keep_fds.binary_search(&fd) {
+            self.keep_fds.insert(i, fd);
+        }
+        self
+    }
+
+    /// Run the file at `path` relative to the directory `dirfd` in the child,
+    /// with `execveat(2)`, instead of looking up the `Command`'s program. If
+    /// `path` is empty, the file `dirfd` itself refers to is run, so a
+    /// sandboxed runner holding only an `O_PATH` descriptor of it, with no
+    /// path that resolves, can still spawn it.
+    ///
+    /// The `Command`'s program is still the child's `argv[0]`, followed by
+    /// its arguments, and this process's environment is passed on with the
+    /// `Command`'s changes, but `CommandExt::arg0` and `Command::env_clear`
+    /// have no effect, since they can't be read back. The descriptor
+    /// must stay open until the child has called `exec`; it can be
+    /// close-on-exec, unless the file is a script, since the interpreter
+    /// opens it through `/dev/fd`. A [`seccomp_filter`] is installed before
+    /// the call, so it must allow `execveat`. This requires Linux 3.19.
+    ///
+    /// [`seccomp_filter`]: #method.seccomp_filter
+    pub fn exec_at<P: AsRef<Path>>(&mut self, dirfd: RawFd, path: P) -> &mut SpawnOptions {
+        self.exec_at = Some(ExecAt {
+            dirfd,
+            path: path.as_ref().to_owned(),
+            strings: vec![],
+            argv: vec![],
+            envp: vec![],
+        });
+        self
+    }
+
+    /// Set the child's scheduling policy with `sched_setscheduler`.
+    ///
+    /// `priority` is the static priority for the real-time policies, from 1
+    /// to 99, and must be 0 for the others. Real-time policies usually need
+    /// `CAP_SYS_NICE`, so this is applied before privileges are dropped.
+    pub fn scheduler(&mut self, policy: SchedPolicy, priority: i32) -> &mut SpawnOptions {
+        self.scheduler = Some((policy, priority));
+        self
+    }
+
+    /// Set the child's nice value, from -20 (highest priority) to 19.
+    ///
+    /// This is applied after the scheduling policy. Making the child nicer
+    /// than the tracer is always allowed, but a lower value needs
+    /// `CAP_SYS_NICE` or a suitable `RLIMIT_NICE`.
+    pub fn nice(&mut self, nice: i32) -> &mut SpawnOptions {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Set the child's `oom_score_adj`, from -1000 to 1000, while it is
+    /// stopped at its initial `exec` trap.
+    ///
+    /// A high value makes the OOM killer pick the child before the tracer.
+    /// Lowering the value below the tracer's needs `CAP_SYS_RESOURCE`.
+    /// Unlike the other settings, this is applied by the tracer rather than
+    /// in the child, and spawning fails if it can't be set.
+    pub fn oom_score_adj(&mut self, adj: i32) -> &mut SpawnOptions {
+        self.oom_score_adj = Some(adj);
+        self
+    }
+
+    /// Move the child into `group` while it is stopped at its initial
+    /// `exec` trap, before it has run any of its program.
+    ///
+    /// Its children start in the group too, so the whole tree can be frozen
+    /// at once. Like [`oom_score_adj`], this is applied by the tracer, and
+    /// spawning fails if the child can't be moved.
+    ///
+    /// [`oom_score_adj`]: #method.oom_score_adj
+    pub fn cgroup(&mut self, group: &Cgroup) -> &mut SpawnOptions {
+        self.cgroup = Some(group.path().to_owned());
+        self
+    }
+
+    /// Open performance counters on the child once it has stopped at its
+    /// initial `exec` trap, available from [`Tracee::perf_counters`].
+    ///
+    /// Like [`oom_score_adj`], this is applied by the tracer, and spawning
+    /// fails if any of the counters can't be opened. Nothing is counted
+    /// until the child is resumed.
+    ///
+    /// [`Tracee::perf_counters`]: struct.Tracee.html#method.perf_counters
+    /// [`oom_score_adj`]: #method.oom_score_adj
+    pub fn perf_counters(&mut self, counters: &[PerfCounter]) -> &mut SpawnOptions {
+        self.perf_counters.extend_from_slice(counters);
+        self
+    }
+
+    /// Load the shared object `library` into the child before any others,
+    /// by prepending it to `LD_PRELOAD`.
+    ///
+    /// Libraries are preloaded in the order they were added, ahead of any
+    /// value of `LD_PRELOAD` set on the `Command` or inherited from this
+    /// process. They are checked to exist and made absolute before the child
+    /// is spawned, and spawning fails if one doesn't exist, or its path
+    /// contains a space or a colon, which separate entries in `LD_PRELOAD`.
+    /// The dynamic loader ignores `LD_PRELOAD` for setuid executables.
+    pub fn preload<P: AsRef<Path>>(&mut self, library: P) -> &mut SpawnOptions {
+        self.preload.push(library.as_ref().to_path_buf());
+        self
+    }
+
+    /// Install `filter` in the child as the very last step before `exec`.
+    ///
+    /// Because hooks and all the other settings are applied first, none of
+    /// them are subject to the filter, but `exec` itself is, so the filter
+    /// must allow `execve`. `PR_SET_NO_NEW_PRIVS` is always set before the
+    /// filter is installed, so that no privileges are needed for it. If the
+    /// filter uses `SeccompAction::Trace`, `PTRACE_O_TRACESECCOMP` is set
+    /// along with any other [`ptrace_options`], so its stops are reported as
+    /// `Event::Seccomp` once the child has stopped at `exec`.
+    ///
+    /// [`ptrace_options`]: #method.ptrace_options
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn seccomp_filter(&mut self, filter: &SeccompFilter) -> &mut SpawnOptions {
+        if filter.traces() {
+            self.ptrace_options(Options::PTRACE_O_TRACESECCOMP);
+        }
+        self.seccomp = Some((filter.clone(), filter.compile()));
+        self
+    }
+
+    /// Set the child's user ID.
+    ///
+    /// Unlike `CommandExt::uid`, this is applied after `PTRACE_TRACEME` and
+    /// all hooks, so they still run with the tracer's privileges. As with
+    /// `CommandExt::uid`, if this process is root and no supplementary
+    /// groups are set with [`groups`], the child's supplementary groups are
+    /// cleared.
+    ///
+    /// [`groups`]: #method.groups
+    pub fn uid(&mut self, uid: u32) -> &mut SpawnOptions {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Set the child's group ID. This is applied just before the user ID.
+    pub fn gid(&mut self, gid: u32) -> &mut SpawnOptions {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Set the child's supplementary groups. This is applied just before
+    /// the group ID.
+    pub fn groups(&mut self, groups: &[u32]) -> &mut SpawnOptions {
+        self.groups = Some(groups.to_vec());
+        self
+    }
+
+    /// Set `PR_SET_NO_NEW_PRIVS` in the child, so that `exec` can never grant
+    /// it privileges it didn't already have, for example through setuid
+    /// binaries or file capabilities.
+    ///
+    /// This is applied after the user and group IDs are set.
+    pub fn no_new_privs(&mut self) -> &mut SpawnOptions {
+        self.no_new_privs = true;
+        self
+    }
+
+    /// Check these options without spawning anything.
+    ///
+    /// This catches options that contradict each other or are out of range,
+    /// settings that need a capability the tracer lacks, and features the
+    /// running kernel doesn't support, returning an [`Error`] wrapped in the
+    /// `io::Error` for the first problem found. Libraries to preload are
+    /// checked as when spawning.
+    ///
+    /// Spawning doesn't call this, and it can't see everything that could
+    /// make spawning fail: capabilities are only checked in the tracer's
+    /// effective set, and security modules may refuse settings it allows.
+    ///
+    /// [`Error`]: enum.Error.html
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |problem| -> io::Result<()> { Err(Error::InvalidOptions(problem).into()) };
+        if self.setsid && self.foreground.is_some() {
+            return invalid("foreground has no effect in a new session");
+        }
+        if self.close_fds_from.is_some_and(|fd| fd < 0) {
+            return invalid("close_fds_from needs a descriptor of 0 or more");
+        }
+        if let Some((policy, priority)) = self.scheduler {
+            let realtime = matches!(policy, SchedPolicy::Fifo | SchedPolicy::RoundRobin);
+            if realtime && !(1..=99).contains(&priority) {
+                return invalid("real-time priorities range from 1 to 99");
+            }
+            if !realtime && priority != 0 {
+                return invalid("only real-time policies take a priority");
+            }
+        }
+        if self.nice.is_some_and(|nice| !(-20..=19).contains(&nice)) {
+            return invalid("nice values range from -20 to 19");
+        }
+        if self
+            .oom_score_adj
+            .is_some_and(|adj| !(-1000..=1000).contains(&adj))
+        {
+            return invalid("oom_score_adj ranges from -1000 to 1000");
+        }
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        if let Some((filter, _)) = &self.seccomp {
+            let traced = self
+                .ptrace_options
+                .is_some_and(|o| o.contains(Options::PTRACE_O_TRACESECCOMP));
+            if filter.traces() && !traced {
+                return invalid("seccomp trace actions need PTRACE_O_TRACESECCOMP");
+            }
+            if unsafe { libc::prctl(libc::PR_GET_SECCOMP, 0, 0, 0, 0) } < 0 {
+                return Err(Error::Unsupported("seccomp").into());
+            }
+        }
+        if self.drop_policy == DropPolicy::Kill && !capabilities::probe_pidfd() {
+            return Err(Error::Unsupported("pidfd_open").into());
+        }
+        if self.suspend_seccomp {
+            check_suspend_seccomp()?;
+        }
+        self.check_privileges()?;
+        preload_value(&self.preload)?;
+        Ok(())
+    }
+
+    /// Check that the tracer has the capabilities these options need.
+    fn check_privileges(&self) -> io::Result<()> {
+        let status = fs::read_to_string("/proc/self/status")?;
+        let caps = status
+            .lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+            .unwrap_or(0);
+        let require = |needed: bool, cap: u64, name| -> io::Result<()> {
+            if needed && caps & cap == 0 {
+                return Err(Error::MissingCapability(name).into());
+            }
+            Ok(())
+        };
+        match self.new_root {
+            Some(NewRoot::Chroot(_)) => require(true, CAP_SYS_CHROOT, "CAP_SYS_CHROOT")?,
+            Some(NewRoot::PivotRoot(_)) => require(true, CAP_SYS_ADMIN, "CAP_SYS_ADMIN")?,
+            None => {}
+        }
+        // Switching to the real or effective ID is allowed.
+        let new_uid = self.uid.is_some_and(|uid| {
+            let uid = Uid::from_raw(uid);
+            uid != Uid::current() && uid != Uid::effective()
+        });
+        require(new_uid, CAP_SETUID, "CAP_SETUID")?;
+        let new_gid = self.gid.is_some_and(|gid| {
+            let gid = Gid::from_raw(gid);
+            gid != Gid::current() && gid != Gid::effective()
+        });
+        // Root's groups are cleared when changing user.
+        let groups = self.groups.is_some() || (self.uid.is_some() && Uid::effective().is_root());
+        require(new_gid || groups, CAP_SETGID, "CAP_SETGID")?;
+        if let Some((SchedPolicy::Fifo, priority)) | Some((SchedPolicy::RoundRobin, priority)) =
+            self.scheduler
+        {
+            let limit = rlimit(libc::RLIMIT_RTPRIO)?;
+            require(priority as u64 > limit, CAP_SYS_NICE, "CAP_SYS_NICE")?;
+        }
+        if let Some(nice) = self.nice {
+            let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+            // RLIMIT_NICE allows values down to 20 minus the limit.
+            let allowed = nice >= current || 20 - nice as i64 <= rlimit(libc::RLIMIT_NICE)? as i64;
+            require(!allowed, CAP_SYS_NICE, "CAP_SYS_NICE")?;
+        }
+        if let Some(adj) = self.oom_score_adj {
+            let current: i32 = fs::read_to_string("/proc/self/oom_score_adj")?
+                .trim()
+                .parse()
+                .unwrap_or(0);
+            require(adj < current, CAP_SYS_RESOURCE, "CAP_SYS_RESOURCE")?;
+        }
+        Ok(())
+    }
+
+    /// Run in the child between `fork` and `exec`.
+    ///
+    /// If `traceme` is false the child isn't traced, but the hooks still run
+    /// in the same order.
+    pub(crate) fn pre_exec(&mut self, traceme: bool) -> io::Result<()> {
+        for hook in &mut self.before_traceme {
+            hook()?;
+        }
+        if traceme {
+            match &self.handshake {
+                Some(handshake) => handshake.wait_for_tracer()?,
+                // Opt-in to ptrace.
+                None => ptrace::traceme().map_err(nix_error)?,
+            }
+        }
+        for hook in &mut self.after_traceme {
+            hook()?;
+        }
+        self.close_fds()?;
+        self.start_session()?;
+        self.change_root()?;
+        self.set_scheduling()?;
+        self.drop_privileges()?;
+        if traceme && self.initial_stop == InitialStop::RaisedStop {
+            signal::raise(Signal::SIGSTOP).map_err(nix_error)?;
+        }
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        if let Some((_, program)) = &self.seccomp {
+            seccomp::install(program, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Build the arguments and environment for [`exec_at`] from `command`,
+    /// before it is spawned.
+    ///
+    /// [`exec_at`]: #method.exec_at
+    pub(crate) fn prepare_exec(&mut self, command: &Command) -> io::Result<()> {
+        let exec_at = match &mut self.exec_at {
+            Some(exec_at) => exec_at,
+            None => return Ok(()),
+        };
+        let c_string = |s: &OsStr| {
+            CString::new(s.as_bytes())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "argument contains a NUL"))
+        };
+        let mut strings = vec![c_string(exec_at.path.as_os_str())?];
+        for arg in iter::once(command.get_program()).chain(command.get_args()) {
+            strings.push(c_string(arg)?);
+        }
+        let argc = strings.len() - 1;
+        let mut vars: Vec<_> = env::vars_os().collect();
+        for (name, value) in command.get_envs() {
+            vars.retain(|(n, _)| n != name);
+            if let Some(value) = value {
+                vars.push((name.to_owned(), value.to_owned()));
+            }
+        }
+        for (mut name, value) in vars {
+            name.push("=");
+            name.push(value);
+            strings.push(c_string(&name)?);
+        }
+        let pointers = |strings: &[CString]| {
+            let mut pointers: Vec<_> = strings.iter().map(|s| s.as_ptr()).collect();
+            pointers.push(ptr::null());
+            pointers
+        };
+        exec_at.argv = pointers(&strings[1..=argc]);
+        exec_at.envp = pointers(&strings[argc + 1..]);
+        exec_at.strings = strings;
+        Ok(())
+    }
+
+    /// Call `execveat` in the child, if [`exec_at`] was set, after
+    /// everything else. This only returns if it fails.
+    ///
+    /// [`exec_at`]: #method.exec_at
+    pub(crate) fn exec(&self) -> io::Result<()> {
+        let exec_at = match &self.exec_at {
+            Some(exec_at) => exec_at,
+            None => return Ok(()),
+        };
+        let flags = if exec_at.path.as_os_str().is_empty() {
+            libc::AT_EMPTY_PATH
+        } else {
+            0
+        };
+        unsafe {
+            libc::syscall(
+                libc::SYS_execveat,
+                exec_at.dirfd,
+                exec_at.strings[0].as_ptr(),
+                exec_at.argv.as_ptr(),
+                exec_at.envp.as_ptr(),
+                flags,
+            );
+        }
+        Err(io::Error::last_os_error())
+    }
+
+    /// Apply the session settings in the child.
+    fn start_session(&self) -> io::Result<()> {
+        if self.setsid {
+            unistd::setsid().map_err(nix_error)?;
+        }
+        if let Some(fd) = self.controlling_terminal {
+            if unsafe { libc::ioctl(fd, libc::TIOCSCTTY, 0) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        match self.foreground {
+            Some(fd) if !self.setsid => {
+                unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(nix_error)?;
+                set_foreground(fd, unistd::getpgrp())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply the file descriptor settings in the child.
+    fn close_fds(&self) -> io::Result<()> {
+        if let Some(min_fd) = self.close_fds_from {
+            // Mark every gap between kept descriptors.
+            let mut from = min_fd.max(0) as libc::c_uint;
+            for &fd in self.keep_fds.iter().filter(|&&fd| fd >= min_fd) {
+                // A kept descriptor at the start leaves no gap before it.
+                if let Some(last) = (fd as libc::c_uint).checked_sub(1) {
+                    mark_cloexec(from, last)?;
+                }
+                from = fd as libc::c_uint + 1;
+            }
+            mark_cloexec(from, libc::c_uint::MAX)?;
+        }
+        for &fd in &self.keep_fds {
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the root directory setting in the child.
+    fn change_root(&self) -> io::Result<()> {
+        match &self.new_root {
+            None => return Ok(()),
+            Some(NewRoot::Chroot(root)) => unistd::chroot(root.as_path()).map_err(nix_error)?,
+            Some(NewRoot::PivotRoot(root)) => {
+                sched::unshare(CloneFlags::CLONE_NEWNS).map_err(nix_error)?;
+                let none: Option<&str> = None;
+                mount::mount(none, "/", none, MsFlags::MS_REC | MsFlags::MS_PRIVATE, none)
+                    .map_err(nix_error)?;
+                mount::mount(
+                    Some(root.as_path()),
+                    root.as_path(),
+                    none,
+                    MsFlags::MS_BIND | MsFlags::MS_REC,
+                    none,
+                )
+                .map_err(nix_error)?;
+                unistd::chdir(root.as_path()).map_err(nix_error)?;
+                // Stack the old root on top of the new one, then detach it.
+                unistd::pivot_root(".", ".").map_err(nix_error)?;
+                mount::umount2(".", MntFlags::MNT_DETACH).map_err(nix_error)?;
+            }
+        }
+        unistd::chdir("/").map_err(nix_error)
+    }
+
+    /// Apply the scheduling settings in the child.
+    fn set_scheduling(&self) -> io::Result<()> {
+        if let Some((policy, priority)) = self.scheduler {
+            let param = libc::sched_param {
+                sched_priority: priority,
+            };
+            if unsafe { libc::sched_setscheduler(0, policy.as_raw(), &param) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(nice) = self.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the credential settings in the child.
+    fn drop_privileges(&self) -> io::Result<()> {
+        let groups = match &self.groups {
+            Some(groups) => Some(&groups[..]),
+            // Don't leave root's groups behind when changing user.
+            None if self.uid.is_some() && Uid::current().is_root() => Some(&[][..]),
+            None => None,
+        };
+        if let Some(groups) = groups {
+            if unsafe { libc::setgroups(groups.len(), groups.as_ptr()) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(gid) = self.gid {
+            unistd::setgid(Gid::from_raw(gid)).map_err(nix_error)?;
+        }
+        if let Some(uid) = self.uid {
+            unistd::setuid(Uid::from_raw(uid)).map_err(nix_error)?;
+        }
+        if self.no_new_privs && unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for SpawnOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("SpawnOptions");
+        f.field("before_traceme", &self.before_traceme.len())
+            .field("after_traceme", &self.after_traceme.len())
+            .field("ptrace_options", &self.ptrace_options)
+            .field("suspend_seccomp", &self.suspend_seccomp)
+            .field("pty", &self.pty)
+            .field("oom_score_adj", &self.oom_score_adj)
+            .field("cgroup", &self.cgroup)
+            .field("perf_counters", &self.perf_counters)
+            .field("preload", &self.preload)
+            .field("drop_policy", &self.drop_policy)
+            .field("initial_stop", &self.initial_stop)
+            .field("setsid", &self.setsid)
+            .field("foreground", &self.foreground)
+            .field("controlling_terminal", &self.controlling_terminal)
+            .field("close_fds_from", &self.close_fds_from)
+            .field("keep_fds", &self.keep_fds)
+            .field(
+                "exec_at",
+                &self.exec_at.as_ref().map(|e| (e.dirfd, &e.path)),
+            )
+            .field("new_root", &self.new_root)
+            .field("scheduler", &self.scheduler)
+            .field("nice", &self.nice)
+            .field("uid", &self.uid)
+            .field("gid", &self.gid)
+            .field("groups", &self.groups)
+            .field("no_new_privs", &self.no_new_privs);
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        f.field(
+            "seccomp_filter",
+            &self.seccomp.as_ref().map(|(filter, _)| filter),
+        );
+        f.finish()
+    }
+}
+
+/// Mark the descriptors from `first` to `last` inclusive as close-on-exec.
+pub(crate) fn mark_cloexec(first: libc::c_uint, last: libc::c_uint) -> io::Result<()> {
+    if first > last {
+        return Ok(());
+    }
+    let flags = libc::CLOSE_RANGE_CLOEXEC;
+    let ret = unsafe { libc::syscall(libc::SYS_close_range, first, last, flags) };
+    if ret == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() != Some(libc::ENOSYS) && e.raw_os_error() != Some(libc::EINVAL) {
+        return Err(e);
+    }
+    // Older kernels lack close_range or its CLOEXEC flag, so mark each
+    // descriptor below the limit individually.
+    let limit = rlimit(libc::RLIMIT_NOFILE)?;
+    let end = (last as libc::rlim_t).min(limit.saturating_sub(1));
+    for fd in first as libc::rlim_t..=end {
+        // Descriptors that aren't open fail with EBADF, which is fine.
+        unsafe { libc::fcntl(fd as libc::c_int, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    Ok(())
+}
+
+/// The soft limit of this process for `resource`.
+fn rlimit(resource: libc::__rlimit_resource_t) -> io::Result<libc::rlim_t> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(resource, &mut limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit.rlim_cur)
+}
+
+/// Make `pgrp` the foreground process group of the terminal open as `fd`.
+///
+/// Only async-signal-safe calls are made, since this runs in the child.
+pub(crate) fn set_foreground(fd: RawFd, pgrp: Pid) -> io::Result<()> {
+    // A background process changing the foreground group gets SIGTTOU
+    // unless it ignores it.
+    let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+    let previous = unsafe { signal::sigaction(Signal::SIGTTOU, &ignore) }.map_err(nix_error)?;
+    let result = unistd::tcsetpgrp(fd, pgrp).map_err(nix_error);
+    unsafe { signal::sigaction(Signal::SIGTTOU, &previous) }.map_err(nix_error)?;
+    result
+}
+
+/// Prepend `libraries` to the `LD_PRELOAD` that `command` will run with,
+/// returning what [`restore_preload`] puts back once it has been spawned,
+/// or `None` if nothing was changed.
+pub(crate) fn set_preload(
+    command: &mut Command,
+    libraries: &[PathBuf],
+) -> io::Result<Option<Option<OsString>>> {
+    let mut value = match preload_value(libraries)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    // A value set on the command wins over the inherited one, and removing
+    // it on the command leaves nothing to merge with.
+    let existing = match command.get_envs().find(|(key, _)| *key == "LD_PRELOAD") {
+        Some((_, value)) => value.map(OsStr::to_os_string),
+        None => env::var_os("LD_PRELOAD"),
+    };
+    if let Some(existing) = existing.as_ref().filter(|existing| !existing.is_empty()) {
+        value.push(":");
+        value.push(existing);
+    }
+    command.env("LD_PRELOAD", value);
+    Ok(Some(existing))
+}
+
+/// Put back the `LD_PRELOAD` of `command` that [`set_preload`] replaced,
+/// so that spawning it again doesn't preload the libraries twice.
+///
+/// An inherited value is put back as one set on the command, which only
+/// differs if this process changes its own `LD_PRELOAD` later.
+pub(crate) fn restore_preload(command: &mut Command, saved: Option<Option<OsString>>) {
+    match saved {
+        Some(Some(value)) => {
+            command.env("LD_PRELOAD", value);
+        }
+        Some(None) => {
+            command.env_remove("LD_PRELOAD");
+        }
+        None => {}
+    }
+}
+
+/// The `LD_PRELOAD` entries that preload `libraries`, checking that they
+/// exist, or `None` if there are none.
+fn preload_value(libraries: &[PathBuf]) -> io::Result<Option<OsString>> {
+    if libraries.is_empty() {
+        return Ok(None);
+    }
+    let mut value = OsString::new();
+    for library in libraries {
+        let path = fs::canonicalize(library).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Can't preload {}: {}", library.display(), e),
+            )
+        })?;
+        if path
+            .as_os_str()
+            .as_bytes()
+            .iter()
+            .any(|&b| b == b':' || b == b' ')
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can't preload {}: invalid path", path.display()),
+            ));
+        }
+        if !value.is_empty() {
+            value.push(":");
+        }
+        value.push(path);
+    }
+    Ok(Some(value))
+}
+
+/// Check that this process is allowed to set `PTRACE_O_SUSPEND_SECCOMP`.
+pub(crate) fn check_suspend_seccomp() -> io::Result<()> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(str::trim)
+    };
+    if field("Seccomp:").is_some_and(|mode| mode != "0") {
+        return Err(Error::TracerSeccomp.into());
+    }
+    let caps = field("CapEff:").and_then(|caps| u64::from_str_radix(caps, 16).ok());
+    if caps.is_some_and(|caps| caps & CAP_SYS_ADMIN == 0) {
+        return Err(Error::MissingCapability("CAP_SYS_ADMIN").into());
+    }
+    Ok(())
+}
+
+/// Set `options` on the stopped tracee `pid`, optionally along with
+/// `PTRACE_O_SUSPEND_SECCOMP`, which `nix` doesn't know about.
+pub(crate) fn set_options(pid: Pid, options: Options, suspend_seccomp: bool) -> io::Result<()> {
+    if !suspend_seccomp {
+        return ptrace::setoptions(pid, options).map_err(nix_error);
+    }
+    let bits = options.bits() | libc::PTRACE_O_SUSPEND_SECCOMP;
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_SETOPTIONS,
+            pid.as_raw(),
+            ptr::null_mut::<libc::c_void>(),
+            bits as libc::c_ulong,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        return Err(match e.raw_os_error() {
+            // The capability must be held in the initial user namespace,
+            // which the check before spawning can't tell.
+            Some(libc::EPERM) => Error::MissingCapability("CAP_SYS_ADMIN").into(),
+            Some(libc::EINVAL) => Error::Unsupported("PTRACE_O_SUSPEND_SECCOMP").into(),
+            _ => e,
+        });
+    }
+    Ok(())
+}
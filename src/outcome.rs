@@ -0,0 +1,114 @@
+//! How a finished tracee ended.
+
+use crate::ForkOutcome;
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+/// How a tracee finished, with what the tracer saw of it on the way.
+///
+/// Unlike `std::process::ExitStatus`, this says whether a terminating
+/// signal dumped core and whether the tracer was the one that killed the
+/// tracee, and counts the stops and signals it went through. It is returned
+/// by [`Tracee::run_to_exit`] and in [`TracedOutput`], and can be made from
+/// a final `WaitStatus` or a [`ForkOutcome`], for which the counters are
+/// left at zero.
+///
+/// [`Tracee::run_to_exit`]: struct.Tracee.html#method.run_to_exit
+/// [`TracedOutput`]: struct.TracedOutput.html
+/// [`ForkOutcome`]: enum.ForkOutcome.html
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Outcome {
+    /// The exit status, if the tracee exited normally.
+    pub exit_code: Option<i32>,
+    /// The signal that terminated the tracee, if one did.
+    pub signal: Option<Signal>,
+    /// Whether the terminating signal dumped core.
+    pub core_dumped: bool,
+    /// Whether the tracer killed the tracee, for example on a timeout.
+    pub killed_by_tracer: bool,
+    /// How many ptrace stops the tracee reported before it finished.
+    pub stops: usize,
+    /// The signals the tracee received, in order, other than the `SIGTRAP`s
+    /// of ptrace's own stops.
+    pub signals: Vec<Signal>,
+}
+
+impl Outcome {
+    /// The outcome of a tracee whose final status is `status`, or `None` if
+    /// the status isn't `WaitStatus::Exited` or `WaitStatus::Signaled`.
+    pub fn from_status(status: WaitStatus) -> Option<Outcome> {
+        match status {
+            WaitStatus::Exited(_, code) => Some(Outcome {
+                exit_code: Some(code),
+                ..Outcome::default()
+            }),
+            WaitStatus::Signaled(_, signal, core_dumped) => Some(Outcome {
+                signal: Some(signal),
+                core_dumped,
+                ..Outcome::default()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether the tracee exited with status 0.
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    /// The equivalent `std::process::ExitStatus`, which loses everything but
+    /// the exit code or signal.
+    pub fn exit_status(&self) -> ExitStatus {
+        let raw = match (self.exit_code, self.signal) {
+            (Some(code), _) => (code & 0xff) << 8,
+            (None, Some(signal)) => signal as i32 | if self.core_dumped { 0x80 } else { 0 },
+            (None, None) => 0,
+        };
+        ExitStatus::from_raw(raw)
+    }
+}
+
+impl From<ForkOutcome> for Outcome {
+    fn from(outcome: ForkOutcome) -> Outcome {
+        match outcome {
+            ForkOutcome::Exited(code) => Outcome {
+                exit_code: Some(code),
+                ..Outcome::default()
+            },
+            ForkOutcome::Crashed(signal, core_dumped) => Outcome {
+                signal: Some(signal),
+                core_dumped,
+                ..Outcome::default()
+            },
+            ForkOutcome::TimedOut => Outcome {
+                signal: Some(Signal::SIGKILL),
+                killed_by_tracer: true,
+                ..Outcome::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::Pid;
+
+    #[test]
+    fn test_outcome_exit_status() {
+        let pid = Pid::from_raw(1);
+        let exited = Outcome::from_status(WaitStatus::Exited(pid, 3)).unwrap();
+        assert!(!exited.success());
+        assert_eq!(exited.exit_status().code(), Some(3));
+        let crashed =
+            Outcome::from_status(WaitStatus::Signaled(pid, Signal::SIGSEGV, true)).unwrap();
+        assert_eq!(crashed.exit_status().signal(), Some(libc::SIGSEGV));
+        assert!(crashed.exit_status().core_dumped());
+        assert_eq!(Outcome::from_status(WaitStatus::StillAlive), None);
+        let timed_out = Outcome::from(ForkOutcome::TimedOut);
+        assert!(timed_out.killed_by_tracer);
+        assert_eq!(timed_out.signal, Some(Signal::SIGKILL));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::Outcome;
+use nix::sys::wait::WaitStatus;
+use std::io::{self, Read};
+use std::process::Child;
+use std::thread::{self, JoinHandle};
+
+/// The output collected from a tracee by an [`OutputCapture`], along with
+/// how it finished.
+///
+/// [`OutputCapture`]: struct.OutputCapture.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TracedOutput {
+    /// The final wait status of the tracee.
+    pub status: WaitStatus,
+    /// How the tracee finished. Its counters are only filled in by
+    /// [`Tracee::wait_with_output`].
+    ///
+    /// [`Tracee::wait_with_output`]: struct.Tracee.html#method.wait_with_output
+    pub outcome: Outcome,
+    /// Everything the tracee wrote to its piped stdout.
+    pub stdout: Vec<u8>,
+    /// Everything the tracee wrote to its piped stderr.
+    pub stderr: Vec<u8>,
+}
+
+/// Drains a tracee's piped stdout and stderr on background threads.
+///
+/// A tracee whose output goes to a pipe can fill the pipe and block in
+/// `write` while the tracer is busy handling its stops, and the tracer can
+/// end up waiting for a stop that never comes. Creating an `OutputCapture`
+/// as soon as the tracee is spawned takes the pipes from its `Child` and
+/// reads them continuously until they are closed, so the tracee never
+/// blocks on them.
+///
+/// Only pipes that were requested with `Stdio::piped()` and haven't already
+/// been taken are captured. Once the tracee has exited, pass its final
+/// status to [`finish`] to collect the output.
+///
+/// [`finish`]: #method.finish
+#[derive(Debug)]
+pub struct OutputCapture {
+    stdout: Option<JoinHandle<io::Result<Vec<u8>>>>,
+    stderr: Option<JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+impl OutputCapture {
+    /// Start draining the piped stdout and stderr of `child`.
+    pub fn new(child: &mut Child) -> OutputCapture {
+        OutputCapture {
+            stdout: child.stdout.take().map(drain),
+            stderr: child.stderr.take().map(drain),
+        }
+    }
+
+    /// Wait for both pipes to be closed and return everything read from them.
+    ///
+    /// The pipes are closed when every process holding their write ends has
+    /// exited, so this blocks while any descendant of the tracee that
+    /// inherited them is still running.
+    pub fn finish(self, status: WaitStatus) -> io::Result<TracedOutput> {
+        Ok(TracedOutput {
+            status,
+            outcome: Outcome::from_status(status).unwrap_or_default(),
+            stdout: join(self.stdout)?,
+            stderr: join(self.stderr)?,
+        })
+    }
+}
+
+fn drain<R: Read + Send + 'static>(mut pipe: R) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        pipe.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+}
+
+fn join(handle: Option<JoinHandle<io::Result<Vec<u8>>>>) -> io::Result<Vec<u8>> {
+    match handle {
+        Some(handle) => handle
+            .join()
+            .map_err(|_| io::Error::other("Output drainer thread panicked"))?,
+        None => Ok(vec![]),
+    }
+}
@@ -0,0 +1,411 @@
+//! Performance counters for a tracee, using `perf_event_open`.
+
+use crate::Error;
+use nix::unistd::Pid;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use std::sync::atomic::{self, Ordering};
+use std::time::Duration;
+
+pub(crate) const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_TYPE_SOFTWARE: u32 = 1;
+
+const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+
+// Bits of the `perf_event_attr` flags bitfield.
+const ATTR_INHERIT: u64 = 1 << 1;
+pub(crate) const ATTR_EXCLUDE_KERNEL: u64 = 1 << 5;
+pub(crate) const ATTR_EXCLUDE_HV: u64 = 1 << 6;
+pub(crate) const ATTR_WRITE_BACKWARD: u64 = 1 << 27;
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+/// `_IO('$', 3)`.
+const PERF_EVENT_IOC_RESET: libc::Ioctl = 0x2403;
+
+// Offsets into `struct perf_event_mmap_page`.
+const DATA_HEAD: usize = 1024;
+const DATA_TAIL: usize = 1032;
+
+/// `struct perf_event_attr` up to `branch_sample_type`, which is the third
+/// published version.
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct PerfEventAttr {
+    pub(crate) kind: u32,
+    pub(crate) size: u32,
+    pub(crate) config: u64,
+    pub(crate) sample_period: u64,
+    pub(crate) sample_type: u64,
+    pub(crate) read_format: u64,
+    pub(crate) flags: u64,
+    pub(crate) wakeup_events: u32,
+    pub(crate) bp_type: u32,
+    pub(crate) config1: u64,
+    pub(crate) config2: u64,
+    pub(crate) branch_sample_type: u64,
+}
+
+impl PerfEventAttr {
+    pub(crate) fn new(kind: u32, config: u64) -> PerfEventAttr {
+        PerfEventAttr {
+            kind,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            ..Default::default()
+        }
+    }
+}
+
+/// An event that a [`PerfCounters`] can count.
+///
+/// [`PerfCounters`]: struct.PerfCounters.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PerfCounter {
+    /// Retired instructions.
+    Instructions,
+    /// CPU cycles.
+    Cycles,
+    /// Cache references, usually to the last level cache.
+    CacheReferences,
+    /// Cache misses, usually in the last level cache.
+    CacheMisses,
+    /// Retired branch instructions.
+    Branches,
+    /// Mispredicted branches.
+    BranchMisses,
+    /// Nanoseconds spent running on a CPU. This is a software counter, so
+    /// it is always available.
+    TaskClock,
+    /// Page faults.
+    PageFaults,
+    /// Context switches.
+    ContextSwitches,
+}
+
+impl PerfCounter {
+    fn kind_and_config(self) -> (u32, u64) {
+        match self {
+            PerfCounter::Cycles => (PERF_TYPE_HARDWARE, 0),
+            PerfCounter::Instructions => (PERF_TYPE_HARDWARE, 1),
+            PerfCounter::CacheReferences => (PERF_TYPE_HARDWARE, 2),
+            PerfCounter::CacheMisses => (PERF_TYPE_HARDWARE, 3),
+            PerfCounter::Branches => (PERF_TYPE_HARDWARE, 4),
+            PerfCounter::BranchMisses => (PERF_TYPE_HARDWARE, 5),
+            PerfCounter::TaskClock => (PERF_TYPE_SOFTWARE, 1),
+            PerfCounter::PageFaults => (PERF_TYPE_SOFTWARE, 2),
+            PerfCounter::ContextSwitches => (PERF_TYPE_SOFTWARE, 3),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PerfCounter::Instructions => "the instructions counter",
+            PerfCounter::Cycles => "the cycles counter",
+            PerfCounter::CacheReferences => "the cache references counter",
+            PerfCounter::CacheMisses => "the cache misses counter",
+            PerfCounter::Branches => "the branches counter",
+            PerfCounter::BranchMisses => "the branch misses counter",
+            PerfCounter::TaskClock => "the task clock",
+            PerfCounter::PageFaults => "the page faults counter",
+            PerfCounter::ContextSwitches => "the context switches counter",
+        }
+    }
+}
+
+/// The value of one counter, as read by [`PerfCounters::read`].
+///
+/// [`PerfCounters::read`]: struct.PerfCounters.html#method.read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PerfValue {
+    /// The counter that was read.
+    pub counter: PerfCounter,
+    /// The raw count.
+    pub count: u64,
+    /// How long the counter has been enabled.
+    pub time_enabled: Duration,
+    /// How long the counter was actually counting. This is less than
+    /// `time_enabled` when more hardware counters were requested than the
+    /// CPU has, and the kernel had to take turns between them.
+    pub time_running: Duration,
+}
+
+impl PerfValue {
+    /// The count scaled up to estimate the total over `time_enabled`.
+    pub fn scaled(&self) -> u64 {
+        let running = self.time_running.as_nanos();
+        if running == 0 {
+            return 0;
+        }
+        (self.count as u128 * self.time_enabled.as_nanos() / running) as u64
+    }
+}
+
+/// Performance counters scoped to one traced process.
+///
+/// The counters only count in user space, and include threads and child
+/// processes the tracee creates after they were opened. Children's counts
+/// are added once they exit. The counters keep their final values after
+/// the tracee exits, so they can be read at any stop or once it is gone.
+///
+/// Open them at spawn time with [`SpawnOptions::perf_counters`], or for a
+/// stopped tracee with [`open`].
+///
+/// [`SpawnOptions::perf_counters`]: struct.SpawnOptions.html#method.perf_counters
+/// [`open`]: #method.open
+#[derive(Debug)]
+pub struct PerfCounters {
+    counters: Vec<(PerfCounter, File)>,
+}
+
+impl PerfCounters {
+    /// Start counting `counters` in process `pid`.
+    ///
+    /// Fails with `Error::Unsupported` if the CPU or kernel can't count one
+    /// of them, which is common for hardware counters in virtual machines.
+    /// Depending on `kernel.perf_event_paranoid`, counting another user's
+    /// processes may need `CAP_PERFMON`.
+    pub fn open(pid: Pid, counters: &[PerfCounter]) -> io::Result<PerfCounters> {
+        let counters = counters
+            .iter()
+            .map(|&counter| Ok((counter, open_counter(pid, counter)?)))
+            .collect::<io::Result<_>>()?;
+        Ok(PerfCounters { counters })
+    }
+
+    /// Read the current value of every counter, in the order they were opened.
+    pub fn read(&self) -> io::Result<Vec<PerfValue>> {
+        self.counters
+            .iter()
+            .map(|(counter, file)| {
+                let mut buf = [0; 24];
+                (&*file).read_exact(&mut buf)?;
+                let word = |i: usize| {
+                    let mut bytes = [0; 8];
+                    bytes.copy_from_slice(&buf[i * 8..i * 8 + 8]);
+                    u64::from_ne_bytes(bytes)
+                };
+                Ok(PerfValue {
+                    counter: *counter,
+                    count: word(0),
+                    time_enabled: Duration::from_nanos(word(1)),
+                    time_running: Duration::from_nanos(word(2)),
+                })
+            })
+            .collect()
+    }
+
+    /// Read the current value of `counter`, if it was opened.
+    pub fn get(&self, counter: PerfCounter) -> io::Result<Option<PerfValue>> {
+        Ok(self.read()?.into_iter().find(|v| v.counter == counter))
+    }
+
+    /// Reset every counter to zero.
+    pub fn reset(&self) -> io::Result<()> {
+        for (_, file) in &self.counters {
+            let ret = unsafe { libc::ioctl(file.as_raw_fd(), PERF_EVENT_IOC_RESET, 0) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open_counter(pid: Pid, counter: PerfCounter) -> io::Result<File> {
+    let (kind, config) = counter.kind_and_config();
+    let mut attr = PerfEventAttr::new(kind, config);
+    attr.read_format = PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING;
+    attr.flags = ATTR_INHERIT | ATTR_EXCLUDE_KERNEL | ATTR_EXCLUDE_HV;
+    perf_event_open(&attr, pid).map_err(|e| match e.raw_os_error() {
+        Some(libc::ENOENT) | Some(libc::EOPNOTSUPP) | Some(libc::ENODEV) => {
+            Error::Unsupported(counter.name()).into()
+        }
+        _ => e,
+    })
+}
+
+/// Open a perf event for `pid` on any CPU.
+pub(crate) fn perf_event_open(attr: &PerfEventAttr, pid: Pid) -> io::Result<File> {
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            attr as *const PerfEventAttr,
+            pid.as_raw(),
+            -1,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd as libc::c_int) })
+}
+
+/// A mapping of part of a perf event's buffer.
+#[derive(Debug)]
+pub(crate) struct Mapping {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    /// Map `len` bytes of `event`'s buffer starting at `offset`.
+    #[cfg(feature = "intel-pt")]
+    pub(crate) fn new(event: &File, offset: usize, len: usize) -> io::Result<Mapping> {
+        Mapping::with_protection(event, offset, len, libc::PROT_READ | libc::PROT_WRITE)
+    }
+
+    /// Map `len` bytes of `event`'s buffer starting at `offset`, with the
+    /// protection `prot`.
+    fn with_protection(
+        event: &File,
+        offset: usize,
+        len: usize,
+        prot: libc::c_int,
+    ) -> io::Result<Mapping> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                event.as_raw_fd(),
+                offset as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+
+    pub(crate) fn u64_at(&self, offset: usize) -> *mut u64 {
+        self.addr.wrapping_add(offset) as *mut u64
+    }
+
+    /// Copy `len` bytes starting at `offset`, wrapping around the end, or as
+    /// much as the mapping holds.
+    pub(crate) fn copy_wrapping(&self, offset: u64, len: usize) -> Vec<u8> {
+        let len = len.min(self.len);
+        let start = (offset % self.len as u64) as usize;
+        let first = len.min(self.len - start);
+        let data = unsafe { std::slice::from_raw_parts(self.addr, self.len) };
+        let mut out = Vec::with_capacity(len);
+        out.extend_from_slice(&data[start..start + first]);
+        out.extend_from_slice(&data[..len - first]);
+        out
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// The ring buffer a sampling perf event writes its records to.
+#[derive(Debug)]
+pub(crate) struct RingBuffer {
+    header: Mapping,
+    data: Mapping,
+}
+
+// The mappings are only accessed through `&mut self` or the kernel.
+unsafe impl Send for RingBuffer {}
+
+impl RingBuffer {
+    /// Map a ring buffer of `pages` pages, rounded up to a power of two, for `event`.
+    pub(crate) fn new(event: &File, pages: usize) -> io::Result<RingBuffer> {
+        RingBuffer::map(event, pages, libc::PROT_READ | libc::PROT_WRITE)
+    }
+
+    /// Map a ring buffer like `new`, but one that the kernel overwrites
+    /// once it is full instead of dropping new records, for an `event`
+    /// opened with `ATTR_WRITE_BACKWARD`. Read it with [`newest`].
+    ///
+    /// [`newest`]: #method.newest
+    pub(crate) fn overwritable(event: &File, pages: usize) -> io::Result<RingBuffer> {
+        // The kernel overwrites buffers that are mapped read-only.
+        RingBuffer::map(event, pages, libc::PROT_READ)
+    }
+
+    fn map(event: &File, pages: usize, prot: libc::c_int) -> io::Result<RingBuffer> {
+        let page = page_size();
+        let len = pages.max(1).next_power_of_two() * page;
+        // The kernel wants the header page and the data in one mapping.
+        let all = Mapping::with_protection(event, 0, page + len, prot)?;
+        let header = Mapping {
+            addr: all.addr,
+            len: page,
+        };
+        let data = Mapping {
+            addr: all.addr.wrapping_add(page),
+            len,
+        };
+        // `header` and `data` unmap the two halves separately.
+        std::mem::forget(all);
+        Ok(RingBuffer { header, data })
+    }
+
+    /// Take every record written since the last call, as the record type
+    /// and the bytes following the record header.
+    pub(crate) fn records(&mut self) -> Vec<(u32, Vec<u8>)> {
+        let head = unsafe { ptr::read_volatile(self.header.u64_at(DATA_HEAD)) };
+        atomic::fence(Ordering::Acquire);
+        let mut tail = unsafe { ptr::read_volatile(self.header.u64_at(DATA_TAIL)) };
+        let mut records = vec![];
+        while head - tail >= 8 {
+            let header = self.data.copy_wrapping(tail, 8);
+            let kind = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+            let size = u16::from_ne_bytes([header[6], header[7]]) as u64;
+            if size < 8 || head - tail < size {
+                break;
+            }
+            records.push((kind, self.data.copy_wrapping(tail + 8, size as usize - 8)));
+            tail += size;
+        }
+        atomic::fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.header.u64_at(DATA_TAIL), tail) };
+        records
+    }
+
+    /// The newest records in an [`overwritable`] ring buffer, newest first,
+    /// up to `max` of them.
+    ///
+    /// [`overwritable`]: #method.overwritable
+    pub(crate) fn newest(&self, max: usize) -> Vec<(u32, Vec<u8>)> {
+        let head = unsafe { ptr::read_volatile(self.header.u64_at(DATA_HEAD)) };
+        atomic::fence(Ordering::Acquire);
+        // Records are written backwards from 0, so the newest is at the head
+        // and the older ones follow it, as far as the buffer holds them.
+        let written = 0u64.wrapping_sub(head).min(self.data.len as u64);
+        let mut offset = 0;
+        let mut records = vec![];
+        while records.len() < max && written - offset >= 8 {
+            let header = self.data.copy_wrapping(head.wrapping_add(offset), 8);
+            let kind = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+            let size = u16::from_ne_bytes([header[6], header[7]]) as u64;
+            if size < 8 || written - offset < size {
+                break;
+            }
+            let body = head.wrapping_add(offset + 8);
+            records.push((kind, self.data.copy_wrapping(body, size as usize - 8)));
+            offset += size;
+        }
+        records
+    }
+}
+
+pub(crate) fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
@@ -0,0 +1,346 @@
+//! Checking ahead of time whether ptrace will be allowed.
+
+use crate::{ptrace_scope, Error};
+use nix::unistd::{getgid, getpid, getuid, Pid};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+
+/// The bit for `CAP_SYS_PTRACE` in a capability set.
+const CAP_SYS_PTRACE: u64 = 1 << 19;
+
+/// A reason that tracing a process will be refused, from [`TraceCheck`].
+///
+/// [`TraceCheck`]: struct.TraceCheck.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TraceDenial {
+    /// A process can't trace itself or its own threads.
+    SameProcess,
+    /// The process is already traced by the given tracer thread.
+    AlreadyTraced(Pid),
+    /// The process's user or group IDs differ from the tracer's real ones,
+    /// for example because it runs a setuid program, and the tracer lacks
+    /// `CAP_SYS_PTRACE`.
+    DifferentUser,
+    /// The process is not dumpable, for example because it changed its
+    /// credentials or called `prctl(PR_SET_DUMPABLE, 0)`, and the tracer
+    /// lacks `CAP_SYS_PTRACE`.
+    NotDumpable,
+    /// Yama's `ptrace_scope` is 1 and the process isn't a descendant of the
+    /// tracer. The process can still allow it with `PR_SET_PTRACER`, which
+    /// can't be checked from outside.
+    NotDescendant,
+    /// Yama's `ptrace_scope` is 2, which needs `CAP_SYS_PTRACE`.
+    AdminOnly,
+    /// Yama's `ptrace_scope` is 3, which disables ptrace.
+    Disabled,
+}
+
+impl fmt::Display for TraceDenial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceDenial::SameProcess => write!(f, "A process can't trace itself"),
+            TraceDenial::AlreadyTraced(tracer) => {
+                write!(f, "The process is already traced by {}", tracer)
+            }
+            TraceDenial::DifferentUser => {
+                write!(f, "The process runs with other credentials than the tracer")
+            }
+            TraceDenial::NotDumpable => write!(f, "The process is not dumpable"),
+            TraceDenial::NotDescendant => write!(
+                f,
+                "Yama only allows tracing descendants, unless the process allows the tracer"
+            ),
+            TraceDenial::AdminOnly => write!(f, "Yama only allows tracing with CAP_SYS_PTRACE"),
+            TraceDenial::Disabled => write!(f, "Yama has disabled ptrace"),
+        }
+    }
+}
+
+/// The result of [`can_trace`] or [`can_spawn_traced`].
+///
+/// This explains every rule that will refuse ptrace, so a frontend can
+/// tell the user what to change. The check is made at one point in time
+/// and the kernel has the last word; other security modules, such as
+/// SELinux or AppArmor, and seccomp filters aren't considered.
+///
+/// [`can_trace`]: fn.can_trace.html
+/// [`can_spawn_traced`]: fn.can_spawn_traced.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceCheck {
+    /// Whether the tracer has `CAP_SYS_PTRACE`, which overrides the user ID,
+    /// dumpable and Yama scope 1 and 2 rules.
+    pub cap_sys_ptrace: bool,
+    /// Yama's `ptrace_scope`, or `None` without Yama.
+    pub yama_scope: Option<u32>,
+    /// Everything that will refuse ptrace.
+    pub denials: Vec<TraceDenial>,
+}
+
+impl TraceCheck {
+    /// Whether nothing was found that will refuse ptrace.
+    pub fn is_allowed(&self) -> bool {
+        self.denials.is_empty()
+    }
+}
+
+/// Check whether this process will be allowed to attach to process `pid`.
+///
+/// Fails with `ErrorKind::NotFound` if there is no such process.
+pub fn can_trace(pid: Pid) -> io::Result<TraceCheck> {
+    let mut check = tracer_check()?;
+    let status = Status::read(pid)?;
+    if status.tgid == getpid() {
+        check.denials.push(TraceDenial::SameProcess);
+    }
+    if let Some(tracer) = status.tracer {
+        check.denials.push(TraceDenial::AlreadyTraced(tracer));
+    }
+    if !check.cap_sys_ptrace {
+        let (uid, gid) = (getuid().as_raw(), getgid().as_raw());
+        let uids_match = status.uids.iter().all(|&id| id == uid);
+        if !uids_match || !status.gids.iter().all(|&id| id == gid) {
+            check.denials.push(TraceDenial::DifferentUser);
+        }
+        // The kernel gives the /proc files of processes that aren't
+        // dumpable to root, which only shows for processes of other users.
+        let owner = fs::metadata(format!("/proc/{}", pid))?.uid();
+        if owner == 0 && status.uids[1] != 0 {
+            check.denials.push(TraceDenial::NotDumpable);
+        }
+        if check.yama_scope == Some(1) && !is_descendant(pid)? {
+            check.denials.push(TraceDenial::NotDescendant);
+        }
+    }
+    Ok(check)
+}
+
+/// What happened to a tracee's credentials when it called `exec`, from
+/// [`Tracee::check_exec`].
+///
+/// [`Tracee::check_exec`]: struct.Tracee.html#method.check_exec
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecCredentials {
+    /// The tracee kept its credentials, since the program isn't setuid or
+    /// setgid, or they made no difference.
+    Unchanged,
+    /// The program is setuid or setgid, but the kernel ran it with the old
+    /// credentials, because a tracer without `CAP_SYS_PTRACE` traces it. The
+    /// program may fail where it expects the privileges.
+    Withheld,
+    /// The tracee gained the program's credentials, which is only allowed
+    /// for a tracer with `CAP_SYS_PTRACE`.
+    Gained,
+}
+
+/// Check what happened to the credentials of tracee `pid` at the `exec` it
+/// is stopped for.
+pub(crate) fn check_exec(pid: Pid) -> io::Result<ExecCredentials> {
+    let status = Status::read(pid)?;
+    let exe = fs::metadata(format!("/proc/{}/exe", pid))?;
+    let credentials = exec_credentials(exe.mode(), exe.uid(), exe.gid(), &status);
+    if credentials == ExecCredentials::Gained && !tracer_check()?.cap_sys_ptrace {
+        return Err(Error::NotDumpable(pid).into());
+    }
+    Ok(credentials)
+}
+
+/// Compare a process's IDs with what executing a program with `mode`,
+/// owned by `uid` and `gid`, would give it.
+fn exec_credentials(mode: u32, uid: u32, gid: u32, status: &Status) -> ExecCredentials {
+    let [real_uid, effective_uid, _] = status.uids;
+    let [real_gid, effective_gid, _] = status.gids;
+    let setuid = mode & libc::S_ISUID != 0 && uid != real_uid;
+    // Without group execute permission the bit means mandatory locking.
+    let setgid =
+        mode & (libc::S_ISGID | libc::S_IXGRP) == libc::S_ISGID | libc::S_IXGRP && gid != real_gid;
+    if (setuid && effective_uid != uid) || (setgid && effective_gid != gid) {
+        ExecCredentials::Withheld
+    } else if setuid || setgid {
+        ExecCredentials::Gained
+    } else {
+        ExecCredentials::Unchanged
+    }
+}
+
+/// Check whether this process will be allowed to trace the children it
+/// spawns with this crate.
+///
+/// Children start out with the tracer's credentials and are its
+/// descendants, so only Yama can refuse them, unless they `exec` a setuid
+/// program.
+pub fn can_spawn_traced() -> io::Result<TraceCheck> {
+    tracer_check()
+}
+
+/// The checks that depend only on the tracer.
+fn tracer_check() -> io::Result<TraceCheck> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    let cap_sys_ptrace = field(&status, "CapEff:")
+        .and_then(|caps| u64::from_str_radix(caps, 16).ok())
+        .is_some_and(|caps| caps & CAP_SYS_PTRACE != 0);
+    let yama_scope = ptrace_scope()?;
+    let mut denials = vec![];
+    match yama_scope {
+        Some(2) if !cap_sys_ptrace => denials.push(TraceDenial::AdminOnly),
+        Some(3) => denials.push(TraceDenial::Disabled),
+        _ => {}
+    }
+    Ok(TraceCheck {
+        cap_sys_ptrace,
+        yama_scope,
+        denials,
+    })
+}
+
+/// Whether `pid` is a descendant of this process.
+fn is_descendant(pid: Pid) -> io::Result<bool> {
+    let me = getpid();
+    let mut pid = pid;
+    while pid.as_raw() > 1 {
+        let status = match Status::read(pid) {
+            Ok(status) => status,
+            // An ancestor exiting while we look means we aren't one.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if status.ppid == me {
+            return Ok(true);
+        }
+        pid = status.ppid;
+    }
+    Ok(false)
+}
+
+/// The fields of `/proc/<pid>/status` these checks need.
+struct Status {
+    tgid: Pid,
+    ppid: Pid,
+    tracer: Option<Pid>,
+    /// The real, effective and saved user IDs.
+    uids: [u32; 3],
+    /// The real, effective and saved group IDs.
+    gids: [u32; 3],
+}
+
+impl Status {
+    fn read(pid: Pid) -> io::Result<Status> {
+        let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Malformed status file");
+        let pid_field = |name: &str| {
+            field(&status, name)
+                .and_then(|value| value.parse().ok())
+                .map(Pid::from_raw)
+                .ok_or_else(invalid)
+        };
+        let ids_field = |name: &str| {
+            let ids: Vec<u32> = field(&status, name)
+                .ok_or_else(invalid)?
+                .split_whitespace()
+                .map(|id| id.parse().map_err(|_| invalid()))
+                .collect::<io::Result<_>>()?;
+            match ids[..] {
+                [real, effective, saved, ..] => Ok([real, effective, saved]),
+                _ => Err(invalid()),
+            }
+        };
+        let tracer = pid_field("TracerPid:")?;
+        Ok(Status {
+            tgid: pid_field("Tgid:")?,
+            ppid: pid_field("PPid:")?,
+            tracer: Some(tracer).filter(|tracer| tracer.as_raw() != 0),
+            uids: ids_field("Uid:")?,
+            gids: ids_field("Gid:")?,
+        })
+    }
+}
+
+fn field<'a>(status: &'a str, name: &str) -> Option<&'a str> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(name))
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::process::Command;
+
+    #[test]
+    fn test_exec_credentials() {
+        let status = Status {
+            tgid: getpid(),
+            ppid: getpid(),
+            tracer: None,
+            uids: [1000, 1000, 1000],
+            gids: [1000, 1000, 1000],
+        };
+        let setuid = libc::S_ISUID | 0o755;
+        let setgid = libc::S_ISGID | 0o755;
+        let check = |mode, status: &Status| exec_credentials(mode, 0, 0, status);
+        assert_eq!(check(0o755, &status), ExecCredentials::Unchanged);
+        assert_eq!(check(setuid, &status), ExecCredentials::Withheld);
+        assert_eq!(check(setgid, &status), ExecCredentials::Withheld);
+        assert_eq!(
+            check(libc::S_ISGID | 0o644, &status),
+            ExecCredentials::Unchanged
+        );
+        let gained = Status {
+            uids: [1000, 0, 0],
+            ..status
+        };
+        assert_eq!(check(setuid, &gained), ExecCredentials::Gained);
+        // Programs owned by the user change nothing.
+        assert_eq!(
+            exec_credentials(setuid, 1000, 1000, &status),
+            ExecCredentials::Unchanged
+        );
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        assert_eq!(tracee.check_exec().unwrap(), ExecCredentials::Unchanged);
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    fn test_can_trace() {
+        let spawn = can_spawn_traced().expect("Error checking permissions");
+        assert_eq!(spawn.is_allowed(), spawn.yama_scope != Some(3));
+        let own = can_trace(getpid()).expect("Error checking permissions");
+        assert!(own.denials.contains(&TraceDenial::SameProcess));
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut child = Command::new(&path)
+            .args(["sleep", "5000"])
+            .spawn()
+            .expect("Error spawning test process");
+        let pid = Pid::from_raw(child.id() as i32);
+        let check = can_trace(pid).expect("Error checking permissions");
+        assert_eq!(check.is_allowed(), spawn.is_allowed());
+        assert!(is_descendant(pid).unwrap());
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+        let e = can_trace(pid).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let check = can_trace(tracee.pid()).expect("Error checking permissions");
+        // The tracer is the thread that spawned it.
+        let tracer = nix::unistd::gettid();
+        assert!(check.denials.contains(&TraceDenial::AlreadyTraced(tracer)));
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
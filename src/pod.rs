@@ -0,0 +1,212 @@
+//! Plain old data that can be copied to and from tracee memory as bytes.
+
+use std::mem;
+use std::ptr;
+use std::slice;
+
+/// A type that can be read from and written to tracee memory as raw bytes,
+/// with [`Tracee::read_value`] and [`Tracee::write_value`].
+///
+/// This is implemented for the integer types, arrays of `Pod` types, and
+/// common C structures from `libc` that system calls take pointers to, such
+/// as `timespec` and `sockaddr_in`. Implement it for your own `#[repr(C)]`
+/// structures to read them the same way.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type, and the type must
+/// not contain padding, pointers to memory owned by the tracer or anything
+/// with a destructor. `#[repr(C)]` structures made only of `Pod` fields,
+/// laid out so the compiler adds no padding between them, qualify.
+///
+/// [`Tracee::read_value`]: struct.Tracee.html#method.read_value
+/// [`Tracee::write_value`]: struct.Tracee.html#method.write_value
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($ty:ty),* $(,)?) => {
+        $(unsafe impl Pod for $ty {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+// Only structures without padding, which `test_no_padding` checks. The
+// reserved fields `libc` keeps private in some of them, such as `stat`'s, are
+// real fields that the kernel fills, not padding. `sysinfo` has padding, so
+// isn't here, and neither is `sockaddr_storage`, whose size is mostly private
+// fields that `libc` declares as padding.
+impl_pod!(
+    libc::timespec,
+    libc::timeval,
+    libc::itimerspec,
+    libc::rlimit,
+    libc::pollfd,
+    libc::sockaddr,
+    libc::sockaddr_in,
+    libc::sockaddr_in6,
+    libc::sockaddr_un,
+    libc::in_addr,
+    libc::in6_addr,
+    libc::stat,
+    libc::utsname,
+);
+
+/// View `value` as its bytes.
+pub(crate) fn bytes_of<T: Pod>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Make a `T` from `bytes`, which must be exactly as long, whatever their
+/// alignment.
+pub(crate) fn from_bytes<T: Pod>(bytes: &[u8]) -> T {
+    assert_eq!(bytes.len(), mem::size_of::<T>());
+    unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::process::Command;
+
+    /// Check that the fields of `$ty`, with `$private` more bytes of fields
+    /// that `libc` keeps private, add up to its size.
+    macro_rules! assert_no_padding {
+        ($ty:ty { $($field:ident),* } $(+ $private:expr)?) => {{
+            let value: $ty = unsafe { mem::zeroed() };
+            let fields = 0 $(+ mem::size_of_val(&value.$field))* $(+ $private)?;
+            assert_eq!(fields, mem::size_of::<$ty>(), stringify!($ty));
+        }};
+    }
+
+    #[test]
+    fn test_no_padding() {
+        assert_no_padding!(libc::timespec { tv_sec, tv_nsec });
+        assert_no_padding!(libc::timeval { tv_sec, tv_usec });
+        assert_no_padding!(libc::itimerspec {
+            it_interval,
+            it_value
+        });
+        assert_no_padding!(libc::rlimit { rlim_cur, rlim_max });
+        assert_no_padding!(libc::pollfd {
+            fd,
+            events,
+            revents
+        });
+        assert_no_padding!(libc::sockaddr { sa_family, sa_data });
+        assert_no_padding!(libc::sockaddr_in {
+            sin_family,
+            sin_port,
+            sin_addr,
+            sin_zero
+        });
+        assert_no_padding!(libc::sockaddr_in6 {
+            sin6_family,
+            sin6_port,
+            sin6_flowinfo,
+            sin6_addr,
+            sin6_scope_id
+        });
+        assert_no_padding!(libc::sockaddr_un {
+            sun_family,
+            sun_path
+        });
+        assert_no_padding!(libc::in_addr { s_addr });
+        assert_no_padding!(libc::in6_addr { s6_addr });
+        assert_no_padding!(libc::utsname {
+            sysname,
+            nodename,
+            release,
+            version,
+            machine,
+            domainname
+        });
+        // `__pad0` and `__unused`.
+        #[cfg(target_arch = "x86_64")]
+        assert_no_padding!(
+            libc::stat {
+                st_dev,
+                st_ino,
+                st_nlink,
+                st_mode,
+                st_uid,
+                st_gid,
+                st_rdev,
+                st_size,
+                st_blksize,
+                st_blocks,
+                st_atime,
+                st_atime_nsec,
+                st_mtime,
+                st_mtime_nsec,
+                st_ctime,
+                st_ctime_nsec
+            } + 4
+                + 24
+        );
+        // `__pad1`, `__pad2` and `__unused`.
+        #[cfg(target_arch = "aarch64")]
+        assert_no_padding!(
+            libc::stat {
+                st_dev,
+                st_ino,
+                st_mode,
+                st_nlink,
+                st_uid,
+                st_gid,
+                st_rdev,
+                st_size,
+                st_blksize,
+                st_blocks,
+                st_atime,
+                st_atime_nsec,
+                st_mtime,
+                st_mtime_nsec,
+                st_ctime,
+                st_ctime_nsec
+            } + 8
+                + 4
+                + 8
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_read_value() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let buf = tracee.allocate(4096, prot).expect("Error allocating");
+        let time = libc::timespec {
+            tv_sec: 1234,
+            tv_nsec: 5678,
+        };
+        tracee.write_value(buf, &time).expect("Error writing value");
+        let read: libc::timespec = tracee.read_value(buf).expect("Error reading value");
+        assert_eq!((read.tv_sec, read.tv_nsec), (1234, 5678));
+        assert_eq!(tracee.read_value::<i64>(buf + 8).unwrap(), 5678);
+
+        // Unaligned addresses are fine.
+        let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = 8080u16.to_be();
+        addr.sin_addr.s_addr = u32::from_be_bytes([127, 0, 0, 1]).to_be();
+        tracee.write_value(buf + 101, &addr).unwrap();
+        let read: libc::sockaddr_in = tracee.read_value(buf + 101).unwrap();
+        assert_eq!(u16::from_be(read.sin_port), 8080);
+        assert_eq!(read.sin_addr.s_addr.to_ne_bytes(), [127, 0, 0, 1]);
+        assert_eq!(
+            tracee.read_value::<[u8; 2]>(buf + 103).unwrap(),
+            [0x1f, 0x90]
+        );
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
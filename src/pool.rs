@@ -0,0 +1,545 @@
+//! A reaper thread that traces many processes and hands out their events.
+
+use crate::channel::Resume;
+use crate::{Event, SpawnOptions, TraceSession, Tracee};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::io;
+use std::process::Command;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long the reaper waits before checking its tracees again when woken
+/// for a state change that wasn't theirs.
+const RECHECK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// An event of a tracee of a pool or one of its descendants.
+type PoolEvent = io::Result<(Pid, Event)>;
+
+/// A call to make on the reaper thread with a tracee, if it is in the pool.
+type Call = Box<dyn FnOnce(Option<&mut Tracee>) + Send>;
+
+enum Request {
+    Spawn(
+        Box<(Command, SpawnOptions)>,
+        Sender<io::Result<(Pid, Receiver<PoolEvent>)>>,
+    ),
+    Resume(Pid, Pid, Resume),
+    Call(Pid, Pid, Call),
+    /// Kill everything spawned as the given pid, whose handle was dropped.
+    Release(Pid),
+    /// A child of the process has a state change to report.
+    Reap,
+    Shutdown,
+}
+
+/// A reaper thread that spawns, traces and waits for many tracees, and
+/// routes each one's events to its own [`PoolTracee`] handle.
+///
+/// This suits services that trace many short-lived children. The reaper
+/// performs every wait and every ptrace request, so the handles can be used
+/// from any thread, and no tracee is left a zombie: one whose handle is
+/// dropped is killed and reaped, and dropping the pool kills and reaps all
+/// of them before the reaper exits.
+///
+/// Ptrace requests are only accepted from the thread that attached, so the
+/// handles are `Send` and `Sync` fronts that marshal work to the reaper:
+/// [`PoolTracee::call`] runs a closure with the [`Tracee`] there, for
+/// anything from reading registers to injecting code.
+///
+/// Each event leaves its tracee stopped until [`PoolTracee::resume`] is
+/// called for it, except for `Event::Exited` and `Event::Signaled`. With
+/// the `PTRACE_O_TRACEFORK` family of options in [`SpawnOptions`], a
+/// tracee's descendants are traced too, and their events go to its handle.
+///
+/// While it has tracees, a second thread blocks until a child of the
+/// process has a state change to report, and wakes the reaper to collect
+/// it. Children of other threads are seen too, and while one of them has a
+/// state change its own thread hasn't collected, the reaper checks on its
+/// tracees every millisecond instead.
+///
+/// [`PoolTracee`]: struct.PoolTracee.html
+/// [`PoolTracee::call`]: struct.PoolTracee.html#method.call
+/// [`PoolTracee::resume`]: struct.PoolTracee.html#method.resume
+/// [`Tracee`]: struct.Tracee.html
+/// [`SpawnOptions`]: struct.SpawnOptions.html
+#[derive(Debug)]
+pub struct TracePool {
+    requests: Sender<Request>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TracePool {
+    /// Start the reaper thread.
+    pub fn new() -> io::Result<TracePool> {
+        let (requests, receiver) = mpsc::channel();
+        let (arm, armed) = mpsc::channel();
+        let wakeups = requests.clone();
+        thread::Builder::new()
+            .name("reaper-waiter".to_string())
+            .spawn(move || watch(wakeups, armed))?;
+        let thread = thread::Builder::new()
+            .name("reaper".to_string())
+            .spawn(move || Reaper::new(receiver, arm).run())?;
+        Ok(TracePool {
+            requests,
+            thread: Some(thread),
+        })
+    }
+
+    /// Spawn `command` with ptrace enabled on the reaper thread.
+    ///
+    /// The new tracee's initial stop is its handle's first event, as
+    /// `Event::Attached`.
+    pub fn spawn(&self, command: Command, options: SpawnOptions) -> io::Result<PoolTracee> {
+        let (reply, spawned) = mpsc::channel();
+        send(
+            &self.requests,
+            Request::Spawn(Box::new((command, options)), reply),
+        )?;
+        let (pid, events) = spawned.recv().map_err(|_| finished())??;
+        Ok(PoolTracee {
+            pid,
+            events: Mutex::new(events),
+            requests: self.requests.clone(),
+        })
+    }
+}
+
+impl Drop for TracePool {
+    fn drop(&mut self) {
+        let _ = self.requests.send(Request::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A handle to a tracee of a [`TracePool`], which receives the events of
+/// the tracee and its traced descendants.
+///
+/// Dropping the handle kills them all.
+///
+/// [`TracePool`]: struct.TracePool.html
+#[derive(Debug)]
+pub struct PoolTracee {
+    pid: Pid,
+    /// Locked so that the handle can be shared between threads.
+    events: Mutex<Receiver<PoolEvent>>,
+    requests: Sender<Request>,
+}
+
+impl PoolTracee {
+    /// The pid of the spawned tracee.
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Wait for the next event of the tracee or one of its descendants, or
+    /// return `None` once all of them have exited.
+    ///
+    /// Errors in resuming them are returned here too.
+    pub fn recv(&self) -> Option<PoolEvent> {
+        self.events.lock().unwrap().recv().ok()
+    }
+
+    /// Wait up to `timeout` for the next event.
+    ///
+    /// Returns `Ok(None)` if the timeout expired, and `Err` with
+    /// `RecvTimeoutError::Disconnected` once all of them have exited.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<PoolEvent>, RecvTimeoutError> {
+        match self.events.lock().unwrap().recv_timeout(timeout) {
+            Ok(event) => Ok(Some(event)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Resume `pid`, the tracee or one of its descendants, as `decision`
+    /// says.
+    ///
+    /// Fails with `ErrorKind::BrokenPipe` if the pool has been dropped.
+    pub fn resume(&self, pid: Pid, decision: Resume) -> io::Result<()> {
+        send(&self.requests, Request::Resume(self.pid, pid, decision))
+    }
+
+    /// Call `f` on the reaper thread with `pid`, the tracee or one of its
+    /// descendants, and return its result.
+    ///
+    /// Most of what `f` can do with the [`Tracee`] needs it to be stopped.
+    /// Fails with `ErrorKind::NotFound` if `pid` isn't a tracee of this
+    /// handle, and with `ErrorKind::BrokenPipe` if the pool has been
+    /// dropped.
+    ///
+    /// [`Tracee`]: struct.Tracee.html
+    pub fn call<F, R>(&self, pid: Pid, f: F) -> io::Result<R>
+    where
+        F: FnOnce(&mut Tracee) -> io::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (reply, result) = mpsc::channel();
+        let call: Call = Box::new(move |tracee| {
+            let _ = reply.send(match tracee {
+                Some(tracee) => f(tracee),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "No such tracee in the pool",
+                )),
+            });
+        });
+        send(&self.requests, Request::Call(self.pid, pid, call))?;
+        result.recv().map_err(|_| finished())?
+    }
+}
+
+impl Drop for PoolTracee {
+    fn drop(&mut self) {
+        let _ = self.requests.send(Request::Release(self.pid));
+    }
+}
+
+fn send(requests: &Sender<Request>, request: Request) -> io::Result<()> {
+    requests.send(request).map_err(|_| finished())
+}
+
+fn finished() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "The reaper thread has finished")
+}
+
+/// Each time the reaper asks with `armed`, block until a child of the
+/// process has a state change to report, and send the reaper a `Reap`.
+///
+/// The status is left to be waited for, which the reaper does with
+/// `__WNOTHREAD`, since ptrace requests for its tracees must come from it.
+fn watch(requests: Sender<Request>, armed: Receiver<()>) {
+    while armed.recv().is_ok() {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WSTOPPED | libc::WNOWAIT | libc::__WALL;
+        loop {
+            let ret = unsafe { libc::waitid(libc::P_ALL, 0, &mut info, flags) };
+            // Any other error means there is nothing to wait for, which the
+            // reaper will find out.
+            if ret == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                break;
+            }
+        }
+        if requests.send(Request::Reap).is_err() {
+            break;
+        }
+    }
+}
+
+/// The state of the reaper thread.
+struct Reaper {
+    session: TraceSession,
+    requests: Receiver<Request>,
+    /// The spawned tracee each tracee descends from.
+    roots: HashMap<Pid, Pid>,
+    /// Where the events of each spawned tracee's tree go, until its handle is
+    /// dropped.
+    handles: HashMap<Pid, Sender<PoolEvent>>,
+    /// New tracees whose parent's event hasn't arrived yet.
+    orphans: Vec<Pid>,
+    /// Asks the waiter thread to wait for the next state change.
+    arm: Sender<()>,
+    /// Whether the waiter thread is waiting, or has sent a `Reap` that
+    /// hasn't been received yet.
+    armed: bool,
+}
+
+impl Reaper {
+    fn new(requests: Receiver<Request>, arm: Sender<()>) -> Reaper {
+        Reaper {
+            session: TraceSession::new(),
+            requests,
+            roots: HashMap::new(),
+            handles: HashMap::new(),
+            orphans: vec![],
+            arm,
+            armed: false,
+        }
+    }
+
+    fn run(mut self) {
+        let mut recheck = false;
+        loop {
+            if !self.armed && !recheck && !self.session.is_empty() {
+                self.armed = self.arm.send(()).is_ok();
+            }
+            let request = if recheck || !self.armed && !self.session.is_empty() {
+                self.requests.recv_timeout(RECHECK_INTERVAL)
+            } else {
+                self.requests
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected)
+            };
+            let woken = match request {
+                Ok(Request::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+                Ok(Request::Reap) => {
+                    self.armed = false;
+                    true
+                }
+                Ok(request) => {
+                    self.handle(request);
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+            };
+            match self.reap() {
+                // A change that was none of the tracees' will still be there
+                // when the waiter thread next looks, so don't ask it yet.
+                Ok(reaped) => recheck = woken && !reaped,
+                Err(e) => {
+                    // The session can't be waited for any more, so stop.
+                    for handle in self.handles.values() {
+                        let _ = handle.send(Err(io::Error::new(e.kind(), e.to_string())));
+                    }
+                    break;
+                }
+            }
+        }
+        self.kill_all();
+    }
+
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Spawn(spawn, reply) => {
+                let (mut command, options) = *spawn;
+                let spawned = self.session.spawn(&mut command, options).map(|pid| {
+                    let (sender, events) = mpsc::channel();
+                    let _ = sender.send(Ok((pid, Event::Attached)));
+                    self.roots.insert(pid, pid);
+                    self.handles.insert(pid, sender);
+                    (pid, events)
+                });
+                if let Err(mpsc::SendError(Ok((pid, _)))) = reply.send(spawned) {
+                    self.release(pid);
+                }
+            }
+            Request::Resume(root, pid, decision) => {
+                if self.roots.get(&pid) != Some(&root) {
+                    return;
+                }
+                if let Err(e) = self.session.resume_as(pid, decision) {
+                    self.route(pid, Err(e));
+                }
+                if let Resume::Detach(_) = decision {
+                    self.roots.remove(&pid);
+                }
+            }
+            Request::Call(root, pid, call) => match self.roots.get(&pid) {
+                Some(&r) if r == root => call(self.session.get_mut(pid)),
+                _ => call(None),
+            },
+            Request::Release(root) => self.release(root),
+            Request::Reap | Request::Shutdown => {}
+        }
+    }
+
+    /// Collect and route every state change that is ready, returning whether
+    /// there were any.
+    fn reap(&mut self) -> io::Result<bool> {
+        let mut reaped = false;
+        while !self.session.is_empty() {
+            let (pid, event) = match self.session.try_wait_any()? {
+                Some(event) => event,
+                None => break,
+            };
+            reaped = true;
+            match event {
+                Event::Fork(child) | Event::Vfork(child) | Event::Clone(child) => {
+                    if let Some(&root) = self.roots.get(&pid) {
+                        self.roots.insert(child, root);
+                        if let Some(at) = self.orphans.iter().position(|&p| p == child) {
+                            self.orphans.remove(at);
+                            self.route(child, Ok((child, Event::Attached)));
+                        }
+                    }
+                }
+                // The parent's event will say whose child this is.
+                Event::Attached if !self.roots.contains_key(&pid) => {
+                    self.orphans.push(pid);
+                    continue;
+                }
+                _ => {}
+            }
+            self.route(pid, Ok((pid, event)));
+            if let Event::Exited(_) | Event::Signaled(..) = event {
+                self.forget(pid);
+            }
+        }
+        Ok(reaped)
+    }
+
+    /// Send `event` to the handle of the tree `pid` belongs to, killing the
+    /// tree if its handle has gone.
+    fn route(&mut self, pid: Pid, event: PoolEvent) {
+        let root = match self.roots.get(&pid) {
+            Some(&root) => root,
+            None => return,
+        };
+        let sent = match self.handles.get(&root) {
+            Some(handle) => handle.send(event).is_ok(),
+            None => false,
+        };
+        if !sent {
+            self.release(root);
+        }
+    }
+
+    fn forget(&mut self, pid: Pid) {
+        if let Some(root) = self.roots.remove(&pid) {
+            // Dropping the sender ends the handle's events.
+            if !self.roots.values().any(|&r| r == root) {
+                self.handles.remove(&root);
+            }
+        }
+    }
+
+    /// Kill every tracee in the tree of `root`, leaving their exits to be
+    /// reaped.
+    fn release(&mut self, root: Pid) {
+        self.handles.remove(&root);
+        for (&pid, _) in self.roots.iter().filter(|&(_, &r)| r == root) {
+            let _ = signal::kill(pid, Signal::SIGKILL);
+        }
+    }
+
+    /// Kill and reap every tracee.
+    fn kill_all(&mut self) {
+        self.handles.clear();
+        let pids: Vec<Pid> = self.session.pids().chain(self.orphans.drain(..)).collect();
+        for pid in pids {
+            let _ = signal::kill(pid, Signal::SIGKILL);
+        }
+        while !self.session.is_empty() {
+            match self.session.wait_any() {
+                // Children that were still being created get killed too.
+                Ok((pid, Event::Attached)) => {
+                    let _ = signal::kill(pid, Signal::SIGKILL);
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SyscallInfo;
+    use nix::sys::ptrace::Options;
+    use std::process::Stdio;
+
+    fn command(args: &[&str]) -> Command {
+        let mut command =
+            Command::new(test_process_path().expect("Failed to get test process path"));
+        command.args(args).stdout(Stdio::null());
+        command
+    }
+
+    #[test]
+    fn test_trace_pool() {
+        let pool = TracePool::new().expect("Error starting pool");
+        let mut options = SpawnOptions::new();
+        options.ptrace_options(Options::PTRACE_O_TRACEFORK);
+        let forking = pool
+            .spawn(command(&["fork"]), options)
+            .expect("Error spawning");
+        let tracees: Vec<PoolTracee> = (0..8)
+            .map(|_| pool.spawn(command(&[]), SpawnOptions::new()))
+            .collect::<io::Result<_>>()
+            .expect("Error spawning");
+        // Drive the tracees from other threads.
+        let threads: Vec<_> = tracees
+            .into_iter()
+            .chain(Some(forking))
+            .map(|tracee| {
+                thread::spawn(move || {
+                    let mut exits = vec![];
+                    while let Some(event) = tracee.recv() {
+                        let (pid, event) = event.expect("Error tracing");
+                        let signal = match event {
+                            Event::Exited(code) => {
+                                exits.push((pid, code));
+                                continue;
+                            }
+                            Event::Signal(signal) => Some(signal),
+                            _ => None,
+                        };
+                        tracee
+                            .resume(pid, Resume::Continue(signal))
+                            .expect("Error resuming");
+                    }
+                    (tracee.pid(), exits)
+                })
+            })
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        for (pid, exits) in &results[..8] {
+            assert_eq!(exits, &[(*pid, 0)]);
+        }
+        let (pid, exits) = &results[8];
+        assert_eq!(exits.len(), 2);
+        assert!(exits.iter().all(|&(_, code)| code == 0));
+        assert_eq!(exits.last(), Some(&(*pid, 0)));
+    }
+
+    #[test]
+    fn test_pool_tracee_call() {
+        let pool = TracePool::new().expect("Error starting pool");
+        let mut options = SpawnOptions::new();
+        options.ptrace_options(Options::PTRACE_O_TRACESYSGOOD);
+        let tracee = pool.spawn(command(&[]), options).expect("Error spawning");
+        let pid = tracee.pid();
+        let tracee = std::sync::Arc::new(tracee);
+        let entries = thread::spawn({
+            let tracee = tracee.clone();
+            move || {
+                let mut entries = 0;
+                while let Some(event) = tracee.recv() {
+                    match event.expect("Error tracing") {
+                        (_, Event::Exited(_)) => continue,
+                        (_, Event::Syscall) => {
+                            let info = tracee.call(pid, |t| t.syscall_info()).unwrap();
+                            if let SyscallInfo::Entry { .. } = info {
+                                entries += 1;
+                            }
+                        }
+                        _ => {}
+                    }
+                    tracee.resume(pid, Resume::Syscall(None)).unwrap();
+                }
+                entries
+            }
+        })
+        .join()
+        .unwrap();
+        assert!(entries > 5);
+        let e = tracee.call(Pid::from_raw(1), |_| Ok(())).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_trace_pool_kills_on_drop() {
+        let pool = TracePool::new().expect("Error starting pool");
+        let tracee = pool
+            .spawn(command(&["sleep"]), SpawnOptions::new())
+            .expect("Error spawning");
+        let pid = tracee.pid();
+        assert_eq!(tracee.recv().unwrap().unwrap(), (pid, Event::Attached));
+        tracee.resume(pid, Resume::Continue(None)).unwrap();
+        drop(pool);
+        assert!(tracee.recv().is_none());
+        // The tracee has been reaped, so it no longer exists.
+        assert_eq!(
+            signal::kill(pid, None).unwrap_err().as_errno(),
+            Some(nix::errno::Errno::ESRCH)
+        );
+    }
+}
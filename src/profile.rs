@@ -0,0 +1,454 @@
+use crate::{tkill, Event, Resume, SyscallInfo, TraceSession, Tracee};
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Measures how long a tracee's system calls take.
+///
+/// Feed every syscall stop to [`record`], or let [`run`] drive a whole
+/// [`TraceSession`], then call [`report`] for per-syscall statistics.
+/// Timestamps are taken in the tracer when each syscall stop is observed, so
+/// the measured latencies include some tracing overhead.
+///
+/// Stopping for every system call slows busy tracees down a lot. With a
+/// [`sampling_interval`], `run` only times some of them instead.
+///
+/// [`record`]: #method.record
+/// [`run`]: #method.run
+/// [`report`]: #method.report
+/// [`sampling_interval`]: #method.sampling_interval
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug, Default)]
+pub struct SyscallProfiler {
+    in_flight: HashMap<Pid, (u64, Instant)>,
+    restarting: HashSet<Pid>,
+    samples: HashMap<u64, Vec<Duration>>,
+    interval: Option<Duration>,
+    /// Tracees resumed with `PTRACE_SYSCALL` to time their next call.
+    sampling: HashSet<Pid>,
+}
+
+impl SyscallProfiler {
+    /// Create a profiler with no samples.
+    pub fn new() -> SyscallProfiler {
+        SyscallProfiler::default()
+    }
+
+    /// Have [`run`] time one system call of each tracee every `interval`,
+    /// letting them run without syscall stops in between.
+    ///
+    /// When the interval is up each tracee is interrupted with `SIGSTOP` and
+    /// resumed with `PTRACE_SYSCALL`, and the next call it makes is timed.
+    /// Neither ptrace nor seccomp can count system calls without stopping
+    /// for them, so samples are taken by time rather than every so many
+    /// calls. The report then estimates how calls compare to each other,
+    /// not their true counts, and calls that come after long stretches
+    /// without any are sampled more than their share.
+    ///
+    /// [`run`]: #method.run
+    pub fn sampling_interval(&mut self, interval: Duration) -> &mut SyscallProfiler {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Record a syscall stop of `tracee`.
+    ///
+    /// Call this each time the tracee reports `Event::Syscall`. Interrupted
+    /// system calls that are restarted are counted once, from their first
+    /// entry to their final exit.
+    pub fn record(&mut self, tracee: &Tracee) -> io::Result<()> {
+        let now = Instant::now();
+        match tracee.syscall_info()? {
+            // A restarted call is timed from when it was first entered.
+            SyscallInfo::Entry { .. } if self.restarting.remove(&tracee.pid()) => {}
+            SyscallInfo::Entry { number, .. } => {
+                self.in_flight.insert(tracee.pid(), (number, now));
+            }
+            info @ SyscallInfo::Exit { .. } if info.is_restart() => {
+                self.restarting.insert(tracee.pid());
+            }
+            SyscallInfo::Exit { .. } => {
+                if let Some((number, start)) = self.in_flight.remove(&tracee.pid()) {
+                    self.samples.entry(number).or_default().push(now - start);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Forget any syscall in progress for `pid`, for example because it exited.
+    pub fn forget(&mut self, pid: Pid) {
+        self.in_flight.remove(&pid);
+        self.restarting.remove(&pid);
+        self.sampling.remove(&pid);
+    }
+
+    /// Profile the system calls of the tracees in `session` until they have
+    /// all exited.
+    ///
+    /// Without sampling, this runs the session with [`TraceSession::run`],
+    /// which should trace system calls, with [`trace_syscalls`].
+    ///
+    /// [`TraceSession::run`]: struct.TraceSession.html#method.run
+    /// [`trace_syscalls`]: struct.TraceSession.html#method.trace_syscalls
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        if let Some(interval) = self.interval {
+            return run_sampler(self, session, interval);
+        }
+        session.run(|session, pid, event| {
+            match event {
+                Event::Syscall => {
+                    if let Some(tracee) = session.get(pid) {
+                        self.record(tracee)?;
+                    }
+                }
+                Event::Exited(_) | Event::Signaled(..) => self.forget(pid),
+                _ => {}
+            }
+            Ok(())
+        })
+    }
+
+    /// Summarize the syscalls recorded so far.
+    pub fn report(&self) -> SyscallReport {
+        let mut syscalls: Vec<SyscallStats> = self
+            .samples
+            .iter()
+            .map(|(&number, samples)| SyscallStats::new(number, samples))
+            .collect();
+        syscalls.sort_by(|a, b| b.total.cmp(&a.total).then(a.number.cmp(&b.number)));
+        SyscallReport { syscalls }
+    }
+}
+
+impl Sampler for SyscallProfiler {
+    fn is_due(&self, pid: Pid) -> bool {
+        !self.sampling.contains(&pid)
+    }
+
+    fn take_sample(&mut self, _session: &TraceSession, pid: Pid) -> io::Result<Resume> {
+        self.sampling.insert(pid);
+        Ok(Resume::Syscall(None))
+    }
+
+    fn handle(
+        &mut self,
+        session: &TraceSession,
+        pid: Pid,
+        event: Event,
+    ) -> io::Result<Option<Resume>> {
+        let signal = match event {
+            Event::Syscall => {
+                if let Some(tracee) = session.get(pid) {
+                    self.record(tracee)?;
+                }
+                // The sample ends with the exit of the call.
+                if !self.in_flight.contains_key(&pid) && !self.restarting.contains(&pid) {
+                    self.sampling.remove(&pid);
+                }
+                None
+            }
+            Event::Signal(signal) => Some(signal),
+            Event::Exited(_) | Event::Signaled(..) => {
+                self.forget(pid);
+                return Ok(None);
+            }
+            _ => None,
+        };
+        Ok(Some(if self.sampling.contains(&pid) {
+            Resume::Syscall(signal)
+        } else {
+            Resume::Continue(signal)
+        }))
+    }
+}
+
+/// Per-syscall latency statistics produced by a [`SyscallProfiler`].
+///
+/// [`SyscallProfiler`]: struct.SyscallProfiler.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyscallReport {
+    /// Statistics for each syscall that completed at least once, ordered by
+    /// descending total time.
+    pub syscalls: Vec<SyscallStats>,
+}
+
+impl SyscallReport {
+    /// Statistics for the syscall `number`, if it was recorded.
+    pub fn get(&self, number: u64) -> Option<&SyscallStats> {
+        self.syscalls.iter().find(|s| s.number == number)
+    }
+
+    /// The total time spent in all recorded syscalls.
+    pub fn total(&self) -> Duration {
+        self.syscalls.iter().map(|s| s.total).sum()
+    }
+}
+
+/// Latency statistics for a single syscall number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyscallStats {
+    /// The syscall number.
+    pub number: u64,
+    /// How many times the syscall completed.
+    pub count: usize,
+    /// The total time spent in the syscall.
+    pub total: Duration,
+    /// The shortest call.
+    pub min: Duration,
+    /// The longest call.
+    pub max: Duration,
+    /// The median latency.
+    pub p50: Duration,
+    /// The 90th percentile latency.
+    pub p90: Duration,
+    /// The 99th percentile latency.
+    pub p99: Duration,
+}
+
+impl SyscallStats {
+    fn new(number: u64, samples: &[Duration]) -> SyscallStats {
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        // Nearest-rank percentile.
+        let percentile = |p: usize| sorted[((sorted.len() * p).div_ceil(100)).max(1) - 1];
+        SyscallStats {
+            number,
+            count: sorted.len(),
+            total: sorted.iter().sum(),
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+        }
+    }
+}
+
+/// Collects stack samples from tracees at a fixed frequency.
+///
+/// [`run`] periodically interrupts every tracee in a [`TraceSession`] with
+/// `SIGSTOP`, records a frame-pointer backtrace of where it was, and resumes
+/// it. The samples can then be written out in the collapsed-stack format
+/// used by flame graph tools.
+///
+/// [`run`]: #method.run
+/// [`TraceSession`]: struct.TraceSession.html
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug)]
+pub struct SamplingProfiler {
+    interval: Duration,
+    max_depth: usize,
+    stacks: HashMap<Vec<u64>, usize>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SamplingProfiler {
+    /// Create a profiler that samples `frequency` times per second.
+    pub fn new(frequency: u32) -> SamplingProfiler {
+        SamplingProfiler {
+            interval: Duration::from_secs(1) / frequency.max(1),
+            max_depth: 128,
+            stacks: HashMap::new(),
+        }
+    }
+
+    /// Set the maximum number of frames recorded per sample. The default is 128.
+    pub fn max_depth(&mut self, max_depth: usize) -> &mut SamplingProfiler {
+        self.max_depth = max_depth.max(1);
+        self
+    }
+
+    /// Record a sample of where the stopped `tracee` currently is.
+    pub fn sample(&mut self, tracee: &Tracee) -> io::Result<()> {
+        let mut stack = tracee.backtrace(self.max_depth)?;
+        // Collapsed stacks are written outermost frame first.
+        stack.reverse();
+        *self.stacks.entry(stack).or_insert(0) += 1;
+        Ok(())
+    }
+
+    /// Resume every tracee in `session` and sample them until they have all exited.
+    ///
+    /// All tracees must be stopped.
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        let interval = self.interval;
+        run_sampler(self, session, interval)
+    }
+
+    /// The total number of samples recorded.
+    pub fn sample_count(&self) -> usize {
+        self.stacks.values().sum()
+    }
+
+    /// Write the samples in collapsed-stack format.
+    ///
+    /// Each line holds the frame addresses of one distinct stack, outermost
+    /// first and separated by semicolons, followed by the number of times it
+    /// was sampled.
+    pub fn write_collapsed<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut stacks: Vec<_> = self.stacks.iter().collect();
+        stacks.sort();
+        for (stack, count) in stacks {
+            let frames: Vec<String> = stack.iter().map(|addr| format!("{:#x}", addr)).collect();
+            writeln!(out, "{} {}", frames.join(";"), count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Sampler for SamplingProfiler {
+    fn is_due(&self, _pid: Pid) -> bool {
+        true
+    }
+
+    fn take_sample(&mut self, session: &TraceSession, pid: Pid) -> io::Result<Resume> {
+        if let Some(tracee) = session.get(pid) {
+            self.sample(tracee)?;
+        }
+        Ok(Resume::Continue(None))
+    }
+
+    fn handle(
+        &mut self,
+        _session: &TraceSession,
+        _pid: Pid,
+        event: Event,
+    ) -> io::Result<Option<Resume>> {
+        Ok(match event {
+            Event::Signal(signal) => Some(Resume::Continue(Some(signal))),
+            Event::Exited(_) | Event::Signaled(..) => None,
+            _ => Some(Resume::Continue(None)),
+        })
+    }
+}
+
+/// A profiler that [`run_sampler`] stops the tracees for, every so often.
+trait Sampler {
+    /// Whether to stop `pid` for a sample once the interval is up.
+    fn is_due(&self, pid: Pid) -> bool;
+
+    /// Sample `pid`, which was stopped for it, and say how to resume it.
+    fn take_sample(&mut self, session: &TraceSession, pid: Pid) -> io::Result<Resume>;
+
+    /// Handle any other event of `pid`, saying how to resume it, if at all.
+    fn handle(
+        &mut self,
+        session: &TraceSession,
+        pid: Pid,
+        event: Event,
+    ) -> io::Result<Option<Resume>>;
+}
+
+/// Resume every tracee in `session` and, until they have all exited, stop
+/// the ones `sampler` wants sampled with `SIGSTOP` every `interval`.
+///
+/// Tracees are resumed through the session, with `PTRACE_CONT` to begin
+/// with and then as `sampler` decides.
+fn run_sampler<S: Sampler>(
+    sampler: &mut S,
+    session: &mut TraceSession,
+    interval: Duration,
+) -> io::Result<()> {
+    let pids: Vec<Pid> = session.pids().collect();
+    for pid in pids {
+        session.resume_as(pid, Resume::Continue(None))?;
+    }
+    // Tracees sent a `SIGSTOP` whose stop hasn't been seen yet.
+    let mut interrupted = HashSet::new();
+    let mut next_sample = Instant::now() + interval;
+    while !session.is_empty() {
+        let (pid, event) = match session.try_wait_any()? {
+            Some(e) => e,
+            None => {
+                let now = Instant::now();
+                if now >= next_sample {
+                    let due: Vec<Pid> = session.pids().filter(|&pid| sampler.is_due(pid)).collect();
+                    interrupt(due, &mut interrupted)?;
+                    next_sample = (next_sample + interval).max(now);
+                } else {
+                    thread::sleep((next_sample - now).min(Duration::from_millis(1)));
+                }
+                continue;
+            }
+        };
+        let decision = match event {
+            Event::Signal(Signal::SIGSTOP) if interrupted.remove(&pid) => {
+                Some(sampler.take_sample(session, pid)?)
+            }
+            Event::Exited(_) | Event::Signaled(..) => {
+                interrupted.remove(&pid);
+                sampler.handle(session, pid, event)?
+            }
+            _ => sampler.handle(session, pid, event)?,
+        };
+        if let (Some(decision), Some(_)) = (decision, session.get(pid)) {
+            session.resume_as(pid, decision)?;
+        }
+    }
+    Ok(())
+}
+
+/// Stop each of `pids` that isn't in `interrupted` with `SIGSTOP`, adding
+/// it there.
+fn interrupt<I: IntoIterator<Item = Pid>>(
+    pids: I,
+    interrupted: &mut HashSet<Pid>,
+) -> io::Result<()> {
+    for pid in pids {
+        if interrupted.contains(&pid) {
+            continue;
+        }
+        match tkill(pid, Signal::SIGSTOP) {
+            Ok(()) => {
+                interrupted.insert(pid);
+            }
+            // The tracee is exiting; its exit will be reported shortly.
+            Err(ref e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use std::process::Command;
+
+    #[test]
+    fn test_sampling_profiler() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session
+            .spawn(
+                Command::new(&path).args(["spin", "200"]),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut profiler = SamplingProfiler::new(100);
+        profiler.run(&mut session).expect("Error profiling");
+        assert!(profiler.sample_count() > 0);
+        let mut out = vec![];
+        profiler
+            .write_collapsed(&mut out)
+            .expect("Error writing samples");
+        let out = String::from_utf8(out).expect("Collapsed stacks aren't UTF-8");
+        let total: usize = out
+            .lines()
+            .map(|line| {
+                let (stack, count) = line.rsplit_once(' ').expect("Malformed line");
+                assert!(stack.starts_with("0x"));
+                count.parse::<usize>().expect("Malformed count")
+            })
+            .sum();
+        assert_eq!(total, profiler.sample_count());
+    }
+}
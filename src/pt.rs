@@ -0,0 +1,124 @@
+//! Intel Processor Trace capture for a tracee, using a perf AUX buffer.
+
+use crate::perf::{self, Mapping, PerfEventAttr, ATTR_EXCLUDE_HV, ATTR_EXCLUDE_KERNEL};
+use crate::Error;
+use nix::unistd::Pid;
+use std::fs::{self, File};
+use std::io;
+use std::ptr;
+use std::sync::atomic::{self, Ordering};
+
+/// Where the kernel publishes the dynamic perf type of the Intel PT PMU.
+const PMU_TYPE_PATH: &str = "/sys/bus/event_source/devices/intel_pt/type";
+
+// Offsets into `struct perf_event_mmap_page`.
+const AUX_HEAD: usize = 1056;
+const AUX_TAIL: usize = 1064;
+const AUX_OFFSET: usize = 1072;
+const AUX_SIZE: usize = 1080;
+
+/// An Intel PT trace of one tracee thread's user-space control flow.
+///
+/// The CPU writes compressed trace packets into a buffer shared with the
+/// kernel with almost no overhead. Call [`read`] whenever the tracee stops
+/// to collect everything it executed since the last call, which ties each
+/// chunk of the trace to a stop event. The packets are returned raw, to be
+/// decoded with a tool such as libipt along with the tracee's executable
+/// and memory map.
+///
+/// When the buffer fills up before it is read, the CPU stops tracing until
+/// there is room again, so the trace has a gap. Only the thread the trace
+/// was opened for is traced.
+///
+/// [`read`]: #method.read
+#[derive(Debug)]
+pub struct IntelPt {
+    // The mappings must be dropped before the event is closed.
+    aux: Mapping,
+    header: Mapping,
+    _event: File,
+}
+
+// The mappings are only accessed through `&mut self` or the kernel.
+unsafe impl Send for IntelPt {}
+
+impl IntelPt {
+    /// Start tracing thread `tid` into a buffer of `pages` pages, which is
+    /// rounded up to a power of two.
+    ///
+    /// Fails with `Error::Unsupported` if the CPU doesn't have Intel PT, or
+    /// it isn't exposed, as in most virtual machines.
+    pub fn open(tid: Pid, pages: usize) -> io::Result<IntelPt> {
+        let kind = match fs::read_to_string(PMU_TYPE_PATH) {
+            Ok(kind) => kind
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::Unsupported("Intel PT").into())
+            }
+            Err(e) => return Err(e),
+        };
+        let mut attr = PerfEventAttr::new(kind, 0);
+        attr.flags = ATTR_EXCLUDE_KERNEL | ATTR_EXCLUDE_HV;
+        let event = perf::perf_event_open(&attr, tid)?;
+        let page = perf::page_size();
+        // Just the header page, with no buffer for ordinary perf records.
+        let header = Mapping::new(&event, 0, page)?;
+        let aux_len = pages.max(1).next_power_of_two() * page;
+        unsafe {
+            ptr::write_volatile(header.u64_at(AUX_OFFSET), page as u64);
+            ptr::write_volatile(header.u64_at(AUX_SIZE), aux_len as u64);
+        }
+        let aux = Mapping::new(&event, page, aux_len)?;
+        Ok(IntelPt {
+            aux,
+            header,
+            _event: event,
+        })
+    }
+
+    /// Take the trace packets written since the last call.
+    pub fn read(&mut self) -> Vec<u8> {
+        let head = unsafe { ptr::read_volatile(self.header.u64_at(AUX_HEAD)) };
+        atomic::fence(Ordering::Acquire);
+        let tail = unsafe { ptr::read_volatile(self.header.u64_at(AUX_TAIL)) };
+        let data = self.aux.copy_wrapping(tail, (head - tail) as usize);
+        // Hand the space back to the kernel once it has been copied.
+        atomic::fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.header.u64_at(AUX_TAIL), head) };
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use nix::sys::wait::WaitStatus;
+    use std::process::Command;
+
+    #[test]
+    fn test_intel_pt() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .args(["spin", "10"])
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut pt = match IntelPt::open(tracee.pid(), 64) {
+            Ok(pt) => pt,
+            Err(e) => {
+                assert!(matches!(Error::from_io(&e), Some(Error::Unsupported(_))));
+                let child = tracee.child_mut().unwrap();
+                child.kill().expect("Error killing child");
+                child.wait().expect("Error waiting for child");
+                return;
+            }
+        };
+        assert!(pt.read().is_empty());
+        let output = tracee.wait_with_output().expect("Error waiting for tracee");
+        assert!(matches!(output.status, WaitStatus::Exited(_, 0)));
+        assert!(!pt.read().is_empty());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{nix_error, tkill, Event, TraceSession};
+use nix::errno::Errno;
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Runs the tracees of a [`TraceSession`] one at a time, to serialize
+/// multithreaded execution.
+///
+/// All tracees are kept stopped except one, which is single-stepped for a
+/// bounded number of instructions before the next tracee in round-robin
+/// order gets a turn. This makes the interleaving of threads deterministic,
+/// which is useful for reproducing race conditions. When only one tracee
+/// exists it runs freely until its next event.
+///
+/// The session should have [`follow_forks`] enabled so that new threads are
+/// scheduled too. A tracee that doesn't complete a step within the block
+/// timeout, for example because it is blocked in a system call waiting for
+/// another thread, is interrupted with `SIGSTOP` and loses the rest of its
+/// quantum. The interrupted system call is restarted when it next runs.
+///
+/// Because single-step traps are consumed by the scheduler, a `SIGTRAP`
+/// raised by a tracee while it is being stepped is not delivered.
+///
+/// [`TraceSession`]: struct.TraceSession.html
+/// [`follow_forks`]: struct.TraceSession.html#method.follow_forks
+#[derive(Debug)]
+pub struct SerialScheduler {
+    quantum: usize,
+    block_timeout: Duration,
+    queue: VecDeque<Pid>,
+    pending_signals: HashMap<Pid, Signal>,
+    interrupted: HashSet<Pid>,
+    /// Parents suspended in `vfork`, keyed by the child they are waiting for.
+    vforks: HashMap<Pid, Pid>,
+}
+
+impl SerialScheduler {
+    /// Create a scheduler that runs each tracee for `quantum` instructions at a time.
+    pub fn new(quantum: usize) -> SerialScheduler {
+        SerialScheduler {
+            quantum: quantum.max(1),
+            block_timeout: Duration::from_millis(10),
+            queue: VecDeque::new(),
+            pending_signals: HashMap::new(),
+            interrupted: HashSet::new(),
+            vforks: HashMap::new(),
+        }
+    }
+
+    /// Set how long a single step may take before the tracee is considered
+    /// blocked and interrupted. The default is 10 milliseconds.
+    pub fn block_timeout(&mut self, timeout: Duration) -> &mut SerialScheduler {
+        self.block_timeout = timeout;
+        self
+    }
+
+    /// Run one quantum of the next tracee in the session.
+    ///
+    /// Returns the events that occurred while it ran, which may include
+    /// events from new tracees it created. Returns an empty list once the
+    /// session has no tracees left.
+    pub fn run_quantum(&mut self, session: &mut TraceSession) -> io::Result<Vec<(Pid, Event)>> {
+        self.sync(session);
+        let mut events = vec![];
+        let pid = match self.next_runnable() {
+            Some(pid) => pid,
+            None => return Ok(events),
+        };
+        if self.queue.is_empty() {
+            // Nothing else can run, so there is nothing to serialize against.
+            let signal = self.pending_signals.remove(&pid);
+            match ptrace::cont(pid, signal) {
+                Ok(()) => {
+                    let (pid, event) = session.wait_for(pid)?;
+                    self.handle_event(session, pid, event, false, &mut events)?;
+                }
+                Err(nix::Error::Sys(Errno::ESRCH)) => {
+                    self.collect_exit(session, pid, &mut events)?
+                }
+                Err(e) => return Err(nix_error(e)),
+            }
+        } else {
+            for _ in 0..self.quantum {
+                let signal = self.pending_signals.remove(&pid);
+                match ptrace::step(pid, signal) {
+                    Ok(()) => {}
+                    Err(nix::Error::Sys(Errno::ESRCH)) => {
+                        self.collect_exit(session, pid, &mut events)?;
+                        break;
+                    }
+                    Err(e) => return Err(nix_error(e)),
+                }
+                let (event_pid, event, interrupted) = self.wait_step(session, pid)?;
+                self.handle_event(session, event_pid, event, true, &mut events)?;
+                if interrupted
+                    || session.get(pid).is_none()
+                    || self.vforks.values().any(|&p| p == pid)
+                {
+                    break;
+                }
+            }
+        }
+        if session.get(pid).is_some() && !self.queue.contains(&pid) {
+            self.queue.push_back(pid);
+        }
+        Ok(events)
+    }
+
+    /// Bring the run queue in line with the tracees in the session.
+    fn sync(&mut self, session: &TraceSession) {
+        self.queue.retain(|pid| session.get(*pid).is_some());
+        let mut new: Vec<Pid> = session
+            .pids()
+            .filter(|pid| !self.queue.contains(pid))
+            .collect();
+        new.sort();
+        self.queue.extend(new);
+    }
+
+    /// Take the next tracee that isn't suspended in `vfork` off the queue.
+    fn next_runnable(&mut self) -> Option<Pid> {
+        let position = self
+            .queue
+            .iter()
+            .position(|pid| !self.vforks.values().any(|parent| parent == pid))?;
+        self.queue.remove(position)
+    }
+
+    /// Wait for a single step of `pid` to finish, interrupting it if it blocks.
+    fn wait_step(
+        &mut self,
+        session: &mut TraceSession,
+        pid: Pid,
+    ) -> io::Result<(Pid, Event, bool)> {
+        let start = Instant::now();
+        loop {
+            if let Some((pid, event)) = session.try_wait_for(pid)? {
+                return Ok((pid, event, false));
+            }
+            if start.elapsed() >= self.block_timeout {
+                break;
+            }
+            thread::yield_now();
+        }
+        tkill(pid, Signal::SIGSTOP)?;
+        self.interrupted.insert(pid);
+        let (pid, event) = session.wait_for(pid)?;
+        Ok((pid, event, true))
+    }
+
+    /// Collect the exit status of a tracee that has disappeared.
+    fn collect_exit(
+        &mut self,
+        session: &mut TraceSession,
+        pid: Pid,
+        events: &mut Vec<(Pid, Event)>,
+    ) -> io::Result<()> {
+        while session.get(pid).is_some() {
+            let (pid, event) = session.wait_for(pid)?;
+            self.handle_event(session, pid, event, false, events)?;
+        }
+        Ok(())
+    }
+
+    fn handle_event(
+        &mut self,
+        session: &mut TraceSession,
+        pid: Pid,
+        event: Event,
+        stepping: bool,
+        events: &mut Vec<(Pid, Event)>,
+    ) -> io::Result<()> {
+        match event {
+            Event::Signal(Signal::SIGTRAP) if stepping => return Ok(()),
+            Event::Signal(Signal::SIGSTOP) if self.interrupted.remove(&pid) => return Ok(()),
+            Event::Signal(signal) => {
+                self.pending_signals.insert(pid, signal);
+            }
+            Event::Fork(child) | Event::Vfork(child) | Event::Clone(child) => {
+                if let Event::Vfork(_) = event {
+                    self.vforks.insert(child, pid);
+                }
+                events.push((pid, event));
+                // Collect the new tracee's initial stop so it can be scheduled.
+                if session.get(child).is_none() {
+                    let (child, event) = session.wait_for(child)?;
+                    self.handle_event(session, child, event, false, events)?;
+                }
+                return Ok(());
+            }
+            Event::Exec(former) => {
+                self.vforks.remove(&pid);
+                if former != pid {
+                    self.forget(former);
+                }
+            }
+            Event::Exited(_) | Event::Signaled(..) => {
+                self.vforks.remove(&pid);
+                self.forget(pid);
+            }
+            _ => {}
+        }
+        events.push((pid, event));
+        Ok(())
+    }
+
+    fn forget(&mut self, pid: Pid) {
+        self.queue.retain(|&p| p != pid);
+        self.pending_signals.remove(&pid);
+        self.interrupted.remove(&pid);
+        self.vforks.retain(|_, parent| *parent != pid);
+    }
+}
@@ -0,0 +1,183 @@
+//! A small builder for seccomp BPF filters that match on system call number.
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+// Classic BPF opcodes.
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JSET_K: u16 = 0x45;
+const BPF_RET_K: u16 = 0x06;
+
+// Offsets into `struct seccomp_data`.
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// System call numbers with this bit set use the x32 ABI.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// What a [`SeccompFilter`] does with a system call.
+///
+/// [`SeccompFilter`]: struct.SeccompFilter.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeccompAction {
+    /// Let the system call run.
+    Allow,
+    /// Let the system call run, and log it.
+    Log,
+    /// Fail the system call with the given errno, without running it.
+    Errno(u16),
+    /// Stop the tracee with a `PTRACE_EVENT_SECCOMP` stop, reported as
+    /// `Event::Seccomp` with the given data, before the system call runs.
+    ///
+    /// Without a tracer that set `PTRACE_O_TRACESECCOMP`, the system call
+    /// fails with `ENOSYS`.
+    Trace(u16),
+    /// Block the system call until a [`SeccompNotifier`] responds to it.
+    ///
+    /// Without a notifier, the system call fails with `ENOSYS`.
+    ///
+    /// [`SeccompNotifier`]: struct.SeccompNotifier.html
+    Notify,
+    /// Send the thread a `SIGSYS`.
+    Trap,
+    /// Kill the thread.
+    KillThread,
+    /// Kill the whole process.
+    KillProcess,
+}
+
+impl SeccompAction {
+    fn as_raw(self) -> u32 {
+        match self {
+            SeccompAction::Allow => libc::SECCOMP_RET_ALLOW,
+            SeccompAction::Log => libc::SECCOMP_RET_LOG,
+            SeccompAction::Errno(errno) => libc::SECCOMP_RET_ERRNO | errno as u32,
+            SeccompAction::Trace(data) => libc::SECCOMP_RET_TRACE | data as u32,
+            SeccompAction::Notify => libc::SECCOMP_RET_USER_NOTIF,
+            SeccompAction::Trap => libc::SECCOMP_RET_TRAP,
+            SeccompAction::KillThread => libc::SECCOMP_RET_KILL_THREAD,
+            SeccompAction::KillProcess => libc::SECCOMP_RET_KILL_PROCESS,
+        }
+    }
+}
+
+/// A seccomp filter that picks an action for each system call by number.
+///
+/// System calls made with a different architecture's calling convention,
+/// such as 32-bit calls from a 64-bit process, kill the process, because
+/// their numbers mean something else. Install a filter in a child with
+/// [`SpawnOptions::seccomp_filter`].
+///
+/// [`SpawnOptions::seccomp_filter`]: struct.SpawnOptions.html#method.seccomp_filter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeccompFilter {
+    default: SeccompAction,
+    rules: Vec<(i64, SeccompAction)>,
+}
+
+impl SeccompFilter {
+    /// Create a filter that applies `default` to every system call without a rule.
+    pub fn new(default: SeccompAction) -> SeccompFilter {
+        SeccompFilter {
+            default,
+            rules: vec![],
+        }
+    }
+
+    /// Apply `action` to system call `number`, such as `libc::SYS_openat`.
+    ///
+    /// This replaces any earlier rule for the same system call.
+    pub fn rule(&mut self, number: i64, action: SeccompAction) -> &mut SeccompFilter {
+        self.rules.retain(|&(n, _)| n != number);
+        self.rules.push((number, action));
+        self
+    }
+
+    /// Whether any system call is handled with `SeccompAction::Trace`.
+    pub(crate) fn traces(&self) -> bool {
+        let is_trace = |action: &SeccompAction| matches!(action, SeccompAction::Trace(_));
+        is_trace(&self.default) || self.rules.iter().any(|(_, action)| is_trace(action))
+    }
+
+    /// Compile the filter to a BPF program.
+    pub(crate) fn compile(&self) -> Vec<libc::sock_filter> {
+        let kill = libc::SECCOMP_RET_KILL_PROCESS;
+        let mut program = vec![
+            stmt(BPF_LD_W_ABS, DATA_ARCH),
+            jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET_K, kill),
+            stmt(BPF_LD_W_ABS, DATA_NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(BPF_JMP_JSET_K, X32_SYSCALL_BIT, 0, 1),
+            stmt(BPF_RET_K, kill),
+        ]);
+        for &(number, action) in &self.rules {
+            program.push(jump(BPF_JMP_JEQ_K, number as u32, 0, 1));
+            program.push(stmt(BPF_RET_K, action.as_raw()));
+        }
+        program.push(stmt(BPF_RET_K, self.default.as_raw()));
+        program
+    }
+}
+
+fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+/// Install the compiled `program` in the calling thread with `flags`,
+/// returning the syscall's result, which is a notification descriptor when
+/// `SECCOMP_FILTER_FLAG_NEW_LISTENER` is given.
+///
+/// `PR_SET_NO_NEW_PRIVS` is set first, so no privileges are needed.
+pub(crate) fn install(program: &[libc::sock_filter], flags: libc::c_ulong) -> io::Result<RawFd> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let prog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            flags,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as RawFd)
+}
+
+/// Whether the running kernel supports `action`, asked with
+/// `SECCOMP_GET_ACTION_AVAIL`.
+///
+/// Kernels older than 4.14 can't answer, so every action is reported as
+/// unavailable on them.
+pub(crate) fn action_available(action: SeccompAction) -> bool {
+    let raw = action.as_raw() & libc::SECCOMP_RET_ACTION_FULL;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_GET_ACTION_AVAIL,
+            0,
+            &raw as *const u32,
+        )
+    };
+    ret == 0
+}
@@ -0,0 +1,1736 @@
+#[cfg(feature = "backend")]
+use crate::backend::Backend;
+use crate::backend::BoxedBackend;
+use crate::fallback::{self, Fallback};
+#[cfg(feature = "hooks")]
+use crate::hook::Hook;
+use crate::tree::read_cmdline;
+#[cfg(feature = "hooks")]
+use crate::Activity;
+use crate::{
+    antidebug, nix_error, permission, syscall, tkill, wait_error, AntiDebugProbe,
+    CommandPtraceSpawn, DropPolicy, Error, Event, EventFilter, Resume, SpawnOptions, Stats,
+    SyscallInfo, Tracee,
+};
+use nix::errno::Errno;
+use nix::sys::ptrace::{self, Options, Request};
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::time::Instant;
+
+/// `PTRACE_EVENT_STOP`, which is missing from older libc versions.
+pub(crate) const PTRACE_EVENT_STOP: i32 = 128;
+
+/// Tell a group-stop from an interrupt in a `PTRACE_EVENT_STOP` with
+/// `signal`.
+///
+/// Both have siginfo, so only the signal tells them apart: an interrupt
+/// reports `SIGTRAP`, unless the process was stopped by a signal and hasn't
+/// had `SIGCONT` since, in which case it reports the stop signal and counts
+/// as a group-stop.
+pub(crate) fn classify_stop(signal: Signal) -> Event {
+    match signal {
+        Signal::SIGSTOP | Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU => {
+            Event::GroupStop(signal)
+        }
+        _ => Event::InterruptStop,
+    }
+}
+
+type ExecPredicate = Box<dyn FnMut(&Path, &[OsString]) -> bool>;
+
+/// The `exec` that reporting starts at, from `TraceSession::trace_from_exec`.
+struct ExecTrigger(ExecPredicate);
+
+impl fmt::Debug for ExecTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExecTrigger").finish_non_exhaustive()
+    }
+}
+
+/// A collection of tracees that are waited on together.
+///
+/// A session keeps track of every process it is tracing, including children
+/// that are automatically attached when [`follow_forks`] is enabled, and keeps
+/// that bookkeeping up to date as tracees fork and exit.
+///
+/// Because [`wait_any`] waits for any child or tracee of the calling thread,
+/// a session should be used from the thread that spawned its tracees, and
+/// that thread's other children should be managed by the session too.
+///
+/// [`follow_forks`]: #method.follow_forks
+/// [`wait_any`]: #method.wait_any
+#[derive(Debug, Default)]
+pub struct TraceSession {
+    tracees: HashMap<Pid, Tracee>,
+    options: Option<Options>,
+    /// Statuses collected by `snapshot_registers` that haven't been reported,
+    /// with when they were collected.
+    pending: VecDeque<(WaitStatus, Instant)>,
+    /// Tracees with a `SIGSTOP` from `snapshot_registers` still to arrive.
+    stray_sigstops: HashSet<Pid>,
+    /// Whether restarted system calls are reported, from `report_syscall_restarts`.
+    report_restarts: bool,
+    /// The instruction and stack pointers of interrupted system calls that
+    /// may be restarted, by tracee.
+    restarting: HashMap<Pid, (u64, u64)>,
+    /// The parents of vfork children that still share their memory.
+    vforks: HashMap<Pid, Pid>,
+    /// The events to report, from `set_filter`.
+    filter: Option<EventFilter>,
+    /// Tracees in a system call whose entry the filter left out.
+    filtered_calls: HashSet<Pid>,
+    /// The most events in a row `wait_any` reports from one tracee while
+    /// others have some, from `fair_scheduling`.
+    fairness: Option<usize>,
+    /// The tracee the last events `wait_any` took came from, and how many.
+    consecutive: Option<(Pid, usize)>,
+    /// Whether tracees' stops are counted, from `collect_stats`.
+    collect_stats: bool,
+    /// The counts of the tracees that have exited.
+    exited_stats: Stats,
+    /// What to wait for before reporting anything, from `trace_from_exec`.
+    exec_trigger: Option<ExecTrigger>,
+    /// Whether debugger checks are reported, from `detect_anti_debug`.
+    detect_anti_debug: bool,
+    /// When the status of the last event returned was collected.
+    event_time: Option<Instant>,
+    /// What to tell about the session's activity, from `set_hook`.
+    #[cfg(feature = "hooks")]
+    hook: Option<Hook>,
+    /// What waits for and resumes the tracees, from `set_backend`.
+    backend: BoxedBackend,
+}
+
+impl TraceSession {
+    /// Create an empty session.
+    pub fn new() -> TraceSession {
+        TraceSession::default()
+    }
+
+    /// Spawn `command_line` with `sh -c`, in a session that traces the shell
+    /// and everything it runs, returning the session and the shell's pid.
+    ///
+    /// The session follows forks, as with [`follow_forks`], and reports
+    /// every `exec` as `Event::Exec`. Tracees also get `PTRACE_O_EXITKILL`,
+    /// so that none of them is left running untraced if the tracer dies.
+    /// The shell is left in its initial stop, ready to be run with
+    /// `ProcessTree::run`, for example, to see which programs it ran.
+    ///
+    /// [`follow_forks`]: #method.follow_forks
+    pub fn shell<S: AsRef<OsStr>>(
+        command_line: S,
+        options: SpawnOptions,
+    ) -> io::Result<(TraceSession, Pid)> {
+        let mut session = TraceSession::new();
+        session.follow_forks();
+        let ptrace_options = session.options.unwrap_or_else(Options::empty)
+            | Options::PTRACE_O_TRACEEXEC
+            | Options::PTRACE_O_EXITKILL;
+        session.ptrace_options(ptrace_options);
+        let pid = session.spawn(Command::new("/bin/sh").arg("-c").arg(command_line), options)?;
+        Ok((session, pid))
+    }
+
+    /// Set ptrace options to be applied to every tracee added to the session.
+    ///
+    /// Tracees that were automatically attached inherit their options from
+    /// their parent.
+    pub fn ptrace_options(&mut self, options: Options) -> &mut TraceSession {
+        self.options = Some(options);
+        self
+    }
+
+    /// Add `options` to the session's ptrace options, setting them on the
+    /// tracees already in the session as well as those added later.
+    ///
+    /// This is for tracers that are given a session with its tracees in it,
+    /// and need some options for themselves. The tracees must be stopped.
+    pub fn add_ptrace_options(&mut self, options: Options) -> io::Result<()> {
+        let options = self.options.unwrap_or_else(Options::empty) | options;
+        let pids: Vec<Pid> = self.pids().collect();
+        for pid in pids {
+            let result = self.backend.0.set_options(pid, options);
+            self.check(pid, Request::PTRACE_SETOPTIONS, result)?;
+        }
+        self.options = Some(options);
+        Ok(())
+    }
+
+    /// Automatically trace children created with `fork`, `vfork` and `clone`.
+    ///
+    /// A vfork child shares its parent's memory until it calls `exec` or
+    /// exits, which the parent reports as `Event::VforkDone`. Meanwhile
+    /// `Tracee::vfork_shared` names the other process for both of them, and
+    /// `Tracee::write_memory` refuses to write either.
+    pub fn follow_forks(&mut self) -> &mut TraceSession {
+        let options = self.options.unwrap_or_else(Options::empty)
+            | Options::PTRACE_O_TRACEFORK
+            | Options::PTRACE_O_TRACEVFORK
+            | Options::PTRACE_O_TRACEVFORKDONE
+            | Options::PTRACE_O_TRACECLONE;
+        self.ptrace_options(options)
+    }
+
+    /// Stop tracees at every system call entry and exit when they are resumed
+    /// with `PTRACE_SYSCALL`, reporting those stops as `Event::Syscall`.
+    ///
+    /// This also reports `exec` as `Event::Exec` rather than a `SIGTRAP`.
+    ///
+    /// When a signal interrupts a system call that the kernel then restarts,
+    /// the tracee stops for the exit of the interrupted call, with a
+    /// kernel-internal errno such as `ERESTARTSYS`, and again for the entry
+    /// of the restarted one. Those stops are resumed with `PTRACE_SYSCALL`
+    /// without being reported, so that each system call is reported as one
+    /// entry and one exit with its real result, with the signal's stop in
+    /// between, unless [`report_syscall_restarts`] is set. If the signal is
+    /// handled and makes the call fail with `EINTR` instead, the kernel
+    /// doesn't stop again, so no exit is reported for it.
+    ///
+    /// [`report_syscall_restarts`]: #method.report_syscall_restarts
+    pub fn trace_syscalls(&mut self) -> &mut TraceSession {
+        let options = self.options.unwrap_or_else(Options::empty)
+            | Options::PTRACE_O_TRACESYSGOOD
+            | Options::PTRACE_O_TRACEEXEC;
+        self.ptrace_options(options)
+    }
+
+    /// Report every system call stop as `Event::Syscall`, including the
+    /// ones for interrupted system calls that are restarted, which are
+    /// hidden by default as described for [`trace_syscalls`].
+    ///
+    /// Use `SyscallInfo::is_restart` to recognize the exits of interrupted
+    /// calls.
+    ///
+    /// [`trace_syscalls`]: #method.trace_syscalls
+    pub fn report_syscall_restarts(&mut self) -> &mut TraceSession {
+        self.report_restarts = true;
+        self
+    }
+
+    /// Report system call entries that check whether the tracee is being
+    /// debugged as `Event::AntiDebug`, with the kind of check, rather than
+    /// `Event::Syscall`.
+    ///
+    /// This sets [`trace_syscalls`], and needs `PTRACE_GET_SYSCALL_INFO`,
+    /// from Linux 5.3, to see which system call is being made. See
+    /// [`AntiDebugProbe`] for the checks that are recognized.
+    ///
+    /// [`trace_syscalls`]: #method.trace_syscalls
+    /// [`AntiDebugProbe`]: enum.AntiDebugProbe.html
+    pub fn detect_anti_debug(&mut self) -> &mut TraceSession {
+        self.detect_anti_debug = true;
+        self.trace_syscalls()
+    }
+
+    /// Only report the events that match `filter`.
+    ///
+    /// Other stops are resumed by the session as it waits: with
+    /// `PTRACE_SYSCALL` if [`trace_syscalls`] is set and `PTRACE_CONT`
+    /// otherwise, delivering any signal, while group-stops are kept with
+    /// `PTRACE_LISTEN`. Tracees that don't match the filter still have
+    /// their exits handled, but not reported, and [`wait_for`] waits for
+    /// the next event that matches.
+    ///
+    /// [`trace_syscalls`]: #method.trace_syscalls
+    /// [`wait_for`]: #method.wait_for
+    pub fn set_filter(&mut self, filter: EventFilter) -> &mut TraceSession {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Report every event again.
+    pub fn clear_filter(&mut self) -> &mut TraceSession {
+        self.filter = None;
+        self.filtered_calls.clear();
+        self
+    }
+
+    /// Leave out every event until a tracee calls `exec` with a program and
+    /// arguments that `predicate` accepts, reporting events as usual from
+    /// that `Event::Exec` on.
+    ///
+    /// This skips the noise of wrappers that run the interesting program,
+    /// such as a shell, or the interpreter of a script, which `/proc` names
+    /// as the program, with the script in the arguments after it. Events
+    /// left out are handled as with [`set_filter`], and a tracee that is in
+    /// a system call when the `exec` matches has the rest of that call left
+    /// out too. The exits of tracees are left out as well, except for the
+    /// last tracee's, so that waits don't fail when nothing matches.
+    ///
+    /// This sets `PTRACE_O_TRACEEXEC`, so it must come before tracees are
+    /// spawned or added.
+    ///
+    /// [`set_filter`]: #method.set_filter
+    pub fn trace_from_exec<F>(&mut self, predicate: F) -> &mut TraceSession
+    where
+        F: FnMut(&Path, &[OsString]) -> bool + 'static,
+    {
+        self.exec_trigger = Some(ExecTrigger(Box::new(predicate)));
+        let options = self.options.unwrap_or_else(Options::empty) | Options::PTRACE_O_TRACEEXEC;
+        self.ptrace_options(options)
+    }
+
+    /// Share [`wait_any`] and [`try_wait_any`] fairly between tracees, so
+    /// that a few busy ones can't hold up the rest.
+    ///
+    /// `waitpid` reports whichever tracees it finds first, which can be the
+    /// same ones over and over. Instead, each wait collects every status
+    /// that is ready, and reports them in the order they arrived, except
+    /// that once `max_consecutive` events in a row have come from one
+    /// tracee, another's comes next, if there is one. Collecting them costs
+    /// one more `waitpid` for each wait.
+    ///
+    /// [`wait_any`]: #method.wait_any
+    /// [`try_wait_any`]: #method.try_wait_any
+    pub fn fair_scheduling(&mut self, max_consecutive: usize) -> &mut TraceSession {
+        self.fairness = Some(max_consecutive.max(1));
+        self
+    }
+
+    /// Count the stops of each tracee, which [`Tracee::stats`] and
+    /// [`stats`] return.
+    ///
+    /// Counting system calls by number costs a `PTRACE_GET_SYSCALL_INFO`
+    /// for each system call stop, and counting breakpoint hits a
+    /// `PTRACE_GETSIGINFO` for each `SIGTRAP`.
+    ///
+    /// [`Tracee::stats`]: struct.Tracee.html#method.stats
+    /// [`stats`]: #method.stats
+    pub fn collect_stats(&mut self) -> &mut TraceSession {
+        self.collect_stats = true;
+        self
+    }
+
+    /// The counts of every tracee the session has had, including those
+    /// that have exited, but not those taken out with [`remove`].
+    ///
+    /// [`remove`]: #method.remove
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.exited_stats.clone();
+        for tracee in self.tracees.values() {
+            stats.add(&tracee.stats());
+        }
+        stats
+    }
+
+    /// Call `hook` with each [`Activity`] of the session from now on: the
+    /// tracees it adds, the events it collects, the resumes it does and the
+    /// ptrace requests of its that fail.
+    ///
+    /// The hook is called on the tracer thread in the middle of the
+    /// session's work, so it should return quickly, for example after
+    /// logging the activity.
+    ///
+    /// [`Activity`]: enum.Activity.html
+    #[cfg(feature = "hooks")]
+    pub fn set_hook<F>(&mut self, hook: F) -> &mut TraceSession
+    where
+        F: FnMut(&Activity) + 'static,
+    {
+        self.hook = Some(Hook(Box::new(hook)));
+        self
+    }
+
+    /// Make the session's waits and resumes, and the `PTRACE_SETOPTIONS`
+    /// and `PTRACE_GETEVENTMSG` requests it makes itself, with `backend`
+    /// instead of nix. See [`Backend`].
+    ///
+    /// This should be set before tracees are added.
+    ///
+    /// [`Backend`]: trait.Backend.html
+    #[cfg(feature = "backend")]
+    pub fn set_backend<B: Backend + 'static>(&mut self, backend: B) -> &mut TraceSession {
+        self.backend = BoxedBackend(Box::new(backend));
+        self
+    }
+
+    /// Spawn `command` with ptrace enabled and add it to the session.
+    ///
+    /// The new tracee is left in its initial stop.
+    pub fn spawn(&mut self, command: &mut Command, mut options: SpawnOptions) -> io::Result<Pid> {
+        if let Some(ptrace_options) = self.options {
+            options.ptrace_options(ptrace_options);
+        }
+        let tracee = command.spawn_tracee(options)?;
+        let pid = tracee.pid();
+        self.tracees.insert(pid, tracee);
+        #[cfg(feature = "hooks")]
+        self.notify(Activity::Added(pid));
+        Ok(pid)
+    }
+
+    /// Add an existing tracee, which must be stopped, to the session.
+    pub fn add(&mut self, tracee: Tracee) -> io::Result<Pid> {
+        let pid = tracee.pid();
+        if let Some(options) = self.options {
+            let result = self.backend.0.set_options(pid, options);
+            self.check(pid, Request::PTRACE_SETOPTIONS, result)?;
+        }
+        self.tracees.insert(pid, tracee);
+        #[cfg(feature = "hooks")]
+        self.notify(Activity::Added(pid));
+        Ok(pid)
+    }
+
+    /// Attach to every thread of the running process `pid` with
+    /// `PTRACE_SEIZE`, stop them all and add them to the session, returning
+    /// their thread IDs with the thread group leader first.
+    ///
+    /// The threads are listed from `/proc/<pid>/task`, which is listed again
+    /// after each new thread has stopped, until no more appear, since the
+    /// threads not yet stopped can create others meanwhile. Once all of them
+    /// are stopped none can, so when this returns every thread of the process
+    /// is in the session, stopped in `Event::InterruptStop` or, if it was
+    /// already stopped, its group-stop. Threads that exit meanwhile are left
+    /// out. The session's ptrace options are only set afterwards.
+    ///
+    /// A thread may stop for a signal before its interrupt arrives. It is
+    /// left stopped there, and the signal is returned by the next wait as
+    /// usual, with the interrupt stop after it is resumed.
+    ///
+    /// If a thread can't be attached, the threads already attached are
+    /// detached again and the error is returned.
+    ///
+    /// Kernels older than 3.4 lack `PTRACE_SEIZE`, and the threads are
+    /// attached with `PTRACE_ATTACH` instead, which stops them with a
+    /// `SIGSTOP` that isn't reported. Their group-stops are then reported as
+    /// `Event::Signal`, and they can't be interrupted.
+    pub fn attach(&mut self, pid: Pid) -> io::Result<Vec<Pid>> {
+        let mut stopped = vec![];
+        let mut seize = !fallback::used(Fallback::Attach);
+        if let Err(e) = attach_threads(pid, &mut stopped, &mut seize) {
+            for &(tid, _) in &stopped {
+                let _ = ptrace::detach(tid, None);
+            }
+            return Err(e);
+        }
+        if let Some(options) = self.options {
+            for &(tid, _) in &stopped {
+                let result = self.backend.0.set_options(tid, options);
+                if let Err(e) = self.check(tid, Request::PTRACE_SETOPTIONS, result) {
+                    // None of the threads is in the session yet, so leave
+                    // them all as they were.
+                    for &(tid, _) in &stopped {
+                        let _ = ptrace::detach(tid, None);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        let mut tids = vec![];
+        for (tid, status) in stopped {
+            match status {
+                WaitStatus::PtraceEvent(_, _, PTRACE_EVENT_STOP) => {}
+                // The stop `PTRACE_ATTACH` caused.
+                WaitStatus::Stopped(_, Signal::SIGSTOP) if !seize => {}
+                status => self.pending.push_back((status, Instant::now())),
+            }
+            self.tracees.insert(tid, Tracee::attached(tid, status));
+            #[cfg(feature = "hooks")]
+            self.notify(Activity::Added(tid));
+            tids.push(tid);
+        }
+        Ok(tids)
+    }
+
+    /// Get the tracee with the given pid.
+    pub fn get(&self, pid: Pid) -> Option<&Tracee> {
+        self.tracees.get(&pid)
+    }
+
+    /// Get the tracee with the given pid mutably.
+    pub fn get_mut(&mut self, pid: Pid) -> Option<&mut Tracee> {
+        self.tracees.get_mut(&pid)
+    }
+
+    /// Remove the tracee with the given pid from the session, without detaching it.
+    pub fn remove(&mut self, pid: Pid) -> Option<Tracee> {
+        self.tracees.remove(&pid)
+    }
+
+    /// The pids of all tracees in the session.
+    pub fn pids(&self) -> impl Iterator<Item = Pid> + '_ {
+        self.tracees.keys().cloned()
+    }
+
+    /// The number of tracees in the session.
+    pub fn len(&self) -> usize {
+        self.tracees.len()
+    }
+
+    /// Whether the session has no tracees.
+    pub fn is_empty(&self) -> bool {
+        self.tracees.is_empty()
+    }
+
+    /// Wait for the next state change of any tracee.
+    ///
+    /// Tracees that exit are removed from the session before their final
+    /// event is returned. New tracees are added to the session when their
+    /// initial stop is reported as `Event::Attached`.
+    pub fn wait_any(&mut self) -> io::Result<(Pid, Event)> {
+        loop {
+            let (status, time) = match self.take_next()? {
+                Some(pending) => pending,
+                // Only ptrace requests from the tracer thread are allowed, so
+                // don't steal statuses from children of other threads.
+                None => (
+                    self.backend
+                        .0
+                        .waitpid(None, WaitPidFlag::__WALL | WaitPidFlag::__WNOTHREAD)
+                        .map_err(|e| self.wait_any_error(e))?,
+                    Instant::now(),
+                ),
+            };
+            if let Some(event) = self.handle_status(status, time)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Check for a state change of any tracee without blocking.
+    ///
+    /// Returns `Ok(None)` if no tracee has a state change to report.
+    pub fn try_wait_any(&mut self) -> io::Result<Option<(Pid, Event)>> {
+        let flags = WaitPidFlag::__WALL | WaitPidFlag::__WNOTHREAD | WaitPidFlag::WNOHANG;
+        loop {
+            let (status, time) = match self.take_next()? {
+                Some(pending) => pending,
+                None => match self
+                    .backend
+                    .0
+                    .waitpid(None, flags)
+                    .map_err(|e| self.wait_any_error(e))?
+                {
+                    WaitStatus::StillAlive => return Ok(None),
+                    status => (status, Instant::now()),
+                },
+            };
+            if let Some(event) = self.handle_status(status, time)? {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    /// With no tracees left `ECHILD` is expected, but otherwise it means they
+    /// were reaped behind the session's back.
+    fn wait_any_error(&self, e: nix::Error) -> io::Error {
+        if self.is_empty() {
+            nix_error(e)
+        } else {
+            wait_error(e)
+        }
+    }
+
+    /// Wait for the next state change of the tracee `pid`.
+    ///
+    /// The session is updated as with [`wait_any`](#method.wait_any).
+    pub fn wait_for(&mut self, pid: Pid) -> io::Result<(Pid, Event)> {
+        loop {
+            let (status, time) = match self.take_pending(Some(pid)) {
+                Some(pending) => pending,
+                None => (
+                    self.backend
+                        .0
+                        .waitpid(Some(pid), WaitPidFlag::__WALL)
+                        .map_err(wait_error)?,
+                    Instant::now(),
+                ),
+            };
+            if let Some(event) = self.handle_status(status, time)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Check for a state change of the tracee `pid` without blocking.
+    ///
+    /// Returns `Ok(None)` if the tracee has no state change to report.
+    pub fn try_wait_for(&mut self, pid: Pid) -> io::Result<Option<(Pid, Event)>> {
+        loop {
+            let (status, time) = match self.take_pending(Some(pid)) {
+                Some(pending) => pending,
+                None => match self
+                    .backend
+                    .0
+                    .waitpid(Some(pid), WaitPidFlag::__WALL | WaitPidFlag::WNOHANG)
+                    .map_err(wait_error)?
+                {
+                    WaitStatus::StillAlive => return Ok(None),
+                    status => (status, Instant::now()),
+                },
+            };
+            if let Some(event) = self.handle_status(status, time)? {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    /// Resume every tracee and pass each event to `record` until they have
+    /// all exited.
+    ///
+    /// `record` gets the session too, to look up the tracee with [`get`] and
+    /// the time of the event with [`event_time`]. A tracee whose last event
+    /// it is, because it exited, is gone by then; any other is resumed once
+    /// `record` returns, delivering the signal it stopped for, if any. That
+    /// is with `PTRACE_SYSCALL` if the session was configured with
+    /// [`trace_syscalls`], and `PTRACE_CONT` otherwise.
+    ///
+    /// All tracees must be stopped. An error from `record` ends the run,
+    /// leaving its tracee stopped. This is how models of the tracees such as
+    /// [`FdTable`] follow a session, and one closure can feed several of
+    /// them.
+    ///
+    /// [`get`]: #method.get
+    /// [`event_time`]: #method.event_time
+    /// [`trace_syscalls`]: #method.trace_syscalls
+    /// [`FdTable`]: struct.FdTable.html
+    pub fn run<F>(&mut self, mut record: F) -> io::Result<()>
+    where
+        F: FnMut(&TraceSession, Pid, Event) -> io::Result<()>,
+    {
+        let sysgood = self.sysgood();
+        self.run_with(|session, pid, event| {
+            record(session, pid, event)?;
+            let signal = match event {
+                Event::Signal(signal) => Some(signal),
+                _ => None,
+            };
+            Ok(Some(if sysgood {
+                Resume::Syscall(signal)
+            } else {
+                Resume::Continue(signal)
+            }))
+        })
+    }
+
+    /// Resume every tracee and pass each event to `handle` until they have
+    /// all exited, resuming the tracee as `handle` decides.
+    ///
+    /// This is [`run`] for tracers that act on their tracees: `handle` gets
+    /// the session mutably, so it can step a tracee, remove one or resume
+    /// another, and returns how to resume the tracee of the event, with
+    /// [`resume_as`], or `None` to leave it stopped, or because it has
+    /// resumed it itself. A tracee that is gone once `handle` returns is
+    /// left alone.
+    ///
+    /// [`run`]: #method.run
+    /// [`resume_as`]: #method.resume_as
+    pub fn run_with<F>(&mut self, mut handle: F) -> io::Result<()>
+    where
+        F: FnMut(&mut TraceSession, Pid, Event) -> io::Result<Option<Resume>>,
+    {
+        let pids: Vec<Pid> = self.pids().collect();
+        for pid in pids {
+            self.resume(pid, None)?;
+        }
+        while !self.is_empty() {
+            let (pid, event) = self.wait_any()?;
+            let decision = handle(self, pid, event)?;
+            if let (Some(decision), Some(_)) = (decision, self.get(pid)) {
+                self.resume_as(pid, decision)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resume the stopped tracee `pid` as [`run`] does, delivering `signal`:
+    /// with `PTRACE_SYSCALL` if the session was configured with
+    /// [`trace_syscalls`], and `PTRACE_CONT` otherwise.
+    ///
+    /// [`run`]: #method.run
+    /// [`trace_syscalls`]: #method.trace_syscalls
+    pub fn resume(&mut self, pid: Pid, signal: Option<Signal>) -> io::Result<()> {
+        let request = if self.sysgood() {
+            Request::PTRACE_SYSCALL
+        } else {
+            Request::PTRACE_CONT
+        };
+        self.resume_with(pid, request, signal)
+    }
+
+    /// Have the stopped tracee `pid` execute a single instruction, with
+    /// `PTRACE_SINGLESTEP`, delivering `signal`.
+    ///
+    /// Like the session's other resumes this goes through its backend, and
+    /// its hook sees it.
+    pub fn step(&mut self, pid: Pid, signal: Option<Signal>) -> io::Result<()> {
+        self.resume_with(pid, Request::PTRACE_SINGLESTEP, signal)
+    }
+
+    /// Resume the stopped tracee `pid` as `decision` says.
+    ///
+    /// A tracee that is detached is removed from the session first, and
+    /// left running when it is dropped.
+    pub fn resume_as(&mut self, pid: Pid, decision: Resume) -> io::Result<()> {
+        match decision {
+            Resume::Continue(signal) => self.resume_with(pid, Request::PTRACE_CONT, signal),
+            Resume::Syscall(signal) => self.resume_with(pid, Request::PTRACE_SYSCALL, signal),
+            Resume::Listen => self.resume_with(pid, Request::PTRACE_LISTEN, None),
+            Resume::Detach(signal) => {
+                if let Some(mut tracee) = self.remove(pid) {
+                    tracee.set_drop_policy(DropPolicy::Leave);
+                }
+                let result = ptrace::detach(pid, signal);
+                self.check(pid, Request::PTRACE_DETACH, result)
+            }
+            Resume::Kill => signal::kill(pid, Signal::SIGKILL).map_err(nix_error),
+        }
+    }
+
+    /// When the status of the event last returned by a wait was collected,
+    /// or `None` if no event has been returned yet.
+    ///
+    /// The time is taken as soon as `waitpid` returns, before the status is
+    /// decoded, so it is close to when the tracee stopped, and comes from
+    /// `Instant`, which uses `CLOCK_MONOTONIC`. Statuses that
+    /// `snapshot_registers` or `attach` collect for later have the time they
+    /// were collected.
+    pub fn event_time(&self) -> Option<Instant> {
+        self.event_time
+    }
+
+    /// Take a snapshot of the registers of every tracee in the session.
+    ///
+    /// Tracees that are already stopped are read where they are. Running
+    /// tracees are stopped with `SIGSTOP`, and once every tracee has been
+    /// read they are resumed with `PTRACE_CONT`, so all of the registers are
+    /// from a point where the whole session was stopped. Tracees that had
+    /// been resumed with `PTRACE_SYSCALL` or `PTRACE_SINGLESTEP` lose that
+    /// mode.
+    ///
+    /// A running tracee may stop for some other event before the `SIGSTOP`
+    /// arrives. It is read and left stopped there, and the event is returned
+    /// by the next wait as usual, while the `SIGSTOP` is discarded when it is
+    /// reported later. Tracees that exit meanwhile are left out.
+    #[cfg(target_arch = "x86_64")]
+    pub fn snapshot_registers(&mut self) -> io::Result<HashMap<Pid, libc::user_regs_struct>> {
+        let mut snapshot = HashMap::new();
+        let mut interrupted = vec![];
+        let pids: Vec<Pid> = self.pids().collect();
+        for pid in pids {
+            match ptrace::getregs(pid) {
+                Ok(regs) => {
+                    snapshot.insert(pid, regs);
+                }
+                // The tracee isn't in a ptrace stop.
+                Err(nix::Error::Sys(Errno::ESRCH)) => match tkill(pid, Signal::SIGSTOP) {
+                    Ok(()) => interrupted.push(pid),
+                    // It has already exited.
+                    Err(ref e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+                    Err(e) => return Err(e),
+                },
+                Err(e) => return Err(nix_error(e)),
+            }
+        }
+        let mut resume = vec![];
+        for pid in interrupted {
+            match waitpid(pid, Some(WaitPidFlag::__WALL)).map_err(nix_error)? {
+                WaitStatus::Stopped(_, Signal::SIGSTOP) => resume.push(pid),
+                status if is_stop(&status) => {
+                    self.stray_sigstops.insert(pid);
+                    self.pending.push_back((status, Instant::now()));
+                }
+                status => {
+                    self.pending.push_back((status, Instant::now()));
+                    continue;
+                }
+            }
+            snapshot.insert(pid, ptrace::getregs(pid).map_err(nix_error)?);
+        }
+        for pid in resume {
+            self.resume_with(pid, Request::PTRACE_CONT, None)?;
+        }
+        Ok(snapshot)
+    }
+
+    /// Take the next status saved for `wait_any` to report, after collecting
+    /// the ready ones if scheduling is fair.
+    fn take_next(&mut self) -> io::Result<Option<(WaitStatus, Instant)>> {
+        let max = match self.fairness {
+            Some(max) => max,
+            None => return Ok(self.take_pending(None)),
+        };
+        let flags = WaitPidFlag::__WALL | WaitPidFlag::__WNOTHREAD | WaitPidFlag::WNOHANG;
+        loop {
+            match self.backend.0.waitpid(None, flags) {
+                Ok(WaitStatus::StillAlive) | Err(nix::Error::Sys(Errno::ECHILD)) => break,
+                Ok(status) => self.pending.push_back((status, Instant::now())),
+                Err(e) => return Err(self.wait_any_error(e)),
+            }
+        }
+        Ok(self.take_fair(max))
+    }
+
+    /// Take the first saved status, unless the last `max` came from its
+    /// tracee and another tracee has one.
+    fn take_fair(&mut self, max: usize) -> Option<(WaitStatus, Instant)> {
+        let index = match self.consecutive {
+            Some((pid, count)) if count >= max => self
+                .pending
+                .iter()
+                .position(|(s, _)| s.pid() != Some(pid))
+                .unwrap_or(0),
+            _ => 0,
+        };
+        let (status, time) = self.pending.remove(index)?;
+        self.consecutive = match (self.consecutive, status.pid()) {
+            (Some((last, count)), Some(pid)) if last == pid => Some((pid, count + 1)),
+            (_, pid) => pid.map(|pid| (pid, 1)),
+        };
+        Some((status, time))
+    }
+
+    /// Take the first status saved by `snapshot_registers`, for `pid` if given.
+    fn take_pending(&mut self, pid: Option<Pid>) -> Option<(WaitStatus, Instant)> {
+        let index = match pid {
+            Some(pid) => self
+                .pending
+                .iter()
+                .position(|(s, _)| s.pid() == Some(pid))?,
+            None => 0,
+        };
+        self.pending.remove(index)
+    }
+
+    /// Update the session for `status`, collected at `time`, returning the
+    /// event to report, or `None` if it has been discarded, either as a stray
+    /// `SIGSTOP` or because the filter left it out.
+    fn handle_status(
+        &mut self,
+        status: WaitStatus,
+        time: Instant,
+    ) -> io::Result<Option<(Pid, Event)>> {
+        let (pid, event) = match self.decode_status(status)? {
+            Some(event) => event,
+            None => return Ok(None),
+        };
+        #[cfg(feature = "hooks")]
+        self.notify(Activity::Stopped(pid, event));
+        if self.collect_stats {
+            if let Some(tracee) = self.tracees.get_mut(&pid) {
+                tracee.stats_mut().record(pid, event);
+            }
+        }
+        if self.wanted(pid, event)? {
+            self.event_time = Some(time);
+            return Ok(Some((pid, event)));
+        }
+        match event {
+            Event::Exited(..) | Event::Signaled(..) => {}
+            Event::GroupStop(_) => self.resume_with(pid, Request::PTRACE_LISTEN, None)?,
+            event => {
+                let signal = match event {
+                    Event::Signal(signal) => Some(signal),
+                    _ => None,
+                };
+                self.resume(pid, signal)?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether the filter, if there is one, matches `event` from `pid`, and
+    /// the `exec` from `trace_from_exec` has already happened.
+    fn wanted(&mut self, pid: Pid, event: Event) -> io::Result<bool> {
+        let syscall = matches!(event, Event::Syscall | Event::AntiDebug(_));
+        if let Some(ExecTrigger(predicate)) = &mut self.exec_trigger {
+            let matched = match event {
+                Event::Exec(_) => fs::read_link(format!("/proc/{}/exe", pid))
+                    .is_ok_and(|path| predicate(&path, &read_cmdline(pid))),
+                Event::Exited(..) | Event::Signaled(..) => self.tracees.is_empty(),
+                _ => false,
+            };
+            if !matched {
+                if syscall {
+                    match syscall_entry(pid) {
+                        Some(true) => self.filtered_calls.insert(pid),
+                        Some(false) => self.filtered_calls.remove(&pid),
+                        // System call stops alternate between entry and exit,
+                        // and new children start outside of a call.
+                        None if self.filtered_calls.remove(&pid) => false,
+                        None => self.filtered_calls.insert(pid),
+                    };
+                }
+                return Ok(false);
+            }
+            if let Event::Exec(_) = event {
+                self.exec_trigger = None;
+                // The rest of the `execve` is left out too.
+                self.filtered_calls.insert(pid);
+            }
+        }
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            // The exit of a call the trigger left out.
+            None => {
+                let left_out =
+                    syscall && self.filtered_calls.remove(&pid) && syscall_entry(pid) != Some(true);
+                return Ok(!left_out);
+            }
+        };
+        if !filter.wants_pid(pid) {
+            return Ok(false);
+        }
+        match event {
+            Event::Signal(signal) => Ok(filter.wants_signal(signal)),
+            Event::Syscall | Event::AntiDebug(_) => match syscall::syscall_info(pid) {
+                Ok(SyscallInfo::Entry { number, .. }) => {
+                    let wanted = filter.wants_syscall(number);
+                    if !wanted {
+                        self.filtered_calls.insert(pid);
+                    }
+                    Ok(wanted)
+                }
+                Ok(SyscallInfo::Exit { .. }) => Ok(!self.filtered_calls.remove(&pid)),
+                _ => Ok(true),
+            },
+            _ => Ok(true),
+        }
+    }
+
+    /// Update the session for `status`, returning the event it stands for,
+    /// or `None` if the status was a stray `SIGSTOP` that has been discarded.
+    fn decode_status(&mut self, status: WaitStatus) -> io::Result<Option<(Pid, Event)>> {
+        let pid = match status.pid() {
+            Some(pid) => pid,
+            None => return Err(io::Error::other("No pid in wait status")),
+        };
+        // The first stop of a process we haven't seen before is the initial
+        // stop of an automatically attached child.
+        if !self.tracees.contains_key(&pid) && is_stop(&status) {
+            let mut tracee = Tracee::attached(pid, status);
+            tracee.set_vfork_shared(self.vforks.get(&pid).cloned());
+            self.tracees.insert(pid, tracee);
+            return Ok(Some((pid, Event::Attached)));
+        }
+        let status = match status {
+            WaitStatus::Stopped(_, Signal::SIGTRAP) if !self.sysgood() && is_syscall_trap(pid) => {
+                WaitStatus::PtraceSyscall(pid)
+            }
+            status => status,
+        };
+        let event = match status {
+            WaitStatus::Exited(_, code) => {
+                self.forget(pid);
+                Event::Exited(code)
+            }
+            WaitStatus::Signaled(_, signal, core_dumped) => {
+                self.forget(pid);
+                Event::Signaled(signal, core_dumped)
+            }
+            WaitStatus::Stopped(_, Signal::SIGSTOP) if self.stray_sigstops.remove(&pid) => {
+                self.resume_with(pid, Request::PTRACE_CONT, None)?;
+                return Ok(None);
+            }
+            WaitStatus::Stopped(_, signal) => Event::Signal(signal),
+            WaitStatus::PtraceSyscall(_) if !self.report_restarts && self.skip_restart(pid)? => {
+                return Ok(None);
+            }
+            WaitStatus::PtraceSyscall(_) => match self.anti_debug_probe(pid)? {
+                Some(probe) => Event::AntiDebug(probe),
+                None => Event::Syscall,
+            },
+            WaitStatus::PtraceEvent(_, signal, event) => {
+                let event = self.decode_event(pid, signal, event)?;
+                self.track_vfork(pid, event);
+                event
+            }
+            WaitStatus::Continued(_) | WaitStatus::StillAlive => {
+                return Err(io::Error::other("Unexpected wait status"))
+            }
+        };
+        Ok(Some((pid, event)))
+    }
+
+    /// Keep track of which tracees share memory because of `vfork`.
+    fn track_vfork(&mut self, pid: Pid, event: Event) {
+        match event {
+            Event::Vfork(child) => {
+                self.vforks.insert(child, pid);
+                self.set_vfork_shared(pid, Some(child));
+                self.set_vfork_shared(child, Some(pid));
+            }
+            Event::VforkDone(child) => self.end_vfork(child),
+            Event::Exec(_) => self.end_vfork(pid),
+            _ => {}
+        }
+    }
+
+    /// Note that the vfork child `child`, if it is one, has its own memory.
+    fn end_vfork(&mut self, child: Pid) {
+        if let Some(parent) = self.vforks.remove(&child) {
+            self.set_vfork_shared(parent, None);
+            self.set_vfork_shared(child, None);
+        }
+    }
+
+    fn set_vfork_shared(&mut self, pid: Pid, other: Option<Pid>) {
+        if let Some(tracee) = self.tracees.get_mut(&pid) {
+            tracee.set_vfork_shared(other);
+        }
+    }
+
+    /// Forget the bookkeeping for `pid`, which has exited.
+    fn forget(&mut self, pid: Pid) {
+        self.end_vfork(pid);
+        // A vfork parent killed meanwhile leaves its child the memory.
+        if let Some(child) = self
+            .vforks
+            .iter()
+            .find(|&(_, &p)| p == pid)
+            .map(|(&c, _)| c)
+        {
+            self.end_vfork(child);
+        }
+        if let Some(tracee) = self.tracees.remove(&pid) {
+            self.exited_stats.add(&tracee.stats());
+        }
+        self.stray_sigstops.remove(&pid);
+        self.restarting.remove(&pid);
+        self.filtered_calls.remove(&pid);
+    }
+
+    /// Resume `pid` from its system call stop if the stop is for an
+    /// interrupted system call or its restart, returning whether it was.
+    fn skip_restart(&mut self, pid: Pid) -> io::Result<bool> {
+        // Without `PTRACE_GET_SYSCALL_INFO`, every stop is reported.
+        let (info, pointers) = match syscall::syscall_stop(pid) {
+            Ok(stop) => stop,
+            Err(_) => return Ok(false),
+        };
+        let skip = match info {
+            SyscallInfo::Exit { .. } if info.is_restart() => {
+                self.restarting.insert(pid, pointers);
+                true
+            }
+            // A restarted call enters from the same place, while a signal
+            // handler running first would have moved the stack pointer.
+            SyscallInfo::Entry { .. } => self.restarting.remove(&pid) == Some(pointers),
+            _ => false,
+        };
+        if skip {
+            self.resume_with(pid, Request::PTRACE_SYSCALL, None)?;
+        }
+        Ok(skip)
+    }
+
+    /// Resume `pid` with `request`, which is `PTRACE_CONT`, `PTRACE_SYSCALL`
+    /// or `PTRACE_LISTEN`, delivering `signal`.
+    fn resume_with(
+        &mut self,
+        pid: Pid,
+        request: Request,
+        signal: Option<Signal>,
+    ) -> io::Result<()> {
+        let result = self.backend.0.resume(pid, request, signal);
+        self.check(pid, request, result)?;
+        #[cfg(feature = "hooks")]
+        self.notify(Activity::Resumed {
+            pid,
+            request,
+            signal,
+        });
+        Ok(())
+    }
+
+    /// Convert the result of the ptrace `request` for `pid`, telling the
+    /// hook if it failed.
+    #[cfg_attr(not(feature = "hooks"), allow(unused_variables))]
+    fn check<T>(&mut self, pid: Pid, request: Request, result: nix::Result<T>) -> io::Result<T> {
+        #[cfg(feature = "hooks")]
+        if let Err(e) = &result {
+            let errno = e.as_errno().unwrap_or(Errno::UnknownErrno);
+            self.notify(Activity::Failed {
+                pid,
+                request,
+                errno,
+            });
+        }
+        result.map_err(nix_error)
+    }
+
+    /// Tell the hook, if there is one, about `activity`.
+    #[cfg(feature = "hooks")]
+    fn notify(&mut self, activity: Activity) {
+        if let Some(hook) = &mut self.hook {
+            (hook.0)(&activity);
+        }
+    }
+
+    /// Whether tracees are given `PTRACE_O_TRACESYSGOOD`, so that their
+    /// system call stops are reported as such.
+    fn sysgood(&self) -> bool {
+        self.options
+            .is_some_and(|o| o.contains(Options::PTRACE_O_TRACESYSGOOD))
+    }
+
+    /// The debugger check `pid` is stopped at the entry of, if checks are
+    /// being detected.
+    fn anti_debug_probe(&self, pid: Pid) -> io::Result<Option<AntiDebugProbe>> {
+        if !self.detect_anti_debug {
+            return Ok(None);
+        }
+        match syscall::syscall_info(pid) {
+            Ok(SyscallInfo::Entry { number, args }) => antidebug::classify(pid, number, &args),
+            _ => Ok(None),
+        }
+    }
+
+    fn decode_event(&mut self, pid: Pid, signal: Signal, event: i32) -> io::Result<Event> {
+        if event == PTRACE_EVENT_STOP {
+            return Ok(classify_stop(signal));
+        }
+        let result = self.backend.0.event_message(pid);
+        let message = self.check(pid, Request::PTRACE_GETEVENTMSG, result)?;
+        let event = match event {
+            libc::PTRACE_EVENT_FORK => Event::Fork(Pid::from_raw(message as i32)),
+            libc::PTRACE_EVENT_VFORK => Event::Vfork(Pid::from_raw(message as i32)),
+            libc::PTRACE_EVENT_CLONE => Event::Clone(Pid::from_raw(message as i32)),
+            libc::PTRACE_EVENT_EXEC => {
+                // If a non-leader thread called exec it has taken over the
+                // leader's pid, and its old thread ID no longer exists.
+                let former = Pid::from_raw(message as i32);
+                if former != pid {
+                    if let Some(tracee) = self.tracees.remove(&former) {
+                        self.exited_stats.add(&tracee.stats());
+                    }
+                }
+                if let Some(tracee) = self.tracees.get_mut(&pid) {
+                    tracee.update_comm();
+                }
+                // Only a lost dumpable state is worth failing the wait for;
+                // the tracee may have been killed while the check ran.
+                if let Err(e) = permission::check_exec(pid) {
+                    if let Some(Error::NotDumpable(_)) = Error::from_io(&e) {
+                        return Err(e);
+                    }
+                }
+                Event::Exec(former)
+            }
+            libc::PTRACE_EVENT_VFORK_DONE => Event::VforkDone(Pid::from_raw(message as i32)),
+            libc::PTRACE_EVENT_EXIT => Event::Exit(message as i32),
+            libc::PTRACE_EVENT_SECCOMP => Event::Seccomp(message as u16),
+            _ => return Err(io::Error::other("Unknown ptrace event")),
+        };
+        Ok(event)
+    }
+}
+
+/// Seize and stop the threads of `pid` until none are left running, adding
+/// each that stopped to `stopped` with its stop status.
+///
+/// The threads are attached with `PTRACE_ATTACH` instead if `seize` is
+/// false, or once `PTRACE_SEIZE` turns out to be missing, when `seize` is
+/// cleared.
+fn attach_threads(
+    pid: Pid,
+    stopped: &mut Vec<(Pid, WaitStatus)>,
+    seize: &mut bool,
+) -> io::Result<()> {
+    let mut seen = HashSet::new();
+    loop {
+        let mut found = false;
+        for tid in threads(pid)? {
+            if !seen.insert(tid) {
+                continue;
+            }
+            found = true;
+            match attach_thread(tid, seize) {
+                Ok(()) => {}
+                // The thread has already exited.
+                Err(nix::Error::Sys(Errno::ESRCH)) if tid != pid => continue,
+                Err(e) => return Err(nix_error(e)),
+            }
+            if *seize {
+                let ret = unsafe { libc::ptrace(libc::PTRACE_INTERRUPT, tid.as_raw(), 0, 0) };
+                if ret < 0 && Errno::last() != Errno::ESRCH {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            match waitpid(tid, Some(WaitPidFlag::__WALL)).map_err(nix_error)? {
+                status if is_stop(&status) => stopped.push((tid, status)),
+                // It exited before stopping.
+                _ => {}
+            }
+        }
+        if !found {
+            return Ok(());
+        }
+    }
+}
+
+/// Attach to thread `tid` with `PTRACE_SEIZE` if `seize` is set, falling
+/// back to `PTRACE_ATTACH` for good if the kernel doesn't know it.
+fn attach_thread(tid: Pid, seize: &mut bool) -> nix::Result<()> {
+    if *seize {
+        match ptrace::seize(tid, Options::empty()) {
+            Err(nix::Error::Sys(errno)) if fallback::is_unknown(errno as i32) => {
+                fallback::record(Fallback::Attach);
+                *seize = false;
+            }
+            result => return result,
+        }
+    }
+    ptrace::attach(tid)
+}
+
+/// The thread IDs of process `pid`, with the thread group leader first.
+fn threads(pid: Pid) -> io::Result<Vec<Pid>> {
+    let mut tids = vec![pid];
+    for entry in fs::read_dir(format!("/proc/{}/task", pid))? {
+        let tid = entry?.file_name().to_str().and_then(|tid| tid.parse().ok());
+        match tid.map(Pid::from_raw) {
+            Some(tid) if tid != pid => tids.push(tid),
+            _ => {}
+        }
+    }
+    Ok(tids)
+}
+
+/// Whether `pid`, stopped with a plain `SIGTRAP`, is at a system call stop
+/// of a tracee without `PTRACE_O_TRACESYSGOOD`, rather than a breakpoint or
+/// a real signal.
+fn is_syscall_trap(pid: Pid) -> bool {
+    // The kernel reports syscall stops with `si_code` set to `SIGTRAP`, and
+    // signals and breakpoints with other codes.
+    match ptrace::getsiginfo(pid) {
+        Ok(info) => info.si_code == libc::SIGTRAP || info.si_code == libc::SIGTRAP | 0x80,
+        Err(_) => false,
+    }
+}
+
+/// Whether the system call stop `pid` is at is the entry of the call rather
+/// than its exit, if `PTRACE_GET_SYSCALL_INFO` or its fallback can tell.
+fn syscall_entry(pid: Pid) -> Option<bool> {
+    match syscall::syscall_info(pid) {
+        Ok(SyscallInfo::Entry { .. }) => Some(true),
+        Ok(SyscallInfo::Exit { .. }) => Some(false),
+        _ => None,
+    }
+}
+
+fn is_stop(status: &WaitStatus) -> bool {
+    matches!(
+        status,
+        WaitStatus::Stopped(..) | WaitStatus::PtraceEvent(..) | WaitStatus::PtraceSyscall(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use nix::sys::signal;
+
+    #[test]
+    // The process is reaped with `waitpid` rather than `Child::wait`.
+    #[allow(clippy::zombie_processes)]
+    fn test_attach_threads_without_seize() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let child = Command::new(&path)
+            .args(["sleep", "200"])
+            .spawn()
+            .expect("Error spawning test process");
+        let pid = Pid::from_raw(child.id() as i32);
+        // Attaching before its `exec` has finished gets a `SIGTRAP` first.
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let mut stopped = vec![];
+        attach_threads(pid, &mut stopped, &mut false).expect("Error attaching");
+        assert_eq!(stopped, [(pid, WaitStatus::Stopped(pid, Signal::SIGSTOP))]);
+        signal::kill(pid, Signal::SIGKILL).unwrap();
+        while waitpid(pid, Some(WaitPidFlag::__WALL)).is_ok() {}
+    }
+
+    #[test]
+    // The process is reaped with `waitpid` rather than `Child::wait`.
+    #[allow(clippy::zombie_processes)]
+    fn test_attach_detaches_on_error() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let child = Command::new(&path)
+            .args(["churn", "5000"])
+            .spawn()
+            .expect("Error spawning test process");
+        let pid = Pid::from_raw(child.id() as i32);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let mut session = TraceSession::new();
+        // The kernel refuses options it doesn't know.
+        session.ptrace_options(unsafe { Options::from_bits_unchecked(1 << 30) });
+        let e = session.attach(pid).expect_err("Attached with bad options");
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+        assert!(session.is_empty());
+        // Short-lived threads may have gone by now.
+        for entry in fs::read_dir(format!("/proc/{}/task", pid)).unwrap() {
+            if let Ok(status) = fs::read_to_string(entry.unwrap().path().join("status")) {
+                assert!(status.contains("TracerPid:\t0\n"), "{}", status);
+            }
+        }
+        signal::kill(pid, Signal::SIGKILL).unwrap();
+        while waitpid(pid, Some(WaitPidFlag::__WALL)).is_ok() {}
+    }
+
+    #[test]
+    fn test_trace_from_exec() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session
+            .trace_syscalls()
+            .trace_from_exec(|_, args| args.get(1).is_some_and(|arg| arg == "sleep"));
+        // Each `exec` runs the test program again with one argument fewer.
+        let pid = session
+            .spawn(
+                Command::new(&path).args(["exec", "exec", "sleep", "10"]),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        ptrace::syscall(pid, None).unwrap();
+        assert_eq!(session.wait_any().unwrap(), (pid, Event::Exec(pid)));
+        assert_eq!(read_cmdline(pid).len(), 3);
+        // The rest of the `execve` is left out.
+        ptrace::syscall(pid, None).unwrap();
+        assert_eq!(session.wait_any().unwrap(), (pid, Event::Syscall));
+        assert!(matches!(
+            syscall::syscall_info(pid),
+            Ok(SyscallInfo::Entry { .. })
+        ));
+        ptrace::cont(pid, None).unwrap();
+        assert_eq!(session.wait_any().unwrap(), (pid, Event::Exited(0)));
+
+        // Without a match only the exit is reported.
+        let mut session = TraceSession::new();
+        session.trace_from_exec(|_, _| false);
+        let pid = session
+            .spawn(
+                Command::new(&path).args(["exec", "sleep", "10"]),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        ptrace::cont(pid, None).unwrap();
+        assert_eq!(session.wait_any().unwrap(), (pid, Event::Exited(0)));
+    }
+
+    #[test]
+    fn test_trace_from_exec_in_child() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session
+            .trace_syscalls()
+            .follow_forks()
+            .trace_from_exec(|_, args| args.get(1).is_some_and(|arg| arg == "sleep"));
+        let pid = session
+            .spawn(
+                Command::new(&path).args(["spawn", "sleep", "10"]),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut entering: HashMap<Pid, bool> = HashMap::new();
+        session
+            .run(|_, tid, event| {
+                if event == Event::Syscall {
+                    // The calls that were under way at the `exec` are left
+                    // out, so every tracee's stops start with an entry.
+                    let entry = syscall_entry(tid).expect("No syscall info");
+                    let expected = entering.entry(tid).or_insert(true);
+                    assert_eq!(entry, *expected, "Wrong syscall stop of {}", tid);
+                    *expected = !entry;
+                }
+                Ok(())
+            })
+            .expect("Error running session");
+        assert!(entering.keys().any(|&tid| tid != pid));
+    }
+
+    #[test]
+    fn test_comm() {
+        let mut session = TraceSession::new();
+        session.ptrace_options(Options::PTRACE_O_TRACEEXEC);
+        let pid = session
+            .spawn(
+                Command::new("/bin/sh").args(["-c", "exec sleep 0"]),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning shell");
+        assert_eq!(session.get(pid).unwrap().comm(), Some("sh"));
+        ptrace::cont(pid, None).unwrap();
+        assert_eq!(session.wait_any().unwrap(), (pid, Event::Exec(pid)));
+        assert_eq!(session.get(pid).unwrap().comm(), Some("sleep"));
+        ptrace::cont(pid, None).unwrap();
+        assert_eq!(session.wait_any().unwrap(), (pid, Event::Exited(0)));
+    }
+
+    #[test]
+    fn test_run() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls().follow_forks();
+        let pid = session
+            .spawn(Command::new(&path).arg("fork"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut syscalls = 0;
+        let mut exits = vec![];
+        session
+            .run(|session, tid, event| {
+                match event {
+                    Event::Syscall => syscalls += 1,
+                    Event::Exited(code) => exits.push((tid, code)),
+                    _ => {}
+                }
+                // Only tracees that have exited are gone.
+                let exited = matches!(event, Event::Exited(_) | Event::Signaled(..));
+                assert_eq!(session.get(tid).is_none(), exited);
+                Ok(())
+            })
+            .expect("Error running session");
+        assert!(syscalls > 0);
+        assert_eq!(exits.len(), 2);
+        assert_eq!(exits.last(), Some(&(pid, 0)));
+
+        // An error from the callback ends the run with the tracee stopped.
+        let pid = session
+            .spawn(
+                Command::new(&path).args(["sleep", "10"]),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let err = session
+            .run(|_, _, _| Err(io::Error::other("stop")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "stop");
+        ptrace::getsiginfo(pid).expect("Tracee isn't stopped");
+        session
+            .remove(pid)
+            .unwrap()
+            .child_mut()
+            .unwrap()
+            .kill()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_run_with() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        let pid = session
+            .spawn(&mut Command::new(&path), SpawnOptions::new())
+            .expect("Error spawning test process");
+        // Options added later are set on the tracee already there.
+        session
+            .add_ptrace_options(Options::PTRACE_O_TRACESYSGOOD)
+            .unwrap();
+        assert!(session.sysgood());
+        // Trace the first few system calls, then let the tracee run.
+        let mut syscalls = 0;
+        let mut exited = false;
+        session
+            .run_with(|session, tid, event| {
+                assert_eq!(tid, pid);
+                match event {
+                    Event::Syscall if syscalls < 10 => {
+                        syscalls += 1;
+                        Ok(Some(Resume::Syscall(None)))
+                    }
+                    Event::Exited(0) => {
+                        exited = true;
+                        assert!(session.is_empty());
+                        Ok(None)
+                    }
+                    _ => Ok(Some(Resume::Continue(None))),
+                }
+            })
+            .expect("Error running session");
+        assert_eq!(syscalls, 10);
+        assert!(exited);
+
+        // A tracee can be detached, which removes it from the session.
+        let pid = session
+            .spawn(&mut Command::new(&path), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut tracee = None;
+        session
+            .run_with(|session, tid, _| {
+                tracee = session.get(tid).map(|t| t.pid());
+                Ok(Some(Resume::Detach(None)))
+            })
+            .expect("Error running session");
+        assert_eq!(tracee, Some(pid));
+        assert!(session.is_empty());
+        let status = waitpid(pid, None).unwrap();
+        assert_eq!(status, WaitStatus::Exited(pid, 0));
+    }
+
+    #[test]
+    #[cfg(feature = "hooks")]
+    fn test_hook() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let seen = Rc::new(RefCell::new(vec![]));
+        let mut session = TraceSession::new();
+        let hook_seen = seen.clone();
+        session
+            .trace_syscalls()
+            .set_hook(move |activity| hook_seen.borrow_mut().push(*activity));
+        let pid = session
+            .spawn(&mut Command::new(&path), SpawnOptions::new())
+            .expect("Error spawning test process");
+        session
+            .run(|_, _, _| Ok(()))
+            .expect("Error running session");
+        let err = session.resume(pid, None).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+
+        let seen = seen.borrow();
+        assert_eq!(seen[0], Activity::Added(pid));
+        let resumed = Activity::Resumed {
+            pid,
+            request: Request::PTRACE_SYSCALL,
+            signal: None,
+        };
+        assert_eq!(seen[1], resumed);
+        assert!(seen.contains(&Activity::Stopped(pid, Event::Syscall)));
+        assert_eq!(
+            seen[seen.len() - 2],
+            Activity::Stopped(pid, Event::Exited(0))
+        );
+        let failed = Activity::Failed {
+            pid,
+            request: Request::PTRACE_SYSCALL,
+            errno: Errno::ESRCH,
+        };
+        assert_eq!(seen[seen.len() - 1], failed);
+    }
+
+    /// Check that each tracee in `activities` was only resumed through the
+    /// session, with every stop followed by a resume until it exited.
+    #[cfg(feature = "hooks")]
+    fn assert_resumed_by_session(activities: &[Activity]) {
+        let mut stopped = HashSet::new();
+        for activity in activities {
+            match *activity {
+                Activity::Added(pid) => assert!(stopped.insert(pid)),
+                Activity::Stopped(pid, Event::Exited(_) | Event::Signaled(..)) => {
+                    stopped.remove(&pid);
+                }
+                Activity::Stopped(pid, event) => {
+                    assert!(
+                        stopped.insert(pid),
+                        "{} resumed unseen before {:?}",
+                        pid,
+                        event
+                    )
+                }
+                Activity::Resumed { pid, .. } => assert!(stopped.remove(&pid)),
+                _ => {}
+            }
+        }
+        assert!(stopped.is_empty(), "{:?} left stopped", stopped);
+    }
+
+    #[test]
+    #[cfg(feature = "hooks")]
+    fn test_hook_failed() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let seen = Rc::new(RefCell::new(vec![]));
+        let mut session = TraceSession::new();
+        let hook_seen = seen.clone();
+        session.set_hook(move |activity| hook_seen.borrow_mut().push(*activity));
+        let pid = session
+            .spawn(&mut Command::new(&path), SpawnOptions::new())
+            .expect("Error spawning test process");
+        // The kernel refuses options it doesn't know.
+        let options = unsafe { Options::from_bits_unchecked(1 << 30) };
+        let e = session.add_ptrace_options(options).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+        let failed = Activity::Failed {
+            pid,
+            request: Request::PTRACE_SETOPTIONS,
+            errno: Errno::EINVAL,
+        };
+        assert_eq!(seen.borrow().last(), Some(&failed));
+        session.resume_as(pid, Resume::Kill).unwrap();
+        assert_eq!(
+            session.wait_any().unwrap(),
+            (pid, Event::Signaled(Signal::SIGKILL, false))
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "hooks", target_arch = "x86_64"))]
+    fn test_hook_tracker_resumes() {
+        use crate::{HeapTracer, LibraryTracer, SamplingProfiler, SyscallProfiler};
+        use std::cell::RefCell;
+        use std::process::Stdio;
+        use std::rc::Rc;
+        use std::time::Duration;
+
+        type Run = fn(&mut TraceSession) -> io::Result<()>;
+        let trackers: [(&[&str], Run); 4] = [
+            (&["alloc"], |session| HeapTracer::new().run(session)),
+            (&["libcalls"], |session| LibraryTracer::new().run(session)),
+            (&["spin", "100"], |session| {
+                SyscallProfiler::new()
+                    .sampling_interval(Duration::from_millis(10))
+                    .run(session)
+            }),
+            (&["spin", "100"], |session| {
+                SamplingProfiler::new(100).run(session)
+            }),
+        ];
+        let path = test_process_path().expect("Failed to get test process path");
+        for (args, run) in trackers {
+            let seen = Rc::new(RefCell::new(vec![]));
+            let mut session = TraceSession::new();
+            let hook_seen = seen.clone();
+            session.set_hook(move |activity| hook_seen.borrow_mut().push(*activity));
+            session
+                .spawn(
+                    Command::new(&path).args(args).stdout(Stdio::null()),
+                    SpawnOptions::new(),
+                )
+                .expect("Error spawning test process");
+            run(&mut session).expect("Error tracing");
+            let seen = seen.borrow();
+            assert!(seen.len() > 3, "{:?} saw {:?}", args, seen);
+            assert_resumed_by_session(&seen);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "backend")]
+    fn test_backend() {
+        use crate::NixBackend;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// Counts the waits and resumes, and makes them with nix.
+        struct Counting(Rc<RefCell<(usize, usize, usize)>>);
+
+        impl Backend for Counting {
+            fn waitpid(&mut self, pid: Option<Pid>, flags: WaitPidFlag) -> nix::Result<WaitStatus> {
+                self.0.borrow_mut().0 += 1;
+                NixBackend.waitpid(pid, flags)
+            }
+
+            fn resume(
+                &mut self,
+                pid: Pid,
+                request: Request,
+                signal: Option<Signal>,
+            ) -> nix::Result<()> {
+                self.0.borrow_mut().1 += 1;
+                NixBackend.resume(pid, request, signal)
+            }
+
+            fn set_options(&mut self, pid: Pid, options: Options) -> nix::Result<()> {
+                NixBackend.set_options(pid, options)
+            }
+
+            fn event_message(&mut self, pid: Pid) -> nix::Result<libc::c_long> {
+                self.0.borrow_mut().2 += 1;
+                NixBackend.event_message(pid)
+            }
+        }
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let counts = Rc::new(RefCell::new((0, 0, 0)));
+        let mut session = TraceSession::new();
+        session.follow_forks().set_backend(Counting(counts.clone()));
+        session
+            .spawn(Command::new(&path).arg("fork"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut events = 0;
+        session
+            .run(|_, _, _| {
+                events += 1;
+                Ok(())
+            })
+            .expect("Error running session");
+        let (waits, resumes, messages) = *counts.borrow();
+        // Every event took a wait, and all but the exits a resume, as did
+        // the start of the run.
+        assert_eq!(waits, events);
+        assert_eq!(resumes, events - 2 + 1);
+        // The fork.
+        assert_eq!(messages, 1);
+    }
+
+    #[test]
+    fn test_shell() {
+        use crate::ProcessTree;
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let path = path.to_str().unwrap();
+        let command_line = format!("{} fork | {} exec exec", path, path);
+        let (mut session, pid) =
+            TraceSession::shell(&command_line, SpawnOptions::new()).expect("Error spawning shell");
+        let mut tree = ProcessTree::new();
+        tree.run(&mut session).expect("Error running shell");
+        let shell = tree.get(pid).expect("No shell in the tree");
+        assert_eq!(shell.parent, None);
+        let programs: Vec<_> = tree
+            .nodes()
+            .iter()
+            .flat_map(|node| &node.execs)
+            .filter(|exec| exec.path.as_deref() == Some(Path::new(path)))
+            .collect();
+        // Both sides of the pipe, and the two `exec`s on the right.
+        assert_eq!(programs.len(), 4, "{:#?}", tree.nodes());
+    }
+
+    #[test]
+    fn test_take_fair() {
+        let (a, b) = (Pid::from_raw(1), Pid::from_raw(2));
+        let mut session = TraceSession::new();
+        for pid in [a, a, a, b, a] {
+            let status = WaitStatus::Stopped(pid, Signal::SIGUSR1);
+            session.pending.push_back((status, Instant::now()));
+        }
+        let mut order = vec![];
+        while let Some((status, _)) = session.take_fair(2) {
+            order.push(status.pid().unwrap());
+        }
+        assert_eq!(order, [a, a, b, a, a]);
+    }
+
+    #[test]
+    fn test_fair_scheduling() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.fair_scheduling(1);
+        for _ in 0..4 {
+            let pid = session
+                .spawn(
+                    Command::new(&path)
+                        .arg("fork")
+                        .stdout(std::process::Stdio::null()),
+                    SpawnOptions::new(),
+                )
+                .expect("Error spawning test process");
+            ptrace::cont(pid, None).unwrap();
+        }
+        let mut exited = 0;
+        while !session.is_empty() {
+            let (pid, event) = session.wait_any().expect("Error waiting for session");
+            match event {
+                Event::Exited(0) => exited += 1,
+                Event::Signal(signal) => ptrace::cont(pid, signal).unwrap(),
+                e => panic!("Unexpected event: {:?}", e),
+            }
+        }
+        assert_eq!(exited, 4);
+    }
+}
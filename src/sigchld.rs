@@ -0,0 +1,174 @@
+use crate::nix_error;
+use nix::fcntl::OFlag;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, SigmaskHow, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::unistd::{self, pipe2};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// The write end of the self-pipe, or -1 if there isn't one, since signal
+/// handlers are global.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// A non-blocking file descriptor that becomes readable when a `SIGCHLD` arrives.
+///
+/// The kernel sends the tracer a `SIGCHLD` whenever a tracee changes state,
+/// including for ptrace stops, so registering this descriptor with `epoll`
+/// or an event loop such as `mio` (via `mio::unix::SourceFd`) lets you drive
+/// tracing from readiness notifications, only calling
+/// [`Tracee::try_wait`] once something has happened.
+///
+/// `SIGCHLD` signals coalesce, so after each wakeup you should call
+/// [`clear`] and then call `try_wait` until it stops returning statuses.
+///
+/// Creating a `SigchldFd` with [`new`] blocks `SIGCHLD` in the calling
+/// thread. For the signal to reliably reach the descriptor it must be
+/// blocked in every thread of the process, so create it before spawning
+/// other threads. If threads that don't block `SIGCHLD` already exist, use
+/// [`self_pipe`] instead.
+///
+/// [`Tracee::try_wait`]: struct.Tracee.html#method.try_wait
+/// [`clear`]: #method.clear
+/// [`new`]: #method.new
+/// [`self_pipe`]: #method.self_pipe
+#[derive(Debug)]
+pub struct SigchldFd {
+    source: Source,
+}
+
+#[derive(Debug)]
+enum Source {
+    /// The signalfd, and the calling thread's signal mask before `SIGCHLD`
+    /// was blocked.
+    Signal(SignalFd, SigSet),
+    /// The read end of the self-pipe, and the `SIGCHLD` action it replaced.
+    Pipe(RawFd, SigAction),
+}
+
+impl SigchldFd {
+    /// Block `SIGCHLD` in the calling thread and create a signalfd for it.
+    ///
+    /// The thread's signal mask is restored when the `SigchldFd` is
+    /// dropped, which should be on the same thread.
+    pub fn new() -> io::Result<SigchldFd> {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGCHLD);
+        let mut previous = SigSet::empty();
+        signal::pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&mask), Some(&mut previous))
+            .map_err(nix_error)?;
+        match SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC) {
+            Ok(fd) => Ok(SigchldFd {
+                source: Source::Signal(fd, previous),
+            }),
+            Err(e) => {
+                let _ = signal::pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(&previous), None);
+                Err(nix_error(e))
+            }
+        }
+    }
+
+    /// Install a `SIGCHLD` handler that writes to a pipe, and return its read
+    /// end.
+    ///
+    /// Unlike a signalfd this works whichever thread the signal is delivered
+    /// to, so `SIGCHLD` must *not* be blocked in all of them. The handler
+    /// uses `SA_RESTART`, so waits in progress aren't interrupted, but
+    /// `poll` and `epoll_wait` fail with `EINTR` when it runs, as they do for
+    /// any handled signal. The previous handler is restored when the
+    /// `SigchldFd` is dropped.
+    ///
+    /// Fails with `ErrorKind::AlreadyExists` if another self-pipe exists.
+    pub fn self_pipe() -> io::Result<SigchldFd> {
+        let (read, write) = pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC).map_err(nix_error)?;
+        if PIPE
+            .compare_exchange(-1, write, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            let _ = unistd::close(read);
+            let _ = unistd::close(write);
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "A SIGCHLD self-pipe already exists",
+            ));
+        }
+        let action = SigAction::new(
+            SigHandler::Handler(notify),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        match unsafe { signal::sigaction(Signal::SIGCHLD, &action) } {
+            Ok(previous) => Ok(SigchldFd {
+                source: Source::Pipe(read, previous),
+            }),
+            Err(e) => {
+                PIPE.store(-1, Ordering::SeqCst);
+                let _ = unistd::close(read);
+                let _ = unistd::close(write);
+                Err(nix_error(e))
+            }
+        }
+    }
+
+    /// Consume all pending `SIGCHLD` notifications, returning how many there were.
+    ///
+    /// This does not block.
+    pub fn clear(&mut self) -> io::Result<usize> {
+        let mut count = 0;
+        match &mut self.source {
+            Source::Signal(fd, _) => {
+                while fd.read_signal().map_err(nix_error)?.is_some() {
+                    count += 1;
+                }
+            }
+            Source::Pipe(fd, _) => {
+                let mut buf = [0; 64];
+                loop {
+                    match unistd::read(*fd, &mut buf) {
+                        Ok(0) | Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => break,
+                        Ok(n) => count += n,
+                        Err(e) => return Err(nix_error(e)),
+                    }
+                }
+            }
+        }
+        Ok(count)
+    }
+}
+
+impl AsRawFd for SigchldFd {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.source {
+            Source::Signal(fd, _) => fd.as_raw_fd(),
+            Source::Pipe(fd, _) => *fd,
+        }
+    }
+}
+
+impl Drop for SigchldFd {
+    fn drop(&mut self) {
+        match &self.source {
+            Source::Signal(_, previous) => {
+                let _ = signal::pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(previous), None);
+            }
+            Source::Pipe(read, previous) => {
+                let _ = unsafe { signal::sigaction(Signal::SIGCHLD, previous) };
+                let write = PIPE.swap(-1, Ordering::SeqCst);
+                let _ = unistd::close(write);
+                let _ = unistd::close(*read);
+            }
+        }
+    }
+}
+
+/// The signal handler, which only makes async-signal-safe calls.
+extern "C" fn notify(_: libc::c_int) {
+    let fd = PIPE.load(Ordering::SeqCst);
+    if fd < 0 {
+        return;
+    }
+    let errno = unsafe { *libc::__errno_location() };
+    // If the pipe is full a wakeup is already pending.
+    unsafe { libc::write(fd, b"\0".as_ptr() as *const libc::c_void, 1) };
+    unsafe { *libc::__errno_location() = errno };
+}
@@ -0,0 +1,337 @@
+//! The signal handlers tracees install, and when they run.
+
+use crate::accounting::read_tgid;
+use crate::{Event, SyscallInfo, TraceSession, Tracee};
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// `SA_RESETHAND`, which resets the action to the default once the handler
+/// is entered.
+const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// A handler installed with `sigaction` or `rt_sigaction`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SignalHandler {
+    /// The address of the handler function.
+    pub address: u64,
+    /// The `SA_*` flags it was installed with.
+    pub flags: u64,
+    /// The signals blocked while it runs, as a bit mask with signal `n` at
+    /// bit `n - 1`.
+    pub mask: u64,
+}
+
+/// Whether a [`HandlerEvent`] is a handler starting or finishing.
+///
+/// [`HandlerEvent`]: struct.HandlerEvent.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HandlerEventKind {
+    /// The signal was delivered, and the handler is about to run.
+    Entered,
+    /// The handler returned, through `rt_sigreturn`.
+    Returned,
+}
+
+/// A signal handler starting or finishing, recorded by [`SignalHandlers`].
+///
+/// [`SignalHandlers`]: struct.SignalHandlers.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HandlerEvent {
+    /// When it happened, relative to when tracking started.
+    pub time: Duration,
+    /// The process the thread belongs to.
+    pub pid: Pid,
+    /// The thread that handled the signal.
+    pub tid: Pid,
+    /// The signal being handled.
+    pub signal: Signal,
+    /// The address of the handler.
+    pub handler: u64,
+    /// Whether the handler was entered or returned.
+    pub kind: HandlerEventKind,
+}
+
+/// Tracks the signal handler table of each tracee process, and reports each
+/// time a handler is entered and returns.
+///
+/// Feed every stop to [`record`], or let [`run`] drive a whole
+/// [`TraceSession`]. Handlers are learned from the `rt_sigaction` calls
+/// that succeed, so the session must trace system calls, and handlers
+/// installed before tracing started aren't known. Handlers are entered at
+/// the signal-delivery-stop of a signal with a known handler, which assumes
+/// the tracer delivers the signal, as `run` does, and return when the
+/// thread calls `rt_sigreturn`. Handlers that leave with `longjmp` instead
+/// leave their entry unmatched.
+///
+/// Handlers are copied to the children of `fork`, and reset by `exec`, as
+/// the kernel does. Real-time signals, which `Signal` can't represent, are
+/// left out, and only 64-bit tracees are understood.
+///
+/// [`record`]: #method.record
+/// [`run`]: #method.run
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug)]
+pub struct SignalHandlers {
+    start: Instant,
+    /// The signal and new handler of each thread's `rt_sigaction` in
+    /// progress, with `None` for the default action or `SIG_IGN`.
+    in_flight: HashMap<Pid, (Signal, Option<SignalHandler>)>,
+    tgids: HashMap<Pid, Pid>,
+    handlers: HashMap<Pid, HashMap<Signal, SignalHandler>>,
+    /// The handlers each thread is running, innermost last.
+    running: HashMap<Pid, Vec<(Signal, u64)>>,
+    events: Vec<HandlerEvent>,
+}
+
+impl Default for SignalHandlers {
+    fn default() -> SignalHandlers {
+        SignalHandlers {
+            start: Instant::now(),
+            in_flight: HashMap::new(),
+            tgids: HashMap::new(),
+            handlers: HashMap::new(),
+            running: HashMap::new(),
+            events: vec![],
+        }
+    }
+}
+
+impl SignalHandlers {
+    /// Create a tracker that knows no handlers, with times measured from now.
+    pub fn new() -> SignalHandlers {
+        SignalHandlers::default()
+    }
+
+    /// Update the handler tables for `event`, reported by `tracee`, and
+    /// return the handler entry or return it is, if any.
+    ///
+    /// Call this for every stop, before resuming the tracee.
+    pub fn record(&mut self, tracee: &Tracee, event: Event) -> io::Result<Option<HandlerEvent>> {
+        let tid = tracee.pid();
+        match event {
+            Event::Syscall => return self.record_syscall(tracee),
+            Event::Signal(signal) => {
+                let pid = self.tgid(tid);
+                let handler = match self.handlers.get_mut(&pid) {
+                    Some(table) => match table.get(&signal) {
+                        Some(&handler) if handler.flags & SA_RESETHAND != 0 => {
+                            table.remove(&signal);
+                            handler
+                        }
+                        Some(&handler) => handler,
+                        None => return Ok(None),
+                    },
+                    None => return Ok(None),
+                };
+                self.running
+                    .entry(tid)
+                    .or_default()
+                    .push((signal, handler.address));
+                return Ok(Some(self.push(
+                    pid,
+                    tid,
+                    signal,
+                    handler.address,
+                    HandlerEventKind::Entered,
+                )));
+            }
+            Event::Fork(child) | Event::Vfork(child) | Event::Clone(child) => {
+                let pid = self.tgid(tid);
+                let child_pid = self.tgid(child);
+                if child_pid != pid {
+                    let table = self.handlers.get(&pid).cloned().unwrap_or_default();
+                    self.handlers.insert(child_pid, table);
+                }
+            }
+            Event::Exec(former) => {
+                self.forget(former);
+                self.running.remove(&tid);
+                self.in_flight.remove(&tid);
+                let pid = self.tgid(tid);
+                self.handlers.remove(&pid);
+            }
+            Event::Exited(_) | Event::Signaled(..) => self.forget(tid),
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Forget any system call or handler in progress for thread `pid`, for
+    /// example because it exited.
+    pub fn forget(&mut self, pid: Pid) {
+        self.in_flight.remove(&pid);
+        self.running.remove(&pid);
+        self.tgids.remove(&pid);
+    }
+
+    /// Track the signal handlers of the tracees in `session` with
+    /// [`TraceSession::run`] until they have all exited.
+    ///
+    /// The session should trace system calls, with [`trace_syscalls`].
+    ///
+    /// [`TraceSession::run`]: struct.TraceSession.html#method.run
+    /// [`trace_syscalls`]: struct.TraceSession.html#method.trace_syscalls
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        session.run(|session, pid, event| {
+            match session.get(pid) {
+                Some(tracee) => {
+                    self.record(tracee, event)?;
+                }
+                None => self.forget(pid),
+            }
+            Ok(())
+        })
+    }
+
+    /// The handlers process `pid` has installed, by signal, or had when it
+    /// exited.
+    pub fn handlers(&self, pid: Pid) -> Option<&HashMap<Signal, SignalHandler>> {
+        self.handlers.get(&pid)
+    }
+
+    /// Every handler entry and return recorded so far, in order.
+    pub fn events(&self) -> &[HandlerEvent] {
+        &self.events
+    }
+
+    fn record_syscall(&mut self, tracee: &Tracee) -> io::Result<Option<HandlerEvent>> {
+        let tid = tracee.pid();
+        match tracee.syscall_info()? {
+            SyscallInfo::Entry { number, args } => {
+                self.in_flight.remove(&tid);
+                match number as i64 {
+                    libc::SYS_rt_sigaction => {
+                        let signal = match Signal::try_from(args[0] as i32) {
+                            Ok(signal) => signal,
+                            Err(_) => return Ok(None),
+                        };
+                        // Without a new action, it only reads the old one.
+                        if args[1] != 0 {
+                            // struct kernel_sigaction: the handler, the flags,
+                            // the restorer and the mask.
+                            let [address, flags, _, mask] =
+                                tracee.read_value::<[u64; 4]>(args[1])?;
+                            let handler = match address as libc::sighandler_t {
+                                libc::SIG_DFL | libc::SIG_IGN => None,
+                                _ => Some(SignalHandler {
+                                    address,
+                                    flags,
+                                    mask,
+                                }),
+                            };
+                            self.in_flight.insert(tid, (signal, handler));
+                        }
+                    }
+                    libc::SYS_rt_sigreturn => {
+                        if let Some((signal, handler)) =
+                            self.running.get_mut(&tid).and_then(|running| running.pop())
+                        {
+                            let pid = self.tgid(tid);
+                            return Ok(Some(self.push(
+                                pid,
+                                tid,
+                                signal,
+                                handler,
+                                HandlerEventKind::Returned,
+                            )));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            SyscallInfo::Exit { is_error, .. } => {
+                if let Some((signal, handler)) = self.in_flight.remove(&tid) {
+                    if !is_error {
+                        let pid = self.tgid(tid);
+                        let table = self.handlers.entry(pid).or_default();
+                        match handler {
+                            Some(handler) => table.insert(signal, handler),
+                            None => table.remove(&signal),
+                        };
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn push(
+        &mut self,
+        pid: Pid,
+        tid: Pid,
+        signal: Signal,
+        handler: u64,
+        kind: HandlerEventKind,
+    ) -> HandlerEvent {
+        let event = HandlerEvent {
+            time: self.start.elapsed(),
+            pid,
+            tid,
+            signal,
+            handler,
+            kind,
+        };
+        self.events.push(event);
+        event
+    }
+
+    /// The process that thread `tid` belongs to.
+    fn tgid(&mut self, tid: Pid) -> Pid {
+        *self
+            .tgids
+            .entry(tid)
+            .or_insert_with(|| read_tgid(tid).unwrap_or(tid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use nix::sys::ptrace;
+    use std::process::Command;
+
+    #[test]
+    fn test_signal_handlers() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        let pid = session
+            .spawn(Command::new(&path).arg("handler"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut handlers = SignalHandlers::new();
+        ptrace::syscall(pid, None).unwrap();
+        loop {
+            let (_, event) = session.wait_for(pid).unwrap();
+            let signal = match event {
+                Event::Exited(code) => {
+                    assert_eq!(code, 0);
+                    break;
+                }
+                Event::Signal(signal) => Some(signal),
+                _ => None,
+            };
+            handlers.record(session.get(pid).unwrap(), event).unwrap();
+            ptrace::syscall(pid, signal).unwrap();
+        }
+        // Rust's runtime handles stack overflows.
+        let table = handlers.handlers(pid).expect("No handlers recorded");
+        assert!(table.contains_key(&Signal::SIGSEGV));
+        let usr1 = table[&Signal::SIGUSR1];
+        let events = handlers.events();
+        assert_eq!(events.len(), 2, "{:?}", events);
+        assert_eq!(events[0].kind, HandlerEventKind::Entered);
+        assert_eq!(events[1].kind, HandlerEventKind::Returned);
+        for event in events {
+            assert_eq!((event.pid, event.tid), (pid, pid));
+            assert_eq!(event.signal, Signal::SIGUSR1);
+            assert_eq!(event.handler, usr1.address);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Spawning a traced child with `fork` and `execve` directly, without a
+//! `std::process::Command`.
+
+use crate::options::mark_cloexec;
+use crate::{wait_error, Tracee};
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+/// Spawn the executable at `path` with exactly the arguments `argv` and the
+/// environment `envp`, stopped at its first instruction as with
+/// [`spawn_tracee`].
+///
+/// Unlike `Command`, nothing is looked up in `PATH`, and nothing is set up
+/// in the child beyond what is asked for: the parent forks with `fork(2)`,
+/// never `vfork`, and the child calls `PTRACE_TRACEME`, sets up its
+/// descriptors and calls `execve(2)`, without allocating. The only other
+/// changes are that the child's signal mask is emptied, and `SIGPIPE` and
+/// `SIGCHLD` are reset to their default actions, so that it doesn't start
+/// with signals that the tracer blocks, such as for a [`SigchldFd`], or
+/// that Rust programs ignore. Each `(child_fd, fd)` in `fds` makes this process's
+/// `fd` the child's `child_fd`, and every other descriptor is closed in the
+/// child, including stdio, so the child's descriptor table is exactly
+/// `fds`.
+///
+/// The child isn't a `std::process::Child`, so the returned `Tracee` has
+/// no [`child`], and must be reaped by waiting for its pid. An `execve`
+/// failure is returned as its error, once the child has exited.
+///
+/// [`spawn_tracee`]: trait.CommandPtraceSpawn.html#tymethod.spawn_tracee
+/// [`SigchldFd`]: struct.SigchldFd.html
+/// [`child`]: struct.Tracee.html#method.child
+pub fn spawn_raw(
+    path: &CStr,
+    argv: &[&CStr],
+    envp: &[&CStr],
+    fds: &[(RawFd, RawFd)],
+) -> io::Result<Tracee> {
+    if fds.iter().any(|&(child_fd, fd)| child_fd < 0 || fd < 0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "descriptors must be 0 or more",
+        ));
+    }
+    let pointers = |strings: &[&CStr]| {
+        let mut pointers: Vec<_> = strings.iter().map(|s| s.as_ptr()).collect();
+        pointers.push(ptr::null());
+        pointers
+    };
+    let argv = pointers(argv);
+    let envp = pointers(envp);
+    // Descriptors are first moved above all of these, so that no mapping
+    // overwrites the source of another.
+    let above = fds
+        .iter()
+        .map(|&(child_fd, fd)| child_fd.max(fd))
+        .max()
+        .map_or(0, |fd| fd + 1);
+    let mut targets: Vec<RawFd> = fds.iter().map(|&(child_fd, _)| child_fd).collect();
+    targets.sort_unstable();
+    targets.dedup();
+    // The child writes its errno here if it fails before `execve` succeeds.
+    let mut errors = [0; 2];
+    if unsafe { libc::pipe2(errors.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let [error_read, error_write] = errors;
+    // Room for the child to record where it moved each descriptor, since
+    // it can't allocate.
+    let mut moved = vec![0; fds.len()];
+    let pid = unsafe { libc::fork() };
+    if pid == 0 {
+        unsafe {
+            child(
+                path,
+                &argv,
+                &envp,
+                fds,
+                &mut moved,
+                &targets,
+                above,
+                error_write,
+            )
+        }
+    }
+    let fork_error = io::Error::last_os_error();
+    unsafe { libc::close(error_write) };
+    if pid < 0 {
+        unsafe { libc::close(error_read) };
+        return Err(fork_error);
+    }
+    let pid = Pid::from_raw(pid);
+    let mut errno = [0u8; 4];
+    let read = loop {
+        let n = unsafe { libc::read(error_read, errno.as_mut_ptr() as *mut libc::c_void, 4) };
+        if n >= 0 || Errno::last() != Errno::EINTR {
+            break n;
+        }
+    };
+    unsafe { libc::close(error_read) };
+    // The child stops at the `exec`, before its error pipe is closed, so it
+    // has either failed or stopped by the time the read returns.
+    let status = waitpid(pid, None).map_err(wait_error)?;
+    if read == 4 {
+        if let WaitStatus::Stopped(..) = status {
+            let _ = nix::sys::signal::kill(pid, Signal::SIGKILL);
+            let _ = waitpid(pid, None);
+        }
+        return Err(io::Error::from_raw_os_error(i32::from_ne_bytes(errno)));
+    }
+    match status {
+        WaitStatus::Stopped(_, Signal::SIGTRAP) => Ok(Tracee::attached(pid, status)),
+        _ => Err(io::Error::other("Child state not correct")),
+    }
+}
+
+/// Set up the child and `exec`, or write the errno to `error_write` and
+/// exit if anything fails.
+///
+/// This runs between `fork` and `exec`, so it only makes async-signal-safe
+/// calls.
+#[allow(clippy::too_many_arguments)]
+unsafe fn child(
+    path: &CStr,
+    argv: &[*const libc::c_char],
+    envp: &[*const libc::c_char],
+    fds: &[(RawFd, RawFd)],
+    moved: &mut [RawFd],
+    targets: &[RawFd],
+    above: RawFd,
+    error_write: RawFd,
+) -> ! {
+    let fail = |fd: RawFd, errno: i32| -> ! {
+        libc::write(
+            fd,
+            &errno as *const i32 as *const libc::c_void,
+            std::mem::size_of::<i32>(),
+        );
+        libc::_exit(127)
+    };
+    if libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0) < 0 {
+        fail(error_write, Errno::last() as i32);
+    }
+    libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    libc::signal(libc::SIGCHLD, libc::SIG_DFL);
+    let mut mask = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+    libc::sigemptyset(mask.as_mut_ptr());
+    libc::pthread_sigmask(libc::SIG_SETMASK, mask.as_ptr(), ptr::null_mut());
+    // Keep the error pipe out of the way of the mappings.
+    let error_fd = libc::fcntl(error_write, libc::F_DUPFD_CLOEXEC, above);
+    if error_fd < 0 {
+        fail(error_write, Errno::last() as i32);
+    }
+    for (&(_, fd), moved) in fds.iter().zip(moved.iter_mut()) {
+        *moved = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, above);
+        if *moved < 0 {
+            fail(error_fd, Errno::last() as i32);
+        }
+    }
+    for (&(child_fd, _), &moved) in fds.iter().zip(moved.iter()) {
+        // `dup2` leaves the new descriptor open across `exec`.
+        if libc::dup2(moved, child_fd) < 0 {
+            fail(error_fd, Errno::last() as i32);
+        }
+    }
+    let mut from = 0;
+    for &fd in targets {
+        let fd = fd as libc::c_uint;
+        if fd > from {
+            if let Err(e) = mark_cloexec(from, fd - 1) {
+                fail(error_fd, e.raw_os_error().unwrap_or(libc::EIO));
+            }
+        }
+        from = fd + 1;
+    }
+    if let Err(e) = mark_cloexec(from, libc::c_uint::MAX) {
+        fail(error_fd, e.raw_os_error().unwrap_or(libc::EIO));
+    }
+    libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+    fail(error_fd, Errno::last() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::FromRawFd;
+
+    /// Run the test process with `arg`, its stdout and descriptor 7 on a
+    /// pipe as its only descriptors, and return its output.
+    fn run(path: &CStr, arg: &str) -> io::Result<Vec<u8>> {
+        let mut pipe = [0; 2];
+        assert_eq!(
+            unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) },
+            0
+        );
+        let (mut read, write) = unsafe { (File::from_raw_fd(pipe[0]), File::from_raw_fd(pipe[1])) };
+        let arg = CString::new(arg).unwrap();
+        let env = CString::new("SPAWN_PTRACE_RAW=1").unwrap();
+        let tracee = spawn_raw(path, &[path, &arg], &[&env], &[(1, pipe[1]), (7, pipe[1])]);
+        drop(write);
+        let tracee = tracee?;
+        let pid = tracee.pid();
+        let environ = std::fs::read(format!("/proc/{}/environ", pid)).unwrap();
+        assert_eq!(environ, b"SPAWN_PTRACE_RAW=1\0");
+        nix::sys::ptrace::cont(pid, None).unwrap();
+        assert_eq!(waitpid(pid, None).unwrap(), WaitStatus::Exited(pid, 0));
+        let mut output = vec![];
+        read.read_to_end(&mut output).unwrap();
+        Ok(output)
+    }
+
+    #[test]
+    fn test_spawn_raw() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(run(&path, "hello").unwrap(), b"hello\n");
+        let fds = String::from_utf8(run(&path, "fds").unwrap()).unwrap();
+        let mut fds: Vec<i32> = fds.lines().map(|fd| fd.parse().unwrap()).collect();
+        fds.sort_unstable();
+        // Rust programs open `/dev/null` on closed stdio descriptors, and the
+        // list is read through 3.
+        assert_eq!(fds, [0, 1, 2, 3, 7]);
+        let missing = CString::new("/nonexistent").unwrap();
+        let err = run(&missing, "hello").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_spawn_raw_signals() {
+        use nix::sys::signal::{pthread_sigmask, SigSet, SigmaskHow};
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGCHLD);
+        let mut previous = SigSet::empty();
+        pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&mask), Some(&mut previous)).unwrap();
+        let tracee = spawn_raw(&path, &[&path], &[], &[]);
+        pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(&previous), None).unwrap();
+        let pid = tracee.expect("Error spawning test process").pid();
+        let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+        let field = |name: &str| {
+            let line = status.lines().find(|l| l.starts_with(name)).unwrap();
+            u64::from_str_radix(line[name.len()..].trim(), 16).unwrap()
+        };
+        assert_eq!(field("SigBlk:"), 0);
+        let defaults = 1 << (libc::SIGPIPE - 1) | 1 << (libc::SIGCHLD - 1);
+        assert_eq!(field("SigIgn:") & defaults, 0);
+        nix::sys::signal::kill(pid, Signal::SIGKILL).unwrap();
+        assert!(matches!(
+            waitpid(pid, None).unwrap(),
+            WaitStatus::Signaled(_, Signal::SIGKILL, _)
+        ));
+    }
+}
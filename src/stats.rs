@@ -0,0 +1,181 @@
+//! Counters of what tracing a process took.
+
+use crate::{syscall, Event, SyscallInfo};
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::collections::HashMap;
+
+/// The `si_code` of a breakpoint's `SIGTRAP`: `int3` reports `SI_KERNEL`,
+/// `brk` `TRAP_BRKPT`, and debug registers `TRAP_HWBKPT`.
+const TRAP_BRKPT: i32 = 1;
+const TRAP_HWBKPT: i32 = 4;
+
+/// Counts of the stops of a tracee or a whole [`TraceSession`], of what
+/// they were for, and of the tracee memory read.
+///
+/// Stops are only counted once [`TraceSession::collect_stats`] is set, and
+/// include those the session's filter leaves out, but not the ones the
+/// session handles itself, such as the stops of restarted system calls.
+/// Memory reads are always counted, but only those made through
+/// `Tracee::read_memory` and the methods built on it.
+///
+/// [`TraceSession`]: struct.TraceSession.html
+/// [`TraceSession::collect_stats`]: struct.TraceSession.html#method.collect_stats
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Stops for signals about to be delivered, including the `SIGTRAP`s
+    /// of breakpoints and single steps.
+    pub signal_stops: u64,
+    /// System call entry and exit stops.
+    pub syscall_stops: u64,
+    /// Stops for ptrace events, such as forks and `exec`s, and the initial
+    /// stops of tracees attached automatically.
+    pub event_stops: u64,
+    /// Group-stops of tracees attached with `PTRACE_SEIZE`.
+    pub group_stops: u64,
+    /// Stops for `PTRACE_INTERRUPT`.
+    pub interrupt_stops: u64,
+    /// System call entries, by number. These need `PTRACE_GET_SYSCALL_INFO`,
+    /// from Linux 5.3.
+    pub syscalls: HashMap<u64, u64>,
+    /// Signal stops, by signal.
+    pub signals: HashMap<Signal, u64>,
+    /// `SIGTRAP`s of software and hardware breakpoints.
+    pub breakpoint_hits: u64,
+    /// Bytes of tracee memory read.
+    pub memory_read: u64,
+}
+
+impl Stats {
+    /// The number of stops of every kind.
+    pub fn stops(&self) -> u64 {
+        self.signal_stops
+            + self.syscall_stops
+            + self.event_stops
+            + self.group_stops
+            + self.interrupt_stops
+    }
+
+    /// Count `event`, reported by thread `pid`, which is still stopped.
+    pub(crate) fn record(&mut self, pid: Pid, event: Event) {
+        match event {
+            Event::Exited(..) | Event::Signaled(..) => {}
+            Event::Signal(signal) => {
+                self.signal_stops += 1;
+                *self.signals.entry(signal).or_insert(0) += 1;
+                if signal == Signal::SIGTRAP && is_breakpoint(pid) {
+                    self.breakpoint_hits += 1;
+                }
+            }
+            Event::Syscall | Event::AntiDebug(_) => {
+                self.syscall_stops += 1;
+                if let Ok(SyscallInfo::Entry { number, .. }) = syscall::syscall_info(pid) {
+                    *self.syscalls.entry(number).or_insert(0) += 1;
+                }
+            }
+            Event::GroupStop(_) => self.group_stops += 1,
+            Event::InterruptStop => self.interrupt_stops += 1,
+            _ => self.event_stops += 1,
+        }
+    }
+
+    /// Add the counts of `other` to these.
+    pub(crate) fn add(&mut self, other: &Stats) {
+        self.signal_stops += other.signal_stops;
+        self.syscall_stops += other.syscall_stops;
+        self.event_stops += other.event_stops;
+        self.group_stops += other.group_stops;
+        self.interrupt_stops += other.interrupt_stops;
+        for (&number, &count) in &other.syscalls {
+            *self.syscalls.entry(number).or_insert(0) += count;
+        }
+        for (&signal, &count) in &other.signals {
+            *self.signals.entry(signal).or_insert(0) += count;
+        }
+        self.breakpoint_hits += other.breakpoint_hits;
+        self.memory_read += other.memory_read;
+    }
+}
+
+/// Whether `pid`, stopped with `SIGTRAP`, hit a breakpoint.
+fn is_breakpoint(pid: Pid) -> bool {
+    match ptrace::getsiginfo(pid) {
+        Ok(info) => matches!(info.si_code, libc::SI_KERNEL | TRAP_BRKPT | TRAP_HWBKPT),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_arch = "x86_64")]
+    use crate::tests::test_process_path;
+    #[cfg(target_arch = "x86_64")]
+    use crate::{Breakpoints, SpawnOptions, TraceSession};
+    #[cfg(target_arch = "x86_64")]
+    use std::process::Command;
+
+    #[test]
+    fn test_stats_add() {
+        let mut stats = Stats {
+            signal_stops: 1,
+            breakpoint_hits: 1,
+            ..Stats::default()
+        };
+        stats.signals.insert(Signal::SIGTRAP, 1);
+        let mut other = Stats {
+            syscall_stops: 2,
+            memory_read: 8,
+            ..Stats::default()
+        };
+        other.syscalls.insert(39, 1);
+        other.signals.insert(Signal::SIGTRAP, 2);
+        stats.add(&other);
+        assert_eq!(stats.stops(), 3);
+        assert_eq!(stats.signals[&Signal::SIGTRAP], 3);
+        assert_eq!(stats.syscalls[&39], 1);
+        assert_eq!(stats.memory_read, 8);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_session_stats() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.collect_stats().trace_syscalls();
+        let pid = session
+            .spawn(Command::new(&path).arg("calls"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut breakpoints = Breakpoints::new();
+        breakpoints
+            .insert_symbol(pid, "spawn_ptrace_leaf")
+            .expect("Error inserting breakpoint");
+        ptrace::syscall(pid, None).unwrap();
+        loop {
+            let (_, event) = session.wait_for(pid).unwrap();
+            match event {
+                Event::Exited(code) => {
+                    assert_eq!(code, 0);
+                    break;
+                }
+                Event::Signal(Signal::SIGTRAP) => {
+                    assert!(breakpoints.hit(pid).unwrap().is_some());
+                    let tracee = session.get(pid).unwrap();
+                    let rip = tracee.registers().unwrap().rip;
+                    tracee.read_value::<u64>(rip).unwrap();
+                    breakpoints.step_over(&mut session, pid).unwrap();
+                }
+                _ => {}
+            }
+            ptrace::syscall(pid, None).unwrap();
+        }
+        let stats = session.stats();
+        assert_eq!(stats.breakpoint_hits, 1);
+        // The hit and the step after it.
+        assert_eq!(stats.signals[&Signal::SIGTRAP], 2);
+        assert_eq!(stats.syscalls[&(libc::SYS_exit_group as u64)], 1);
+        assert_eq!(stats.syscall_stops % 2, 1);
+        assert_eq!(stats.memory_read, 8);
+    }
+}
@@ -0,0 +1,187 @@
+#[cfg(target_arch = "x86_64")]
+use crate::fallback::{self, Fallback};
+#[cfg(target_arch = "x86_64")]
+use crate::nix_error;
+#[cfg(target_arch = "x86_64")]
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+use std::io;
+use std::mem;
+
+/// Details of the system call a tracee is stopped at.
+///
+/// This is read with `PTRACE_GET_SYSCALL_INFO`, which requires Linux 5.3 or
+/// newer. On older kernels it is decoded from the tracee's registers on
+/// x86-64, which can't tell seccomp stops from system call entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyscallInfo {
+    /// The tracee is stopped at entry to the given system call.
+    Entry {
+        /// The system call number.
+        number: u64,
+        /// The system call arguments.
+        args: [u64; 6],
+    },
+    /// The tracee is stopped at exit from a system call.
+    Exit {
+        /// The return value, or the negated errno if `is_error` is set.
+        value: i64,
+        /// Whether the system call failed.
+        is_error: bool,
+    },
+    /// The tracee is stopped at a `PTRACE_EVENT_SECCOMP` stop for the given system call.
+    Seccomp {
+        /// The system call number.
+        number: u64,
+        /// The system call arguments.
+        args: [u64; 6],
+        /// The `SECCOMP_RET_DATA` portion of the filter's return value.
+        ret_data: u32,
+    },
+    /// The tracee is not stopped at a system call.
+    None,
+}
+
+/// Kernel-internal errnos that a system call returns when it was
+/// interrupted by a signal and may be restarted, from
+/// `include/linux/errno.h`.
+const ERESTARTSYS: i64 = 512;
+const ERESTART_RESTARTBLOCK: i64 = 516;
+
+impl SyscallInfo {
+    /// Whether this is the exit of a system call that was interrupted by a
+    /// signal, with one of the kernel-internal errnos from `ERESTARTSYS` to
+    /// `ERESTART_RESTARTBLOCK`.
+    ///
+    /// This isn't the system call's real result: once the signal has been
+    /// handled, the kernel either restarts the system call, which then stops
+    /// at entry again, or makes it fail with `EINTR` without another stop.
+    pub fn is_restart(&self) -> bool {
+        match *self {
+            SyscallInfo::Exit { value, is_error } => {
+                is_error && (-ERESTART_RESTARTBLOCK..=-ERESTARTSYS).contains(&value)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Read the system call the stopped tracee `pid` is at.
+pub(crate) fn syscall_info(pid: Pid) -> io::Result<SyscallInfo> {
+    syscall_stop(pid).map(|(info, _)| info)
+}
+
+/// Read the system call the stopped tracee `pid` is at, along with its
+/// instruction and stack pointers.
+pub(crate) fn syscall_stop(pid: Pid) -> io::Result<(SyscallInfo, (u64, u64))> {
+    #[cfg(target_arch = "x86_64")]
+    if fallback::used(Fallback::SyscallRegisters) {
+        return syscall_from_registers(pid);
+    }
+    let mut info: libc::ptrace_syscall_info = unsafe { mem::zeroed() };
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GET_SYSCALL_INFO,
+            pid.as_raw(),
+            mem::size_of::<libc::ptrace_syscall_info>(),
+            &mut info as *mut libc::ptrace_syscall_info,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        #[cfg(target_arch = "x86_64")]
+        if e.raw_os_error().is_some_and(fallback::is_unknown) {
+            fallback::record(Fallback::SyscallRegisters);
+            return syscall_from_registers(pid);
+        }
+        return Err(e);
+    }
+    let (info_ip, info_sp) = (info.instruction_pointer, info.stack_pointer);
+    let info = unsafe {
+        match info.op {
+            libc::PTRACE_SYSCALL_INFO_ENTRY => SyscallInfo::Entry {
+                number: info.u.entry.nr,
+                args: info.u.entry.args,
+            },
+            libc::PTRACE_SYSCALL_INFO_EXIT => SyscallInfo::Exit {
+                value: info.u.exit.sval,
+                is_error: info.u.exit.is_error != 0,
+            },
+            libc::PTRACE_SYSCALL_INFO_SECCOMP => SyscallInfo::Seccomp {
+                number: info.u.seccomp.nr,
+                args: info.u.seccomp.args,
+                ret_data: info.u.seccomp.ret_data,
+            },
+            _ => SyscallInfo::None,
+        }
+    };
+    Ok((info, (info_ip, info_sp)))
+}
+
+/// Decode the system call the stopped tracee `pid` is at from its
+/// registers, for kernels without `PTRACE_GET_SYSCALL_INFO`.
+///
+/// The tracee is assumed to be at a syscall stop. The kernel sets `rax` to
+/// `-ENOSYS` before a system call's entry stop, so a system call that
+/// returns `ENOSYS` is taken for an entry too.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn syscall_from_registers(pid: Pid) -> io::Result<(SyscallInfo, (u64, u64))> {
+    let regs = ptrace::getregs(pid).map_err(nix_error)?;
+    let value = regs.rax as i64;
+    let info = if value == -(libc::ENOSYS as i64) {
+        SyscallInfo::Entry {
+            number: regs.orig_rax,
+            args: [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9],
+        }
+    } else {
+        SyscallInfo::Exit {
+            value,
+            is_error: (-4095..0).contains(&value),
+        }
+    };
+    Ok((info, (regs.rip, regs.rsp)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_restart() {
+        let exit = |value, is_error| SyscallInfo::Exit { value, is_error };
+        assert!(exit(-ERESTARTSYS, true).is_restart());
+        assert!(exit(-ERESTART_RESTARTBLOCK, true).is_restart());
+        assert!(!exit(-libc::EINTR as i64, true).is_restart());
+        assert!(!exit(512, false).is_restart());
+        assert!(!SyscallInfo::None.is_restart());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_syscall_from_registers() {
+        use crate::tests::test_process_path;
+        use crate::{Event, SpawnOptions, TraceSession};
+        use std::process::{Command, Stdio};
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls();
+        let pid = session
+            .spawn(
+                Command::new(&path).stdout(Stdio::null()),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        // An entry and then an exit.
+        for _ in 0..2 {
+            ptrace::syscall(pid, None).unwrap();
+            assert_eq!(session.wait_for(pid).unwrap(), (pid, Event::Syscall));
+            let (info, pointers) = syscall_from_registers(pid).unwrap();
+            assert_eq!((info, pointers), syscall_stop(pid).unwrap());
+        }
+        assert!(matches!(
+            syscall_info(pid).unwrap(),
+            SyscallInfo::Exit { .. }
+        ));
+    }
+}
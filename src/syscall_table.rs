@@ -0,0 +1,137 @@
+//! System call names, numbers and argument counts for each architecture.
+//!
+//! The tables are generated from the kernel's uapi headers by
+//! `scripts/gen-syscall-tables.py`, which should be run again to pick up
+//! system calls added by newer kernels.
+
+mod aarch64;
+mod i386;
+mod x32;
+mod x86_64;
+
+/// The system calls of one ABI, for looking up names and argument counts
+/// of the numbers tracees use.
+///
+/// Argument counts are those of the system call's C prototype in the
+/// kernel. On 32-bit ABIs, 64-bit arguments such as file offsets take two
+/// registers but count once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyscallTable {
+    /// Entries of number, name and argument count, sorted by number.
+    syscalls: &'static [(u64, &'static str, u8)],
+}
+
+impl SyscallTable {
+    /// The x86-64 system calls.
+    pub const X86_64: SyscallTable = SyscallTable {
+        syscalls: x86_64::SYSCALLS,
+    };
+    /// The x32 system calls, numbered without the x32 bit, as returned by
+    /// `Abi::syscall_number`.
+    pub const X32: SyscallTable = SyscallTable {
+        syscalls: x32::SYSCALLS,
+    };
+    /// The i386 system calls, which 32-bit tracees make on x86-64 too.
+    pub const I386: SyscallTable = SyscallTable {
+        syscalls: i386::SYSCALLS,
+    };
+    /// The AArch64 system calls, from the kernel's generic table.
+    pub const AARCH64: SyscallTable = SyscallTable {
+        syscalls: aarch64::SYSCALLS,
+    };
+
+    /// The table for the architecture this crate was built for, or `None`
+    /// if there isn't one.
+    pub fn native() -> Option<SyscallTable> {
+        if cfg!(target_arch = "x86_64") {
+            Some(SyscallTable::X86_64)
+        } else if cfg!(target_arch = "aarch64") {
+            Some(SyscallTable::AARCH64)
+        } else {
+            None
+        }
+    }
+
+    fn entry(&self, number: u64) -> Option<&(u64, &'static str, u8)> {
+        let index = self
+            .syscalls
+            .binary_search_by_key(&number, |&(n, _, _)| n)
+            .ok()?;
+        Some(&self.syscalls[index])
+    }
+
+    /// The name of system call `number`, such as `"openat"`.
+    pub fn name(&self, number: u64) -> Option<&'static str> {
+        self.entry(number).map(|&(_, name, _)| name)
+    }
+
+    /// The number of the system call called `name`.
+    pub fn number(&self, name: &str) -> Option<u64> {
+        self.syscalls
+            .iter()
+            .find(|&&(_, n, _)| n == name)
+            .map(|&(number, _, _)| number)
+    }
+
+    /// The number of arguments system call `number` takes.
+    pub fn arg_count(&self, number: u64) -> Option<usize> {
+        self.entry(number).map(|&(_, _, args)| args as usize)
+    }
+
+    /// Every system call in the table as its number, name and argument
+    /// count, in order of number.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &'static str, usize)> {
+        self.syscalls
+            .iter()
+            .map(|&(number, name, args)| (number, name, args as usize))
+    }
+}
+
+/// The name of native system call `number`, looked up in
+/// [`SyscallTable::native`].
+///
+/// [`SyscallTable::native`]: struct.SyscallTable.html#method.native
+pub fn syscall_name(number: u64) -> Option<&'static str> {
+    SyscallTable::native()?.name(number)
+}
+
+/// The number of the native system call called `name`, looked up in
+/// [`SyscallTable::native`].
+///
+/// [`SyscallTable::native`]: struct.SyscallTable.html#method.native
+pub fn syscall_number(name: &str) -> Option<u64> {
+    SyscallTable::native()?.number(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syscall_table() {
+        for table in &[
+            SyscallTable::X86_64,
+            SyscallTable::X32,
+            SyscallTable::I386,
+            SyscallTable::AARCH64,
+        ] {
+            assert!(table.syscalls.windows(2).all(|w| w[0].0 < w[1].0));
+            assert!(table.iter().all(|(_, _, args)| args <= 6));
+        }
+        assert_eq!(SyscallTable::X86_64.name(0), Some("read"));
+        assert_eq!(SyscallTable::X86_64.arg_count(9), Some(6));
+        assert_eq!(SyscallTable::X32.number("rt_sigaction"), Some(512));
+        assert_eq!(SyscallTable::I386.name(1), Some("exit"));
+        assert_eq!(SyscallTable::I386.arg_count(90), Some(1));
+        assert_eq!(SyscallTable::AARCH64.number("openat"), Some(56));
+        assert_eq!(SyscallTable::AARCH64.number("open"), None);
+        assert_eq!(SyscallTable::X86_64.name(100_000), None);
+
+        assert_eq!(syscall_name(libc::SYS_openat as u64), Some("openat"));
+        assert_eq!(syscall_number("getpid"), Some(libc::SYS_getpid as u64));
+        assert_eq!(
+            syscall_name(libc::SYS_exit_group as u64),
+            Some("exit_group")
+        );
+    }
+}
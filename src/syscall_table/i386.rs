@@ -0,0 +1,445 @@
+// Generated by scripts/gen-syscall-tables.py from the Linux 6.1 uapi headers.
+// Do not edit.
+
+pub(super) const SYSCALLS: &[(u64, &str, u8)] = &[
+    (0, "restart_syscall", 0),
+    (1, "exit", 1),
+    (2, "fork", 0),
+    (3, "read", 3),
+    (4, "write", 3),
+    (5, "open", 3),
+    (6, "close", 1),
+    (7, "waitpid", 3),
+    (8, "creat", 2),
+    (9, "link", 2),
+    (10, "unlink", 1),
+    (11, "execve", 3),
+    (12, "chdir", 1),
+    (13, "time", 1),
+    (14, "mknod", 3),
+    (15, "chmod", 2),
+    (16, "lchown", 3),
+    (17, "break", 0),
+    (18, "oldstat", 2),
+    (19, "lseek", 3),
+    (20, "getpid", 0),
+    (21, "mount", 5),
+    (22, "umount", 2),
+    (23, "setuid", 1),
+    (24, "getuid", 0),
+    (25, "stime", 1),
+    (26, "ptrace", 4),
+    (27, "alarm", 1),
+    (28, "oldfstat", 2),
+    (29, "pause", 0),
+    (30, "utime", 2),
+    (31, "stty", 0),
+    (32, "gtty", 0),
+    (33, "access", 2),
+    (34, "nice", 1),
+    (35, "ftime", 0),
+    (36, "sync", 0),
+    (37, "kill", 2),
+    (38, "rename", 2),
+    (39, "mkdir", 2),
+    (40, "rmdir", 1),
+    (41, "dup", 1),
+    (42, "pipe", 1),
+    (43, "times", 1),
+    (44, "prof", 0),
+    (45, "brk", 1),
+    (46, "setgid", 1),
+    (47, "getgid", 0),
+    (48, "signal", 2),
+    (49, "geteuid", 0),
+    (50, "getegid", 0),
+    (51, "acct", 1),
+    (52, "umount2", 2),
+    (53, "lock", 0),
+    (54, "ioctl", 3),
+    (55, "fcntl", 3),
+    (56, "mpx", 0),
+    (57, "setpgid", 2),
+    (58, "ulimit", 2),
+    (59, "oldolduname", 1),
+    (60, "umask", 1),
+    (61, "chroot", 1),
+    (62, "ustat", 2),
+    (63, "dup2", 2),
+    (64, "getppid", 0),
+    (65, "getpgrp", 0),
+    (66, "setsid", 0),
+    (67, "sigaction", 3),
+    (68, "sgetmask", 0),
+    (69, "ssetmask", 1),
+    (70, "setreuid", 2),
+    (71, "setregid", 2),
+    (72, "sigsuspend", 1),
+    (73, "sigpending", 1),
+    (74, "sethostname", 2),
+    (75, "setrlimit", 2),
+    (76, "getrlimit", 2),
+    (77, "getrusage", 2),
+    (78, "gettimeofday", 2),
+    (79, "settimeofday", 2),
+    (80, "getgroups", 2),
+    (81, "setgroups", 2),
+    (82, "select", 1),
+    (83, "symlink", 2),
+    (84, "oldlstat", 2),
+    (85, "readlink", 3),
+    (86, "uselib", 1),
+    (87, "swapon", 2),
+    (88, "reboot", 4),
+    (89, "readdir", 3),
+    (90, "mmap", 1),
+    (91, "munmap", 2),
+    (92, "truncate", 2),
+    (93, "ftruncate", 2),
+    (94, "fchmod", 2),
+    (95, "fchown", 3),
+    (96, "getpriority", 2),
+    (97, "setpriority", 3),
+    (98, "profil", 0),
+    (99, "statfs", 2),
+    (100, "fstatfs", 2),
+    (101, "ioperm", 3),
+    (102, "socketcall", 2),
+    (103, "syslog", 3),
+    (104, "setitimer", 3),
+    (105, "getitimer", 2),
+    (106, "stat", 2),
+    (107, "lstat", 2),
+    (108, "fstat", 2),
+    (109, "olduname", 1),
+    (110, "iopl", 1),
+    (111, "vhangup", 0),
+    (112, "idle", 0),
+    (113, "vm86old", 1),
+    (114, "wait4", 4),
+    (115, "swapoff", 1),
+    (116, "sysinfo", 1),
+    (117, "ipc", 6),
+    (118, "fsync", 1),
+    (119, "sigreturn", 0),
+    (120, "clone", 5),
+    (121, "setdomainname", 2),
+    (122, "uname", 1),
+    (123, "modify_ldt", 3),
+    (124, "adjtimex", 1),
+    (125, "mprotect", 3),
+    (126, "sigprocmask", 3),
+    (127, "create_module", 2),
+    (128, "init_module", 3),
+    (129, "delete_module", 2),
+    (130, "get_kernel_syms", 1),
+    (131, "quotactl", 4),
+    (132, "getpgid", 1),
+    (133, "fchdir", 1),
+    (134, "bdflush", 2),
+    (135, "sysfs", 3),
+    (136, "personality", 1),
+    (137, "afs_syscall", 0),
+    (138, "setfsuid", 1),
+    (139, "setfsgid", 1),
+    (140, "_llseek", 5),
+    (141, "getdents", 3),
+    (142, "_newselect", 5),
+    (143, "flock", 2),
+    (144, "msync", 3),
+    (145, "readv", 3),
+    (146, "writev", 3),
+    (147, "getsid", 1),
+    (148, "fdatasync", 1),
+    (149, "_sysctl", 1),
+    (150, "mlock", 2),
+    (151, "munlock", 2),
+    (152, "mlockall", 1),
+    (153, "munlockall", 0),
+    (154, "sched_setparam", 2),
+    (155, "sched_getparam", 2),
+    (156, "sched_setscheduler", 3),
+    (157, "sched_getscheduler", 1),
+    (158, "sched_yield", 0),
+    (159, "sched_get_priority_max", 1),
+    (160, "sched_get_priority_min", 1),
+    (161, "sched_rr_get_interval", 2),
+    (162, "nanosleep", 2),
+    (163, "mremap", 5),
+    (164, "setresuid", 3),
+    (165, "getresuid", 3),
+    (166, "vm86", 3),
+    (167, "query_module", 5),
+    (168, "poll", 3),
+    (169, "nfsservctl", 3),
+    (170, "setresgid", 3),
+    (171, "getresgid", 3),
+    (172, "prctl", 5),
+    (173, "rt_sigreturn", 0),
+    (174, "rt_sigaction", 4),
+    (175, "rt_sigprocmask", 4),
+    (176, "rt_sigpending", 2),
+    (177, "rt_sigtimedwait", 4),
+    (178, "rt_sigqueueinfo", 3),
+    (179, "rt_sigsuspend", 2),
+    (180, "pread64", 4),
+    (181, "pwrite64", 4),
+    (182, "chown", 3),
+    (183, "getcwd", 2),
+    (184, "capget", 2),
+    (185, "capset", 2),
+    (186, "sigaltstack", 2),
+    (187, "sendfile", 4),
+    (188, "getpmsg", 5),
+    (189, "putpmsg", 5),
+    (190, "vfork", 0),
+    (191, "ugetrlimit", 2),
+    (192, "mmap2", 6),
+    (193, "truncate64", 2),
+    (194, "ftruncate64", 2),
+    (195, "stat64", 2),
+    (196, "lstat64", 2),
+    (197, "fstat64", 2),
+    (198, "lchown32", 3),
+    (199, "getuid32", 0),
+    (200, "getgid32", 0),
+    (201, "geteuid32", 0),
+    (202, "getegid32", 0),
+    (203, "setreuid32", 2),
+    (204, "setregid32", 2),
+    (205, "getgroups32", 2),
+    (206, "setgroups32", 2),
+    (207, "fchown32", 3),
+    (208, "setresuid32", 3),
+    (209, "getresuid32", 3),
+    (210, "setresgid32", 3),
+    (211, "getresgid32", 3),
+    (212, "chown32", 3),
+    (213, "setuid32", 1),
+    (214, "setgid32", 1),
+    (215, "setfsuid32", 1),
+    (216, "setfsgid32", 1),
+    (217, "pivot_root", 2),
+    (218, "mincore", 3),
+    (219, "madvise", 3),
+    (220, "getdents64", 3),
+    (221, "fcntl64", 3),
+    (224, "gettid", 0),
+    (225, "readahead", 3),
+    (226, "setxattr", 5),
+    (227, "lsetxattr", 5),
+    (228, "fsetxattr", 5),
+    (229, "getxattr", 4),
+    (230, "lgetxattr", 4),
+    (231, "fgetxattr", 4),
+    (232, "listxattr", 3),
+    (233, "llistxattr", 3),
+    (234, "flistxattr", 3),
+    (235, "removexattr", 2),
+    (236, "lremovexattr", 2),
+    (237, "fremovexattr", 2),
+    (238, "tkill", 2),
+    (239, "sendfile64", 4),
+    (240, "futex", 6),
+    (241, "sched_setaffinity", 3),
+    (242, "sched_getaffinity", 3),
+    (243, "set_thread_area", 1),
+    (244, "get_thread_area", 1),
+    (245, "io_setup", 2),
+    (246, "io_destroy", 1),
+    (247, "io_getevents", 5),
+    (248, "io_submit", 3),
+    (249, "io_cancel", 3),
+    (250, "fadvise64", 4),
+    (252, "exit_group", 1),
+    (253, "lookup_dcookie", 3),
+    (254, "epoll_create", 1),
+    (255, "epoll_ctl", 4),
+    (256, "epoll_wait", 4),
+    (257, "remap_file_pages", 5),
+    (258, "set_tid_address", 1),
+    (259, "timer_create", 3),
+    (260, "timer_settime", 4),
+    (261, "timer_gettime", 2),
+    (262, "timer_getoverrun", 1),
+    (263, "timer_delete", 1),
+    (264, "clock_settime", 2),
+    (265, "clock_gettime", 2),
+    (266, "clock_getres", 2),
+    (267, "clock_nanosleep", 4),
+    (268, "statfs64", 3),
+    (269, "fstatfs64", 3),
+    (270, "tgkill", 3),
+    (271, "utimes", 2),
+    (272, "fadvise64_64", 4),
+    (273, "vserver", 0),
+    (274, "mbind", 6),
+    (275, "get_mempolicy", 5),
+    (276, "set_mempolicy", 3),
+    (277, "mq_open", 4),
+    (278, "mq_unlink", 1),
+    (279, "mq_timedsend", 5),
+    (280, "mq_timedreceive", 5),
+    (281, "mq_notify", 2),
+    (282, "mq_getsetattr", 3),
+    (283, "kexec_load", 4),
+    (284, "waitid", 5),
+    (286, "add_key", 5),
+    (287, "request_key", 4),
+    (288, "keyctl", 5),
+    (289, "ioprio_set", 3),
+    (290, "ioprio_get", 2),
+    (291, "inotify_init", 0),
+    (292, "inotify_add_watch", 3),
+    (293, "inotify_rm_watch", 2),
+    (294, "migrate_pages", 4),
+    (295, "openat", 4),
+    (296, "mkdirat", 3),
+    (297, "mknodat", 4),
+    (298, "fchownat", 5),
+    (299, "futimesat", 3),
+    (300, "fstatat64", 4),
+    (301, "unlinkat", 3),
+    (302, "renameat", 4),
+    (303, "linkat", 5),
+    (304, "symlinkat", 3),
+    (305, "readlinkat", 4),
+    (306, "fchmodat", 3),
+    (307, "faccessat", 3),
+    (308, "pselect6", 6),
+    (309, "ppoll", 5),
+    (310, "unshare", 1),
+    (311, "set_robust_list", 2),
+    (312, "get_robust_list", 3),
+    (313, "splice", 6),
+    (314, "sync_file_range", 4),
+    (315, "tee", 4),
+    (316, "vmsplice", 4),
+    (317, "move_pages", 6),
+    (318, "getcpu", 3),
+    (319, "epoll_pwait", 6),
+    (320, "utimensat", 4),
+    (321, "signalfd", 3),
+    (322, "timerfd_create", 2),
+    (323, "eventfd", 1),
+    (324, "fallocate", 4),
+    (325, "timerfd_settime", 4),
+    (326, "timerfd_gettime", 2),
+    (327, "signalfd4", 4),
+    (328, "eventfd2", 2),
+    (329, "epoll_create1", 1),
+    (330, "dup3", 3),
+    (331, "pipe2", 2),
+    (332, "inotify_init1", 1),
+    (333, "preadv", 5),
+    (334, "pwritev", 5),
+    (335, "rt_tgsigqueueinfo", 4),
+    (336, "perf_event_open", 5),
+    (337, "recvmmsg", 5),
+    (338, "fanotify_init", 2),
+    (339, "fanotify_mark", 5),
+    (340, "prlimit64", 4),
+    (341, "name_to_handle_at", 5),
+    (342, "open_by_handle_at", 3),
+    (343, "clock_adjtime", 2),
+    (344, "syncfs", 1),
+    (345, "sendmmsg", 4),
+    (346, "setns", 2),
+    (347, "process_vm_readv", 6),
+    (348, "process_vm_writev", 6),
+    (349, "kcmp", 5),
+    (350, "finit_module", 3),
+    (351, "sched_setattr", 3),
+    (352, "sched_getattr", 4),
+    (353, "renameat2", 5),
+    (354, "seccomp", 3),
+    (355, "getrandom", 3),
+    (356, "memfd_create", 2),
+    (357, "bpf", 3),
+    (358, "execveat", 5),
+    (359, "socket", 3),
+    (360, "socketpair", 4),
+    (361, "bind", 3),
+    (362, "connect", 3),
+    (363, "listen", 2),
+    (364, "accept4", 4),
+    (365, "getsockopt", 5),
+    (366, "setsockopt", 5),
+    (367, "getsockname", 3),
+    (368, "getpeername", 3),
+    (369, "sendto", 6),
+    (370, "sendmsg", 3),
+    (371, "recvfrom", 6),
+    (372, "recvmsg", 3),
+    (373, "shutdown", 2),
+    (374, "userfaultfd", 1),
+    (375, "membarrier", 3),
+    (376, "mlock2", 3),
+    (377, "copy_file_range", 6),
+    (378, "preadv2", 6),
+    (379, "pwritev2", 6),
+    (380, "pkey_mprotect", 4),
+    (381, "pkey_alloc", 2),
+    (382, "pkey_free", 1),
+    (383, "statx", 5),
+    (384, "arch_prctl", 2),
+    (385, "io_pgetevents", 6),
+    (386, "rseq", 4),
+    (393, "semget", 3),
+    (394, "semctl", 4),
+    (395, "shmget", 3),
+    (396, "shmctl", 3),
+    (397, "shmat", 3),
+    (398, "shmdt", 1),
+    (399, "msgget", 2),
+    (400, "msgsnd", 4),
+    (401, "msgrcv", 5),
+    (402, "msgctl", 3),
+    (403, "clock_gettime64", 2),
+    (404, "clock_settime64", 2),
+    (405, "clock_adjtime64", 2),
+    (406, "clock_getres_time64", 2),
+    (407, "clock_nanosleep_time64", 4),
+    (408, "timer_gettime64", 2),
+    (409, "timer_settime64", 4),
+    (410, "timerfd_gettime64", 2),
+    (411, "timerfd_settime64", 4),
+    (412, "utimensat_time64", 4),
+    (413, "pselect6_time64", 6),
+    (414, "ppoll_time64", 5),
+    (416, "io_pgetevents_time64", 6),
+    (417, "recvmmsg_time64", 5),
+    (418, "mq_timedsend_time64", 5),
+    (419, "mq_timedreceive_time64", 5),
+    (420, "semtimedop_time64", 4),
+    (421, "rt_sigtimedwait_time64", 4),
+    (422, "futex_time64", 6),
+    (423, "sched_rr_get_interval_time64", 2),
+    (424, "pidfd_send_signal", 4),
+    (425, "io_uring_setup", 2),
+    (426, "io_uring_enter", 6),
+    (427, "io_uring_register", 4),
+    (428, "open_tree", 3),
+    (429, "move_mount", 5),
+    (430, "fsopen", 2),
+    (431, "fsconfig", 5),
+    (432, "fsmount", 3),
+    (433, "fspick", 3),
+    (434, "pidfd_open", 2),
+    (435, "clone3", 2),
+    (436, "close_range", 3),
+    (437, "openat2", 4),
+    (438, "pidfd_getfd", 3),
+    (439, "faccessat2", 4),
+    (440, "process_madvise", 5),
+    (441, "epoll_pwait2", 6),
+    (442, "mount_setattr", 5),
+    (443, "quotactl_fd", 4),
+    (444, "landlock_create_ruleset", 3),
+    (445, "landlock_add_rule", 4),
+    (446, "landlock_restrict_self", 2),
+    (447, "memfd_secret", 1),
+    (448, "process_mrelease", 2),
+    (449, "futex_waitv", 5),
+    (450, "set_mempolicy_home_node", 4),
+];
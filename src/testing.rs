@@ -0,0 +1,581 @@
+//! Fixture programs and event assertions for testing tracers.
+//!
+//! Testing a tracer needs programs that do one well-defined thing to trace,
+//! and a way to collect what the tracer saw of them without hanging when
+//! something goes wrong. A [`Fixture`] is a tiny static executable that is
+//! generated on the fly, so it needs no compiler, no helper binary built
+//! next to the tests and no libc. Each fixture makes only the system calls
+//! its behavior needs, which keeps the events it produces predictable.
+//! [`collect_events`] runs a session to completion with a timeout, and the
+//! `assert_*` functions check the events it collected.
+//!
+//! Fixtures are x86-64 machine code, so [`Fixture`] only exists on x86-64.
+//! The rest of the module works on every architecture.
+//!
+//! [`Fixture`]: struct.Fixture.html
+//! [`collect_events`]: fn.collect_events.html
+
+#[cfg(target_arch = "x86_64")]
+pub use self::fixture::Fixture;
+use crate::{Event, Resume, TraceSession};
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The fixture programs, which are x86-64 machine code.
+#[cfg(target_arch = "x86_64")]
+mod fixture {
+    use std::io;
+    use std::time::Duration;
+    use std::{
+        env,
+        fs::{self, OpenOptions},
+        io::Write,
+        os::unix::fs::OpenOptionsExt,
+        path::{Path, PathBuf},
+        process::{self, Command},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Where fixture programs are loaded.
+    const BASE: u32 = 0x40_0000;
+    /// The offset of the code in the file, after the ELF and program headers.
+    const CODE_OFFSET: u32 = 64 + 56;
+    /// Scratch memory for the program's data, in the zeroed part of its segment.
+    const DATA: u32 = BASE + 0x1000;
+    /// The top of the stack for the threads a program creates.
+    const STACK_TOP: u32 = BASE + 0x2_0000;
+
+    const SYS_NANOSLEEP: u32 = 35;
+    const SYS_CLONE: u32 = 56;
+    const SYS_FORK: u32 = 57;
+    const SYS_EXIT: u32 = 60;
+    const SYS_WAIT4: u32 = 61;
+    const SYS_FUTEX: u32 = 202;
+    const SYS_CLOCK_GETTIME: u32 = 228;
+    const SYS_EXIT_GROUP: u32 = 231;
+
+    /// Distinguishes the fixture files of one process.
+    static NEXT_FIXTURE: AtomicUsize = AtomicUsize::new(0);
+
+    /// A tiny generated program for a tracer to trace.
+    ///
+    /// Each constructor writes a static x86-64 executable that does one thing
+    /// to a new file in the temporary directory, which is removed when the
+    /// fixture is dropped, and [`command`] returns a `Command` that runs it.
+    /// The temporary directory must not be mounted `noexec`. Fixtures ignore
+    /// their arguments and environment, and none of them read, write or open
+    /// any files.
+    ///
+    /// [`command`]: #method.command
+    #[derive(Debug)]
+    pub struct Fixture {
+        path: PathBuf,
+    }
+
+    impl Fixture {
+        /// A program that exits with status `code` right away.
+        pub fn exit(code: i32) -> io::Result<Fixture> {
+            let mut asm = Asm::default();
+            asm.exit_group(code as u32);
+            Fixture::write(asm)
+        }
+
+        /// A program that sleeps for `duration` with `nanosleep`, then exits
+        /// with status 0.
+        pub fn sleep(duration: Duration) -> io::Result<Fixture> {
+            let mut asm = Asm::default();
+            asm.store(DATA, duration.as_secs());
+            asm.store(DATA + 8, u64::from(duration.subsec_nanos()));
+            asm.mov_edi(DATA);
+            asm.code(&[0x31, 0xf6]); // xor esi, esi
+            asm.syscall(SYS_NANOSLEEP);
+            asm.exit_group(0);
+            Fixture::write(asm)
+        }
+
+        /// A program that writes to address 0, which kills it with `SIGSEGV`.
+        pub fn segfault() -> io::Result<Fixture> {
+            let mut asm = Asm::default();
+            // mov byte [0], 0
+            asm.code(&[0xc6, 0x04, 0x25, 0, 0, 0, 0, 0]);
+            asm.exit_group(0);
+            Fixture::write(asm)
+        }
+
+        /// A program that forks a child, which exits with status 0, and waits
+        /// for it before exiting with status 0 itself.
+        pub fn fork() -> io::Result<Fixture> {
+            let mut asm = Asm::default();
+            asm.syscall(SYS_FORK);
+            asm.code(&[0x85, 0xc0]); // test eax, eax
+            let parent = asm.jump(0x75); // jnz
+            asm.exit_group(0);
+            asm.patch(parent);
+            asm.mov_edi(u32::MAX); // Any child.
+            asm.code(&[0x31, 0xf6]); // xor esi, esi
+            asm.code(&[0x31, 0xd2]); // xor edx, edx
+            asm.code(&[0x45, 0x31, 0xd2]); // xor r10d, r10d
+            asm.syscall(SYS_WAIT4);
+            asm.exit_group(0);
+            Fixture::write(asm)
+        }
+
+        /// A program that creates `count` threads one after the other, each of
+        /// which exits right away, and waits for each to finish before creating
+        /// the next one, then exits with status 0.
+        pub fn threads(count: u32) -> io::Result<Fixture> {
+            let flags = libc::CLONE_VM
+                | libc::CLONE_FS
+                | libc::CLONE_FILES
+                | libc::CLONE_SIGHAND
+                | libc::CLONE_THREAD
+                | libc::CLONE_SYSVSEM
+                | libc::CLONE_PARENT_SETTID
+                | libc::CLONE_CHILD_CLEARTID;
+            let mut asm = Asm::default();
+            if count > 0 {
+                asm.code(&[0x41, 0xbc]); // mov r12d, count
+                asm.code(&count.to_le_bytes());
+                let next = asm.here();
+                asm.mov_edi(flags as u32);
+                asm.code(&[0xbe]); // mov esi, STACK_TOP
+                asm.code(&STACK_TOP.to_le_bytes());
+                // The kernel stores the thread's ID at DATA before `clone`
+                // returns, and clears it once the thread has exited.
+                asm.code(&[0xba]); // mov edx, DATA
+                asm.code(&DATA.to_le_bytes());
+                asm.code(&[0x41, 0xba]); // mov r10d, DATA
+                asm.code(&DATA.to_le_bytes());
+                asm.code(&[0x45, 0x31, 0xc0]); // xor r8d, r8d
+                asm.syscall(SYS_CLONE);
+                asm.code(&[0x85, 0xc0]); // test eax, eax
+                let parent = asm.jump(0x75); // jnz
+                asm.code(&[0x31, 0xff]); // xor edi, edi
+                asm.syscall(SYS_EXIT);
+                asm.patch(parent);
+                let check = asm.here();
+                asm.code(&[0x8b, 0x14, 0x25]); // mov edx, [DATA]
+                asm.code(&DATA.to_le_bytes());
+                asm.code(&[0x85, 0xd2]); // test edx, edx
+                let done = asm.jump(0x74); // jz
+                asm.mov_edi(DATA);
+                asm.code(&[0x31, 0xf6]); // xor esi, esi (FUTEX_WAIT)
+                asm.code(&[0x45, 0x31, 0xd2]); // xor r10d, r10d
+                asm.syscall(SYS_FUTEX);
+                asm.jump_to(0xeb, check); // jmp
+                asm.patch(done);
+                asm.code(&[0x41, 0xff, 0xcc]); // dec r12d
+                asm.jump_to(0x75, next); // jnz
+            }
+            asm.exit_group(0);
+            Fixture::write(asm)
+        }
+
+        /// A program that runs a busy loop for `duration`, making a
+        /// `clock_gettime` system call every hundred thousand iterations, then
+        /// exits with status 0.
+        pub fn spin(duration: Duration) -> io::Result<Fixture> {
+            let mut asm = Asm::default();
+            asm.monotonic_nanos();
+            asm.code(&[0x48, 0x89, 0xc3]); // mov rbx, rax
+            asm.code(&[0x48, 0xb9]); // mov rcx, duration
+            asm.code(&(duration.as_nanos() as u64).to_le_bytes());
+            asm.code(&[0x48, 0x01, 0xcb]); // add rbx, rcx
+            let again = asm.here();
+            asm.code(&[0xb9]); // mov ecx, 100000
+            asm.code(&100_000u32.to_le_bytes());
+            let spin = asm.here();
+            asm.code(&[0xff, 0xc9]); // dec ecx
+            asm.jump_to(0x75, spin); // jnz
+            asm.monotonic_nanos();
+            asm.code(&[0x48, 0x39, 0xd8]); // cmp rax, rbx
+            asm.jump_to(0x7c, again); // jl
+            asm.exit_group(0);
+            Fixture::write(asm)
+        }
+
+        /// The fixture's executable.
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /// A `Command` that runs the fixture.
+        pub fn command(&self) -> Command {
+            Command::new(&self.path)
+        }
+
+        fn write(asm: Asm) -> io::Result<Fixture> {
+            let path = env::temp_dir().join(format!(
+                "spawn-ptrace-fixture-{}-{}",
+                process::id(),
+                NEXT_FIXTURE.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o755)
+                .open(&path)?;
+            let fixture = Fixture { path };
+            file.write_all(&elf(&asm.code))?;
+            Ok(fixture)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    /// A static executable whose one segment holds `code`, followed by zeroed
+    /// memory for the program's data and stacks.
+    fn elf(code: &[u8]) -> Vec<u8> {
+        let size = u64::from(CODE_OFFSET) + code.len() as u64;
+        assert!(size <= u64::from(DATA - BASE), "Fixture program is too big");
+        let mut elf = Vec::with_capacity(size as usize);
+        // The ELF header: 64-bit, little-endian, current version.
+        elf.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        elf.extend_from_slice(&[0; 9]);
+        elf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        elf.extend_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+        elf.extend_from_slice(&1u32.to_le_bytes()); // EV_CURRENT
+        elf.extend_from_slice(&u64::from(BASE + CODE_OFFSET).to_le_bytes());
+        elf.extend_from_slice(&64u64.to_le_bytes()); // Program headers.
+        elf.extend_from_slice(&0u64.to_le_bytes()); // No section headers.
+        elf.extend_from_slice(&0u32.to_le_bytes()); // Flags.
+        elf.extend_from_slice(&64u16.to_le_bytes());
+        elf.extend_from_slice(&56u16.to_le_bytes());
+        elf.extend_from_slice(&1u16.to_le_bytes());
+        elf.extend_from_slice(&[0; 6]);
+        // A readable, writable and executable PT_LOAD segment for everything.
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&7u32.to_le_bytes());
+        elf.extend_from_slice(&0u64.to_le_bytes());
+        elf.extend_from_slice(&u64::from(BASE).to_le_bytes());
+        elf.extend_from_slice(&u64::from(BASE).to_le_bytes());
+        elf.extend_from_slice(&size.to_le_bytes());
+        elf.extend_from_slice(&u64::from(STACK_TOP - BASE).to_le_bytes());
+        elf.extend_from_slice(&0x1000u64.to_le_bytes());
+        elf.extend_from_slice(code);
+        elf
+    }
+
+    /// Machine code for a fixture program, written an instruction at a time.
+    #[derive(Default)]
+    struct Asm {
+        code: Vec<u8>,
+    }
+
+    impl Asm {
+        fn code(&mut self, bytes: &[u8]) {
+            self.code.extend_from_slice(bytes);
+        }
+
+        fn here(&self) -> usize {
+            self.code.len()
+        }
+
+        /// A short jump with opcode `op` to a later position, which is set with
+        /// `patch`.
+        fn jump(&mut self, op: u8) -> usize {
+            self.code(&[op, 0]);
+            self.here()
+        }
+
+        /// Make the jump that ends at `from` go to the current position.
+        fn patch(&mut self, from: usize) {
+            let offset = self.here() - from;
+            assert!(offset <= i8::MAX as usize, "Fixture jump is too far");
+            self.code[from - 1] = offset as u8;
+        }
+
+        /// A short jump with opcode `op` back to `target`.
+        fn jump_to(&mut self, op: u8, target: usize) {
+            let offset = target as isize - (self.here() + 2) as isize;
+            assert!(offset >= i8::MIN as isize, "Fixture jump is too far");
+            self.code(&[op, offset as i8 as u8]);
+        }
+
+        fn mov_edi(&mut self, value: u32) {
+            self.code(&[0xbf]);
+            self.code(&value.to_le_bytes());
+        }
+
+        /// Store `value` at the absolute address `addr`, using `rax`.
+        fn store(&mut self, addr: u32, value: u64) {
+            self.code(&[0x48, 0xb8]); // mov rax, value
+            self.code(&value.to_le_bytes());
+            self.code(&[0x48, 0x89, 0x04, 0x25]); // mov [addr], rax
+            self.code(&addr.to_le_bytes());
+        }
+
+        fn syscall(&mut self, number: u32) {
+            self.code(&[0xb8]); // mov eax, number
+            self.code(&number.to_le_bytes());
+            self.code(&[0x0f, 0x05]);
+        }
+
+        fn exit_group(&mut self, code: u32) {
+            self.mov_edi(code);
+            self.syscall(SYS_EXIT_GROUP);
+        }
+
+        /// Read `CLOCK_MONOTONIC` into `rax`, in nanoseconds, using the
+        /// timespec at `DATA`.
+        fn monotonic_nanos(&mut self) {
+            self.mov_edi(libc::CLOCK_MONOTONIC as u32);
+            self.code(&[0xbe]); // mov esi, DATA
+            self.code(&DATA.to_le_bytes());
+            self.syscall(SYS_CLOCK_GETTIME);
+            self.code(&[0x48, 0x8b, 0x04, 0x25]); // mov rax, [DATA]
+            self.code(&DATA.to_le_bytes());
+            self.code(&[0x48, 0x69, 0xc0]); // imul rax, rax, 1000000000
+            self.code(&1_000_000_000u32.to_le_bytes());
+            self.code(&[0x48, 0x03, 0x04, 0x25]); // add rax, [DATA + 8]
+            self.code(&(DATA + 8).to_le_bytes());
+        }
+    }
+}
+
+/// Resume every tracee in `session` and collect the events they report
+/// until they have all exited.
+///
+/// Each stop is resumed as `resume` says, except that the signal of an
+/// `Event::Signal` is passed on in place of the one in `resume`. All
+/// tracees must be stopped. If they haven't all exited within `timeout`,
+/// this fails with `ErrorKind::TimedOut`, and the tracees that are left
+/// stay in the session, to be dropped with their `DropPolicy`.
+pub fn collect_events(
+    session: &mut TraceSession,
+    resume: Resume,
+    timeout: Duration,
+) -> io::Result<Vec<(Pid, Event)>> {
+    let deadline = Instant::now() + timeout;
+    let pids: Vec<Pid> = session.pids().collect();
+    for pid in pids {
+        session.resume_as(pid, resume)?;
+    }
+    let mut events = vec![];
+    while !session.is_empty() {
+        let (pid, event) = match session.try_wait_any()? {
+            Some(event) => event,
+            None if Instant::now() >= deadline => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Tracees didn't exit in time",
+                ));
+            }
+            None => {
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+        };
+        events.push((pid, event));
+        let resume = match (event, resume) {
+            (Event::Exited(_), _) | (Event::Signaled(..), _) => continue,
+            (Event::Signal(signal), Resume::Continue(_)) => Resume::Continue(Some(signal)),
+            (Event::Signal(signal), Resume::Syscall(_)) => Resume::Syscall(Some(signal)),
+            (Event::Signal(signal), Resume::Detach(_)) => Resume::Detach(Some(signal)),
+            _ => resume,
+        };
+        match session.resume_as(pid, resume) {
+            // The tracee may have been killed meanwhile, and its death is
+            // still to be reported.
+            Err(ref e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+            result => result?,
+        }
+    }
+    Ok(events)
+}
+
+/// The last event of tracee `pid` in `events`.
+pub fn last_event(events: &[(Pid, Event)], pid: Pid) -> Option<Event> {
+    events
+        .iter()
+        .rev()
+        .find(|&&(p, _)| p == pid)
+        .map(|&(_, event)| event)
+}
+
+/// Panic unless the last event of tracee `pid` in `events` is `expected`,
+/// such as `Event::Exited(0)`.
+#[track_caller]
+pub fn assert_last_event(events: &[(Pid, Event)], pid: Pid, expected: Event) {
+    let last = last_event(events, pid);
+    assert!(
+        last == Some(expected),
+        "Expected the last event of {} to be {:?}, got {:?}, in {:?}",
+        pid,
+        expected,
+        last,
+        events
+    );
+}
+
+/// Panic unless `expected` appear in `events` in the same order, from any
+/// tracees and with any other events in between.
+#[track_caller]
+pub fn assert_events_in_order(events: &[(Pid, Event)], expected: &[Event]) {
+    let mut rest = events.iter();
+    for want in expected {
+        assert!(
+            rest.any(|(_, event)| event == want),
+            "Expected {:?} in order, missing {:?}, in {:?}",
+            expected,
+            want,
+            events
+        );
+    }
+}
+
+/// Panic unless `events` holds exactly `count` events that match `matches`.
+#[track_caller]
+pub fn assert_event_count<F>(events: &[(Pid, Event)], count: usize, matches: F)
+where
+    F: Fn(&Event) -> bool,
+{
+    let found = events.iter().filter(|(_, event)| matches(event)).count();
+    assert!(
+        found == count,
+        "Expected {} matching events, found {}, in {:?}",
+        count,
+        found,
+        events
+    );
+}
+
+/// Resume tracee `pid` with `PTRACE_CONT` until it reports an event that
+/// matches `matches`, which is returned, passing on the signals it receives.
+///
+/// The tracee must be stopped. Fails with `ErrorKind::UnexpectedEof` if it
+/// exits first, and with `ErrorKind::TimedOut` after `timeout`.
+pub fn cont_until<F>(
+    session: &mut TraceSession,
+    pid: Pid,
+    timeout: Duration,
+    matches: F,
+) -> io::Result<Event>
+where
+    F: Fn(&Event) -> bool,
+{
+    let deadline = Instant::now() + timeout;
+    let mut signal = None;
+    loop {
+        ptrace::cont(pid, signal).map_err(crate::nix_error)?;
+        let event = loop {
+            if let Some((_, event)) = session.try_wait_for(pid)? {
+                break event;
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Tracee didn't report the event in time",
+                ));
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        if matches(&event) {
+            return Ok(event);
+        }
+        signal = match event {
+            Event::Exited(_) | Event::Signaled(..) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Tracee finished first with {:?}", event),
+                ));
+            }
+            Event::Signal(signal) => Some(signal),
+            _ => None,
+        };
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::{DropPolicy, SpawnOptions};
+    use nix::sys::signal::Signal;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn trace(fixture: &Fixture, session: &mut TraceSession) -> (Pid, Vec<(Pid, Event)>) {
+        let pid = session
+            .spawn(&mut fixture.command(), SpawnOptions::new())
+            .expect("Error spawning fixture");
+        let events = collect_events(session, Resume::Continue(None), TIMEOUT)
+            .expect("Error collecting events");
+        (pid, events)
+    }
+
+    #[test]
+    fn test_fixture_exit_and_segfault() {
+        let fixture = Fixture::exit(7).unwrap();
+        let (pid, events) = trace(&fixture, &mut TraceSession::new());
+        assert_eq!(events, [(pid, Event::Exited(7))]);
+        let fixture = Fixture::segfault().unwrap();
+        let (pid, events) = trace(&fixture, &mut TraceSession::new());
+        assert_events_in_order(&events, &[Event::Signal(Signal::SIGSEGV)]);
+        match last_event(&events, pid) {
+            Some(Event::Signaled(Signal::SIGSEGV, _)) => {}
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        let path = fixture.path().to_owned();
+        drop(fixture);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_fixture_fork_and_threads() {
+        let fixture = Fixture::fork().unwrap();
+        let mut session = TraceSession::new();
+        session.follow_forks();
+        let (pid, events) = trace(&fixture, &mut session);
+        assert_event_count(&events, 1, |event| matches!(event, Event::Fork(_)));
+        assert_event_count(&events, 2, |event| *event == Event::Exited(0));
+        assert_last_event(&events, pid, Event::Exited(0));
+        let fixture = Fixture::threads(3).unwrap();
+        let mut session = TraceSession::new();
+        session.follow_forks();
+        let (pid, events) = trace(&fixture, &mut session);
+        assert_event_count(&events, 3, |event| matches!(event, Event::Clone(_)));
+        assert_last_event(&events, pid, Event::Exited(0));
+    }
+
+    #[test]
+    fn test_fixture_sleep_and_spin() {
+        for fixture in [
+            Fixture::sleep(Duration::from_millis(50)).unwrap(),
+            Fixture::spin(Duration::from_millis(50)).unwrap(),
+        ] {
+            let start = Instant::now();
+            let (pid, events) = trace(&fixture, &mut TraceSession::new());
+            assert!(start.elapsed() >= Duration::from_millis(50));
+            assert_last_event(&events, pid, Event::Exited(0));
+        }
+    }
+
+    #[test]
+    fn test_cont_until() {
+        let fixture = Fixture::sleep(Duration::from_secs(200)).unwrap();
+        let mut session = TraceSession::new();
+        let pid = session
+            .spawn(&mut fixture.command(), SpawnOptions::new())
+            .expect("Error spawning fixture");
+        nix::sys::signal::kill(pid, Signal::SIGTERM).unwrap();
+        let event = cont_until(&mut session, pid, TIMEOUT, |event| {
+            matches!(event, Event::Signaled(..))
+        })
+        .unwrap();
+        assert_eq!(event, Event::Signaled(Signal::SIGTERM, false));
+        let mut options = SpawnOptions::new();
+        options.drop_policy(DropPolicy::Kill);
+        let pid = session
+            .spawn(&mut fixture.command(), options)
+            .expect("Error spawning fixture");
+        let e = cont_until(&mut session, pid, Duration::from_millis(50), |_| true).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,146 @@
+//! Access to the GDT-based TLS descriptors that 32-bit x86 programs use.
+
+use nix::unistd::Pid;
+use std::io;
+
+const PTRACE_GET_THREAD_AREA: libc::c_uint = 25;
+const PTRACE_SET_THREAD_AREA: libc::c_uint = 26;
+
+// Bits of the flags word of `struct user_desc`.
+const SEG_32BIT: u32 = 1 << 0;
+const CONTENTS_SHIFT: u32 = 1;
+const READ_EXEC_ONLY: u32 = 1 << 3;
+const LIMIT_IN_PAGES: u32 = 1 << 4;
+const SEG_NOT_PRESENT: u32 = 1 << 5;
+const USEABLE: u32 = 1 << 6;
+
+/// The kernel's `struct user_desc`.
+#[repr(C)]
+#[derive(Default)]
+struct UserDesc {
+    entry_number: u32,
+    base_addr: u32,
+    limit: u32,
+    flags: u32,
+}
+
+/// One of a thread's TLS segment descriptors in the GDT, as set by
+/// `set_thread_area` and read with [`Tracee::thread_area`].
+///
+/// 32-bit programs, including ones running in compatibility mode on a
+/// 64-bit kernel, find their thread-local storage through a segment
+/// register that selects one of these; 64-bit programs use the `fs_base`
+/// register instead. On x86-64 the TLS entries are numbered 12 to 14.
+///
+/// [`Tracee::thread_area`]: struct.Tracee.html#method.thread_area
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThreadArea {
+    /// The GDT entry this describes.
+    pub entry_number: u32,
+    /// The segment's base address.
+    pub base_addr: u32,
+    /// The segment's limit, in bytes or in pages.
+    pub limit: u32,
+    /// Whether this is a 32-bit segment.
+    pub seg_32bit: bool,
+    /// The segment type: 0 for data, 1 for expand-down data, 2 for code.
+    pub contents: u8,
+    /// Whether the segment is read-only, or execute-only for code.
+    pub read_exec_only: bool,
+    /// Whether `limit` is in pages rather than bytes.
+    pub limit_in_pages: bool,
+    /// Whether the segment is absent, which is how empty entries read.
+    pub seg_not_present: bool,
+    /// The bit available for software use.
+    pub useable: bool,
+}
+
+impl ThreadArea {
+    fn from_raw(desc: &UserDesc) -> ThreadArea {
+        ThreadArea {
+            entry_number: desc.entry_number,
+            base_addr: desc.base_addr,
+            limit: desc.limit,
+            seg_32bit: desc.flags & SEG_32BIT != 0,
+            contents: (desc.flags >> CONTENTS_SHIFT & 0b11) as u8,
+            read_exec_only: desc.flags & READ_EXEC_ONLY != 0,
+            limit_in_pages: desc.flags & LIMIT_IN_PAGES != 0,
+            seg_not_present: desc.flags & SEG_NOT_PRESENT != 0,
+            useable: desc.flags & USEABLE != 0,
+        }
+    }
+
+    fn to_raw(self) -> UserDesc {
+        let flag = |set: bool, bit: u32| if set { bit } else { 0 };
+        UserDesc {
+            entry_number: self.entry_number,
+            base_addr: self.base_addr,
+            limit: self.limit,
+            flags: flag(self.seg_32bit, SEG_32BIT)
+                | (self.contents as u32 & 0b11) << CONTENTS_SHIFT
+                | flag(self.read_exec_only, READ_EXEC_ONLY)
+                | flag(self.limit_in_pages, LIMIT_IN_PAGES)
+                | flag(self.seg_not_present, SEG_NOT_PRESENT)
+                | flag(self.useable, USEABLE),
+        }
+    }
+}
+
+/// Read GDT entry `entry` of the stopped thread `tid`.
+pub(crate) fn get(tid: Pid, entry: u32) -> io::Result<ThreadArea> {
+    let mut desc = UserDesc::default();
+    thread_area(PTRACE_GET_THREAD_AREA, tid, entry, &mut desc)?;
+    Ok(ThreadArea::from_raw(&desc))
+}
+
+/// Set the GDT entry of the stopped thread `tid` that `area` describes.
+pub(crate) fn set(tid: Pid, area: &ThreadArea) -> io::Result<()> {
+    let mut desc = area.to_raw();
+    thread_area(PTRACE_SET_THREAD_AREA, tid, area.entry_number, &mut desc)
+}
+
+fn thread_area(request: libc::c_uint, tid: Pid, entry: u32, desc: &mut UserDesc) -> io::Result<()> {
+    let ret = unsafe { libc::ptrace(request, tid.as_raw(), entry as usize, desc as *mut UserDesc) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::process::Command;
+
+    #[test]
+    fn test_thread_area() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        // A 64-bit process has no TLS segments.
+        let empty = tracee.thread_area(12).expect("Error reading thread area");
+        assert_eq!(empty.entry_number, 12);
+        assert!(empty.seg_not_present);
+        assert!(tracee.thread_area(0).is_err());
+
+        let area = ThreadArea {
+            entry_number: 13,
+            base_addr: 0x1000,
+            limit: 0xfffff,
+            seg_32bit: true,
+            limit_in_pages: true,
+            useable: true,
+            ..ThreadArea::default()
+        };
+        tracee
+            .set_thread_area(&area)
+            .expect("Error setting thread area");
+        assert_eq!(tracee.thread_area(13).unwrap(), area);
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
@@ -0,0 +1,724 @@
+//! A timeline of a trace session, written as Chrome trace events or passed
+//! on as spans.
+
+use crate::accounting::read_tgid;
+use crate::{syscall, Event, SyscallInfo, SyscallTable, TraceSession, Tracee};
+use nix::unistd::Pid;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A system call that has been entered.
+#[derive(Debug)]
+struct Call {
+    number: u64,
+    args: Vec<u64>,
+    start: Duration,
+}
+
+/// Something recorded on a thread's lane.
+#[derive(Debug)]
+enum Mark {
+    /// A system call from entry to exit, with its return value, or `None`
+    /// if the thread exited in it.
+    Syscall {
+        call: Call,
+        end: Duration,
+        value: Option<i64>,
+    },
+    /// Something that happened at one point, with a category, a name and
+    /// arguments.
+    Instant {
+        time: Duration,
+        category: &'static str,
+        name: String,
+        args: Vec<(&'static str, i64)>,
+    },
+}
+
+/// The life of a process, from when it was first seen to its exit.
+#[derive(Debug)]
+struct Life {
+    pid: Pid,
+    /// The life of the process that forked it, if that was seen.
+    parent: Option<usize>,
+    start: Duration,
+    name: String,
+    /// Its `exec`s, with when they were, its new name and the thread that
+    /// called them.
+    execs: Vec<(Duration, String, Pid)>,
+    /// When it ended, and how.
+    end: Option<(Duration, Vec<(&'static str, i64)>)>,
+}
+
+/// What a [`Span`] covers.
+///
+/// [`Span`]: struct.Span.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SpanKind {
+    /// A process, from when it was first seen or forked to its exit.
+    Process,
+    /// A program a process ran, from its `exec` to the next one or the
+    /// process's exit.
+    Exec,
+    /// A system call, from its entry to its exit.
+    Syscall,
+}
+
+/// A span of time from a [`Timeline`], as passed to a [`SpanSink`] by
+/// [`Timeline::export_spans`].
+///
+/// [`Timeline`]: struct.Timeline.html
+/// [`SpanSink`]: trait.SpanSink.html
+/// [`Timeline::export_spans`]: struct.Timeline.html#method.export_spans
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    /// An ID for the span, unique within one export.
+    pub id: u64,
+    /// The ID of the span this one is part of: the process that forked a
+    /// process, the process of an `exec`, and the program or process a
+    /// system call was made in.
+    pub parent: Option<u64>,
+    /// What the span covers.
+    pub kind: SpanKind,
+    /// The `comm` of a process or program, or a system call's name.
+    pub name: String,
+    /// The process.
+    pub pid: Pid,
+    /// The thread that made a system call, and otherwise the process's
+    /// leader.
+    pub tid: Pid,
+    /// When the span started.
+    pub start: SystemTime,
+    /// When the span ended, or `None` if it was still going.
+    pub end: Option<SystemTime>,
+    /// Numbers describing the span: a process's exit `code`, or the
+    /// `signal` that killed it and whether it dumped `core`, the `former`
+    /// thread ID of the thread that called an `exec`, and a system call's
+    /// arguments, `arg0` on, and `return` value.
+    pub attributes: Vec<(&'static str, i64)>,
+}
+
+/// Takes the spans of a [`Timeline`] from [`Timeline::export_spans`], for
+/// example to pass them on to an OpenTelemetry tracer.
+///
+/// This is implemented for closures that take a [`Span`].
+///
+/// [`Timeline`]: struct.Timeline.html
+/// [`Timeline::export_spans`]: struct.Timeline.html#method.export_spans
+/// [`Span`]: struct.Span.html
+pub trait SpanSink {
+    /// Take the next span. A span's parent is always passed before it.
+    fn span(&mut self, span: Span);
+}
+
+impl<F: FnMut(Span)> SpanSink for F {
+    fn span(&mut self, span: Span) {
+        self(span)
+    }
+}
+
+/// The names of the attributes of a system call's arguments.
+const ARG_NAMES: [&str; 6] = ["arg0", "arg1", "arg2", "arg3", "arg4", "arg5"];
+
+/// Records the system calls, signals, forks, execs and exits of the tracees
+/// in a session, and writes them as a timeline in the Chrome trace event
+/// format, which Perfetto and `about://tracing` display.
+///
+/// Feed every event to [`record`], including exits, or let [`run`] drive a
+/// whole [`TraceSession`], then call [`write_json`], or [`export_spans`] to
+/// pass the lives of the processes and their system calls on as spans,
+/// such as to OpenTelemetry. Each thread gets a
+/// lane, grouped by process and named after its `comm` as it was last
+/// seen. System calls are spans from their entry to their exit, with their
+/// arguments and return value, so the session must trace system calls for
+/// them to appear, and the rest are instant events. Times are taken in the
+/// tracer as each stop is seen, so the spans include some tracing overhead.
+///
+/// [`record`]: #method.record
+/// [`run`]: #method.run
+/// [`write_json`]: #method.write_json
+/// [`export_spans`]: #method.export_spans
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug)]
+pub struct Timeline {
+    start: Instant,
+    /// The time of day at `start`.
+    wall_start: SystemTime,
+    in_flight: HashMap<Pid, Call>,
+    tgids: HashMap<Pid, Pid>,
+    names: HashMap<Pid, String>,
+    marks: Vec<(Pid, Mark)>,
+    lives: Vec<Life>,
+    /// The lives of the processes that haven't exited, by pid.
+    living: HashMap<Pid, usize>,
+}
+
+impl Default for Timeline {
+    fn default() -> Timeline {
+        Timeline {
+            start: Instant::now(),
+            wall_start: SystemTime::now(),
+            in_flight: HashMap::new(),
+            tgids: HashMap::new(),
+            names: HashMap::new(),
+            marks: vec![],
+            lives: vec![],
+            living: HashMap::new(),
+        }
+    }
+}
+
+impl Timeline {
+    /// Create an empty timeline, with times measured from now.
+    pub fn new() -> Timeline {
+        Timeline::default()
+    }
+
+    /// Record `event`, reported by thread `pid`, as happening now.
+    ///
+    /// Call this for every event, before resuming the tracee.
+    pub fn record(&mut self, pid: Pid, event: Event) -> io::Result<()> {
+        self.record_at(pid, event, Instant::now())
+    }
+
+    /// Record `event`, reported by thread `pid`, as happening at `time`,
+    /// such as the [`TraceSession::event_time`] it was collected at.
+    ///
+    /// [`TraceSession::event_time`]: struct.TraceSession.html#method.event_time
+    pub fn record_at(&mut self, pid: Pid, event: Event, time: Instant) -> io::Result<()> {
+        let time = time.saturating_duration_since(self.start);
+        self.name(pid);
+        self.begin_life(pid, None, time);
+        let (category, name, args) = match event {
+            Event::Syscall => return self.record_syscall(pid, time),
+            Event::Signal(signal) => ("signal", signal.as_str(), vec![]),
+            Event::Fork(child) | Event::Vfork(child) | Event::Clone(child) => {
+                let name = match event {
+                    Event::Fork(_) => "fork",
+                    Event::Vfork(_) => "vfork",
+                    _ => "clone",
+                };
+                self.name(child);
+                let parent = self.living.get(&self.tgid(pid)).copied();
+                self.begin_life(child, parent, time);
+                // The child's own first event may have come first.
+                if let (Some(parent), Some(&life)) = (parent, self.living.get(&child)) {
+                    if parent < life {
+                        self.lives[life].parent.get_or_insert(parent);
+                    }
+                }
+                ("process", name, vec![("child", child.as_raw() as i64)])
+            }
+            Event::Exec(former) => {
+                // The thread that called exec took over the leader's pid, and
+                // returns from it there.
+                if former != pid {
+                    self.in_flight.remove(&pid);
+                    if let Some(call) = self.in_flight.remove(&former) {
+                        self.in_flight.insert(pid, call);
+                    }
+                }
+                self.names.remove(&pid);
+                self.name(pid);
+                if let Some(&life) = self.living.get(&pid) {
+                    let name = self.names.get(&pid).cloned().unwrap_or_default();
+                    self.lives[life].execs.push((time, name, former));
+                }
+                ("process", "exec", vec![("former", former.as_raw() as i64)])
+            }
+            Event::Exited(code) => {
+                self.end_call(pid, time, None);
+                self.end_life(pid, time, vec![("code", code as i64)]);
+                ("process", "exit", vec![("code", code as i64)])
+            }
+            Event::Signaled(signal, core) => {
+                self.end_call(pid, time, None);
+                let how = vec![("signal", signal as i64), ("core", core as i64)];
+                self.end_life(pid, time, how);
+                ("signal", signal.as_str(), vec![("core", core as i64)])
+            }
+            _ => return Ok(()),
+        };
+        self.marks.push((
+            pid,
+            Mark::Instant {
+                time,
+                category,
+                name: name.to_owned(),
+                args,
+            },
+        ));
+        Ok(())
+    }
+
+    /// Record the events of the tracees in `session` with
+    /// [`TraceSession::run`] until they have all exited, at the times their
+    /// statuses were collected.
+    ///
+    /// System calls are only recorded if the session traces them, with
+    /// [`trace_syscalls`], and children only if it has [`follow_forks`].
+    /// Threads are named with the [`Tracee::comm`] the session read when it
+    /// added them, unless they were seen before.
+    ///
+    /// [`TraceSession::run`]: struct.TraceSession.html#method.run
+    /// [`Tracee::comm`]: struct.Tracee.html#method.comm
+    /// [`trace_syscalls`]: struct.TraceSession.html#method.trace_syscalls
+    /// [`follow_forks`]: struct.TraceSession.html#method.follow_forks
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        session.run(|session, pid, event| {
+            let time = session.event_time().unwrap_or_else(Instant::now);
+            // The session read the thread's name when it was added, which a
+            // thread that is gone by its first event can't be looked up for.
+            if let Some(comm) = session.get(pid).and_then(Tracee::comm) {
+                self.names.entry(pid).or_insert_with(|| comm.to_owned());
+            }
+            self.record_at(pid, event, time)
+        })
+    }
+
+    /// Write the timeline to `out` as a JSON object in the Chrome trace
+    /// event format.
+    ///
+    /// System calls that a thread exited in, like the `exit_group` that
+    /// ended a process, end at the exit, without a return value, and those
+    /// still in progress are begun without an end.
+    pub fn write_json<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+        let mut first = true;
+        let mut names: Vec<_> = self.names.iter().collect();
+        names.sort();
+        for (&tid, name) in names {
+            let pid = self.tgids.get(&tid).copied().unwrap_or(tid);
+            // A process is named after its leader.
+            let kinds: &[&str] = if pid == tid {
+                &["process_name", "thread_name"]
+            } else {
+                &["thread_name"]
+            };
+            for kind in kinds {
+                separate(&mut out, &mut first)?;
+                write!(
+                    out,
+                    "{{\"ph\":\"M\",\"name\":\"{}\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":{}}}}}",
+                    kind,
+                    pid,
+                    tid,
+                    json_string(name)
+                )?;
+            }
+        }
+        for (tid, mark) in &self.marks {
+            let pid = self.tgids.get(tid).copied().unwrap_or(*tid);
+            separate(&mut out, &mut first)?;
+            match mark {
+                Mark::Syscall { call, end, value } => {
+                    let value = match value {
+                        Some(value) => format!(",\"return\":{}", value),
+                        None => String::new(),
+                    };
+                    write!(
+                        out,
+                        "{{\"ph\":\"X\",\"cat\":\"syscall\",\"name\":{},\"pid\":{},\"tid\":{},\"ts\":{},\"dur\":{},\"args\":{{{}{}}}}}",
+                        json_string(&syscall_label(call.number)),
+                        pid,
+                        tid,
+                        micros(call.start),
+                        micros(end.saturating_sub(call.start)),
+                        call_args(call),
+                        value
+                    )?;
+                }
+                Mark::Instant {
+                    time,
+                    category,
+                    name,
+                    args,
+                } => {
+                    let args: Vec<String> = args
+                        .iter()
+                        .map(|(key, value)| format!("\"{}\":{}", key, value))
+                        .collect();
+                    write!(
+                        out,
+                        "{{\"ph\":\"i\",\"s\":\"t\",\"cat\":\"{}\",\"name\":{},\"pid\":{},\"tid\":{},\"ts\":{},\"args\":{{{}}}}}",
+                        category,
+                        json_string(name),
+                        pid,
+                        tid,
+                        micros(*time),
+                        args.join(",")
+                    )?;
+                }
+            }
+        }
+        let mut in_flight: Vec<_> = self.in_flight.iter().collect();
+        in_flight.sort_by_key(|(_, call)| call.start);
+        for (tid, call) in in_flight {
+            let pid = self.tgids.get(tid).copied().unwrap_or(*tid);
+            separate(&mut out, &mut first)?;
+            write!(
+                out,
+                "{{\"ph\":\"B\",\"cat\":\"syscall\",\"name\":{},\"pid\":{},\"tid\":{},\"ts\":{},\"args\":{{{}}}}}",
+                json_string(&syscall_label(call.number)),
+                pid,
+                tid,
+                micros(call.start),
+                call_args(call)
+            )?;
+        }
+        writeln!(out, "\n]}}")?;
+        out.flush()
+    }
+
+    /// Pass the timeline to `sink` as spans: one for each process, one for
+    /// each program it ran with `exec` within that, and one for each system
+    /// call that took at least `min_syscall`, if it is given.
+    ///
+    /// The processes a process forked are within its span, and each system
+    /// call is within the program or process that made it.
+    pub fn export_spans<S: SpanSink>(&self, sink: &mut S, min_syscall: Option<Duration>) {
+        let wall = |time: Duration| self.wall_start + time;
+        let mut next_id = 0;
+        // The IDs of each life's span and those of its execs.
+        let mut ids: Vec<(u64, Vec<u64>)> = vec![];
+        for life in &self.lives {
+            let id = next_id;
+            next_id += 1;
+            let end = life.end.as_ref().map(|(end, _)| *end);
+            sink.span(Span {
+                id,
+                parent: life.parent.map(|parent| ids[parent].0),
+                kind: SpanKind::Process,
+                name: life.name.clone(),
+                pid: life.pid,
+                tid: life.pid,
+                start: wall(life.start),
+                end: end.map(wall),
+                attributes: life.end.iter().flat_map(|(_, how)| how.clone()).collect(),
+            });
+            let mut exec_ids = vec![];
+            for (i, (start, name, former)) in life.execs.iter().enumerate() {
+                let exec_end = life.execs.get(i + 1).map(|(next, _, _)| *next).or(end);
+                exec_ids.push(next_id);
+                sink.span(Span {
+                    id: next_id,
+                    parent: Some(id),
+                    kind: SpanKind::Exec,
+                    name: name.clone(),
+                    pid: life.pid,
+                    tid: life.pid,
+                    start: wall(*start),
+                    end: exec_end.map(wall),
+                    attributes: vec![("former", former.as_raw() as i64)],
+                });
+                next_id += 1;
+            }
+            ids.push((id, exec_ids));
+        }
+        let min_syscall = match min_syscall {
+            Some(min_syscall) => min_syscall,
+            None => return,
+        };
+        for (tid, mark) in &self.marks {
+            let (call, end, value) = match mark {
+                Mark::Syscall { call, end, value } => (call, *end, *value),
+                Mark::Instant { .. } => continue,
+            };
+            if end.saturating_sub(call.start) < min_syscall {
+                continue;
+            }
+            let pid = self.tgids.get(tid).copied().unwrap_or(*tid);
+            let parent = self.life_at(pid, call.start).map(|life| {
+                let (id, exec_ids) = &ids[life];
+                let execs = &self.lives[life].execs;
+                match execs.iter().rposition(|(time, _, _)| *time <= call.start) {
+                    Some(exec) => exec_ids[exec],
+                    None => *id,
+                }
+            });
+            let mut attributes: Vec<_> = ARG_NAMES
+                .iter()
+                .zip(&call.args)
+                .map(|(&name, &arg)| (name, arg as i64))
+                .collect();
+            attributes.extend(value.map(|value| ("return", value)));
+            sink.span(Span {
+                id: next_id,
+                parent,
+                kind: SpanKind::Syscall,
+                name: syscall_label(call.number),
+                pid,
+                tid: *tid,
+                start: wall(call.start),
+                end: Some(wall(end)),
+                attributes,
+            });
+            next_id += 1;
+        }
+    }
+
+    fn record_syscall(&mut self, tid: Pid, time: Duration) -> io::Result<()> {
+        match syscall::syscall_info(tid)? {
+            SyscallInfo::Entry { number, args } => {
+                let count = SyscallTable::native()
+                    .and_then(|table| table.arg_count(number))
+                    .unwrap_or(6);
+                let call = Call {
+                    number,
+                    args: args[..count].to_vec(),
+                    start: time,
+                };
+                self.in_flight.insert(tid, call);
+            }
+            SyscallInfo::Exit { value, .. } => self.end_call(tid, time, Some(value)),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// End the system call thread `tid` is in, if any, at `time`.
+    fn end_call(&mut self, tid: Pid, time: Duration, value: Option<i64>) {
+        if let Some(call) = self.in_flight.remove(&tid) {
+            self.marks.push((
+                tid,
+                Mark::Syscall {
+                    call,
+                    end: time,
+                    value,
+                },
+            ));
+        }
+    }
+
+    /// Start the life of process `pid` at `time`, forked by the process with
+    /// life `parent`, unless it has already started or `pid` is a thread
+    /// that isn't its process's leader.
+    fn begin_life(&mut self, pid: Pid, parent: Option<usize>, time: Duration) {
+        if self.tgid(pid) != pid || self.living.contains_key(&pid) {
+            return;
+        }
+        self.living.insert(pid, self.lives.len());
+        self.lives.push(Life {
+            pid,
+            parent,
+            start: time,
+            name: self.names.get(&pid).cloned().unwrap_or_default(),
+            execs: vec![],
+            end: None,
+        });
+    }
+
+    /// End the life of process `pid` at `time`, if it is a process, with
+    /// attributes saying `how`.
+    fn end_life(&mut self, pid: Pid, time: Duration, how: Vec<(&'static str, i64)>) {
+        if let Some(life) = self.living.remove(&pid) {
+            self.lives[life].end = Some((time, how));
+        }
+    }
+
+    /// The life of process `pid` that `time` falls in.
+    fn life_at(&self, pid: Pid, time: Duration) -> Option<usize> {
+        self.lives.iter().rposition(|life| {
+            life.pid == pid
+                && life.start <= time
+                && life.end.as_ref().is_none_or(|(end, _)| *end >= time)
+        })
+    }
+
+    /// The process that thread `tid` belongs to, as far as it is known.
+    fn tgid(&self, tid: Pid) -> Pid {
+        self.tgids.get(&tid).copied().unwrap_or(tid)
+    }
+
+    /// Remember the process and name of thread `tid`, if they aren't known.
+    fn name(&mut self, tid: Pid) {
+        if let Entry::Vacant(entry) = self.tgids.entry(tid) {
+            if let Some(pid) = read_tgid(tid) {
+                entry.insert(pid);
+            }
+        }
+        if let Entry::Vacant(entry) = self.names.entry(tid) {
+            if let Ok(comm) = fs::read_to_string(format!("/proc/{}/comm", tid)) {
+                entry.insert(comm.trim_end().to_owned());
+            }
+        }
+    }
+}
+
+/// Write the comma between trace events, unless this is the first.
+fn separate<W: Write>(out: &mut W, first: &mut bool) -> io::Result<()> {
+    if !*first {
+        writeln!(out, ",")?;
+    }
+    *first = false;
+    Ok(())
+}
+
+/// The name of system call `number`, or its number if it has none.
+fn syscall_label(number: u64) -> String {
+    match crate::syscall_name(number) {
+        Some(name) => name.to_owned(),
+        None => format!("syscall {}", number),
+    }
+}
+
+/// The arguments of `call` as a JSON member, in hexadecimal.
+fn call_args(call: &Call) -> String {
+    let args: Vec<String> = call
+        .args
+        .iter()
+        .map(|arg| format!("\"{:#x}\"", arg))
+        .collect();
+    format!("\"args\":[{}]", args.join(","))
+}
+
+/// `duration` in microseconds, which trace event times are in.
+fn micros(duration: Duration) -> String {
+    let nanos = duration.as_nanos();
+    format!("{}.{:03}", nanos / 1000, nanos % 1000)
+}
+
+/// `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use std::process::Command;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a \"b\"\\\n"), "\"a \\\"b\\\"\\\\\\u000a\"");
+        assert_eq!(micros(Duration::from_nanos(1_234_005)), "1234.005");
+    }
+
+    #[test]
+    fn test_timeline() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls().follow_forks();
+        let pid = session
+            .spawn(Command::new(&path).arg("fork"), SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut timeline = Timeline::new();
+        timeline
+            .run(&mut session)
+            .expect("Error recording timeline");
+        let mut out = vec![];
+        timeline
+            .write_json(&mut out)
+            .expect("Error writing timeline");
+        let out = String::from_utf8(out).expect("Timeline isn't UTF-8");
+        assert!(out.starts_with("{\"displayTimeUnit\":\"ns\",\"traceEvents\":[\n"));
+        assert!(out.ends_with("\n]}\n"));
+        let events: Vec<&str> = out
+            .lines()
+            .skip(1)
+            .filter(|line| line.starts_with('{'))
+            .collect();
+        let lane = format!("\"pid\":{},\"tid\":{}", pid, pid);
+        assert!(events
+            .iter()
+            .any(|e| e.contains("\"process_name\"") && e.contains(&lane)));
+        let fork = events
+            .iter()
+            .find(|e| e.contains("\"cat\":\"process\",\"name\":\"fork\"") && e.contains(&lane))
+            .expect("No fork recorded");
+        let child = fork
+            .split("\"child\":")
+            .nth(1)
+            .and_then(|rest| rest.split('}').next())
+            .expect("No child in fork");
+        let child_lane = format!("\"pid\":{},\"tid\":{}", child, child);
+        assert!(events
+            .iter()
+            .any(|e| e.contains("\"name\":\"exit\"") && e.contains(&child_lane)));
+        assert!(events.iter().any(|e| e
+            .contains("\"ph\":\"X\",\"cat\":\"syscall\",\"name\":\"wait4\"")
+            && e.contains(&lane)));
+        // Each process ends in an exit_group that never returns.
+        for lane in &[lane, child_lane] {
+            assert!(events.iter().any(|e| e
+                .contains("\"ph\":\"X\",\"cat\":\"syscall\",\"name\":\"exit_group\"")
+                && e.contains(lane)
+                && !e.contains("\"return\"")));
+        }
+        assert!(!events.iter().any(|e| e.contains("\"ph\":\"B\"")));
+    }
+
+    #[test]
+    fn test_export_spans() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session.trace_syscalls().follow_forks();
+        // The test program runs itself again, then forks.
+        let pid = session
+            .spawn(
+                Command::new(&path).args(["exec", "fork"]),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut timeline = Timeline::new();
+        timeline
+            .run(&mut session)
+            .expect("Error recording timeline");
+
+        let mut spans = vec![];
+        timeline.export_spans(&mut |span| spans.push(span), None);
+        let kinds: Vec<_> = spans.iter().map(|span| span.kind).collect();
+        assert_eq!(
+            kinds,
+            [SpanKind::Process, SpanKind::Exec, SpanKind::Process]
+        );
+        let (process, exec, child) = (&spans[0], &spans[1], &spans[2]);
+        assert_eq!((process.pid, process.parent), (pid, None));
+        assert_eq!(process.attributes, [("code", 0)]);
+        assert_eq!(exec.parent, Some(process.id));
+        assert_eq!(exec.end, process.end);
+        assert_eq!(exec.attributes, [("former", pid.as_raw() as i64)]);
+        assert_eq!(child.parent, Some(process.id));
+        assert_ne!(child.pid, pid);
+        assert!(exec.start <= child.start && child.end <= process.end);
+
+        // Every system call comes after the spans it is part of.
+        let mut spans = vec![];
+        timeline.export_spans(&mut |span| spans.push(span), Some(Duration::ZERO));
+        let wait = spans
+            .iter()
+            .find(|span| span.kind == SpanKind::Syscall && span.name == "wait4")
+            .expect("No wait4 exported");
+        assert_eq!(wait.parent, Some(exec.id));
+        assert_eq!(wait.attributes[0], ("arg0", child.pid.as_raw() as i64));
+        assert!(wait
+            .attributes
+            .contains(&("return", child.pid.as_raw() as i64)));
+        let exits = spans
+            .iter()
+            .filter(|span| span.kind == SpanKind::Syscall && span.name == "exit_group");
+        assert_eq!(exits.count(), 2);
+        let ids: Vec<u64> = spans.iter().map(|span| span.id).collect();
+        for (i, span) in spans.iter().enumerate() {
+            if let Some(parent) = span.parent {
+                assert!(ids[..i].contains(&parent));
+            }
+        }
+    }
+}
@@ -0,0 +1,835 @@
+#[cfg(all(feature = "disassembly", target_arch = "x86_64"))]
+use crate::insn::MAX_INSN_LEN;
+#[cfg(all(feature = "disassembly", target_arch = "x86_64"))]
+use crate::Instruction;
+#[cfg(target_arch = "x86_64")]
+use crate::{abi, thread_area, Abi, Checkpoint, RemoteAllocation, ThreadArea, XState};
+use crate::{
+    cleanup, maps, memory, nix_error, options, permission, pod, session, syscall, wait_error,
+    waitid, DropPolicy, Error, Event, ExecCredentials, MemoryCache, MemoryMap, MemoryStrategy,
+    Outcome, OutputCapture, PerfCounters, PidFd, Pod, Stats, SyscallInfo, TracedOutput, Vdso,
+    WaitInfo,
+};
+#[cfg(all(feature = "image-export", target_arch = "x86_64"))]
+use crate::{image, ImageSink};
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::fs::File;
+use std::io;
+use std::mem;
+#[cfg(target_arch = "x86_64")]
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(target_arch = "x86_64")]
+use std::path::Path;
+use std::process::Child;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// A process being traced with ptrace.
+///
+/// For a child spawned by this crate this holds the `std::process::Child`
+/// along with the wait status that was observed when the child first
+/// stopped, so you can inspect exactly what the initial stop looked like.
+/// Tracees that were automatically attached by a [`TraceSession`], such as
+/// forked children, have no `Child`.
+///
+/// # Reaping
+///
+/// The `Tracee`, or the `TraceSession` holding it, owns waiting for the
+/// process: every stop is reported to exactly one wait, so a wait anywhere
+/// else steals it. Don't call `Child::wait` or `Child::try_wait` while the
+/// process is traced, and don't reap children in a `SIGCHLD` handler or
+/// ignore `SIGCHLD`. If that happens anyway, waits in this crate fail with
+/// [`Error::ChildReaped`] or [`Error::SigchldIgnored`] rather than a bare
+/// `ECHILD`. Once [`into_child`] has given up the `Child`, it is the
+/// caller's to wait for.
+///
+/// [`TraceSession`]: struct.TraceSession.html
+/// [`Error::ChildReaped`]: enum.Error.html#variant.ChildReaped
+/// [`Error::SigchldIgnored`]: enum.Error.html#variant.SigchldIgnored
+/// [`into_child`]: #method.into_child
+#[derive(Debug)]
+pub struct Tracee {
+    pid: Pid,
+    child: Option<Child>,
+    initial_status: WaitStatus,
+    pidfd: Option<PidFd>,
+    pty_master: Option<File>,
+    foreground: Option<(RawFd, Pid)>,
+    mem: Mutex<memory::MemFile>,
+    perf_counters: Option<PerfCounters>,
+    drop_policy: DropPolicy,
+    vfork_shared: Option<Pid>,
+    stats: Stats,
+    memory_read: AtomicU64,
+    /// The name when the tracee was added or last called `exec`.
+    comm: Option<String>,
+}
+
+impl Tracee {
+    pub(crate) fn new(child: Child, initial_status: WaitStatus) -> Tracee {
+        let mut tracee = Tracee::attached(Pid::from_raw(child.id() as i32), initial_status);
+        tracee.child = Some(child);
+        tracee
+    }
+
+    /// Create a `Tracee` for a process that is already traced and stopped.
+    pub(crate) fn attached(pid: Pid, initial_status: WaitStatus) -> Tracee {
+        // The process can't have been reaped yet, so its pid is still valid.
+        let pidfd = PidFd::open(pid).ok();
+        Tracee {
+            pid,
+            child: None,
+            initial_status,
+            pidfd,
+            pty_master: None,
+            foreground: None,
+            mem: Mutex::default(),
+            perf_counters: None,
+            drop_policy: DropPolicy::Leave,
+            vfork_shared: None,
+            stats: Stats::default(),
+            memory_read: AtomicU64::new(0),
+            comm: crate::thread_name(pid).ok(),
+        }
+    }
+
+    /// The process ID of the tracee.
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// The wait status observed when the child first stopped.
+    pub fn initial_status(&self) -> WaitStatus {
+        self.initial_status
+    }
+
+    /// A pidfd referring to the tracee.
+    ///
+    /// This is opened when the tracee is first stopped, and is `None` if the
+    /// running kernel does not support pidfds.
+    pub fn pidfd(&self) -> Option<&PidFd> {
+        self.pidfd.as_ref()
+    }
+
+    /// The master side of the tracee's pseudo-terminal, if it was spawned with
+    /// [`SpawnOptions::pty`].
+    ///
+    /// Reading from it returns the tracee's output, and writing to it sends
+    /// input. Once the tracee and any children sharing the terminal have
+    /// exited, reads fail with `EIO`.
+    ///
+    /// [`SpawnOptions::pty`]: struct.SpawnOptions.html#method.pty
+    pub fn pty_master(&self) -> Option<&File> {
+        self.pty_master.as_ref()
+    }
+
+    /// Take ownership of the master side of the tracee's pseudo-terminal.
+    pub fn take_pty_master(&mut self) -> Option<File> {
+        self.pty_master.take()
+    }
+
+    pub(crate) fn set_pty_master(&mut self, master: File) {
+        self.pty_master = Some(master);
+    }
+
+    /// Make the process group that was in the foreground before spawning,
+    /// normally the tracer's, the terminal's foreground group again.
+    ///
+    /// Does nothing unless the tracee was spawned with
+    /// [`SpawnOptions::foreground`].
+    ///
+    /// [`SpawnOptions::foreground`]: struct.SpawnOptions.html#method.foreground
+    pub fn restore_foreground(&self) -> io::Result<()> {
+        match self.foreground {
+            Some((fd, pgrp)) => options::set_foreground(fd, pgrp),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn set_previous_foreground(&mut self, fd: RawFd, pgrp: Pid) {
+        self.foreground = Some((fd, pgrp));
+    }
+
+    /// The performance counters opened with [`SpawnOptions::perf_counters`].
+    ///
+    /// [`SpawnOptions::perf_counters`]: struct.SpawnOptions.html#method.perf_counters
+    pub fn perf_counters(&self) -> Option<&PerfCounters> {
+        self.perf_counters.as_ref()
+    }
+
+    /// Take ownership of the tracee's performance counters, for example to
+    /// read them after [`wait_with_output`], which consumes the tracee.
+    ///
+    /// [`wait_with_output`]: #method.wait_with_output
+    pub fn take_perf_counters(&mut self) -> Option<PerfCounters> {
+        self.perf_counters.take()
+    }
+
+    pub(crate) fn set_perf_counters(&mut self, perf_counters: PerfCounters) {
+        self.perf_counters = Some(perf_counters);
+    }
+
+    /// What happens to the tracee when this is dropped.
+    pub fn drop_policy(&self) -> DropPolicy {
+        self.drop_policy
+    }
+
+    /// Set what happens to the tracee when this is dropped, including while
+    /// unwinding from a panic. See [`install_panic_hook`] for panics that
+    /// don't drop it.
+    ///
+    /// Call this from the thread that traces the tracee.
+    ///
+    /// [`install_panic_hook`]: fn.install_panic_hook.html
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        let pidfd = self.pidfd.as_ref().map(|pidfd| pidfd.as_raw_fd());
+        cleanup::guard(self.pid, pidfd, policy);
+        self.drop_policy = policy;
+    }
+
+    /// Check for a state change in the tracee without blocking.
+    ///
+    /// Returns `Ok(None)` if the tracee has not stopped or exited since the
+    /// last time its status was collected.
+    pub fn try_wait(&self) -> io::Result<Option<WaitStatus>> {
+        match waitpid(self.pid(), Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL)) {
+            Ok(WaitStatus::StillAlive) => Ok(None),
+            Ok(status) => Ok(Some(status)),
+            Err(e) => Err(wait_error(e)),
+        }
+    }
+
+    /// Wait for a state change in the tracee with `waitid`, which reports
+    /// the full `siginfo_t` rather than the `waitpid` encoding.
+    ///
+    /// `flags` may include `WNOHANG`, to return `Ok(None)` instead of
+    /// blocking, and `WNOWAIT`, to leave the change to be collected again
+    /// by a later wait. If it selects none of `WEXITED`, `WSTOPPED` and
+    /// `WCONTINUED`, exits and stops are reported.
+    pub fn wait_info(&self, flags: WaitPidFlag) -> io::Result<Option<WaitInfo>> {
+        waitid::waitid(self.pid, flags)
+    }
+
+    /// The tracee's name, from `/proc/<pid>/task/<tid>/comm`.
+    ///
+    /// Each thread has its own name, so for a thread attached by a
+    /// [`TraceSession`] this is the name it was given with, for example,
+    /// `pthread_setname_np`. Threads are named after their process unless
+    /// they are renamed.
+    ///
+    /// [`TraceSession`]: struct.TraceSession.html
+    pub fn name(&self) -> io::Result<String> {
+        crate::thread_name(self.pid)
+    }
+
+    /// The tracee's name as it was when it was added to the session, or
+    /// when it last called `exec`, or `None` if it couldn't be read.
+    ///
+    /// Unlike [`name`], this doesn't read `/proc` again, so it is still
+    /// there once the tracee has exited, but it doesn't follow renames.
+    ///
+    /// [`name`]: #method.name
+    pub fn comm(&self) -> Option<&str> {
+        self.comm.as_deref()
+    }
+
+    /// Read the tracee's name again, after an `exec`.
+    pub(crate) fn update_comm(&mut self) {
+        self.comm = crate::thread_name(self.pid).ok();
+    }
+
+    /// Rename the tracee, by making it call `prctl` with `PR_SET_NAME`.
+    ///
+    /// Only a thread's own process may write its `/proc/<pid>/comm`, so the
+    /// system call is injected instead, and the tracee must be stopped. The
+    /// name is truncated to 15 bytes. Names containing a NUL byte fail with
+    /// `ErrorKind::InvalidInput`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_name(&self, name: &str) -> io::Result<()> {
+        crate::inject::set_name(self.pid, name)
+    }
+
+    /// Take a checkpoint of the tracee, by making it fork a frozen snapshot
+    /// of itself that fresh copies can be restored from later. See
+    /// [`Checkpoint`].
+    ///
+    /// The tracee must be stopped, and is left stopped where it was.
+    ///
+    /// [`Checkpoint`]: struct.Checkpoint.html
+    #[cfg(target_arch = "x86_64")]
+    pub fn checkpoint(&self) -> io::Result<Checkpoint> {
+        Checkpoint::new(self.pid)
+    }
+
+    /// Set the tracee's `oom_score_adj`, from -1000 to 1000.
+    pub fn set_oom_score_adj(&self, adj: i32) -> io::Result<()> {
+        crate::set_oom_score_adj(self.pid, adj)
+    }
+
+    /// Read the tracee's general-purpose registers.
+    ///
+    /// The tracee must be stopped.
+    #[cfg(target_arch = "x86_64")]
+    pub fn registers(&self) -> io::Result<libc::user_regs_struct> {
+        ptrace::getregs(self.pid).map_err(nix_error)
+    }
+
+    /// Set the tracee's general-purpose registers.
+    ///
+    /// The tracee must be stopped.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_registers(&self, regs: libc::user_regs_struct) -> io::Result<()> {
+        ptrace::setregs(self.pid, regs).map_err(nix_error)
+    }
+
+    /// The ABI the tracee runs under, which says how to read its registers
+    /// and memory.
+    ///
+    /// This can change when the tracee calls `exec`, so check it again after
+    /// `Event::Exec`. The tracee must be stopped.
+    #[cfg(target_arch = "x86_64")]
+    pub fn abi(&self) -> io::Result<Abi> {
+        abi::abi(self.pid)
+    }
+
+    /// Read the tracee's complete FPU and SIMD state with `NT_X86_XSTATE`.
+    ///
+    /// Fails with `Error::Unsupported` if the CPU has no `XSAVE`. The tracee
+    /// must be stopped.
+    #[cfg(target_arch = "x86_64")]
+    pub fn xstate(&self) -> io::Result<XState> {
+        XState::read(self.pid)
+    }
+
+    /// Set the tracee's FPU and SIMD state, usually to one read with
+    /// [`xstate`] and modified.
+    ///
+    /// The tracee must be stopped.
+    ///
+    /// [`xstate`]: #method.xstate
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_xstate(&self, xstate: &XState) -> io::Result<()> {
+        xstate.write(self.pid)
+    }
+
+    /// Read the tracee's TLS segment descriptor for GDT entry `entry`, with
+    /// `PTRACE_GET_THREAD_AREA`.
+    ///
+    /// This is how a 32-bit tracee's thread-local storage is found. Entries
+    /// other than the TLS ones, 12 to 14 on x86-64, fail with
+    /// `ErrorKind::InvalidInput`. The tracee must be stopped.
+    #[cfg(target_arch = "x86_64")]
+    pub fn thread_area(&self, entry: u32) -> io::Result<ThreadArea> {
+        thread_area::get(self.pid, entry)
+    }
+
+    /// Set the tracee's TLS segment descriptor for `area.entry_number`,
+    /// with `PTRACE_SET_THREAD_AREA`.
+    ///
+    /// The tracee must be stopped.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_thread_area(&self, area: &ThreadArea) -> io::Result<()> {
+        thread_area::set(self.pid, area)
+    }
+
+    /// Read `buf.len()` bytes of the tracee's memory starting at `addr`.
+    ///
+    /// Large reads use `/proc/<pid>/mem`, which is opened on first use and
+    /// kept open. Anything that can't be read that way falls back to
+    /// `process_vm_readv`, then `PTRACE_PEEKDATA`. The tracee must be stopped.
+    pub fn read_memory(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.mem().read(self.pid, addr, buf)?;
+        self.count_read(buf.len());
+        Ok(())
+    }
+
+    /// Read the NUL-terminated string at `addr` in the tracee's memory,
+    /// without the NUL, reading at most `max_len` bytes.
+    ///
+    /// Only the pages the string covers are read, a page at a time. A
+    /// string that runs into unreadable memory before its NUL is cut off
+    /// there; if nothing at `addr` can be read, this fails. The tracee must
+    /// be stopped.
+    pub fn read_cstring(&self, addr: u64, max_len: usize) -> io::Result<Vec<u8>> {
+        let string = memory::read_cstring(self.pid, addr, max_len)?;
+        self.count_read(string.len());
+        Ok(string)
+    }
+
+    /// Read a string like [`read_cstring`], replacing invalid UTF-8 with
+    /// U+FFFD.
+    ///
+    /// [`read_cstring`]: #method.read_cstring
+    pub fn read_string_lossy(&self, addr: u64, max_len: usize) -> io::Result<String> {
+        let bytes = self.read_cstring(addr, max_len)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Read a `T` from the tracee's memory at `addr`, which needn't be aligned
+    /// for `T`. The tracee must be stopped.
+    pub fn read_value<T: Pod>(&self, addr: u64) -> io::Result<T> {
+        let mut buf = vec![0; mem::size_of::<T>()];
+        self.read_memory(addr, &mut buf)?;
+        Ok(pod::from_bytes(&buf))
+    }
+
+    /// Write `value` into the tracee's memory at `addr`, which needn't be
+    /// aligned for `T`, like [`write_memory`].
+    ///
+    /// [`write_memory`]: #method.write_memory
+    pub fn write_value<T: Pod>(&self, addr: u64, value: &T) -> io::Result<()> {
+        self.write_memory(addr, pod::bytes_of(value))
+    }
+
+    /// Write `data` into the tracee's memory starting at `addr`.
+    ///
+    /// This can write to read-only mappings such as code, which makes it
+    /// suitable for patching instructions. It uses `/proc/<pid>/mem` where
+    /// possible, falling back to `PTRACE_POKEDATA`. The tracee must be
+    /// stopped.
+    ///
+    /// Fails with [`Error::VforkShared`] while the tracee shares its memory
+    /// with a process it vforked or was vforked by; see [`vfork_shared`].
+    ///
+    /// [`Error::VforkShared`]: enum.Error.html#variant.VforkShared
+    /// [`vfork_shared`]: #method.vfork_shared
+    pub fn write_memory(&self, addr: u64, data: &[u8]) -> io::Result<()> {
+        if let Some(other) = self.vfork_shared {
+            return Err(Error::VforkShared(other).into());
+        }
+        self.mem().write(self.pid, addr, data)
+    }
+
+    /// The strategy that completed the last [`read_memory`] or
+    /// [`write_memory`], or `None` if neither has been called.
+    ///
+    /// [`read_memory`]: #method.read_memory
+    /// [`write_memory`]: #method.write_memory
+    pub fn memory_strategy(&self) -> Option<MemoryStrategy> {
+        self.mem().last_strategy()
+    }
+
+    /// An empty [`MemoryCache`] of the tracee's memory, for making many
+    /// small reads and writes in few system calls while it is stopped.
+    ///
+    /// [`MemoryCache`]: struct.MemoryCache.html
+    pub fn memory_cache(&self) -> MemoryCache {
+        MemoryCache::new(self.pid)
+    }
+
+    fn count_read(&self, len: usize) {
+        self.memory_read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// The counts of the tracee's stops, if its session collects them, and
+    /// of the memory read through it.
+    ///
+    /// See [`Stats`] for what is counted.
+    ///
+    /// [`Stats`]: struct.Stats.html
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats.clone();
+        stats.memory_read = self.memory_read.load(Ordering::Relaxed);
+        stats
+    }
+
+    pub(crate) fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    fn mem(&self) -> MutexGuard<'_, memory::MemFile> {
+        self.mem.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Check what happened to the tracee's credentials at the `exec` it is
+    /// stopped for, such as its initial stop or `Event::Exec`.
+    ///
+    /// Executing a setuid or setgid program makes a tracee non-dumpable, so
+    /// a tracer without `CAP_SYS_PTRACE` can no longer open its
+    /// `/proc/<pid>/mem` or use `process_vm_readv` on it. This fails with
+    /// [`Error::NotDumpable`] in that case, rather than leaving later memory
+    /// accesses to fail with `EACCES` or fall back to slower methods. The
+    /// kernel normally keeps a tracer without the capability from causing
+    /// it, by withholding the credentials, which is reported as
+    /// `ExecCredentials::Withheld`.
+    ///
+    /// A [`TraceSession`] makes this check itself at every `Event::Exec`, so
+    /// this is mostly useful for the initial stop.
+    ///
+    /// [`Error::NotDumpable`]: enum.Error.html#variant.NotDumpable
+    /// [`TraceSession`]: struct.TraceSession.html
+    pub fn check_exec(&self) -> io::Result<ExecCredentials> {
+        permission::check_exec(self.pid)
+    }
+
+    /// The process the tracee shares its memory with because of a `vfork`,
+    /// as seen by the [`TraceSession`] it is in, or `None`.
+    ///
+    /// This is set for a parent from its `Event::Vfork` until its
+    /// `Event::VforkDone`, and for the child until it calls `exec` or exits.
+    /// Meanwhile [`write_memory`] fails with [`Error::VforkShared`], since
+    /// writing either process would change the other. Breakpoints and other
+    /// writes that don't go through the `Tracee` aren't checked.
+    ///
+    /// [`TraceSession`]: struct.TraceSession.html
+    /// [`write_memory`]: #method.write_memory
+    /// [`Error::VforkShared`]: enum.Error.html#variant.VforkShared
+    pub fn vfork_shared(&self) -> Option<Pid> {
+        self.vfork_shared
+    }
+
+    pub(crate) fn set_vfork_shared(&mut self, other: Option<Pid>) {
+        self.vfork_shared = other;
+    }
+
+    /// The tracee's memory mappings, as listed in `/proc/<pid>/maps`.
+    pub fn memory_maps(&self) -> io::Result<Vec<MemoryMap>> {
+        maps::read_maps(self.pid)
+    }
+
+    /// The tracee's vDSO and its symbols, or `None` if it has no vDSO.
+    ///
+    /// The tracee must be stopped.
+    pub fn vdso(&self) -> io::Result<Option<Vdso>> {
+        Vdso::read(self.pid)
+    }
+
+    /// Pass the tracee's registers, memory mappings and their contents, and
+    /// open file descriptors to `sink`, for example to write them as CRIU
+    /// images with [`CriuPages`]. See [`ImageSink`].
+    ///
+    /// Only this thread's registers are exported. The tracee must be
+    /// stopped, and is left stopped.
+    ///
+    /// [`CriuPages`]: struct.CriuPages.html
+    /// [`ImageSink`]: trait.ImageSink.html
+    #[cfg(all(feature = "image-export", target_arch = "x86_64"))]
+    pub fn export_image<S: ImageSink>(&self, sink: &mut S) -> io::Result<()> {
+        image::export(self, sink)
+    }
+
+    /// Decode the x86-64 instruction at `addr` in the tracee's memory.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if the code there isn't a valid
+    /// instruction, or runs into memory that can't be read first. The tracee
+    /// must be stopped.
+    #[cfg(all(feature = "disassembly", target_arch = "x86_64"))]
+    pub fn instruction_at(&self, addr: u64) -> io::Result<Instruction> {
+        let code = memory::read_available(self.pid, addr, MAX_INSN_LEN)?;
+        self.count_read(code.len());
+        Instruction::decode(&code)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid instruction"))
+    }
+
+    /// Decode the instruction at the tracee's program counter, the one it
+    /// executes next, like [`instruction_at`].
+    ///
+    /// [`instruction_at`]: #method.instruction_at
+    #[cfg(all(feature = "disassembly", target_arch = "x86_64"))]
+    pub fn next_instruction(&self) -> io::Result<Instruction> {
+        self.instruction_at(self.registers()?.rip)
+    }
+
+    /// Walk the tracee's stack using frame pointers.
+    ///
+    /// Returns the program counter followed by the return address of each
+    /// frame, innermost first, up to `max_depth` entries. Frames compiled
+    /// without frame pointers will cause the stack to be truncated.
+    ///
+    /// The tracee must be stopped.
+    #[cfg(target_arch = "x86_64")]
+    pub fn backtrace(&self, max_depth: usize) -> io::Result<Vec<u64>> {
+        let regs = self.registers()?;
+        crate::unwind::frame_pointer_backtrace(self.pid, &regs, max_depth)
+    }
+
+    /// Make the tracee call the function at `addr` with `args`, and return
+    /// the value it returns.
+    ///
+    /// Up to six integer or pointer arguments are passed in registers, as
+    /// the System V calling convention does; more fail with
+    /// `ErrorKind::InvalidInput`. The function is entered with the stack
+    /// aligned below the red zone and returns to address 0, and the fault
+    /// that causes marks the end of the call, so no code is patched. The
+    /// tracee's registers are restored afterwards, and any signals that
+    /// arrived during the call are sent to it again.
+    ///
+    /// The tracee must be stopped, and is left stopped. The call fails if
+    /// it crashes or hits a breakpoint, and the same deadlocks are possible
+    /// as with [`inject_library`], which is built on this.
+    ///
+    /// [`inject_library`]: #method.inject_library
+    #[cfg(target_arch = "x86_64")]
+    pub fn call_function(&self, addr: u64, args: &[u64]) -> io::Result<u64> {
+        crate::inject::call(self.pid, addr, args)
+    }
+
+    /// Change the protection of the tracee's pages covering `range` to
+    /// `prot`, a combination of `libc::PROT_READ`, `PROT_WRITE` and
+    /// `PROT_EXEC`, or `PROT_NONE`, by making it call `mprotect`.
+    ///
+    /// The range is widened to whole pages. Writes with [`write_memory`]
+    /// already work on read-only code, so this is for letting the tracee
+    /// itself write or execute memory, such as code patched in at run time.
+    /// Errors from `mprotect` are returned as they are, for example
+    /// `ENOMEM` if part of the range isn't mapped. The tracee must be
+    /// stopped, and its registers are restored afterwards.
+    ///
+    /// [`write_memory`]: #method.write_memory
+    #[cfg(target_arch = "x86_64")]
+    pub fn protect_memory(&self, range: Range<u64>, prot: libc::c_int) -> io::Result<()> {
+        crate::inject::protect(self.pid, range, prot)
+    }
+
+    /// Map `len` bytes of new, zeroed memory into the tracee with protection
+    /// `prot`, by making it call `mmap`, and return its address.
+    ///
+    /// The memory is private and anonymous, and page-aligned, for use as
+    /// scratch space for strings, trampolines or injected code. It stays
+    /// mapped until [`free`] or the tracee's next `exec`. Errors from
+    /// `mmap` are returned as they are. The tracee must be stopped, and its
+    /// registers are restored afterwards.
+    ///
+    /// [`free`]: #method.free
+    #[cfg(target_arch = "x86_64")]
+    pub fn allocate(&self, len: u64, prot: libc::c_int) -> io::Result<u64> {
+        crate::inject::allocate(self.pid, len, prot)
+    }
+
+    /// Map memory into the tracee like [`allocate`], returning a handle that
+    /// unmaps it again when it is dropped, so scratch buffers don't pile up
+    /// in long-running tracees.
+    ///
+    /// [`allocate`]: #method.allocate
+    #[cfg(target_arch = "x86_64")]
+    pub fn allocate_scoped(&self, len: u64, prot: libc::c_int) -> io::Result<RemoteAllocation<'_>> {
+        let addr = self.allocate(len, prot)?;
+        Ok(RemoteAllocation::new(self, addr, len))
+    }
+
+    /// Unmap `len` bytes of the tracee's memory at `addr`, such as memory
+    /// from [`allocate`], by making it call `munmap`.
+    ///
+    /// [`allocate`]: #method.allocate
+    #[cfg(target_arch = "x86_64")]
+    pub fn free(&self, addr: u64, len: u64) -> io::Result<()> {
+        crate::inject::deallocate(self.pid, addr, len)
+    }
+
+    /// Copy the machine code `code` into a new executable mapping in the
+    /// tracee and run it until it traps, returning the registers at the trap.
+    ///
+    /// The code starts with the tracee's current registers, apart from the
+    /// program counter and a stack pointer moved below the red zone and
+    /// aligned to 16 bytes. An `int3` is appended, so code that runs off its
+    /// end traps there; the code may also trap earlier with its own `int3`.
+    /// Afterwards the tracee's registers are restored, the mapping is
+    /// removed and any signals that arrived meanwhile are sent to it again.
+    /// If the code faults instead of trapping, this fails. The tracee must
+    /// be stopped, and is left stopped.
+    ///
+    /// # Warning
+    ///
+    /// Nothing checks what the code does. It runs with all of the tracee's
+    /// privileges, and anything it changes besides the registers, such as
+    /// memory, file descriptors, signal dispositions or the stack below the
+    /// red zone, stays changed. Code that jumps elsewhere, loops forever or
+    /// makes a system call that doesn't return leaves this waiting, and
+    /// code that stops the process in a way the tracee didn't expect can
+    /// corrupt it. The tracee's other threads keep running meanwhile, and
+    /// may see the mapping. Prefer [`call_function`] or [`inject_library`]
+    /// where they are enough.
+    ///
+    /// [`call_function`]: #method.call_function
+    /// [`inject_library`]: #method.inject_library
+    #[cfg(target_arch = "x86_64")]
+    pub fn run_code(&self, code: &[u8]) -> io::Result<libc::user_regs_struct> {
+        crate::inject::run_code(self.pid, code)
+    }
+
+    /// Load the shared library at `path` into the tracee by making it call
+    /// `dlopen`, and return the address the library was loaded at.
+    ///
+    /// The path is resolved by the tracer, so the tracee must see the same
+    /// filesystem. The tracee must be stopped with its libc already loaded,
+    /// which isn't the case at the initial `exec` trap of a dynamically
+    /// linked program; this fails with `ErrorKind::NotFound` then. Its
+    /// registers are restored afterwards, but the library's constructors
+    /// run in the tracee, along with any threads it had that weren't
+    /// stopped. `dlopen` may deadlock if the tracee stopped while holding a
+    /// lock it needs, such as inside `malloc` or the dynamic loader.
+    #[cfg(target_arch = "x86_64")]
+    pub fn inject_library<P: AsRef<Path>>(&self, path: P) -> io::Result<u64> {
+        crate::inject::load_library(self.pid, path.as_ref())
+    }
+
+    /// Details of the system call the tracee is stopped at.
+    pub fn syscall_info(&self) -> io::Result<SyscallInfo> {
+        syscall::syscall_info(self.pid)
+    }
+
+    /// Make the ptrace request `request` on the tracee, for requests this
+    /// crate doesn't wrap, returning the result of the call.
+    ///
+    /// `errno` is cleared first, so that requests such as `PTRACE_PEEKDATA`,
+    /// whose result may be -1, only fail if the kernel reported an error.
+    ///
+    /// # Safety
+    ///
+    /// `addr` and `data` must be valid for `request` as described in
+    /// `ptrace(2)`, for example pointing to buffers of the right size.
+    /// The request must also leave the tracee traced and stopped, or at least
+    /// in a state the caller keeps track of: the `Tracee`, and any
+    /// `TraceSession` it is in, aren't told about detaching, resuming or
+    /// changing options, and they will keep acting on what they last knew.
+    pub unsafe fn raw_request(
+        &self,
+        request: libc::c_uint,
+        addr: *mut libc::c_void,
+        data: *mut libc::c_void,
+    ) -> io::Result<libc::c_long> {
+        nix::errno::Errno::clear();
+        let ret = libc::ptrace(request, self.pid.as_raw(), addr, data);
+        if ret == -1 && nix::errno::errno() != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret)
+    }
+
+    /// A reference to the underlying `std::process::Child`, if this crate spawned the tracee.
+    pub fn child(&self) -> Option<&Child> {
+        self.child.as_ref()
+    }
+
+    /// A mutable reference to the underlying `std::process::Child`, if this
+    /// crate spawned the tracee.
+    ///
+    /// This can be used to take the child's stdio handles, or to kill it.
+    /// Waiting for it this way bypasses the `Tracee`; see [Reaping](#reaping).
+    pub fn child_mut(&mut self) -> Option<&mut Child> {
+        self.child.as_mut()
+    }
+
+    /// Start draining the tracee's piped stdout and stderr in the background.
+    ///
+    /// Returns `None` if this crate didn't spawn the tracee. See
+    /// [`OutputCapture`] for details.
+    ///
+    /// [`OutputCapture`]: struct.OutputCapture.html
+    pub fn capture_output(&mut self) -> Option<OutputCapture> {
+        self.child.as_mut().map(OutputCapture::new)
+    }
+
+    /// Resume the stopped tracee and let it run to completion, collecting its
+    /// piped stdout and stderr, like `Child::wait_with_output`.
+    ///
+    /// Its stops are handled as by [`run_to_exit`]. Because only this
+    /// process is waited for, any other traced threads or children it
+    /// creates are not resumed, so this should only be used for tracees that
+    /// don't have `PTRACE_O_TRACECLONE` or the fork options set.
+    ///
+    /// [`run_to_exit`]: #method.run_to_exit
+    pub fn wait_with_output(mut self) -> io::Result<TracedOutput> {
+        let capture = self
+            .capture_output()
+            .ok_or_else(|| io::Error::other("Tracee was not spawned by this crate"))?;
+        let (status, outcome) = self.run_to_status(|_, signal| Ok(Some(signal)))?;
+        let mut output = capture.finish(status)?;
+        output.outcome = outcome;
+        Ok(output)
+    }
+
+    /// Resume the stopped tracee and wait for it to exit, as its tracer, and
+    /// return how it finished.
+    ///
+    /// This is the loop a tracer that only wants to stay attached needs:
+    /// signals the tracee receives are passed on to it, and every other stop
+    /// is resumed without one. `SIGTRAP`s are never passed on, since ptrace
+    /// reports its own stops with them, such as the traps after an `exec`
+    /// without `PTRACE_O_TRACEEXEC`. Group-stops of tracees attached with
+    /// `PTRACE_SEIZE` are kept with `PTRACE_LISTEN` until the tracee gets
+    /// `SIGCONT`; other tracees can't stay group-stopped under ptrace, and
+    /// carry on.
+    ///
+    /// Like [`wait_with_output`], this only waits for this process, so it
+    /// shouldn't be used for tracees that have `PTRACE_O_TRACECLONE` or the
+    /// fork options set.
+    ///
+    /// [`wait_with_output`]: #method.wait_with_output
+    pub fn run_to_exit(&self) -> io::Result<Outcome> {
+        self.run_to_exit_with(|_, signal| Ok(Some(signal)))
+    }
+
+    /// Like [`run_to_exit`], but calling `on_signal` with each signal the
+    /// tracee is about to receive, other than `SIGTRAP`, which returns the
+    /// signal to deliver in its place, if any.
+    ///
+    /// [`run_to_exit`]: #method.run_to_exit
+    pub fn run_to_exit_with<F>(&self, on_signal: F) -> io::Result<Outcome>
+    where
+        F: FnMut(&Tracee, Signal) -> io::Result<Option<Signal>>,
+    {
+        self.run_to_status(on_signal).map(|(_, outcome)| outcome)
+    }
+
+    /// Like `run_to_exit_with`, also returning the final wait status.
+    pub(crate) fn run_to_status<F>(&self, mut on_signal: F) -> io::Result<(WaitStatus, Outcome)>
+    where
+        F: FnMut(&Tracee, Signal) -> io::Result<Option<Signal>>,
+    {
+        let mut resume = Some(None);
+        let mut stops = 0;
+        let mut signals = vec![];
+        loop {
+            if let Some(signal) = resume {
+                ptrace::cont(self.pid, signal).map_err(nix_error)?;
+            }
+            let status = waitpid(self.pid, Some(WaitPidFlag::__WALL)).map_err(wait_error)?;
+            if let Some(mut outcome) = Outcome::from_status(status) {
+                outcome.stops = stops;
+                outcome.signals = signals;
+                return Ok((status, outcome));
+            }
+            stops += 1;
+            resume = match status {
+                WaitStatus::Stopped(_, Signal::SIGTRAP) => Some(None),
+                // Group-stops have no siginfo, unlike signal-delivery stops.
+                WaitStatus::Stopped(_, signal) if ptrace::getsiginfo(self.pid).is_ok() => {
+                    signals.push(signal);
+                    Some(on_signal(self, signal)?)
+                }
+                WaitStatus::PtraceEvent(_, signal, session::PTRACE_EVENT_STOP)
+                    if session::classify_stop(signal) != Event::InterruptStop =>
+                {
+                    let ret = unsafe { libc::ptrace(libc::PTRACE_LISTEN, self.pid.as_raw(), 0, 0) };
+                    if ret < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    None
+                }
+                _ => Some(None),
+            };
+        }
+    }
+
+    /// Consume this `Tracee`, returning the underlying `std::process::Child`
+    /// if this crate spawned the tracee.
+    pub fn into_child(mut self) -> Option<Child> {
+        self.set_drop_policy(DropPolicy::Leave);
+        self.child.take()
+    }
+}
+
+impl Drop for Tracee {
+    fn drop(&mut self) {
+        if self.drop_policy != DropPolicy::Leave {
+            let pidfd = self.pidfd.as_ref().map(|pidfd| pidfd.as_raw_fd());
+            cleanup::guard(self.pid, pidfd, DropPolicy::Leave);
+            cleanup::apply(self.pid, pidfd, self.drop_policy);
+        }
+    }
+}
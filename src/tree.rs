@@ -0,0 +1,373 @@
+//! The tree of processes and threads created during a trace.
+
+use crate::accounting::read_tgid;
+use crate::{Event, TraceSession};
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// How a task in a [`ProcessTree`] came to exist.
+///
+/// [`ProcessTree`]: struct.ProcessTree.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Origin {
+    /// The task was already traced when the tree started following it.
+    Root,
+    /// The task was created by `fork`, or a `clone` without `CLONE_THREAD`.
+    Fork,
+    /// The task was created by `vfork`.
+    Vfork,
+    /// The task is a thread, created by a `clone` with `CLONE_THREAD`.
+    Thread,
+}
+
+/// How a task in a [`ProcessTree`] ended.
+///
+/// [`ProcessTree`]: struct.ProcessTree.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Exit {
+    /// The task exited normally with the given exit code.
+    Exited(i32),
+    /// The task was killed by the given signal.
+    Signaled(Signal),
+    /// The thread vanished without a status when it called `exec`, because
+    /// it took over the pid of its process's leader, whose node records the
+    /// `exec`.
+    Replaced,
+}
+
+/// A successful `exec` by a task in a [`ProcessTree`].
+///
+/// [`ProcessTree`]: struct.ProcessTree.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExecRecord {
+    /// When the `exec` completed, relative to when the tree started.
+    pub time: Duration,
+    /// The program that was executed, from `/proc/<pid>/exe`.
+    pub path: Option<PathBuf>,
+    /// The arguments it was executed with, from `/proc/<pid>/cmdline`.
+    pub args: Vec<OsString>,
+}
+
+/// A process or thread in a [`ProcessTree`].
+///
+/// [`ProcessTree`]: struct.ProcessTree.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskNode {
+    /// The task's thread ID, which is its pid if it is a process.
+    pub pid: Pid,
+    /// The process the task belongs to.
+    pub tgid: Pid,
+    /// How the task was created.
+    pub origin: Origin,
+    /// The index in [`ProcessTree::nodes`] of the task that created this
+    /// one, or `None` for a root.
+    ///
+    /// [`ProcessTree::nodes`]: struct.ProcessTree.html#method.nodes
+    pub parent: Option<usize>,
+    /// The indexes in [`ProcessTree::nodes`] of the tasks this one created,
+    /// in the order they were created.
+    ///
+    /// [`ProcessTree::nodes`]: struct.ProcessTree.html#method.nodes
+    pub children: Vec<usize>,
+    /// When the task was first seen, relative to when the tree started.
+    pub created: Duration,
+    /// Every program the task executed, in order.
+    pub execs: Vec<ExecRecord>,
+    /// When the task ended, if it has.
+    pub ended: Option<Duration>,
+    /// How the task ended, if it has.
+    pub exit: Option<Exit>,
+}
+
+impl TaskNode {
+    /// The program the task is running, from its last `exec`.
+    ///
+    /// Children inherit their parent's program, so this looks back through
+    /// the task's ancestors in `tree` if it hasn't called `exec` itself.
+    pub fn program<'a>(&'a self, tree: &'a ProcessTree) -> Option<&'a ExecRecord> {
+        let mut node = self;
+        loop {
+            if let Some(exec) = node.execs.last() {
+                return Some(exec);
+            }
+            node = &tree.nodes[node.parent?];
+        }
+    }
+}
+
+/// The tree of every process and thread seen in a trace, built from fork,
+/// clone and exec events.
+///
+/// Feed each event to [`record`], or let [`run`] drive a whole
+/// [`TraceSession`]. Tasks are kept after they exit, so the tree can be
+/// queried both during and after the trace, for example to find which
+/// programs a `make` ran and which of them failed. Pids may be reused over
+/// a long trace, so nodes are identified by their index in [`nodes`], and
+/// [`get`] finds the latest task with a pid.
+///
+/// [`record`]: #method.record
+/// [`run`]: #method.run
+/// [`nodes`]: #method.nodes
+/// [`get`]: #method.get
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug)]
+pub struct ProcessTree {
+    start: Instant,
+    nodes: Vec<TaskNode>,
+    /// The node of each task, by pid.
+    latest: HashMap<Pid, usize>,
+}
+
+impl Default for ProcessTree {
+    fn default() -> ProcessTree {
+        ProcessTree {
+            start: Instant::now(),
+            nodes: vec![],
+            latest: HashMap::new(),
+        }
+    }
+}
+
+impl ProcessTree {
+    /// Create an empty tree, with times measured from now.
+    pub fn new() -> ProcessTree {
+        ProcessTree::default()
+    }
+
+    /// Add `pid` as a root of the tree, reading the program it runs from
+    /// `/proc`.
+    ///
+    /// [`run`] adds the tracees already in its session this way.
+    ///
+    /// [`run`]: #method.run
+    pub fn add_root(&mut self, pid: Pid) -> usize {
+        let index = self.insert(pid, Origin::Root, None);
+        let exec = self.exec_record(pid);
+        self.nodes[index].execs.push(exec);
+        index
+    }
+
+    /// Update the tree for `event` reported by `pid`.
+    pub fn record(&mut self, pid: Pid, event: Event) {
+        match event {
+            Event::Fork(child) | Event::Vfork(child) | Event::Clone(child) => {
+                let parent = self.node_of(pid);
+                let origin = match event {
+                    Event::Fork(_) => Origin::Fork,
+                    Event::Vfork(_) => Origin::Vfork,
+                    _ if read_tgid(child) == Some(self.nodes[parent].tgid) => Origin::Thread,
+                    _ => Origin::Fork,
+                };
+                match self.latest.get(&child) {
+                    // The child's initial stop was reported first.
+                    Some(&index) if self.nodes[index].parent.is_none() => {
+                        let tgid = self.nodes[parent].tgid;
+                        let node = &mut self.nodes[index];
+                        node.origin = origin;
+                        node.parent = Some(parent);
+                        if origin == Origin::Thread {
+                            node.tgid = tgid;
+                        }
+                        self.nodes[parent].children.push(index);
+                    }
+                    _ => {
+                        self.insert(child, origin, Some(parent));
+                    }
+                }
+            }
+            // A task whose parent's event hasn't been seen yet.
+            Event::Attached if !self.latest.contains_key(&pid) => {
+                self.insert(pid, Origin::Root, None);
+            }
+            Event::Exec(former) => {
+                // If a thread other than the leader called exec, it has taken
+                // over the leader's pid, and its old thread ID is gone.
+                if former != pid {
+                    if let Some(index) = self.latest.remove(&former) {
+                        self.end(index, Exit::Replaced);
+                    }
+                }
+                let index = self.node_of(pid);
+                let exec = self.exec_record(pid);
+                self.nodes[index].execs.push(exec);
+            }
+            Event::Exited(code) => self.exit(pid, Exit::Exited(code)),
+            Event::Signaled(signal, _) => self.exit(pid, Exit::Signaled(signal)),
+            _ => {}
+        }
+    }
+
+    /// Build the tree from the events of `session` with
+    /// [`TraceSession::run`] until its tracees have all exited.
+    ///
+    /// The tracees already in the session become roots. The session should
+    /// have been configured with [`follow_forks`] and `PTRACE_O_TRACEEXEC`,
+    /// which [`trace_syscalls`] also sets.
+    ///
+    /// [`TraceSession::run`]: struct.TraceSession.html#method.run
+    /// [`follow_forks`]: struct.TraceSession.html#method.follow_forks
+    /// [`trace_syscalls`]: struct.TraceSession.html#method.trace_syscalls
+    pub fn run(&mut self, session: &mut TraceSession) -> io::Result<()> {
+        let pids: Vec<Pid> = session.pids().collect();
+        for pid in pids {
+            if !self.latest.contains_key(&pid) {
+                self.add_root(pid);
+            }
+        }
+        session.run(|_, pid, event| {
+            self.record(pid, event);
+            Ok(())
+        })
+    }
+
+    /// Every task seen so far, in the order they were first seen.
+    pub fn nodes(&self) -> &[TaskNode] {
+        &self.nodes
+    }
+
+    /// The latest task with thread ID `pid`.
+    pub fn get(&self, pid: Pid) -> Option<&TaskNode> {
+        self.latest.get(&pid).map(|&index| &self.nodes[index])
+    }
+
+    /// The tasks that have no parent in the tree.
+    pub fn roots(&self) -> impl Iterator<Item = &TaskNode> + '_ {
+        self.nodes.iter().filter(|node| node.parent.is_none())
+    }
+
+    /// The tasks below the node at `index`, depth first, not including it.
+    pub fn descendants(&self, index: usize) -> Vec<&TaskNode> {
+        let mut found = vec![];
+        let mut stack: Vec<usize> = self.nodes[index].children.iter().rev().cloned().collect();
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            found.push(node);
+            stack.extend(node.children.iter().rev());
+        }
+        found
+    }
+
+    fn insert(&mut self, pid: Pid, origin: Origin, parent: Option<usize>) -> usize {
+        let tgid = match (origin, parent) {
+            (Origin::Thread, Some(parent)) => self.nodes[parent].tgid,
+            (Origin::Root, _) => read_tgid(pid).unwrap_or(pid),
+            _ => pid,
+        };
+        self.nodes.push(TaskNode {
+            pid,
+            tgid,
+            origin,
+            parent,
+            children: vec![],
+            created: self.start.elapsed(),
+            execs: vec![],
+            ended: None,
+            exit: None,
+        });
+        let index = self.nodes.len() - 1;
+        if let Some(parent) = parent {
+            self.nodes[parent].children.push(index);
+        }
+        self.latest.insert(pid, index);
+        index
+    }
+
+    /// The node of `pid`, which is added as a root if it hasn't been seen.
+    fn node_of(&mut self, pid: Pid) -> usize {
+        match self.latest.get(&pid) {
+            Some(&index) => index,
+            None => self.add_root(pid),
+        }
+    }
+
+    fn exit(&mut self, pid: Pid, exit: Exit) {
+        if let Some(&index) = self.latest.get(&pid) {
+            self.end(index, exit);
+        }
+    }
+
+    fn end(&mut self, index: usize, exit: Exit) {
+        let node = &mut self.nodes[index];
+        if node.exit.is_none() {
+            node.ended = Some(self.start.elapsed());
+            node.exit = Some(exit);
+        }
+    }
+
+    fn exec_record(&self, pid: Pid) -> ExecRecord {
+        ExecRecord {
+            time: self.start.elapsed(),
+            path: fs::read_link(format!("/proc/{}/exe", pid)).ok(),
+            args: read_cmdline(pid),
+        }
+    }
+}
+
+/// The arguments of process `pid`, from `/proc/<pid>/cmdline`, or none if
+/// it can't be read.
+pub(crate) fn read_cmdline(pid: Pid) -> Vec<OsString> {
+    fs::read(format!("/proc/{}/cmdline", pid))
+        .map(|cmdline| {
+            cmdline
+                .split(|&b| b == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| OsString::from_vec(arg.to_vec()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::SpawnOptions;
+    use nix::sys::ptrace;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_process_tree() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut session = TraceSession::new();
+        session
+            .ptrace_options(ptrace::Options::PTRACE_O_TRACEEXEC)
+            .follow_forks();
+        let pid = session
+            .spawn(
+                Command::new(&path)
+                    .args(["exec", "fork"])
+                    .stdout(Stdio::null()),
+                SpawnOptions::new(),
+            )
+            .expect("Error spawning test process");
+        let mut tree = ProcessTree::new();
+        tree.run(&mut session).expect("Error building tree");
+
+        let roots: Vec<_> = tree.roots().collect();
+        assert_eq!(roots.len(), 1);
+        let root = roots[0];
+        assert_eq!((root.pid, root.origin), (pid, Origin::Root));
+        assert_eq!(root.exit, Some(Exit::Exited(0)));
+        // What it ran when the tree started, then its exec of itself.
+        assert_eq!(root.execs.len(), 2);
+        assert_eq!(root.execs[1].args[1..], ["fork"]);
+        assert_eq!(root.execs[1].path.as_ref(), Some(&path));
+
+        let children = tree.descendants(0);
+        assert_eq!(children.len(), 1);
+        let child = children[0];
+        assert_eq!(child.origin, Origin::Fork);
+        assert_eq!(child.parent, Some(0));
+        assert_eq!(child.exit, Some(Exit::Exited(0)));
+        assert!(child.created >= root.execs[1].time);
+        assert!(child.ended <= root.ended);
+        assert_eq!(child.program(&tree), root.execs.last());
+    }
+}
@@ -0,0 +1,41 @@
+use crate::memory;
+use nix::unistd::Pid;
+use std::io;
+
+/// Walk the stack of stopped tracee `pid` by following frame pointers.
+///
+/// Returns the program counter followed by the return address of each
+/// frame, innermost first, stopping after `max_depth` frames or when the
+/// frame pointer chain no longer looks valid. Code compiled without frame
+/// pointers will produce truncated stacks.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn frame_pointer_backtrace(
+    pid: Pid,
+    regs: &libc::user_regs_struct,
+    max_depth: usize,
+) -> io::Result<Vec<u64>> {
+    let mut frames = vec![regs.rip];
+    let mut fp = regs.rbp;
+    while frames.len() < max_depth {
+        // Frame pointers are always word-aligned, and a null frame pointer
+        // marks the outermost frame.
+        if fp == 0 || !fp.is_multiple_of(8) {
+            break;
+        }
+        let (next_fp, return_address) =
+            match (memory::read_u64(pid, fp), memory::read_u64(pid, fp + 8)) {
+                (Ok(next_fp), Ok(return_address)) => (next_fp, return_address),
+                _ => break,
+            };
+        if return_address == 0 {
+            break;
+        }
+        frames.push(return_address);
+        // The stack grows down, so callers' frames are at higher addresses.
+        if next_fp <= fp {
+            break;
+        }
+        fp = next_fp;
+    }
+    Ok(frames)
+}
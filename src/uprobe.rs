@@ -0,0 +1,208 @@
+//! Uprobes on functions in a tracee's executable, using the perf `uprobe` PMU.
+
+use crate::elf::Elf;
+use crate::perf::{self, PerfEventAttr, RingBuffer};
+use crate::{maps, Error};
+use nix::unistd::Pid;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::time::Duration;
+
+/// Where the kernel publishes the dynamic perf type of the uprobe PMU.
+const PMU_TYPE_PATH: &str = "/sys/bus/event_source/devices/uprobe/type";
+
+const PERF_SAMPLE_IP: u64 = 1 << 0;
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+
+const PERF_RECORD_SAMPLE: u32 = 9;
+
+/// The size of each probe's ring buffer, in pages.
+const RING_PAGES: usize = 8;
+
+/// One time a thread reached a probed function, reported by [`Uprobes::read`].
+///
+/// [`Uprobes::read`]: struct.Uprobes.html#method.read
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UprobeHit {
+    /// The symbol that was probed.
+    pub symbol: String,
+    /// The thread that hit the probe.
+    pub tid: Pid,
+    /// The address of the probe in the tracee.
+    pub address: u64,
+    /// When the probe was hit, on the kernel's perf clock.
+    pub time: Duration,
+}
+
+#[derive(Debug)]
+struct Probe {
+    symbol: String,
+    address: u64,
+    ring: RingBuffer,
+    _event: File,
+}
+
+/// Uprobes on functions in a tracee's main executable.
+///
+/// The kernel places the probes itself, without modifying the tracee's
+/// memory through ptrace or stopping it, so they don't interfere with
+/// breakpoints or the tracee's ptrace stops. Hits are recorded in a buffer
+/// for each probe, and [`read`] collects them, so they can be merged with
+/// the events from a [`TraceSession`] by reading them at each stop.
+///
+/// Only the thread the probes were attached to is probed: the kernel can't
+/// map the buffer of an event that is inherited by new threads. Placing
+/// uprobes needs `CAP_PERFMON` or `CAP_SYS_ADMIN`.
+/// If a probe's buffer fills up before it is read, later hits are lost.
+///
+/// [`read`]: #method.read
+/// [`TraceSession`]: struct.TraceSession.html
+#[derive(Debug)]
+pub struct Uprobes {
+    probes: Vec<Probe>,
+}
+
+impl Uprobes {
+    /// Attach a probe to each function in `symbols` from the executable of
+    /// thread `pid`, which should be stopped at its initial `exec` trap, or
+    /// at least before the functions run.
+    ///
+    /// Fails with `ErrorKind::NotFound` if a symbol isn't defined, and with
+    /// `Error::Unsupported` if the kernel has no uprobe PMU.
+    pub fn attach(pid: Pid, symbols: &[&str]) -> io::Result<Uprobes> {
+        let kind: u32 = match fs::read_to_string(PMU_TYPE_PATH) {
+            Ok(kind) => kind
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::Unsupported("perf uprobes").into())
+            }
+            Err(e) => return Err(e),
+        };
+        let exe_link = format!("/proc/{}/exe", pid);
+        let exe = fs::read_link(&exe_link)?;
+        let data = fs::read(&exe_link)?;
+        let elf = Elf::parse(&data)?;
+        let all_symbols = elf.symbols()?;
+        // The executable's first mapping is at the start of the file.
+        let bias = maps::read_maps(pid)?
+            .iter()
+            .find(|m| m.offset == 0 && m.pathname.as_deref() == exe.to_str())
+            .map(|m| m.start.wrapping_sub(elf.first_load_delta().unwrap_or(0)))
+            .ok_or_else(|| io::Error::other("Executable is not mapped"))?;
+        // The magic link resolves to the exact file the tracee is running.
+        let path = CString::new(exe_link).unwrap();
+        let mut probes = vec![];
+        for &name in symbols {
+            let symbol = all_symbols
+                .iter()
+                .find(|s| s.name == name && s.is_function)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("No symbol {}", name))
+                })?;
+            let offset = elf
+                .file_offset(symbol.value)?
+                .ok_or_else(|| io::Error::other("Symbol is not in a loaded segment"))?;
+            let mut attr = PerfEventAttr::new(kind, 0);
+            attr.config1 = path.as_ptr() as u64;
+            attr.config2 = offset;
+            attr.sample_period = 1;
+            attr.sample_type = PERF_SAMPLE_IP | PERF_SAMPLE_TID | PERF_SAMPLE_TIME;
+            attr.wakeup_events = 1;
+            let event = perf::perf_event_open(&attr, pid)?;
+            let ring = RingBuffer::new(&event, RING_PAGES)?;
+            probes.push(Probe {
+                symbol: name.to_string(),
+                address: bias.wrapping_add(symbol.value),
+                ring,
+                _event: event,
+            });
+        }
+        Ok(Uprobes { probes })
+    }
+
+    /// The address in the tracee of the probe on `symbol`.
+    pub fn address(&self, symbol: &str) -> Option<u64> {
+        self.probes
+            .iter()
+            .find(|p| p.symbol == symbol)
+            .map(|p| p.address)
+    }
+
+    /// Take every hit recorded since the last call, in the order they happened.
+    pub fn read(&mut self) -> Vec<UprobeHit> {
+        let mut hits = vec![];
+        for probe in &mut self.probes {
+            for (kind, body) in probe.ring.records() {
+                if kind != PERF_RECORD_SAMPLE || body.len() < 24 {
+                    continue;
+                }
+                let word = |i: usize| {
+                    let mut bytes = [0; 8];
+                    bytes.copy_from_slice(&body[i * 8..i * 8 + 8]);
+                    u64::from_ne_bytes(bytes)
+                };
+                // The IP, then the pid and tid, then the time.
+                let tid = (word(1) >> 32) as i32;
+                hits.push(UprobeHit {
+                    symbol: probe.symbol.clone(),
+                    tid: Pid::from_raw(tid),
+                    address: word(0),
+                    time: Duration::from_nanos(word(2)),
+                });
+            }
+        }
+        hits.sort_by_key(|hit| hit.time);
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use nix::sys::wait::WaitStatus;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_uprobes() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .stdout(Stdio::piped())
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        assert_eq!(
+            Uprobes::attach(pid, &["no_such_function"])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        let mut uprobes = match Uprobes::attach(pid, &["main"]) {
+            Ok(uprobes) => uprobes,
+            Err(e) => {
+                // Not privileged, or no uprobe support.
+                assert!(matches!(
+                    e.kind(),
+                    io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+                ));
+                let child = tracee.child_mut().unwrap();
+                child.kill().expect("Error killing child");
+                child.wait().expect("Error waiting for child");
+                return;
+            }
+        };
+        assert!(uprobes.read().is_empty());
+        let output = tracee.wait_with_output().expect("Error waiting for tracee");
+        assert!(matches!(output.status, WaitStatus::Exited(_, 0)));
+        let hits = uprobes.read();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].symbol, "main");
+        assert_eq!(hits[0].tid, pid);
+        assert_eq!(Some(hits[0].address), uprobes.address("main"));
+    }
+}
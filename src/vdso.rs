@@ -0,0 +1,162 @@
+//! Symbols from the vDSO the kernel maps into every process.
+
+use crate::elf::Elf;
+use crate::{maps, memory};
+use nix::unistd::Pid;
+use std::io;
+
+/// The pathname `/proc/<pid>/maps` gives the vDSO.
+const VDSO_PATHNAME: &str = "[vdso]";
+
+/// A function or variable defined by the vDSO.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VdsoSymbol {
+    /// The symbol's name, such as `__vdso_clock_gettime`.
+    pub name: String,
+    /// The symbol's address in the tracee.
+    pub address: u64,
+    /// The symbol's size in bytes, which may be 0 if it is unknown.
+    pub size: u64,
+}
+
+/// The vDSO mapped into a tracee, read with [`Tracee::vdso`].
+///
+/// Calls such as `clock_gettime`, `gettimeofday`, `time` and `getcpu` are
+/// usually answered by the vDSO without entering the kernel, so they never
+/// cause a system call stop. Setting a breakpoint on the vDSO function is
+/// the way to see them instead.
+///
+/// [`Tracee::vdso`]: struct.Tracee.html#method.vdso
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Vdso {
+    /// The start address of the vDSO mapping.
+    pub start: u64,
+    /// The end address of the vDSO mapping, exclusive.
+    pub end: u64,
+    symbols: Vec<VdsoSymbol>,
+}
+
+impl Vdso {
+    /// Find and parse the vDSO of process `pid`, which isn't mapped into
+    /// every process, for example with `vdso=0` on the kernel command line.
+    pub(crate) fn read(pid: Pid) -> io::Result<Option<Vdso>> {
+        let map = match maps::read_maps(pid)?
+            .into_iter()
+            .find(|m| m.pathname.as_deref() == Some(VDSO_PATHNAME))
+        {
+            Some(map) => map,
+            None => return Ok(None),
+        };
+        // The vDSO is a complete shared object, section headers included.
+        let mut image = vec![0; (map.end - map.start) as usize];
+        memory::read(pid, map.start, &mut image)?;
+        let elf = Elf::parse(&image)?;
+        let bias = map.start.wrapping_sub(elf.first_load_delta()?);
+        let mut symbols: Vec<VdsoSymbol> = elf
+            .symbols()?
+            .into_iter()
+            .map(|s| VdsoSymbol {
+                name: s.name,
+                address: bias.wrapping_add(s.value),
+                size: s.size,
+            })
+            .collect();
+        symbols.sort_by(|a, b| a.address.cmp(&b.address).then(a.name.cmp(&b.name)));
+        symbols.dedup();
+        Ok(Some(Vdso {
+            start: map.start,
+            end: map.end,
+            symbols,
+        }))
+    }
+
+    /// All of the vDSO's symbols, ordered by address.
+    pub fn symbols(&self) -> &[VdsoSymbol] {
+        &self.symbols
+    }
+
+    /// The address of the vDSO's symbol `name`.
+    ///
+    /// Most vDSO functions are exported both with and without a `__vdso_`
+    /// prefix, depending on the architecture, so if `name` isn't found the
+    /// other form is tried as well.
+    pub fn address(&self, name: &str) -> Option<u64> {
+        let other = match name.strip_prefix("__vdso_") {
+            Some(bare) => bare.to_string(),
+            None => format!("__vdso_{}", name),
+        };
+        let find = |name: &str| self.symbols.iter().find(|s| s.name == name);
+        find(name).or_else(|| find(&other)).map(|s| s.address)
+    }
+
+    /// The symbol containing `addr`, if it is in the vDSO.
+    ///
+    /// Addresses in the vDSO outside any symbol are attributed to the
+    /// closest symbol before them.
+    pub fn symbolize(&self, addr: u64) -> Option<&VdsoSymbol> {
+        if addr < self.start || addr >= self.end {
+            return None;
+        }
+        self.symbols.iter().rev().find(|s| s.address <= addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::process::Command;
+
+    #[test]
+    fn test_vdso() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let vdso = tracee.vdso().expect("Error reading vDSO").expect("No vDSO");
+        let addr = vdso
+            .address("clock_gettime")
+            .expect("No clock_gettime in vDSO");
+        assert_eq!(vdso.address("__vdso_clock_gettime"), Some(addr));
+        assert!(addr >= vdso.start && addr < vdso.end);
+        let symbol = vdso.symbolize(addr + 1).expect("Address not symbolized");
+        assert_eq!(symbol.address, addr);
+        assert!(symbol.name.ends_with("clock_gettime"));
+        assert_eq!(vdso.symbolize(vdso.end), None);
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_vdso_breakpoint() {
+        use crate::Breakpoints;
+        use nix::sys::ptrace;
+        use nix::sys::signal::Signal;
+        use nix::sys::wait::{waitpid, WaitStatus};
+
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .args(["spin", "10"])
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        let vdso = tracee.vdso().unwrap().expect("No vDSO");
+        let addr = vdso.address("clock_gettime").unwrap();
+        let mut breakpoints = Breakpoints::new();
+        breakpoints
+            .insert(pid, addr)
+            .expect("Error inserting breakpoint");
+        // `Instant::now` calls clock_gettime in the vDSO, which makes no system call.
+        ptrace::cont(pid, None).expect("Error continuing child");
+        assert_eq!(
+            waitpid(pid, None),
+            Ok(WaitStatus::Stopped(pid, Signal::SIGTRAP))
+        );
+        assert_eq!(breakpoints.hit(pid).unwrap(), Some(addr));
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
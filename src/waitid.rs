@@ -0,0 +1,186 @@
+//! Waiting with `waitid`, which reports a state change as a full `siginfo_t`.
+
+use nix::sys::signal::Signal;
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::time::Duration;
+
+/// The kind of state change in a [`WaitInfo`], from its `si_code`.
+///
+/// [`WaitInfo`]: struct.WaitInfo.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WaitCode {
+    /// The process exited, `CLD_EXITED`.
+    Exited,
+    /// The process was killed by a signal, `CLD_KILLED`.
+    Killed,
+    /// The process was killed by a signal and dumped core, `CLD_DUMPED`.
+    Dumped,
+    /// The process is in a group-stop that isn't reported to a tracer,
+    /// `CLD_STOPPED`.
+    Stopped,
+    /// The traced process stopped, `CLD_TRAPPED`.
+    Trapped,
+    /// The process was continued by `SIGCONT`, `CLD_CONTINUED`.
+    Continued,
+}
+
+/// A state change reported by [`Tracee::wait_info`].
+///
+/// Unlike a `WaitStatus`, this includes the CPU time the process has used,
+/// from the resource usage `waitid` returns, and its uid. It can be collected without reaping, so a
+/// later wait sees the same change again.
+///
+/// [`Tracee::wait_info`]: struct.Tracee.html#method.wait_info
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WaitInfo {
+    /// The thread whose state changed.
+    pub pid: Pid,
+    /// The real user ID of the process.
+    pub uid: u32,
+    /// What happened.
+    pub code: WaitCode,
+    /// The exit status for `Exited`, or the signal number otherwise. For a
+    /// `Trapped` ptrace event stop the event is in the bits above the
+    /// signal, as in the `waitpid` encoding.
+    pub status: i32,
+    /// The user CPU time the process has used.
+    pub utime: Duration,
+    /// The system CPU time the process has used.
+    pub stime: Duration,
+}
+
+impl WaitInfo {
+    /// The signal that caused the change, or `None` if the process exited.
+    pub fn signal(&self) -> Option<Signal> {
+        match self.code {
+            WaitCode::Exited => None,
+            _ => Signal::try_from(self.status & 0x7f).ok(),
+        }
+    }
+
+    /// The same state change as `waitpid` would report it.
+    pub fn to_wait_status(&self) -> WaitStatus {
+        let pid = self.pid;
+        match (self.code, self.signal()) {
+            (WaitCode::Exited, _) => WaitStatus::Exited(pid, self.status),
+            (WaitCode::Killed, Some(signal)) => WaitStatus::Signaled(pid, signal, false),
+            (WaitCode::Dumped, Some(signal)) => WaitStatus::Signaled(pid, signal, true),
+            (WaitCode::Trapped, Some(Signal::SIGTRAP)) if self.status == 0x80 | libc::SIGTRAP => {
+                WaitStatus::PtraceSyscall(pid)
+            }
+            (WaitCode::Trapped, Some(signal)) if self.status >> 8 != 0 => {
+                WaitStatus::PtraceEvent(pid, signal, self.status >> 8)
+            }
+            (WaitCode::Continued, _) => WaitStatus::Continued(pid),
+            (_, Some(signal)) => WaitStatus::Stopped(pid, signal),
+            // The kernel only reports valid signals.
+            (_, None) => WaitStatus::StillAlive,
+        }
+    }
+}
+
+/// Wait for a state change in `pid` with `waitid`, returning `None` if
+/// `WNOHANG` was given and there is none.
+///
+/// If `flags` has none of `WEXITED`, `WSTOPPED` and `WCONTINUED`, exits and
+/// stops are reported. `__WALL` is always added, so threads can be waited for.
+pub(crate) fn waitid(pid: Pid, flags: WaitPidFlag) -> io::Result<Option<WaitInfo>> {
+    let mut flags = flags | WaitPidFlag::__WALL;
+    let states = WaitPidFlag::WEXITED | WaitPidFlag::WSTOPPED | WaitPidFlag::WCONTINUED;
+    if !flags.intersects(states) {
+        flags |= WaitPidFlag::WEXITED | WaitPidFlag::WSTOPPED;
+    }
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    // The kernel no longer fills in si_utime and si_stime, but the system
+    // call, unlike the libc wrapper, can also return the resource usage.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_waitid,
+            libc::P_PID,
+            pid.as_raw(),
+            &mut info as *mut libc::siginfo_t,
+            flags.bits(),
+            &mut usage as *mut libc::rusage,
+        )
+    };
+    if ret < 0 {
+        return Err(crate::wait_error(nix::Error::last()));
+    }
+    let (child, uid, status) = unsafe { (info.si_pid(), info.si_uid(), info.si_status()) };
+    // With WNOHANG and nothing to report, the siginfo is left zeroed.
+    if child == 0 {
+        return Ok(None);
+    }
+    let code = match info.si_code {
+        libc::CLD_EXITED => WaitCode::Exited,
+        libc::CLD_KILLED => WaitCode::Killed,
+        libc::CLD_DUMPED => WaitCode::Dumped,
+        libc::CLD_STOPPED => WaitCode::Stopped,
+        libc::CLD_TRAPPED => WaitCode::Trapped,
+        libc::CLD_CONTINUED => WaitCode::Continued,
+        code => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown waitid code {}", code),
+            ))
+        }
+    };
+    Ok(Some(WaitInfo {
+        pid: Pid::from_raw(child),
+        uid,
+        code,
+        status,
+        utime: duration(usage.ru_utime),
+        stime: duration(usage.ru_stime),
+    }))
+}
+
+fn duration(time: libc::timeval) -> Duration {
+    Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use nix::sys::ptrace;
+    use nix::sys::signal::kill;
+    use std::process::Command;
+
+    #[test]
+    fn test_wait_info() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let tracee = Command::new(&path)
+            .args(["spin", "100"])
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let pid = tracee.pid();
+        assert_eq!(tracee.wait_info(WaitPidFlag::WNOHANG).unwrap(), None);
+        ptrace::cont(pid, None).expect("Error continuing child");
+        kill(pid, Signal::SIGSTOP).expect("Error stopping child");
+        let flags = WaitPidFlag::WSTOPPED | WaitPidFlag::WNOWAIT;
+        let info = tracee.wait_info(flags).expect("Error waiting").unwrap();
+        assert_eq!(info.pid, pid);
+        assert_eq!(info.uid, nix::unistd::getuid().as_raw());
+        assert_eq!(info.code, WaitCode::Trapped);
+        assert_eq!(info.signal(), Some(Signal::SIGSTOP));
+        // WNOWAIT left the stop to be collected again.
+        assert_eq!(tracee.try_wait().unwrap(), Some(info.to_wait_status()));
+        ptrace::cont(pid, None).expect("Error continuing child");
+        let info = tracee
+            .wait_info(WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT)
+            .expect("Error waiting")
+            .unwrap();
+        assert_eq!(info.code, WaitCode::Exited);
+        assert_eq!(info.status, 0);
+        assert_eq!(info.signal(), None);
+        assert!(info.utime + info.stime > Duration::from_millis(10));
+        assert_eq!(tracee.try_wait().unwrap(), Some(WaitStatus::Exited(pid, 0)));
+    }
+}
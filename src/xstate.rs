@@ -0,0 +1,246 @@
+//! The extended x86-64 register state saved by `XSAVE`.
+
+use crate::Error;
+use nix::unistd::Pid;
+use std::arch::x86_64::__cpuid_count;
+use std::convert::TryInto;
+use std::io;
+
+/// The regset holding the `XSAVE` area, from `<elf.h>`.
+const NT_X86_XSTATE: libc::c_int = 0x202;
+
+/// The size of the legacy `FXSAVE` region at the start of the area.
+const LEGACY_SIZE: usize = 512;
+/// The size of the header after the legacy region, which holds `XSTATE_BV`.
+const HEADER_SIZE: usize = 64;
+
+const MXCSR: usize = 24;
+const XMM: usize = 160;
+
+/// State components, numbered as in `XSTATE_BV`.
+const YMM_HI128: u32 = 2;
+const ZMM_HI256: u32 = 6;
+const HI16_ZMM: u32 = 7;
+
+/// A thread's complete FPU and SIMD state, in the standard `XSAVE` format,
+/// read with [`Tracee::xstate`].
+///
+/// Accessors are provided for the vector registers, and [`component`]
+/// gives access to the other state components, such as the MPX bounds
+/// registers or AVX-512 mask registers. Component offsets come from the
+/// tracer's `CPUID`, so the tracee must be on the same machine, as is
+/// always the case for ptrace.
+///
+/// [`Tracee::xstate`]: struct.Tracee.html#method.xstate
+/// [`component`]: #method.component
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XState {
+    data: Vec<u8>,
+}
+
+impl XState {
+    /// Read the state of the stopped thread `tid`.
+    ///
+    /// Fails with `Error::Unsupported` if the CPU has no `XSAVE`.
+    pub(crate) fn read(tid: Pid) -> io::Result<XState> {
+        let size = __cpuid_count(0xd, 0).ecx as usize;
+        let mut data = vec![0; size.max(LEGACY_SIZE + HEADER_SIZE)];
+        let len = regset(libc::PTRACE_GETREGSET, tid, &mut data)?;
+        data.truncate(len);
+        Ok(XState { data })
+    }
+
+    /// Write this state to the stopped thread `tid`.
+    pub(crate) fn write(&self, tid: Pid) -> io::Result<()> {
+        let mut data = self.data.clone();
+        regset(libc::PTRACE_SETREGSET, tid, &mut data).map(drop)
+    }
+
+    /// The raw `XSAVE` area.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The `XSTATE_BV` bitmap, with a bit set for each state component that
+    /// isn't in its initial, all-zero state.
+    pub fn xstate_bv(&self) -> u64 {
+        u64::from_ne_bytes(self.data[LEGACY_SIZE..LEGACY_SIZE + 8].try_into().unwrap())
+    }
+
+    /// The `MXCSR` control and status register.
+    pub fn mxcsr(&self) -> u32 {
+        u32::from_ne_bytes(self.data[MXCSR..MXCSR + 4].try_into().unwrap())
+    }
+
+    /// The bytes of state component `index` in the area, or `None` if the
+    /// CPU or the kernel doesn't support it.
+    ///
+    /// Components 0 and 1, the x87 and SSE state, are in the legacy region
+    /// rather than having their own. A component whose bit is clear in
+    /// [`xstate_bv`] is in its initial state, and reads as zeros.
+    ///
+    /// [`xstate_bv`]: #method.xstate_bv
+    pub fn component(&self, index: u32) -> Option<&[u8]> {
+        let (offset, size) = component_range(index)?;
+        self.data.get(offset..offset + size)
+    }
+
+    /// Register `xmm<n>`, for `n` up to 15.
+    pub fn xmm(&self, n: usize) -> Option<[u8; 16]> {
+        if n >= 16 {
+            return None;
+        }
+        let offset = XMM + n * 16;
+        self.data[offset..offset + 16].try_into().ok()
+    }
+
+    /// Register `ymm<n>`, for `n` up to 15, or `None` without AVX.
+    pub fn ymm(&self, n: usize) -> Option<[u8; 32]> {
+        let high = self.component(YMM_HI128)?.get(n * 16..n * 16 + 16)?;
+        let mut ymm = [0; 32];
+        ymm[..16].copy_from_slice(&self.xmm(n)?);
+        ymm[16..].copy_from_slice(high);
+        Some(ymm)
+    }
+
+    /// Register `zmm<n>`, for `n` up to 31, or `None` without AVX-512.
+    pub fn zmm(&self, n: usize) -> Option<[u8; 64]> {
+        let mut zmm = [0; 64];
+        if n < 16 {
+            let high = self.component(ZMM_HI256)?.get(n * 32..n * 32 + 32)?;
+            zmm[..32].copy_from_slice(&self.ymm(n)?);
+            zmm[32..].copy_from_slice(high);
+        } else {
+            let offset = (n - 16) * 64;
+            zmm.copy_from_slice(self.component(HI16_ZMM)?.get(offset..offset + 64)?);
+        }
+        Some(zmm)
+    }
+
+    /// Set register `xmm<n>`, keeping the upper bits of `ymm<n>` and
+    /// `zmm<n>`. Returns `false` if there is no such register.
+    pub fn set_xmm(&mut self, n: usize, value: [u8; 16]) -> bool {
+        if n >= 16 {
+            return false;
+        }
+        let offset = XMM + n * 16;
+        self.data[offset..offset + 16].copy_from_slice(&value);
+        self.mark(1);
+        true
+    }
+
+    /// Set register `ymm<n>`, keeping the upper bits of `zmm<n>`. Returns
+    /// `false` if there is no such register.
+    pub fn set_ymm(&mut self, n: usize, value: [u8; 32]) -> bool {
+        if n >= 16 || !self.set_component(YMM_HI128, n * 16, &value[16..]) {
+            return false;
+        }
+        self.set_xmm(n, value[..16].try_into().unwrap())
+    }
+
+    /// Set register `zmm<n>`. Returns `false` if there is no such register.
+    pub fn set_zmm(&mut self, n: usize, value: [u8; 64]) -> bool {
+        if n >= 16 {
+            return n < 32 && self.set_component(HI16_ZMM, (n - 16) * 64, &value);
+        }
+        if !self.set_component(ZMM_HI256, n * 32, &value[32..]) {
+            return false;
+        }
+        self.set_ymm(n, value[..32].try_into().unwrap())
+    }
+
+    /// Copy `value` to `offset` within component `index`, marking the
+    /// component as in use.
+    fn set_component(&mut self, index: u32, offset: usize, value: &[u8]) -> bool {
+        let (start, size) = match component_range(index) {
+            Some(range) => range,
+            None => return false,
+        };
+        if offset + value.len() > size || start + size > self.data.len() {
+            return false;
+        }
+        self.data[start + offset..start + offset + value.len()].copy_from_slice(value);
+        self.mark(index);
+        true
+    }
+
+    fn mark(&mut self, index: u32) {
+        let bv = self.xstate_bv() | 1 << index;
+        self.data[LEGACY_SIZE..LEGACY_SIZE + 8].copy_from_slice(&bv.to_ne_bytes());
+    }
+}
+
+/// The offset and size of state component `index` in the standard format.
+fn component_range(index: u32) -> Option<(usize, usize)> {
+    if !(2..63).contains(&index) {
+        return None;
+    }
+    let supported = __cpuid_count(0xd, 0);
+    let mask = supported.eax as u64 | (supported.edx as u64) << 32;
+    if mask & 1 << index == 0 {
+        return None;
+    }
+    let leaf = __cpuid_count(0xd, index);
+    Some((leaf.ebx as usize, leaf.eax as usize))
+}
+
+/// Get or set the `XSAVE` regset of `tid` with `buf`, returning the number
+/// of bytes the kernel used.
+fn regset(request: libc::c_uint, tid: Pid, buf: &mut [u8]) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let ret = unsafe {
+        libc::ptrace(
+            request,
+            tid.as_raw(),
+            NT_X86_XSTATE as usize,
+            &mut iov as *mut libc::iovec,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ENODEV) {
+            return Err(Error::Unsupported("XSAVE").into());
+        }
+        return Err(e);
+    }
+    Ok(iov.iov_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_process_path;
+    use crate::{CommandPtraceSpawn, SpawnOptions};
+    use std::process::Command;
+
+    #[test]
+    fn test_xstate() {
+        let path = test_process_path().expect("Failed to get test process path");
+        let mut tracee = Command::new(&path)
+            .spawn_tracee(SpawnOptions::new())
+            .expect("Error spawning test process");
+        let mut xstate = tracee.xstate().expect("Error reading xstate");
+        assert!(xstate.as_bytes().len() >= 576);
+        // The power-on default, with all exceptions masked.
+        assert_eq!(xstate.mxcsr(), 0x1f80);
+        assert!(xstate.xmm(16).is_none());
+        assert!(xstate.set_xmm(3, [0x11; 16]));
+        if let Some(ymm) = xstate.ymm(5) {
+            assert_eq!(ymm, [0; 32]);
+            assert!(xstate.set_ymm(5, [0x22; 32]));
+        }
+        if xstate.zmm(20).is_some() {
+            assert!(xstate.set_zmm(20, [0x33; 64]));
+        }
+        tracee.set_xstate(&xstate).expect("Error writing xstate");
+        let written = tracee.xstate().expect("Error reading xstate");
+        assert_eq!(written.xmm(3), Some([0x11; 16]));
+        assert_eq!(written.ymm(5), xstate.ymm(5));
+        assert_eq!(written.zmm(20), xstate.zmm(20));
+        let child = tracee.child_mut().unwrap();
+        child.kill().expect("Error killing child");
+        child.wait().expect("Error waiting for child");
+    }
+}
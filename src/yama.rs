@@ -0,0 +1,75 @@
+use nix::unistd::Pid;
+use std::fs;
+use std::io;
+
+/// Which process may attach to the caller with ptrace under the Yama LSM.
+///
+/// See [`set_ptracer`].
+///
+/// [`set_ptracer`]: fn.set_ptracer.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ptracer {
+    /// Only the given process and its descendants may attach.
+    Pid(Pid),
+    /// Any process with the same uid may attach.
+    Any,
+    /// Remove any exception previously declared.
+    None,
+}
+
+/// Declare which process may attach to this one with `PTRACE_ATTACH`, with
+/// `prctl(PR_SET_PTRACER, ...)`.
+///
+/// When Yama's `ptrace_scope` is 1, a process may only be attached to by its
+/// ancestors, unless it makes an exception for a tracer this way. Fails with
+/// `EINVAL` if the kernel wasn't built with Yama.
+///
+/// To make an exception in a child you spawn, use
+/// [`SpawnOptions::allow_ptracer`].
+///
+/// [`SpawnOptions::allow_ptracer`]: struct.SpawnOptions.html#method.allow_ptracer
+pub fn set_ptracer(ptracer: Ptracer) -> io::Result<()> {
+    let arg = match ptracer {
+        Ptracer::Pid(pid) => pid.as_raw() as libc::c_ulong,
+        Ptracer::Any => libc::PR_SET_PTRACER_ANY as libc::c_ulong,
+        Ptracer::None => 0,
+    };
+    let ret = unsafe { libc::prctl(libc::PR_SET_PTRACER, arg, 0, 0, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Read Yama's `kernel.yama.ptrace_scope` setting.
+///
+/// Returns `None` if the kernel wasn't built with Yama.
+pub fn ptrace_scope() -> io::Result<Option<u32>> {
+    match fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope") {
+        Ok(scope) => scope
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_ptracer() {
+        let has_yama = ptrace_scope()
+            .expect("Error reading ptrace_scope")
+            .is_some();
+        for ptracer in [Ptracer::Pid(Pid::parent()), Ptracer::Any, Ptracer::None] {
+            match set_ptracer(ptracer) {
+                Ok(()) => assert!(has_yama),
+                Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EINVAL)),
+            }
+        }
+    }
+}